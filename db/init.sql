@@ -8,27 +8,110 @@ CREATE TABLE IF NOT EXISTS events
     event_version  text                         NOT NULL,
     payload        json                         NOT NULL,
     metadata       json                         NOT NULL,
+    created_at     text                         NOT NULL DEFAULT '',
+    prev_hash      text,
+    event_hash     text,
     PRIMARY KEY (aggregate_type, aggregate_id, sequence)
 );
 
+-- support filtering events by type and commit time, e.g. with `EventQuery`
+CREATE INDEX IF NOT EXISTS events_event_type ON events (event_type);
+CREATE INDEX IF NOT EXISTS events_created_at ON events (created_at);
+
+-- these are only needed if `Partitioning` is used to split events across tables
+CREATE TABLE IF NOT EXISTS events_partitions
+(
+    ordinal integer NOT NULL,
+    name    text    NOT NULL UNIQUE,
+    PRIMARY KEY (ordinal)
+);
+CREATE VIEW IF NOT EXISTS events_all AS
+SELECT rowid AS rowid, aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata, created_at FROM events;
+
 -- this table is only needed if snapshotting is employed
 CREATE TABLE IF NOT EXISTS snapshots
 (
-    aggregate_type   text                                 NOT NULL,
-    aggregate_id     text                                 NOT NULL,
-    last_sequence    bigint CHECK (last_sequence >= 0)    NOT NULL,
-    current_snapshot bigint CHECK (current_snapshot >= 0) NOT NULL,
-    payload          json                                 NOT NULL,
+    aggregate_type    text                                 NOT NULL,
+    aggregate_id      text                                 NOT NULL,
+    last_sequence     bigint CHECK (last_sequence >= 0)    NOT NULL,
+    current_snapshot  bigint CHECK (current_snapshot >= 0) NOT NULL,
+    aggregate_version text                                 NOT NULL DEFAULT '',
+    payload           json                                 NOT NULL,
     PRIMARY KEY (aggregate_type, aggregate_id, last_sequence)
 );
 
+-- snapshot updates are guarded on `last_sequence`, which relies on a single snapshot per aggregate instance
+CREATE UNIQUE INDEX IF NOT EXISTS snapshots_aggregate ON snapshots (aggregate_type, aggregate_id);
+
+-- this table is only needed if a `HashChain` is used, recording the Merkle roots of the event hashes
+CREATE TABLE IF NOT EXISTS events_merkle_roots
+(
+    first_position integer NOT NULL,
+    last_position  integer NOT NULL,
+    leaves         integer NOT NULL,
+    root           text    NOT NULL,
+    computed_at    text    NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    PRIMARY KEY (first_position)
+);
+
+-- this table is only needed if `MetadataStorage::Dictionary` is used to intern event metadata
+CREATE TABLE IF NOT EXISTS metadata_dictionary
+(
+    id    integer NOT NULL,
+    key   text    NOT NULL,
+    value json    NOT NULL,
+    PRIMARY KEY (id),
+    UNIQUE (key, value)
+);
+
+-- this table is only needed if `PayloadStorage::Deduplicated` is used to store repeated event payloads once
+CREATE TABLE IF NOT EXISTS event_payloads
+(
+    id       integer NOT NULL,
+    hash     text    NOT NULL,
+    contents json    NOT NULL,
+    PRIMARY KEY (id)
+);
+
+CREATE INDEX IF NOT EXISTS event_payloads_hash ON event_payloads (hash);
+
+-- this table is only needed if `WriterLease` is used to elect a single writer process
+CREATE TABLE IF NOT EXISTS writer_leases
+(
+    name       text    NOT NULL,
+    holder     text    NOT NULL,
+    expires_at integer NOT NULL,
+    PRIMARY KEY (name)
+);
+
+-- this table is only needed if views are tracked with `SqliteViewRepository::with_progress_tracking`
+CREATE TABLE IF NOT EXISTS view_progress
+(
+    view_name  text    NOT NULL,
+    position   integer NOT NULL,
+    updated_at text    NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    PRIMARY KEY (view_name)
+);
+
+-- the configuration of each event store, recorded and verified by `SqliteEventRepository::ready`
+CREATE TABLE IF NOT EXISTS store_config
+(
+    events_table text NOT NULL,
+    fingerprint  text NOT NULL,
+    config       json NOT NULL,
+    recorded_at  text NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    PRIMARY KEY (events_table)
+);
+
 -- one view table should be created for every `SqliteViewRepository` used
 -- replace name with the value used in `SqliteViewRepository::new(view_name: String)`
+-- `last_applied` records the events applied to each view, see `with_event_dedup`
 CREATE TABLE IF NOT EXISTS test_view
 (
-    view_id text                        NOT NULL,
-    version bigint CHECK (version >= 0) NOT NULL,
-    payload json                        NOT NULL,
+    view_id      text                        NOT NULL,
+    version      bigint CHECK (version >= 0) NOT NULL,
+    payload      json                        NOT NULL,
+    last_applied json                        NOT NULL DEFAULT '{}',
     PRIMARY KEY (view_id)
 );
 
@@ -0,0 +1,5 @@
+-- Adds the commit timestamp of each event, see the `audit` module, to an existing events table.
+-- Events committed before the migration have an empty timestamp.
+ALTER TABLE events ADD COLUMN created_at text NOT NULL DEFAULT '';
+
+CREATE INDEX IF NOT EXISTS events_created_at ON events (created_at);
@@ -0,0 +1,15 @@
+-- Adds the columns of the hash chain, see `HashChain`, to an existing events table. Events
+-- committed before the hash chain was configured remain unhashed and precede the chain.
+ALTER TABLE events ADD COLUMN prev_hash text;
+ALTER TABLE events ADD COLUMN event_hash text;
+
+-- the Merkle roots of the event hashes, see `SqliteEventRepository::compute_merkle_root`
+CREATE TABLE IF NOT EXISTS events_merkle_roots
+(
+    first_position integer NOT NULL,
+    last_position  integer NOT NULL,
+    leaves         integer NOT NULL,
+    root           text    NOT NULL,
+    computed_at    text    NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    PRIMARY KEY (first_position)
+);
@@ -0,0 +1,11 @@
+-- Migrates an existing snapshots table to the single snapshot per aggregate instance that
+-- snapshot updates are now guarded on. Only the most recent snapshot of each aggregate instance,
+-- the one with the highest `last_sequence`, is kept.
+DELETE FROM snapshots
+  WHERE EXISTS (SELECT 1
+                  FROM snapshots newer
+                  WHERE newer.aggregate_type = snapshots.aggregate_type
+                    AND newer.aggregate_id = snapshots.aggregate_id
+                    AND newer.last_sequence > snapshots.last_sequence);
+
+CREATE UNIQUE INDEX IF NOT EXISTS snapshots_aggregate ON snapshots (aggregate_type, aggregate_id);
@@ -0,0 +1,4 @@
+-- Adds the version of the aggregate each snapshot was taken of, see
+-- `SqliteEventRepository::with_aggregate_version`, to an existing snapshots table. Snapshots taken
+-- before the migration are unversioned, see `UNVERSIONED_AGGREGATE`.
+ALTER TABLE snapshots ADD COLUMN aggregate_version text NOT NULL DEFAULT '';
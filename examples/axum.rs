@@ -0,0 +1,159 @@
+//! A minimal web service using `CqrsState` as axum application state.
+//!
+//! Run with `cargo run --example axum --features web`, then:
+//!
+//! ```text
+//! curl -X POST localhost:3030/counters/my-counter/increment
+//! curl localhost:3030/counters/my-counter
+//! ```
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use cqrs_es::persist::GenericQuery;
+use cqrs_es::{Aggregate, DomainEvent, EventEnvelope, View};
+use rusqlite_es::{default_sqlite_pool, sqlite_cqrs, CqrsState, SqliteViewRepository};
+use serde::{Deserialize, Serialize};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS events
+(
+    aggregate_type text                         NOT NULL,
+    aggregate_id   text                         NOT NULL,
+    sequence       bigint CHECK (sequence >= 0) NOT NULL,
+    event_type     text                         NOT NULL,
+    event_version  text                         NOT NULL,
+    payload        json                         NOT NULL,
+    metadata       json                         NOT NULL,
+    created_at     text                         NOT NULL DEFAULT '',
+    PRIMARY KEY (aggregate_type, aggregate_id, sequence)
+);
+CREATE TABLE IF NOT EXISTS counter_view
+(
+    view_id text                        NOT NULL,
+    version bigint CHECK (version >= 0) NOT NULL,
+    payload json                        NOT NULL,
+    PRIMARY KEY (view_id)
+);";
+
+#[derive(Default, Serialize, Deserialize)]
+struct Counter {
+    count: u64,
+}
+
+#[derive(Deserialize)]
+enum CounterCommand {
+    Increment,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum CounterEvent {
+    Incremented,
+}
+
+impl DomainEvent for CounterEvent {
+    fn event_type(&self) -> String {
+        "Incremented".to_string()
+    }
+
+    fn event_version(&self) -> String {
+        "1.0".to_string()
+    }
+}
+
+#[derive(Debug)]
+struct CounterError(String);
+
+impl Display for CounterError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for CounterError {}
+
+#[async_trait]
+impl Aggregate for Counter {
+    type Command = CounterCommand;
+    type Event = CounterEvent;
+    type Error = CounterError;
+    type Services = ();
+
+    fn aggregate_type() -> String {
+        "Counter".to_string()
+    }
+
+    async fn handle(
+        &self,
+        command: Self::Command,
+        _services: &Self::Services,
+    ) -> Result<Vec<Self::Event>, Self::Error> {
+        match command {
+            CounterCommand::Increment => Ok(vec![CounterEvent::Incremented]),
+        }
+    }
+
+    fn apply(&mut self, event: Self::Event) {
+        match event {
+            CounterEvent::Incremented => self.count += 1,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CounterView {
+    count: u64,
+}
+
+impl View<Counter> for CounterView {
+    fn update(&mut self, event: &EventEnvelope<Counter>) {
+        match event.payload {
+            CounterEvent::Incremented => self.count += 1,
+        }
+    }
+}
+
+type AppState = CqrsState<Counter, CounterView>;
+
+async fn increment(State(state): State<AppState>, Path(id): Path<String>) -> StatusCode {
+    match state.execute(&id, CounterCommand::Increment).await {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+async fn count(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<CounterView>, StatusCode> {
+    match state.load_view(&id).await {
+        Ok(Some(view)) => Ok(Json(view)),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let pool = default_sqlite_pool("axum_example.db");
+    pool.get()
+        .unwrap()
+        .execute_batch(SCHEMA)
+        .expect("failed to create tables");
+
+    let view_repo = Arc::new(SqliteViewRepository::new("counter_view", pool.clone()));
+    let query = GenericQuery::new(view_repo.clone());
+    let cqrs = sqlite_cqrs(pool, vec![Box::new(query)], ());
+    let state = CqrsState::new(cqrs, view_repo);
+
+    let app = Router::new()
+        .route("/counters/:id/increment", post(increment))
+        .route("/counters/:id", get(count))
+        .with_state(state);
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:3030").await.unwrap();
+    axum::serve(listener, app).await.unwrap();
+}
@@ -0,0 +1,113 @@
+use std::fmt::{Display, Formatter};
+
+use async_trait::async_trait;
+use cqrs_es::{Aggregate, DomainEvent};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BankAccount {
+    opened: bool,
+    balance: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub enum BankAccountCommand {
+    OpenAccount { account_id: String },
+    DepositMoney { amount: i64 },
+    WithdrawMoney { amount: i64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BankAccountEvent {
+    AccountOpened { account_id: String },
+    CustomerDepositedMoney { amount: i64, balance: i64 },
+    CustomerWithdrewCash { amount: i64, balance: i64 },
+}
+
+impl DomainEvent for BankAccountEvent {
+    fn event_type(&self) -> String {
+        match self {
+            BankAccountEvent::AccountOpened { .. } => "AccountOpened",
+            BankAccountEvent::CustomerDepositedMoney { .. } => "CustomerDepositedMoney",
+            BankAccountEvent::CustomerWithdrewCash { .. } => "CustomerWithdrewCash",
+        }
+        .to_string()
+    }
+
+    fn event_version(&self) -> String {
+        "1.0".to_string()
+    }
+}
+
+#[derive(Debug)]
+pub struct BankAccountError(String);
+
+impl Display for BankAccountError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for BankAccountError {}
+
+impl From<&str> for BankAccountError {
+    fn from(message: &str) -> Self {
+        Self(message.to_string())
+    }
+}
+
+#[async_trait]
+impl Aggregate for BankAccount {
+    type Command = BankAccountCommand;
+    type Event = BankAccountEvent;
+    type Error = BankAccountError;
+    type Services = ();
+
+    fn aggregate_type() -> String {
+        "BankAccount".to_string()
+    }
+
+    async fn handle(
+        &self,
+        command: Self::Command,
+        _services: &Self::Services,
+    ) -> Result<Vec<Self::Event>, Self::Error> {
+        match command {
+            BankAccountCommand::OpenAccount { account_id } => {
+                if self.opened {
+                    return Err("account already open".into());
+                }
+                Ok(vec![BankAccountEvent::AccountOpened { account_id }])
+            }
+            BankAccountCommand::DepositMoney { amount } => {
+                if !self.opened {
+                    return Err("account not open".into());
+                }
+                Ok(vec![BankAccountEvent::CustomerDepositedMoney {
+                    amount,
+                    balance: self.balance + amount,
+                }])
+            }
+            BankAccountCommand::WithdrawMoney { amount } => {
+                if !self.opened {
+                    return Err("account not open".into());
+                }
+                if self.balance < amount {
+                    return Err("insufficient funds".into());
+                }
+                Ok(vec![BankAccountEvent::CustomerWithdrewCash {
+                    amount,
+                    balance: self.balance - amount,
+                }])
+            }
+        }
+    }
+
+    fn apply(&mut self, event: Self::Event) {
+        match event {
+            BankAccountEvent::AccountOpened { .. } => self.opened = true,
+            BankAccountEvent::CustomerDepositedMoney { balance, .. }
+            | BankAccountEvent::CustomerWithdrewCash { balance, .. } => self.balance = balance,
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! An end-to-end bank account service: an event sourced aggregate with snapshots, an SQLite
+//! backed view, a view rebuild command and an HTTP API.
+//!
+//! Serve the API with `cargo run --example bank-account --features web`, then:
+//!
+//! ```text
+//! curl -X POST localhost:3030/accounts/acct-1 -H 'content-type: application/json' \
+//!     -d '{"OpenAccount": {"account_id": "acct-1"}}'
+//! curl -X POST localhost:3030/accounts/acct-1 -H 'content-type: application/json' \
+//!     -d '{"DepositMoney": {"amount": 100}}'
+//! curl localhost:3030/accounts/acct-1
+//! ```
+//!
+//! After changing `BankAccountView`, rebuild the view from the event log with
+//! `cargo run --example bank-account --features web -- rebuild`.
+mod domain;
+mod queries;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::get;
+use axum::{Json, Router};
+use cqrs_es::persist::{PersistedEventStore, QueryReplay};
+use cqrs_es::{AggregateError, CqrsFramework};
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite_es::{
+    default_sqlite_pool, CqrsState, ReplayProgress, SqliteEventRepository, SqliteViewRepository,
+};
+
+use crate::domain::{BankAccount, BankAccountCommand};
+use crate::queries::{AccountQuery, BankAccountView, ACCOUNT_VIEW};
+
+const DATABASE: &str = "bank_account_example.db";
+// Load at most this many events on top of the latest snapshot.
+const SNAPSHOT_SIZE: usize = 20;
+
+type AppState = CqrsState<BankAccount, BankAccountView>;
+
+fn event_repository(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    SqliteEventRepository::new(pool).with_aggregate_version::<BankAccount>("1")
+}
+
+fn app_state(pool: Pool<SqliteConnectionManager>) -> AppState {
+    let view_repo = Arc::new(SqliteViewRepository::new(ACCOUNT_VIEW, pool.clone()));
+    let query = AccountQuery::new(view_repo.clone());
+    let store = PersistedEventStore::new_snapshot_store(event_repository(pool), SNAPSHOT_SIZE);
+    let cqrs = CqrsFramework::new(store, vec![Box::new(query)], ());
+    CqrsState::new(cqrs, view_repo)
+}
+
+async fn rebuild(pool: Pool<SqliteConnectionManager>) {
+    pool.get()
+        .unwrap()
+        .execute(&format!("DELETE FROM {ACCOUNT_VIEW}"), [])
+        .unwrap();
+    let view_repo = Arc::new(SqliteViewRepository::new(ACCOUNT_VIEW, pool.clone()));
+    let progress = ReplayProgress::new();
+    let event_repo = event_repository(pool).with_replay_progress(progress.clone());
+    let replay = QueryReplay::new(event_repo, AccountQuery::new(view_repo));
+    replay.replay_all().await.expect("view rebuild failed");
+    let report = progress.report();
+    println!(
+        "rebuilt {ACCOUNT_VIEW} from {} events ({:.0} events/s)",
+        report.processed, report.rate
+    );
+}
+
+async fn execute(
+    State(state): State<AppState>,
+    Path(account_id): Path<String>,
+    headers: HeaderMap,
+    Json(command): Json<BankAccountCommand>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    // Recorded with the events, e.g. for audit queries.
+    let mut metadata = HashMap::new();
+    if let Some(user_id) = headers.get("x-user-id").and_then(|v| v.to_str().ok()) {
+        metadata.insert("user_id".to_string(), user_id.to_string());
+    }
+    match state
+        .execute_with_metadata(&account_id, command, metadata)
+        .await
+    {
+        Ok(()) => Ok(StatusCode::NO_CONTENT),
+        Err(AggregateError::UserError(err)) => Err((StatusCode::BAD_REQUEST, err.to_string())),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+async fn query(
+    State(state): State<AppState>,
+    Path(account_id): Path<String>,
+) -> Result<Json<BankAccountView>, StatusCode> {
+    match state.load_view(&account_id).await {
+        Ok(Some(view)) => Ok(Json(view)),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let pool = default_sqlite_pool(DATABASE);
+    event_repository(pool.clone())
+        .ready()
+        .await
+        .expect("failed to create the event tables");
+    pool.get()
+        .unwrap()
+        .execute_batch(include_str!("schema.sql"))
+        .expect("failed to create the view table");
+
+    if std::env::args().nth(1).as_deref() == Some("rebuild") {
+        rebuild(pool).await;
+        return;
+    }
+
+    let app = Router::new()
+        .route("/accounts/:account_id", get(query).post(execute))
+        .with_state(app_state(pool));
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:3030").await.unwrap();
+    axum::serve(listener, app).await.unwrap();
+}
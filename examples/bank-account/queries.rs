@@ -0,0 +1,49 @@
+use cqrs_es::persist::GenericQuery;
+use cqrs_es::{EventEnvelope, View};
+use rusqlite_es::SqliteViewRepository;
+use serde::{Deserialize, Serialize};
+
+use crate::domain::{BankAccount, BankAccountEvent};
+
+/// The view table, see `schema.sql`.
+pub const ACCOUNT_VIEW: &str = "account_view";
+
+pub type AccountQuery =
+    GenericQuery<SqliteViewRepository<BankAccountView, BankAccount>, BankAccountView, BankAccount>;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BankAccountView {
+    account_id: Option<String>,
+    balance: i64,
+    ledger: Vec<LedgerEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    description: String,
+    amount: i64,
+}
+
+impl View<BankAccount> for BankAccountView {
+    fn update(&mut self, event: &EventEnvelope<BankAccount>) {
+        match &event.payload {
+            BankAccountEvent::AccountOpened { account_id } => {
+                self.account_id = Some(account_id.clone());
+            }
+            BankAccountEvent::CustomerDepositedMoney { amount, balance } => {
+                self.ledger.push(LedgerEntry {
+                    description: "deposit".to_string(),
+                    amount: *amount,
+                });
+                self.balance = *balance;
+            }
+            BankAccountEvent::CustomerWithdrewCash { amount, balance } => {
+                self.ledger.push(LedgerEntry {
+                    description: "withdrawal".to_string(),
+                    amount: -amount,
+                });
+                self.balance = *balance;
+            }
+        }
+    }
+}
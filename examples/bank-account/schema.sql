@@ -0,0 +1,12 @@
+-- The event and snapshot tables are created by `SqliteEventRepository::ready` at startup, view
+-- tables are not and are created here.
+
+-- `last_applied` records the events applied to each view, see `with_event_dedup`
+CREATE TABLE IF NOT EXISTS account_view
+(
+    view_id      text                        NOT NULL,
+    version      bigint CHECK (version >= 0) NOT NULL,
+    payload      json                        NOT NULL,
+    last_applied json                        NOT NULL DEFAULT '{}',
+    PRIMARY KEY (view_id)
+);
@@ -0,0 +1,219 @@
+#![forbid(unsafe_code)]
+#![deny(missing_docs)]
+#![deny(clippy::all)]
+//! # sqlite-es-derive
+//!
+//! > Derives `rusqlite_es::SqliteView` for views stored in a table with a column per field, see
+//! > `rusqlite_es::SqliteTableViewRepository`.
+//!
+//! Enable the `derive` feature of `rusqlite-es` rather than depending on this crate directly.
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{
+    parse_macro_input, Data, DeriveInput, Error, Fields, GenericArgument, LitStr, PathArguments,
+    Type,
+};
+
+/// Derives `rusqlite_es::SqliteView` for a struct with named fields, mapping each field to a
+/// column of the same name.
+///
+/// Integers and booleans are stored as `integer`, floats as `real` and strings as `text`
+/// columns, `Option`s of these as nullable columns. Fields of any other type are stored as
+/// `json` and must implement `Serialize` and `Deserialize`.
+///
+/// Attributes:
+/// - `#[sqlite_view(table = "...")]` on the struct names the view table, which otherwise is the
+///   struct's name in snake case
+/// - `#[sqlite_view(index)]` on a field declares an index on its column
+/// - `#[sqlite_view(json)]` on a field stores it as `json` whatever its type
+///
+/// ```ignore
+/// #[derive(Debug, Default, Serialize, Deserialize, SqliteView)]
+/// #[sqlite_view(table = "account_query")]
+/// struct AccountView {
+///     #[sqlite_view(index)]
+///     owner: String,
+///     balance: f64,
+///     ledger: Vec<LedgerEntry>,
+/// }
+/// ```
+#[proc_macro_derive(SqliteView, attributes(sqlite_view))]
+pub fn derive_sqlite_view(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+struct Column {
+    field: syn::Ident,
+    sql_type: &'static str,
+    nullable: bool,
+    indexed: bool,
+}
+
+fn expand(input: DeriveInput) -> Result<proc_macro2::TokenStream, Error> {
+    let name = &input.ident;
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new_spanned(
+            name,
+            "SqliteView can only be derived for structs",
+        ));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(Error::new_spanned(
+            name,
+            "SqliteView can only be derived for structs with named fields",
+        ));
+    };
+
+    let mut table = snake_case(&name.to_string());
+    for attr in &input.attrs {
+        if !attr.path().is_ident("sqlite_view") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("table") {
+                table = meta.value()?.parse::<LitStr>()?.value();
+                Ok(())
+            } else {
+                Err(meta.error("expected `table = \"...\"`"))
+            }
+        })?;
+    }
+
+    let mut columns = Vec::new();
+    for field in &fields.named {
+        let mut indexed = false;
+        let mut json = false;
+        for attr in &field.attrs {
+            if !attr.path().is_ident("sqlite_view") {
+                continue;
+            }
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("index") {
+                    indexed = true;
+                    Ok(())
+                } else if meta.path.is_ident("json") {
+                    json = true;
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `index` or `json`"))
+                }
+            })?;
+        }
+        let (sql_type, nullable) = match json {
+            true => ("json", false),
+            false => column_type(&field.ty),
+        };
+        columns.push(Column {
+            field: field.ident.clone().expect("named fields have an ident"),
+            sql_type,
+            nullable,
+            indexed,
+        });
+    }
+    if columns.is_empty() {
+        return Err(Error::new(
+            Span::call_site(),
+            "SqliteView requires at least one field",
+        ));
+    }
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let declarations = columns.iter().map(|column| {
+        let name = column.field.to_string();
+        let sql_type = column.sql_type;
+        let nullable = column.nullable;
+        let indexed = column.indexed;
+        quote! {
+            ::rusqlite_es::ViewColumn {
+                name: #name,
+                sql_type: #sql_type,
+                nullable: #nullable,
+                indexed: #indexed,
+            }
+        }
+    });
+    let values = columns.iter().map(|column| {
+        let field = &column.field;
+        match column.sql_type {
+            "json" => quote! { ::rusqlite_es::json_column(&self.#field)? },
+            _ => quote! { ::rusqlite_es::rusqlite::ToSql::to_sql(&self.#field)? },
+        }
+    });
+    let reads = columns.iter().enumerate().map(|(index, column)| {
+        let field = &column.field;
+        match column.sql_type {
+            "json" => quote! { #field: ::rusqlite_es::json_from_row(row, offset + #index)? },
+            _ => quote! { #field: row.get(offset + #index)? },
+        }
+    });
+
+    Ok(quote! {
+        impl #impl_generics ::rusqlite_es::SqliteView for #name #ty_generics #where_clause {
+            const TABLE: &'static str = #table;
+            const COLUMNS: &'static [::rusqlite_es::ViewColumn] = &[#(#declarations),*];
+
+            fn to_row(
+                &self,
+            ) -> ::std::result::Result<
+                ::std::vec::Vec<::rusqlite_es::rusqlite::types::ToSqlOutput<'_>>,
+                ::rusqlite_es::rusqlite::Error,
+            > {
+                ::std::result::Result::Ok(::std::vec![#(#values),*])
+            }
+
+            fn from_row(
+                row: &::rusqlite_es::rusqlite::Row<'_>,
+                offset: usize,
+            ) -> ::std::result::Result<Self, ::rusqlite_es::rusqlite::Error> {
+                ::std::result::Result::Ok(Self { #(#reads),* })
+            }
+        }
+    })
+}
+
+// Returns the SQL type of a field's column and whether it is nullable.
+fn column_type(ty: &Type) -> (&'static str, bool) {
+    let Type::Path(path) = ty else {
+        return ("json", false);
+    };
+    let Some(segment) = path.path.segments.last() else {
+        return ("json", false);
+    };
+    match segment.ident.to_string().as_str() {
+        "i8" | "i16" | "i32" | "i64" | "isize" | "u8" | "u16" | "u32" | "u64" | "usize"
+        | "bool" => ("integer", false),
+        "f32" | "f64" => ("real", false),
+        "String" => ("text", false),
+        "Option" => match &segment.arguments {
+            PathArguments::AngleBracketed(arguments) => match arguments.args.first() {
+                Some(GenericArgument::Type(inner)) => match column_type(inner) {
+                    // `None` is stored as a JSON null rather than SQL NULL
+                    ("json", _) => ("json", false),
+                    (sql_type, _) => (sql_type, true),
+                },
+                _ => ("json", false),
+            },
+            _ => ("json", false),
+        },
+        _ => ("json", false),
+    }
+}
+
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for (index, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if index > 0 {
+                snake.push('_');
+            }
+            snake.extend(c.to_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+
+use crate::error::SqliteAggregateError;
+
+tokio::task_local! {
+    static ACCESS_CLAIMS: Arc<HashMap<String, String>>;
+}
+
+/// The kind of record an `AccessRequest` is made for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    /// The events or snapshot of an aggregate instance.
+    Aggregate,
+    /// A view instance.
+    View,
+}
+
+/// A request to read a record, see `AccessPolicy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessRequest<'a> {
+    /// The kind of record requested.
+    pub kind: AccessKind,
+    /// The aggregate type, or the name of the view table.
+    pub resource: &'a str,
+    /// The id of the aggregate or view instance.
+    pub id: &'a str,
+    /// The claims of the caller, see `with_access_claims`. Empty outside of
+    /// `with_access_claims`.
+    pub claims: &'a HashMap<String, String>,
+}
+
+/// Decides whether the caller may read an aggregate or view instance, see
+/// `SqliteEventRepository::with_access_policy` and `SqliteViewRepository::with_access_policy`.
+///
+/// The policy is consulted whenever an aggregate instance is loaded, i.e. when a command is
+/// dispatched to it or it is loaded with `load_aggregates`, or its events are streamed, e.g. by
+/// `materialize_view_on_the_fly`, and whenever a view is loaded with `load` or `load_at_least`
+/// or listed with `list_views`. The caller is identified by the claims passed to
+/// `with_access_claims`. Besides implementing the trait, a `Fn(&AccessRequest) -> bool` closure
+/// can be used as a policy.
+///
+/// ```
+/// use rusqlite_es::{AccessPolicy, AccessRequest};
+///
+/// fn owner_only() -> impl AccessPolicy {
+///     |request: &AccessRequest| -> bool {
+///         request.claims.get("role").is_some_and(|role| role == "admin")
+///             || request.claims.get("user_id").is_some_and(|user| user == request.id)
+///     }
+/// }
+/// ```
+pub trait AccessPolicy: Send + Sync {
+    /// Returns true if the caller may read the requested record.
+    fn allows(&self, request: &AccessRequest<'_>) -> bool;
+}
+
+impl<F> AccessPolicy for F
+where
+    F: Fn(&AccessRequest<'_>) -> bool + Send + Sync,
+{
+    fn allows(&self, request: &AccessRequest<'_>) -> bool {
+        self(request)
+    }
+}
+
+/// Runs an operation, e.g. handling a request, on behalf of a caller with the provided claims,
+/// e.g. the user id and roles of the signed-in user, which are passed to the configured
+/// `AccessPolicy`. A record the policy refuses is not loaded, the operation receives
+/// `SqliteAggregateError::AccessDenied` instead, as the `UnknownError` of a `PersistenceError`
+/// or the `UnexpectedError` of an `AggregateError`.
+///
+/// ```
+/// # use cqrs_es::doc::MyAggregate;
+/// use std::collections::HashMap;
+///
+/// use cqrs_es::persist::PersistenceError;
+/// use rusqlite_es::{with_access_claims, SqliteEventRepository};
+///
+/// async fn load_as(
+///     repo: &SqliteEventRepository,
+///     user_id: &str,
+///     id: &str,
+/// ) -> Result<Option<MyAggregate>, PersistenceError> {
+///     let claims = HashMap::from([("user_id".to_string(), user_id.to_string())]);
+///     with_access_claims(claims, || async {
+///         Ok(repo.load_aggregates::<MyAggregate>(&[id]).await?.remove(id))
+///     })
+///     .await
+/// }
+/// ```
+pub async fn with_access_claims<F, Fut, T>(claims: HashMap<String, String>, operation: F) -> T
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = T>,
+{
+    ACCESS_CLAIMS.scope(Arc::new(claims), operation()).await
+}
+
+// Consults the policy, if any, on behalf of the caller of the current `with_access_claims`.
+pub(crate) fn check_access(
+    access_policy: &Option<Arc<dyn AccessPolicy>>,
+    kind: AccessKind,
+    resource: &str,
+    id: &str,
+) -> Result<(), SqliteAggregateError> {
+    let Some(access_policy) = access_policy else {
+        return Ok(());
+    };
+    let claims = ACCESS_CLAIMS
+        .try_with(Arc::clone)
+        .unwrap_or_else(|_| Arc::new(HashMap::new()));
+    let request = AccessRequest {
+        kind,
+        resource,
+        id,
+        claims: &claims,
+    };
+    if access_policy.allows(&request) {
+        Ok(())
+    } else {
+        Err(SqliteAggregateError::AccessDenied {
+            resource: resource.to_string(),
+            id: id.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::fs;
+
+    use cqrs_es::persist::{PersistedEventRepository, PersistenceError, ViewRepository};
+    use serde_json::json;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, TestView, TEST_CONNECTION_STRING,
+    };
+    use crate::{
+        default_sqlite_pool, with_access_claims, AccessKind, AccessRequest, ConsistencyToken,
+        JsonEncoding, SqliteAggregateError, SqliteEventRepository, SqliteViewRepository,
+    };
+
+    fn owner_only(request: &AccessRequest) -> bool {
+        request.claims.get("user_id").map(String::as_str) == Some(request.id)
+    }
+
+    fn denied<T>(result: Result<T, PersistenceError>) -> bool {
+        match result {
+            Err(PersistenceError::UnknownError(err)) => matches!(
+                err.downcast_ref::<SqliteAggregateError>(),
+                Some(SqliteAggregateError::AccessDenied { .. })
+            ),
+            _ => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn access_policy() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+        let repo =
+            SqliteEventRepository::new(pool.clone()).with_access_policy(Box::new(owner_only));
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = json!({});
+        repo.insert_events::<TestAggregate>(&[created])
+            .await
+            .unwrap();
+
+        let owner = HashMap::from([("user_id".to_string(), id.clone())]);
+        let events = with_access_claims(owner.clone(), || repo.get_events::<TestAggregate>(&id))
+            .await
+            .unwrap();
+        assert_eq!(1, events.len());
+        assert!(denied(repo.get_events::<TestAggregate>(&id).await));
+        assert!(denied(
+            with_access_claims(HashMap::new(), || repo.get_snapshot::<TestAggregate>(&id)).await
+        ));
+        assert!(denied(repo.load_aggregates::<TestAggregate>(&[&id]).await));
+        assert!(denied(repo.stream_events::<TestAggregate>(&id).await));
+        assert!(denied(
+            repo.materialize_view_on_the_fly::<TestView, TestAggregate>(&id)
+                .await
+        ));
+
+        let view_policy = || {
+            Box::new(|request: &AccessRequest| {
+                request.kind == AccessKind::View && owner_only(request)
+            })
+        };
+        let view_repo =
+            SqliteViewRepository::<TestView, TestAggregate>::new("test_view", pool.clone())
+                .with_access_policy(view_policy());
+        assert!(denied(view_repo.load(&id).await));
+        assert_eq!(
+            None,
+            with_access_claims(owner, || view_repo.load(&id))
+                .await
+                .unwrap()
+        );
+        let token: ConsistencyToken = format!("1.1.{id}").parse().unwrap();
+        assert!(matches!(
+            view_repo.load_at_least(&id, &token).await,
+            Err(SqliteAggregateError::AccessDenied { .. })
+        ));
+
+        // the policy is kept when the encoding is configured afterwards
+        let view_repo = SqliteViewRepository::<TestView, TestAggregate>::new("test_view", pool)
+            .with_access_policy(view_policy())
+            .with_json_encoding(JsonEncoding::Text);
+        assert!(denied(view_repo.load(&id).await));
+    }
+}
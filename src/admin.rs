@@ -0,0 +1,1219 @@
+//! Maintenance operations that only depend on the stored events and snapshots, not on the
+//! aggregate types, as run by the `sqlite-es-admin` binary (feature `cli`).
+//!
+//! Operations that need the aggregate or view types, e.g. replaying aggregates with
+//! `SqliteEventRepository::replay_all` or views with `QueryReplay`, are run from the
+//! application instead. Clearing snapshots or a view table here makes the application rebuild
+//! them from the events.
+//!
+//! ```
+//! use rusqlite_es::admin;
+//! use rusqlite_es::{SqliteAggregateError, SqliteEventRepository};
+//!
+//! async fn check(repo: &SqliteEventRepository) -> Result<bool, SqliteAggregateError> {
+//!     let report = admin::verify_integrity(repo).await?;
+//!     Ok(report.is_ok())
+//! }
+//! ```
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use cqrs_es::persist::SerializedEvent;
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::{Connection, OpenFlags, Row, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::connection::{in_transaction, with_checked_connection, ConnectionProvider};
+use crate::error::SqliteAggregateError;
+use crate::identifier::TableName;
+use crate::mapping::deser_event;
+use crate::{Partitioning, SqliteEventRepository};
+
+/// The outcome of `verify_integrity`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// The problems reported by SQLite's `PRAGMA integrity_check`, empty if none.
+    pub database: Vec<String>,
+    /// The aggregate instances, as `(aggregate_type, aggregate_id)`, whose event sequences do
+    /// not run from 1 without gaps.
+    pub sequence_gaps: Vec<(String, String)>,
+    /// The aggregate instances whose snapshot covers events that do not exist.
+    pub orphaned_snapshots: Vec<(String, String)>,
+}
+
+impl IntegrityReport {
+    /// Whether no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.database.is_empty()
+            && self.sequence_gaps.is_empty()
+            && self.orphaned_snapshots.is_empty()
+    }
+}
+
+/// The outcome of `diff_stores`, empty if both stores hold the same events.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoreDiff {
+    /// The number of events held by both stores, whether identical or divergent.
+    pub compared: usize,
+    /// The events only held by the first store.
+    pub only_in_a: Vec<EventKey>,
+    /// The events only held by the second store.
+    pub only_in_b: Vec<EventKey>,
+    /// The events held by both stores that differ in type, version, payload or metadata.
+    pub divergent: Vec<EventKey>,
+}
+
+impl StoreDiff {
+    /// Whether both stores hold the same events.
+    pub fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty() && self.divergent.is_empty()
+    }
+}
+
+/// Identifies an event reported by `diff_stores`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EventKey {
+    /// The type of the aggregate.
+    pub aggregate_type: String,
+    /// The id of the aggregate instance.
+    pub aggregate_id: String,
+    /// The sequence number of the event.
+    pub sequence: usize,
+}
+
+/// An aggregate instance listed by `list_aggregates`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AggregateSummary {
+    /// The type of the aggregate.
+    pub aggregate_type: String,
+    /// The id of the aggregate instance.
+    pub aggregate_id: String,
+    /// The number of events of the aggregate instance.
+    pub events: usize,
+    /// The sequence of the last event of the aggregate instance.
+    pub last_sequence: usize,
+}
+
+/// The outcome of `checkpoint`, as reported by `PRAGMA wal_checkpoint`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CheckpointResult {
+    /// Whether the checkpoint could not complete because of concurrent readers or writers.
+    pub busy: bool,
+    /// The number of frames in the write-ahead log, -1 if the database is not in WAL mode.
+    pub log_frames: i64,
+    /// The number of frames written back to the database file.
+    pub checkpointed_frames: i64,
+}
+
+/// The storage used by the database, as reported by `space_report`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SpaceReport {
+    /// The size of a database page in bytes.
+    pub page_size: u64,
+    /// The number of pages of the database file, including free pages.
+    pub page_count: u64,
+    /// The number of unused pages, which are reused by later writes and only returned to the
+    /// file system by `VACUUM`.
+    pub freelist_pages: u64,
+    /// The size of the write-ahead log file, `None` if the database has no log file, e.g. an
+    /// in-memory database.
+    pub wal_bytes: Option<u64>,
+    /// The tables and indexes, largest first.
+    pub tables: Vec<TableSpace>,
+    /// The aggregate types with events or snapshots, largest first.
+    pub aggregate_types: Vec<AggregateTypeSpace>,
+    /// The maintenance worth running, if any.
+    pub recommendations: Vec<SpaceRecommendation>,
+}
+
+impl SpaceReport {
+    /// The size of the database file in bytes.
+    pub fn database_bytes(&self) -> u64 {
+        self.page_size * self.page_count
+    }
+
+    /// The bytes taken by unused pages.
+    pub fn free_bytes(&self) -> u64 {
+        self.page_size * self.freelist_pages
+    }
+}
+
+/// The storage used by a table or index, see `SpaceReport`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableSpace {
+    /// The name of the table or index.
+    pub name: String,
+    /// The bytes of the pages holding the table or index.
+    pub bytes: u64,
+}
+
+/// The storage used by the events and snapshots of an aggregate type, see `SpaceReport`.
+///
+/// Sizes count the stored payloads and metadata, excluding the overhead of rows and indexes.
+/// Deduplicated payloads and interned metadata count as their reference only.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AggregateTypeSpace {
+    /// The type of the aggregate.
+    pub aggregate_type: String,
+    /// The number of events.
+    pub events: u64,
+    /// The bytes of the payloads and metadata of the events.
+    pub event_bytes: u64,
+    /// The number of snapshots.
+    pub snapshots: u64,
+    /// The bytes of the snapshot payloads.
+    pub snapshot_bytes: u64,
+}
+
+/// Maintenance recommended by `space_report`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpaceRecommendation {
+    /// A large part of the database file is unused, running `VACUUM` returns it to the file
+    /// system.
+    Vacuum {
+        /// The bytes that would be reclaimed.
+        reclaimable_bytes: u64,
+    },
+    /// The write-ahead log has grown large, e.g. because long-running readers prevented it from
+    /// being reset, see `checkpoint`.
+    Checkpoint {
+        /// The size of the write-ahead log file.
+        wal_bytes: u64,
+    },
+    /// An aggregate type takes up most of the events, exporting its finished instances, see
+    /// `export_events`, and deleting them would free the most space.
+    Archive {
+        /// The type of the aggregate.
+        aggregate_type: String,
+        /// The bytes of the aggregate type's events.
+        event_bytes: u64,
+    },
+    /// The snapshots of an aggregate type take up more space than its events, deleting them,
+    /// see `clear_snapshots`, or snapshotting less often costs little replay time.
+    PruneSnapshots {
+        /// The type of the aggregate.
+        aggregate_type: String,
+        /// The bytes of the aggregate type's snapshots.
+        snapshot_bytes: u64,
+    },
+}
+
+// How long a backup waits for the connections holding the locks it needs.
+const BACKUP_BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+const BACKUP_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+// Below this many bytes, maintenance is not worth recommending.
+const MIN_RECOMMENDED_BYTES: u64 = 1024 * 1024;
+
+// An event as written by `export_events`, one JSON object per line.
+#[derive(Serialize, Deserialize)]
+struct ExportedEvent {
+    aggregate_type: String,
+    aggregate_id: String,
+    sequence: usize,
+    event_type: String,
+    event_version: String,
+    payload: Value,
+    metadata: Value,
+}
+
+// A view as written by `export_views`, one JSON object per line.
+#[derive(Serialize, Deserialize)]
+struct ExportedView {
+    view_id: String,
+    version: i64,
+    payload: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_applied: Option<Value>,
+}
+
+/// Creates any missing tables and indexes, see `SqliteEventRepository::ready`.
+pub async fn init_schema<P: ConnectionProvider>(
+    repo: &SqliteEventRepository<P>,
+) -> Result<(), SqliteAggregateError> {
+    repo.clone().ready().await?;
+    Ok(())
+}
+
+/// Runs SQLite's integrity check and checks that event sequences have no gaps and that
+/// snapshots do not cover events that do not exist.
+pub async fn verify_integrity<P: ConnectionProvider>(
+    repo: &SqliteEventRepository<P>,
+) -> Result<IntegrityReport, SqliteAggregateError> {
+    let query_factory = repo.query_factory();
+    let events = query_factory.event_source();
+    let snapshots = query_factory.snapshot_table();
+    with_checked_connection(repo.pool(), |connection| {
+        let database = connection
+            .prepare("PRAGMA integrity_check")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .filter(|problem| !matches!(problem.as_deref(), Ok("ok")))
+            .collect::<Result<_, _>>()?;
+        let sequence_gaps = connection
+            .prepare(&format!(
+                "SELECT aggregate_type, aggregate_id FROM {events} GROUP BY aggregate_type, aggregate_id HAVING count(*) != max(sequence) OR min(sequence) != 1 ORDER BY aggregate_type, aggregate_id"
+            ))?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        let orphaned_snapshots = connection
+            .prepare(&format!(
+                "SELECT s.aggregate_type, s.aggregate_id FROM {snapshots} AS s WHERE s.last_sequence > (SELECT coalesce(max(e.sequence), 0) FROM {events} AS e WHERE e.aggregate_type = s.aggregate_type AND e.aggregate_id = s.aggregate_id) ORDER BY s.aggregate_type, s.aggregate_id"
+            ))?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        Ok(IntegrityReport {
+            database,
+            sequence_gaps,
+            orphaned_snapshots,
+        })
+    })
+}
+
+/// Lists the aggregate instances with events, optionally only those of one aggregate type, in
+/// the order of their type and id.
+pub async fn list_aggregates<P: ConnectionProvider>(
+    repo: &SqliteEventRepository<P>,
+    aggregate_type: Option<&str>,
+) -> Result<Vec<AggregateSummary>, SqliteAggregateError> {
+    let events = repo.query_factory().event_source();
+    with_checked_connection(repo.pool(), |connection| {
+        connection
+            .prepare(&format!(
+                "SELECT aggregate_type, aggregate_id, count(*), max(sequence) FROM {events} WHERE ?1 IS NULL OR aggregate_type = ?1 GROUP BY aggregate_type, aggregate_id ORDER BY aggregate_type, aggregate_id"
+            ))?
+            .query_map([aggregate_type], |row| {
+                Ok(AggregateSummary {
+                    aggregate_type: row.get(0)?,
+                    aggregate_id: row.get(1)?,
+                    events: row.get::<_, i64>(2)? as usize,
+                    last_sequence: row.get::<_, i64>(3)? as usize,
+                })
+            })?
+            .collect()
+    })
+}
+
+/// Writes all events in commit order to `writer` as JSON lines, returning the number of events
+/// written. The export is read back with `import_events`. Metadata is exported in its stored
+/// form, see `MetadataCodec`, payloads stored in a `BlobStore` are exported in full.
+pub async fn export_events<P, W>(
+    repo: &SqliteEventRepository<P>,
+    mut writer: W,
+) -> Result<usize, SqliteAggregateError>
+where
+    P: ConnectionProvider,
+    W: Write,
+{
+    let connection = repo.pool().connection()?;
+    let mut statement = connection.prepare(repo.query_factory().everything())?;
+    let mut rows = statement.query([])?;
+    let mut count = 0;
+    while let Some(row) = rows.next()? {
+        let mut event = deser_event(row)?;
+        if let Some(external_payloads) = repo.external_payloads() {
+            external_payloads.resolve(&mut event.payload)?;
+        }
+        let exported = ExportedEvent {
+            aggregate_type: event.aggregate_type,
+            aggregate_id: event.aggregate_id,
+            sequence: event.sequence,
+            event_type: event.event_type,
+            event_version: event.event_version,
+            payload: event.payload,
+            metadata: event.metadata,
+        };
+        serde_json::to_writer(&mut writer, &exported)?;
+        writeln!(writer).map_err(|err| SqliteAggregateError::UnknownError(Box::new(err)))?;
+        count += 1;
+    }
+    writer
+        .flush()
+        .map_err(|err| SqliteAggregateError::UnknownError(Box::new(err)))?;
+    Ok(count)
+}
+
+/// Inserts the events exported by `export_events` within a single transaction, see
+/// `import::bulk_load`, returning the number of events inserted.
+pub async fn import_events<P, R>(
+    repo: &SqliteEventRepository<P>,
+    reader: R,
+) -> Result<usize, SqliteAggregateError>
+where
+    P: ConnectionProvider,
+    R: BufRead,
+{
+    let mut events = Vec::new();
+    for line in reader.lines() {
+        let line = line.map_err(|err| SqliteAggregateError::UnknownError(Box::new(err)))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event: ExportedEvent = serde_json::from_str(&line)?;
+        events.push(SerializedEvent::new(
+            event.aggregate_id,
+            event.sequence,
+            event.aggregate_type,
+            event.event_type,
+            event.event_version,
+            event.payload,
+            event.metadata,
+        ));
+    }
+    crate::import::bulk_load(repo, &events).await
+}
+
+/// Writes all rows of a view table to `writer` as JSON lines, returning the number of views
+/// written, e.g. to back up a read model or to transplant it into another environment without
+/// replaying all events. The export is read back with `import_views`. The events applied to
+/// each view are exported along with it if the table tracks them, see
+/// `SqliteViewRepository::with_event_dedup`.
+pub async fn export_views<P, W>(
+    repo: &SqliteEventRepository<P>,
+    view_table: &str,
+    mut writer: W,
+) -> Result<usize, SqliteAggregateError>
+where
+    P: ConnectionProvider,
+    W: Write,
+{
+    let view_table = TableName::unchecked(view_table);
+    let connection = repo.pool().connection()?;
+    let with_applied = connection
+        .prepare(&format!("SELECT * FROM {view_table} LIMIT 0"))?
+        .column_names()
+        .contains(&"last_applied");
+    let last_applied = if with_applied { "last_applied" } else { "NULL" };
+    let mut statement = connection.prepare(&format!(
+        "SELECT view_id, version, json(payload), {last_applied} FROM {view_table} ORDER BY view_id"
+    ))?;
+    let mut rows = statement.query([])?;
+    let mut count = 0;
+    while let Some(row) = rows.next()? {
+        let exported = ExportedView {
+            view_id: row.get(0)?,
+            version: row.get(1)?,
+            payload: row.get(2)?,
+            last_applied: row.get(3)?,
+        };
+        serde_json::to_writer(&mut writer, &exported)?;
+        writeln!(writer).map_err(|err| SqliteAggregateError::UnknownError(Box::new(err)))?;
+        count += 1;
+    }
+    writer
+        .flush()
+        .map_err(|err| SqliteAggregateError::UnknownError(Box::new(err)))?;
+    Ok(count)
+}
+
+/// Inserts the views exported by `export_views` into a view table within a single transaction,
+/// replacing existing views with the same id, and returns the number of views inserted. The
+/// table is expected to exist, views exported along with their applied events require it to
+/// have a `last_applied` column.
+///
+/// The imported views reflect the events of the environment they were exported from, the
+/// table's progress, see `SqliteViewRepository::with_progress_tracking`, is left unchanged.
+pub async fn import_views<P, R>(
+    repo: &SqliteEventRepository<P>,
+    view_table: &str,
+    reader: R,
+) -> Result<usize, SqliteAggregateError>
+where
+    P: ConnectionProvider,
+    R: BufRead,
+{
+    let mut views = Vec::new();
+    for line in reader.lines() {
+        let line = line.map_err(|err| SqliteAggregateError::UnknownError(Box::new(err)))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        views.push(serde_json::from_str::<ExportedView>(&line)?);
+    }
+    let view_table = TableName::unchecked(view_table);
+    let mut connection = repo.pool().connection()?;
+    in_transaction(&mut connection, TransactionBehavior::Immediate, |tx| {
+        for view in &views {
+            match &view.last_applied {
+                None => tx.execute(
+                    &format!(
+                        "INSERT OR REPLACE INTO {view_table} (view_id, version, payload) VALUES (?, ?, ?)"
+                    ),
+                    (&view.view_id, view.version, &view.payload),
+                )?,
+                Some(last_applied) => tx.execute(
+                    &format!(
+                        "INSERT OR REPLACE INTO {view_table} (view_id, version, payload, last_applied) VALUES (?, ?, ?, ?)"
+                    ),
+                    (&view.view_id, view.version, &view.payload, last_applied),
+                )?,
+            };
+        }
+        Ok(views.len())
+    })
+}
+
+/// Compares the events of two stores, e.g. a replica, a restored backup or the result of a
+/// migration with the original, reporting the events missing from either store and those that
+/// differ. Events are matched by aggregate type, aggregate id and sequence and compared by a
+/// hash of their type, version, payload and metadata, payloads and metadata being compared as
+/// JSON values regardless of how each store encodes them. Global positions, commit timestamps,
+/// snapshots and views are not compared.
+///
+/// Both stores are read in the order of their primary key, one event at a time, so stores of
+/// any size can be compared. The stores must not share a pool limited to a single connection.
+pub async fn diff_stores<PA, PB>(
+    a: &SqliteEventRepository<PA>,
+    b: &SqliteEventRepository<PB>,
+) -> Result<StoreDiff, SqliteAggregateError>
+where
+    PA: ConnectionProvider,
+    PB: ConnectionProvider,
+{
+    let (connection_a, connection_b) = (a.pool().connection()?, b.pool().connection()?);
+    let mut statement_a = connection_a.prepare(&ordered_events(a))?;
+    let mut statement_b = connection_b.prepare(&ordered_events(b))?;
+    let mut rows_a = statement_a.query([])?;
+    let mut rows_b = statement_b.query([])?;
+    let mut next_a = hashed_event(a, rows_a.next()?)?;
+    let mut next_b = hashed_event(b, rows_b.next()?)?;
+    let mut diff = StoreDiff::default();
+    loop {
+        match (next_a.take(), next_b.take()) {
+            (None, None) => return Ok(diff),
+            (Some((key, hash_a)), Some((other, hash_b))) if key == other => {
+                diff.compared += 1;
+                if hash_a != hash_b {
+                    diff.divergent.push(key);
+                }
+                next_a = hashed_event(a, rows_a.next()?)?;
+                next_b = hashed_event(b, rows_b.next()?)?;
+            }
+            (Some((key, _)), Some(other)) if key < other.0 => {
+                diff.only_in_a.push(key);
+                next_a = hashed_event(a, rows_a.next()?)?;
+                next_b = Some(other);
+            }
+            (Some((key, _)), None) => {
+                diff.only_in_a.push(key);
+                next_a = hashed_event(a, rows_a.next()?)?;
+            }
+            (event_a, Some((other, _))) => {
+                diff.only_in_b.push(other);
+                next_a = event_a;
+                next_b = hashed_event(b, rows_b.next()?)?;
+            }
+        }
+    }
+}
+
+// All events in the order of the primary key.
+fn ordered_events<P: ConnectionProvider>(repo: &SqliteEventRepository<P>) -> String {
+    let query_factory = repo.query_factory();
+    format!(
+        "SELECT {} FROM {} ORDER BY aggregate_type, aggregate_id, sequence",
+        query_factory.event_columns(),
+        query_factory.event_source()
+    )
+}
+
+// Reads an event as its key and the hash of its contents.
+fn hashed_event<P: ConnectionProvider>(
+    repo: &SqliteEventRepository<P>,
+    row: Option<&Row<'_>>,
+) -> Result<Option<(EventKey, u64)>, SqliteAggregateError> {
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    // read as the repository reads it, so that stores encoding their metadata differently or
+    // renaming event types by alias compare equal
+    let event = repo.read_event(row)?;
+    let mut hasher = DefaultHasher::new();
+    event.event_type.hash(&mut hasher);
+    event.event_version.hash(&mut hasher);
+    // serialized with the keys of objects in sorted order
+    serde_json::to_string(&event.payload)?.hash(&mut hasher);
+    serde_json::to_string(&event.metadata)?.hash(&mut hasher);
+    let key = EventKey {
+        aggregate_type: event.aggregate_type,
+        aggregate_id: event.aggregate_id,
+        sequence: event.sequence,
+    };
+    Ok(Some((key, hasher.finish())))
+}
+
+/// Deletes the snapshots, optionally only those of one aggregate type, so that aggregate
+/// instances are rebuilt from their events when next loaded. Returns the number of snapshots
+/// deleted.
+pub async fn clear_snapshots<P: ConnectionProvider>(
+    repo: &SqliteEventRepository<P>,
+    aggregate_type: Option<&str>,
+) -> Result<usize, SqliteAggregateError> {
+    let snapshots = repo.query_factory().snapshot_table();
+    with_checked_connection(repo.pool(), |connection| {
+        connection.execute(
+            &format!("DELETE FROM {snapshots} WHERE ?1 IS NULL OR aggregate_type = ?1"),
+            [aggregate_type],
+        )
+    })
+}
+
+/// Deletes all rows of a view table, so that the view can be rebuilt from the events, e.g. with
+/// `QueryReplay`. Returns the number of rows deleted.
+pub async fn clear_view<P: ConnectionProvider>(
+    repo: &SqliteEventRepository<P>,
+    view_table: &str,
+) -> Result<usize, SqliteAggregateError> {
+    let view_table = TableName::unchecked(view_table);
+    with_checked_connection(repo.pool(), |connection| {
+        connection.execute(&format!("DELETE FROM {view_table}"), [])
+    })
+}
+
+/// Checkpoints the write-ahead log, truncating it once all of its frames have been written
+/// back to the database file.
+pub async fn checkpoint<P: ConnectionProvider>(
+    repo: &SqliteEventRepository<P>,
+) -> Result<CheckpointResult, SqliteAggregateError> {
+    with_checked_connection(repo.pool(), |connection| {
+        connection.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| {
+            Ok(CheckpointResult {
+                busy: row.get::<_, i64>(0)? != 0,
+                log_frames: row.get(1)?,
+                checkpointed_frames: row.get(2)?,
+            })
+        })
+    })
+}
+
+/// Writes a point-in-time copy of the database to the file `dest` using SQLite's online backup
+/// API, e.g. to offer "undo to yesterday" in a desktop application embedding the store.
+///
+/// The copy is taken in a single step while holding a read lock, so it reflects the commits
+/// completed when it started and is never torn by concurrent commits, which continue in
+/// write-ahead log mode. It is written aside and renamed to `dest` once complete, replacing any
+/// previous copy atomically. The copy is restored with `restore_database`.
+pub async fn snapshot_database<P, T>(
+    repo: &SqliteEventRepository<P>,
+    dest: T,
+) -> Result<(), SqliteAggregateError>
+where
+    P: ConnectionProvider,
+    T: AsRef<Path>,
+{
+    let repo = repo.clone();
+    let dest = dest.as_ref().to_path_buf();
+    on_blocking_thread(move || write_copy(&repo, &dest, |_| Ok(()))).await
+}
+
+/// Writes a branch of the store to the file `dest`, holding the events committed up to and
+/// including the global position `up_to_position`, e.g. to run a simulation or a replay with
+/// modified logic against it without touching the production data. Returns the number of
+/// events in the branch.
+///
+/// The branch is a copy of the database, see `snapshot_database`, without the events committed
+/// after the position. Its snapshots are deleted, as they may reflect the events left out,
+/// while view tables and any other tables are copied as they are and should be cleared and
+/// replayed on the branch. Partitioned stores, see `Partitioning`, are not supported.
+pub async fn fork_store<P, T>(
+    repo: &SqliteEventRepository<P>,
+    dest: T,
+    up_to_position: i64,
+) -> Result<usize, SqliteAggregateError>
+where
+    P: ConnectionProvider,
+    T: AsRef<Path>,
+{
+    let query_factory = repo.query_factory();
+    if query_factory.partitioning() != Partitioning::None {
+        return Err(SqliteAggregateError::UnknownError(
+            "forking a partitioned store is not supported".into(),
+        ));
+    }
+    let events = TableName::unchecked(query_factory.event_table());
+    let position = query_factory.position().to_string();
+    let snapshots = query_factory.snapshot_table().clone();
+    let repo = repo.clone();
+    let dest = dest.as_ref().to_path_buf();
+    on_blocking_thread(move || {
+        write_copy(&repo, &dest, |fork| {
+            in_transaction(fork, TransactionBehavior::Immediate, |tx| {
+                tx.execute(
+                    &format!("DELETE FROM {events} WHERE {position} > ?"),
+                    [up_to_position],
+                )?;
+                tx.execute(&format!("DELETE FROM {snapshots}"), [])?;
+                let count: i64 =
+                    tx.query_row(&format!("SELECT count(*) FROM {events}"), [], |row| {
+                        row.get(0)
+                    })?;
+                Ok(count as usize)
+            })
+        })
+    })
+    .await
+}
+
+// Copies the database aside, prepares the copy and renames it to `dest` once complete.
+fn write_copy<P, F, T>(
+    repo: &SqliteEventRepository<P>,
+    dest: &Path,
+    prepare: F,
+) -> Result<T, SqliteAggregateError>
+where
+    P: ConnectionProvider,
+    F: FnOnce(&mut Connection) -> Result<T, SqliteAggregateError>,
+{
+    let partial = dest.with_extension("partial");
+    // left behind by an interrupted copy
+    let _ = fs::remove_file(&partial);
+    let value = {
+        let connection = repo.pool().connection()?;
+        let mut copy = Connection::open(&partial)?;
+        copy_database(&connection, &mut copy)?;
+        drop(connection);
+        prepare(&mut copy)?
+    };
+    fs::rename(&partial, dest).map_err(|err| SqliteAggregateError::UnknownError(Box::new(err)))?;
+    Ok(value)
+}
+
+/// Replaces the contents of the database with the copy taken by `snapshot_database` at `src`,
+/// discarding everything committed since.
+///
+/// The copy is written in a single step while holding the database's write lock, waiting for
+/// the commits in progress to complete and holding back new ones until it is done, so that
+/// connections see either the previous contents or the restored ones, never a mix. State kept
+/// outside of the database is not rolled back and should be reset by the application, e.g.
+/// cached aggregates, views held in memory or the positions of external consumers.
+pub async fn restore_database<P, T>(
+    repo: &SqliteEventRepository<P>,
+    src: T,
+) -> Result<(), SqliteAggregateError>
+where
+    P: ConnectionProvider,
+    T: AsRef<Path>,
+{
+    let repo = repo.clone();
+    let src = src.as_ref().to_path_buf();
+    on_blocking_thread(move || {
+        let copy = Connection::open_with_flags(src, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let mut connection = repo.pool().connection()?;
+        copy_database(&copy, &mut connection)
+    })
+    .await
+}
+
+// Runs a copy of the database on a blocking thread, since waiting for the locks held by other
+// connections would otherwise block the runtime.
+async fn on_blocking_thread<T, F>(f: F) -> Result<T, SqliteAggregateError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, SqliteAggregateError> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|err| SqliteAggregateError::UnknownError(Box::new(err)))?
+}
+
+// Copies the whole database in one step, retrying while the other connections hold the locks.
+fn copy_database(from: &Connection, to: &mut Connection) -> Result<(), SqliteAggregateError> {
+    let backup = Backup::new(from, to)?;
+    let deadline = Instant::now() + BACKUP_BUSY_TIMEOUT;
+    loop {
+        match backup.step(-1)? {
+            StepResult::Done => return Ok(()),
+            StepResult::Busy | StepResult::Locked if Instant::now() < deadline => {
+                std::thread::sleep(BACKUP_RETRY_DELAY);
+            }
+            StepResult::Busy | StepResult::Locked => {
+                return Err(SqliteAggregateError::UnknownError(
+                    "the database remained locked, the backup did not complete".into(),
+                ))
+            }
+            // more pages to copy, not returned when copying all of them at once
+            _ => {}
+        }
+    }
+}
+
+/// Summarizes the storage used by the database and recommends the maintenance worth running,
+/// e.g. on a storage-constrained device. Only recommendations that free at least a megabyte are
+/// made.
+///
+/// Table sizes rely on SQLite's `dbstat` virtual table, which is available with the bundled
+/// SQLite (feature `bundled`), they are left empty if it is not. Summing the sizes reads every
+/// page of the database, so the report is not meant to be run frequently on large databases.
+pub async fn space_report<P: ConnectionProvider>(
+    repo: &SqliteEventRepository<P>,
+) -> Result<SpaceReport, SqliteAggregateError> {
+    let events = repo.query_factory().event_source();
+    let snapshots = repo.query_factory().snapshot_table();
+    let mut report = with_checked_connection(repo.pool(), |connection| {
+        let pragma =
+            |name: &str| connection.pragma_query_value(None, name, |row| row.get::<_, i64>(0));
+        let page_size = pragma("page_size")? as u64;
+        let page_count = pragma("page_count")? as u64;
+        let freelist_pages = pragma("freelist_count")? as u64;
+        let file: String = connection.query_row(
+            "SELECT file FROM pragma_database_list WHERE name = 'main'",
+            [],
+            |row| row.get(0),
+        )?;
+        let wal_bytes = (!file.is_empty())
+            .then(|| std::fs::metadata(format!("{file}-wal")).ok())
+            .flatten()
+            .map(|metadata| metadata.len());
+        let tables = match connection.prepare(
+            "SELECT name, sum(pgsize) AS bytes FROM dbstat GROUP BY name ORDER BY bytes DESC, name",
+        ) {
+            Ok(mut statement) => statement
+                .query_map([], |row| {
+                    Ok(TableSpace {
+                        name: row.get(0)?,
+                        bytes: row.get::<_, i64>(1)? as u64,
+                    })
+                })?
+                .collect::<Result<_, _>>()?,
+            // SQLite was built without the `dbstat` virtual table
+            Err(_) => Vec::new(),
+        };
+        let aggregate_types = connection
+            .prepare(&format!(
+                "SELECT aggregate_type, sum(events), sum(event_bytes), sum(snapshots), sum(snapshot_bytes) FROM (
+  SELECT aggregate_type, count(*) AS events, sum(length(payload) + length(metadata)) AS event_bytes, 0 AS snapshots, 0 AS snapshot_bytes FROM {events} GROUP BY aggregate_type
+  UNION ALL
+  SELECT aggregate_type, 0, 0, count(*), sum(length(payload)) FROM {snapshots} GROUP BY aggregate_type
+) GROUP BY aggregate_type ORDER BY sum(event_bytes) + sum(snapshot_bytes) DESC, aggregate_type"
+            ))?
+            .query_map([], |row| {
+                Ok(AggregateTypeSpace {
+                    aggregate_type: row.get(0)?,
+                    events: row.get::<_, i64>(1)? as u64,
+                    event_bytes: row.get::<_, i64>(2)? as u64,
+                    snapshots: row.get::<_, i64>(3)? as u64,
+                    snapshot_bytes: row.get::<_, i64>(4)? as u64,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(SpaceReport {
+            page_size,
+            page_count,
+            freelist_pages,
+            wal_bytes,
+            tables,
+            aggregate_types,
+            recommendations: Vec::new(),
+        })
+    })?;
+    report.recommendations = recommend(&report);
+    Ok(report)
+}
+
+fn recommend(report: &SpaceReport) -> Vec<SpaceRecommendation> {
+    let mut recommendations = Vec::new();
+    let database_bytes = report.database_bytes();
+    // a quarter of the file is unused
+    if report.free_bytes() >= MIN_RECOMMENDED_BYTES && report.free_bytes() * 4 >= database_bytes {
+        recommendations.push(SpaceRecommendation::Vacuum {
+            reclaimable_bytes: report.free_bytes(),
+        });
+    }
+    if let Some(wal_bytes) = report.wal_bytes {
+        if wal_bytes >= MIN_RECOMMENDED_BYTES && wal_bytes * 4 >= database_bytes {
+            recommendations.push(SpaceRecommendation::Checkpoint { wal_bytes });
+        }
+    }
+    let event_bytes = report
+        .aggregate_types
+        .iter()
+        .map(|space| space.event_bytes)
+        .sum::<u64>();
+    for space in &report.aggregate_types {
+        // more than half of the events
+        if space.event_bytes >= MIN_RECOMMENDED_BYTES && space.event_bytes * 2 > event_bytes {
+            recommendations.push(SpaceRecommendation::Archive {
+                aggregate_type: space.aggregate_type.clone(),
+                event_bytes: space.event_bytes,
+            });
+        }
+        if space.snapshot_bytes >= MIN_RECOMMENDED_BYTES && space.snapshot_bytes > space.event_bytes
+        {
+            recommendations.push(SpaceRecommendation::PruneSnapshots {
+                aggregate_type: space.aggregate_type.clone(),
+                snapshot_bytes: space.snapshot_bytes,
+            });
+        }
+    }
+    recommendations
+}
+
+#[cfg(test)]
+mod test {
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use crate::admin::{
+        checkpoint, clear_snapshots, clear_view, diff_stores, export_events, export_views,
+        fork_store, import_events, import_views, init_schema, list_aggregates, restore_database,
+        snapshot_database, space_report, verify_integrity, AggregateSummary, EventKey,
+        SpaceRecommendation,
+    };
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, SqliteEventRepository, TypedMetadata};
+
+    #[tokio::test]
+    async fn maintenance() {
+        let repo = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING));
+        init_schema(&repo).await.unwrap();
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = json!({});
+        let mut tested = test_event_envelope(
+            &id,
+            2,
+            TestEvent::Tested(Tested {
+                test_name: "admin".to_string(),
+            }),
+        );
+        tested.metadata = json!({});
+        let aggregate = serde_json::to_value(TestAggregate::default()).unwrap();
+        repo.persist::<TestAggregate>(&[created, tested], Some((id.clone(), aggregate, 1)))
+            .await
+            .unwrap();
+
+        assert!(verify_integrity(&repo).await.unwrap().is_ok());
+        assert_eq!(
+            vec![AggregateSummary {
+                aggregate_type: "TestAggregate".to_string(),
+                aggregate_id: id.clone(),
+                events: 2,
+                last_sequence: 2,
+            }],
+            list_aggregates(&repo, Some("TestAggregate")).await.unwrap()
+        );
+
+        let mut export = Vec::new();
+        assert_eq!(2, export_events(&repo, &mut export).await.unwrap());
+        let imported = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING));
+        init_schema(&imported).await.unwrap();
+        assert_eq!(2, import_events(&imported, &export[..]).await.unwrap());
+        assert_eq!(
+            repo.get_events::<TestAggregate>(&id).await.unwrap(),
+            imported.get_events::<TestAggregate>(&id).await.unwrap()
+        );
+
+        repo.with_connection(|conn| conn.execute("DELETE FROM events WHERE sequence = 2", []))
+            .unwrap();
+        let report = verify_integrity(&repo).await.unwrap();
+        assert_eq!(
+            vec![("TestAggregate".to_string(), id.clone())],
+            report.orphaned_snapshots
+        );
+        repo.with_connection(|conn| {
+            conn.execute("DELETE FROM events WHERE sequence = 1", [])?;
+            conn.execute(
+                "INSERT INTO events (aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata) VALUES ('TestAggregate', ?, 2, '', '', '{}', '{}')",
+                [&id],
+            )
+        })
+        .unwrap();
+        let report = verify_integrity(&repo).await.unwrap();
+        assert_eq!(
+            vec![("TestAggregate".to_string(), id.clone())],
+            report.sequence_gaps
+        );
+
+        assert_eq!(1, clear_snapshots(&repo, None).await.unwrap());
+        // an in-memory database is not in WAL mode
+        assert_eq!(-1, checkpoint(&repo).await.unwrap().log_frames);
+    }
+
+    #[tokio::test]
+    async fn space() {
+        let repo = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING));
+        init_schema(&repo).await.unwrap();
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = json!({});
+        let aggregate = json!({ "history": "x".repeat(2 * 1024 * 1024) });
+        repo.persist::<TestAggregate>(&[created], Some((id.clone(), aggregate, 1)))
+            .await
+            .unwrap();
+
+        let report = space_report(&repo).await.unwrap();
+        assert!(report.database_bytes() > 2 * 1024 * 1024);
+        assert_eq!(None, report.wal_bytes);
+        assert!(report.tables.iter().any(|table| table.name == "snapshots"));
+        let space = &report.aggregate_types[0];
+        assert_eq!(
+            ("TestAggregate", 1, 1),
+            (space.aggregate_type.as_str(), space.events, space.snapshots)
+        );
+        assert!(space.snapshot_bytes > space.event_bytes);
+        assert_eq!(
+            vec![SpaceRecommendation::PruneSnapshots {
+                aggregate_type: "TestAggregate".to_string(),
+                snapshot_bytes: space.snapshot_bytes,
+            }],
+            report.recommendations
+        );
+
+        clear_snapshots(&repo, None).await.unwrap();
+        let report = space_report(&repo).await.unwrap();
+        assert!(report.free_bytes() >= 2 * 1024 * 1024);
+        assert_eq!(
+            vec![SpaceRecommendation::Vacuum {
+                reclaimable_bytes: report.free_bytes(),
+            }],
+            report.recommendations
+        );
+    }
+
+    #[tokio::test]
+    async fn views() {
+        let repo = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING));
+        let contents = std::fs::read_to_string("db/init.sql").unwrap();
+        repo.with_connection(|conn| {
+            conn.execute_batch(&contents)?;
+            conn.execute_batch(
+                "INSERT INTO test_view (view_id, version, payload, last_applied) VALUES ('view-1', 2, '{\"events\":[]}', '{\"account-1\":2}');
+                 CREATE TABLE plain_view (view_id text PRIMARY KEY, version bigint, payload json);
+                 INSERT INTO plain_view VALUES ('view-2', 1, '{}');",
+            )
+        })
+        .unwrap();
+
+        let mut export = Vec::new();
+        assert_eq!(
+            1,
+            export_views(&repo, "test_view", &mut export).await.unwrap()
+        );
+        assert_eq!(1, clear_view(&repo, "test_view").await.unwrap());
+        assert_eq!(
+            1,
+            import_views(&repo, "test_view", &export[..]).await.unwrap()
+        );
+        let mut reimported = Vec::new();
+        export_views(&repo, "test_view", &mut reimported)
+            .await
+            .unwrap();
+        assert_eq!(export, reimported);
+        let view: serde_json::Value = serde_json::from_slice(&export).unwrap();
+        assert_eq!(json!({"account-1": 2}), view["last_applied"]);
+
+        // a table not tracking applied events is exported without them
+        let mut export = Vec::new();
+        export_views(&repo, "plain_view", &mut export)
+            .await
+            .unwrap();
+        assert_eq!(
+            "{\"view_id\":\"view-2\",\"version\":1,\"payload\":{}}\n",
+            String::from_utf8(export.clone()).unwrap()
+        );
+        assert_eq!(
+            1,
+            import_views(&repo, "plain_view", &export[..])
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshot_and_restore() {
+        let repo = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING));
+        init_schema(&repo).await.unwrap();
+        let event = |id: &str| {
+            let mut created =
+                test_event_envelope(id, 1, TestEvent::Created(Created { id: id.into() }));
+            created.metadata = json!({});
+            created
+        };
+        repo.persist::<TestAggregate>(&[event("kept")], None)
+            .await
+            .unwrap();
+        let dest = std::env::temp_dir().join(format!("{}.db", uuid::Uuid::new_v4()));
+        snapshot_database(&repo, &dest).await.unwrap();
+        assert!(dest.exists());
+        assert!(!dest.with_extension("partial").exists());
+
+        repo.persist::<TestAggregate>(&[event("undone")], None)
+            .await
+            .unwrap();
+        restore_database(&repo, &dest).await.unwrap();
+        assert_eq!(
+            1,
+            repo.get_events::<TestAggregate>("kept")
+                .await
+                .unwrap()
+                .len()
+        );
+        assert!(repo
+            .get_events::<TestAggregate>("undone")
+            .await
+            .unwrap()
+            .is_empty());
+        std::fs::remove_file(&dest).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fork() {
+        let repo = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING));
+        init_schema(&repo).await.unwrap();
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = json!({});
+        let aggregate = serde_json::to_value(TestAggregate::default()).unwrap();
+        repo.persist::<TestAggregate>(&[created], Some((id.clone(), aggregate.clone(), 1)))
+            .await
+            .unwrap();
+        let position: i64 = repo
+            .with_connection(|conn| {
+                conn.query_row("SELECT max(rowid) FROM events", [], |row| row.get(0))
+            })
+            .unwrap();
+        let mut tested = test_event_envelope(
+            &id,
+            2,
+            TestEvent::Tested(Tested {
+                test_name: "production only".to_string(),
+            }),
+        );
+        tested.metadata = json!({});
+        repo.persist::<TestAggregate>(&[tested], Some((id.clone(), aggregate, 2)))
+            .await
+            .unwrap();
+
+        let dest = std::env::temp_dir().join(format!("{}.db", uuid::Uuid::new_v4()));
+        assert_eq!(1, fork_store(&repo, &dest, position).await.unwrap());
+        let branch = SqliteEventRepository::new(default_sqlite_pool(dest.to_str().unwrap()));
+        assert_eq!(
+            1,
+            branch.get_events::<TestAggregate>(&id).await.unwrap().len()
+        );
+        assert!(branch
+            .get_snapshot::<TestAggregate>(&id)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            2,
+            repo.get_events::<TestAggregate>(&id).await.unwrap().len()
+        );
+        drop(branch);
+        std::fs::remove_file(&dest).unwrap();
+
+        // a table name requiring quoting
+        let repo = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .with_tables("fork-events", "fork-snapshots");
+        init_schema(&repo).await.unwrap();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = json!({});
+        repo.persist::<TestAggregate>(&[created], None)
+            .await
+            .unwrap();
+        let dest = std::env::temp_dir().join(format!("{}.db", uuid::Uuid::new_v4()));
+        assert_eq!(0, fork_store(&repo, &dest, 0).await.unwrap());
+        std::fs::remove_file(&dest).unwrap();
+    }
+
+    #[tokio::test]
+    async fn diff() {
+        let event = |id: &str, sequence: usize, test_name: &str| {
+            let mut event = match sequence {
+                1 => test_event_envelope(id, 1, TestEvent::Created(Created { id: id.into() })),
+                _ => test_event_envelope(
+                    id,
+                    sequence,
+                    TestEvent::Tested(Tested {
+                        test_name: test_name.to_string(),
+                    }),
+                ),
+            };
+            event.metadata = json!({"user_id": "alice", "request_id": "r-1"});
+            event
+        };
+        let a = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING));
+        let b = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING));
+        init_schema(&a).await.unwrap();
+        init_schema(&b).await.unwrap();
+        a.persist::<TestAggregate>(&[event("x", 1, ""), event("x", 2, "same")], None)
+            .await
+            .unwrap();
+        a.persist::<TestAggregate>(&[event("y", 1, ""), event("y", 2, "original")], None)
+            .await
+            .unwrap();
+        b.persist::<TestAggregate>(&[event("x", 1, "")], None)
+            .await
+            .unwrap();
+        b.persist::<TestAggregate>(&[event("y", 1, ""), event("y", 2, "migrated")], None)
+            .await
+            .unwrap();
+        b.persist::<TestAggregate>(&[event("z", 1, "")], None)
+            .await
+            .unwrap();
+        let key = |id: &str, sequence| EventKey {
+            aggregate_type: "TestAggregate".to_string(),
+            aggregate_id: id.to_string(),
+            sequence,
+        };
+        let diff = diff_stores(&a, &b).await.unwrap();
+        assert_eq!(3, diff.compared);
+        assert_eq!(vec![key("x", 2)], diff.only_in_a);
+        assert_eq!(vec![key("z", 1)], diff.only_in_b);
+        assert_eq!(vec![key("y", 2)], diff.divergent);
+        assert!(!diff.is_empty());
+    }
+
+    #[tokio::test]
+    async fn diff_with_metadata_codecs() {
+        #[derive(Serialize, Deserialize)]
+        struct RequestMetadata {
+            user_id: String,
+            attempt: u32,
+        }
+
+        let a = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING));
+        let b = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .with_metadata_codec(Box::new(TypedMetadata::<RequestMetadata>::new()));
+        init_schema(&a).await.unwrap();
+        init_schema(&b).await.unwrap();
+        let metadata = TypedMetadata::to_map(&RequestMetadata {
+            user_id: "alice".to_string(),
+            attempt: 2,
+        })
+        .unwrap();
+        let mut created =
+            test_event_envelope("x", 1, TestEvent::Created(Created { id: "x".into() }));
+        created.metadata = serde_json::to_value(metadata).unwrap();
+        for repo in [&a, &b] {
+            repo.persist::<TestAggregate>(&[created.clone()], None)
+                .await
+                .unwrap();
+        }
+
+        // the stores hold the metadata in different forms, which decode to the same metadata
+        let stored = |repo: &SqliteEventRepository| -> serde_json::Value {
+            repo.with_connection(|conn| {
+                conn.query_row("SELECT metadata FROM events", [], |row| row.get(0))
+            })
+            .unwrap()
+        };
+        assert_ne!(stored(&a), stored(&b));
+        let diff = diff_stores(&a, &b).await.unwrap();
+        assert_eq!(1, diff.compared);
+        assert!(diff.is_empty());
+    }
+}
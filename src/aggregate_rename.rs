@@ -0,0 +1,334 @@
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::replay_progress::ReplayRun;
+use crate::{HashChain, Partitioning, SqliteEventRepository};
+
+/// Options for `SqliteEventRepository::rename_aggregate_type`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AggregateRename {
+    batch_size: usize,
+    dry_run: bool,
+}
+
+impl AggregateRename {
+    /// Creates options renaming 100 aggregate instances per transaction.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sets the number of aggregate instances renamed within each transaction.
+    pub fn with_batch_size(self, batch_size: usize) -> Self {
+        Self {
+            batch_size: batch_size.max(1),
+            ..self
+        }
+    }
+
+    /// Only counts the events and snapshots that would be renamed, without changing them.
+    pub fn with_dry_run(self, dry_run: bool) -> Self {
+        Self { dry_run, ..self }
+    }
+}
+
+impl Default for AggregateRename {
+    fn default() -> Self {
+        Self {
+            batch_size: 100,
+            dry_run: false,
+        }
+    }
+}
+
+/// The outcome of `SqliteEventRepository::rename_aggregate_type`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenameReport {
+    /// The number of events renamed, or that would be renamed by a dry run.
+    pub events: usize,
+    /// The number of snapshots renamed, or that would be renamed by a dry run.
+    pub snapshots: usize,
+    /// The number of aggregate instances whose id is already used under the new aggregate type.
+    /// A rename is refused while there are conflicts.
+    pub conflicts: usize,
+}
+
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    /// Moves the events and snapshots stored under the aggregate type `old` to the aggregate
+    /// type `new`, e.g. after renaming an aggregate.
+    ///
+    /// Aggregate instances are renamed in batches, see `AggregateRename::with_batch_size`, each
+    /// within its own transaction, so commits may continue alongside the rename. Commits to the
+    /// old aggregate type made during the rename are picked up by a later batch. A repository
+    /// configured with a `ReplayProgress` reports each renamed event and snapshot and stops
+    /// between batches once cancelled, with `SqliteAggregateError::ReplayCancelled`; running the
+    /// rename again completes it.
+    ///
+    /// A dry run, see `AggregateRename::with_dry_run`, only reports what would be renamed. The
+    /// rename is refused if any aggregate id is already used under the new aggregate type, and in
+    /// a store configured with a `HashChain`, as the aggregate type is covered by event hashes.
+    ///
+    /// ```
+    /// use rusqlite_es::{AggregateRename, SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// async fn rename(repo: &SqliteEventRepository) -> Result<(), SqliteAggregateError> {
+    ///     let options = AggregateRename::new().with_batch_size(500);
+    ///     let report = repo
+    ///         .rename_aggregate_type("Account", "BankAccount", options.with_dry_run(true))
+    ///         .await?;
+    ///     println!("renaming {} events", report.events);
+    ///     repo.rename_aggregate_type("Account", "BankAccount", options)
+    ///         .await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn rename_aggregate_type(
+        &self,
+        old: &str,
+        new: &str,
+        options: AggregateRename,
+    ) -> Result<RenameReport, SqliteAggregateError> {
+        let query_factory = self.query_factory();
+        if query_factory.partitioning() != Partitioning::None {
+            return Err(SqliteAggregateError::UnknownError(
+                "aggregate types cannot be renamed in a partitioned store".into(),
+            ));
+        }
+        // the aggregate type is covered by the hash of every event
+        if query_factory.hash_chain() != HashChain::None {
+            return Err(SqliteAggregateError::UnknownError(
+                "aggregate types cannot be renamed in a store with a hash chain".into(),
+            ));
+        }
+        if old == new {
+            return Err(SqliteAggregateError::UnknownError(
+                format!("aggregate type {old} cannot be renamed to itself").into(),
+            ));
+        }
+        let report = {
+            let connection = self.pool().connection()?;
+            let count = |sql: &str, params: &[&str]| -> Result<usize, rusqlite::Error> {
+                connection
+                    .prepare_cached(sql)?
+                    .query_row(rusqlite::params_from_iter(params), |row| {
+                        row.get::<_, i64>(0)
+                    })
+                    .map(|count| count as usize)
+            };
+            RenameReport {
+                events: count(query_factory.count_all_events(), &[old])?,
+                snapshots: count(query_factory.count_snapshots(), &[old])?,
+                conflicts: count(query_factory.count_rename_conflicts(), &[old, new])?,
+            }
+        };
+        if options.dry_run {
+            return Ok(report);
+        }
+        if report.conflicts > 0 {
+            return Err(SqliteAggregateError::UnknownError(
+                format!(
+                    "{} aggregate ids of {old} are already used by {new}",
+                    report.conflicts
+                )
+                .into(),
+            ));
+        }
+        let run = self
+            .replay_progress()
+            .as_ref()
+            .map(|progress| progress.start(report.events + report.snapshots));
+        let run = run.as_ref();
+        let events = self
+            .rename_batches(query_factory.rename_events(), old, new, options, run)
+            .await?;
+        let snapshots = self
+            .rename_batches(query_factory.rename_snapshots(), old, new, options, run)
+            .await?;
+        Ok(RenameReport {
+            events,
+            snapshots,
+            conflicts: 0,
+        })
+    }
+
+    // Runs a rename statement until no rows are left to rename, returning the number of rows
+    // renamed.
+    async fn rename_batches(
+        &self,
+        sql: &str,
+        old: &str,
+        new: &str,
+        options: AggregateRename,
+        run: Option<&ReplayRun>,
+    ) -> Result<usize, SqliteAggregateError> {
+        let mut renamed = 0;
+        loop {
+            if run.is_some_and(ReplayRun::is_cancelled) {
+                return Err(SqliteAggregateError::ReplayCancelled);
+            }
+            let aggregate_ids = self
+                .write_async(|tx| {
+                    let mut statement = tx.prepare_cached(sql)?;
+                    let aggregate_ids = statement
+                        .query_map((old, new, options.batch_size as i64), |row| {
+                            row.get::<_, String>(0)
+                        })?
+                        .collect::<Result<Vec<_>, _>>()?;
+                    Ok(aggregate_ids)
+                })
+                .await?;
+            if aggregate_ids.is_empty() {
+                return Ok(renamed);
+            }
+            if let Some(run) = run {
+                for aggregate_id in &aggregate_ids {
+                    run.record(aggregate_id);
+                }
+            }
+            renamed += aggregate_ids.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde_json::json;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{
+        default_sqlite_pool, AggregateRename, HashChain, RenameReport, ReplayProgress,
+        SqliteEventRepository,
+    };
+
+    #[tokio::test]
+    async fn rename_aggregate_type() {
+        let progress = ReplayProgress::new();
+        let repo = SqliteEventRepository::connect(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .await
+            .unwrap()
+            .into_inner()
+            .with_replay_progress(progress.clone());
+        let old = format!("Renamed{}", uuid::Uuid::new_v4().simple());
+        let new = format!("{old}V2");
+        let ids = (0..3)
+            .map(|_| uuid::Uuid::new_v4().to_string())
+            .collect::<Vec<_>>();
+        for id in &ids {
+            let mut created =
+                test_event_envelope(id, 1, TestEvent::Created(Created { id: id.clone() }));
+            let mut tested = test_event_envelope(
+                id,
+                2,
+                TestEvent::Tested(Tested {
+                    test_name: "renamed".to_string(),
+                }),
+            );
+            created.metadata = json!({});
+            tested.metadata = json!({});
+            repo.insert_events::<TestAggregate>(&[created, tested])
+                .await
+                .unwrap();
+            retype(&repo, id, &old);
+        }
+        repo.with_connection(|conn| {
+            conn.execute(
+                "INSERT INTO snapshots (aggregate_type, aggregate_id, last_sequence, current_snapshot, payload) VALUES (?, ?, 2, 1, '{}')",
+                (&old, &ids[0]),
+            )
+        })
+        .unwrap();
+
+        let expected = RenameReport {
+            events: 6,
+            snapshots: 1,
+            conflicts: 0,
+        };
+        let dry_run = AggregateRename::new().with_dry_run(true);
+        assert_eq!(
+            expected,
+            repo.rename_aggregate_type(&old, &new, dry_run)
+                .await
+                .unwrap()
+        );
+        let count_events = |aggregate_type: &str| -> i64 {
+            repo.with_connection(|conn| {
+                conn.query_row(
+                    "SELECT count(*) FROM events WHERE aggregate_type = ?",
+                    [aggregate_type],
+                    |row| row.get(0),
+                )
+            })
+            .unwrap()
+        };
+        assert_eq!(6, count_events(&old));
+
+        let options = AggregateRename::new().with_batch_size(1);
+        assert_eq!(
+            expected,
+            repo.rename_aggregate_type(&old, &new, options)
+                .await
+                .unwrap()
+        );
+        assert_eq!(0, count_events(&old));
+        assert_eq!(6, count_events(&new));
+        let report = progress.report();
+        assert_eq!(Some(7), report.total);
+        assert_eq!(7, report.processed);
+
+        // renaming back conflicts once an aggregate id is used by both types
+        let mut conflicting = test_event_envelope(
+            &ids[1],
+            1,
+            TestEvent::Created(Created { id: ids[1].clone() }),
+        );
+        conflicting.metadata = json!({});
+        repo.insert_events::<TestAggregate>(&[conflicting])
+            .await
+            .unwrap();
+        retype(&repo, &ids[1], &old);
+        let report = repo
+            .rename_aggregate_type(&new, &old, dry_run)
+            .await
+            .unwrap();
+        assert_eq!(1, report.conflicts);
+        assert!(repo
+            .rename_aggregate_type(&new, &old, AggregateRename::new())
+            .await
+            .is_err());
+        assert_eq!(6, count_events(&new));
+    }
+
+    #[tokio::test]
+    async fn rename_refused_with_hash_chain() {
+        let repo = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .with_hash_chain(HashChain::Global)
+            .ready()
+            .await
+            .unwrap()
+            .into_inner();
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = json!({});
+        repo.persist::<TestAggregate>(&[created], None)
+            .await
+            .unwrap();
+        assert!(repo
+            .rename_aggregate_type("TestAggregate", "Renamed", AggregateRename::new())
+            .await
+            .is_err());
+        assert!(repo.verify_hash_chain().await.unwrap().is_ok());
+    }
+
+    // Moves the events of a test aggregate instance to another aggregate type.
+    fn retype(repo: &SqliteEventRepository, aggregate_id: &str, aggregate_type: &str) {
+        repo.with_connection(|conn| {
+            conn.execute(
+                "UPDATE events SET aggregate_type = ? WHERE aggregate_type = 'TestAggregate' AND aggregate_id = ?",
+                (aggregate_type, aggregate_id),
+            )
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,505 @@
+use std::collections::HashMap;
+
+use cqrs_es::persist::{PersistedEventRepository, PersistenceError, SerializedEvent};
+use cqrs_es::Aggregate;
+use rusqlite::OptionalExtension;
+use serde_json::Value;
+
+use crate::access_policy::check_access;
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::mapping::versioned_snapshot;
+use crate::payload_limits::PayloadKind;
+use crate::replay_progress::ReplayRun;
+use crate::snapshot_diff::replay;
+use crate::{AccessKind, SqliteEventRepository};
+
+// The number of aggregate instances loaded per query by `load_aggregates`.
+const LOAD_BATCH_SIZE: usize = 500;
+
+/// Configures `SqliteEventRepository::replay_all`.
+///
+/// ```
+/// use rusqlite_es::AggregateReplay;
+///
+/// let replay = AggregateReplay::new()
+///     .with_batch_size(500)
+///     .with_snapshot_refresh(true);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AggregateReplay {
+    batch_size: usize,
+    refresh_snapshots: bool,
+}
+
+impl Default for AggregateReplay {
+    fn default() -> Self {
+        Self {
+            batch_size: 100,
+            refresh_snapshots: false,
+        }
+    }
+}
+
+impl AggregateReplay {
+    /// Creates a replay of batches of 100 aggregate instances that leaves snapshots untouched.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Replays `batch_size` aggregate instances at a time, refreshing their snapshots in a
+    /// single transaction per batch.
+    pub fn with_batch_size(self, batch_size: usize) -> Self {
+        Self {
+            batch_size: batch_size.max(1),
+            ..self
+        }
+    }
+
+    /// Replaces the snapshot of each replayed aggregate instance with its rebuilt state.
+    pub fn with_snapshot_refresh(self, refresh_snapshots: bool) -> Self {
+        Self {
+            refresh_snapshots,
+            ..self
+        }
+    }
+}
+
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    /// Rebuilds an aggregate instance purely from its events, ignoring any snapshot, e.g. to
+    /// check the outcome of a change to `Aggregate::apply`. An aggregate instance without events
+    /// is returned in its default state.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// use cqrs_es::persist::PersistenceError;
+    /// use rusqlite_es::SqliteEventRepository;
+    ///
+    /// async fn rebuild(repo: &SqliteEventRepository, id: &str) -> Result<MyAggregate, PersistenceError> {
+    ///     repo.replay_aggregate::<MyAggregate>(id).await
+    /// }
+    /// ```
+    pub async fn replay_aggregate<A: Aggregate>(
+        &self,
+        aggregate_id: &str,
+    ) -> Result<A, PersistenceError> {
+        let events = self.get_events::<A>(aggregate_id).await?;
+        Ok(replay(A::default(), events)?)
+    }
+
+    /// Rebuilds every aggregate instance of type `A` purely from its events, ignoring
+    /// snapshots, and passes each to `sink` along with its id. Returns the number of aggregate
+    /// instances replayed.
+    ///
+    /// Aggregate instances are replayed in batches in the order of their ids. If configured, the
+    /// snapshots of each batch are replaced with the rebuilt states within a single transaction
+    /// once the whole batch has been passed to `sink`, so an interrupted replay leaves every
+    /// snapshot either refreshed or untouched. Replays report to and can be cancelled through
+    /// the repository's `ReplayProgress`. A cancelled replay ends with
+    /// `SqliteAggregateError::ReplayCancelled`, leaving the snapshots of the batch being
+    /// replayed untouched.
+    ///
+    /// The replay is intended to be run as a maintenance operation, e.g. after changing
+    /// `Aggregate::apply`, while no commands are executed for the aggregate type. Otherwise a
+    /// refreshed snapshot may replace one that already covers later events.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// use rusqlite_es::{AggregateReplay, SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// async fn refresh_snapshots(repo: &SqliteEventRepository) -> Result<usize, SqliteAggregateError> {
+    ///     let replay = AggregateReplay::new().with_snapshot_refresh(true);
+    ///     repo.replay_all::<MyAggregate, _>(replay, |id, aggregate| {
+    ///         println!("{id}: {aggregate:?}");
+    ///     })
+    ///     .await
+    /// }
+    /// ```
+    pub async fn replay_all<A, F>(
+        &self,
+        options: AggregateReplay,
+        mut sink: F,
+    ) -> Result<usize, SqliteAggregateError>
+    where
+        A: Aggregate,
+        F: FnMut(&str, A),
+    {
+        let aggregate_type = A::aggregate_type();
+        let run = match self.replay_progress() {
+            Some(progress) => {
+                let total: i64 = self.pool().connection()?.query_row(
+                    self.query_factory().count_all_events(),
+                    [&aggregate_type],
+                    |row| row.get(0),
+                )?;
+                Some(progress.start(total as usize))
+            }
+            None => None,
+        };
+        let mut replayed = 0;
+        let mut last_id = String::new();
+        loop {
+            let ids = self.aggregate_ids(&aggregate_type, &last_id, options.batch_size)?;
+            let Some(last) = ids.last() else {
+                return Ok(replayed);
+            };
+            last_id = last.clone();
+            let mut snapshots = Vec::with_capacity(ids.len());
+            for aggregate_id in ids {
+                if run.as_ref().is_some_and(ReplayRun::is_cancelled) {
+                    return Err(SqliteAggregateError::ReplayCancelled);
+                }
+                let events = self.aggregate_events(&aggregate_type, &aggregate_id)?;
+                let last_sequence = events.last().map_or(0, |event| event.sequence);
+                if let Some(run) = &run {
+                    events.iter().for_each(|_| run.record(&aggregate_id));
+                }
+                let aggregate = replay(A::default(), events)?;
+                if options.refresh_snapshots {
+                    snapshots.push((
+                        aggregate_id.clone(),
+                        last_sequence,
+                        serde_json::to_value(&aggregate)?,
+                    ));
+                }
+                sink(&aggregate_id, aggregate);
+                replayed += 1;
+            }
+            if !snapshots.is_empty() {
+                self.refresh_snapshots::<A>(&snapshots).await?;
+            }
+        }
+    }
+
+    /// Loads many aggregate instances of type `A` at once, each from its snapshot, if usable,
+    /// followed by the events committed since. Snapshots and events are read with two queries
+    /// per batch of 500 ids rather than a round trip per aggregate instance, e.g. for batch jobs
+    /// touching thousands of aggregate instances.
+    ///
+    /// Aggregate instances with neither a snapshot nor events are not included in the result.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// use std::collections::HashMap;
+    ///
+    /// use cqrs_es::persist::PersistenceError;
+    /// use rusqlite_es::SqliteEventRepository;
+    ///
+    /// async fn load(repo: &SqliteEventRepository, ids: &[&str]) -> Result<HashMap<String, MyAggregate>, PersistenceError> {
+    ///     repo.load_aggregates::<MyAggregate>(ids).await
+    /// }
+    /// ```
+    pub async fn load_aggregates<A: Aggregate>(
+        &self,
+        aggregate_ids: &[&str],
+    ) -> Result<HashMap<String, A>, PersistenceError> {
+        let aggregate_type = A::aggregate_type();
+        for id in aggregate_ids {
+            check_access(
+                self.access_policy(),
+                AccessKind::Aggregate,
+                &aggregate_type,
+                id,
+            )?;
+        }
+        Ok(self.load_aggregates_unchecked::<A>(aggregate_ids)?)
+    }
+
+    // Loads many aggregate instances without consulting the access policy, for loads made by
+    // the repository itself rather than on behalf of a caller.
+    pub(crate) fn load_aggregates_unchecked<A: Aggregate>(
+        &self,
+        aggregate_ids: &[&str],
+    ) -> Result<HashMap<String, A>, SqliteAggregateError> {
+        let mut ids = aggregate_ids.to_vec();
+        ids.sort_unstable();
+        ids.dedup();
+        let mut loaded = HashMap::with_capacity(ids.len());
+        for batch in ids.chunks(LOAD_BATCH_SIZE) {
+            loaded.extend(self.load_batch::<A>(batch)?);
+        }
+        Ok(loaded)
+    }
+
+    fn load_batch<A: Aggregate>(
+        &self,
+        aggregate_ids: &[&str],
+    ) -> Result<HashMap<String, A>, SqliteAggregateError> {
+        let aggregate_type = A::aggregate_type();
+        let connection = self.pool().connection()?;
+
+        let mut params = vec![aggregate_type.as_str()];
+        params.extend_from_slice(aggregate_ids);
+        let mut statement = connection
+            .prepare_cached(&self.query_factory().select_snapshots(aggregate_ids.len()))?;
+        let snapshots = statement
+            .query_map(rusqlite::params_from_iter(params), versioned_snapshot)?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut hydrated: HashMap<String, (A, usize)> = HashMap::new();
+        for (snapshot, aggregate_version) in snapshots {
+            // an unusable snapshot is ignored, as by `get_snapshot`
+            let Some(snapshot) = self.upcast_snapshot::<A>(snapshot, aggregate_version) else {
+                continue;
+            };
+            if let Ok(aggregate) = serde_json::from_value::<A>(snapshot.aggregate) {
+                hydrated.insert(
+                    snapshot.aggregate_id,
+                    (aggregate, snapshot.current_sequence),
+                );
+            }
+        }
+
+        let mut params = Vec::with_capacity(aggregate_ids.len() * 2 + 1);
+        for aggregate_id in aggregate_ids {
+            let after = hydrated
+                .get(*aggregate_id)
+                .map_or(0, |(_, sequence)| *sequence);
+            params.push(rusqlite::types::Value::Text(aggregate_id.to_string()));
+            params.push(rusqlite::types::Value::Integer(after as i64));
+        }
+        params.push(rusqlite::types::Value::Text(aggregate_type));
+        let mut statement =
+            connection.prepare_cached(&self.query_factory().tail_events(aggregate_ids.len()))?;
+        let mut rows = statement.query(rusqlite::params_from_iter(params))?;
+        let mut tails: HashMap<String, Vec<SerializedEvent>> = HashMap::new();
+        while let Some(row) = rows.next()? {
+            let event = self.read_event(row)?;
+            tails
+                .entry(event.aggregate_id.clone())
+                .or_default()
+                .push(event);
+        }
+
+        let mut loaded = HashMap::with_capacity(aggregate_ids.len());
+        for (aggregate_id, events) in tails {
+            let aggregate = hydrated
+                .remove(&aggregate_id)
+                .map_or_else(A::default, |(aggregate, _)| aggregate);
+            loaded.insert(aggregate_id, replay(aggregate, events)?);
+        }
+        loaded.extend(
+            hydrated
+                .into_iter()
+                .map(|(aggregate_id, (aggregate, _))| (aggregate_id, aggregate)),
+        );
+        Ok(loaded)
+    }
+
+    pub(crate) fn aggregate_events(
+        &self,
+        aggregate_type: &str,
+        aggregate_id: &str,
+    ) -> Result<Vec<SerializedEvent>, SqliteAggregateError> {
+        let connection = self.pool().connection()?;
+        let mut statement = connection.prepare_cached(self.query_factory().select_events())?;
+        let mut rows = statement.query((aggregate_type, aggregate_id))?;
+        let mut events = Vec::new();
+        while let Some(row) = rows.next()? {
+            events.push(self.read_event(row)?);
+        }
+        Ok(events)
+    }
+
+    pub(crate) fn aggregate_ids(
+        &self,
+        aggregate_type: &str,
+        after: &str,
+        limit: usize,
+    ) -> Result<Vec<String>, SqliteAggregateError> {
+        let connection = self.pool().connection()?;
+        let mut statement = connection.prepare_cached(self.query_factory().aggregate_ids())?;
+        let ids = statement
+            .query_map((aggregate_type, after, limit as i64), |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(ids)
+    }
+
+    // Replaces the snapshots, keeping the count of snapshots taken of each aggregate instance.
+    async fn refresh_snapshots<A: Aggregate>(
+        &self,
+        snapshots: &[(String, usize, Value)],
+    ) -> Result<(), SqliteAggregateError> {
+        let aggregate_type = A::aggregate_type();
+        let query_factory = self.query_factory();
+        self.write_async(|tx| {
+            for (aggregate_id, last_sequence, aggregate) in snapshots {
+                self.payload_limits()
+                    .check(PayloadKind::Snapshot, aggregate_id, aggregate)?;
+                let current_snapshot: i64 = tx
+                    .prepare_cached(query_factory.select_snapshot())?
+                    .query_row((&aggregate_type, aggregate_id), |row| {
+                        row.get("current_snapshot")
+                    })
+                    .optional()?
+                    .unwrap_or(1);
+                tx.prepare_cached(query_factory.delete_snapshot())?
+                    .execute((&aggregate_type, aggregate_id))?;
+                tx.prepare_cached(query_factory.insert_snapshot())?
+                    .execute((
+                        &aggregate_type,
+                        aggregate_id,
+                        *last_sequence as i64,
+                        current_snapshot,
+                        self.aggregate_version::<A>(),
+                        aggregate,
+                    ))?;
+            }
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde_json::json;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, AggregateReplay, ReplayProgress, SqliteEventRepository};
+
+    #[tokio::test]
+    async fn replay_all() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let progress = ReplayProgress::new();
+        let repo = SqliteEventRepository::new(pool).with_replay_progress(progress.clone());
+        let mut ids = vec![
+            uuid::Uuid::new_v4().to_string(),
+            uuid::Uuid::new_v4().to_string(),
+        ];
+        ids.sort();
+        for id in &ids {
+            let mut created =
+                test_event_envelope(id, 1, TestEvent::Created(Created { id: id.clone() }));
+            created.metadata = json!({});
+            let mut tested = test_event_envelope(
+                id,
+                2,
+                TestEvent::Tested(Tested {
+                    test_name: "replayed".to_string(),
+                }),
+            );
+            tested.metadata = json!({});
+            repo.insert_events::<TestAggregate>(&[created, tested])
+                .await
+                .unwrap();
+        }
+        // `TestAggregate::apply` ignores events, so a snapshot holding any state is stale
+        let stale = json!({"id": "stale", "description": "", "tests": []});
+        repo.with_connection(|conn| {
+            conn.execute(
+                "INSERT INTO snapshots (aggregate_type, aggregate_id, last_sequence, current_snapshot, payload) VALUES ('TestAggregate', ?, 1, 3, ?)",
+                (&ids[0], &stale),
+            )
+        })
+        .unwrap();
+
+        assert_eq!(
+            TestAggregate::default(),
+            repo.replay_aggregate::<TestAggregate>(&ids[0])
+                .await
+                .unwrap()
+        );
+
+        let mut replayed = Vec::new();
+        let count = repo
+            .replay_all::<TestAggregate, _>(AggregateReplay::new(), |id, aggregate| {
+                replayed.push((id.to_string(), aggregate))
+            })
+            .await
+            .unwrap();
+        assert_eq!(2, count);
+        assert_eq!(ids[0], replayed[0].0);
+        assert_eq!(ids[1], replayed[1].0);
+        assert_eq!(TestAggregate::default(), replayed[0].1);
+        assert_eq!(4, progress.report().processed);
+        let snapshot = repo.get_snapshot::<TestAggregate>(&ids[0]).await.unwrap();
+        assert_eq!(stale, snapshot.unwrap().aggregate);
+
+        let replay = AggregateReplay::new()
+            .with_batch_size(1)
+            .with_snapshot_refresh(true);
+        let count = repo
+            .replay_all::<TestAggregate, _>(replay, |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(2, count);
+        let default = serde_json::to_value(TestAggregate::default()).unwrap();
+        let refreshed = repo
+            .get_snapshot::<TestAggregate>(&ids[0])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(default, refreshed.aggregate);
+        assert_eq!(2, refreshed.current_sequence);
+        assert_eq!(3, refreshed.current_snapshot);
+        let created = repo
+            .get_snapshot::<TestAggregate>(&ids[1])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(default, created.aggregate);
+        assert_eq!(1, created.current_snapshot);
+    }
+
+    #[tokio::test]
+    async fn load_aggregates() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let repo = SqliteEventRepository::new(pool);
+        let ids = [
+            uuid::Uuid::new_v4().to_string(),
+            uuid::Uuid::new_v4().to_string(),
+        ];
+        for id in &ids {
+            let mut created =
+                test_event_envelope(id, 1, TestEvent::Created(Created { id: id.clone() }));
+            created.metadata = json!({});
+            let mut tested = test_event_envelope(
+                id,
+                2,
+                TestEvent::Tested(Tested {
+                    test_name: "loaded".to_string(),
+                }),
+            );
+            tested.metadata = json!({});
+            repo.insert_events::<TestAggregate>(&[created, tested])
+                .await
+                .unwrap();
+        }
+        // the events covered by the snapshot are not read, so an unreadable one goes unnoticed
+        let snapshot = json!({"id": "snapshot", "description": "", "tests": []});
+        repo.with_connection(|conn| {
+            conn.execute(
+                "INSERT INTO snapshots (aggregate_type, aggregate_id, last_sequence, current_snapshot, payload) VALUES ('TestAggregate', ?, 2, 1, ?)",
+                (&ids[0], &snapshot),
+            )?;
+            conn.execute(
+                "UPDATE events SET payload = '{\"Unknown\": {}}' WHERE aggregate_id = ? AND sequence = 1",
+                [&ids[0]],
+            )
+        })
+        .unwrap();
+
+        let loaded = repo
+            .load_aggregates::<TestAggregate>(&[&ids[0], &ids[1], &ids[1], "missing"])
+            .await
+            .unwrap();
+        assert_eq!(2, loaded.len());
+        assert_eq!("snapshot", loaded[&ids[0]].id);
+        assert_eq!(TestAggregate::default(), loaded[&ids[1]]);
+    }
+}
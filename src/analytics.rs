@@ -0,0 +1,297 @@
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use arrow_array::{ArrayRef, Int64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::arrow::ArrowWriter;
+use rusqlite::types::Value;
+use rusqlite::Row;
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::SqliteEventRepository;
+
+const PARQUET_BATCH_SIZE: usize = 8192;
+const COLUMNS: [&str; 6] = [
+    "aggregate_type",
+    "aggregate_id",
+    "sequence",
+    "event_type",
+    "event_version",
+    "timestamp",
+];
+
+/// The file format written by `SqliteEventRepository::export_analytics`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Comma-separated values with a header row.
+    Csv,
+    /// Apache Parquet, compressed with Snappy.
+    Parquet,
+}
+
+struct AnalyticsRow {
+    aggregate_type: String,
+    aggregate_id: String,
+    sequence: i64,
+    event_type: String,
+    event_version: String,
+    timestamp: String,
+    fields: Vec<Option<String>>,
+}
+
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    /// Exports every event, flattened to one row per event, to a CSV or Parquet file at `path`
+    /// for loading into analytics tools such as DuckDB or a data warehouse. Returns the number of
+    /// events exported.
+    ///
+    /// Each row holds the aggregate type, aggregate id, sequence, event type, event version and
+    /// commit timestamp of the event, followed by a column for each of the requested `fields`.
+    /// A field is a JSON path within the event's body, i.e. within the payload under its event
+    /// type tag, such as `"amount"` or `"address.city"`. Fields are written as text and are
+    /// empty (or null) for events without them.
+    ///
+    /// ```
+    /// use rusqlite_es::{ExportFormat, SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// async fn export(repo: &SqliteEventRepository) -> Result<usize, SqliteAggregateError> {
+    ///     repo.export_analytics("events.parquet", ExportFormat::Parquet, &["amount"])
+    ///         .await
+    /// }
+    /// ```
+    pub async fn export_analytics<T: AsRef<Path>>(
+        &self,
+        path: T,
+        format: ExportFormat,
+        fields: &[&str],
+    ) -> Result<usize, SqliteAggregateError> {
+        let repo = self.clone();
+        let path = path.as_ref().to_path_buf();
+        let fields: Vec<String> = fields.iter().map(|field| field.to_string()).collect();
+        // the events are read and the file is written on a blocking thread
+        tokio::task::spawn_blocking(move || {
+            let fields: Vec<&str> = fields.iter().map(String::as_str).collect();
+            repo.write_analytics(&path, format, &fields)
+        })
+        .await
+        .map_err(|err| SqliteAggregateError::UnknownError(Box::new(err)))?
+    }
+
+    fn write_analytics(
+        &self,
+        path: &Path,
+        format: ExportFormat,
+        fields: &[&str],
+    ) -> Result<usize, SqliteAggregateError> {
+        let connection = self.pool().connection()?;
+        let mut statement =
+            connection.prepare(&self.query_factory().analytics_events(fields.len()))?;
+        let mut rows = statement.query(rusqlite::params_from_iter(fields))?;
+        let file = File::create(path).map_err(export_error)?;
+        let mut count = 0;
+        match format {
+            ExportFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(file);
+                writer
+                    .write_record(COLUMNS.iter().chain(fields))
+                    .map_err(export_error)?;
+                while let Some(row) = rows.next()? {
+                    let row = deser_analytics_row(row, fields.len())?;
+                    let sequence = row.sequence.to_string();
+                    let record = [
+                        row.aggregate_type.as_str(),
+                        row.aggregate_id.as_str(),
+                        sequence.as_str(),
+                        row.event_type.as_str(),
+                        row.event_version.as_str(),
+                        row.timestamp.as_str(),
+                    ];
+                    let values = row
+                        .fields
+                        .iter()
+                        .map(|field| field.as_deref().unwrap_or(""));
+                    writer
+                        .write_record(record.into_iter().chain(values))
+                        .map_err(export_error)?;
+                    count += 1;
+                }
+                writer.flush().map_err(export_error)?;
+            }
+            ExportFormat::Parquet => {
+                let schema = parquet_schema(fields);
+                let properties = parquet::file::properties::WriterProperties::builder()
+                    .set_compression(parquet::basic::Compression::SNAPPY)
+                    .build();
+                let mut writer = ArrowWriter::try_new(file, schema.clone(), Some(properties))
+                    .map_err(export_error)?;
+                let mut batch = Vec::with_capacity(PARQUET_BATCH_SIZE);
+                while let Some(row) = rows.next()? {
+                    batch.push(deser_analytics_row(row, fields.len())?);
+                    if batch.len() == PARQUET_BATCH_SIZE {
+                        count += batch.len();
+                        writer
+                            .write(&record_batch(&schema, &batch, fields.len())?)
+                            .map_err(export_error)?;
+                        batch.clear();
+                    }
+                }
+                if !batch.is_empty() {
+                    count += batch.len();
+                    writer
+                        .write(&record_batch(&schema, &batch, fields.len())?)
+                        .map_err(export_error)?;
+                }
+                writer.close().map_err(export_error)?;
+            }
+        }
+        Ok(count)
+    }
+}
+
+fn deser_analytics_row(row: &Row, field_count: usize) -> Result<AnalyticsRow, rusqlite::Error> {
+    let mut fields = Vec::with_capacity(field_count);
+    for index in 0..field_count {
+        fields.push(match row.get::<_, Value>(COLUMNS.len() + index)? {
+            Value::Null => None,
+            Value::Integer(value) => Some(value.to_string()),
+            Value::Real(value) => Some(value.to_string()),
+            Value::Text(value) => Some(value),
+            Value::Blob(value) => Some(String::from_utf8_lossy(&value).into_owned()),
+        });
+    }
+    Ok(AnalyticsRow {
+        aggregate_type: row.get(0)?,
+        aggregate_id: row.get(1)?,
+        sequence: row.get(2)?,
+        event_type: row.get(3)?,
+        event_version: row.get(4)?,
+        timestamp: row.get(5)?,
+        fields,
+    })
+}
+
+fn parquet_schema(fields: &[&str]) -> SchemaRef {
+    let mut columns: Vec<Field> = COLUMNS
+        .iter()
+        .map(|column| match *column {
+            "sequence" => Field::new(*column, DataType::Int64, false),
+            _ => Field::new(*column, DataType::Utf8, false),
+        })
+        .collect();
+    columns.extend(
+        fields
+            .iter()
+            .map(|field| Field::new(*field, DataType::Utf8, true)),
+    );
+    Arc::new(Schema::new(columns))
+}
+
+fn record_batch(
+    schema: &SchemaRef,
+    rows: &[AnalyticsRow],
+    field_count: usize,
+) -> Result<RecordBatch, SqliteAggregateError> {
+    let text = |value: fn(&AnalyticsRow) -> &str| -> ArrayRef {
+        Arc::new(StringArray::from_iter_values(rows.iter().map(value)))
+    };
+    let mut columns: Vec<ArrayRef> = vec![
+        text(|row| &row.aggregate_type),
+        text(|row| &row.aggregate_id),
+        Arc::new(Int64Array::from_iter_values(
+            rows.iter().map(|row| row.sequence),
+        )),
+        text(|row| &row.event_type),
+        text(|row| &row.event_version),
+        text(|row| &row.timestamp),
+    ];
+    for index in 0..field_count {
+        columns.push(Arc::new(StringArray::from_iter(
+            rows.iter().map(|row| row.fields[index].as_deref()),
+        )));
+    }
+    RecordBatch::try_new(schema.clone(), columns).map_err(export_error)
+}
+
+fn export_error<E: std::error::Error + Send + Sync + 'static>(err: E) -> SqliteAggregateError {
+    SqliteAggregateError::UnknownError(Box::new(err))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use arrow_array::{Array, StringArray};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, ExportFormat, SqliteEventRepository};
+
+    #[tokio::test]
+    async fn export_analytics() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let event_repo = SqliteEventRepository::new(pool);
+        event_repo
+            .insert_events::<TestAggregate>(&[
+                test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() })),
+                test_event_envelope(
+                    &id,
+                    2,
+                    TestEvent::Tested(Tested {
+                        test_name: "exported".to_string(),
+                    }),
+                ),
+            ])
+            .await
+            .unwrap();
+
+        let csv_path = std::env::temp_dir().join(format!("{id}.csv"));
+        let count = event_repo
+            .export_analytics(&csv_path, ExportFormat::Csv, &["test_name"])
+            .await
+            .unwrap();
+        // the events follow the one inserted by init.sql
+        assert_eq!(3, count);
+        let csv = fs::read_to_string(&csv_path).unwrap();
+        fs::remove_file(&csv_path).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            "aggregate_type,aggregate_id,sequence,event_type,event_version,timestamp,test_name",
+            lines[0]
+        );
+        assert!(lines[2].starts_with(&format!("TestAggregate,{id},1,Created,1.0,")));
+        assert!(lines[2].ends_with(','));
+        assert!(lines[3].ends_with(",exported"));
+
+        let parquet_path = std::env::temp_dir().join(format!("{id}.parquet"));
+        let count = event_repo
+            .export_analytics(&parquet_path, ExportFormat::Parquet, &["test_name"])
+            .await
+            .unwrap();
+        assert_eq!(3, count);
+        let file = fs::File::open(&parquet_path).unwrap();
+        let mut reader = ParquetRecordBatchReaderBuilder::try_new(file)
+            .unwrap()
+            .build()
+            .unwrap();
+        let batch = reader.next().unwrap().unwrap();
+        fs::remove_file(&parquet_path).unwrap();
+        assert_eq!(3, batch.num_rows());
+        let test_names = batch
+            .column_by_name("test_name")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert!(test_names.is_null(1));
+        assert_eq!("exported", test_names.value(2));
+    }
+}
@@ -0,0 +1,242 @@
+//! Read-only queries over the event log for building audit trails.
+//!
+//! Records are returned in the order in which they were committed. Time ranges are compared
+//! against the `created_at` column, an RFC 3339 UTC timestamp with millisecond precision
+//! (e.g., `"2024-01-31T09:30:00.000Z"`) recorded when each event is committed.
+//!
+//! _Note: tables created before `created_at` was introduced are migrated with
+//! `db/migrate_created_at.sql`, events committed prior to the migration have an empty
+//! timestamp._
+//!
+//! ```
+//! use cqrs_es::persist::PersistenceError;
+//! use rusqlite_es::audit::{self, AuditRecord};
+//! use rusqlite_es::SqliteEventRepository;
+//!
+//! async fn january_activity(
+//!     repo: &SqliteEventRepository,
+//!     user_id: &str,
+//! ) -> Result<Vec<AuditRecord>, PersistenceError> {
+//!     audit::events_for_user(repo, user_id, "2024-01-01".."2024-02-01").await
+//! }
+//! ```
+use std::ops::{Bound, RangeBounds};
+
+use cqrs_es::persist::PersistenceError;
+use rusqlite::Row;
+use serde_json::Value;
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::SqliteEventRepository;
+
+/// The default event metadata key identifying the user responsible for an event, see
+/// `SqliteEventRepository::with_actor_metadata_key`.
+pub const ACTOR_METADATA_KEY: &str = "user_id";
+
+/// A committed event enriched with the details relevant to an audit trail.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    /// The type of aggregate the event applies to.
+    pub aggregate_type: String,
+    /// The id of the aggregate instance the event applies to.
+    pub aggregate_id: String,
+    /// The sequence number of the event for this aggregate instance.
+    pub sequence: usize,
+    /// The type of the event.
+    pub event_type: String,
+    /// The version of the event.
+    pub event_version: String,
+    /// When the event was committed, empty for events committed before timestamps were recorded.
+    pub timestamp: String,
+    /// The user responsible for the event, taken from the actor metadata entry, `user_id` unless
+    /// configured otherwise with `SqliteEventRepository::with_actor_metadata_key`.
+    pub actor: Option<String>,
+    /// The serialized event payload.
+    pub payload: Value,
+    /// The event metadata.
+    pub metadata: Value,
+}
+
+/// Loads the events committed by `user_id` (per the actor metadata entry, see
+/// `ACTOR_METADATA_KEY`) within the provided time range.
+pub async fn events_for_user<'a, P, R>(
+    repo: &SqliteEventRepository<P>,
+    user_id: &str,
+    range: R,
+) -> Result<Vec<AuditRecord>, PersistenceError>
+where
+    P: ConnectionProvider,
+    R: RangeBounds<&'a str>,
+{
+    let condition = format!("json_extract({}, ?) = ?", repo.query_factory().metadata());
+    let params = vec![actor_path(repo), user_id.to_string()];
+    Ok(select_records(repo, &condition, params, range)?)
+}
+
+/// Loads the events for the aggregate instance `aggregate_id`, of any aggregate type, committed
+/// within the provided time range.
+pub async fn events_for_aggregate<'a, P, R>(
+    repo: &SqliteEventRepository<P>,
+    aggregate_id: &str,
+    range: R,
+) -> Result<Vec<AuditRecord>, PersistenceError>
+where
+    P: ConnectionProvider,
+    R: RangeBounds<&'a str>,
+{
+    Ok(select_records(
+        repo,
+        "aggregate_id = ?",
+        vec![aggregate_id.to_string()],
+        range,
+    )?)
+}
+
+fn select_records<'a, P, R>(
+    repo: &SqliteEventRepository<P>,
+    condition: &str,
+    condition_params: Vec<String>,
+    range: R,
+) -> Result<Vec<AuditRecord>, SqliteAggregateError>
+where
+    P: ConnectionProvider,
+    R: RangeBounds<&'a str>,
+{
+    let mut conditions = vec![condition.to_string()];
+    // the actor path is bound in the select list, ahead of the conditions
+    let mut params = vec![actor_path(repo)];
+    params.extend(condition_params);
+    match range.start_bound() {
+        Bound::Included(start) => {
+            conditions.push("created_at >= ?".to_string());
+            params.push(start.to_string());
+        }
+        Bound::Excluded(start) => {
+            conditions.push("created_at > ?".to_string());
+            params.push(start.to_string());
+        }
+        Bound::Unbounded => {}
+    }
+    match range.end_bound() {
+        Bound::Included(end) => {
+            conditions.push("created_at <= ?".to_string());
+            params.push(end.to_string());
+        }
+        Bound::Excluded(end) => {
+            conditions.push("created_at < ?".to_string());
+            params.push(end.to_string());
+        }
+        Bound::Unbounded => {}
+    }
+    let query = repo.query_factory().audit_events(&conditions.join(" AND "));
+    let connection = repo.pool().connection()?;
+    let mut statement = connection.prepare(&query)?;
+    let mut rows = statement.query(rusqlite::params_from_iter(params))?;
+    let mut result: Vec<AuditRecord> = Default::default();
+    while let Some(row) = rows.next()? {
+        let mut record = deser_record(row)?;
+        if let Some(external_payloads) = repo.external_payloads() {
+            external_payloads.resolve(&mut record.payload)?;
+        }
+        result.push(record);
+    }
+    Ok(result)
+}
+
+// The JSON path of the actor metadata entry, quoted so that keys may contain dots.
+fn actor_path<P: ConnectionProvider>(repo: &SqliteEventRepository<P>) -> String {
+    format!("$.\"{}\"", repo.actor_metadata_key())
+}
+
+fn deser_record(row: &Row) -> Result<AuditRecord, rusqlite::Error> {
+    let sequence: i64 = row.get("sequence")?;
+    Ok(AuditRecord {
+        aggregate_type: row.get("aggregate_type")?,
+        aggregate_id: row.get("aggregate_id")?,
+        sequence: sequence as usize,
+        event_type: row.get("event_type")?,
+        event_version: row.get("event_version")?,
+        timestamp: row.get("created_at")?,
+        actor: row.get("actor")?,
+        payload: row.get("payload")?,
+        metadata: row.get("metadata")?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use serde_json::json;
+
+    use crate::audit::{events_for_aggregate, events_for_user};
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, SqliteEventRepository};
+
+    #[tokio::test]
+    async fn audit_queries() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let repo = SqliteEventRepository::new(pool);
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = json!({"user_id": "alice"});
+        let mut tested = test_event_envelope(
+            &id,
+            2,
+            TestEvent::Tested(Tested {
+                test_name: "audited".to_string(),
+            }),
+        );
+        tested.metadata = json!({"user_id": "bob"});
+        repo.insert_events::<TestAggregate>(&[created, tested])
+            .await
+            .unwrap();
+
+        let records = events_for_aggregate(&repo, &id, ..).await.unwrap();
+        assert_eq!(2, records.len());
+        assert_eq!(Some("alice".to_string()), records[0].actor);
+        assert_eq!("Tested", records[1].event_type);
+        assert!(records[1].timestamp.ends_with('Z'));
+
+        let records = events_for_user(&repo, "bob", ..).await.unwrap();
+        assert_eq!(1, records.len());
+        assert_eq!(2, records[0].sequence);
+        assert_eq!(json!({"user_id": "bob"}), records[0].metadata);
+
+        let timestamp = records[0].timestamp.as_str();
+        let records = events_for_user(&repo, "bob", timestamp..).await.unwrap();
+        assert_eq!(1, records.len());
+        let records = events_for_user(&repo, "bob", ..timestamp).await.unwrap();
+        assert!(records.is_empty());
+
+        // the actor is read from the configured metadata entry
+        let repo = repo.with_actor_metadata_key("team.lead");
+        let mut tested = test_event_envelope(
+            &id,
+            3,
+            TestEvent::Tested(Tested {
+                test_name: "delegated".to_string(),
+            }),
+        );
+        tested.metadata = json!({"team.lead": "carol"});
+        repo.insert_events::<TestAggregate>(&[tested])
+            .await
+            .unwrap();
+        let records = events_for_user(&repo, "carol", ..).await.unwrap();
+        assert_eq!(1, records.len());
+        assert_eq!(Some("carol".to_string()), records[0].actor);
+        assert_eq!(
+            None,
+            events_for_aggregate(&repo, &id, ..).await.unwrap()[0].actor
+        );
+    }
+}
@@ -0,0 +1,229 @@
+//! Runs the maintenance operations of `rusqlite_es::admin` against an SQLite event store.
+//!
+//! ```text
+//! sqlite-es-admin <database> <command> [arguments]
+//! ```
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::process::ExitCode;
+
+use rusqlite_es::admin;
+use rusqlite_es::{default_sqlite_pool, SqliteAggregateError, SqliteEventRepository};
+
+const USAGE: &str = "usage: sqlite-es-admin <database> <command> [arguments]
+
+commands:
+    init                            create any missing tables and indexes
+    verify                          check the database, event sequences and snapshots
+    list [aggregate_type]           list the aggregate instances with events
+    export [file]                   write all events as JSON lines to a file or stdout
+    import [file]                   insert the events exported to a file or stdin
+    export-views <view_table> [file]
+                                    write all rows of a view table as JSON lines
+    import-views <view_table> [file]
+                                    insert the views exported to a file or stdin
+    clear-snapshots [aggregate_type]
+                                    delete snapshots, rebuilding aggregates from their events
+    clear-view <view_table>         delete all rows of a view table before it is replayed
+    checkpoint                      checkpoint and truncate the write-ahead log
+    snapshot <file>                 write a point-in-time copy of the database to a file
+    restore <file>                  replace the database with a copy written by snapshot
+    fork <file> <position>          write a branch holding the events up to a global position
+    diff <other_database>           report the events missing from or differing in either store
+    space                           summarize the storage used and recommend maintenance";
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    let (database, command, arguments) = match args.as_slice() {
+        [database, command, arguments @ ..] => (*database, *command, arguments),
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .expect("unable to start the runtime");
+    let repo = SqliteEventRepository::new(default_sqlite_pool(database));
+    match runtime.block_on(run(&repo, command, arguments)) {
+        Ok(Some(code)) => code,
+        Ok(None) => {
+            eprintln!("{USAGE}");
+            ExitCode::from(2)
+        }
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// Runs a command, returning `None` if the command or its arguments are not recognized.
+async fn run(
+    repo: &SqliteEventRepository,
+    command: &str,
+    arguments: &[&str],
+) -> Result<Option<ExitCode>, SqliteAggregateError> {
+    match (command, arguments) {
+        ("init", []) => {
+            admin::init_schema(repo).await?;
+            println!("schema is ready");
+        }
+        ("verify", []) => {
+            let report = admin::verify_integrity(repo).await?;
+            for problem in &report.database {
+                println!("database: {problem}");
+            }
+            for (aggregate_type, aggregate_id) in &report.sequence_gaps {
+                println!("sequence gap: {aggregate_type} {aggregate_id}");
+            }
+            for (aggregate_type, aggregate_id) in &report.orphaned_snapshots {
+                println!("orphaned snapshot: {aggregate_type} {aggregate_id}");
+            }
+            if !report.is_ok() {
+                return Ok(Some(ExitCode::FAILURE));
+            }
+            println!("ok");
+        }
+        ("list", [] | [_]) => {
+            for aggregate in admin::list_aggregates(repo, arguments.first().copied()).await? {
+                println!(
+                    "{}\t{}\t{} events\tlast sequence {}",
+                    aggregate.aggregate_type,
+                    aggregate.aggregate_id,
+                    aggregate.events,
+                    aggregate.last_sequence
+                );
+            }
+        }
+        ("export", []) => {
+            let count = admin::export_events(repo, io::stdout().lock()).await?;
+            eprintln!("exported {count} events");
+        }
+        ("export", [file]) => {
+            let file = File::create(file).map_err(io_error)?;
+            let count = admin::export_events(repo, io::BufWriter::new(file)).await?;
+            eprintln!("exported {count} events");
+        }
+        ("import", []) => {
+            let count = admin::import_events(repo, io::stdin().lock()).await?;
+            eprintln!("imported {count} events");
+        }
+        ("import", [file]) => {
+            let file = File::open(file).map_err(io_error)?;
+            let count = admin::import_events(repo, BufReader::new(file)).await?;
+            eprintln!("imported {count} events");
+        }
+        ("export-views", [view_table]) => {
+            let count = admin::export_views(repo, view_table, io::stdout().lock()).await?;
+            eprintln!("exported {count} views");
+        }
+        ("export-views", [view_table, file]) => {
+            let file = File::create(file).map_err(io_error)?;
+            let count = admin::export_views(repo, view_table, io::BufWriter::new(file)).await?;
+            eprintln!("exported {count} views");
+        }
+        ("import-views", [view_table]) => {
+            let count = admin::import_views(repo, view_table, io::stdin().lock()).await?;
+            eprintln!("imported {count} views");
+        }
+        ("import-views", [view_table, file]) => {
+            let file = File::open(file).map_err(io_error)?;
+            let count = admin::import_views(repo, view_table, BufReader::new(file)).await?;
+            eprintln!("imported {count} views");
+        }
+        ("clear-snapshots", [] | [_]) => {
+            let count = admin::clear_snapshots(repo, arguments.first().copied()).await?;
+            println!("deleted {count} snapshots");
+        }
+        ("clear-view", [view_table]) => {
+            let count = admin::clear_view(repo, view_table).await?;
+            println!("deleted {count} rows");
+        }
+        ("checkpoint", []) => {
+            let result = admin::checkpoint(repo).await?;
+            println!(
+                "checkpointed {} of {} frames{}",
+                result.checkpointed_frames,
+                result.log_frames,
+                if result.busy { " (busy)" } else { "" }
+            );
+        }
+        ("snapshot", [file]) => {
+            admin::snapshot_database(repo, file).await?;
+            println!("written to {file}");
+        }
+        ("restore", [file]) => {
+            admin::restore_database(repo, file).await?;
+            println!("restored from {file}");
+        }
+        ("fork", [file, position]) => {
+            let Ok(position) = position.parse() else {
+                return Ok(None);
+            };
+            let count = admin::fork_store(repo, file, position).await?;
+            println!("forked {count} events to {file}");
+        }
+        ("diff", [other_database]) => {
+            let other = SqliteEventRepository::new(default_sqlite_pool(other_database));
+            let diff = admin::diff_stores(repo, &other).await?;
+            let keys = [
+                ("only in this database", &diff.only_in_a),
+                ("only in the other database", &diff.only_in_b),
+                ("divergent", &diff.divergent),
+            ];
+            for (kind, keys) in keys {
+                for key in keys {
+                    println!(
+                        "{kind}\t{}\t{}\t{}",
+                        key.aggregate_type, key.aggregate_id, key.sequence
+                    );
+                }
+            }
+            println!(
+                "compared {} events, {} missing, {} divergent",
+                diff.compared,
+                diff.only_in_a.len() + diff.only_in_b.len(),
+                diff.divergent.len()
+            );
+            if !diff.is_empty() {
+                return Ok(Some(ExitCode::FAILURE));
+            }
+        }
+        ("space", []) => {
+            let report = admin::space_report(repo).await?;
+            println!(
+                "{} bytes in {} pages, {} bytes free",
+                report.database_bytes(),
+                report.page_count,
+                report.free_bytes()
+            );
+            if let Some(wal_bytes) = report.wal_bytes {
+                println!("write-ahead log: {wal_bytes} bytes");
+            }
+            for table in &report.tables {
+                println!("{}\t{} bytes", table.name, table.bytes);
+            }
+            for space in &report.aggregate_types {
+                println!(
+                    "{}\t{} events ({} bytes)\t{} snapshots ({} bytes)",
+                    space.aggregate_type,
+                    space.events,
+                    space.event_bytes,
+                    space.snapshots,
+                    space.snapshot_bytes
+                );
+            }
+            for recommendation in &report.recommendations {
+                println!("recommended: {recommendation:?}");
+            }
+        }
+        _ => return Ok(None),
+    }
+    Ok(Some(ExitCode::SUCCESS))
+}
+
+fn io_error(err: io::Error) -> SqliteAggregateError {
+    SqliteAggregateError::UnknownError(Box::new(err))
+}
@@ -0,0 +1,579 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use cqrs_es::persist::{PersistenceError, SerializedEvent};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::hash_chain::hex;
+use crate::mapping::deser_event;
+use crate::SqliteEventRepository;
+
+/// The key of the reference stored in place of an externally stored payload.
+pub const BLOB_REFERENCE_KEY: &str = "$blob";
+
+/// Stores payloads too large to be kept inline, see `SqliteEventRepository::with_blob_store`.
+///
+/// Blobs are written before the transaction committing their event, a blob whose commit fails
+/// is left behind until it is deleted with `SqliteEventRepository::collect_blobs`. The key of a
+/// blob includes the SHA-256 hash of its contents, so a blob is never replaced by different
+/// contents. Besides `FileBlobStore`, an implementation may store blobs with an object storage
+/// service.
+pub trait BlobStore: Send + Sync {
+    /// Stores `contents` under `key`. A blob already stored under `key` holds the same contents
+    /// and is kept as it is.
+    fn put(&self, key: &str, contents: &[u8]) -> Result<(), SqliteAggregateError>;
+
+    /// Returns the contents stored under `key`.
+    fn get(&self, key: &str) -> Result<Vec<u8>, SqliteAggregateError>;
+
+    /// Lists the stored blobs.
+    fn list(&self) -> Result<Vec<StoredBlob>, SqliteAggregateError>;
+
+    /// Deletes the blob stored under `key`, if any.
+    fn delete(&self, key: &str) -> Result<(), SqliteAggregateError>;
+}
+
+/// A blob held by a `BlobStore`, see `BlobStore::list`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredBlob {
+    /// The key of the blob.
+    pub key: String,
+    /// When the blob was stored.
+    pub stored_at: SystemTime,
+}
+
+// Numbers the partial files written by this process.
+static PARTIAL_BLOBS: AtomicU64 = AtomicU64::new(0);
+
+/// Stores blobs as files below a directory, each key being a relative path.
+#[derive(Debug, Clone)]
+pub struct FileBlobStore {
+    directory: PathBuf,
+}
+
+impl FileBlobStore {
+    /// Creates a store keeping its blobs below `directory`, which is created as needed.
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+        }
+    }
+}
+
+impl BlobStore for FileBlobStore {
+    fn put(&self, key: &str, contents: &[u8]) -> Result<(), SqliteAggregateError> {
+        let path = self.directory.join(key);
+        if path.exists() {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(blob_error)?;
+        }
+        // written aside and renamed into place, so that readers never see a partial blob, under a
+        // name unique to the writer since concurrent writers may store the same blob
+        let partial = path.with_extension(format!(
+            "{}-{}.partial",
+            std::process::id(),
+            PARTIAL_BLOBS.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&partial, contents).map_err(blob_error)?;
+        fs::rename(&partial, &path).map_err(blob_error)
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, SqliteAggregateError> {
+        fs::read(self.directory.join(key)).map_err(blob_error)
+    }
+
+    fn list(&self) -> Result<Vec<StoredBlob>, SqliteAggregateError> {
+        let mut blobs = Vec::new();
+        if self.directory.exists() {
+            list_files(&self.directory, "", &mut blobs)?;
+        }
+        Ok(blobs)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), SqliteAggregateError> {
+        match fs::remove_file(self.directory.join(key)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(blob_error(err)),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Adds the blobs below `directory` to `blobs`, keyed by their path relative to the store's
+// directory, which is `prefix`. Partial files of blobs being written are skipped.
+fn list_files(
+    directory: &Path,
+    prefix: &str,
+    blobs: &mut Vec<StoredBlob>,
+) -> Result<(), SqliteAggregateError> {
+    for entry in fs::read_dir(directory).map_err(blob_error)? {
+        let entry = entry.map_err(blob_error)?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let key = format!("{prefix}{name}");
+        let metadata = entry.metadata().map_err(blob_error)?;
+        if metadata.is_dir() {
+            list_files(&entry.path(), &format!("{key}/"), blobs)?;
+        } else if !name.ends_with(".partial") {
+            blobs.push(StoredBlob {
+                key,
+                stored_at: metadata.modified().map_err(blob_error)?,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn blob_error(err: std::io::Error) -> SqliteAggregateError {
+    SqliteAggregateError::UnknownError(Box::new(err))
+}
+
+// Moves payloads larger than a threshold into a `BlobStore`.
+pub(crate) struct ExternalPayloads {
+    blob_store: Box<dyn BlobStore>,
+    threshold_bytes: usize,
+    // the keys of the blobs stored ahead of the transactions committing them, with the number of
+    // commits in progress for each, see `stage`
+    staged: Mutex<HashMap<String, usize>>,
+}
+
+// A payload to be stored externally.
+struct Externalized {
+    key: String,
+    contents: Vec<u8>,
+    reference: Value,
+}
+
+impl ExternalPayloads {
+    pub(crate) fn new(blob_store: Box<dyn BlobStore>, threshold_bytes: usize) -> Self {
+        Self {
+            blob_store,
+            threshold_bytes,
+            staged: Default::default(),
+        }
+    }
+
+    // The key, contents and reference of an event payload larger than the threshold, `None` for
+    // smaller payloads.
+    fn externalized(
+        &self,
+        aggregate_type: &str,
+        aggregate_id: &str,
+        payload: &Value,
+    ) -> Result<Option<Externalized>, SqliteAggregateError> {
+        let contents = serde_json::to_vec(payload)?;
+        if contents.len() <= self.threshold_bytes {
+            return Ok(None);
+        }
+        // The key is derived from the contents, so that the blob of an event that lost the
+        // optimistic lock to a concurrent commit cannot replace the blob of the committed event.
+        let hash = hex(&Sha256::digest(&contents));
+        let key = format!(
+            "{}/{}/{}.json",
+            key_segment(aggregate_type),
+            key_segment(aggregate_id),
+            hash
+        );
+        let reference = json!({
+            BLOB_REFERENCE_KEY: {
+                "key": key,
+                "size": contents.len(),
+                "hash": hash,
+            }
+        });
+        Ok(Some(Externalized {
+            key,
+            contents,
+            reference,
+        }))
+    }
+
+    // The keys and contents of the blobs of the payloads larger than the threshold, given as
+    // their aggregate type, aggregate id and payload.
+    pub(crate) fn large_payloads<'a>(
+        &self,
+        payloads: impl IntoIterator<Item = (&'a str, &'a str, &'a Value)>,
+    ) -> Result<Vec<(String, Vec<u8>)>, SqliteAggregateError> {
+        let mut blobs = Vec::new();
+        for (aggregate_type, aggregate_id, payload) in payloads {
+            if let Some(externalized) = self.externalized(aggregate_type, aggregate_id, payload)? {
+                blobs.push((externalized.key, externalized.contents));
+            }
+        }
+        Ok(blobs)
+    }
+
+    // Stores blobs returned by `large_payloads` ahead of the transaction committing them, so that
+    // the blob store is not written while the write lock is held. The blobs are staged until the
+    // returned guard is dropped, the transaction then does not store them again.
+    pub(crate) fn stage(
+        self: &Arc<Self>,
+        blobs: Vec<(String, Vec<u8>)>,
+    ) -> Result<StagedBlobs, SqliteAggregateError> {
+        let mut staged = StagedBlobs {
+            external_payloads: self.clone(),
+            keys: Vec::with_capacity(blobs.len()),
+        };
+        for (key, contents) in blobs {
+            self.blob_store.put(&key, &contents)?;
+            *self.staged().entry(key.clone()).or_default() += 1;
+            staged.keys.push(key);
+        }
+        Ok(staged)
+    }
+
+    fn staged(&self) -> std::sync::MutexGuard<'_, HashMap<String, usize>> {
+        self.staged
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Returns the reference to be stored in place of an event payload larger than the
+    // threshold, storing the payload in the blob store unless it was staged. Smaller payloads
+    // are returned unchanged.
+    pub(crate) fn externalize(
+        &self,
+        aggregate_type: &str,
+        aggregate_id: &str,
+        payload: Value,
+    ) -> Result<Value, SqliteAggregateError> {
+        let Some(externalized) = self.externalized(aggregate_type, aggregate_id, &payload)? else {
+            return Ok(payload);
+        };
+        if !self.staged().contains_key(&externalized.key) {
+            self.blob_store
+                .put(&externalized.key, &externalized.contents)?;
+        }
+        Ok(externalized.reference)
+    }
+
+    // Replaces a reference to an externally stored payload with the payload, verifying its size
+    // and hash.
+    pub(crate) fn resolve(&self, payload: &mut Value) -> Result<(), SqliteAggregateError> {
+        let Some(reference) = payload.get(BLOB_REFERENCE_KEY) else {
+            return Ok(());
+        };
+        let (Some(key), Some(size), Some(hash)) = (
+            reference.get("key").and_then(Value::as_str),
+            reference.get("size").and_then(Value::as_u64),
+            reference.get("hash").and_then(Value::as_str),
+        ) else {
+            return Err(SqliteAggregateError::UnknownError(
+                format!("malformed blob reference {reference}").into(),
+            ));
+        };
+        let contents = self.blob_store.get(key)?;
+        if contents.len() as u64 != size || hex(&Sha256::digest(&contents)) != hash {
+            return Err(SqliteAggregateError::UnknownError(
+                format!("blob {key} does not match its reference").into(),
+            ));
+        }
+        *payload = serde_json::from_slice(&contents)?;
+        Ok(())
+    }
+}
+
+// Blobs stored ahead of the transaction committing them, unstaged once dropped.
+pub(crate) struct StagedBlobs {
+    external_payloads: Arc<ExternalPayloads>,
+    keys: Vec<String>,
+}
+
+impl Drop for StagedBlobs {
+    fn drop(&mut self) {
+        let mut staged = self.external_payloads.staged();
+        for key in &self.keys {
+            if let Some(commits) = staged.get_mut(key) {
+                *commits -= 1;
+                if *commits == 0 {
+                    staged.remove(key);
+                }
+            }
+        }
+    }
+}
+
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    /// Deletes the blobs of the repository's blob store that no event refers to, e.g. those left
+    /// behind by commits that failed, returning the number of blobs deleted. See
+    /// `with_blob_store`.
+    ///
+    /// Blobs are stored before their events are committed, so only blobs stored at least
+    /// `min_age` ago are deleted, which must exceed the duration of any commit in progress,
+    /// including those of other processes sharing the store.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use rusqlite_es::{SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// async fn collect(repo: &SqliteEventRepository) -> Result<(), SqliteAggregateError> {
+    ///     let deleted = repo.collect_blobs(Duration::from_secs(3600)).await?;
+    ///     println!("deleted {deleted} unreferenced blobs");
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn collect_blobs(&self, min_age: Duration) -> Result<usize, SqliteAggregateError> {
+        let Some(external_payloads) = self.external_payloads().clone() else {
+            return Ok(0);
+        };
+        let pool = self.pool().clone();
+        let everything = self.query_factory().everything().to_string();
+        tokio::task::spawn_blocking(move || {
+            // listed before the references are read, a blob stored in between is not deleted
+            let blobs = external_payloads.blob_store.list()?;
+            let mut referenced = HashSet::new();
+            let connection = pool.connection()?;
+            let mut statement = connection.prepare(&everything)?;
+            let mut rows = statement.query([])?;
+            while let Some(row) = rows.next()? {
+                let event = deser_event(row)?;
+                if let Some(key) = event.payload[BLOB_REFERENCE_KEY]["key"].as_str() {
+                    referenced.insert(key.to_string());
+                }
+            }
+            let now = SystemTime::now();
+            let mut deleted = 0;
+            for blob in blobs {
+                let age = now.duration_since(blob.stored_at).unwrap_or_default();
+                if age < min_age
+                    || referenced.contains(&blob.key)
+                    || external_payloads.staged().contains_key(&blob.key)
+                {
+                    continue;
+                }
+                external_payloads.blob_store.delete(&blob.key)?;
+                deleted += 1;
+            }
+            Ok(deleted)
+        })
+        .await
+        .map_err(|err| SqliteAggregateError::UnknownError(Box::new(err)))?
+    }
+}
+
+// Resolves externally stored payloads of the events handed to `push` by a stream.
+pub(crate) fn resolved<F>(
+    external_payloads: Option<Arc<ExternalPayloads>>,
+    mut push: F,
+) -> impl FnMut(Result<SerializedEvent, PersistenceError>) -> bool + Send + 'static
+where
+    F: FnMut(Result<SerializedEvent, PersistenceError>) -> bool + Send + 'static,
+{
+    move |event_result| match (&external_payloads, event_result) {
+        (Some(external_payloads), Ok(mut event)) => {
+            match external_payloads.resolve(&mut event.payload) {
+                Ok(()) => push(Ok(event)),
+                Err(err) => push(Err(err.into())),
+            }
+        }
+        (_, event_result) => push(event_result),
+    }
+}
+
+// Escapes the characters of a key segment that are not safe within a file name or object key.
+fn key_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' => (byte as char).to_string(),
+            byte => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde_json::{json, Value};
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{
+        default_sqlite_pool, BlobStore, FileBlobStore, SqliteAggregateError, SqliteEventRepository,
+        StoredBlob, BLOB_REFERENCE_KEY,
+    };
+
+    #[tokio::test]
+    async fn external_payloads() {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let repo = SqliteEventRepository::connect(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .await
+            .unwrap()
+            .into_inner()
+            .with_blob_store(Box::new(FileBlobStore::new(&directory)), 100);
+        let id = format!("media/{}", uuid::Uuid::new_v4());
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        let mut tested = test_event_envelope(
+            &id,
+            2,
+            TestEvent::Tested(Tested {
+                test_name: "x".repeat(1000),
+            }),
+        );
+        created.metadata = json!({});
+        tested.metadata = json!({});
+        repo.persist::<TestAggregate>(&[created.clone(), tested.clone()], None)
+            .await
+            .unwrap();
+
+        let stored: Vec<Value> = repo
+            .with_connection(|conn| {
+                conn.prepare("SELECT payload FROM events WHERE aggregate_id = ? ORDER BY sequence")?
+                    .query_map([&id], |row| row.get(0))?
+                    .collect()
+            })
+            .unwrap();
+        assert_eq!(created.payload, stored[0]);
+        let key = stored[1][BLOB_REFERENCE_KEY]["key"].as_str().unwrap();
+        assert!(!key.contains("media/"));
+        assert!(directory.join(key).exists());
+
+        let events = repo.get_events::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(tested.payload, events[1].payload);
+        let mut stream = repo.stream_everything().await.unwrap();
+        let mut streamed = None;
+        while let Some(event) = stream.next().await {
+            let event = event.unwrap();
+            if event.aggregate_id == id && event.sequence == 2 {
+                streamed = Some(event.payload);
+            }
+        }
+        assert_eq!(Some(tested.payload.clone()), streamed);
+
+        // a concurrent writer losing the optimistic lock leaves the committed blob intact
+        let mut conflicting = test_event_envelope(
+            &id,
+            2,
+            TestEvent::Tested(Tested {
+                test_name: "y".repeat(1000),
+            }),
+        );
+        conflicting.metadata = json!({});
+        assert!(repo
+            .persist::<TestAggregate>(&[conflicting], None)
+            .await
+            .is_err());
+        let events = repo.get_events::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(tested.payload, events[1].payload);
+
+        // the replacement event of a compacted stream is stored externally as well
+        let compacted = TestEvent::Tested(Tested {
+            test_name: "z".repeat(1000),
+        });
+        let replacement = compacted.clone();
+        repo.compact_stream::<TestAggregate, _>(&id, 2, move |_| replacement.clone())
+            .await
+            .unwrap();
+        let stored: Value = repo
+            .with_connection(|conn| {
+                conn.query_row(
+                    "SELECT payload FROM events WHERE aggregate_id = ? AND sequence = 1",
+                    [&id],
+                    |row| row.get(0),
+                )
+            })
+            .unwrap();
+        assert!(stored.get(BLOB_REFERENCE_KEY).is_some());
+        let events = repo.get_events::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(serde_json::to_value(&compacted).unwrap(), events[0].payload);
+
+        // a blob that no longer matches its reference is not read
+        let key = stored[BLOB_REFERENCE_KEY]["key"].as_str().unwrap();
+        fs::write(directory.join(key), b"{}").unwrap();
+        assert!(repo.get_events::<TestAggregate>(&id).await.is_err());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    // A blob store failing blobs stored while a write transaction is in progress.
+    struct OutsideWrites {
+        blob_store: FileBlobStore,
+        active_writes: Arc<AtomicUsize>,
+    }
+
+    impl BlobStore for OutsideWrites {
+        fn put(&self, key: &str, contents: &[u8]) -> Result<(), SqliteAggregateError> {
+            if self.active_writes.load(Ordering::SeqCst) > 0 {
+                return Err(SqliteAggregateError::UnknownError(
+                    "blob stored within a write transaction".into(),
+                ));
+            }
+            self.blob_store.put(key, contents)
+        }
+
+        fn get(&self, key: &str) -> Result<Vec<u8>, SqliteAggregateError> {
+            self.blob_store.get(key)
+        }
+
+        fn list(&self) -> Result<Vec<StoredBlob>, SqliteAggregateError> {
+            self.blob_store.list()
+        }
+
+        fn delete(&self, key: &str) -> Result<(), SqliteAggregateError> {
+            self.blob_store.delete(key)
+        }
+    }
+
+    #[tokio::test]
+    async fn collect_blobs() {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let repo = SqliteEventRepository::connect(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .await
+            .unwrap()
+            .into_inner();
+        let blob_store = OutsideWrites {
+            blob_store: FileBlobStore::new(&directory),
+            active_writes: repo.active_writes(),
+        };
+        let repo = repo.with_blob_store(Box::new(blob_store), 100);
+        let id = uuid::Uuid::new_v4().to_string();
+        let tested = |sequence, name: &str| {
+            let mut event = test_event_envelope(
+                &id,
+                sequence,
+                TestEvent::Tested(Tested {
+                    test_name: name.repeat(1000),
+                }),
+            );
+            event.metadata = json!({});
+            event
+        };
+        repo.persist::<TestAggregate>(&[tested(1, "x")], None)
+            .await
+            .unwrap();
+
+        // the blob of a commit losing the optimistic lock is left behind
+        assert!(repo
+            .persist::<TestAggregate>(&[tested(1, "y")], None)
+            .await
+            .is_err());
+        let stored = FileBlobStore::new(&directory).list().unwrap();
+        assert_eq!(2, stored.len());
+        assert!(stored.iter().all(|blob| blob.key.ends_with(".json")));
+
+        // recent blobs are kept, they may belong to commits in progress
+        assert_eq!(
+            0,
+            repo.collect_blobs(Duration::from_secs(3600)).await.unwrap()
+        );
+        assert_eq!(1, repo.collect_blobs(Duration::ZERO).await.unwrap());
+        assert_eq!(1, FileBlobStore::new(&directory).list().unwrap().len());
+        let events = repo.get_events::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(tested(1, "x").payload, events[0].payload);
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+use rusqlite::Connection;
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::SqliteEventRepository;
+
+/// The optional SQLite features available on a connection, see
+/// `SqliteEventRepository::capabilities`, so that features building on them can be enabled
+/// only where they are supported and degrade gracefully elsewhere, e.g. when linking a system
+/// library built without FTS5.
+///
+/// Features are detected by trying them, so features provided by extensions loaded into the
+/// connection, see `PoolOptions::with_extension`, are reported as well.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SqliteCapabilities {
+    /// The version of the linked SQLite library, e.g. `3.39.2`.
+    pub version: String,
+    /// Whether the JSON functions, e.g. `json_extract`, are available.
+    pub json: bool,
+    /// Whether the binary JSON functions, e.g. `jsonb`, are available, from SQLite 3.45 on.
+    pub jsonb: bool,
+    /// Whether the FTS5 full-text search module is available.
+    pub fts5: bool,
+    /// Whether statements may have a `RETURNING` clause, from SQLite 3.35 on.
+    pub returning: bool,
+    /// Whether the math functions, e.g. `sqrt` and `ln`, are available. They are not compiled
+    /// into the library bundled by the `bundled` feature.
+    pub math_functions: bool,
+}
+
+impl SqliteCapabilities {
+    /// Detects the features available on `conn`. FTS5 is detected by creating and dropping a
+    /// temporary table within a savepoint, which does not touch the main database.
+    pub fn detect(conn: &Connection) -> Result<Self, SqliteAggregateError> {
+        let function = |sql: &str| conn.prepare(sql).is_ok();
+        Ok(Self {
+            version: rusqlite::version().to_string(),
+            json: function("SELECT json_extract('{}', '$')"),
+            jsonb: function("SELECT jsonb('{}')"),
+            fts5: has_module(conn, "fts5")?,
+            returning: rusqlite::version_number() >= 3_035_000,
+            math_functions: function("SELECT sqrt(4), ln(1)"),
+        })
+    }
+}
+
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    /// Detects the optional SQLite features available on the repository's connections.
+    ///
+    /// ```
+    /// use rusqlite_es::{SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// async fn search_enabled(repo: &SqliteEventRepository) -> Result<bool, SqliteAggregateError> {
+    ///     Ok(repo.capabilities().await?.fts5)
+    /// }
+    /// ```
+    pub async fn capabilities(&self) -> Result<SqliteCapabilities, SqliteAggregateError> {
+        let connection = self.pool().connection()?;
+        SqliteCapabilities::detect(&connection)
+    }
+}
+
+// Whether the virtual table module `module` is available, modules are only looked up once a
+// virtual table is created.
+fn has_module(conn: &Connection, module: &str) -> Result<bool, SqliteAggregateError> {
+    conn.execute_batch("SAVEPOINT rusqlite_es_capabilities")?;
+    let created = conn
+        .execute_batch(&format!(
+            "CREATE VIRTUAL TABLE temp.rusqlite_es_capabilities USING {module}(content)"
+        ))
+        .is_ok();
+    conn.execute_batch("ROLLBACK TO rusqlite_es_capabilities; RELEASE rusqlite_es_capabilities")?;
+    Ok(created)
+}
+
+// A connection initializer loading a runtime-loadable extension, see
+// `PoolOptions::with_extension`.
+#[cfg(feature = "load_extension")]
+#[allow(unsafe_code)]
+pub(crate) fn extension(
+    path: std::path::PathBuf,
+    entry_point: Option<String>,
+) -> crate::ConnectionInit {
+    crate::ConnectionInit::new(move |conn| {
+        // SAFETY: the caller of `PoolOptions::with_extension` vouches for the extension,
+        // loading is only enabled for as long as the guard lives
+        unsafe {
+            let _guard = rusqlite::LoadExtensionGuard::new(conn)?;
+            conn.load_extension(&path, entry_point.as_deref())
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::testing::tests::TEST_CONNECTION_STRING;
+    use crate::{default_sqlite_pool, SqliteEventRepository};
+
+    #[tokio::test]
+    async fn capabilities() {
+        let repo = SqliteEventRepository::connect(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .await
+            .unwrap()
+            .into_inner();
+        let capabilities = repo.capabilities().await.unwrap();
+        assert_eq!(rusqlite::version(), capabilities.version);
+        assert!(capabilities.json && capabilities.returning);
+        #[cfg(feature = "bundled")]
+        assert!(capabilities.fts5);
+
+        // the probe leaves no trace, not even within an open transaction
+        repo.with_connection(|conn| {
+            conn.execute_batch("BEGIN; CREATE TABLE probed (id integer);")?;
+            crate::SqliteCapabilities::detect(conn).unwrap();
+            let tables: i64 =
+                conn.query_row("SELECT count(*) FROM sqlite_temp_master", [], |row| {
+                    row.get(0)
+                })?;
+            assert_eq!(0, tables);
+            conn.execute_batch("COMMIT")?;
+            conn.query_row("SELECT count(*) FROM probed", [], |row| {
+                row.get::<_, i64>(0)
+            })
+        })
+        .unwrap();
+    }
+
+    #[cfg(feature = "load_extension")]
+    #[test]
+    #[allow(unsafe_code)]
+    fn missing_extension() {
+        // SAFETY: there is no extension to run
+        let options =
+            unsafe { crate::PoolOptions::new().with_extension("/nonexistent/extension", None) };
+        assert!(crate::sqlite_pool(TEST_CONNECTION_STRING, options).is_err());
+    }
+}
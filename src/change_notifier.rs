@@ -0,0 +1,290 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::error::SqliteAggregateError;
+
+/// The file within a notification directory holding the position of the last commit.
+pub const CHANGE_POSITION_FILE: &str = "position";
+
+const SOCKET_EXTENSION: &str = "sock";
+
+static SUBSCRIPTIONS: AtomicUsize = AtomicUsize::new(0);
+
+static NOTIFIERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Notifies other processes sharing the database of commits, so that a reader polling for new
+/// events, e.g. a relay publishing them to a message broker, wakes as soon as events are
+/// committed rather than on its next tick. See `SqliteEventRepository::with_change_notifier`.
+///
+/// Notifications are exchanged through a directory, typically next to the database file. On
+/// each commit the writer records the global position of the last committed event in the
+/// `position` file of the directory and sends it as a datagram to the unix domain socket of each
+/// subscriber, see `subscribe`. Notifications are hints only, a reader must still query the
+/// events committed after the position it has processed, and should keep polling on a longer
+/// interval in case a notification is lost, e.g. while it was restarting.
+///
+/// Socket paths are limited to about 100 bytes, so the directory should have a short path.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use rusqlite_es::{ChangeNotifier, SqliteAggregateError};
+///
+/// async fn relay(notifier: ChangeNotifier) -> Result<(), SqliteAggregateError> {
+///     let subscription = notifier.subscribe()?;
+///     let mut processed = 0;
+///     loop {
+///         // subscribed before reading the last position, no commit goes unnoticed
+///         let position = notifier.last_position()?.unwrap_or(0);
+///         if position > processed {
+///             // publish the events committed after `processed`
+///             processed = position;
+///         }
+///         subscription.changed(Duration::from_secs(5)).await?;
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ChangeNotifier {
+    directory: PathBuf,
+    // the highest position recorded by the notifier and its clones, also serializing their writes
+    recorded: Arc<Mutex<i64>>,
+    // distinguishes the notifier's partial position file from those of other writers
+    writer: usize,
+}
+
+impl ChangeNotifier {
+    /// Creates a notifier exchanging notifications through `directory`, which is created as
+    /// needed.
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            recorded: Arc::new(Mutex::new(0)),
+            writer: NOTIFIERS.fetch_add(1, Ordering::SeqCst),
+        }
+    }
+
+    /// Records `position` as the position of the last commit and notifies the current
+    /// subscribers. Subscribers that are gone, e.g. a reader that crashed, are removed. A
+    /// position below one already notified through this notifier, e.g. of a commit whose
+    /// notification was overtaken, is ignored.
+    ///
+    /// The notification does blocking file system I/O, call it from a blocking thread in async
+    /// code. `SqliteEventRepository` notifies its commits in the background.
+    pub fn notify(&self, position: i64) -> Result<(), SqliteAggregateError> {
+        let mut recorded = self
+            .recorded
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if position < *recorded {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.directory).map_err(notify_error)?;
+        let path = self.directory.join(CHANGE_POSITION_FILE);
+        // written aside and renamed into place, so that readers never see a partial position,
+        // under a name of its own as other repositories or processes may share the directory
+        let partial =
+            path.with_extension(format!("{}-{}.partial", std::process::id(), self.writer));
+        fs::write(&partial, position.to_string()).map_err(notify_error)?;
+        fs::rename(&partial, &path).map_err(notify_error)?;
+        *recorded = position;
+
+        let sender = UnixDatagram::unbound().map_err(notify_error)?;
+        // a subscriber whose buffer is full has notifications pending, it need not be waited for
+        sender.set_nonblocking(true).map_err(notify_error)?;
+        let message = position.to_string();
+        for entry in fs::read_dir(&self.directory).map_err(notify_error)? {
+            let socket = entry.map_err(notify_error)?.path();
+            if socket.extension().and_then(|extension| extension.to_str()) != Some(SOCKET_EXTENSION)
+            {
+                continue;
+            }
+            if let Err(err) = sender.send_to(message.as_bytes(), &socket) {
+                // nobody is bound to the socket anymore
+                if err.kind() == ErrorKind::ConnectionRefused {
+                    let _ = fs::remove_file(&socket);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The position of the last commit, `None` if no commit was notified yet.
+    pub fn last_position(&self) -> Result<Option<i64>, SqliteAggregateError> {
+        match fs::read_to_string(self.directory.join(CHANGE_POSITION_FILE)) {
+            Ok(contents) => Ok(Some(parse_position(&contents)?)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(notify_error(err)),
+        }
+    }
+
+    /// Subscribes to the commits notified from now on.
+    pub fn subscribe(&self) -> Result<ChangeSubscription, SqliteAggregateError> {
+        fs::create_dir_all(&self.directory).map_err(notify_error)?;
+        let path = self.directory.join(format!(
+            "{}-{}.{SOCKET_EXTENSION}",
+            std::process::id(),
+            SUBSCRIPTIONS.fetch_add(1, Ordering::SeqCst)
+        ));
+        // left behind by an earlier process with the same id
+        let _ = fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).map_err(notify_error)?;
+        Ok(ChangeSubscription {
+            socket: Arc::new(socket),
+            path,
+        })
+    }
+}
+
+/// A subscription to the commits notified by a `ChangeNotifier`, see
+/// `ChangeNotifier::subscribe`. The subscription's socket is removed once it is dropped.
+#[derive(Debug)]
+pub struct ChangeSubscription {
+    socket: Arc<UnixDatagram>,
+    path: PathBuf,
+}
+
+impl ChangeSubscription {
+    /// Waits up to `timeout` for a commit, returning the highest position notified since the
+    /// previous call, or `None` if nothing was committed in time.
+    pub async fn changed(&self, timeout: Duration) -> Result<Option<i64>, SqliteAggregateError> {
+        let socket = self.socket.clone();
+        tokio::task::spawn_blocking(move || receive(&socket, timeout))
+            .await
+            .map_err(|err| SqliteAggregateError::UnknownError(Box::new(err)))?
+    }
+
+    /// The path of the subscription's socket.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ChangeSubscription {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Waits for a notification, then drains those already queued behind it.
+fn receive(socket: &UnixDatagram, timeout: Duration) -> Result<Option<i64>, SqliteAggregateError> {
+    let mut buffer = [0; 32];
+    socket.set_nonblocking(false).map_err(notify_error)?;
+    // a zero timeout is rejected, it would block indefinitely
+    socket
+        .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))
+        .map_err(notify_error)?;
+    let mut position = match socket.recv(&mut buffer) {
+        Ok(length) => parse_datagram(&buffer[..length])?,
+        Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            return Ok(None)
+        }
+        Err(err) => return Err(notify_error(err)),
+    };
+    socket.set_nonblocking(true).map_err(notify_error)?;
+    loop {
+        match socket.recv(&mut buffer) {
+            Ok(length) => position = position.max(parse_datagram(&buffer[..length])?),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(Some(position)),
+            Err(err) => return Err(notify_error(err)),
+        }
+    }
+}
+
+fn parse_datagram(datagram: &[u8]) -> Result<i64, SqliteAggregateError> {
+    parse_position(&String::from_utf8_lossy(datagram))
+}
+
+fn parse_position(position: &str) -> Result<i64, SqliteAggregateError> {
+    position.trim().parse().map_err(|_| {
+        SqliteAggregateError::UnknownError(format!("invalid change position {position}").into())
+    })
+}
+
+fn notify_error(err: std::io::Error) -> SqliteAggregateError {
+    SqliteAggregateError::UnknownError(Box::new(err))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::os::unix::net::UnixDatagram;
+    use std::time::Duration;
+
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde_json::json;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, ChangeNotifier, SqliteEventRepository};
+
+    #[tokio::test]
+    async fn change_notifier() {
+        // kept short, socket paths are limited in length
+        let directory = std::env::temp_dir().join(&uuid::Uuid::new_v4().to_string()[..8]);
+        let notifier = ChangeNotifier::new(&directory);
+        let subscription = notifier.subscribe().unwrap();
+        // a subscriber that crashed leaves its socket behind
+        let stale = directory.join("0-0.sock");
+        drop(UnixDatagram::bind(&stale).unwrap());
+        assert_eq!(None, notifier.last_position().unwrap());
+        assert_eq!(
+            None,
+            subscription
+                .changed(Duration::from_millis(10))
+                .await
+                .unwrap()
+        );
+
+        let repo = SqliteEventRepository::connect(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .await
+            .unwrap()
+            .into_inner()
+            .with_change_notifier(notifier.clone());
+        for _ in 0..2 {
+            let id = uuid::Uuid::new_v4().to_string();
+            let mut created =
+                test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+            created.metadata = json!({});
+            repo.persist::<TestAggregate>(&[created], None)
+                .await
+                .unwrap();
+        }
+
+        // commits are notified in the background, possibly coalesced into the latest position
+        let mut notified = None;
+        while let Some(position) = subscription
+            .changed(Duration::from_millis(200))
+            .await
+            .unwrap()
+        {
+            assert!(notified < Some(position));
+            notified = Some(position);
+        }
+        let position = notifier.last_position().unwrap().unwrap();
+        assert!(position > 0);
+        assert_eq!(Some(position), notified);
+        // positions overtaken by a later notification are ignored
+        notifier.notify(position - 1).unwrap();
+        assert_eq!(Some(position), notifier.last_position().unwrap());
+        let other = ChangeNotifier::new(&directory);
+        other.notify(position + 1).unwrap();
+        assert_eq!(Some(position + 1), notifier.last_position().unwrap());
+        assert!(fs::read_dir(&directory).unwrap().all(|entry| !entry
+            .unwrap()
+            .path()
+            .to_string_lossy()
+            .ends_with(".partial")));
+        let path = subscription.path().to_path_buf();
+        drop(subscription);
+        assert!(!path.exists());
+        assert!(!stale.exists());
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
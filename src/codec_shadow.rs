@@ -0,0 +1,255 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use crate::error::SqliteAggregateError;
+use crate::MetadataCodec;
+
+/// Whether a `CodecMismatch` was found when metadata was committed or read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShadowOperation {
+    /// The candidate's round trip of committed metadata differs from the active codec's.
+    Write,
+    /// The candidate decodes stored metadata differently than the active codec.
+    Read,
+}
+
+/// A difference between the active codec and the candidate of a `ShadowCodec`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodecMismatch {
+    /// The operation during which the mismatch was found.
+    pub operation: ShadowOperation,
+    /// The metadata as produced by the active codec.
+    pub expected: Value,
+    /// The metadata as produced by the candidate, or the candidate's error.
+    pub actual: Result<Value, String>,
+}
+
+/// The counts of a `ShadowCodec`, see `ShadowCodec::report`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ShadowReport {
+    /// The number of events whose metadata was encoded by both codecs.
+    pub writes: usize,
+    /// The number of events whose metadata was decoded by both codecs.
+    pub reads: usize,
+    /// The number of writes and reads where the codecs disagreed.
+    pub mismatches: usize,
+}
+
+type MismatchReporter = dyn Fn(&CodecMismatch) + Send + Sync;
+
+#[derive(Default)]
+struct ShadowCounts {
+    writes: AtomicUsize,
+    reads: AtomicUsize,
+    mismatches: AtomicUsize,
+}
+
+/// Runs a candidate `MetadataCodec` in the shadow of the active one, so that a switch of codecs
+/// can be verified against production traffic before it is made.
+///
+/// Metadata is stored and read with the active codec only. In addition, committed metadata is
+/// encoded and decoded by the candidate and the result compared with the active codec's, and
+/// stored metadata is decoded by the candidate as well, verifying that the candidate reads the
+/// existing events. Differences and candidate errors are counted and passed to the mismatch
+/// reporter, they never fail a commit or a read. The codec is named after the active codec in
+/// the recorded `StoreConfig`.
+///
+/// Only metadata codecs are shadowed. Payloads have no pluggable codec: they are stored in the
+/// repository's `JsonEncoding`, and rows of either encoding are read, so switching encodings
+/// requires neither a shadow nor a migration.
+///
+/// A `ShadowCodec` is cheap to clone, all clones share their counts, so a clone may be kept to
+/// poll `report` once the codec is configured with `SqliteEventRepository::with_metadata_codec`.
+///
+/// ```
+/// use serde::{Deserialize, Serialize};
+/// use rusqlite_es::{ShadowCodec, SqliteEventRepository, TypedMetadata};
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct RequestMetadata {
+///     user_id: String,
+/// }
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct RequestMetadataV2 {
+///     user_id: String,
+///     tenant: Option<String>,
+/// }
+///
+/// fn configure_repo(store: SqliteEventRepository) -> (SqliteEventRepository, ShadowCodec) {
+///     let shadow = ShadowCodec::new(
+///         Box::new(TypedMetadata::<RequestMetadata>::new()),
+///         Box::new(TypedMetadata::<RequestMetadataV2>::new()),
+///     )
+///     .with_mismatch_reporter(|mismatch| eprintln!("codec mismatch: {mismatch:?}"));
+///     (store.with_metadata_codec(Box::new(shadow.clone())), shadow)
+/// }
+/// ```
+#[derive(Clone)]
+pub struct ShadowCodec {
+    active: Arc<dyn MetadataCodec>,
+    candidate: Arc<dyn MetadataCodec>,
+    on_mismatch: Option<Arc<MismatchReporter>>,
+    counts: Arc<ShadowCounts>,
+}
+
+impl ShadowCodec {
+    /// Creates a codec storing metadata with `active` and comparing `candidate` against it.
+    pub fn new(active: Box<dyn MetadataCodec>, candidate: Box<dyn MetadataCodec>) -> Self {
+        Self {
+            active: Arc::from(active),
+            candidate: Arc::from(candidate),
+            on_mismatch: None,
+            counts: Default::default(),
+        }
+    }
+
+    /// Calls `on_mismatch` with each difference between the codecs.
+    pub fn with_mismatch_reporter<F>(self, on_mismatch: F) -> Self
+    where
+        F: Fn(&CodecMismatch) + Send + Sync + 'static,
+    {
+        Self {
+            on_mismatch: Some(Arc::new(on_mismatch)),
+            ..self
+        }
+    }
+
+    /// Returns the counts of compared writes and reads and of the mismatches found.
+    pub fn report(&self) -> ShadowReport {
+        ShadowReport {
+            writes: self.counts.writes.load(Ordering::SeqCst),
+            reads: self.counts.reads.load(Ordering::SeqCst),
+            mismatches: self.counts.mismatches.load(Ordering::SeqCst),
+        }
+    }
+
+    fn compare(
+        &self,
+        operation: ShadowOperation,
+        expected: &Value,
+        actual: Result<Value, SqliteAggregateError>,
+    ) {
+        let count = match operation {
+            ShadowOperation::Write => &self.counts.writes,
+            ShadowOperation::Read => &self.counts.reads,
+        };
+        count.fetch_add(1, Ordering::SeqCst);
+        if matches!(&actual, Ok(actual) if actual == expected) {
+            return;
+        }
+        self.counts.mismatches.fetch_add(1, Ordering::SeqCst);
+        if let Some(on_mismatch) = &self.on_mismatch {
+            on_mismatch(&CodecMismatch {
+                operation,
+                expected: expected.clone(),
+                actual: actual.map_err(|err| err.to_string()),
+            });
+        }
+    }
+}
+
+impl MetadataCodec for ShadowCodec {
+    fn encode(&self, metadata: &Value) -> Result<Value, SqliteAggregateError> {
+        let stored = self.active.encode(metadata)?;
+        let expected = self.active.decode(stored.clone())?;
+        let actual = self
+            .candidate
+            .encode(metadata)
+            .and_then(|encoded| self.candidate.decode(encoded));
+        self.compare(ShadowOperation::Write, &expected, actual);
+        Ok(stored)
+    }
+
+    fn decode(&self, metadata: Value) -> Result<Value, SqliteAggregateError> {
+        let actual = self.candidate.decode(metadata.clone());
+        let expected = self.active.decode(metadata)?;
+        self.compare(ShadowOperation::Read, &expected, actual);
+        Ok(expected)
+    }
+
+    fn name(&self) -> &str {
+        self.active.name()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, TEST_CONNECTION_STRING,
+    };
+    use crate::{
+        default_sqlite_pool, MetadataCodec, ShadowCodec, ShadowOperation, ShadowReport,
+        SqliteEventRepository, TypedMetadata,
+    };
+
+    #[derive(Serialize, Deserialize)]
+    struct RequestMetadata {
+        user_id: String,
+        attempt: u32,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct RenamedMetadata {
+        user_id: String,
+        #[serde(default)]
+        retry: u32,
+    }
+
+    #[tokio::test]
+    async fn shadow_codec() {
+        let mismatches = Arc::new(Mutex::new(Vec::new()));
+        let recorded = mismatches.clone();
+        let shadow = ShadowCodec::new(
+            Box::new(TypedMetadata::<RequestMetadata>::new()),
+            Box::new(TypedMetadata::<RenamedMetadata>::new()),
+        )
+        .with_mismatch_reporter(move |mismatch| recorded.lock().unwrap().push(mismatch.clone()));
+        assert_eq!(
+            shadow.name(),
+            TypedMetadata::<RequestMetadata>::new().name()
+        );
+        let repo = SqliteEventRepository::connect(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .await
+            .unwrap()
+            .into_inner()
+            .with_metadata_codec(Box::new(shadow.clone()));
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = json!({"user_id": "alice", "attempt": "2"});
+        repo.persist::<TestAggregate>(&[created], None)
+            .await
+            .unwrap();
+        let events = repo.get_events::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(
+            json!({"user_id": "\"alice\"", "attempt": "2"}),
+            events[0].metadata
+        );
+
+        assert_eq!(
+            ShadowReport {
+                writes: 1,
+                reads: 1,
+                mismatches: 1,
+            },
+            shadow.report()
+        );
+        // both codecs decode stored metadata alike, only the candidate's encoding differs
+        let mismatches = mismatches.lock().unwrap();
+        assert_eq!(1, mismatches.len());
+        assert_eq!(ShadowOperation::Write, mismatches[0].operation);
+        assert_eq!(
+            Ok(json!({"user_id": "\"alice\"", "retry": "0"})),
+            mismatches[0].actual
+        );
+    }
+}
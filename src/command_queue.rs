@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard, OwnedSemaphorePermit, Semaphore};
+
+use crate::error::SqliteAggregateError;
+
+/// Serializes the commands executed on the same aggregate instance within this process, e.g.
+/// by `CqrsState::with_command_queue`.
+///
+/// Concurrent commands on one aggregate instance otherwise all load it, handle the command and
+/// race to commit, with all but the first failing with an optimistic lock conflict, wasting
+/// their work. Queued, each command waits for the previous one on the same aggregate instance
+/// and loads the result of it instead, while commands on different instances still run
+/// concurrently. Commands are let through in the order they were queued. Commands executed by
+/// other processes are not queued and still conflict as usual.
+///
+/// Optionally, `with_max_concurrent` also limits the commands executing at once across all
+/// aggregate instances, e.g. to the single writer connection, letting the commands through in
+/// the order they became ready to execute. As a command on an aggregate instance is only ready
+/// once the previous one on it has completed, a chatty aggregate instance then takes at most one
+/// of the slots and its next command lines up behind the commands of the others.
+///
+/// A queue is cheap to clone, clones share the same queues.
+///
+/// ```
+/// # use cqrs_es::doc::MyAggregate;
+/// use cqrs_es::{Aggregate, AggregateError, CqrsFramework, EventStore};
+/// use rusqlite_es::CommandQueue;
+///
+/// async fn execute<ES: EventStore<MyAggregate>>(
+///     cqrs: &CqrsFramework<MyAggregate, ES>,
+///     queue: &CommandQueue,
+///     aggregate_id: &str,
+///     command: <MyAggregate as Aggregate>::Command,
+/// ) -> Result<(), AggregateError<<MyAggregate as Aggregate>::Error>> {
+///     let _permit = queue.lock(aggregate_id).await?;
+///     cqrs.execute(aggregate_id, command).await
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CommandQueue {
+    aggregates: Arc<Mutex<HashMap<String, Queued>>>,
+    max_waiting: Option<usize>,
+    slots: Option<Arc<Semaphore>>,
+}
+
+// The queue of an aggregate instance, removed once no command holds or waits for it.
+#[derive(Debug)]
+struct Queued {
+    lock: Arc<AsyncMutex<()>>,
+    commands: usize,
+}
+
+impl CommandQueue {
+    /// Creates a queue without a limit on the commands waiting per aggregate instance.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Rejects a command with `SqliteAggregateError::CommandQueueFull` rather than queueing it
+    /// once `max_waiting` commands already wait behind the one executing on the same aggregate
+    /// instance, limiting the rate of commands a single, e.g. hot or abused, aggregate instance
+    /// may take up.
+    pub fn with_max_waiting(self, max_waiting: usize) -> Self {
+        Self {
+            max_waiting: Some(max_waiting),
+            ..self
+        }
+    }
+
+    /// Executes at most `max_concurrent` commands at once across all aggregate instances,
+    /// admitting them first come, first served, so that one aggregate instance cannot starve the
+    /// others. The limit is shared by the clones of the queue.
+    pub fn with_max_concurrent(self, max_concurrent: usize) -> Self {
+        Self {
+            slots: Some(Arc::new(Semaphore::new(max_concurrent.max(1)))),
+            ..self
+        }
+    }
+
+    /// Waits until the commands queued before on the aggregate instance `aggregate_id` have
+    /// completed, and for a free slot if the concurrent commands are limited, returning a permit
+    /// to execute the next command. The following command on the aggregate instance waits until
+    /// the permit is dropped.
+    pub async fn lock(&self, aggregate_id: &str) -> Result<CommandPermit, SqliteAggregateError> {
+        let lock = {
+            let mut aggregates = self.aggregates();
+            let queued = aggregates
+                .entry(aggregate_id.to_string())
+                .or_insert_with(|| Queued {
+                    lock: Default::default(),
+                    commands: 0,
+                });
+            if let Some(max_waiting) = self.max_waiting {
+                // one of the queued commands is executing
+                if queued.commands > max_waiting {
+                    return Err(SqliteAggregateError::CommandQueueFull {
+                        aggregate_id: aggregate_id.to_string(),
+                        max_waiting,
+                    });
+                }
+            }
+            queued.commands += 1;
+            queued.lock.clone()
+        };
+        // registered before waiting, so that a command cancelled while waiting leaves the queue
+        let registration = Registration {
+            aggregates: self.aggregates.clone(),
+            aggregate_id: aggregate_id.to_string(),
+        };
+        let guard = lock.lock_owned().await;
+        let slot = match &self.slots {
+            Some(slots) => Some(
+                slots
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(|err| SqliteAggregateError::UnknownError(Box::new(err)))?,
+            ),
+            None => None,
+        };
+        Ok(CommandPermit {
+            _slot: slot,
+            _guard: guard,
+            _registration: registration,
+        })
+    }
+
+    /// The number of commands executing or waiting on the aggregate instance `aggregate_id`.
+    pub fn queued(&self, aggregate_id: &str) -> usize {
+        self.aggregates()
+            .get(aggregate_id)
+            .map_or(0, |queued| queued.commands)
+    }
+
+    fn aggregates(&self) -> MutexGuard<'_, HashMap<String, Queued>> {
+        self.aggregates
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Permits executing a command on an aggregate instance, see `CommandQueue::lock`.
+#[derive(Debug)]
+pub struct CommandPermit {
+    _slot: Option<OwnedSemaphorePermit>,
+    // released before the registration, so that a command never finds the queue of an
+    // aggregate instance removed while it is still held
+    _guard: OwnedMutexGuard<()>,
+    _registration: Registration,
+}
+
+#[derive(Debug)]
+struct Registration {
+    aggregates: Arc<Mutex<HashMap<String, Queued>>>,
+    aggregate_id: String,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut aggregates = self
+            .aggregates
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(queued) = aggregates.get_mut(&self.aggregate_id) {
+            queued.commands -= 1;
+            if queued.commands == 0 {
+                aggregates.remove(&self.aggregate_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::{CommandQueue, SqliteAggregateError};
+
+    #[tokio::test]
+    async fn command_queue() {
+        let queue = CommandQueue::new();
+        let executing = Arc::new(AtomicUsize::new(0));
+        let tasks = (0..8)
+            .map(|i| {
+                let queue = queue.clone();
+                let executing = executing.clone();
+                tokio::spawn(async move {
+                    let _permit = queue.lock(&format!("account-{}", i % 2)).await.unwrap();
+                    let concurrent = executing.fetch_add(1, Ordering::SeqCst) + 1;
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    executing.fetch_sub(1, Ordering::SeqCst);
+                    concurrent
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut max_concurrent = 0;
+        for task in tasks {
+            max_concurrent = max_concurrent.max(task.await.unwrap());
+        }
+        // one command per aggregate instance at a time
+        assert!(max_concurrent <= 2);
+        assert_eq!(0, queue.queued("account-0"));
+        assert!(queue.aggregates().is_empty());
+
+        let queue = queue.with_max_waiting(1);
+        let executing = queue.lock("account-1").await.unwrap();
+        let waiting = {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.lock("account-1").await.map(drop) })
+        };
+        while queue.queued("account-1") < 2 {
+            tokio::task::yield_now().await;
+        }
+        assert!(matches!(
+            queue.lock("account-1").await,
+            Err(SqliteAggregateError::CommandQueueFull { max_waiting: 1, .. })
+        ));
+        assert!(queue.lock("account-2").await.is_ok());
+        drop(executing);
+        waiting.await.unwrap().unwrap();
+        assert_eq!(0, queue.queued("account-1"));
+    }
+
+    #[tokio::test]
+    async fn max_concurrent() {
+        let queue = CommandQueue::new().with_max_concurrent(1);
+        let chatty = queue.lock("chatty").await.unwrap();
+        let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let tasks = ["chatty", "quiet"]
+            .into_iter()
+            .map(|id| {
+                let queue = queue.clone();
+                let order = order.clone();
+                tokio::spawn(async move {
+                    let _permit = queue.lock(id).await.unwrap();
+                    order.lock().unwrap().push(id);
+                })
+            })
+            .collect::<Vec<_>>();
+        while queue.queued("chatty") < 2 || queue.queued("quiet") < 1 {
+            tokio::task::yield_now().await;
+        }
+        drop(chatty);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        // the quiet aggregate instance was ready first, the chatty one lined up behind it
+        assert_eq!(vec!["quiet", "chatty"], *order.lock().unwrap());
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use crate::error::SqliteAggregateError;
+
+/// The event metadata entry recording an event's global position, written when
+/// `SqliteEventRepository::with_commit_receipts` is configured to do so.
+pub const GLOBAL_POSITION_METADATA_KEY: &str = "global_position";
+
+/// The outcome of a successful commit, e.g. for use as an HTTP ETag supporting `If-Match`
+/// concurrency checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommitReceipt {
+    /// The global position (the events table `rowid`) of the last event committed.
+    pub global_position: i64,
+    /// The sequence number of the last event committed, i.e. the aggregate instance's version.
+    pub aggregate_version: usize,
+}
+
+/// Identifies a commit for "read your writes" across requests, see
+/// `SqliteViewRepository::load_at_least`.
+///
+/// A token is formatted as `{aggregate_version}.{global_position}.{aggregate_id}` to be handed to
+/// a client, e.g. in a response header, and parsed back from the client's next request.
+///
+/// ```
+/// use rusqlite_es::{CommitReceipt, ConsistencyToken};
+///
+/// let receipt = CommitReceipt {
+///     global_position: 42,
+///     aggregate_version: 3,
+/// };
+/// let token = ConsistencyToken::new("account-1", receipt);
+/// assert_eq!("3.42.account-1", token.to_string());
+/// assert_eq!(token, "3.42.account-1".parse().unwrap());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsistencyToken {
+    aggregate_id: String,
+    receipt: CommitReceipt,
+}
+
+impl ConsistencyToken {
+    /// Creates the token of a commit to the aggregate instance `aggregate_id`.
+    pub fn new(aggregate_id: &str, receipt: CommitReceipt) -> Self {
+        Self {
+            aggregate_id: aggregate_id.to_string(),
+            receipt,
+        }
+    }
+
+    /// The id of the committed aggregate instance.
+    pub fn aggregate_id(&self) -> &str {
+        &self.aggregate_id
+    }
+
+    /// The version of the aggregate instance after the commit.
+    pub fn aggregate_version(&self) -> usize {
+        self.receipt.aggregate_version
+    }
+
+    /// The global position of the last event committed.
+    pub fn global_position(&self) -> i64 {
+        self.receipt.global_position
+    }
+}
+
+impl Display for ConsistencyToken {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}",
+            self.receipt.aggregate_version, self.receipt.global_position, self.aggregate_id
+        )
+    }
+}
+
+impl FromStr for ConsistencyToken {
+    type Err = SqliteAggregateError;
+
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
+        let invalid = || SqliteAggregateError::UnknownError("invalid consistency token".into());
+        let mut parts = token.splitn(3, '.');
+        let aggregate_version = parts.next().and_then(|part| part.parse().ok());
+        let global_position = parts.next().and_then(|part| part.parse().ok());
+        match (aggregate_version, global_position, parts.next()) {
+            (Some(aggregate_version), Some(global_position), Some(aggregate_id)) => Ok(Self {
+                aggregate_id: aggregate_id.to_string(),
+                receipt: CommitReceipt {
+                    global_position,
+                    aggregate_version,
+                },
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// How commit receipts are recorded, see `SqliteEventRepository::with_commit_receipts`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CommitReceipts {
+    /// Receipts are not recorded.
+    #[default]
+    Disabled,
+    /// Receipts are recorded for retrieval with `SqliteEventRepository::take_commit_receipt`.
+    Recorded,
+    /// Receipts are recorded and each event's global position is also written to its metadata
+    /// under `GLOBAL_POSITION_METADATA_KEY`.
+    RecordedWithMetadata,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct ReceiptLog {
+    receipts: Arc<Mutex<HashMap<(String, String), CommitReceipt>>>,
+}
+
+impl ReceiptLog {
+    pub(crate) fn record(&self, aggregate_type: &str, aggregate_id: &str, receipt: CommitReceipt) {
+        self.receipts.lock().unwrap().insert(
+            (aggregate_type.to_string(), aggregate_id.to_string()),
+            receipt,
+        );
+    }
+
+    pub(crate) fn take(&self, aggregate_type: &str, aggregate_id: &str) -> Option<CommitReceipt> {
+        self.receipts
+            .lock()
+            .unwrap()
+            .remove(&(aggregate_type.to_string(), aggregate_id.to_string()))
+    }
+}
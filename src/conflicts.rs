@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use cqrs_es::persist::SerializedEvent;
+use rusqlite::Connection;
+
+use crate::identifier::TableName;
+
+/// Counts the optimistic lock conflicts of a repository per aggregate instance and optionally
+/// records each conflict in a table, see `SqliteEventRepository::with_conflict_log`.
+///
+/// Conflicts are retried by the caller and otherwise go unnoticed, the counts identify hot
+/// aggregate instances whose commands frequently race and may call for modeling changes. Both
+/// conflicting event commits and snapshot updates are counted.
+///
+/// A `ConflictLog` is cheap to clone, all clones share the same counts, so one clone can be
+/// kept to read the counts of the repository it was passed to.
+///
+/// ```
+/// use rusqlite_es::ConflictLog;
+///
+/// fn report(conflicts: &ConflictLog) {
+///     for hot in conflicts.hottest(10) {
+///         println!("{} {}: {} conflicts", hot.aggregate_type, hot.aggregate_id, hot.conflicts);
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ConflictLog {
+    table: Option<String>,
+    counts: Arc<Mutex<HashMap<(String, String), u64>>>,
+}
+
+/// The number of conflicts of an aggregate instance, see `ConflictLog::hottest`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConflictCount {
+    /// The type of the aggregate.
+    pub aggregate_type: String,
+    /// The id of the aggregate instance.
+    pub aggregate_id: String,
+    /// The number of conflicts counted.
+    pub conflicts: u64,
+}
+
+impl ConflictLog {
+    /// Creates a log that only counts conflicts.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Also records each conflict, with the aggregate type and id, the sequence and metadata of
+    /// the first event of the rejected commit and the time of the conflict, in the provided
+    /// table. The table is created by `SqliteEventRepository::ready`, see `create_table`.
+    pub fn with_table(self, table: &str) -> Self {
+        Self {
+            table: Some(table.to_string()),
+            ..self
+        }
+    }
+
+    /// The total number of conflicts counted.
+    pub fn total(&self) -> u64 {
+        self.counts.lock().unwrap().values().sum()
+    }
+
+    /// The number of conflicts counted for an aggregate instance.
+    pub fn count(&self, aggregate_type: &str, aggregate_id: &str) -> u64 {
+        self.counts
+            .lock()
+            .unwrap()
+            .get(&(aggregate_type.to_string(), aggregate_id.to_string()))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// The `n` aggregate instances with the most conflicts, most conflicts first.
+    pub fn hottest(&self, n: usize) -> Vec<ConflictCount> {
+        let mut counts = self
+            .counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(
+                |((aggregate_type, aggregate_id), conflicts)| ConflictCount {
+                    aggregate_type: aggregate_type.clone(),
+                    aggregate_id: aggregate_id.clone(),
+                    conflicts: *conflicts,
+                },
+            )
+            .collect::<Vec<_>>();
+        counts.sort_by(|a, b| {
+            b.conflicts
+                .cmp(&a.conflicts)
+                .then_with(|| a.aggregate_id.cmp(&b.aggregate_id))
+        });
+        counts.truncate(n);
+        counts
+    }
+
+    /// Returns the DDL creating the conflicts table if it does not yet exist, `None` unless a
+    /// table is configured.
+    pub fn create_table(&self) -> Option<String> {
+        let table = TableName::unchecked(self.table.as_deref()?);
+        Some(format!(
+            "
+CREATE TABLE IF NOT EXISTS {table}
+(
+    id             integer NOT NULL,
+    aggregate_type text    NOT NULL,
+    aggregate_id   text    NOT NULL,
+    sequence       bigint  NOT NULL,
+    metadata       json    NOT NULL,
+    recorded_at    text    NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    PRIMARY KEY (id)
+);"
+        ))
+    }
+
+    // Counts a conflict of the commit of `events` and records it in the table, if configured.
+    // A conflict that cannot be recorded is logged, the original error is still reported.
+    pub(crate) fn record(
+        &self,
+        connection: &Connection,
+        aggregate_type: &str,
+        events: &[SerializedEvent],
+    ) {
+        let Some(event) = events.first() else {
+            return;
+        };
+        *self
+            .counts
+            .lock()
+            .unwrap()
+            .entry((aggregate_type.to_string(), event.aggregate_id.clone()))
+            .or_default() += 1;
+        let Some(table) = &self.table else {
+            return;
+        };
+        let insert = format!(
+            "INSERT INTO {} (aggregate_type, aggregate_id, sequence, metadata) VALUES (?, ?, ?, ?)",
+            TableName::unchecked(table)
+        );
+        if let Err(err) = connection.execute(
+            &insert,
+            (
+                aggregate_type,
+                &event.aggregate_id,
+                event.sequence as i64,
+                &event.metadata,
+            ),
+        ) {
+            tracing::warn!(
+                target: "rusqlite_es::conflicts",
+                aggregate_type,
+                aggregate_id = %event.aggregate_id,
+                error = %err,
+                "could not record an optimistic lock conflict"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde_json::json;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, ConflictCount, ConflictLog, SqliteEventRepository};
+
+    #[tokio::test]
+    async fn conflicts_are_counted_and_recorded() {
+        let conflicts = ConflictLog::new().with_table("conflicts");
+        let repo = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .with_conflict_log(conflicts.clone())
+            .ready()
+            .await
+            .unwrap();
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = json!({"user": "alice"});
+        repo.persist::<TestAggregate>(&[created.clone()], None)
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            assert!(repo
+                .persist::<TestAggregate>(&[created.clone()], None)
+                .await
+                .is_err());
+        }
+
+        assert_eq!(2, conflicts.total());
+        assert_eq!(2, conflicts.count("TestAggregate", &id));
+        assert_eq!(
+            vec![ConflictCount {
+                aggregate_type: "TestAggregate".to_string(),
+                aggregate_id: id.clone(),
+                conflicts: 2,
+            }],
+            conflicts.hottest(5)
+        );
+        let recorded: Vec<(String, i64, serde_json::Value)> = repo
+            .with_connection(|conn| {
+                conn.prepare("SELECT aggregate_id, sequence, metadata FROM conflicts")?
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                    .collect()
+            })
+            .unwrap();
+        assert_eq!(2, recorded.len());
+        assert_eq!((id, 1, json!({"user": "alice"})), recorded[0]);
+    }
+}
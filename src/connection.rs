@@ -0,0 +1,581 @@
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{Connection, TransactionBehavior};
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::sync::{Mutex, MutexGuard};
+use tokio::task::block_in_place;
+
+use crate::error::SqliteAggregateError;
+use crate::{SqliteEventRepository, SqliteViewRepository};
+
+/// Provides the repositories with connections to an SQLite database.
+///
+/// This is implemented for r2d2's `Pool<SqliteConnectionManager>`, the default used throughout
+/// this crate, and can be implemented to plug in any other pool or connection manager.
+/// Connections are checked out synchronously, repositories only hold them while executing
+/// statements and never across an `.await`.
+///
+/// ```
+/// use std::sync::{Arc, Mutex, MutexGuard};
+/// use rusqlite::Connection;
+/// use rusqlite_es::{ConnectionProvider, SqliteAggregateError};
+///
+/// #[derive(Clone)]
+/// struct SharedConnection(Arc<Mutex<Connection>>);
+///
+/// impl ConnectionProvider for SharedConnection {
+///     type Connection<'a> = MutexGuard<'a, Connection>;
+///
+///     fn connection(&self) -> Result<Self::Connection<'_>, SqliteAggregateError> {
+///         self.0
+///             .lock()
+///             .map_err(|err| SqliteAggregateError::ConnectionError(err.to_string().into()))
+///     }
+/// }
+/// ```
+pub trait ConnectionProvider: Clone + Send + Sync + 'static {
+    /// A connection checked out from the provider, it is returned to the provider when dropped.
+    type Connection<'a>: DerefMut<Target = Connection>
+    where
+        Self: 'a;
+
+    /// Checks out a connection, blocking until one is available.
+    fn connection(&self) -> Result<Self::Connection<'_>, SqliteAggregateError>;
+}
+
+impl ConnectionProvider for Pool<SqliteConnectionManager> {
+    type Connection<'a> = PooledConnection<SqliteConnectionManager>;
+
+    fn connection(&self) -> Result<Self::Connection<'_>, SqliteAggregateError> {
+        Ok(self.get()?)
+    }
+}
+
+/// Runs `f` with a connection checked out from `pool`, for the repositories' `with_connection`.
+///
+/// A connection returned to the pool inside a transaction would leak that transaction into
+/// whatever uses the connection next, so a transaction left open by `f` is rolled back and
+/// reported as an error. A connection checked out within a transaction, e.g. that of a
+/// `TestTransaction`, is left as is.
+pub(crate) fn with_checked_connection<P, F, T>(pool: &P, f: F) -> Result<T, SqliteAggregateError>
+where
+    P: ConnectionProvider,
+    F: FnOnce(&mut Connection) -> Result<T, rusqlite::Error>,
+{
+    let mut connection = pool.connection()?;
+    let checked_out_in_transaction = !connection.is_autocommit();
+    let result = f(&mut connection);
+    if !checked_out_in_transaction && !connection.is_autocommit() {
+        connection.execute_batch("ROLLBACK")?;
+        return Err(SqliteAggregateError::UnknownError(
+            "a transaction was left open on the connection and has been rolled back".into(),
+        ));
+    }
+    Ok(result?)
+}
+
+// Runs `f` within a transaction with the given behavior, or within a savepoint if the connection
+// is already within a transaction, e.g. that of a `TestTransaction`. The transaction is committed
+// if `f` succeeds and rolled back otherwise.
+pub(crate) fn in_transaction<T>(
+    connection: &mut Connection,
+    behavior: TransactionBehavior,
+    f: impl FnOnce(&Connection) -> Result<T, SqliteAggregateError>,
+) -> Result<T, SqliteAggregateError> {
+    if connection.is_autocommit() {
+        let tx = connection.transaction_with_behavior(behavior)?;
+        let value = f(&tx)?;
+        tx.commit()?;
+        Ok(value)
+    } else {
+        let savepoint = connection.savepoint()?;
+        let value = f(&savepoint)?;
+        savepoint.commit()?;
+        Ok(value)
+    }
+}
+
+/// A `ConnectionProvider` that owns a single SQLite connection, serializing all access to it.
+///
+/// This is intended for constrained environments, e.g. CLI tools or embedded devices, where a
+/// thread-based connection pool is unnecessary. The connection is guarded by an async mutex, but
+/// the repositories check it out synchronously: while the connection is in use, e.g. by an open
+/// event stream, a task checking it out waits within `block_in_place` on a multi-threaded
+/// runtime, so that the worker's other tasks keep running, and blocks the runtime on a
+/// current-thread runtime. On a current-thread runtime the wait is bounded by the checkout
+/// timeout, see `with_checkout_timeout`, after which the checkout fails with
+/// `SqliteAggregateError::ConnectionError`.
+///
+/// _Note: an open event stream holds the connection until it completes, so a stream should be
+/// fully consumed (or dropped) before committing further events. On a current-thread runtime,
+/// a call made while a stream that is not being consumed holds the connection can never succeed,
+/// the stream being unable to make progress while the runtime waits, and fails once the
+/// checkout timeout has elapsed._
+#[derive(Clone)]
+pub struct SingleConnection {
+    connection: Arc<Mutex<Connection>>,
+    checkout_timeout: Duration,
+}
+
+// How long a current-thread runtime waits by default for a `SingleConnection` in use.
+const DEFAULT_CHECKOUT_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl SingleConnection {
+    /// Wraps an existing connection.
+    pub fn new(connection: Connection) -> Self {
+        Self {
+            connection: Arc::new(Mutex::new(connection)),
+            checkout_timeout: DEFAULT_CHECKOUT_TIMEOUT,
+        }
+    }
+
+    /// Configures how long a checkout waits on a current-thread runtime for the connection to be
+    /// released, by default five seconds.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use rusqlite_es::SingleConnection;
+    ///
+    /// let connection = SingleConnection::open_in_memory()
+    ///     .unwrap()
+    ///     .with_checkout_timeout(Duration::from_secs(1));
+    /// # drop(connection);
+    /// ```
+    pub fn with_checkout_timeout(self, checkout_timeout: Duration) -> Self {
+        Self {
+            checkout_timeout,
+            ..self
+        }
+    }
+
+    /// Opens a connection to the database file at `path` using write-ahead logging.
+    ///
+    /// ```
+    /// use rusqlite_es::{SingleConnection, SingleConnectionEventRepository};
+    ///
+    /// let connection = SingleConnection::open("single_connection_example.db").unwrap();
+    /// let repo = SingleConnectionEventRepository::new(connection);
+    /// # drop(repo);
+    /// # std::fs::remove_file("single_connection_example.db").unwrap();
+    /// ```
+    pub fn open<T: AsRef<Path>>(path: T) -> Result<Self, SqliteAggregateError> {
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "wal")?;
+        connection.pragma_update(None, "synchronous", "normal")?;
+        Ok(Self::new(connection))
+    }
+
+    /// Opens a connection to a new in-memory database.
+    pub fn open_in_memory() -> Result<Self, SqliteAggregateError> {
+        Ok(Self::new(Connection::open_in_memory()?))
+    }
+}
+
+// How often a current-thread runtime checks whether a `SingleConnection` in use was released.
+const SINGLE_CONNECTION_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+impl ConnectionProvider for SingleConnection {
+    type Connection<'a> = MutexGuard<'a, Connection>;
+
+    fn connection(&self) -> Result<Self::Connection<'_>, SqliteAggregateError> {
+        if let Ok(connection) = self.connection.try_lock() {
+            return Ok(connection);
+        }
+        match Handle::try_current().map(|handle| handle.runtime_flavor()) {
+            Ok(RuntimeFlavor::MultiThread) => {
+                Ok(block_in_place(|| self.connection.blocking_lock()))
+            }
+            // neither `blocking_lock` nor `block_in_place` may be called on the thread of a
+            // current-thread runtime, a holder running as a task on that thread is never polled
+            // while waiting, so the wait is bounded
+            Ok(_) => {
+                let deadline = Instant::now() + self.checkout_timeout;
+                while Instant::now() < deadline {
+                    std::thread::sleep(SINGLE_CONNECTION_POLL_INTERVAL);
+                    if let Ok(connection) = self.connection.try_lock() {
+                        return Ok(connection);
+                    }
+                }
+                Err(SqliteAggregateError::ConnectionError(
+                    format!(
+                        "the single connection was not released within {:?}, e.g. it is held by an event stream that is not being consumed",
+                        self.checkout_timeout
+                    )
+                    .into(),
+                ))
+            }
+            Err(_) => Ok(self.connection.blocking_lock()),
+        }
+    }
+}
+
+// The number of virtual machine instructions between calls of the analytics progress handler.
+const ANALYTICS_PROGRESS_OPS: i32 = 1000;
+
+// How long an analytics query pauses at a time while the repository writes.
+const ANALYTICS_WRITE_BACKOFF: Duration = Duration::from_millis(1);
+
+/// A read-only connection for ad-hoc reporting queries, see
+/// `SqliteEventRepository::analytics_connection`.
+///
+/// The connection is restored to its regular settings when dropped, before it is returned to the
+/// pool.
+pub struct AnalyticsConnection<'a, P: ConnectionProvider + 'a> {
+    connection: P::Connection<'a>,
+    checked_out_in_transaction: bool,
+}
+
+impl<'a, P: ConnectionProvider + 'a> AnalyticsConnection<'a, P> {
+    fn new(
+        connection: P::Connection<'a>,
+        timeout: Duration,
+        active_writes: Arc<AtomicUsize>,
+    ) -> Result<Self, SqliteAggregateError> {
+        connection.pragma_update(None, "query_only", true)?;
+        // outside of write-ahead logging a paused reader would hold up the writer it yields to
+        let journal_mode: String =
+            connection.pragma_query_value(None, "journal_mode", |row| row.get(0))?;
+        let yields_to_writes = journal_mode.eq_ignore_ascii_case("wal");
+        let deadline = Instant::now() + timeout;
+        connection.progress_handler(
+            ANALYTICS_PROGRESS_OPS,
+            Some(move || {
+                while yields_to_writes
+                    && active_writes.load(Ordering::SeqCst) > 0
+                    && Instant::now() < deadline
+                {
+                    std::thread::sleep(ANALYTICS_WRITE_BACKOFF);
+                }
+                Instant::now() >= deadline
+            }),
+        );
+        let checked_out_in_transaction = !connection.is_autocommit();
+        Ok(Self {
+            connection,
+            checked_out_in_transaction,
+        })
+    }
+}
+
+impl<'a, P: ConnectionProvider + 'a> Deref for AnalyticsConnection<'a, P> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.connection
+    }
+}
+
+impl<'a, P: ConnectionProvider + 'a> Drop for AnalyticsConnection<'a, P> {
+    fn drop(&mut self) {
+        self.connection.progress_handler(0, None::<fn() -> bool>);
+        if !self.checked_out_in_transaction && !self.connection.is_autocommit() {
+            let _ = self.connection.execute_batch("ROLLBACK");
+        }
+        let _ = self.connection.pragma_update(None, "query_only", false);
+    }
+}
+
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    /// Checks out a connection for ad-hoc reporting queries that cannot modify or block the
+    /// operational store.
+    ///
+    /// The connection is read-only (`PRAGMA query_only`), any statement writing to the database
+    /// fails. Queries are interrupted once `timeout` has elapsed since the connection was checked
+    /// out, failing with `rusqlite::ErrorCode::OperationInterrupted`, so that a long-running
+    /// report cannot hold on to the connection or, outside of write-ahead logging, block writers.
+    ///
+    /// In write-ahead log mode, queries run at a lower priority than the repository's writes:
+    /// while a write transaction of the repository or one of its clones is in progress, a query
+    /// pauses between batches of instructions, leaving the CPU and disk to the writer. Writes
+    /// made by other repositories or processes are not noticed. Outside of write-ahead logging
+    /// queries do not pause, since a reader holds up writers for as long as it runs.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use rusqlite_es::{SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// fn events_per_type(repo: &SqliteEventRepository) -> Result<Vec<(String, i64)>, SqliteAggregateError> {
+    ///     let connection = repo.analytics_connection(Duration::from_secs(5))?;
+    ///     let mut statement = connection
+    ///         .prepare("SELECT event_type, count(*) FROM events GROUP BY event_type")?;
+    ///     let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    ///     Ok(rows.collect::<Result<_, _>>()?)
+    /// }
+    /// ```
+    pub fn analytics_connection(
+        &self,
+        timeout: Duration,
+    ) -> Result<AnalyticsConnection<'_, P>, SqliteAggregateError> {
+        AnalyticsConnection::new(self.pool().connection()?, timeout, self.active_writes())
+    }
+}
+
+/// An event repository using a single connection rather than a connection pool.
+pub type SingleConnectionEventRepository = SqliteEventRepository<SingleConnection>;
+
+/// A view repository using a single connection rather than a connection pool.
+pub type SingleConnectionViewRepository<V, A> = SqliteViewRepository<V, A, SingleConnection>;
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use cqrs_es::persist::{
+        PersistedEventRepository, PersistenceError, ViewContext, ViewRepository,
+    };
+    use rusqlite::ErrorCode;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, TestView, Tested,
+        TEST_CONNECTION_STRING,
+    };
+    use crate::{
+        default_sqlite_pool, ConnectionProvider, SingleConnection, SingleConnectionEventRepository,
+        SingleConnectionViewRepository, SqliteAggregateError, SqliteEventRepository,
+    };
+
+    #[tokio::test]
+    async fn single_connection_repositories() {
+        let connection = SingleConnection::open_in_memory().unwrap();
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        connection
+            .connection()
+            .unwrap()
+            .execute_batch(contents.as_str())
+            .unwrap();
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let event_repo = SingleConnectionEventRepository::new(connection.clone());
+        event_repo
+            .insert_events::<TestAggregate>(&[
+                test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() })),
+                test_event_envelope(
+                    &id,
+                    2,
+                    TestEvent::Tested(Tested {
+                        test_name: "a test was run".to_string(),
+                    }),
+                ),
+            ])
+            .await
+            .unwrap();
+        let events = event_repo.get_events::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(2, events.len());
+
+        let mut stream = event_repo.stream_everything().await.unwrap();
+        let mut found_in_stream = 0;
+        while let Some(event) = stream.next().await {
+            event.unwrap();
+            found_in_stream += 1;
+        }
+        assert_eq!(3, found_in_stream);
+
+        let view_repo =
+            SingleConnectionViewRepository::<TestView, TestAggregate>::new("test_view", connection);
+        let view = TestView {
+            events: vec![TestEvent::Created(Created { id: id.clone() })],
+        };
+        view_repo
+            .update_view(view.clone(), ViewContext::new(id.clone(), 0))
+            .await
+            .unwrap();
+        assert_eq!(Some(view), view_repo.load(&id).await.unwrap());
+    }
+
+    async fn contended_single_connection() {
+        let connection = SingleConnection::open_in_memory().unwrap();
+        let held = connection.clone();
+        let holder = std::thread::spawn(move || {
+            let _guard = held.connection().unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+        });
+        std::thread::sleep(Duration::from_millis(10));
+        let one: i64 = connection
+            .connection()
+            .unwrap()
+            .query_row("SELECT 1", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(1, one);
+        holder.join().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn single_connection_contention_multi_thread() {
+        contended_single_connection().await;
+    }
+
+    #[tokio::test]
+    async fn single_connection_contention_current_thread() {
+        contended_single_connection().await;
+    }
+
+    #[tokio::test]
+    async fn single_connection_held_by_stream() {
+        let connection = SingleConnection::open_in_memory()
+            .unwrap()
+            .with_checkout_timeout(Duration::from_millis(100));
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        connection
+            .connection()
+            .unwrap()
+            .execute_batch(contents.as_str())
+            .unwrap();
+        let id = uuid::Uuid::new_v4().to_string();
+        let event_repo =
+            SingleConnectionEventRepository::new(connection).with_streaming_channel_size(1);
+        let events = (1..=4)
+            .map(|sequence| {
+                test_event_envelope(
+                    &id,
+                    sequence,
+                    TestEvent::Tested(Tested {
+                        test_name: format!("test {sequence}"),
+                    }),
+                )
+            })
+            .collect::<Vec<_>>();
+        event_repo
+            .insert_events::<TestAggregate>(&events)
+            .await
+            .unwrap();
+
+        // the stream fills its channel and holds the connection while it is not consumed
+        let mut stream = event_repo.stream_everything().await.unwrap();
+        stream.next().await.unwrap().unwrap();
+        match event_repo.get_events::<TestAggregate>(&id).await {
+            Err(PersistenceError::ConnectionError(_)) => {}
+            result => panic!("expected the checkout to time out, found {:?}", result),
+        }
+        drop(stream);
+        assert_eq!(
+            4,
+            event_repo
+                .get_events::<TestAggregate>(&id)
+                .await
+                .unwrap()
+                .len()
+        );
+    }
+
+    #[test]
+    fn with_connection() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        pool.get()
+            .unwrap()
+            .execute_batch(contents.as_str())
+            .unwrap();
+        let event_repo = SqliteEventRepository::new(pool);
+
+        let journal_mode: String = event_repo
+            .with_connection(|conn| conn.pragma_query_value(None, "journal_mode", |row| row.get(0)))
+            .unwrap();
+        assert_eq!("memory", journal_mode);
+
+        match event_repo.with_connection(|conn| conn.execute_batch("BEGIN")) {
+            Err(SqliteAggregateError::UnknownError(_)) => {}
+            _ => panic!("expected the open transaction to be reported"),
+        }
+        let autocommit = event_repo
+            .with_connection(|conn| Ok(conn.is_autocommit()))
+            .unwrap();
+        assert!(autocommit);
+    }
+
+    #[test]
+    fn analytics_connection() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        pool.get()
+            .unwrap()
+            .execute_batch(contents.as_str())
+            .unwrap();
+        let event_repo = SqliteEventRepository::new(pool);
+
+        let connection = event_repo
+            .analytics_connection(Duration::from_millis(100))
+            .unwrap();
+        let count: i64 = connection
+            .query_row("SELECT count(*) FROM events", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(1, count);
+        assert!(connection.execute("DELETE FROM events", []).is_err());
+        let endless = connection.query_row(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n) SELECT max(i) FROM n",
+            [],
+            |row| row.get::<_, i64>(0),
+        );
+        match endless {
+            Err(rusqlite::Error::SqliteFailure(err, _))
+                if err.code == ErrorCode::OperationInterrupted => {}
+            result => panic!("expected the query to be interrupted, found {:?}", result),
+        }
+        drop(connection);
+
+        // the pooled connection is restored once the analytics connection is dropped
+        let query_only: bool = event_repo
+            .with_connection(|conn| conn.pragma_query_value(None, "query_only", |row| row.get(0)))
+            .unwrap();
+        assert!(!query_only);
+        event_repo
+            .with_connection(|conn| {
+                conn.query_row(
+                    "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 100000) SELECT max(i) FROM n",
+                    [],
+                    |row| row.get::<_, i64>(0),
+                )
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn analytics_connection_yields_to_writes() {
+        let path = std::env::temp_dir().join(format!("{}.db", uuid::Uuid::new_v4()));
+        let pool = default_sqlite_pool(path.to_str().unwrap());
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        pool.get()
+            .unwrap()
+            .execute_batch(contents.as_str())
+            .unwrap();
+        let event_repo = SqliteEventRepository::new(pool);
+        let report = "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 100000) SELECT max(i) FROM n";
+
+        // a query pauses while the repository writes, here until its timeout
+        let active_writes = event_repo.active_writes();
+        active_writes.fetch_add(1, Ordering::SeqCst);
+        let connection = event_repo
+            .analytics_connection(Duration::from_millis(50))
+            .unwrap();
+        let paused = connection.query_row(report, [], |row| row.get::<_, i64>(0));
+        match paused {
+            Err(rusqlite::Error::SqliteFailure(err, _))
+                if err.code == ErrorCode::OperationInterrupted => {}
+            result => panic!("expected the query to be interrupted, found {:?}", result),
+        }
+        drop(connection);
+
+        active_writes.fetch_sub(1, Ordering::SeqCst);
+        let connection = event_repo
+            .analytics_connection(Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(
+            100000,
+            connection
+                .query_row(report, [], |row| row.get::<_, i64>(0))
+                .unwrap()
+        );
+        drop(connection);
+        drop(event_repo);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, Mutex};
+
+use rusqlite::Connection;
+
+// The initializers registered with `register_connection_init`, in the order of registration.
+static CONNECTION_INITS: Mutex<Vec<ConnectionInit>> = Mutex::new(Vec::new());
+
+type InitFn = dyn Fn(&mut Connection) -> Result<(), rusqlite::Error> + Send + Sync;
+
+/// Prepares each connection opened by a pool built with `default_sqlite_pool` or
+/// `sqlite_pool`, see `register_connection_init` and `PoolOptions::with_init`.
+#[derive(Clone)]
+pub struct ConnectionInit(Arc<InitFn>);
+
+impl ConnectionInit {
+    /// Wraps a closure run on each connection once it is opened and switched to write-ahead
+    /// logging, before it is handed out by the pool.
+    pub fn new<F>(init: F) -> Self
+    where
+        F: Fn(&mut Connection) -> Result<(), rusqlite::Error> + Send + Sync + 'static,
+    {
+        Self(Arc::new(init))
+    }
+
+    pub(crate) fn run(&self, conn: &mut Connection) -> Result<(), rusqlite::Error> {
+        (self.0)(conn)
+    }
+}
+
+impl Debug for ConnectionInit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ConnectionInit")
+    }
+}
+
+/// Registers a closure run on every connection of the pools built afterwards with
+/// `default_sqlite_pool` or `sqlite_pool`, e.g. to register custom functions or collations or to
+/// load extensions such as sqlean, without building the pool by hand. Closures run in the order
+/// of their registration, before those of the pool's `PoolOptions`. An error returned by a
+/// closure fails building the pool, or checking out a new connection from it.
+///
+/// Deterministic SQL functions are registered with `register_sql_function`. Collations require
+/// the `collation` feature of rusqlite, extensions its `load_extension` feature, enabled in the
+/// application's own manifest.
+///
+/// ```
+/// use rusqlite_es::{default_sqlite_pool, register_connection_init};
+///
+/// register_connection_init(|conn| conn.pragma_update(None, "cache_size", -64_000));
+/// let pool = default_sqlite_pool(":memory:");
+/// ```
+pub fn register_connection_init<F>(init: F)
+where
+    F: Fn(&mut Connection) -> Result<(), rusqlite::Error> + Send + Sync + 'static,
+{
+    register_init(ConnectionInit::new(init));
+}
+
+pub(crate) fn register_init(init: ConnectionInit) {
+    CONNECTION_INITS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .push(init);
+}
+
+// The initializers registered so far, applied to a pool being built.
+pub(crate) fn registered_connection_inits() -> Vec<ConnectionInit> {
+    CONNECTION_INITS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::testing::tests::TEST_CONNECTION_STRING;
+    use crate::{default_sqlite_pool, register_connection_init, sqlite_pool, PoolOptions};
+
+    static INITIALIZED: AtomicUsize = AtomicUsize::new(0);
+
+    #[test]
+    fn connection_inits() {
+        register_connection_init(|_| {
+            INITIALIZED.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+        let before = INITIALIZED.load(Ordering::SeqCst);
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        pool.get().unwrap();
+        assert!(INITIALIZED.load(Ordering::SeqCst) > before);
+
+        let options = PoolOptions::new().with_init(|conn| {
+            conn.pragma_update(None, "foreign_keys", true)?;
+            conn.pragma_update(None, "user_version", 7)
+        });
+        let pool = sqlite_pool(TEST_CONNECTION_STRING, options).unwrap();
+        let conn = pool.get().unwrap();
+        let (foreign_keys, user_version): (bool, i64) = (
+            conn.pragma_query_value(None, "foreign_keys", |row| row.get(0))
+                .unwrap(),
+            conn.pragma_query_value(None, "user_version", |row| row.get(0))
+                .unwrap(),
+        );
+        assert_eq!((true, 7), (foreign_keys, user_version));
+
+        // a failing initializer fails building the pool
+        let options = PoolOptions::new().with_init(|conn| conn.execute_batch("not sql"));
+        assert!(sqlite_pool(TEST_CONNECTION_STRING, options).is_err());
+    }
+}
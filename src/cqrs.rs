@@ -1,29 +1,193 @@
-use cqrs_es::persist::PersistedEventStore;
-use cqrs_es::{Aggregate, CqrsFramework, Query};
+use std::panic::RefUnwindSafe;
+use std::sync::Arc;
 
-use crate::{SqliteCqrs, SqliteEventRepository};
-use r2d2::Pool;
+use cqrs_es::persist::{GenericQuery, PersistedEventStore};
+use cqrs_es::{Aggregate, CqrsFramework, Query, View};
+
+use crate::connection_init::registered_connection_inits;
+use crate::encryption::key_init;
+use crate::sql_functions::sql_function;
+use crate::{
+    ConnectionInit, KeyProvider, QueryRegistry, SqliteAggregateError, SqliteCqrs,
+    SqliteEventRepository, SqliteGenericQuery, SqliteViewRepository,
+};
+use r2d2::{ManageConnection, Pool};
 use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::functions::Context;
+use rusqlite::{Connection, ToSql};
+
+/// The minimum SQLite version required by the SQL this crate generates, JSON functions are
+/// built into SQLite from this version on.
+pub const MINIMUM_SQLITE_VERSION: &str = "3.38.0";
+const MINIMUM_SQLITE_VERSION_NUMBER: i32 = 3_038_000;
+
+/// Verifies that the linked SQLite library meets `MINIMUM_SQLITE_VERSION`. This always succeeds
+/// with the `bundled` feature enabled, it is intended for builds linking a system library.
+///
+/// ```
+/// use rusqlite_es::verify_sqlite_version;
+///
+/// verify_sqlite_version().expect("SQLite library is too old");
+/// ```
+pub fn verify_sqlite_version() -> Result<(), SqliteAggregateError> {
+    if rusqlite::version_number() < MINIMUM_SQLITE_VERSION_NUMBER {
+        return Err(SqliteAggregateError::UnsupportedSqliteVersion {
+            required: MINIMUM_SQLITE_VERSION,
+            found: rusqlite::version(),
+        });
+    }
+    Ok(())
+}
 
 /// A convenience method for building a simple connection pool for an SQLite database.
 /// A connection pool is needed for both the event and view repositories.
 ///
+/// The pool is sized as `sqlite_pool` sizes it by default: databases in WAL mode get a
+/// connection per CPU for reading in addition to the one writing, other databases, e.g.
+/// in-memory ones, a single connection.
+///
 /// ```
 /// use r2d2::Pool;
 /// use r2d2_sqlite::SqliteConnectionManager;
-/// use sqlite_es::default_sqlite_pool;
+/// use rusqlite_es::default_sqlite_pool;
 ///
 /// let connection_string = "test.db";
 /// let pool: Pool<SqliteConnectionManager> = default_sqlite_pool(connection_string);
 /// ```
 pub fn default_sqlite_pool(connection_string: &str) -> Pool<SqliteConnectionManager> {
-    let manager = SqliteConnectionManager::file(connection_string)
-        .with_init(|conn| conn.pragma_update(None, "journal_mode", "wal"))
-        .with_init(|conn| conn.pragma_update(None, "synchronous", "normal"));
-    Pool::builder()
-        .max_size(1)
-        .build(manager)
-        .expect("unable to build pool")
+    sqlite_pool(connection_string, PoolOptions::new()).expect("unable to build pool")
+}
+
+/// The sizing and connection initialization of a pool built by `sqlite_pool`.
+#[derive(Debug, Clone, Default)]
+pub struct PoolOptions {
+    readers: Option<u32>,
+    key: Option<ConnectionInit>,
+    inits: Vec<ConnectionInit>,
+}
+
+impl PoolOptions {
+    /// Sizes the pool after the journal mode of the database and the number of CPUs.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Holds up to `readers` connections in addition to the one writing rather than one per
+    /// CPU, if the database is in WAL mode. Zero limits the pool to a single connection.
+    pub fn with_readers(self, readers: u32) -> Self {
+        Self {
+            readers: Some(readers),
+            ..self
+        }
+    }
+
+    /// Keys each connection of the pool to an encrypted database with the key supplied by
+    /// `key_provider`, see `apply_key`. The key is applied before any other statement is run on
+    /// the connection, as SQLCipher requires.
+    pub fn with_key<K: KeyProvider + 'static>(self, key_provider: K) -> Self {
+        Self {
+            key: Some(key_init(key_provider)),
+            ..self
+        }
+    }
+
+    /// Runs `init` on each connection of the pool, after the closures registered with
+    /// `register_connection_init`, e.g. to register a function only this pool's queries use.
+    pub fn with_init<F>(mut self, init: F) -> Self
+    where
+        F: Fn(&mut Connection) -> Result<(), rusqlite::Error> + Send + Sync + 'static,
+    {
+        self.inits.push(ConnectionInit::new(init));
+        self
+    }
+
+    /// Registers a deterministic SQL function on each connection of the pool, as
+    /// `register_sql_function` does for all pools.
+    pub fn with_function<F, T>(mut self, name: &str, n_args: i32, function: F) -> Self
+    where
+        F: Fn(&Context<'_>) -> Result<T, rusqlite::Error> + Send + Sync + RefUnwindSafe + 'static,
+        T: ToSql,
+    {
+        self.inits.push(sql_function(name, n_args, function));
+        self
+    }
+
+    /// Loads the runtime-loadable extension at `path` into each connection of the pool, e.g.
+    /// sqlean or an encryption extension, calling its entry point `entry_point` or the one
+    /// derived from its file name if `None`. Loading is disabled again once the extension is
+    /// loaded, so SQL run on the connection cannot load further ones. Whether the features
+    /// an extension provides are available is reported by `SqliteEventRepository::capabilities`.
+    ///
+    /// # Safety
+    ///
+    /// The extension runs arbitrary native code within the process, it must be trusted as
+    /// any other native dependency, see `rusqlite::Connection::load_extension`.
+    #[cfg(feature = "load_extension")]
+    #[allow(unsafe_code)]
+    pub unsafe fn with_extension(
+        mut self,
+        path: impl AsRef<std::path::Path>,
+        entry_point: Option<&str>,
+    ) -> Self {
+        self.inits.push(crate::capabilities::extension(
+            path.as_ref().to_path_buf(),
+            entry_point.map(str::to_string),
+        ));
+        self
+    }
+}
+
+/// Builds a connection pool for an SQLite database, switching its connections to write-ahead
+/// logging and sizing it after the journal mode the database ends up in.
+///
+/// With write-ahead logging, readers do not block the writer nor each other, the pool then
+/// holds a connection per CPU for reading in addition to the one writing, as SQLite lets a
+/// single connection write at a time anyway. Databases that cannot use write-ahead logging,
+/// e.g. in-memory databases, of which every connection would open a separate one, get a single
+/// connection. Connections are opened as they are needed.
+///
+/// ```
+/// use r2d2::Pool;
+/// use r2d2_sqlite::SqliteConnectionManager;
+/// use rusqlite_es::{sqlite_pool, PoolOptions, SqliteAggregateError};
+///
+/// fn pool() -> Result<Pool<SqliteConnectionManager>, SqliteAggregateError> {
+///     sqlite_pool("test.db", PoolOptions::new().with_readers(4))
+/// }
+/// ```
+pub fn sqlite_pool(
+    connection_string: &str,
+    options: PoolOptions,
+) -> Result<Pool<SqliteConnectionManager>, SqliteAggregateError> {
+    let mut inits = registered_connection_inits();
+    inits.extend(options.inits);
+    // a manager runs a single initializer, a later `with_init` replaces an earlier one
+    let key = options.key;
+    let manager = SqliteConnectionManager::file(connection_string).with_init(move |conn| {
+        if let Some(key) = &key {
+            key.run(conn)?;
+        }
+        conn.pragma_update(None, "journal_mode", "wal")?;
+        conn.pragma_update(None, "synchronous", "normal")?;
+        inits.iter().try_for_each(|init| init.run(conn))
+    });
+    let journal_mode: String =
+        manager
+            .connect()?
+            .pragma_query_value(None, "journal_mode", |row| row.get(0))?;
+    let max_size = match journal_mode.as_str() {
+        "wal" => {
+            let readers = options.readers.unwrap_or_else(|| {
+                std::thread::available_parallelism().map_or(1, |cpus| cpus.get() as u32)
+            });
+            readers + 1
+        }
+        _ => 1,
+    };
+    Ok(Pool::builder()
+        .max_size(max_size)
+        .min_idle(Some(1))
+        .build(manager)?)
 }
 
 /// A convenience function for creating a CqrsFramework from a database connection pool
@@ -41,6 +205,19 @@ where
     CqrsFramework::new(store, query_processor, services)
 }
 
+/// A convenience function for creating a CqrsFramework dispatching to the queries of a
+/// `QueryRegistry`, which may be appended to after the framework was created.
+pub fn sqlite_cqrs_with_registry<A>(
+    pool: Pool<SqliteConnectionManager>,
+    queries: QueryRegistry<A>,
+    services: A::Services,
+) -> SqliteCqrs<A>
+where
+    A: Aggregate + 'static,
+{
+    sqlite_cqrs(pool, vec![Box::new(queries)], services)
+}
+
 /// A convenience function for creating a CqrsFramework using a snapshot store.
 pub fn sqlite_snapshot_cqrs<A>(
     pool: Pool<SqliteConnectionManager>,
@@ -57,6 +234,27 @@ where
 }
 
 /// A convenience function for creating a CqrsFramework using an aggregate store.
+///
+/// An aggregate store serializes the aggregate into its snapshot on every commit and loads it
+/// from the snapshot alone, without reading events. The stored aggregate can be loaded outside
+/// of the framework through an event store of the same kind, e.g. by a reporting job.
+///
+/// ```
+/// # use cqrs_es::doc::MyAggregate;
+/// use cqrs_es::persist::PersistedEventStore;
+/// use cqrs_es::{AggregateContext, AggregateError, EventStore};
+/// use rusqlite_es::SqliteEventRepository;
+///
+/// async fn print_aggregate(
+///     repo: SqliteEventRepository,
+///     id: &str,
+/// ) -> Result<(), AggregateError<<MyAggregate as cqrs_es::Aggregate>::Error>> {
+///     let store = PersistedEventStore::<_, MyAggregate>::new_aggregate_store(repo);
+///     let context = store.load_aggregate(id).await?;
+///     println!("{:?}", context.aggregate());
+///     Ok(())
+/// }
+/// ```
 pub fn sqlite_aggregate_cqrs<A>(
     pool: Pool<SqliteConnectionManager>,
     query_processor: Vec<Box<dyn Query<A>>>,
@@ -70,14 +268,69 @@ where
     CqrsFramework::new(store, query_processor, services)
 }
 
+/// A convenience function for creating a GenericQuery storing its views in the SQLite table
+/// `view_name`, see `SqliteViewRepository::new`. Errors are passed to the handler configured with
+/// `GenericQuery::use_error_handler`.
+///
+/// ```
+/// # use cqrs_es::doc::MyAggregate;
+/// # use cqrs_es::persist::doc::MyView;
+/// use r2d2::Pool;
+/// use r2d2_sqlite::SqliteConnectionManager;
+/// use rusqlite_es::{sqlite_generic_query, SqliteGenericQuery};
+///
+/// fn configure_query(pool: Pool<SqliteConnectionManager>) -> SqliteGenericQuery<MyView, MyAggregate> {
+///     let mut query = sqlite_generic_query("my_view_table", pool);
+///     query.use_error_handler(Box::new(|err| eprintln!("my_view_table is stale: {err}")));
+///     query
+/// }
+/// ```
+pub fn sqlite_generic_query<V, A>(
+    view_name: &str,
+    pool: Pool<SqliteConnectionManager>,
+) -> SqliteGenericQuery<V, A>
+where
+    V: View<A>,
+    A: Aggregate,
+{
+    GenericQuery::new(Arc::new(SqliteViewRepository::new(view_name, pool)))
+}
+
 #[cfg(test)]
 mod test {
     use crate::testing::tests::{
         TestAggregate, TestQueryRepository, TestServices, TestView, TEST_CONNECTION_STRING,
     };
-    use crate::{default_sqlite_pool, sqlite_cqrs, SqliteViewRepository};
+    use crate::{
+        default_sqlite_pool, sqlite_cqrs, sqlite_generic_query, sqlite_pool, PoolOptions,
+        SqliteViewRepository,
+    };
     use std::sync::Arc;
 
+    #[test]
+    fn pool_size() {
+        // every connection to an in-memory database opens a separate database
+        assert_eq!(1, default_sqlite_pool(TEST_CONNECTION_STRING).max_size());
+        let path = std::env::temp_dir().join(format!("{}.db", uuid::Uuid::new_v4()));
+        let connection_string = path.to_str().unwrap();
+        let pool = default_sqlite_pool(connection_string);
+        assert!(pool.max_size() > 1);
+        let pool = sqlite_pool(connection_string, PoolOptions::new().with_readers(3)).unwrap();
+        assert_eq!(4, pool.max_size());
+        let (reader, writer) = (pool.get().unwrap(), pool.get().unwrap());
+        writer
+            .execute_batch("CREATE TABLE pooled (id integer); INSERT INTO pooled VALUES (1);")
+            .unwrap();
+        let count: i64 = reader
+            .query_row("SELECT count(*) FROM pooled", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(1, count);
+        drop((reader, writer, pool));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{connection_string}{suffix}"));
+        }
+    }
+
     #[tokio::test]
     async fn test_valid_cqrs_framework() {
         let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
@@ -85,4 +338,12 @@ mod test {
         let query = TestQueryRepository::new(Arc::new(repo));
         let _ps = sqlite_cqrs(pool, vec![Box::new(query)], TestServices);
     }
+
+    #[tokio::test]
+    async fn generic_query() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let mut query = sqlite_generic_query::<TestView, TestAggregate>("test_view", pool.clone());
+        query.use_error_handler(Box::new(|err| panic!("{err}")));
+        let _ps = sqlite_cqrs(pool, vec![Box::new(query)], TestServices);
+    }
 }
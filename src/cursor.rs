@@ -0,0 +1,174 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::error::SqliteAggregateError;
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+const EVENTS: u8 = b'e';
+const VIEWS: u8 = b'v';
+
+/// An opaque position within the results of a paginated query, see
+/// `SqliteEventRepository::query_events_page` and `SqliteViewRepository::list_views`.
+///
+/// A cursor is returned with each page and passed to the query for the following page. It is
+/// displayed and serialized as a URL-safe base64 string, e.g. to be returned by and accepted
+/// from web APIs, without exposing the columns that results are ordered by. Pages are stable
+/// under concurrent commits: events committed later only ever appear after the cursor.
+///
+/// ```
+/// use rusqlite_es::{Cursor, SqliteAggregateError};
+///
+/// fn from_request(cursor: Option<&str>) -> Result<Option<Cursor>, SqliteAggregateError> {
+///     cursor.map(str::parse).transpose()
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cursor {
+    kind: u8,
+    position: i64,
+}
+
+/// A page of the results of a paginated query, see `Cursor`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page<T> {
+    /// The results on this page.
+    pub items: Vec<T>,
+    /// The cursor to request the following page with, `None` if this is the last page.
+    pub next: Option<Cursor>,
+}
+
+impl Cursor {
+    pub(crate) fn events(position: i64) -> Self {
+        Self {
+            kind: EVENTS,
+            position,
+        }
+    }
+
+    pub(crate) fn views(position: i64) -> Self {
+        Self {
+            kind: VIEWS,
+            position,
+        }
+    }
+
+    // The position of the cursor within the results of an event query.
+    pub(crate) fn event_position(&self) -> Result<i64, SqliteAggregateError> {
+        self.position_of(EVENTS)
+    }
+
+    // The position of the cursor within the results of a view listing.
+    pub(crate) fn view_position(&self) -> Result<i64, SqliteAggregateError> {
+        self.position_of(VIEWS)
+    }
+
+    fn position_of(&self, kind: u8) -> Result<i64, SqliteAggregateError> {
+        match self.kind == kind {
+            true => Ok(self.position),
+            false => Err(SqliteAggregateError::UnknownError(
+                "the cursor belongs to another query".into(),
+            )),
+        }
+    }
+}
+
+impl<T> Page<T> {
+    // A page of at most `limit` items, the following page starting after the last one.
+    pub(crate) fn new(
+        items: Vec<(T, i64)>,
+        limit: Option<usize>,
+        cursor: impl Fn(i64) -> Cursor,
+    ) -> Self {
+        let next = match (limit, items.last()) {
+            (Some(limit), Some((_, position))) if items.len() >= limit => Some(cursor(*position)),
+            _ => None,
+        };
+        Self {
+            items: items.into_iter().map(|(item, _)| item).collect(),
+            next,
+        }
+    }
+}
+
+impl Display for Cursor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut bytes = vec![self.kind];
+        bytes.extend_from_slice(&self.position.to_be_bytes());
+        // 9 bytes encode to 12 characters without padding
+        for chunk in bytes.chunks(3) {
+            let bits = u32::from(chunk[0]) << 16 | u32::from(chunk[1]) << 8 | u32::from(chunk[2]);
+            for shift in [18, 12, 6, 0] {
+                write!(f, "{}", ALPHABET[(bits >> shift & 0x3f) as usize] as char)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = SqliteAggregateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || SqliteAggregateError::UnknownError(format!("invalid cursor '{s}'").into());
+        if s.len() != 12 {
+            return Err(invalid());
+        }
+        let mut bytes = Vec::with_capacity(9);
+        for chunk in s.as_bytes().chunks(4) {
+            let mut bits = 0_u32;
+            for c in chunk {
+                let value = ALPHABET.iter().position(|a| a == c).ok_or_else(invalid)?;
+                bits = bits << 6 | value as u32;
+            }
+            bytes.extend_from_slice(&bits.to_be_bytes()[1..]);
+        }
+        let kind = bytes[0];
+        if kind != EVENTS && kind != VIEWS {
+            return Err(invalid());
+        }
+        let position = i64::from_be_bytes(bytes[1..].try_into().map_err(|_| invalid())?);
+        Ok(Self { kind, position })
+    }
+}
+
+impl Serialize for Cursor {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cursor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Cursor;
+
+    #[test]
+    fn cursor() {
+        for position in [0, 1, 42, i64::MAX, (7 << 40) + 3] {
+            let cursor = Cursor::events(position);
+            let encoded = cursor.to_string();
+            assert_eq!(12, encoded.len());
+            assert_eq!(cursor, encoded.parse().unwrap());
+            assert_eq!(position, cursor.event_position().unwrap());
+            assert!(cursor.view_position().is_err());
+        }
+        let cursor = Cursor::views(5);
+        let json = serde_json::to_string(&cursor).unwrap();
+        assert_eq!(format!("\"{cursor}\""), json);
+        assert_eq!(cursor, serde_json::from_str::<Cursor>(&json).unwrap());
+
+        assert!("".parse::<Cursor>().is_err());
+        assert!("not a cursor".parse::<Cursor>().is_err());
+        assert!("AAAAAAAAAAAA".parse::<Cursor>().is_err());
+    }
+}
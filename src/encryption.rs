@@ -0,0 +1,179 @@
+use std::fmt::{Debug, Formatter};
+
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::error::SqliteAggregateError;
+use crate::{sqlite_pool, ConnectionInit, PoolOptions};
+
+/// Supplies the key of an encrypted database, see `encrypted_sqlite_pool`.
+///
+/// Keys are requested whenever a connection is opened, so a provider may fetch them from a
+/// secret store rather than holding them for the lifetime of the application. Besides
+/// `StaticKey` and `EnvironmentKey`, any `Fn() -> Result<String, SqliteAggregateError>` closure
+/// can be used as a provider, e.g. to read the key from the operating system's keychain or to
+/// decrypt it with a key management service.
+///
+/// ```
+/// use rusqlite_es::{KeyProvider, SqliteAggregateError};
+///
+/// fn key_from_secret_store() -> impl KeyProvider {
+///     || -> Result<String, SqliteAggregateError> {
+///         // e.g. call out to a key management service
+///         Ok("correct horse battery staple".to_string())
+///     }
+/// }
+/// ```
+pub trait KeyProvider: Send + Sync {
+    /// Returns the key, either a passphrase or a raw key in SQLCipher's `x'...'` notation.
+    fn key(&self) -> Result<String, SqliteAggregateError>;
+}
+
+impl<F> KeyProvider for F
+where
+    F: Fn() -> Result<String, SqliteAggregateError> + Send + Sync,
+{
+    fn key(&self) -> Result<String, SqliteAggregateError> {
+        self()
+    }
+}
+
+/// A key held in memory, e.g. read from configuration at startup. The key is not included in
+/// the `Debug` output.
+#[derive(Clone)]
+pub struct StaticKey(String);
+
+impl StaticKey {
+    /// Creates a provider always returning `key`.
+    pub fn new(key: &str) -> Self {
+        Self(key.to_string())
+    }
+}
+
+impl Debug for StaticKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StaticKey(..)")
+    }
+}
+
+impl KeyProvider for StaticKey {
+    fn key(&self) -> Result<String, SqliteAggregateError> {
+        Ok(self.0.clone())
+    }
+}
+
+/// A key read from an environment variable whenever a connection is opened.
+#[derive(Debug, Clone)]
+pub struct EnvironmentKey {
+    variable: String,
+}
+
+impl EnvironmentKey {
+    /// Creates a provider reading the key from the environment variable `variable`.
+    pub fn new(variable: &str) -> Self {
+        Self {
+            variable: variable.to_string(),
+        }
+    }
+}
+
+impl KeyProvider for EnvironmentKey {
+    fn key(&self) -> Result<String, SqliteAggregateError> {
+        std::env::var(&self.variable).map_err(|err| {
+            SqliteAggregateError::UnknownError(
+                format!("encryption key variable {}: {err}", self.variable).into(),
+            )
+        })
+    }
+}
+
+/// Keys a connection to an encrypted database with the key supplied by `key_provider`, e.g. for
+/// a `SingleConnection`, and verifies that the key opens the database.
+///
+/// Encryption requires an SQLite library built with SQLCipher, e.g. by building rusqlite with
+/// its `bundled-sqlcipher` feature in place of this crate's `bundled` feature. An error is
+/// returned for any other SQLite library, which would otherwise silently ignore the key.
+pub fn apply_key(
+    connection: &Connection,
+    key_provider: &dyn KeyProvider,
+) -> Result<(), SqliteAggregateError> {
+    let cipher_version: Option<String> = connection
+        .query_row("PRAGMA cipher_version", [], |row| row.get(0))
+        .optional()?;
+    if cipher_version.is_none() {
+        return Err(SqliteAggregateError::UnknownError(
+            "the linked SQLite library does not support encryption (SQLCipher)".into(),
+        ));
+    }
+    connection.pragma_update(None, "key", key_provider.key()?)?;
+    // reading the schema fails if the key does not match the database
+    connection.query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(()))?;
+    Ok(())
+}
+
+/// Builds a connection pool for an encrypted SQLite database, keying each connection with the
+/// key supplied by `key_provider`, see `apply_key`. The database is created, encrypted with the
+/// key, if it does not exist.
+///
+/// The pool is built by `sqlite_pool` with `PoolOptions::with_key`, so it is sized and its
+/// connections are initialized as any other pool, use these to configure it further.
+///
+/// ```no_run
+/// use rusqlite_es::{encrypted_sqlite_pool, EnvironmentKey};
+///
+/// let pool = encrypted_sqlite_pool("events.db", EnvironmentKey::new("EVENTS_DB_KEY"))
+///     .expect("unable to open the encrypted database");
+/// ```
+pub fn encrypted_sqlite_pool<K: KeyProvider + 'static>(
+    connection_string: &str,
+    key_provider: K,
+) -> Result<Pool<SqliteConnectionManager>, SqliteAggregateError> {
+    sqlite_pool(connection_string, PoolOptions::new().with_key(key_provider))
+}
+
+// Applies the key of `key_provider` to each connection of a pool, see `PoolOptions::with_key`.
+pub(crate) fn key_init<K: KeyProvider + 'static>(key_provider: K) -> ConnectionInit {
+    ConnectionInit::new(move |conn| {
+        apply_key(conn, &key_provider).map_err(|err| {
+            rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_AUTH),
+                Some(err.to_string()),
+            )
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use rusqlite::Connection;
+
+    use crate::{
+        apply_key, encrypted_sqlite_pool, sqlite_pool, EnvironmentKey, KeyProvider, PoolOptions,
+        SqliteAggregateError, StaticKey,
+    };
+
+    #[test]
+    fn key_providers() {
+        let key = StaticKey::new("secret");
+        assert_eq!("secret", key.key().unwrap());
+        assert_eq!("StaticKey(..)", format!("{key:?}"));
+        assert!(EnvironmentKey::new("RUSQLITE_ES_TEST_UNSET_KEY")
+            .key()
+            .is_err());
+        let callback = || -> Result<String, SqliteAggregateError> { Ok("from kms".to_string()) };
+        assert_eq!("from kms", callback.key().unwrap());
+    }
+
+    #[test]
+    fn encryption_requires_sqlcipher() {
+        // the bundled SQLite library is built without SQLCipher
+        let connection = Connection::open_in_memory().unwrap();
+        assert!(apply_key(&connection, &StaticKey::new("secret")).is_err());
+        assert!(encrypted_sqlite_pool(":memory:", StaticKey::new("secret")).is_err());
+        let options = PoolOptions::new()
+            .with_readers(2)
+            .with_key(StaticKey::new("secret"));
+        assert!(sqlite_pool(":memory:", options).is_err());
+    }
+}
@@ -1,13 +1,73 @@
 use std::fmt::{Debug, Display, Formatter};
+use std::time::Duration;
 
 use cqrs_es::persist::PersistenceError;
 use cqrs_es::AggregateError;
 
+use crate::PayloadKind;
+
+/// Errors returned by the SQLite repositories.
 #[derive(Debug)]
 pub enum SqliteAggregateError {
+    /// A commit conflicted with a concurrent commit for the same aggregate instance.
     OptimisticLock,
+    /// A snapshot update conflicted with a more recent snapshot of the same aggregate instance.
+    SnapshotConflict,
+    /// The database connection could not be established or was lost.
     ConnectionError(Box<dyn std::error::Error + Send + Sync + 'static>),
+    /// A stored value could not be deserialized.
     DeserializationError(Box<dyn std::error::Error + Send + Sync + 'static>),
+    /// The linked SQLite library is older than the minimum version this crate requires.
+    UnsupportedSqliteVersion {
+        /// The minimum supported SQLite version.
+        required: &'static str,
+        /// The version of the linked SQLite library.
+        found: &'static str,
+    },
+    /// A serialized payload exceeded the configured `PayloadLimits`.
+    PayloadTooLarge {
+        /// The kind of payload that was rejected.
+        kind: PayloadKind,
+        /// The serialized size of the payload in bytes.
+        size: usize,
+        /// The configured maximum size in bytes.
+        limit: usize,
+    },
+    /// An event payload was rejected by the validator registered in the `EventSchemaRegistry`.
+    InvalidEvent {
+        /// The type of the rejected event.
+        event_type: String,
+        /// The reason given by the validator.
+        reason: String,
+    },
+    /// A replay was stopped through its `ReplayProgress` handle.
+    ReplayCancelled,
+    /// A view had not caught up with a `ConsistencyToken` within the configured timeout.
+    ViewBehind {
+        /// The id of the view instance.
+        view_id: String,
+    },
+    /// A query ran longer than the timeout configured on its repository and was interrupted.
+    Timeout {
+        /// The configured timeout.
+        timeout: Duration,
+    },
+    /// Reading an aggregate or view instance was refused by the configured `AccessPolicy`.
+    AccessDenied {
+        /// The aggregate type, or the name of the view table.
+        resource: String,
+        /// The id of the aggregate or view instance.
+        id: String,
+    },
+    /// A command was rejected because too many commands were already queued on the aggregate
+    /// instance, see `CommandQueue::with_max_waiting`.
+    CommandQueueFull {
+        /// The id of the aggregate instance.
+        aggregate_id: String,
+        /// The configured maximum of waiting commands.
+        max_waiting: usize,
+    },
+    /// Any other error.
     UnknownError(Box<dyn std::error::Error + Send + Sync + 'static>),
 }
 
@@ -15,15 +75,64 @@ impl Display for SqliteAggregateError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             SqliteAggregateError::OptimisticLock => write!(f, "optimistic lock error"),
+            SqliteAggregateError::SnapshotConflict => write!(f, "snapshot conflict"),
             SqliteAggregateError::UnknownError(error) => write!(f, "{}", error),
             SqliteAggregateError::DeserializationError(error) => write!(f, "{}", error),
             SqliteAggregateError::ConnectionError(error) => write!(f, "{}", error),
+            SqliteAggregateError::UnsupportedSqliteVersion { required, found } => write!(
+                f,
+                "SQLite {} is not supported, version {} or later is required",
+                found, required
+            ),
+            SqliteAggregateError::PayloadTooLarge { kind, size, limit } => write!(
+                f,
+                "{} payload of {} bytes exceeds the limit of {} bytes",
+                kind, size, limit
+            ),
+            SqliteAggregateError::InvalidEvent { event_type, reason } => {
+                write!(f, "invalid {} event: {}", event_type, reason)
+            }
+            SqliteAggregateError::ReplayCancelled => write!(f, "replay cancelled"),
+            SqliteAggregateError::ViewBehind { view_id } => {
+                write!(f, "view {} has not caught up with the commit", view_id)
+            }
+            SqliteAggregateError::Timeout { timeout } => {
+                write!(
+                    f,
+                    "query interrupted after exceeding the timeout of {:?}",
+                    timeout
+                )
+            }
+            SqliteAggregateError::AccessDenied { resource, id } => {
+                write!(f, "access to {} {} denied", resource, id)
+            }
+            SqliteAggregateError::CommandQueueFull {
+                aggregate_id,
+                max_waiting,
+            } => write!(
+                f,
+                "more than {} commands are queued for aggregate instance {}",
+                max_waiting, aggregate_id
+            ),
         }
     }
 }
 
 impl std::error::Error for SqliteAggregateError {}
 
+impl SqliteAggregateError {
+    pub(crate) fn is_busy(&self) -> bool {
+        match self {
+            SqliteAggregateError::UnknownError(error) => matches!(
+                error.downcast_ref::<rusqlite::Error>(),
+                Some(rusqlite::Error::SqliteFailure(failure, _))
+                    if failure.code == rusqlite::ErrorCode::DatabaseBusy
+            ),
+            _ => false,
+        }
+    }
+}
+
 impl From<rusqlite::Error> for SqliteAggregateError {
     fn from(err: rusqlite::Error) -> Self {
         match &err {
@@ -48,7 +157,9 @@ impl From<r2d2::Error> for SqliteAggregateError {
 impl<T: std::error::Error> From<SqliteAggregateError> for AggregateError<T> {
     fn from(err: SqliteAggregateError) -> Self {
         match err {
-            SqliteAggregateError::OptimisticLock => AggregateError::AggregateConflict,
+            SqliteAggregateError::OptimisticLock | SqliteAggregateError::SnapshotConflict => {
+                AggregateError::AggregateConflict
+            }
             SqliteAggregateError::ConnectionError(error) => {
                 AggregateError::DatabaseConnectionError(error)
             }
@@ -56,6 +167,16 @@ impl<T: std::error::Error> From<SqliteAggregateError> for AggregateError<T> {
                 AggregateError::DeserializationError(error)
             }
             SqliteAggregateError::UnknownError(error) => AggregateError::UnexpectedError(error),
+            SqliteAggregateError::UnsupportedSqliteVersion { .. }
+            | SqliteAggregateError::PayloadTooLarge { .. }
+            | SqliteAggregateError::InvalidEvent { .. }
+            | SqliteAggregateError::ReplayCancelled
+            | SqliteAggregateError::ViewBehind { .. }
+            | SqliteAggregateError::Timeout { .. }
+            | SqliteAggregateError::AccessDenied { .. }
+            | SqliteAggregateError::CommandQueueFull { .. } => {
+                AggregateError::UnexpectedError(Box::new(err))
+            }
         }
     }
 }
@@ -76,7 +197,9 @@ impl From<serde_json::Error> for SqliteAggregateError {
 impl From<SqliteAggregateError> for PersistenceError {
     fn from(err: SqliteAggregateError) -> Self {
         match err {
-            SqliteAggregateError::OptimisticLock => PersistenceError::OptimisticLockError,
+            SqliteAggregateError::OptimisticLock | SqliteAggregateError::SnapshotConflict => {
+                PersistenceError::OptimisticLockError
+            }
             SqliteAggregateError::ConnectionError(error) => {
                 PersistenceError::ConnectionError(error)
             }
@@ -84,6 +207,16 @@ impl From<SqliteAggregateError> for PersistenceError {
                 PersistenceError::UnknownError(error)
             }
             SqliteAggregateError::UnknownError(error) => PersistenceError::UnknownError(error),
+            SqliteAggregateError::UnsupportedSqliteVersion { .. }
+            | SqliteAggregateError::PayloadTooLarge { .. }
+            | SqliteAggregateError::InvalidEvent { .. }
+            | SqliteAggregateError::ReplayCancelled
+            | SqliteAggregateError::ViewBehind { .. }
+            | SqliteAggregateError::Timeout { .. }
+            | SqliteAggregateError::AccessDenied { .. }
+            | SqliteAggregateError::CommandQueueFull { .. } => {
+                PersistenceError::UnknownError(Box::new(err))
+            }
         }
     }
 }
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use cqrs_es::persist::{PersistenceError, SerializedEvent};
+use serde_json::Value;
+
+/// Maps the former names of renamed event types to their current names, see
+/// `SqliteEventRepository::with_event_type_aliases`.
+///
+/// Events are stored under the event type they were committed with, which is also the name of
+/// the enum variant in their serialized payload. Once a variant is renamed in code, events
+/// loaded or streamed by a repository configured with an alias are renamed as they are read,
+/// both in their event type and their payload, so that they deserialize into the renamed
+/// variant without rewriting the stored events. Aliases may be chained, e.g. after renaming a
+/// variant twice.
+///
+/// ```
+/// use rusqlite_es::EventTypeAliases;
+///
+/// let aliases = EventTypeAliases::new()
+///     .alias("AccountOpenedV1", "AccountOpened")
+///     .alias("FundsAdded", "MoneyDeposited");
+/// assert_eq!("AccountOpened", aliases.resolve("AccountOpenedV1"));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct EventTypeAliases {
+    aliases: HashMap<String, String>,
+}
+
+impl EventTypeAliases {
+    /// Creates a registry without any aliases.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Reads events stored with the event type `former` as events of type `current`.
+    pub fn alias(mut self, former: &str, current: &str) -> Self {
+        self.aliases.insert(former.to_string(), current.to_string());
+        self
+    }
+
+    /// Returns the current name of an event type, following chained aliases.
+    pub fn resolve<'a>(&'a self, event_type: &'a str) -> &'a str {
+        let mut resolved = event_type;
+        // a cycle of aliases ends once every alias has been followed
+        for _ in 0..self.aliases.len() {
+            match self.aliases.get(resolved) {
+                Some(current) => resolved = current,
+                None => break,
+            }
+        }
+        resolved
+    }
+
+    /// Returns the event types stored for events now of type `event_type`, i.e. the event type
+    /// itself and the former names resolving to it.
+    pub fn stored_names(&self, event_type: &str) -> Vec<String> {
+        let mut names = vec![event_type.to_string()];
+        names.extend(
+            self.aliases
+                .keys()
+                .filter(|former| self.resolve(former) == event_type && *former != event_type)
+                .cloned(),
+        );
+        names
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.aliases.is_empty()
+    }
+
+    // Renames an event stored under a former event type.
+    pub(crate) fn rename(&self, event: &mut SerializedEvent) {
+        let current = self.resolve(&event.event_type);
+        if current == event.event_type {
+            return;
+        }
+        let current = current.to_string();
+        if let Value::Object(payload) = &mut event.payload {
+            if let Some(content) = payload.remove(&event.event_type) {
+                payload.insert(current.clone(), content);
+            }
+        }
+        event.event_type = current;
+    }
+}
+
+// Renames the events handed to `push` by a stream.
+pub(crate) fn aliased<F>(
+    aliases: Arc<EventTypeAliases>,
+    mut push: F,
+) -> impl FnMut(Result<SerializedEvent, PersistenceError>) -> bool + Send + 'static
+where
+    F: FnMut(Result<SerializedEvent, PersistenceError>) -> bool + Send + 'static,
+{
+    move |event_result| match event_result {
+        Ok(mut event) => {
+            aliases.rename(&mut event);
+            push(Ok(event))
+        }
+        event_result => push(event_result),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde_json::json;
+
+    use crate::testing::tests::{Created, TestAggregate, TestEvent, TEST_CONNECTION_STRING};
+    use crate::{default_sqlite_pool, EventQuery, EventTypeAliases, SqliteEventRepository};
+
+    #[tokio::test]
+    async fn event_type_aliases() {
+        let aliases = EventTypeAliases::new()
+            .alias("Opened", "Begun")
+            .alias("Begun", "Created")
+            .alias("Cyclic", "Cycle")
+            .alias("Cycle", "Cyclic");
+        assert_eq!("Created", aliases.resolve("Opened"));
+        assert_eq!("Tested", aliases.resolve("Tested"));
+        let mut stored_names = aliases.stored_names("Created");
+        stored_names.sort();
+        assert_eq!(vec!["Begun", "Created", "Opened"], stored_names);
+        aliases.resolve("Cyclic");
+
+        let repo = SqliteEventRepository::connect(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .await
+            .unwrap()
+            .into_inner()
+            .with_event_type_aliases(aliases);
+        let id = uuid::Uuid::new_v4().to_string();
+        repo.with_connection(|conn| {
+            conn.execute(
+                "INSERT INTO events (aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata) \
+                 VALUES ('TestAggregate', ?, 1, 'Opened', '1.0', ?, '{}')",
+                (&id, json!({"Opened": {"id": id}})),
+            )
+        })
+        .unwrap();
+
+        let events = repo.get_events::<TestAggregate>(&id).await.unwrap();
+        assert_eq!("Created", events[0].event_type);
+        assert_eq!(json!({"Created": {"id": id}}), events[0].payload);
+        // the renamed event deserializes into the current variant
+        let loaded = repo.load_aggregates::<TestAggregate>(&[&id]).await.unwrap();
+        assert!(loaded.contains_key(&id));
+
+        let mut stream = repo.stream_events::<TestAggregate>(&id).await.unwrap();
+        let streamed = stream.next::<TestAggregate>(&None).await.unwrap().unwrap();
+        assert_eq!(
+            TestEvent::Created(Created { id: id.clone() }),
+            streamed.payload
+        );
+
+        let queried = repo
+            .query_events(
+                &EventQuery::new()
+                    .with_aggregate_id(&id)
+                    .with_event_types(&["Created"]),
+            )
+            .await
+            .unwrap();
+        assert_eq!(1, queried.len());
+    }
+}
@@ -0,0 +1,152 @@
+use cqrs_es::persist::SerializedEvent;
+use cqrs_es::{Aggregate, EventEnvelope};
+use tokio::sync::broadcast;
+
+/// Broadcasts the events of an aggregate type to in-process subscribers once they have been
+/// committed, e.g. to push them to websocket clients, without implementing `Query`. See
+/// `SqliteEventRepository::with_event_bus`.
+///
+/// Subscribers receive the events committed after they subscribed, in commit order. As with
+/// any `tokio::sync::broadcast` channel, a subscriber lagging more than `capacity` events behind
+/// misses the oldest of them and is notified by `RecvError::Lagged`. Events are only broadcast
+/// by the repository they were committed through, not by other processes sharing the database.
+///
+/// ```
+/// # use cqrs_es::doc::MyAggregate;
+/// use r2d2::Pool;
+/// use r2d2_sqlite::SqliteConnectionManager;
+/// use rusqlite_es::{EventBus, SqliteEventRepository};
+///
+/// async fn listen(pool: Pool<SqliteConnectionManager>) {
+///     let bus = EventBus::<MyAggregate>::new(1024);
+///     let repo = SqliteEventRepository::new(pool).with_event_bus(bus.clone());
+///     let mut events = bus.subscribe();
+///     while let Ok(event) = events.recv().await {
+///         println!("{}: {:?}", event.aggregate_id, event.payload);
+///     }
+/// }
+/// ```
+pub struct EventBus<A: Aggregate> {
+    sender: broadcast::Sender<EventEnvelope<A>>,
+}
+
+// Implemented manually, deriving would needlessly require the aggregate to be `Clone`.
+impl<A: Aggregate> Clone for EventBus<A> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<A: Aggregate> EventBus<A> {
+    /// Creates a bus retaining up to `capacity` events for subscribers that fall behind.
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity.max(1));
+        Self { sender }
+    }
+
+    /// Subscribes to the events committed from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<EventEnvelope<A>> {
+        self.sender.subscribe()
+    }
+
+    /// The number of current subscribers.
+    pub fn subscriber_count(&self) -> usize {
+        self.sender.receiver_count()
+    }
+}
+
+/// Notified of the events of an aggregate type once they have been committed, see
+/// `SqliteEventRepository::with_event_bus`.
+pub(crate) trait CommitListener: Send + Sync {
+    fn aggregate_type(&self) -> String;
+
+    fn committed(&self, events: &[SerializedEvent]);
+}
+
+impl<A: Aggregate> CommitListener for EventBus<A> {
+    fn aggregate_type(&self) -> String {
+        A::aggregate_type()
+    }
+
+    fn committed(&self, events: &[SerializedEvent]) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        for event in events {
+            match EventEnvelope::<A>::try_from(event.clone()) {
+                // sending only fails without subscribers
+                Ok(envelope) => {
+                    let _ = self.sender.send(envelope);
+                }
+                Err(err) => tracing::warn!(
+                    target: "rusqlite_es::event_bus",
+                    aggregate_id = %event.aggregate_id,
+                    sequence = event.sequence,
+                    error = %err,
+                    "committed event could not be broadcast"
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde_json::json;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, EventBus, SqliteEventRepository};
+
+    #[tokio::test]
+    async fn event_bus() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let bus = EventBus::<TestAggregate>::new(16);
+        let repo = SqliteEventRepository::new(pool).with_event_bus(bus.clone());
+        let mut subscriber = bus.subscribe();
+        assert_eq!(1, bus.subscriber_count());
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = json!({});
+        let mut tested = test_event_envelope(
+            &id,
+            2,
+            TestEvent::Tested(Tested {
+                test_name: "broadcast".to_string(),
+            }),
+        );
+        tested.metadata = json!({});
+        repo.persist::<TestAggregate>(&[created.clone(), tested], None)
+            .await
+            .unwrap();
+
+        let event = subscriber.recv().await.unwrap();
+        assert_eq!(id, event.aggregate_id);
+        assert_eq!(
+            TestEvent::Created(Created { id: id.clone() }),
+            event.payload
+        );
+        assert_eq!(2, subscriber.recv().await.unwrap().sequence);
+
+        // events rolled back by a conflict are not broadcast
+        assert!(repo
+            .persist::<TestAggregate>(&[created], None)
+            .await
+            .is_err());
+        assert!(matches!(subscriber.try_recv(), Err(TryRecvError::Empty)));
+    }
+}
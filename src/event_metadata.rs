@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use cqrs_es::{Aggregate, DomainEvent};
+use serde_json::Value;
+
+/// An event contributing metadata of its own, e.g. the reason for a state change or the id of
+/// an external record it was derived from, see `SqliteEventRepository::with_event_metadata`.
+///
+/// The framework attaches the metadata of a command execution verbatim to every event it
+/// produced. Configured with `with_event_metadata`, the repository merges each event's own
+/// metadata into it before the event is stored.
+///
+/// ```
+/// use std::collections::HashMap;
+///
+/// use cqrs_es::DomainEvent;
+/// use rusqlite_es::EventMetadata;
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// enum AccountEvent {
+///     Opened { account_id: String },
+///     Closed { reason: String },
+/// }
+///
+/// impl DomainEvent for AccountEvent {
+///     fn event_type(&self) -> String {
+///         match self {
+///             AccountEvent::Opened { .. } => "Opened".to_string(),
+///             AccountEvent::Closed { .. } => "Closed".to_string(),
+///         }
+///     }
+///
+///     fn event_version(&self) -> String {
+///         "1.0".to_string()
+///     }
+/// }
+///
+/// impl EventMetadata for AccountEvent {
+///     fn event_metadata(&self) -> HashMap<String, String> {
+///         match self {
+///             AccountEvent::Closed { reason } => {
+///                 HashMap::from([("closure_reason".to_string(), reason.clone())])
+///             }
+///             _ => HashMap::new(),
+///         }
+///     }
+/// }
+/// ```
+pub trait EventMetadata: DomainEvent {
+    /// Returns the metadata of this event, merged with the metadata of the command that
+    /// produced it.
+    fn event_metadata(&self) -> HashMap<String, String>;
+}
+
+/// Which entry is stored when the metadata of a command and of one of its events share a key,
+/// see `SqliteEventRepository::with_event_metadata`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetadataPrecedence {
+    /// The event's entry replaces the command's.
+    #[default]
+    Event,
+    /// The command's entry is kept, the event only adds entries the command does not have.
+    Command,
+}
+
+type EventMetadataSource = dyn Fn(&Value) -> Option<HashMap<String, String>> + Send + Sync;
+
+// Merges the metadata of the events of an aggregate type into the metadata of their command.
+#[derive(Clone)]
+pub(crate) struct MetadataInheritance {
+    source: Arc<EventMetadataSource>,
+    precedence: MetadataPrecedence,
+}
+
+impl MetadataInheritance {
+    pub(crate) fn new<A>(precedence: MetadataPrecedence) -> Self
+    where
+        A: Aggregate,
+        A::Event: EventMetadata,
+    {
+        Self {
+            source: Arc::new(|payload| {
+                serde_json::from_value::<A::Event>(payload.clone())
+                    .ok()
+                    .map(|event| event.event_metadata())
+            }),
+            precedence,
+        }
+    }
+
+    // Returns the metadata to store for an event, payloads that are not events of the current
+    // version of the aggregate keep the command's metadata.
+    pub(crate) fn merge(&self, payload: &Value, metadata: &Value) -> Value {
+        let (Some(event_metadata), Value::Object(command_metadata)) =
+            ((self.source)(payload), metadata)
+        else {
+            return metadata.clone();
+        };
+        let mut merged = command_metadata.clone();
+        for (key, value) in event_metadata {
+            if self.precedence == MetadataPrecedence::Event || !merged.contains_key(&key) {
+                merged.insert(key, Value::String(value));
+            }
+        }
+        Value::Object(merged)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::fs;
+
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde_json::json;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{
+        default_sqlite_pool, EventMetadata, MetadataPrecedence, PayloadStorage,
+        SqliteEventRepository,
+    };
+
+    impl EventMetadata for TestEvent {
+        fn event_metadata(&self) -> HashMap<String, String> {
+            match self {
+                TestEvent::Created(_) => HashMap::from([
+                    ("origin".to_string(), "signup".to_string()),
+                    ("user_id".to_string(), "system".to_string()),
+                ]),
+                _ => HashMap::new(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn event_metadata() {
+        for (precedence, user_id) in [
+            (MetadataPrecedence::Event, "system"),
+            (MetadataPrecedence::Command, "alice"),
+        ] {
+            let repo = SqliteEventRepository::connect(default_sqlite_pool(TEST_CONNECTION_STRING))
+                .await
+                .unwrap()
+                .into_inner()
+                .with_event_metadata::<TestAggregate>(precedence);
+            let id = uuid::Uuid::new_v4().to_string();
+            let command_metadata = json!({"user_id": "alice", "request_id": "42"});
+            let mut created =
+                test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+            created.metadata = command_metadata.clone();
+            let mut tested = test_event_envelope(
+                &id,
+                2,
+                TestEvent::Tested(Tested {
+                    test_name: "inherited".to_string(),
+                }),
+            );
+            tested.metadata = command_metadata.clone();
+            repo.persist::<TestAggregate>(&[created, tested], None)
+                .await
+                .unwrap();
+
+            let events = repo.get_events::<TestAggregate>(&id).await.unwrap();
+            assert_eq!(
+                json!({"user_id": user_id, "request_id": "42", "origin": "signup"}),
+                events[0].metadata
+            );
+            assert_eq!(command_metadata, events[1].metadata);
+        }
+    }
+
+    #[tokio::test]
+    async fn event_metadata_with_deduplicated_payloads() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let repo = SqliteEventRepository::new(pool)
+            .with_payload_storage(PayloadStorage::Deduplicated)
+            .with_event_metadata::<TestAggregate>(MetadataPrecedence::Event);
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = json!({"user_id": "alice"});
+        repo.persist::<TestAggregate>(&[created], None)
+            .await
+            .unwrap();
+
+        let events = repo.get_events::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(
+            json!({"user_id": "system", "origin": "signup"}),
+            events[0].metadata
+        );
+    }
+}
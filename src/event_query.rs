@@ -0,0 +1,646 @@
+use std::ops::{Bound, RangeBounds};
+
+use cqrs_es::persist::{PersistenceError, SerializedEvent};
+use cqrs_es::Aggregate;
+use rusqlite::types::Value;
+
+use crate::access_policy::check_access;
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::query_timeout::with_timeout;
+use crate::slow_query::timed;
+use crate::sql_query::SqlQueryFactory;
+use crate::{
+    AccessKind, Cursor, IndexAdvisor, MetadataStorage, Page, Partitioning, SqliteEventRepository,
+    REDACTED_PARAM,
+};
+
+/// The order in which `SqliteEventRepository::query_events` returns events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EventOrder {
+    /// In the order in which they were committed (ascending global position).
+    #[default]
+    Committed,
+    /// Most recently committed first (descending global position).
+    MostRecent,
+}
+
+/// A composable filter over the events table, see `SqliteEventRepository::query_events`.
+///
+/// All configured conditions must match. Time ranges are compared against the RFC 3339 UTC
+/// commit timestamp (see the `audit` module), positions against the global position (the
+/// events table `rowid`, see `EventTableLayout`).
+///
+/// ```
+/// use rusqlite_es::{EventOrder, EventQuery};
+///
+/// let query = EventQuery::new()
+///     .with_aggregate_type("BankAccount")
+///     .with_event_types(&["CustomerDepositedMoney", "CustomerWithdrewCash"])
+///     .with_metadata("user_id", "alice")
+///     .with_created("2024-01-01".."2024-02-01")
+///     .with_order(EventOrder::MostRecent)
+///     .with_limit(100);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventQuery {
+    aggregate_type: Option<String>,
+    aggregate_id: Option<String>,
+    event_types: Vec<String>,
+    metadata: Vec<(String, Option<String>)>,
+    created: (Bound<String>, Bound<String>),
+    positions: (Bound<i64>, Bound<i64>),
+    order: EventOrder,
+    limit: Option<usize>,
+    cursor: Option<Cursor>,
+}
+
+impl Default for EventQuery {
+    fn default() -> Self {
+        Self {
+            aggregate_type: None,
+            aggregate_id: None,
+            event_types: Vec::new(),
+            metadata: Vec::new(),
+            created: (Bound::Unbounded, Bound::Unbounded),
+            positions: (Bound::Unbounded, Bound::Unbounded),
+            order: EventOrder::default(),
+            limit: None,
+            cursor: None,
+        }
+    }
+}
+
+impl EventQuery {
+    /// Creates a query matching every event.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Matches events of the aggregate type.
+    pub fn with_aggregate_type(self, aggregate_type: &str) -> Self {
+        Self {
+            aggregate_type: Some(aggregate_type.to_string()),
+            ..self
+        }
+    }
+
+    /// Matches events of the aggregate instance, of any aggregate type unless one is configured.
+    pub fn with_aggregate_id(self, aggregate_id: &str) -> Self {
+        Self {
+            aggregate_id: Some(aggregate_id.to_string()),
+            ..self
+        }
+    }
+
+    /// Matches events of any of the event types.
+    pub fn with_event_types(self, event_types: &[&str]) -> Self {
+        Self {
+            event_types: event_types.iter().map(|t| t.to_string()).collect(),
+            ..self
+        }
+    }
+
+    /// Matches events whose metadata entry `key` is the string `value`.
+    pub fn with_metadata(mut self, key: &str, value: &str) -> Self {
+        self.metadata
+            .push((key.to_string(), Some(value.to_string())));
+        self
+    }
+
+    /// Matches events with a metadata entry `key`, whatever its value.
+    pub fn with_metadata_key(mut self, key: &str) -> Self {
+        self.metadata.push((key.to_string(), None));
+        self
+    }
+
+    /// Matches events committed within the time range.
+    pub fn with_created<'a, R: RangeBounds<&'a str>>(self, range: R) -> Self {
+        Self {
+            created: (
+                range.start_bound().map(|start| start.to_string()),
+                range.end_bound().map(|end| end.to_string()),
+            ),
+            ..self
+        }
+    }
+
+    /// Matches events whose global position is within the range.
+    pub fn with_positions<R: RangeBounds<i64>>(self, range: R) -> Self {
+        Self {
+            positions: (range.start_bound().cloned(), range.end_bound().cloned()),
+            ..self
+        }
+    }
+
+    /// Configures the order of the results.
+    pub fn with_order(self, order: EventOrder) -> Self {
+        Self { order, ..self }
+    }
+
+    /// Returns at most `limit` events.
+    pub fn with_limit(self, limit: usize) -> Self {
+        Self {
+            limit: Some(limit),
+            ..self
+        }
+    }
+
+    /// Matches events following the cursor in the configured order, i.e. those on the page
+    /// after the one the cursor was returned with, see `SqliteEventRepository::query_events_page`.
+    pub fn with_cursor(self, cursor: Cursor) -> Self {
+        Self {
+            cursor: Some(cursor),
+            ..self
+        }
+    }
+
+    // Records the metadata entries compared by the query, as long as an index can serve them.
+    fn record_predicates(&self, index_advisor: &IndexAdvisor, query_factory: &SqlQueryFactory) {
+        if query_factory.metadata_storage() != MetadataStorage::Inline
+            || query_factory.partitioning() != Partitioning::None
+        {
+            return;
+        }
+        for (key, value) in &self.metadata {
+            if value.is_some() {
+                index_advisor.record(
+                    query_factory.event_table(),
+                    "metadata",
+                    &metadata_json_path(key),
+                );
+            }
+        }
+    }
+
+    // Compiles the query to SQL, every value is bound as a parameter.
+    pub(crate) fn to_sql(&self, query_factory: &SqlQueryFactory) -> (String, Vec<Value>) {
+        let mut conditions: Vec<String> = Vec::new();
+        let mut params: Vec<Value> = Vec::new();
+        if let Some(aggregate_type) = &self.aggregate_type {
+            conditions.push("aggregate_type = ?".to_string());
+            params.push(Value::Text(aggregate_type.clone()));
+        }
+        if let Some(aggregate_id) = &self.aggregate_id {
+            conditions.push("aggregate_id = ?".to_string());
+            params.push(Value::Text(aggregate_id.clone()));
+        }
+        if !self.event_types.is_empty() {
+            let placeholders = vec!["?"; self.event_types.len()].join(", ");
+            conditions.push(format!("event_type IN ({placeholders})"));
+            params.extend(self.event_types.iter().cloned().map(Value::Text));
+        }
+        let metadata = query_factory.metadata();
+        for (key, value) in &self.metadata {
+            // the path is inlined so that an index on the metadata entry can be used
+            let path = metadata_path(key);
+            match value {
+                Some(value) => {
+                    conditions.push(format!("json_extract({metadata}, {path}) = ?"));
+                    params.push(Value::Text(value.clone()));
+                }
+                None => conditions.push(format!("json_type({metadata}, {path}) IS NOT NULL")),
+            }
+        }
+        push_range(
+            &mut conditions,
+            &mut params,
+            "created_at",
+            &self.created,
+            |value| Value::Text(value.clone()),
+        );
+        push_range(
+            &mut conditions,
+            &mut params,
+            query_factory.position(),
+            &self.positions,
+            |value| Value::Integer(*value),
+        );
+        if let Some(Ok(position)) = self.cursor.map(|cursor| cursor.event_position()) {
+            let following = match self.order {
+                EventOrder::Committed => ">",
+                EventOrder::MostRecent => "<",
+            };
+            conditions.push(format!("{} {following} ?", query_factory.position()));
+            params.push(Value::Integer(position));
+        }
+        let conditions = if conditions.is_empty() {
+            "1 = 1".to_string()
+        } else {
+            conditions.join(" AND ")
+        };
+        let order = match self.order {
+            EventOrder::Committed => "ASC",
+            EventOrder::MostRecent => "DESC",
+        };
+        // the limit is bound rather than inlined, so that every limit shares a cached statement
+        if let Some(limit) = self.limit {
+            params.push(Value::Integer(limit as i64));
+        }
+        let query = query_factory.query_events(&conditions, order, self.limit.is_some());
+        (query, params)
+    }
+}
+
+// The metadata entry `key` as a quoted SQL string literal holding a JSON path.
+pub(crate) fn metadata_path(key: &str) -> String {
+    format!("'{}'", metadata_json_path(key).replace('\'', "''"))
+}
+
+// The JSON path of the metadata entry `key`.
+fn metadata_json_path(key: &str) -> String {
+    format!("$.\"{}\"", key.replace('"', "\"\""))
+}
+
+fn push_range<T>(
+    conditions: &mut Vec<String>,
+    params: &mut Vec<Value>,
+    column: &str,
+    range: &(Bound<T>, Bound<T>),
+    to_value: impl Fn(&T) -> Value,
+) {
+    match &range.0 {
+        Bound::Included(start) => {
+            conditions.push(format!("{column} >= ?"));
+            params.push(to_value(start));
+        }
+        Bound::Excluded(start) => {
+            conditions.push(format!("{column} > ?"));
+            params.push(to_value(start));
+        }
+        Bound::Unbounded => {}
+    }
+    match &range.1 {
+        Bound::Included(end) => {
+            conditions.push(format!("{column} <= ?"));
+            params.push(to_value(end));
+        }
+        Bound::Excluded(end) => {
+            conditions.push(format!("{column} < ?"));
+            params.push(to_value(end));
+        }
+        Bound::Unbounded => {}
+    }
+}
+
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    /// Loads the events matching the query, across any number of aggregate types.
+    ///
+    /// ```
+    /// use cqrs_es::persist::{PersistenceError, SerializedEvent};
+    /// use rusqlite_es::{EventOrder, EventQuery, SqliteEventRepository};
+    ///
+    /// async fn latest_deposits(repo: &SqliteEventRepository) -> Result<Vec<SerializedEvent>, PersistenceError> {
+    ///     let query = EventQuery::new()
+    ///         .with_event_types(&["CustomerDepositedMoney"])
+    ///         .with_order(EventOrder::MostRecent)
+    ///         .with_limit(10);
+    ///     repo.query_events(&query).await
+    /// }
+    /// ```
+    pub async fn query_events(
+        &self,
+        query: &EventQuery,
+    ) -> Result<Vec<SerializedEvent>, PersistenceError> {
+        let events = self.select_query_events(query)?;
+        Ok(events.into_iter().map(|(event, _)| event).collect())
+    }
+
+    /// Loads a page of the events matching the query, the query's limit being the page size.
+    /// The following page is loaded with the same query and the cursor returned with the page,
+    /// see `EventQuery::with_cursor` and `Cursor`.
+    ///
+    /// ```
+    /// use cqrs_es::persist::{PersistenceError, SerializedEvent};
+    /// use rusqlite_es::{Cursor, EventQuery, Page, SqliteEventRepository};
+    ///
+    /// async fn list_events(
+    ///     repo: &SqliteEventRepository,
+    ///     cursor: Option<Cursor>,
+    /// ) -> Result<Page<SerializedEvent>, PersistenceError> {
+    ///     let query = EventQuery::new().with_aggregate_type("BankAccount").with_limit(50);
+    ///     match cursor {
+    ///         Some(cursor) => repo.query_events_page(&query.with_cursor(cursor)).await,
+    ///         None => repo.query_events_page(&query).await,
+    ///     }
+    /// }
+    /// ```
+    pub async fn query_events_page(
+        &self,
+        query: &EventQuery,
+    ) -> Result<Page<SerializedEvent>, PersistenceError> {
+        let events = self.select_query_events(query)?;
+        Ok(Page::new(events, query.limit, Cursor::events))
+    }
+
+    /// The number of events of the aggregate instance `aggregate_id`, e.g. to show how many
+    /// pages its history has without loading it. The events are counted on the primary key.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// use cqrs_es::persist::PersistenceError;
+    /// use rusqlite_es::SqliteEventRepository;
+    ///
+    /// async fn history_pages(repo: &SqliteEventRepository, id: &str) -> Result<usize, PersistenceError> {
+    ///     let events = repo.count_events::<MyAggregate>(id).await?;
+    ///     Ok(events.div_ceil(50))
+    /// }
+    /// ```
+    pub async fn count_events<A: Aggregate>(
+        &self,
+        aggregate_id: &str,
+    ) -> Result<usize, PersistenceError> {
+        let aggregate_type = A::aggregate_type();
+        check_access(
+            self.access_policy(),
+            AccessKind::Aggregate,
+            &aggregate_type,
+            aggregate_id,
+        )?;
+        Ok(self.count(
+            self.query_factory().count_events(),
+            &aggregate_type,
+            aggregate_id,
+        )?)
+    }
+
+    /// The number of events of type `event_type` across all instances of aggregate type `A`,
+    /// including those stored under a former name of the event type, see `EventTypeAliases`.
+    /// The events are counted on the `event_type` index.
+    pub async fn count_events_by_type<A: Aggregate>(
+        &self,
+        event_type: &str,
+    ) -> Result<usize, PersistenceError> {
+        let aggregate_type = A::aggregate_type();
+        let mut count = 0;
+        for stored_name in self.event_type_aliases().stored_names(event_type) {
+            count += self.count(
+                self.query_factory().count_events_by_type(),
+                &aggregate_type,
+                &stored_name,
+            )?;
+        }
+        Ok(count)
+    }
+
+    fn count(
+        &self,
+        sql: &str,
+        aggregate_type: &str,
+        key: &str,
+    ) -> Result<usize, SqliteAggregateError> {
+        let connection = self.pool().connection()?;
+        with_timeout(&connection, self.query_timeout(), || {
+            let count: i64 = connection
+                .prepare_cached(sql)?
+                .query_row((aggregate_type, key), |row| row.get(0))?;
+            Ok(count as usize)
+        })
+    }
+
+    // Loads the events matching the query along with their global position.
+    fn select_query_events(
+        &self,
+        query: &EventQuery,
+    ) -> Result<Vec<(SerializedEvent, i64)>, PersistenceError> {
+        if let Some(cursor) = &query.cursor {
+            cursor.event_position()?;
+        }
+        let aliases = self.event_type_aliases();
+        let aliased;
+        let query = match aliases.is_empty() {
+            true => query,
+            false => {
+                aliased = EventQuery {
+                    event_types: query
+                        .event_types
+                        .iter()
+                        .flat_map(|event_type| aliases.stored_names(event_type))
+                        .collect(),
+                    ..query.clone()
+                };
+                &aliased
+            }
+        };
+        let (sql, params) = query.to_sql(self.query_factory());
+        if let Some(index_advisor) = self.index_advisor() {
+            query.record_predicates(index_advisor, self.query_factory());
+        }
+        let connection = self.pool().connection()?;
+        let logged_params = params.iter().map(logged_param).collect::<Vec<_>>();
+        let logged_params = logged_params.iter().map(String::as_str).collect::<Vec<_>>();
+        timed(
+            self.slow_query_log(),
+            &sql,
+            &logged_params,
+            || {
+                with_timeout(&connection, self.query_timeout(), || {
+                    let mut statement = connection
+                        .prepare_cached(&sql)
+                        .map_err(SqliteAggregateError::from)?;
+                    let mut rows = statement
+                        .query(rusqlite::params_from_iter(params))
+                        .map_err(SqliteAggregateError::from)?;
+                    let mut result: Vec<(SerializedEvent, i64)> = Default::default();
+                    while let Some(row) = rows.next().map_err(SqliteAggregateError::from)? {
+                        let position = row.get("position").map_err(SqliteAggregateError::from)?;
+                        result.push((self.read_event(row)?, position));
+                    }
+                    Ok(result)
+                })
+            },
+            Vec::len,
+        )
+    }
+}
+
+fn logged_param(param: &Value) -> String {
+    match param {
+        Value::Null => "NULL".to_string(),
+        Value::Integer(value) => value.to_string(),
+        Value::Real(value) => value.to_string(),
+        Value::Text(value) => value.clone(),
+        Value::Blob(_) => REDACTED_PARAM.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use rusqlite::types::Value;
+    use serde_json::json;
+
+    use crate::sql_query::SqlQueryFactory;
+    use crate::testing::tests::{
+        test_event_envelope, Created, SomethingElse, TestAggregate, TestEvent, Tested,
+        TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, EventOrder, EventQuery, JsonEncoding, SqliteEventRepository};
+
+    #[test]
+    fn compiled_query() {
+        let query_factory = SqlQueryFactory::new("events", "snapshots", JsonEncoding::Text);
+        let (sql, params) = EventQuery::new()
+            .with_aggregate_type("TestAggregate")
+            .with_event_types(&["Created", "Tested"])
+            .with_metadata_key("user_id")
+            .with_positions(10..)
+            .with_order(EventOrder::MostRecent)
+            .with_limit(5)
+            .to_sql(&query_factory);
+        assert_eq!(
+            "
+SELECT aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata, rowid AS position
+  FROM events
+  WHERE aggregate_type = ? AND event_type IN (?, ?) AND json_type(metadata, '$.\"user_id\"') IS NOT NULL AND rowid >= ?
+  ORDER BY rowid DESC
+  LIMIT ?",
+            sql
+        );
+        assert_eq!(5, params.len());
+        assert_eq!(Some(&Value::Integer(5)), params.last());
+    }
+
+    #[tokio::test]
+    async fn query_events() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let repo = SqliteEventRepository::new(pool);
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = json!({"user_id": "alice"});
+        let mut tested = test_event_envelope(
+            &id,
+            2,
+            TestEvent::Tested(Tested {
+                test_name: "queried".to_string(),
+            }),
+        );
+        tested.metadata = json!({"user_id": "bob"});
+        let mut something_else = test_event_envelope(
+            &id,
+            3,
+            TestEvent::SomethingElse(SomethingElse {
+                description: "unattributed".to_string(),
+            }),
+        );
+        something_else.metadata = json!({});
+        repo.insert_events::<TestAggregate>(&[created, tested, something_else])
+            .await
+            .unwrap();
+
+        let (repo, id) = (&repo, &id);
+        let event_types = |query: EventQuery| async move {
+            repo.query_events(&query.with_aggregate_id(id))
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|event| event.event_type)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec!["Created", "Tested", "SomethingElse"],
+            event_types(EventQuery::new()).await
+        );
+        assert_eq!(
+            vec!["SomethingElse", "Tested"],
+            event_types(
+                EventQuery::new()
+                    .with_event_types(&["Tested", "SomethingElse"])
+                    .with_order(EventOrder::MostRecent)
+            )
+            .await
+        );
+        assert_eq!(
+            vec!["Tested"],
+            event_types(EventQuery::new().with_metadata("user_id", "bob")).await
+        );
+        assert_eq!(
+            vec!["Created"],
+            event_types(EventQuery::new().with_metadata_key("user_id").with_limit(1)).await
+        );
+        assert!(event_types(EventQuery::new().with_created(.."2000-01-01"))
+            .await
+            .is_empty());
+        assert!(
+            event_types(EventQuery::new().with_aggregate_type("Customer"))
+                .await
+                .is_empty()
+        );
+
+        assert_eq!(3, repo.count_events::<TestAggregate>(id).await.unwrap());
+        assert_eq!(
+            0,
+            repo.count_events::<TestAggregate>("unknown").await.unwrap()
+        );
+        assert_eq!(
+            1,
+            repo.count_events_by_type::<TestAggregate>("Tested")
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn query_events_page() {
+        let repo = SqliteEventRepository::connect(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .await
+            .unwrap();
+        let id = uuid::Uuid::new_v4().to_string();
+        let events = (1..=5)
+            .map(|sequence| {
+                let mut event = test_event_envelope(
+                    &id,
+                    sequence,
+                    TestEvent::Tested(Tested {
+                        test_name: sequence.to_string(),
+                    }),
+                );
+                event.metadata = json!({});
+                event
+            })
+            .collect::<Vec<_>>();
+        repo.insert_events::<TestAggregate>(&events).await.unwrap();
+
+        let query = EventQuery::new().with_aggregate_id(&id).with_limit(2);
+        let mut sequences = Vec::new();
+        let mut page = repo.query_events_page(&query).await.unwrap();
+        loop {
+            sequences.extend(page.items.iter().map(|event| event.sequence));
+            let Some(cursor) = page.next else {
+                break;
+            };
+            page = repo
+                .query_events_page(&query.clone().with_cursor(cursor))
+                .await
+                .unwrap();
+        }
+        assert_eq!(vec![1, 2, 3, 4, 5], sequences);
+
+        let recent = query.with_order(EventOrder::MostRecent);
+        let page = repo.query_events_page(&recent).await.unwrap();
+        let page = repo
+            .query_events_page(&recent.clone().with_cursor(page.next.unwrap()))
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![3, 2],
+            page.items
+                .iter()
+                .map(|event| event.sequence)
+                .collect::<Vec<_>>()
+        );
+
+        let view_cursor = "dgAAAAAAAAAB".parse().unwrap();
+        assert!(repo
+            .query_events_page(&recent.with_cursor(view_cursor))
+            .await
+            .is_err());
+    }
+}
@@ -1,15 +1,50 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use async_trait::async_trait;
 use cqrs_es::persist::{
     PersistedEventRepository, PersistenceError, ReplayStream, SerializedEvent, SerializedSnapshot,
 };
-use cqrs_es::Aggregate;
+use cqrs_es::{Aggregate, DomainEvent, EventEnvelope, View};
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{OptionalExtension, Row, Transaction, TransactionBehavior};
+use rusqlite::{CachedStatement, Connection, OptionalExtension, Params, Row, ToSql};
 use serde_json::Value;
 
+use crate::access_policy::check_access;
+use crate::audit::ACTOR_METADATA_KEY;
+use crate::blob_store::{resolved, ExternalPayloads, StagedBlobs};
+use crate::commit_receipt::ReceiptLog;
+use crate::connection::{in_transaction, with_checked_connection, ConnectionProvider};
 use crate::error::SqliteAggregateError;
+use crate::event_aliases::aliased;
+use crate::event_bus::CommitListener;
+use crate::event_metadata::MetadataInheritance;
+use crate::event_stream::{feed_events, push_to_replay_feed, push_to_sender, TrackedProgress};
+use crate::group_commit::GroupCommitter;
+use crate::hash_chain::chain_event;
+use crate::mapping::{deser_event, versioned_snapshot};
+use crate::metadata_codec::decoded;
+use crate::metadata_dictionary::{intern_global_position, intern_metadata};
+use crate::partitioning::route_partition;
+use crate::payload_dedup::intern_payload;
+use crate::query_timeout::with_timeout;
+use crate::slow_query::timed;
 use crate::sql_query::SqlQueryFactory;
+use crate::view_repository::TransactionalView;
+#[cfg(unix)]
+use crate::ChangeNotifier;
+use crate::{
+    AccessKind, AccessPolicy, BlobStore, CommitReceipt, CommitReceipts, ConflictLog,
+    ConsistencyToken, EventBus, EventCounts, EventMetadata, EventSchemaRegistry, EventTableLayout,
+    EventTypeAliases, GroupCommit, HashChain, IndexAdvisor, JsonEncoding, LatencyMetrics,
+    LatencyStats, MetadataCodec, MetadataPrecedence, MetadataStorage, Partitioning, PayloadKind,
+    PayloadLimits, PayloadStorage, ReplayProgress, SerializedEventStream, Shutdown, SlowQueryLog,
+    SnapshotPolicy, SnapshotUpcaster, SqliteViewRepository, StreamErrorPolicy, WriteTransaction,
+    PARTITION_POSITION_BITS, REDACTED_PARAM, UNVERSIONED_AGGREGATE,
+};
 
 const DEFAULT_EVENT_TABLE: &str = "events";
 const DEFAULT_SNAPSHOT_TABLE: &str = "snapshots";
@@ -17,20 +52,59 @@ const DEFAULT_SNAPSHOT_TABLE: &str = "snapshots";
 const DEFAULT_STREAMING_CHANNEL_SIZE: usize = 200;
 
 /// An event repository relying on a Sqlite database for persistence.
-pub struct SqliteEventRepository {
-    pool: Pool<SqliteConnectionManager>,
+///
+/// Cloning is cheap, clones share the connection pool and configuration.
+#[derive(Clone)]
+pub struct SqliteEventRepository<P = Pool<SqliteConnectionManager>> {
+    pool: P,
     query_factory: SqlQueryFactory,
     stream_channel_size: usize,
+    aggregate_versions: HashMap<String, String>,
+    metadata_inheritance: HashMap<String, MetadataInheritance>,
+    snapshot_upcasters: Arc<Vec<Box<dyn SnapshotUpcaster>>>,
+    rewrite_invalid_snapshots: bool,
+    validate_snapshots: bool,
+    json_encoding: JsonEncoding,
+    shutdown: Option<Shutdown>,
+    snapshot_policy: Option<Arc<dyn SnapshotPolicy>>,
+    event_counts: EventCounts,
+    write_transaction: WriteTransaction,
+    payload_limits: PayloadLimits,
+    event_schemas: EventSchemaRegistry,
+    transactional_views: Vec<Arc<dyn TransactionalView>>,
+    commit_receipts: CommitReceipts,
+    receipt_log: ReceiptLog,
+    replay_progress: Option<ReplayProgress>,
+    slow_query_log: Option<SlowQueryLog>,
+    query_timeout: Option<Duration>,
+    index_advisor: Option<IndexAdvisor>,
+    group_commit: Option<Arc<GroupCommitter>>,
+    commit_listeners: Vec<Arc<dyn CommitListener>>,
+    stream_error_policy: StreamErrorPolicy,
+    conflict_log: Option<ConflictLog>,
+    metadata_codec: Option<Arc<dyn MetadataCodec>>,
+    event_type_aliases: Arc<EventTypeAliases>,
+    external_payloads: Option<Arc<ExternalPayloads>>,
+    access_policy: Option<Arc<dyn AccessPolicy>>,
+    latency_metrics: Option<LatencyMetrics>,
+    actor_metadata_key: String,
+    active_writes: Arc<AtomicUsize>,
+    #[cfg(unix)]
+    change_notifier: Option<ChangeNotifier>,
 }
 
 #[async_trait]
-impl PersistedEventRepository for SqliteEventRepository {
+impl<P: ConnectionProvider> PersistedEventRepository for SqliteEventRepository<P> {
     async fn get_events<A: Aggregate>(
         &self,
         aggregate_id: &str,
     ) -> Result<Vec<SerializedEvent>, PersistenceError> {
-        self.select_events::<A>(aggregate_id, self.query_factory.select_events())
-            .await
+        let started = Instant::now();
+        let result = self
+            .select_events::<A>(aggregate_id, self.query_factory.select_events())
+            .await;
+        self.record_latency(LatencyMetrics::record_load, started);
+        result
     }
 
     async fn get_last_events<A: Aggregate>(
@@ -38,28 +112,21 @@ impl PersistedEventRepository for SqliteEventRepository {
         aggregate_id: &str,
         last_sequence: usize,
     ) -> Result<Vec<SerializedEvent>, PersistenceError> {
+        let started = Instant::now();
         let query = self.query_factory.get_last_events(last_sequence);
-        self.select_events::<A>(aggregate_id, &query).await
+        let result = self.select_events::<A>(aggregate_id, &query).await;
+        self.record_latency(LatencyMetrics::record_load, started);
+        result
     }
 
     async fn get_snapshot<A: Aggregate>(
         &self,
         aggregate_id: &str,
     ) -> Result<Option<SerializedSnapshot>, PersistenceError> {
-        let connection = self.pool.get().map_err(SqliteAggregateError::from)?;
-        let mut statement = connection
-            .prepare_cached(self.query_factory.select_snapshot())
-            .map_err(SqliteAggregateError::from)?;
-        match statement
-            .query_row((A::aggregate_type(), &aggregate_id), |row| {
-                self.deser_snapshot(row)
-            })
-            .optional()
-            .map_err(SqliteAggregateError::from)?
-        {
-            Some(snapshot) => Ok(Some(snapshot)),
-            None => Ok(None),
-        }
+        let started = Instant::now();
+        let result = self.load_snapshot::<A>(aggregate_id).await;
+        self.record_latency(LatencyMetrics::record_load, started);
+        result
     }
 
     async fn persist<A: Aggregate>(
@@ -67,106 +134,711 @@ impl PersistedEventRepository for SqliteEventRepository {
         events: &[SerializedEvent],
         snapshot_update: Option<(String, Value, usize)>,
     ) -> Result<(), PersistenceError> {
-        match snapshot_update {
-            None => {
-                self.insert_events::<A>(events)?;
-            }
-            Some((aggregate_id, aggregate, current_snapshot)) => {
-                println!("Aggregate ID ({aggregate_id})  Current snapshot: {current_snapshot}");
-                if current_snapshot == 1 {
-                    self.insert::<A>(aggregate, aggregate_id, current_snapshot, events)?;
-                } else {
-                    self.update::<A>(aggregate, aggregate_id, current_snapshot, events)?;
-                }
+        let started = Instant::now();
+        let result = self
+            .persist_tracked::<A>(events, snapshot_update, false)
+            .await;
+        self.record_latency(LatencyMetrics::record_commit, started);
+        if let (Err(PersistenceError::OptimisticLockError), Some(conflict_log)) =
+            (&result, &self.conflict_log)
+        {
+            if let Ok(connection) = self.pool.connection() {
+                conflict_log.record(&connection, &A::aggregate_type(), events);
             }
-        };
-        Ok(())
+        }
+        result
     }
 
     async fn stream_events<A: Aggregate>(
         &self,
         aggregate_id: &str,
     ) -> Result<ReplayStream, PersistenceError> {
-        Ok(stream_events(
+        check_access(
+            &self.access_policy,
+            AccessKind::Aggregate,
+            &A::aggregate_type(),
+            aggregate_id,
+        )?;
+        Ok(self.replay_stream(
             self.query_factory.select_events().to_string(),
-            A::aggregate_type(),
-            aggregate_id.to_string(),
-            self.pool.clone(),
-            self.stream_channel_size,
+            vec![A::aggregate_type(), aggregate_id.to_string()],
+            None,
         ))
     }
 
-    // TODO: aggregate id is unused here, `stream_events` function needs to be broken up
     async fn stream_all_events<A: Aggregate>(&self) -> Result<ReplayStream, PersistenceError> {
-        Ok(stream_events(
+        Ok(self.replay_stream(
             self.query_factory.all_events().to_string(),
-            A::aggregate_type(),
-            "".to_string(),
-            self.pool.clone(),
-            self.stream_channel_size,
+            vec![A::aggregate_type()],
+            self.tracked_progress(self.query_factory.count_all_events()),
         ))
     }
 }
 
-fn stream_events(
-    _query: String,
-    _aggregate_type: String,
-    _aggregate_id: String,
-    _pool: Pool<SqliteConnectionManager>,
-    channel_size: usize,
-) -> ReplayStream {
-    let (mut _feed, stream) = ReplayStream::new(channel_size);
-    // tokio::spawn(async move {
-    //     let connection = pool.get().unwrap();
-    //     let mut statement = connection.prepare_cached(&query).unwrap();
-    //     let mut rows = statement.query((&aggregate_type, &aggregate_id)).unwrap();
-    //     while let Some(row) = rows.next().unwrap() {
-    //         let event_result: Result<SerializedEvent, PersistenceError> =
-    //             SqliteEventRepository::deser_event(row).map_err(Into::into);
-    //         if feed.push(event_result).await.is_err() {
-    //             // TODO: in the unlikely event of a broken channel this error should be reported.
-    //             return;
-    //         };
-    //     }
-    // });
-    stream
-}
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    async fn load_snapshot<A: Aggregate>(
+        &self,
+        aggregate_id: &str,
+    ) -> Result<Option<SerializedSnapshot>, PersistenceError> {
+        check_access(
+            &self.access_policy,
+            AccessKind::Aggregate,
+            &A::aggregate_type(),
+            aggregate_id,
+        )?;
+        let (snapshot, aggregate_version) = match self.select_snapshot::<A>(aggregate_id)? {
+            Some(stored) => stored,
+            None => return Ok(None),
+        };
+        let current_snapshot = snapshot.current_snapshot;
+        // Deserializing the snapshot to validate it doubles the cost of loading it, it is only
+        // done when asked for.
+        let validate = self.validate_snapshots || self.rewrite_invalid_snapshots;
+        match self
+            .upcast_snapshot::<A>(snapshot, aggregate_version)
+            .filter(|snapshot| {
+                !validate || serde_json::from_value::<A>(snapshot.aggregate.clone()).is_ok()
+            }) {
+            Some(snapshot) => Ok(Some(snapshot)),
+            None if self.rewrite_invalid_snapshots => {
+                self.rewrite_snapshot::<A>(aggregate_id, current_snapshot)
+                    .await
+            }
+            // An unusable snapshot is ignored, the aggregate will be rebuilt from its events.
+            None => Ok(None),
+        }
+    }
+
+    fn record_latency(&self, record: fn(&LatencyMetrics, Duration), started: Instant) {
+        if let Some(latency_metrics) = &self.latency_metrics {
+            record(latency_metrics, started.elapsed());
+        }
+    }
+
+    fn replay_stream(
+        &self,
+        query: String,
+        params: Vec<String>,
+        progress: TrackedProgress,
+    ) -> ReplayStream {
+        let (feed, stream) = ReplayStream::new(self.stream_channel_size);
+        feed_events(
+            query,
+            params,
+            self.pool.clone(),
+            self.shutdown.clone(),
+            progress,
+            self.stream_error_policy.clone(),
+            decoded(
+                self.metadata_codec.clone(),
+                aliased(
+                    self.event_type_aliases.clone(),
+                    resolved(self.external_payloads.clone(), push_to_replay_feed(feed)),
+                ),
+            ),
+        );
+        stream
+    }
 
-impl SqliteEventRepository {
     async fn select_events<A: Aggregate>(
         &self,
         aggregate_id: &str,
         query: &str,
     ) -> Result<Vec<SerializedEvent>, PersistenceError> {
-        let connection = self.pool.get().map_err(SqliteAggregateError::from)?;
-        let mut statement = connection
-            .prepare_cached(query)
-            .map_err(SqliteAggregateError::from)?;
-        let mut rows = statement
-            .query((A::aggregate_type(), aggregate_id))
-            .map_err(SqliteAggregateError::from)?;
-        let mut result: Vec<SerializedEvent> = Default::default();
-        while let Some(row) = rows.next().map_err(SqliteAggregateError::from)? {
-            result.push(SqliteEventRepository::deser_event(row)?);
-        }
+        let aggregate_type = A::aggregate_type();
+        check_access(
+            &self.access_policy,
+            AccessKind::Aggregate,
+            &aggregate_type,
+            aggregate_id,
+        )?;
+        let connection = self.pool.connection()?;
+        let result = timed(
+            &self.slow_query_log,
+            query,
+            &[&aggregate_type, aggregate_id],
+            || {
+                with_timeout(&connection, self.query_timeout, || {
+                    let mut statement = connection
+                        .prepare_cached(query)
+                        .map_err(SqliteAggregateError::from)?;
+                    let mut rows = statement
+                        .query((&aggregate_type, aggregate_id))
+                        .map_err(SqliteAggregateError::from)?;
+                    let mut result: Vec<SerializedEvent> = Default::default();
+                    while let Some(row) = rows.next().map_err(SqliteAggregateError::from)? {
+                        result.push(self.read_event(row)?);
+                    }
+                    Ok::<_, PersistenceError>(result)
+                })
+            },
+            Vec::len,
+        )?;
         Ok(result)
     }
+
+    /// Streams all events for every aggregate type, interleaved in the order in which they were
+    /// committed (their global position).
+    ///
+    /// This is intended for system-wide consumers, e.g. audit logs or generic replicators, that
+    /// should not be limited to a single aggregate type.
+    ///
+    /// ```
+    /// use cqrs_es::persist::PersistenceError;
+    /// use rusqlite_es::SqliteEventRepository;
+    ///
+    /// async fn count_all_events(repo: &SqliteEventRepository) -> Result<usize, PersistenceError> {
+    ///     let mut stream = repo.stream_everything().await?;
+    ///     let mut count = 0;
+    ///     while let Some(event) = stream.next().await {
+    ///         event?;
+    ///         count += 1;
+    ///     }
+    ///     Ok(count)
+    /// }
+    /// ```
+    pub async fn stream_everything(&self) -> Result<SerializedEventStream, PersistenceError> {
+        let (sender, stream) = SerializedEventStream::new(self.stream_channel_size);
+        feed_events(
+            self.query_factory.everything().to_string(),
+            vec![],
+            self.pool.clone(),
+            self.shutdown.clone(),
+            self.tracked_progress(self.query_factory.count_everything()),
+            self.stream_error_policy.clone(),
+            decoded(
+                self.metadata_codec.clone(),
+                aliased(
+                    self.event_type_aliases.clone(),
+                    resolved(self.external_payloads.clone(), push_to_sender(sender)),
+                ),
+            ),
+        );
+        Ok(stream)
+    }
+
+    /// Builds a view for a single aggregate instance by folding its events through
+    /// `View::update`, without loading or persisting it through a view repository. Returns
+    /// `None` if the aggregate instance has no events.
+    ///
+    /// This is intended for ad-hoc inspection and for views read too rarely to justify a table,
+    /// every call replays all of the aggregate instance's events.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// # use cqrs_es::persist::doc::MyView;
+    /// use cqrs_es::persist::PersistenceError;
+    /// use rusqlite_es::SqliteEventRepository;
+    ///
+    /// async fn inspect(repo: &SqliteEventRepository, id: &str) -> Result<Option<MyView>, PersistenceError> {
+    ///     repo.materialize_view_on_the_fly::<MyView, MyAggregate>(id).await
+    /// }
+    /// ```
+    pub async fn materialize_view_on_the_fly<V, A>(
+        &self,
+        aggregate_id: &str,
+    ) -> Result<Option<V>, PersistenceError>
+    where
+        V: View<A>,
+        A: Aggregate,
+    {
+        let mut stream = PersistedEventRepository::stream_events::<A>(self, aggregate_id).await?;
+        let mut view: Option<V> = None;
+        while let Some(event) = stream.next::<A>(&None).await {
+            view.get_or_insert_with(Default::default).update(&event?);
+        }
+        Ok(view)
+    }
+
+    /// Loads an aggregate instance from its latest snapshot and the events committed after it,
+    /// rather than from all of its events. If there is no usable snapshot, all of the events are
+    /// loaded.
+    ///
+    /// Snapshots are read (and upcast) as by `get_snapshot`, the events are deserialized into
+    /// `EventEnvelope`s so that their metadata is available, see `SnapshotLoad::into_aggregate`
+    /// for the hydrated state.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// use cqrs_es::persist::PersistenceError;
+    /// use rusqlite_es::SqliteEventRepository;
+    ///
+    /// async fn load(repo: &SqliteEventRepository, id: &str) -> Result<MyAggregate, PersistenceError> {
+    ///     Ok(repo.load_from_snapshot::<MyAggregate>(id).await?.into_aggregate())
+    /// }
+    /// ```
+    pub async fn load_from_snapshot<A: Aggregate>(
+        &self,
+        aggregate_id: &str,
+    ) -> Result<SnapshotLoad<A>, PersistenceError> {
+        let (aggregate, snapshot_sequence, events) =
+            match PersistedEventRepository::get_snapshot::<A>(self, aggregate_id).await? {
+                Some(snapshot) => {
+                    let aggregate: A = serde_json::from_value(snapshot.aggregate)
+                        .map_err(SqliteAggregateError::from)?;
+                    let events = PersistedEventRepository::get_last_events::<A>(
+                        self,
+                        aggregate_id,
+                        snapshot.current_sequence,
+                    )
+                    .await?;
+                    (Some(aggregate), snapshot.current_sequence, events)
+                }
+                None => (
+                    None,
+                    0,
+                    PersistedEventRepository::get_events::<A>(self, aggregate_id).await?,
+                ),
+            };
+        let events = events
+            .into_iter()
+            .map(EventEnvelope::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(SnapshotLoad {
+            aggregate,
+            snapshot_sequence,
+            events,
+        })
+    }
+
+    /// Compacts an aggregate instance's stream by folding its events up to and including
+    /// sequence `through` into a single replacement event, then renumbering the remaining
+    /// events to follow it. Returns the number of events removed.
+    ///
+    /// The replacement event is returned by `fold`, which is given the folded events in order.
+    /// It keeps the first event's sequence number (1), global position and metadata, and is
+    /// checked against the configured payload limits and event schemas. The aggregate instance's
+    /// snapshot is deleted since it refers to the old sequence numbers. Compaction is refused in a
+    /// store configured with a `HashChain`, it would break the chain.
+    ///
+    /// The stream is rewritten within a single write transaction and afterwards the usual
+    /// optimistic locking applies to the renumbered stream. However, an aggregate instance loaded
+    /// before compaction holds the old sequence numbers, so compaction is intended to be run as
+    /// a maintenance operation while no commands are executed for the aggregate instance. Views
+    /// of the aggregate instance record the old sequence numbers and should be rebuilt.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// use rusqlite_es::{SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// async fn compact(repo: &SqliteEventRepository, id: &str) -> Result<usize, SqliteAggregateError> {
+    ///     repo.compact_stream::<MyAggregate, _>(id, 1000, |events| {
+    ///         // ... summarize the effect of the events ...
+    ///         # events.last().unwrap().payload.clone()
+    ///     })
+    ///     .await
+    /// }
+    /// ```
+    pub async fn compact_stream<A, F>(
+        &self,
+        aggregate_id: &str,
+        through: usize,
+        fold: F,
+    ) -> Result<usize, SqliteAggregateError>
+    where
+        A: Aggregate,
+        F: Fn(&[EventEnvelope<A>]) -> A::Event,
+    {
+        if self.query_factory.partitioning() != Partitioning::None {
+            return Err(SqliteAggregateError::UnknownError(
+                "streams cannot be compacted in a partitioned store".into(),
+            ));
+        }
+        // the replacement event and the renumbered events would no longer match their hashes
+        if self.query_factory.hash_chain() != HashChain::None {
+            return Err(SqliteAggregateError::UnknownError(
+                "streams cannot be compacted in a store with a hash chain".into(),
+            ));
+        }
+        let aggregate_type = A::aggregate_type();
+        let (removed, remaining) = self
+            .write_async(|tx| {
+                let mut statement = tx.prepare_cached(self.query_factory.select_events())?;
+                let mut rows = statement.query((&aggregate_type, aggregate_id))?;
+                let mut folded: Vec<EventEnvelope<A>> = Vec::new();
+                let mut remaining = 0;
+                let mut last_sequence = 0;
+                while let Some(row) = rows.next()? {
+                    let event = self.read_event(row)?;
+                    last_sequence = event.sequence as i64;
+                    if event.sequence <= through {
+                        folded.push(EventEnvelope::try_from(event).map_err(|err| {
+                            SqliteAggregateError::DeserializationError(Box::new(err))
+                        })?);
+                    } else {
+                        remaining += 1;
+                    }
+                }
+                if folded.len() < 2 {
+                    return Ok((0, 0));
+                }
+                let last_folded = folded.len();
+
+                let replacement = fold(&folded);
+                let mut payload = serde_json::to_value(&replacement)?;
+                self.event_schemas.validate_payload(
+                    &replacement.event_type(),
+                    &replacement.event_version(),
+                    &payload,
+                )?;
+                if let Some(external_payloads) = &self.external_payloads {
+                    payload =
+                        external_payloads.externalize(&aggregate_type, aggregate_id, payload)?;
+                }
+                self.payload_limits
+                    .check(PayloadKind::Event, aggregate_id, &payload)?;
+                tx.execute(
+                    self.query_factory.compact_first_event(),
+                    (
+                        replacement.event_type(),
+                        replacement.event_version(),
+                        &payload,
+                        &aggregate_type,
+                        aggregate_id,
+                    ),
+                )?;
+                tx.execute(
+                    self.query_factory.delete_compacted_events(),
+                    (&aggregate_type, aggregate_id, last_folded as i64),
+                )?;
+                // Renumbering is done in two steps, first moving the remaining events beyond the last
+                // sequence number, since renumbering in place could collide with the primary key of
+                // an event not yet renumbered.
+                tx.execute(
+                    self.query_factory.shift_events(),
+                    (
+                        last_sequence,
+                        &aggregate_type,
+                        aggregate_id,
+                        last_folded as i64,
+                    ),
+                )?;
+                tx.execute(
+                    self.query_factory.restore_shifted_events(),
+                    (
+                        last_sequence + last_folded as i64 - 1,
+                        &aggregate_type,
+                        aggregate_id,
+                        last_sequence,
+                    ),
+                )?;
+                tx.execute(
+                    self.query_factory.delete_snapshot(),
+                    (&aggregate_type, aggregate_id),
+                )?;
+                Ok((last_folded - 1, remaining))
+            })
+            .await?;
+        if removed > 0 && self.snapshot_policy.is_some() {
+            self.event_counts
+                .set(&aggregate_type, aggregate_id, remaining + 1);
+        }
+        Ok(removed)
+    }
+
+    /// Appends events to an aggregate instance without loading it, allocating their sequence
+    /// numbers following the instance's last event within the write transaction.
+    ///
+    /// This is intended for append-only streams, e.g. telemetry, whose writers cannot know the
+    /// current sequence number. Concurrent appends to the same aggregate instance never conflict,
+    /// since write transactions are serialized by SQLite, but the aggregate's `handle` and any
+    /// snapshot offered by the framework are bypassed. The events are stored without metadata
+    /// and are handed to the configured transactional views and commit listeners.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::{MyAggregate, MyEvents};
+    /// use rusqlite_es::{CommitReceipt, SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// async fn record(
+    ///     repo: &SqliteEventRepository,
+    ///     device_id: &str,
+    ///     readings: &[MyEvents],
+    /// ) -> Result<CommitReceipt, SqliteAggregateError> {
+    ///     repo.append_unordered::<MyAggregate>(device_id, readings).await
+    /// }
+    /// ```
+    pub async fn append_unordered<A: Aggregate>(
+        &self,
+        aggregate_id: &str,
+        events: &[A::Event],
+    ) -> Result<CommitReceipt, SqliteAggregateError> {
+        let aggregate_type = A::aggregate_type();
+        let payloads = events
+            .iter()
+            .map(|event| {
+                Ok((
+                    event.event_type(),
+                    event.event_version(),
+                    serde_json::to_value(event)?,
+                ))
+            })
+            .collect::<Result<Vec<_>, SqliteAggregateError>>()?;
+        let _staged = self
+            .stage_blobs(
+                payloads
+                    .iter()
+                    .map(|(_, _, payload)| (aggregate_type.as_str(), aggregate_id, payload)),
+            )
+            .await?;
+        let (receipt, appended) = self
+            .write_async(|tx| {
+                let last_sequence: i64 = tx.query_row(
+                    self.query_factory.last_sequence(),
+                    (&aggregate_type, aggregate_id),
+                    |row| row.get(0),
+                )?;
+                let appended = payloads
+                    .iter()
+                    .zip(last_sequence as usize + 1..)
+                    .map(|((event_type, event_version, payload), sequence)| {
+                        SerializedEvent::new(
+                            aggregate_id.to_string(),
+                            sequence,
+                            aggregate_type.clone(),
+                            event_type.clone(),
+                            event_version.clone(),
+                            payload.clone(),
+                            serde_json::json!({}),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                let receipt =
+                    self.persist_events::<A>(self.query_factory.insert_event(), tx, &appended)?;
+                Ok((receipt, appended))
+            })
+            .await?;
+        self.event_counts
+            .add_if_tracked(&aggregate_type, aggregate_id, appended.len());
+        self.committed(&aggregate_type, &appended, receipt);
+        Ok(receipt)
+    }
+
+    /// Inserts the events within a single transaction, skipping events whose sequence already
+    /// exists for their aggregate instance, e.g. to re-run an import that was interrupted or
+    /// whose source overlaps an earlier one. Events may belong to any number of aggregate types
+    /// and are checked against the repository's payload limits and event schemas.
+    ///
+    /// A skipped event is not compared with the stored one. Views, including transactional
+    /// views, are not updated and should be rebuilt after importing, see `import::bulk_load`.
+    ///
+    /// ```
+    /// use cqrs_es::persist::SerializedEvent;
+    /// use rusqlite_es::{SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// async fn reimport(
+    ///     repo: &SqliteEventRepository,
+    ///     events: &[SerializedEvent],
+    /// ) -> Result<(), SqliteAggregateError> {
+    ///     let inserted = repo.insert_events_if_absent(events).await?;
+    ///     println!("{} imported, {} already present", inserted.inserted, inserted.skipped);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn insert_events_if_absent(
+        &self,
+        events: &[SerializedEvent],
+    ) -> Result<InsertedEvents, SqliteAggregateError> {
+        let _staged = self.stage_event_blobs(None, events).await?;
+        let inserted = self
+            .write_async(|tx| {
+                let mut inserted = 0;
+                for event in events {
+                    let receipt = self.insert_event_row(
+                        self.query_factory.insert_event_if_absent(),
+                        tx,
+                        &event.aggregate_type,
+                        event,
+                        true,
+                    )?;
+                    inserted += usize::from(receipt.is_some());
+                }
+                Ok(inserted)
+            })
+            .await?;
+        Ok(InsertedEvents {
+            inserted,
+            skipped: events.len() - inserted,
+        })
+    }
+
+    /// Commits events of an aggregate instance like `persist` (without a snapshot update), also
+    /// running `statements` within the same transaction, e.g. to update the application's own
+    /// tables or to write to an outbox.
+    ///
+    /// `statements` runs after the events are inserted and may run again if a deferred write
+    /// transaction is retried, see `WriteTransaction`. An error returned by `statements` rolls
+    /// back the events, an optimistic lock conflict of the events is reported before
+    /// `statements` runs. Group commit is bypassed.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// use cqrs_es::persist::SerializedEvent;
+    /// use rusqlite_es::{CommitReceipt, SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// async fn commit_with_outbox(
+    ///     repo: &SqliteEventRepository,
+    ///     events: &[SerializedEvent],
+    /// ) -> Result<CommitReceipt, SqliteAggregateError> {
+    ///     repo.persist_with::<MyAggregate, _>(events, |tx| {
+    ///         for event in tx.events() {
+    ///             tx.execute(
+    ///                 "INSERT INTO outbox (aggregate_id, sequence, payload) VALUES (?, ?, ?)",
+    ///                 (&event.aggregate_id, event.sequence as i64, &event.payload),
+    ///             )?;
+    ///         }
+    ///         Ok(())
+    ///     })
+    ///     .await
+    /// }
+    /// ```
+    pub async fn persist_with<A, F>(
+        &self,
+        events: &[SerializedEvent],
+        statements: F,
+    ) -> Result<CommitReceipt, SqliteAggregateError>
+    where
+        A: Aggregate,
+        F: Fn(&CommitTransaction<'_>) -> Result<(), rusqlite::Error>,
+    {
+        let aggregate_type = A::aggregate_type();
+        let _staged = self
+            .stage_event_blobs(Some(&aggregate_type), events)
+            .await?;
+        let receipt = self
+            .write_async(|tx| {
+                let receipt =
+                    self.persist_events::<A>(self.query_factory.insert_event(), tx, events)?;
+                statements(&CommitTransaction { tx, events })?;
+                if tx.is_autocommit() {
+                    return Err(SqliteAggregateError::UnknownError(
+                        "the commit transaction was ended by the statements run with it".into(),
+                    ));
+                }
+                Ok(receipt)
+            })
+            .await?;
+        if let Some(event) = events.first() {
+            self.event_counts
+                .add_if_tracked(&aggregate_type, &event.aggregate_id, events.len());
+        }
+        self.committed(&aggregate_type, events, receipt);
+        Ok(receipt)
+    }
+}
+
+/// The outcome of `SqliteEventRepository::insert_events_if_absent`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InsertedEvents {
+    /// The number of events inserted.
+    pub inserted: usize,
+    /// The number of events skipped because their sequence already existed.
+    pub skipped: usize,
+}
+
+/// The transaction committing events, handed to the statements run by
+/// `SqliteEventRepository::persist_with`. It only allows running statements, the transaction
+/// itself is committed or rolled back by the repository and transaction control statements
+/// (e.g. `COMMIT` or `SAVEPOINT`) are rejected.
+pub struct CommitTransaction<'a> {
+    tx: &'a Connection,
+    events: &'a [SerializedEvent],
+}
+
+impl<'a> CommitTransaction<'a> {
+    /// The events being committed.
+    pub fn events(&self) -> &[SerializedEvent] {
+        self.events
+    }
+
+    /// Executes a statement, returning the number of rows changed, see `Connection::execute`.
+    pub fn execute<T: Params>(&self, sql: &str, params: T) -> Result<usize, rusqlite::Error> {
+        self.tx.execute(statement(sql)?, params)
+    }
+
+    /// Runs a query returning a single row, see `Connection::query_row`.
+    pub fn query_row<T, F, R>(&self, sql: &str, params: T, f: F) -> Result<R, rusqlite::Error>
+    where
+        T: Params,
+        F: FnOnce(&Row<'_>) -> Result<R, rusqlite::Error>,
+    {
+        self.tx.query_row(statement(sql)?, params, f)
+    }
+
+    /// Prepares a statement, caching it on the connection, see `Connection::prepare_cached`.
+    pub fn prepare_cached(&self, sql: &str) -> Result<CachedStatement<'_>, rusqlite::Error> {
+        self.tx.prepare_cached(statement(sql)?)
+    }
+}
+
+// Rejects statements that would end the transaction of a `CommitTransaction`.
+fn statement(sql: &str) -> Result<&str, rusqlite::Error> {
+    let keyword = sql
+        .trim_start()
+        .split(|c: char| !c.is_ascii_alphabetic())
+        .next()
+        .unwrap_or_default();
+    match keyword.to_ascii_uppercase().as_str() {
+        "BEGIN" | "COMMIT" | "END" | "ROLLBACK" | "SAVEPOINT" | "RELEASE" => {
+            Err(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_MISUSE),
+                Some("transaction control statements cannot run within a commit".to_string()),
+            ))
+        }
+        _ => Ok(sql),
+    }
+}
+
+/// An aggregate instance loaded by `SqliteEventRepository::load_from_snapshot`.
+#[derive(Debug)]
+pub struct SnapshotLoad<A: Aggregate> {
+    /// The aggregate state from the latest snapshot, `None` if there is no usable snapshot.
+    pub aggregate: Option<A>,
+    /// The sequence number of the last event included in the snapshot, 0 without a snapshot.
+    pub snapshot_sequence: usize,
+    /// The events committed after the snapshot, in order.
+    pub events: Vec<EventEnvelope<A>>,
+}
+
+impl<A: Aggregate> SnapshotLoad<A> {
+    /// The sequence number of the last event, i.e. the aggregate instance's version.
+    pub fn current_sequence(&self) -> usize {
+        self.events
+            .last()
+            .map_or(self.snapshot_sequence, |event| event.sequence)
+    }
+
+    /// Applies the events to the snapshot state (or to the default state, without a snapshot).
+    pub fn into_aggregate(self) -> A {
+        let mut aggregate = self.aggregate.unwrap_or_default();
+        for event in self.events {
+            aggregate.apply(event.payload);
+        }
+        aggregate
+    }
 }
 
-impl SqliteEventRepository {
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    pub(crate) fn pool(&self) -> &P {
+        &self.pool
+    }
+
+    pub(crate) fn query_factory(&self) -> &SqlQueryFactory {
+        &self.query_factory
+    }
+
     /// Creates a new `SqliteEventRepository` from the provided database connection.
     /// This uses the default tables 'events' and 'snapshots'.
     ///
     /// ```
     /// use r2d2::Pool;
     /// use r2d2_sqlite::SqliteConnectionManager;
-    /// use sqlite_es::SqliteEventRepository;
+    /// use rusqlite_es::SqliteEventRepository;
     ///
     /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
     ///     SqliteEventRepository::new(pool)
     /// }
     /// ```
-    pub fn new(pool: Pool<SqliteConnectionManager>) -> Self {
+    pub fn new(pool: P) -> Self {
         Self::use_tables(pool, DEFAULT_EVENT_TABLE, DEFAULT_SNAPSHOT_TABLE)
     }
 
@@ -176,7 +848,7 @@ impl SqliteEventRepository {
     /// ```
     /// use r2d2::Pool;
     /// use r2d2_sqlite::SqliteConnectionManager;
-    /// use sqlite_es::SqliteEventRepository;
+    /// use rusqlite_es::SqliteEventRepository;
     ///
     /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
     ///     let store = SqliteEventRepository::new(pool);
@@ -185,214 +857,2487 @@ impl SqliteEventRepository {
     /// ```
     pub fn with_streaming_channel_size(self, stream_channel_size: usize) -> Self {
         Self {
-            pool: self.pool,
-            query_factory: self.query_factory,
             stream_channel_size,
+            ..self
         }
     }
 
-    /// Configures a `SqliteEventRepository` to use the provided table names.
+    /// Configures the current version of an aggregate's serialized structure. This version is
+    /// stored alongside each snapshot of the aggregate and, when loading a snapshot stored with
+    /// a different version, the registered snapshot upcasters are applied. If the snapshot
+    /// cannot be upcast to the current version it is ignored and the aggregate is rebuilt from
+    /// its events.
     ///
-    /// _Example: configure the repository to use "my_event_table" and "my_snapshot_table"
-    /// for the event and snapshot table names._
+    /// Aggregates without a configured version use `UNVERSIONED_AGGREGATE`.
+    ///
+    /// _Example: mark the current structure of `MyAggregate` as version "2.0"._
     /// ```
+    /// # use cqrs_es::doc::MyAggregate;
     /// use r2d2::Pool;
     /// use r2d2_sqlite::SqliteConnectionManager;
-    /// use sqlite_es::SqliteEventRepository;
+    /// use rusqlite_es::SqliteEventRepository;
     ///
     /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
     ///     let store = SqliteEventRepository::new(pool);
-    ///     store.with_tables("my_event_table", "my_snapshot_table")
+    ///     store.with_aggregate_version::<MyAggregate>("2.0")
     /// }
     /// ```
-    pub fn with_tables(self, events_table: &str, snapshots_table: &str) -> Self {
-        Self::use_tables(self.pool, events_table, snapshots_table)
+    pub fn with_aggregate_version<A: Aggregate>(mut self, aggregate_version: &str) -> Self {
+        self.aggregate_versions
+            .insert(A::aggregate_type(), aggregate_version.to_string());
+        self
     }
 
-    fn use_tables(
-        pool: Pool<SqliteConnectionManager>,
-        events_table: &str,
-        snapshots_table: &str,
-    ) -> Self {
+    /// Configures the repository to merge the metadata of each event of `A`, see
+    /// `EventMetadata`, into the metadata of the command that produced it, `precedence`
+    /// deciding between entries with the same key. The merged metadata is stored with each
+    /// event, so events loaded or streamed from the store carry it; the events handed to queries
+    /// by the framework carry the command's metadata.
+    ///
+    /// ```
+    /// use cqrs_es::Aggregate;
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{EventMetadata, MetadataPrecedence, SqliteEventRepository};
+    ///
+    /// fn configure_repo<A>(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository
+    /// where
+    ///     A: Aggregate,
+    ///     A::Event: EventMetadata,
+    /// {
+    ///     SqliteEventRepository::new(pool).with_event_metadata::<A>(MetadataPrecedence::Command)
+    /// }
+    /// ```
+    pub fn with_event_metadata<A>(mut self, precedence: MetadataPrecedence) -> Self
+    where
+        A: Aggregate,
+        A::Event: EventMetadata,
+    {
+        self.metadata_inheritance.insert(
+            A::aggregate_type(),
+            MetadataInheritance::new::<A>(precedence),
+        );
+        self
+    }
+
+    /// Configures the repository to read events stored under a former event type as events of
+    /// their current type, see `EventTypeAliases`. `EventQuery::with_event_types` also matches
+    /// the former names of the queried event types.
+    ///
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{EventTypeAliases, SqliteEventRepository};
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    ///     let aliases = EventTypeAliases::new().alias("AccountOpenedV1", "AccountOpened");
+    ///     SqliteEventRepository::new(pool).with_event_type_aliases(aliases)
+    /// }
+    /// ```
+    pub fn with_event_type_aliases(self, event_type_aliases: EventTypeAliases) -> Self {
         Self {
-            pool,
-            query_factory: SqlQueryFactory::new(events_table, snapshots_table),
-            stream_channel_size: DEFAULT_STREAMING_CHANNEL_SIZE,
+            event_type_aliases: Arc::new(event_type_aliases),
+            ..self
         }
     }
 
-    pub(crate) fn insert_events<A: Aggregate>(
-        &self,
-        events: &[SerializedEvent],
-    ) -> Result<(), SqliteAggregateError> {
-        let mut connection = self.pool.get().map_err(SqliteAggregateError::from)?;
-        let tx = connection
-            .transaction_with_behavior(TransactionBehavior::Immediate)
-            .map_err(SqliteAggregateError::from)?;
-        self.persist_events::<A>(self.query_factory.insert_event(), &tx, events)?;
-        tx.commit().map_err(SqliteAggregateError::from)?;
-        Ok(())
+    pub(crate) fn event_type_aliases(&self) -> &EventTypeAliases {
+        &self.event_type_aliases
     }
 
-    pub(crate) fn insert<A: Aggregate>(
-        &self,
-        aggregate_payload: Value,
-        aggregate_id: String,
-        current_snapshot: usize,
-        events: &[SerializedEvent],
-    ) -> Result<(), SqliteAggregateError> {
-        let mut connection = self.pool.get().map_err(SqliteAggregateError::from)?;
-        let tx = connection
-            .transaction_with_behavior(TransactionBehavior::Immediate)
-            .map_err(SqliteAggregateError::from)?;
-
-        let current_sequence =
-            self.persist_events::<A>(self.query_factory.insert_event(), &tx, events)?;
-
-        let mut statement = tx
-            .prepare_cached(self.query_factory.insert_snapshot())
-            .map_err(SqliteAggregateError::from)?;
-        statement
-            .execute((
-                A::aggregate_type(),
-                aggregate_id.as_str(),
-                current_sequence as i32,
-                current_snapshot as i32,
-                &aggregate_payload,
-            ))
-            .map_err(SqliteAggregateError::from)?;
-        drop(statement);
+    /// Configures the repository to use snapshot upcasters when loading snapshots stored with
+    /// an outdated aggregate version. The upcasters are applied in the order provided.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{SqliteEventRepository, VersionSnapshotUpcaster};
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    ///     let upcaster = VersionSnapshotUpcaster::new("MyAggregate", "1.0", "2.0", Box::new(|v| v));
+    ///     SqliteEventRepository::new(pool)
+    ///         .with_aggregate_version::<MyAggregate>("2.0")
+    ///         .with_snapshot_upcasters(vec![Box::new(upcaster)])
+    /// }
+    /// ```
+    pub fn with_snapshot_upcasters(
+        self,
+        snapshot_upcasters: Vec<Box<dyn SnapshotUpcaster>>,
+    ) -> Self {
+        Self {
+            snapshot_upcasters: Arc::new(snapshot_upcasters),
+            ..self
+        }
+    }
 
-        tx.commit().map_err(SqliteAggregateError::from)?;
-        Ok(())
+    /// Configures whether a snapshot that cannot be used, because it can neither be upcast to the
+    /// current aggregate version nor deserialized into the aggregate, is immediately rewritten.
+    /// Enabling this option also enables snapshot validation, see `with_snapshot_validation`.
+    ///
+    /// Unusable snapshots are ignored, the aggregate is instead rebuilt by replaying its events.
+    /// By default the replacement snapshot is only written once the next snapshot is due; with
+    /// this option enabled the repository replays the events itself and rewrites the snapshot
+    /// while loading. Note that events are replayed without any event upcasters, if they cannot
+    /// be deserialized the snapshot is left for the event store to replace.
+    ///
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::SqliteEventRepository;
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    ///     let store = SqliteEventRepository::new(pool);
+    ///     store.with_invalid_snapshot_rewrite(true)
+    /// }
+    /// ```
+    pub fn with_invalid_snapshot_rewrite(self, rewrite_invalid_snapshots: bool) -> Self {
+        Self {
+            rewrite_invalid_snapshots,
+            ..self
+        }
     }
 
-    pub(crate) fn update<A: Aggregate>(
-        &self,
-        aggregate_payload: Value,
-        aggregate_id: String,
-        current_snapshot: usize,
-        events: &[SerializedEvent],
-    ) -> Result<(), SqliteAggregateError> {
-        let mut connection = self.pool.get().map_err(SqliteAggregateError::from)?;
-        let tx = connection
-            .transaction_with_behavior(TransactionBehavior::Immediate)
-            .map_err(SqliteAggregateError::from)?;
+    /// Configures whether snapshots are deserialized into the aggregate as they are loaded, to
+    /// ignore those that cannot be deserialized and rebuild the aggregate from its events
+    /// instead. Disabled by default, a snapshot that cannot be deserialized then fails the
+    /// command loading it, unless `with_invalid_snapshot_rewrite` is enabled.
+    ///
+    /// Validation deserializes every snapshot a second time, the event store deserializes it
+    /// again once loaded. Snapshots that cannot be upcast to the current aggregate version are
+    /// ignored either way.
+    ///
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::SqliteEventRepository;
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    ///     let store = SqliteEventRepository::new(pool);
+    ///     store.with_snapshot_validation(true)
+    /// }
+    /// ```
+    pub fn with_snapshot_validation(self, validate_snapshots: bool) -> Self {
+        Self {
+            validate_snapshots,
+            ..self
+        }
+    }
 
-        let current_sequence =
-            self.persist_events::<A>(self.query_factory.insert_event(), &tx, events)?;
-        println!("Current sequence: {current_sequence}");
+    /// Configures the encoding used to store event payloads, metadata and snapshots.
+    ///
+    /// With `JsonEncoding::Jsonb` rows previously written as text remain readable, allowing an
+    /// existing store to switch encodings without rewriting its tables. `ready` fails with
+    /// `SqliteAggregateError::UnsupportedSqliteVersion` if the linked SQLite library does not
+    /// provide the JSONB functions.
+    ///
+    /// _Example: store JSON using SQLite's JSONB encoding (requires SQLite 3.45.0 or later)._
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{JsonEncoding, SqliteEventRepository};
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    ///     let store = SqliteEventRepository::new(pool);
+    ///     store.with_json_encoding(JsonEncoding::Jsonb)
+    /// }
+    /// ```
+    pub fn with_json_encoding(self, json_encoding: JsonEncoding) -> Self {
+        Self {
+            query_factory: self.query_factory.with_json_encoding(json_encoding),
+            json_encoding,
+            ..self
+        }
+    }
 
-        let mut statement = tx
-            .prepare_cached(self.query_factory.update_snapshot())
-            .map_err(SqliteAggregateError::from)?;
-        let rows_affected = statement
-            .execute((
-                current_sequence as i32,
-                &aggregate_payload,
-                current_snapshot as i32,
-                A::aggregate_type(),
-                aggregate_id.as_str(),
-                (current_snapshot - 1) as i32,
-            ))
-            .map_err(SqliteAggregateError::from)?;
-        drop(statement);
+    /// Configures how event metadata is stored, see `MetadataStorage`. Events already stored
+    /// with their metadata inline remain readable and may be compacted with `compact_metadata`.
+    ///
+    /// _Example: intern repeated metadata entries in the `metadata_dictionary` table._
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{MetadataStorage, SqliteEventRepository};
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    ///     let store = SqliteEventRepository::new(pool);
+    ///     store.with_metadata_storage(MetadataStorage::Dictionary)
+    /// }
+    /// ```
+    pub fn with_metadata_storage(self, metadata_storage: MetadataStorage) -> Self {
+        Self {
+            query_factory: self.query_factory.with_metadata_storage(metadata_storage),
+            ..self
+        }
+    }
 
-        tx.commit().map_err(SqliteAggregateError::from)?;
-        println!("Rows affected: {rows_affected}");
-        match rows_affected {
-            1 => Ok(()),
-            _ => Err(SqliteAggregateError::OptimisticLock),
+    /// Configures how event payloads are stored, see `PayloadStorage`. Events already stored
+    /// with their payload inline remain readable.
+    ///
+    /// _Example: store repeated payloads once in the `event_payloads` table._
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{PayloadStorage, SqliteEventRepository};
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    ///     let store = SqliteEventRepository::new(pool);
+    ///     store.with_payload_storage(PayloadStorage::Deduplicated)
+    /// }
+    /// ```
+    pub fn with_payload_storage(self, payload_storage: PayloadStorage) -> Self {
+        Self {
+            query_factory: self.query_factory.with_payload_storage(payload_storage),
+            ..self
         }
     }
 
-    fn deser_event(row: &Row) -> Result<SerializedEvent, SqliteAggregateError> {
-        let aggregate_type: String = row
-            .get("aggregate_type")
-            .map_err(SqliteAggregateError::from)?;
-        let aggregate_id: String = row
-            .get("aggregate_id")
-            .map_err(SqliteAggregateError::from)?;
-        let sequence = {
-            let s: i64 = row.get("sequence").map_err(SqliteAggregateError::from)?;
-            s as usize
-        };
-        let event_type: String = row.get("event_type").map_err(SqliteAggregateError::from)?;
-        let event_version: String = row
-            .get("event_version")
-            .map_err(SqliteAggregateError::from)?;
-        let payload: Value = row.get("payload").map_err(SqliteAggregateError::from)?;
-        let metadata: Value = row.get("metadata").map_err(SqliteAggregateError::from)?;
-        Ok(SerializedEvent::new(
-            aggregate_id,
-            sequence,
-            aggregate_type,
-            event_type,
-            event_version,
-            payload,
-            metadata,
-        ))
+    /// Configures how new events are partitioned across event tables, see `Partitioning`.
+    ///
+    /// Events are committed to the current partition, which is created as needed, and read
+    /// through the `{events}_all` view over the events table and all of its partitions. The
+    /// `events_partitions` table and `events_all` view of `db/init.sql` must exist (named after
+    /// the events table if it is configured). Events committed before partitioning was configured
+    /// remain in the events table. Whole partitions can be dropped with `drop_partition`.
+    ///
+    /// Global positions of partitioned events hold the partition's ordinal in their high bits,
+    /// see `PARTITION_POSITION_BITS`, so they remain unique and in commit order but are not
+    /// contiguous. Operations that rewrite stored events, `compact_stream` and
+    /// `compact_metadata`, are not supported on a partitioned store.
+    ///
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{Partitioning, SqliteEventRepository};
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    ///     let store = SqliteEventRepository::new(pool);
+    ///     store.with_partitioning(Partitioning::Monthly)
+    /// }
+    /// ```
+    pub fn with_partitioning(self, partitioning: Partitioning) -> Self {
+        Self {
+            query_factory: self.query_factory.with_partitioning(partitioning),
+            ..self
+        }
     }
 
-    fn deser_snapshot(&self, row: &Row) -> Result<SerializedSnapshot, rusqlite::Error> {
-        let aggregate_id = row.get("aggregate_id")?;
-        let s: i64 = row.get("last_sequence")?;
-        let current_sequence = s as usize;
-        let s: i64 = row.get("current_snapshot")?;
-        let current_snapshot = s as usize;
-        let aggregate: Value = row.get("payload")?;
-        Ok(SerializedSnapshot {
-            aggregate_id,
-            aggregate,
-            current_sequence,
-            current_snapshot,
-        })
+    /// Configures how events are keyed and ordered within the events table, see
+    /// `EventTableLayout`. The layout also applies to partitions created with `with_partitioning`.
+    ///
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{EventTableLayout, ReadyRepository, SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// async fn configure_repo(pool: Pool<SqliteConnectionManager>) -> Result<ReadyRepository, SqliteAggregateError> {
+    ///     SqliteEventRepository::new(pool)
+    ///         .with_table_layout(EventTableLayout::WithoutRowid)
+    ///         .ready()
+    ///         .await
+    /// }
+    /// ```
+    pub fn with_table_layout(self, table_layout: EventTableLayout) -> Self {
+        Self {
+            query_factory: self.query_factory.with_table_layout(table_layout),
+            ..self
+        }
     }
 
-    fn persist_events<A: Aggregate>(
+    /// Links committed events by their hashes, so that changes to past events can be detected
+    /// with `verify_hash_chain`, see `HashChain`. Each commit then also reads back and hashes the
+    /// events it inserted.
+    ///
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{HashChain, SqliteEventRepository};
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    ///     SqliteEventRepository::new(pool).with_hash_chain(HashChain::Global)
+    /// }
+    /// ```
+    pub fn with_hash_chain(self, hash_chain: HashChain) -> Self {
+        Self {
+            query_factory: self.query_factory.with_hash_chain(hash_chain),
+            ..self
+        }
+    }
+
+    /// Adds a trigger to the schema created by `ready`, deleting the snapshot of an aggregate
+    /// instance once its last event is deleted, so that removing an aggregate instance, e.g. to
+    /// erase personal data, only requires deleting its events:
+    ///
+    /// ```sql
+    /// DELETE FROM events WHERE aggregate_type = 'Customer' AND aggregate_id = 'customer-1';
+    /// ```
+    ///
+    /// The trigger is also created on every partition, see `with_partitioning`. It is not
+    /// supported along with `with_snapshot_schema`, a trigger cannot reach into another database.
+    /// Existing schemas, e.g. created with `/db/init.sql`, are given the trigger by `ready`.
+    ///
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{ReadyRepository, SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// async fn configure_repo(pool: Pool<SqliteConnectionManager>) -> Result<ReadyRepository, SqliteAggregateError> {
+    ///     SqliteEventRepository::new(pool)
+    ///         .with_cascading_deletes(true)
+    ///         .ready()
+    ///         .await
+    /// }
+    /// ```
+    pub fn with_cascading_deletes(self, cascading_deletes: bool) -> Self {
+        Self {
+            query_factory: self.query_factory.with_cascading_deletes(cascading_deletes),
+            ..self
+        }
+    }
+
+    /// Configures the repository to register its background tasks, such as event streams, with
+    /// the provided `Shutdown` so that they are stopped and awaited during a graceful shutdown.
+    ///
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{Shutdown, SqliteEventRepository};
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>, shutdown: Shutdown) -> SqliteEventRepository {
+    ///     let store = SqliteEventRepository::new(pool);
+    ///     store.with_shutdown(shutdown)
+    /// }
+    /// ```
+    pub fn with_shutdown(self, shutdown: Shutdown) -> Self {
+        Self {
+            shutdown: Some(shutdown),
+            ..self
+        }
+    }
+
+    /// Configures the transaction behavior used when committing events and snapshots, see
+    /// `WriteTransaction`. The default, `WriteTransaction::Immediate`, is appropriate unless
+    /// several processes write to the same database.
+    ///
+    /// _Example: use deferred transactions, retrying busy commits up to five times._
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{SqliteEventRepository, WriteTransaction};
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    ///     let store = SqliteEventRepository::new(pool);
+    ///     store.with_write_transaction(WriteTransaction::Deferred { max_retries: 5 })
+    /// }
+    /// ```
+    pub fn with_write_transaction(self, write_transaction: WriteTransaction) -> Self {
+        Self {
+            write_transaction,
+            ..self
+        }
+    }
+
+    /// Configures limits on the serialized size of event payloads and snapshots, see
+    /// `PayloadLimits`. A commit including an oversized payload fails without writing any events.
+    ///
+    /// _Example: reject payloads over 1 MiB and log those over 64 KiB._
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{PayloadLimits, SqliteEventRepository};
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    ///     let limits = PayloadLimits::new()
+    ///         .with_max_bytes(1024 * 1024)
+    ///         .with_warning(64 * 1024, |kind, id, size| {
+    ///             eprintln!("large {kind} payload for {id}: {size} bytes");
+    ///         });
+    ///     SqliteEventRepository::new(pool).with_payload_limits(limits)
+    /// }
+    /// ```
+    pub fn with_payload_limits(self, payload_limits: PayloadLimits) -> Self {
+        Self {
+            payload_limits,
+            ..self
+        }
+    }
+
+    /// Configures the validators applied to event payloads before they are inserted, see
+    /// `EventSchemaRegistry`. A commit including an invalid event fails without writing any
+    /// events.
+    ///
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{EventSchemaRegistry, SqliteEventRepository};
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    ///     let registry = EventSchemaRegistry::new().register("NameAdded", |_version, payload| {
+    ///         payload["NameAdded"]["name"]
+    ///             .as_str()
+    ///             .map(|_| ())
+    ///             .ok_or_else(|| "a name is required".to_string())
+    ///     });
+    ///     SqliteEventRepository::new(pool).with_event_schemas(registry)
+    /// }
+    /// ```
+    pub fn with_event_schemas(self, event_schemas: EventSchemaRegistry) -> Self {
+        Self {
+            event_schemas,
+            ..self
+        }
+    }
+
+    /// The validators applied to event payloads, these can also be used to check events read
+    /// from the store.
+    pub fn event_schemas(&self) -> &EventSchemaRegistry {
+        &self.event_schemas
+    }
+
+    /// Registers a view that is updated within the same transaction that commits the events it
+    /// is built from, so the view is always consistent with the event store. This is intended for
+    /// single node applications that do not want the eventual consistency of a `GenericQuery`.
+    ///
+    /// As with `GenericQuery`, each view instance is keyed by its aggregate id, unless the view
+    /// repository is configured `with_view_ids`. The view must not
+    /// also be registered with the framework as a query, that would apply each event twice. A
+    /// commit fails, writing neither events nor views, if the view cannot be updated.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// # use cqrs_es::persist::doc::MyView;
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{SqliteEventRepository, SqliteViewRepository};
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    ///     let view_repo = SqliteViewRepository::<MyView, MyAggregate>::new("my_view", pool.clone());
+    ///     SqliteEventRepository::new(pool).with_transactional_view(view_repo)
+    /// }
+    /// ```
+    pub fn with_transactional_view<V, A, VP>(
+        mut self,
+        view_repository: SqliteViewRepository<V, A, VP>,
+    ) -> Self
+    where
+        V: View<A> + 'static,
+        A: Aggregate + 'static,
+        VP: ConnectionProvider,
+    {
+        self.transactional_views.push(Arc::new(view_repository));
+        self
+    }
+
+    /// Configures how streamed events that cannot be read are handled, see
+    /// `StreamErrorPolicy`. By default such an event is passed on to the stream as an error.
+    ///
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{SqliteEventRepository, StreamErrorPolicy};
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    ///     SqliteEventRepository::new(pool).with_stream_error_policy(StreamErrorPolicy::SkipAndReport)
+    /// }
+    /// ```
+    pub fn with_stream_error_policy(self, stream_error_policy: StreamErrorPolicy) -> Self {
+        Self {
+            stream_error_policy,
+            ..self
+        }
+    }
+
+    pub(crate) fn stream_error_policy(&self) -> &StreamErrorPolicy {
+        &self.stream_error_policy
+    }
+
+    /// Counts, and optionally records, the optimistic lock conflicts of commits through the
+    /// repository, see `ConflictLog`.
+    ///
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{ConflictLog, SqliteEventRepository};
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>, conflicts: ConflictLog) -> SqliteEventRepository {
+    ///     SqliteEventRepository::new(pool).with_conflict_log(conflicts.with_table("conflicts"))
+    /// }
+    /// ```
+    pub fn with_conflict_log(self, conflict_log: ConflictLog) -> Self {
+        Self {
+            conflict_log: Some(conflict_log),
+            ..self
+        }
+    }
+
+    pub(crate) fn conflict_log(&self) -> &Option<ConflictLog> {
+        &self.conflict_log
+    }
+
+    // Persists a commit already made to the primary of a `MirroredEventRepository`. The snapshot
+    // is replaced unconditionally, the primary has guarded it against concurrent updates and this
+    // database may lack the snapshot the update would be guarded on.
+    pub(crate) async fn persist_mirrored<A: Aggregate>(
         &self,
-        insert_event_query: &str,
-        tx: &Transaction<'_>,
         events: &[SerializedEvent],
-    ) -> Result<usize, SqliteAggregateError> {
-        let mut current_sequence: usize = 0;
-        for event in events {
-            current_sequence = event.sequence;
-            let payload = serde_json::to_value(&event.payload)?;
-            let metadata = serde_json::to_value(&event.metadata)?;
-            let mut statement = tx
-                .prepare_cached(insert_event_query)
-                .map_err(SqliteAggregateError::from)?;
-            statement
-                .execute((
-                    A::aggregate_type(),
-                    event.aggregate_id.as_str(),
-                    event.sequence as i32,
-                    &event.event_type,
-                    &event.event_version,
-                    &payload,
-                    &metadata,
-                ))
-                .map_err(SqliteAggregateError::from)?;
+        snapshot_update: Option<(String, Value, usize)>,
+    ) -> Result<(), PersistenceError> {
+        let started = Instant::now();
+        let result = self
+            .persist_tracked::<A>(events, snapshot_update, true)
+            .await;
+        self.record_latency(LatencyMetrics::record_commit, started);
+        result
+    }
+
+    // Persists the events and snapshot update, see `PersistedEventRepository::persist`. With
+    // `replace_snapshot` any stored snapshot is replaced rather than guarded on.
+    async fn persist_tracked<A: Aggregate>(
+        &self,
+        events: &[SerializedEvent],
+        snapshot_update: Option<(String, Value, usize)>,
+        replace_snapshot: bool,
+    ) -> Result<(), PersistenceError> {
+        let aggregate_type = A::aggregate_type();
+
+        let receipt = match snapshot_update {
+            None => {
+                let receipt = self.commit_events::<A>(events).await?;
+                if let Some(event) = events.first() {
+                    self.event_counts.add_if_tracked(
+                        &aggregate_type,
+                        &event.aggregate_id,
+                        events.len(),
+                    );
+                }
+                receipt
+            }
+            Some((aggregate_id, aggregate, current_snapshot)) => {
+                if !self.snapshot_due::<A>(&aggregate_id, events)? {
+                    self.commit_events::<A>(events).await?
+                } else {
+                    let receipt = if current_snapshot == 1 || replace_snapshot {
+                        self.insert::<A>(aggregate, aggregate_id.clone(), current_snapshot, events)
+                            .await?
+                    } else {
+                        self.update::<A>(aggregate, aggregate_id.clone(), current_snapshot, events)
+                            .await?
+                    };
+                    if self.snapshot_policy.is_some() {
+                        self.event_counts.set(&aggregate_type, &aggregate_id, 0);
+                    }
+                    receipt
+                }
+            }
+        };
+        self.committed(&aggregate_type, events, receipt);
+        Ok(())
+    }
+
+    // Records the receipt of committed events and hands them to the commit listeners.
+    fn committed(&self, aggregate_type: &str, events: &[SerializedEvent], receipt: CommitReceipt) {
+        if let (CommitReceipts::Recorded | CommitReceipts::RecordedWithMetadata, Some(event)) =
+            (self.commit_receipts, events.last())
+        {
+            self.receipt_log
+                .record(aggregate_type, &event.aggregate_id, receipt);
+        }
+        for listener in &self.commit_listeners {
+            if listener.aggregate_type() == aggregate_type {
+                listener.committed(events);
+            }
+        }
+        #[cfg(unix)]
+        if let Some(change_notifier) = &self.change_notifier {
+            // notified off the commit path, the notification does blocking file system I/O
+            let change_notifier = change_notifier.clone();
+            let position = receipt.global_position;
+            tokio::task::spawn_blocking(move || {
+                // the events are committed, a failed notification only delays readers
+                if let Err(err) = change_notifier.notify(position) {
+                    tracing::warn!(
+                        target: "rusqlite_es::change_notifier",
+                        position,
+                        error = %err,
+                        "commit could not be notified"
+                    );
+                }
+            });
+        }
+    }
+
+    /// Broadcasts the events of `A` committed through the repository to the subscribers of
+    /// `event_bus`, see `EventBus`.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{EventBus, SqliteEventRepository};
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>, bus: EventBus<MyAggregate>) -> SqliteEventRepository {
+    ///     SqliteEventRepository::new(pool).with_event_bus(bus)
+    /// }
+    /// ```
+    pub fn with_event_bus<A: Aggregate + 'static>(mut self, event_bus: EventBus<A>) -> Self {
+        self.commit_listeners.push(Arc::new(event_bus));
+        self
+    }
+
+    /// Runs `f` with a connection checked out from the repository's pool, e.g. to set custom
+    /// PRAGMAs or run ad-hoc maintenance queries without keeping a separate pool.
+    ///
+    /// The connection is returned to the pool once `f` completes, so it must not be retained.
+    /// Transactions should be committed or rolled back within `f`, one left open is rolled back
+    /// and reported as an error. The connection is held while `f` runs, so `f` should not block
+    /// for long.
+    ///
+    /// ```
+    /// use rusqlite_es::{SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// fn event_count(repo: &SqliteEventRepository) -> Result<i64, SqliteAggregateError> {
+    ///     repo.with_connection(|conn| {
+    ///         conn.query_row("SELECT count(*) FROM events", [], |row| row.get(0))
+    ///     })
+    /// }
+    /// ```
+    pub fn with_connection<F, T>(&self, f: F) -> Result<T, SqliteAggregateError>
+    where
+        F: FnOnce(&mut Connection) -> Result<T, rusqlite::Error>,
+    {
+        with_checked_connection(&self.pool, f)
+    }
+
+    /// Configures the repository to report the progress of `stream_all_events` and
+    /// `stream_everything` replays to the provided handle, which can also cancel them.
+    ///
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{ReplayProgress, SqliteEventRepository};
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>, progress: ReplayProgress) -> SqliteEventRepository {
+    ///     SqliteEventRepository::new(pool).with_replay_progress(progress)
+    /// }
+    /// ```
+    pub fn with_replay_progress(self, replay_progress: ReplayProgress) -> Self {
+        Self {
+            replay_progress: Some(replay_progress),
+            ..self
+        }
+    }
+
+    /// Configures the repository to report reads and writes of events and snapshots that take
+    /// longer than the log's threshold, see `SlowQueryLog`. Streamed replays are not reported.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{SlowQueryLog, SqliteEventRepository};
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    ///     let log = SlowQueryLog::new(Duration::from_millis(100));
+    ///     SqliteEventRepository::new(pool).with_slow_query_log(log)
+    /// }
+    /// ```
+    pub fn with_slow_query_log(self, slow_query_log: SlowQueryLog) -> Self {
+        Self {
+            slow_query_log: Some(slow_query_log),
+            ..self
+        }
+    }
+
+    /// Configures the repository to record the latencies of commits and loads in the provided
+    /// histograms, see `LatencyMetrics`.
+    pub fn with_latency_metrics(self, latency_metrics: LatencyMetrics) -> Self {
+        Self {
+            latency_metrics: Some(latency_metrics),
+            ..self
+        }
+    }
+
+    /// Configures the metadata entry identifying the user responsible for an event, read by the
+    /// queries of the `audit` module, rather than `user_id`.
+    ///
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::SqliteEventRepository;
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    ///     SqliteEventRepository::new(pool).with_actor_metadata_key("principal")
+    /// }
+    /// ```
+    pub fn with_actor_metadata_key(self, actor_metadata_key: &str) -> Self {
+        Self {
+            actor_metadata_key: actor_metadata_key.to_string(),
+            ..self
+        }
+    }
+
+    pub(crate) fn actor_metadata_key(&self) -> &str {
+        &self.actor_metadata_key
+    }
+
+    /// Returns the percentiles of the commit and load latencies recorded since the repository
+    /// was configured `with_latency_metrics`, if it was.
+    pub fn stats(&self) -> Option<LatencyStats> {
+        self.latency_metrics.as_ref().map(LatencyMetrics::stats)
+    }
+
+    /// Configures the repository to interrupt reads of events and snapshots, including
+    /// `query_events`, that run longer than `query_timeout`, returning
+    /// `SqliteAggregateError::Timeout`. The interruption is checked through an SQLite progress
+    /// handler, so that a pathological query cannot hold on to its connection. Streamed replays
+    /// and commits are not interrupted.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::SqliteEventRepository;
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    ///     SqliteEventRepository::new(pool).with_query_timeout(Duration::from_secs(2))
+    /// }
+    /// ```
+    pub fn with_query_timeout(self, query_timeout: Duration) -> Self {
+        Self {
+            query_timeout: Some(query_timeout),
+            ..self
+        }
+    }
+
+    /// Configures the repository to record the metadata entries matched by `query_events` with
+    /// the advisor, see `IndexAdvisor`.
+    ///
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{IndexAdvisor, SqliteEventRepository};
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>, advisor: IndexAdvisor) -> SqliteEventRepository {
+    ///     SqliteEventRepository::new(pool).with_index_advisor(advisor)
+    /// }
+    /// ```
+    pub fn with_index_advisor(self, index_advisor: IndexAdvisor) -> Self {
+        Self {
+            index_advisor: Some(index_advisor),
+            ..self
+        }
+    }
+
+    /// Configures the repository to coalesce concurrent commits of events into a single
+    /// transaction, see `GroupCommit`. Commits that also store a snapshot are written
+    /// immediately.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{GroupCommit, SqliteEventRepository};
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    ///     SqliteEventRepository::new(pool)
+    ///         .with_group_commit(GroupCommit::new(Duration::from_millis(5)))
+    /// }
+    /// ```
+    pub fn with_group_commit(self, group_commit: GroupCommit) -> Self {
+        Self {
+            group_commit: Some(GroupCommitter::new(group_commit)),
+            ..self
+        }
+    }
+
+    pub(crate) fn without_group_commit(&self) -> Self {
+        Self {
+            group_commit: None,
+            ..self.clone()
+        }
+    }
+
+    // Reads an event from a row of a query selecting the event columns, decoding its metadata
+    // and renaming its event type if it has been aliased.
+    pub(crate) fn read_event(
+        &self,
+        row: &Row<'_>,
+    ) -> Result<SerializedEvent, SqliteAggregateError> {
+        let mut event = deser_event(row)?;
+        if let Some(external_payloads) = &self.external_payloads {
+            external_payloads.resolve(&mut event.payload)?;
+        }
+        if let Some(metadata_codec) = &self.metadata_codec {
+            event.metadata = metadata_codec.decode(event.metadata)?;
+        }
+        self.event_type_aliases.rename(&mut event);
+        Ok(event)
+    }
+
+    pub(crate) fn json_encoding(&self) -> JsonEncoding {
+        self.json_encoding
+    }
+
+    pub(crate) fn metadata_codec(&self) -> &Option<Arc<dyn MetadataCodec>> {
+        &self.metadata_codec
+    }
+
+    pub(crate) fn access_policy(&self) -> &Option<Arc<dyn AccessPolicy>> {
+        &self.access_policy
+    }
+
+    pub(crate) fn external_payloads(&self) -> &Option<Arc<ExternalPayloads>> {
+        &self.external_payloads
+    }
+
+    pub(crate) fn slow_query_log(&self) -> &Option<SlowQueryLog> {
+        &self.slow_query_log
+    }
+
+    pub(crate) fn index_advisor(&self) -> &Option<IndexAdvisor> {
+        &self.index_advisor
+    }
+
+    pub(crate) fn query_timeout(&self) -> Option<Duration> {
+        self.query_timeout
+    }
+
+    pub(crate) fn payload_limits(&self) -> &PayloadLimits {
+        &self.payload_limits
+    }
+
+    pub(crate) fn replay_progress(&self) -> &Option<ReplayProgress> {
+        &self.replay_progress
+    }
+
+    /// Configures the repository to record a `CommitReceipt` for each commit, holding the
+    /// resulting global position and aggregate version, and optionally to write each event's
+    /// global position into its metadata. See `take_commit_receipt`.
+    ///
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{CommitReceipts, SqliteEventRepository};
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    ///     SqliteEventRepository::new(pool).with_commit_receipts(CommitReceipts::Recorded)
+    /// }
+    /// ```
+    pub fn with_commit_receipts(self, commit_receipts: CommitReceipts) -> Self {
+        Self {
+            commit_receipts,
+            ..self
+        }
+    }
+
+    /// Removes and returns the receipt of the latest commit for the aggregate instance, if one
+    /// was recorded. A clone of the repository passed to the framework can be kept to retrieve
+    /// receipts after executing commands, clones share their recorded receipts.
+    ///
+    /// Receipts are recorded per aggregate instance until taken, if commands for the same
+    /// aggregate instance are executed concurrently only the latest receipt is kept.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// use rusqlite_es::SqliteEventRepository;
+    ///
+    /// fn etag(repo: &SqliteEventRepository, id: &str) -> Option<String> {
+    ///     repo.take_commit_receipt::<MyAggregate>(id)
+    ///         .map(|receipt| format!("\"{}\"", receipt.aggregate_version))
+    /// }
+    /// ```
+    pub fn take_commit_receipt<A: Aggregate>(&self, aggregate_id: &str) -> Option<CommitReceipt> {
+        self.receipt_log.take(&A::aggregate_type(), aggregate_id)
+    }
+
+    /// Takes the receipt of the last commit to an aggregate instance as a `ConsistencyToken`,
+    /// see `take_commit_receipt`.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// use rusqlite_es::SqliteEventRepository;
+    ///
+    /// fn token_header(repo: &SqliteEventRepository, aggregate_id: &str) -> Option<String> {
+    ///     repo.take_consistency_token::<MyAggregate>(aggregate_id)
+    ///         .map(|token| token.to_string())
+    /// }
+    /// ```
+    pub fn take_consistency_token<A: Aggregate>(
+        &self,
+        aggregate_id: &str,
+    ) -> Option<ConsistencyToken> {
+        self.take_commit_receipt::<A>(aggregate_id)
+            .map(|receipt| ConsistencyToken::new(aggregate_id, receipt))
+    }
+
+    /// Configures a policy deciding, per aggregate instance, whether a snapshot offered by the
+    /// framework is written, see `SnapshotPolicy`. The repository tracks the number of events
+    /// committed since each aggregate instance's last snapshot to inform the policy.
+    ///
+    /// _Example: snapshot aggregates with many events more often than quiet ones._
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::SqliteEventRepository;
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    ///     let store = SqliteEventRepository::new(pool);
+    ///     store.with_snapshot_policy(Box::new(
+    ///         |_aggregate_type: &str, aggregate_id: &str, events_since_snapshot: usize| {
+    ///             events_since_snapshot >= 20 || aggregate_id.starts_with("hot-")
+    ///         },
+    ///     ))
+    /// }
+    /// ```
+    pub fn with_snapshot_policy(self, snapshot_policy: Box<dyn SnapshotPolicy>) -> Self {
+        Self {
+            snapshot_policy: Some(Arc::from(snapshot_policy)),
+            ..self
+        }
+    }
+
+    /// Configures how event metadata is converted between the framework's
+    /// `HashMap<String, String>` and the stored JSON, see `MetadataCodec`. Events already stored
+    /// must be readable by the codec.
+    ///
+    /// _Example: store metadata as the serialization of a metadata struct._
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use serde::{Deserialize, Serialize};
+    /// use rusqlite_es::{SqliteEventRepository, TypedMetadata};
+    ///
+    /// #[derive(Serialize, Deserialize)]
+    /// struct RequestMetadata {
+    ///     user_id: String,
+    ///     attempt: u32,
+    /// }
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    ///     let store = SqliteEventRepository::new(pool);
+    ///     store.with_metadata_codec(Box::new(TypedMetadata::<RequestMetadata>::new()))
+    /// }
+    /// ```
+    pub fn with_metadata_codec(self, metadata_codec: Box<dyn MetadataCodec>) -> Self {
+        Self {
+            metadata_codec: Some(Arc::from(metadata_codec)),
+            ..self
+        }
+    }
+
+    /// Configures the repository to consult `access_policy` before the events or snapshot of an
+    /// aggregate instance are loaded, i.e. when a command is dispatched to it or it is loaded
+    /// with `load_aggregates`. Refused loads fail with `SqliteAggregateError::AccessDenied`.
+    /// Event streams and queries spanning aggregate instances, e.g. `query_events`, are not
+    /// checked. See `AccessPolicy`.
+    ///
+    /// ```
+    /// use rusqlite_es::{AccessRequest, SqliteEventRepository};
+    ///
+    /// fn configure_repo(store: SqliteEventRepository) -> SqliteEventRepository {
+    ///     store.with_access_policy(Box::new(|request: &AccessRequest| {
+    ///         request.claims.contains_key("user_id")
+    ///     }))
+    /// }
+    /// ```
+    pub fn with_access_policy(self, access_policy: Box<dyn AccessPolicy>) -> Self {
+        Self {
+            access_policy: Some(Arc::from(access_policy)),
+            ..self
+        }
+    }
+
+    /// Configures the repository to store event payloads larger than `threshold_bytes` of
+    /// serialized JSON in `blob_store`, keeping a reference to the payload in the events table,
+    /// e.g. for media-heavy domains whose payloads run to several megabytes.
+    ///
+    /// Blobs are stored before the write transaction committing their events, those of commits
+    /// that fail are deleted by `collect_blobs`. References are resolved as events are read,
+    /// verifying the size and SHA-256 hash of the blob. Payload limits, see `with_payload_limits`, apply to the references of externally stored
+    /// payloads. SQL reading the `payload` column directly sees the references rather than the
+    /// payloads.
+    ///
+    /// ```
+    /// use rusqlite_es::{FileBlobStore, SqliteEventRepository};
+    ///
+    /// fn configure_repo(store: SqliteEventRepository) -> SqliteEventRepository {
+    ///     store.with_blob_store(Box::new(FileBlobStore::new("blobs")), 256 * 1024)
+    /// }
+    /// ```
+    pub fn with_blob_store(self, blob_store: Box<dyn BlobStore>, threshold_bytes: usize) -> Self {
+        Self {
+            external_payloads: Some(Arc::new(ExternalPayloads::new(blob_store, threshold_bytes))),
+            ..self
+        }
+    }
+
+    /// Notifies the commits made through the repository with `change_notifier`, waking readers
+    /// in other processes sharing the database, see `ChangeNotifier`. Commits are notified on a
+    /// blocking thread once committed, so a persist does not wait for the notification. Only
+    /// available on unix.
+    ///
+    /// ```
+    /// use rusqlite_es::{ChangeNotifier, SqliteEventRepository};
+    ///
+    /// fn configure_repo(store: SqliteEventRepository) -> SqliteEventRepository {
+    ///     store.with_change_notifier(ChangeNotifier::new("/var/lib/app/notify"))
+    /// }
+    /// ```
+    #[cfg(unix)]
+    pub fn with_change_notifier(self, change_notifier: ChangeNotifier) -> Self {
+        Self {
+            change_notifier: Some(change_notifier),
+            ..self
+        }
+    }
+
+    /// The per-aggregate counts of events committed since the last snapshot, these are tracked
+    /// when a `SnapshotPolicy` is configured.
+    pub fn event_counts(&self) -> &EventCounts {
+        &self.event_counts
+    }
+
+    /// Configures a `SqliteEventRepository` to use the provided table names. Names that are not
+    /// plain identifiers are quoted wherever they appear in SQL, see `TableName`.
+    ///
+    /// _Example: configure the repository to use "my_event_table" and "my_snapshot_table"
+    /// for the event and snapshot table names._
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::SqliteEventRepository;
+    ///
+    /// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+    ///     let store = SqliteEventRepository::new(pool);
+    ///     store.with_tables("my_event_table", "my_snapshot_table")
+    /// }
+    /// ```
+    pub fn with_tables(self, events_table: &str, snapshots_table: &str) -> Self {
+        Self {
+            query_factory: SqlQueryFactory::new(events_table, snapshots_table, self.json_encoding)
+                .with_metadata_storage(self.query_factory.metadata_storage())
+                .with_payload_storage(self.query_factory.payload_storage())
+                .with_partitioning(self.query_factory.partitioning())
+                .with_table_layout(self.query_factory.table_layout())
+                .with_snapshot_schema(self.query_factory.snapshot_table().schema())
+                .with_cascading_deletes(self.query_factory.cascading_deletes())
+                .with_hash_chain(self.query_factory.hash_chain()),
+            ..self
+        }
+    }
+
+    /// Configures the repository to store snapshots in the provided schema, i.e. a database
+    /// attached to every pooled connection, rather than alongside the events. This keeps the
+    /// write amplification and WAL growth caused by snapshot updates out of the append-only
+    /// events file.
+    ///
+    /// Events and snapshots are still written in a single transaction, but SQLite only commits a
+    /// transaction spanning several databases atomically if the main database is not in WAL
+    /// mode. Otherwise, a crash may persist the events without the snapshot, in which case the
+    /// stale snapshot is brought up to date from the events when the aggregate is next loaded.
+    ///
+    /// _Example: store snapshots in 'snapshots.db', attached as 'snapshot_db'._
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{ReadyRepository, SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// async fn configure_repo() -> Result<ReadyRepository, SqliteAggregateError> {
+    ///     let manager = SqliteConnectionManager::file("events.db").with_init(|conn| {
+    ///         conn.execute_batch("ATTACH DATABASE 'snapshots.db' AS snapshot_db")
+    ///     });
+    ///     let pool = Pool::new(manager)?;
+    ///     SqliteEventRepository::new(pool)
+    ///         .with_snapshot_schema("snapshot_db")
+    ///         .ready()
+    ///         .await
+    /// }
+    /// ```
+    pub fn with_snapshot_schema(self, schema: &str) -> Self {
+        Self {
+            query_factory: self.query_factory.with_snapshot_schema(Some(schema)),
+            ..self
+        }
+    }
+
+    fn use_tables(pool: P, events_table: &str, snapshots_table: &str) -> Self {
+        Self {
+            pool,
+            query_factory: SqlQueryFactory::new(
+                events_table,
+                snapshots_table,
+                JsonEncoding::default(),
+            ),
+            stream_channel_size: DEFAULT_STREAMING_CHANNEL_SIZE,
+            aggregate_versions: Default::default(),
+            metadata_inheritance: Default::default(),
+            snapshot_upcasters: Default::default(),
+            rewrite_invalid_snapshots: false,
+            validate_snapshots: false,
+            json_encoding: JsonEncoding::default(),
+            shutdown: None,
+            snapshot_policy: None,
+            event_counts: Default::default(),
+            write_transaction: Default::default(),
+            payload_limits: Default::default(),
+            event_schemas: Default::default(),
+            transactional_views: Default::default(),
+            commit_receipts: Default::default(),
+            receipt_log: Default::default(),
+            replay_progress: None,
+            slow_query_log: None,
+            query_timeout: None,
+            index_advisor: None,
+            group_commit: None,
+            commit_listeners: Default::default(),
+            stream_error_policy: Default::default(),
+            conflict_log: None,
+            metadata_codec: None,
+            event_type_aliases: Default::default(),
+            external_payloads: None,
+            access_policy: None,
+            latency_metrics: None,
+            actor_metadata_key: ACTOR_METADATA_KEY.to_string(),
+            active_writes: Default::default(),
+            #[cfg(unix)]
+            change_notifier: None,
+        }
+    }
+
+    fn tracked_progress(&self, count_query: &str) -> TrackedProgress {
+        self.replay_progress
+            .clone()
+            .map(|progress| (progress, count_query.to_string()))
+    }
+
+    pub(crate) fn aggregate_version<A: Aggregate>(&self) -> &str {
+        self.aggregate_versions
+            .get(&A::aggregate_type())
+            .map_or(UNVERSIONED_AGGREGATE, String::as_str)
+    }
+
+    pub(crate) fn upcast_snapshot<A: Aggregate>(
+        &self,
+        mut snapshot: SerializedSnapshot,
+        mut aggregate_version: String,
+    ) -> Option<SerializedSnapshot> {
+        let aggregate_type = A::aggregate_type();
+        let current_version = self.aggregate_version::<A>();
+        for upcaster in self.snapshot_upcasters.iter() {
+            if aggregate_version == current_version {
+                break;
+            }
+            if upcaster.can_upcast(&aggregate_type, &aggregate_version) {
+                (snapshot.aggregate, aggregate_version) = upcaster.upcast(snapshot.aggregate);
+            }
+        }
+        // An incompatible snapshot is discarded, the aggregate will be rebuilt from its events.
+        (aggregate_version == current_version).then_some(snapshot)
+    }
+
+    fn snapshot_due<A: Aggregate>(
+        &self,
+        aggregate_id: &str,
+        events: &[SerializedEvent],
+    ) -> Result<bool, SqliteAggregateError> {
+        let snapshot_policy = match &self.snapshot_policy {
+            None => return Ok(true),
+            Some(snapshot_policy) => snapshot_policy,
+        };
+        let aggregate_type = A::aggregate_type();
+        let events_since_snapshot = match self
+            .event_counts
+            .since_snapshot(&aggregate_type, aggregate_id)
+        {
+            Some(count) => count + events.len(),
+            None => {
+                // Not yet tracked, derive the count from the stored snapshot's sequence.
+                let snapshot_sequence = self
+                    .select_snapshot::<A>(aggregate_id)?
+                    .map_or(0, |(snapshot, _)| snapshot.current_sequence);
+                let last_sequence = events.last().map_or(0, |event| event.sequence);
+                last_sequence.saturating_sub(snapshot_sequence)
+            }
+        };
+        let due =
+            snapshot_policy.should_snapshot(&aggregate_type, aggregate_id, events_since_snapshot);
+        if !due {
+            self.event_counts
+                .set(&aggregate_type, aggregate_id, events_since_snapshot);
+        }
+        Ok(due)
+    }
+
+    pub(crate) fn select_snapshot<A: Aggregate>(
+        &self,
+        aggregate_id: &str,
+    ) -> Result<Option<(SerializedSnapshot, String)>, SqliteAggregateError> {
+        let connection = self.pool.connection()?;
+        let query = self.query_factory.select_snapshot();
+        let aggregate_type = A::aggregate_type();
+        timed(
+            &self.slow_query_log,
+            query,
+            &[&aggregate_type, aggregate_id],
+            || {
+                with_timeout(&connection, self.query_timeout, || {
+                    let mut statement = connection
+                        .prepare_cached(query)
+                        .map_err(SqliteAggregateError::from)?;
+                    statement
+                        .query_row((&aggregate_type, &aggregate_id), versioned_snapshot)
+                        .optional()
+                        .map_err(SqliteAggregateError::from)
+                })
+            },
+            |snapshot| usize::from(snapshot.is_some()),
+        )
+    }
+
+    async fn rewrite_snapshot<A: Aggregate>(
+        &self,
+        aggregate_id: &str,
+        current_snapshot: usize,
+    ) -> Result<Option<SerializedSnapshot>, PersistenceError> {
+        let events = self
+            .select_events::<A>(aggregate_id, self.query_factory.select_events())
+            .await?;
+        let mut aggregate = A::default();
+        let mut current_sequence = 0;
+        for event in events {
+            let envelope: EventEnvelope<A> = match event.try_into() {
+                Ok(envelope) => envelope,
+                Err(_) => return Ok(None),
+            };
+            current_sequence = envelope.sequence;
+            aggregate.apply(envelope.payload);
+        }
+        let aggregate = serde_json::to_value(aggregate).map_err(SqliteAggregateError::from)?;
+
+        let connection = self.pool.connection()?;
+        let mut statement = connection
+            .prepare_cached(self.query_factory.rewrite_snapshot())
+            .map_err(SqliteAggregateError::from)?;
+        statement
+            .execute((
+                current_sequence as i32,
+                &aggregate,
+                current_snapshot as i32,
+                self.aggregate_version::<A>(),
+                A::aggregate_type(),
+                aggregate_id,
+                current_snapshot as i32,
+            ))
+            .map_err(SqliteAggregateError::from)?;
+        Ok(Some(SerializedSnapshot {
+            aggregate_id: aggregate_id.to_string(),
+            aggregate,
+            current_sequence,
+            current_snapshot,
+        }))
+    }
+
+    // Runs `write` within a transaction using the configured behavior, retrying if the
+    // database is busy and the behavior allows it. The thread is blocked between retries, async
+    // callers use `write_async`.
+    pub(crate) fn write<T>(
+        &self,
+        write: impl Fn(&Connection) -> Result<T, SqliteAggregateError>,
+    ) -> Result<T, SqliteAggregateError> {
+        let mut attempt = 0;
+        loop {
+            match self.write_once(&write) {
+                Err(err) if err.is_busy() => match self.write_transaction.retry_backoff(attempt) {
+                    Some(backoff) => {
+                        std::thread::sleep(backoff);
+                        attempt += 1;
+                    }
+                    None => return Err(err),
+                },
+                result => return result,
+            }
+        }
+    }
+
+    // Runs `write` as `write` does, waiting between retries without blocking the runtime. The
+    // connection is returned to the pool while waiting.
+    pub(crate) async fn write_async<T>(
+        &self,
+        write: impl Fn(&Connection) -> Result<T, SqliteAggregateError>,
+    ) -> Result<T, SqliteAggregateError> {
+        let mut attempt = 0;
+        loop {
+            match self.write_once(&write) {
+                Err(err) if err.is_busy() => match self.write_transaction.retry_backoff(attempt) {
+                    Some(backoff) => {
+                        tokio::time::sleep(backoff).await;
+                        attempt += 1;
+                    }
+                    None => return Err(err),
+                },
+                result => return result,
+            }
+        }
+    }
+
+    fn write_once<T>(
+        &self,
+        write: &impl Fn(&Connection) -> Result<T, SqliteAggregateError>,
+    ) -> Result<T, SqliteAggregateError> {
+        let mut connection = self.pool.connection()?;
+        let _active = ActiveWrite::start(&self.active_writes);
+        in_transaction(&mut connection, self.write_transaction.behavior(), write)
+    }
+
+    // The number of write transactions in progress, see `analytics_connection`.
+    pub(crate) fn active_writes(&self) -> Arc<AtomicUsize> {
+        self.active_writes.clone()
+    }
+
+    async fn commit_events<A: Aggregate>(
+        &self,
+        events: &[SerializedEvent],
+    ) -> Result<CommitReceipt, SqliteAggregateError> {
+        match &self.group_commit {
+            Some(group_commit) => {
+                let aggregate_type = A::aggregate_type();
+                let _staged = self
+                    .stage_event_blobs(Some(&aggregate_type), events)
+                    .await?;
+                group_commit.commit(self, aggregate_type, events).await
+            }
+            None => self.insert_events::<A>(events).await,
+        }
+    }
+
+    // Stores the blobs of the payloads of `events` ahead of the write transaction committing
+    // them, each event under `aggregate_type` if given, else under its own aggregate type. See
+    // `ExternalPayloads::stage`.
+    async fn stage_event_blobs(
+        &self,
+        aggregate_type: Option<&str>,
+        events: &[SerializedEvent],
+    ) -> Result<Option<StagedBlobs>, SqliteAggregateError> {
+        self.stage_blobs(events.iter().map(|event| {
+            (
+                aggregate_type.unwrap_or(&event.aggregate_type),
+                event.aggregate_id.as_str(),
+                &event.payload,
+            )
+        }))
+        .await
+    }
+
+    // Stores the blobs of large payloads, given as their aggregate type, aggregate id and
+    // payload, ahead of the write transaction committing them, see `ExternalPayloads::stage`.
+    async fn stage_blobs<'a>(
+        &self,
+        payloads: impl IntoIterator<Item = (&'a str, &'a str, &'a Value)>,
+    ) -> Result<Option<StagedBlobs>, SqliteAggregateError> {
+        let Some(external_payloads) = &self.external_payloads else {
+            return Ok(None);
+        };
+        let blobs = external_payloads.large_payloads(payloads)?;
+        if blobs.is_empty() {
+            return Ok(None);
+        }
+        let external_payloads = external_payloads.clone();
+        tokio::task::spawn_blocking(move || external_payloads.stage(blobs))
+            .await
+            .map_err(|err| SqliteAggregateError::UnknownError(Box::new(err)))?
+            .map(Some)
+    }
+
+    pub(crate) async fn insert_events<A: Aggregate>(
+        &self,
+        events: &[SerializedEvent],
+    ) -> Result<CommitReceipt, SqliteAggregateError> {
+        let _staged = self
+            .stage_event_blobs(Some(&A::aggregate_type()), events)
+            .await?;
+        self.write_async(|tx| {
+            self.persist_events::<A>(self.query_factory.insert_event(), tx, events)
+        })
+        .await
+    }
+
+    pub(crate) async fn insert<A: Aggregate>(
+        &self,
+        aggregate_payload: Value,
+        aggregate_id: String,
+        current_snapshot: usize,
+        events: &[SerializedEvent],
+    ) -> Result<CommitReceipt, SqliteAggregateError> {
+        self.payload_limits
+            .check(PayloadKind::Snapshot, &aggregate_id, &aggregate_payload)?;
+        let _staged = self
+            .stage_event_blobs(Some(&A::aggregate_type()), events)
+            .await?;
+        self.write_async(|tx| {
+            let receipt =
+                self.persist_events::<A>(self.query_factory.insert_event(), tx, events)?;
+
+            // Any existing snapshot was discarded as incompatible or is mirrored over, the new
+            // snapshot replaces it.
+            tx.prepare_cached(self.query_factory.delete_snapshot())
+                .map_err(SqliteAggregateError::from)?
+                .execute((A::aggregate_type(), aggregate_id.as_str()))
+                .map_err(SqliteAggregateError::from)?;
+            let mut statement = tx
+                .prepare_cached(self.query_factory.insert_snapshot())
+                .map_err(SqliteAggregateError::from)?;
+            statement
+                .execute((
+                    A::aggregate_type(),
+                    aggregate_id.as_str(),
+                    receipt.aggregate_version as i32,
+                    current_snapshot as i32,
+                    self.aggregate_version::<A>(),
+                    &aggregate_payload,
+                ))
+                .map_err(SqliteAggregateError::from)?;
+            Ok(receipt)
+        })
+        .await
+    }
+
+    pub(crate) async fn update<A: Aggregate>(
+        &self,
+        aggregate_payload: Value,
+        aggregate_id: String,
+        current_snapshot: usize,
+        events: &[SerializedEvent],
+    ) -> Result<CommitReceipt, SqliteAggregateError> {
+        self.payload_limits
+            .check(PayloadKind::Snapshot, &aggregate_id, &aggregate_payload)?;
+        let _staged = self
+            .stage_event_blobs(Some(&A::aggregate_type()), events)
+            .await?;
+        self.write_async(|tx| {
+            let receipt =
+                self.persist_events::<A>(self.query_factory.insert_event(), tx, events)?;
+            let mut statement = tx
+                .prepare_cached(self.query_factory.update_snapshot())
+                .map_err(SqliteAggregateError::from)?;
+            let rows_affected = statement
+                .execute((
+                    receipt.aggregate_version as i32,
+                    &aggregate_payload,
+                    current_snapshot as i32,
+                    self.aggregate_version::<A>(),
+                    A::aggregate_type(),
+                    aggregate_id.as_str(),
+                    receipt.aggregate_version as i32,
+                ))
+                .map_err(SqliteAggregateError::from)?;
+            // The snapshot is only replaced by one covering later events, a concurrent writer
+            // may already have stored a more recent snapshot. Returning an error rolls back the
+            // events along with the stale snapshot.
+            match rows_affected {
+                1 => Ok(receipt),
+                _ => Err(SqliteAggregateError::SnapshotConflict),
+            }
+        })
+        .await
+    }
+
+    fn persist_events<A: Aggregate>(
+        &self,
+        insert_event_query: &str,
+        tx: &Connection,
+        events: &[SerializedEvent],
+    ) -> Result<CommitReceipt, SqliteAggregateError> {
+        self.persist_aggregate_events(&A::aggregate_type(), insert_event_query, tx, events)
+    }
+
+    pub(crate) fn persist_aggregate_events(
+        &self,
+        aggregate_type: &str,
+        insert_event_query: &str,
+        tx: &Connection,
+        events: &[SerializedEvent],
+    ) -> Result<CommitReceipt, SqliteAggregateError> {
+        let mut receipt = CommitReceipt {
+            global_position: 0,
+            aggregate_version: 0,
+        };
+        for event in events {
+            receipt = self.persist_event(insert_event_query, tx, aggregate_type, event)?;
+        }
+        for view in &self.transactional_views {
+            if view.aggregate_type() == aggregate_type {
+                view.apply(tx, events)?;
+            }
+        }
+        Ok(receipt)
+    }
+
+    fn persist_event(
+        &self,
+        insert_event_query: &str,
+        tx: &Connection,
+        aggregate_type: &str,
+        event: &SerializedEvent,
+    ) -> Result<CommitReceipt, SqliteAggregateError> {
+        self.insert_event_row(insert_event_query, tx, aggregate_type, event, false)?
+            .ok_or(SqliteAggregateError::OptimisticLock)
+    }
+
+    // Inserts an event, returning `None` if it was skipped because an event with the same
+    // sequence already exists and `if_absent` is set, along with an `insert_event_if_absent`
+    // query.
+    fn insert_event_row(
+        &self,
+        insert_event_query: &str,
+        tx: &Connection,
+        aggregate_type: &str,
+        event: &SerializedEvent,
+        if_absent: bool,
+    ) -> Result<Option<CommitReceipt>, SqliteAggregateError> {
+        let mut payload = serde_json::to_value(&event.payload)?;
+        self.event_schemas
+            .validate_payload(&event.event_type, &event.event_version, &payload)?;
+        // the event's own metadata is read from its payload before the payload is replaced by a
+        // reference to where it is stored
+        let inherited = self
+            .metadata_inheritance
+            .get(aggregate_type)
+            .map(|inheritance| inheritance.merge(&payload, &event.metadata));
+        if let Some(external_payloads) = &self.external_payloads {
+            payload =
+                external_payloads.externalize(aggregate_type, &event.aggregate_id, payload)?;
+        }
+        self.payload_limits
+            .check(PayloadKind::Event, &event.aggregate_id, &payload)?;
+        if self.query_factory.payload_storage() == PayloadStorage::Deduplicated {
+            payload = Value::from(intern_payload(tx, &self.query_factory, &payload)?);
+        }
+        let event_metadata = inherited.as_ref().unwrap_or(&event.metadata);
+        let mut metadata = match &self.metadata_codec {
+            Some(metadata_codec) => metadata_codec.encode(event_metadata)?,
+            None => serde_json::to_value(event_metadata)?,
+        };
+        let interned = self.query_factory.metadata_storage() == MetadataStorage::Dictionary
+            && metadata.is_object();
+        if interned {
+            metadata = intern_metadata(tx, &self.query_factory, &metadata)?;
+        }
+        // a partitioned store routes the event to the current partition, sequences must then be
+        // checked across all partitions
+        let (ordinal, partition_queries) = match self.query_factory.partitioning() {
+            Partitioning::None => (0, None),
+            _ => {
+                let (ordinal, partition) = route_partition(tx, &self.query_factory)?;
+                let existing: i64 = tx
+                    .query_row(
+                        self.query_factory.count_sequence(),
+                        (
+                            aggregate_type,
+                            event.aggregate_id.as_str(),
+                            event.sequence as i64,
+                        ),
+                        |row| row.get(0),
+                    )
+                    .map_err(SqliteAggregateError::from)?;
+                match existing {
+                    0 => {}
+                    _ if if_absent => return Ok(None),
+                    _ => return Err(SqliteAggregateError::OptimisticLock),
+                }
+                (ordinal, Some(self.query_factory.for_partition(&partition)))
+            }
+        };
+        let query_factory = partition_queries.as_ref().unwrap_or(&self.query_factory);
+        let insert_event_query = match &partition_queries {
+            Some(partition_queries) if if_absent => partition_queries.insert_event_if_absent(),
+            Some(partition_queries) => partition_queries.insert_event(),
+            None => insert_event_query,
+        };
+        // without a rowid, the event's position within its table is assigned explicitly
+        let assigned_position: Option<i64> = match query_factory.table_layout() {
+            EventTableLayout::Rowid => None,
+            EventTableLayout::WithoutRowid => Some(
+                tx.query_row(query_factory.next_position(), [], |row| row.get(0))
+                    .map_err(SqliteAggregateError::from)?,
+            ),
+        };
+        let sequence = event.sequence as i32;
+        let mut params: Vec<&dyn ToSql> = vec![
+            &aggregate_type,
+            &event.aggregate_id,
+            &sequence,
+            &event.event_type,
+            &event.event_version,
+            &payload,
+            &metadata,
+        ];
+        let logged_sequence = event.sequence.to_string();
+        let logged_position = assigned_position.map(|position| position.to_string());
+        let mut logged_params = vec![
+            aggregate_type,
+            &event.aggregate_id,
+            &logged_sequence,
+            &event.event_type,
+            &event.event_version,
+            REDACTED_PARAM,
+            REDACTED_PARAM,
+        ];
+        if let (Some(position), Some(logged_position)) = (&assigned_position, &logged_position) {
+            params.push(position);
+            logged_params.push(logged_position);
+        }
+        let inserted = timed(
+            &self.slow_query_log,
+            insert_event_query,
+            &logged_params,
+            || {
+                tx.prepare_cached(insert_event_query)?
+                    .execute(rusqlite::params_from_iter(params))
+            },
+            |inserted| *inserted,
+        )
+        .map_err(SqliteAggregateError::from)?;
+        if inserted == 0 {
+            return Ok(None);
+        }
+        let rowid = assigned_position.unwrap_or_else(|| tx.last_insert_rowid());
+        let receipt = CommitReceipt {
+            global_position: (ordinal << PARTITION_POSITION_BITS) + rowid,
+            aggregate_version: event.sequence,
+        };
+        if self.commit_receipts == CommitReceipts::RecordedWithMetadata && interned {
+            let id = intern_global_position(tx, &self.query_factory)?;
+            tx.prepare_cached(query_factory.append_metadata())
+                .map_err(SqliteAggregateError::from)?
+                .execute([id, rowid])
+                .map_err(SqliteAggregateError::from)?;
+        } else if self.commit_receipts == CommitReceipts::RecordedWithMetadata {
+            tx.prepare_cached(query_factory.set_global_position())
+                .map_err(SqliteAggregateError::from)?
+                .execute([receipt.global_position, rowid])
+                .map_err(SqliteAggregateError::from)?;
+        }
+        if self.query_factory.hash_chain() != HashChain::None {
+            if partition_queries.is_some() {
+                return Err(SqliteAggregateError::UnknownError(
+                    "hash chains are not supported on a partitioned store".into(),
+                ));
+            }
+            chain_event(tx, &self.query_factory, rowid)?;
+        }
+        Ok(Some(receipt))
+    }
+
+    // Inserts events of any aggregate type, each under its own `aggregate_type`, within a single
+    // transaction. Transactional views are not updated.
+    pub(crate) async fn insert_any_events(
+        &self,
+        events: &[SerializedEvent],
+    ) -> Result<(), SqliteAggregateError> {
+        let _staged = self.stage_event_blobs(None, events).await?;
+        self.write_async(|tx| {
+            for event in events {
+                self.persist_event(
+                    self.query_factory.insert_event(),
+                    tx,
+                    &event.aggregate_type,
+                    event,
+                )?;
+            }
+            Ok(())
+        })
+        .await
+    }
+}
+
+// Counts a write transaction as in progress until dropped, see `analytics_connection`.
+struct ActiveWrite<'a>(&'a AtomicUsize);
+
+impl<'a> ActiveWrite<'a> {
+    fn start(active_writes: &'a AtomicUsize) -> Self {
+        active_writes.fetch_add(1, Ordering::SeqCst);
+        Self(active_writes)
+    }
+}
+
+impl Drop for ActiveWrite<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cqrs_es::persist::PersistedEventRepository;
+    use std::fs;
+
+    use crate::error::SqliteAggregateError;
+    use crate::testing::tests::{
+        snapshot_context, test_event_envelope, Created, SomethingElse, TestAggregate, TestEvent,
+        TestView, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{
+        default_sqlite_pool, CommitReceipts, CommitTransaction, HashChain, InsertedEvents,
+        JsonEncoding, Partitioning, PayloadLimits, SqliteEventRepository, VersionSnapshotUpcaster,
+        WriteTransaction, GLOBAL_POSITION_METADATA_KEY,
+    };
+
+    #[tokio::test]
+    async fn event_repositories() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let event_repo: SqliteEventRepository =
+            SqliteEventRepository::new(pool.clone()).with_streaming_channel_size(1);
+        let events = event_repo.get_events::<TestAggregate>(&id).await.unwrap();
+        assert!(events.is_empty());
+
+        event_repo
+            .insert_events::<TestAggregate>(&[
+                test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() })),
+                test_event_envelope(
+                    &id,
+                    2,
+                    TestEvent::Tested(Tested {
+                        test_name: "a test was run".to_string(),
+                    }),
+                ),
+            ])
+            .await
+            .unwrap();
+        let events = event_repo.get_events::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(2, events.len());
+        events.iter().for_each(|e| assert_eq!(&id, &e.aggregate_id));
+
+        // Optimistic lock error
+        let result = event_repo
+            .insert_events::<TestAggregate>(&[
+                test_event_envelope(
+                    &id,
+                    3,
+                    TestEvent::SomethingElse(SomethingElse {
+                        description: "this should not persist".to_string(),
+                    }),
+                ),
+                test_event_envelope(
+                    &id,
+                    2,
+                    TestEvent::SomethingElse(SomethingElse {
+                        description: "bad sequence number".to_string(),
+                    }),
+                ),
+            ])
+            .await
+            .unwrap_err();
+        match result {
+            SqliteAggregateError::OptimisticLock => {}
+            _ => panic!("invalid error result found during insert: {}", result),
+        };
+
+        let events = event_repo.get_events::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(2, events.len());
+
+        verify_replay_stream(&id, event_repo).await;
+    }
+
+    async fn verify_replay_stream(id: &str, event_repo: SqliteEventRepository) {
+        let mut stream = event_repo.stream_events::<TestAggregate>(id).await.unwrap();
+        let mut found_in_stream = 0;
+        while (stream.next::<TestAggregate>(&None).await).is_some() {
+            found_in_stream += 1;
+        }
+        assert_eq!(found_in_stream, 2);
+
+        let mut stream = event_repo
+            .stream_all_events::<TestAggregate>()
+            .await
+            .unwrap();
+        let mut found_in_stream = 0;
+        while (stream.next::<TestAggregate>(&None).await).is_some() {
+            found_in_stream += 1;
+        }
+        assert!(found_in_stream >= 2);
+    }
+
+    #[tokio::test]
+    async fn stream_everything() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let first_id = uuid::Uuid::new_v4().to_string();
+        let second_id = uuid::Uuid::new_v4().to_string();
+        let event_repo = SqliteEventRepository::new(pool.clone()).with_streaming_channel_size(1);
+        event_repo
+            .insert_events::<TestAggregate>(&[test_event_envelope(
+                &first_id,
+                1,
+                TestEvent::Created(Created {
+                    id: first_id.clone(),
+                }),
+            )])
+            .await
+            .unwrap();
+        event_repo
+            .insert_events::<TestAggregate>(&[test_event_envelope(
+                &second_id,
+                1,
+                TestEvent::Created(Created {
+                    id: second_id.clone(),
+                }),
+            )])
+            .await
+            .unwrap();
+        event_repo
+            .insert_events::<TestAggregate>(&[test_event_envelope(
+                &first_id,
+                2,
+                TestEvent::Tested(Tested {
+                    test_name: "a test was run".to_string(),
+                }),
+            )])
+            .await
+            .unwrap();
+
+        let mut stream = event_repo.stream_everything().await.unwrap();
+        let mut found = Vec::new();
+        while let Some(event) = stream.next().await {
+            found.push(event.unwrap());
+        }
+        // The pre-loaded `Customer` event from `db/init.sql` is committed first.
+        assert_eq!(4, found.len());
+        assert_eq!("Customer", found[0].aggregate_type);
+        let committed: Vec<(&str, usize)> = found[1..]
+            .iter()
+            .map(|e| (e.aggregate_id.as_str(), e.sequence))
+            .collect();
+        assert_eq!(
+            vec![
+                (first_id.as_str(), 1),
+                (second_id.as_str(), 1),
+                (first_id.as_str(), 2)
+            ],
+            committed
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshot_repositories() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let event_repo: SqliteEventRepository = SqliteEventRepository::new(pool.clone());
+        let snapshot = event_repo.get_snapshot::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(None, snapshot);
+
+        let test_description = "some test snapshot here".to_string();
+        let test_tests = vec!["testA".to_string(), "testB".to_string()];
+        event_repo
+            .insert::<TestAggregate>(
+                serde_json::to_value(TestAggregate {
+                    id: id.clone(),
+                    description: test_description.clone(),
+                    tests: test_tests.clone(),
+                })
+                .unwrap(),
+                id.clone(),
+                1,
+                &[],
+            )
+            .await
+            .unwrap();
+
+        let snapshot = event_repo.get_snapshot::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(
+            Some(snapshot_context(
+                id.clone(),
+                0,
+                1,
+                serde_json::to_value(TestAggregate {
+                    id: id.clone(),
+                    description: test_description.clone(),
+                    tests: test_tests.clone(),
+                })
+                .unwrap()
+            )),
+            snapshot
+        );
+
+        // sequence iterated, does update
+        event_repo
+            .update::<TestAggregate>(
+                serde_json::to_value(TestAggregate {
+                    id: id.clone(),
+                    description: "a test description that should be saved".to_string(),
+                    tests: test_tests.clone(),
+                })
+                .unwrap(),
+                id.clone(),
+                2,
+                &[test_event_envelope(
+                    &id,
+                    1,
+                    TestEvent::Created(Created { id: id.clone() }),
+                )],
+            )
+            .await
+            .unwrap();
+
+        let snapshot = event_repo.get_snapshot::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(
+            Some(snapshot_context(
+                id.clone(),
+                1,
+                2,
+                serde_json::to_value(TestAggregate {
+                    id: id.clone(),
+                    description: "a test description that should be saved".to_string(),
+                    tests: test_tests.clone(),
+                })
+                .unwrap()
+            )),
+            snapshot
+        );
+
+        // a snapshot covering no later events is stale, does not update
+        let result = event_repo
+            .update::<TestAggregate>(
+                serde_json::to_value(TestAggregate {
+                    id: id.clone(),
+                    description: "a test description that should not be saved".to_string(),
+                    tests: test_tests.clone(),
+                })
+                .unwrap(),
+                id.clone(),
+                3,
+                &[],
+            )
+            .await
+            .unwrap_err();
+        match result {
+            SqliteAggregateError::SnapshotConflict => {}
+            _ => panic!("invalid error result found during update: {}", result),
+        };
+
+        let snapshot = event_repo.get_snapshot::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(
+            Some(snapshot_context(
+                id.clone(),
+                1,
+                2,
+                serde_json::to_value(TestAggregate {
+                    id: id.clone(),
+                    description: "a test description that should be saved".to_string(),
+                    tests: test_tests.clone(),
+                })
+                .unwrap()
+            )),
+            snapshot
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshot_versioning() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let legacy_repo = SqliteEventRepository::new(pool.clone());
+        legacy_repo
+            .insert::<TestAggregate>(
+                serde_json::json!({"id": id, "description": "legacy"}),
+                id.clone(),
+                1,
+                &[],
+            )
+            .await
+            .unwrap();
+
+        // no upcaster available, the snapshot is ignored in favor of replaying events
+        let incompatible_repo =
+            SqliteEventRepository::new(pool.clone()).with_aggregate_version::<TestAggregate>("2");
+        let snapshot = incompatible_repo
+            .get_snapshot::<TestAggregate>(&id)
+            .await
+            .unwrap();
+        assert_eq!(None, snapshot);
+
+        let upcasting_repo = SqliteEventRepository::new(pool.clone())
+            .with_aggregate_version::<TestAggregate>("2")
+            .with_snapshot_upcasters(vec![
+                Box::new(VersionSnapshotUpcaster::new(
+                    "TestAggregate",
+                    "",
+                    "1",
+                    Box::new(|mut aggregate| {
+                        aggregate["tests"] = serde_json::json!([]);
+                        aggregate
+                    }),
+                )),
+                Box::new(VersionSnapshotUpcaster::new(
+                    "TestAggregate",
+                    "1",
+                    "2",
+                    Box::new(|mut aggregate| {
+                        aggregate["description"] = serde_json::json!("upcast");
+                        aggregate
+                    }),
+                )),
+            ]);
+        let snapshot = upcasting_repo
+            .get_snapshot::<TestAggregate>(&id)
+            .await
+            .unwrap();
+        assert_eq!(
+            Some(snapshot_context(
+                id.clone(),
+                0,
+                1,
+                serde_json::to_value(TestAggregate {
+                    id: id.clone(),
+                    description: "upcast".to_string(),
+                    tests: vec![],
+                })
+                .unwrap()
+            )),
+            snapshot
+        );
+
+        // a fresh snapshot replaces the incompatible one
+        incompatible_repo
+            .insert::<TestAggregate>(
+                serde_json::to_value(TestAggregate {
+                    id: id.clone(),
+                    description: "current".to_string(),
+                    tests: vec![],
+                })
+                .unwrap(),
+                id.clone(),
+                1,
+                &[],
+            )
+            .await
+            .unwrap();
+        let snapshot = incompatible_repo
+            .get_snapshot::<TestAggregate>(&id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!("current", snapshot.aggregate["description"]);
+    }
+
+    #[tokio::test]
+    async fn invalid_snapshot_fallback() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut events = vec![
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() })),
+            test_event_envelope(
+                &id,
+                2,
+                TestEvent::Tested(Tested {
+                    test_name: "a test was run".to_string(),
+                }),
+            ),
+        ];
+        events
+            .iter_mut()
+            .for_each(|event| event.metadata = serde_json::json!({}));
+        let event_repo = SqliteEventRepository::new(pool.clone());
+        event_repo
+            .insert::<TestAggregate>(
+                serde_json::json!({"not": "a test aggregate"}),
+                id.clone(),
+                1,
+                &events,
+            )
+            .await
+            .unwrap();
+
+        // without validation the snapshot is loaded as stored
+        let snapshot = event_repo.get_snapshot::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(
+            serde_json::json!({"not": "a test aggregate"}),
+            snapshot.unwrap().aggregate
+        );
+
+        let event_repo = event_repo.with_snapshot_validation(true);
+        let snapshot = event_repo.get_snapshot::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(None, snapshot);
+
+        let event_repo = event_repo
+            .with_snapshot_validation(false)
+            .with_invalid_snapshot_rewrite(true);
+        let expected = Some(snapshot_context(
+            id.clone(),
+            2,
+            1,
+            serde_json::to_value(TestAggregate::default()).unwrap(),
+        ));
+        let snapshot = event_repo.get_snapshot::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(expected, snapshot);
+
+        // the rewritten snapshot is now stored
+        let event_repo = event_repo.with_invalid_snapshot_rewrite(false);
+        let snapshot = event_repo.get_snapshot::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(expected, snapshot);
+    }
+
+    #[tokio::test]
+    async fn jsonb_encoding() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let ready = SqliteEventRepository::new(pool.clone())
+            .with_json_encoding(JsonEncoding::Jsonb)
+            .ready()
+            .await;
+        if rusqlite::version_number() < 3_045_000 {
+            // JSONB is unavailable in the linked SQLite library, the encoding is refused
+            assert!(matches!(
+                ready,
+                Err(SqliteAggregateError::UnsupportedSqliteVersion {
+                    required: "3.45.0",
+                    ..
+                })
+            ));
+            return;
+        }
+        ready.unwrap();
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let text_repo = SqliteEventRepository::new(pool.clone());
+        text_repo
+            .insert_events::<TestAggregate>(&[test_event_envelope(
+                &id,
+                1,
+                TestEvent::Created(Created { id: id.clone() }),
+            )])
+            .await
+            .unwrap();
+        let jsonb_repo = SqliteEventRepository::new(pool).with_json_encoding(JsonEncoding::Jsonb);
+        jsonb_repo
+            .insert_events::<TestAggregate>(&[test_event_envelope(
+                &id,
+                2,
+                TestEvent::Tested(Tested {
+                    test_name: "a test was run".to_string(),
+                }),
+            )])
+            .await
+            .unwrap();
+
+        // both encodings are readable
+        let events = jsonb_repo.get_events::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(2, events.len());
+        assert_eq!(
+            serde_json::to_value(TestEvent::Created(Created { id: id.clone() })).unwrap(),
+            events[0].payload
+        );
+        assert_eq!(
+            serde_json::to_value(TestEvent::Tested(Tested {
+                test_name: "a test was run".to_string(),
+            }))
+            .unwrap(),
+            events[1].payload
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshot_policy() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let event_repo = SqliteEventRepository::new(pool).with_snapshot_policy(Box::new(
+            |_: &str, _: &str, events_since_snapshot: usize| events_since_snapshot >= 3,
+        ));
+        let aggregate = serde_json::to_value(TestAggregate::default()).unwrap();
+        for sequence in 1..=4 {
+            let event = test_event_envelope(
+                &id,
+                sequence,
+                TestEvent::Tested(Tested {
+                    test_name: format!("test {}", sequence),
+                }),
+            );
+            // the framework offers a snapshot on every commit
+            event_repo
+                .persist::<TestAggregate>(&[event], Some((id.clone(), aggregate.clone(), 1)))
+                .await
+                .unwrap();
+            let snapshot = event_repo.get_snapshot::<TestAggregate>(&id).await.unwrap();
+            let events_since_snapshot = event_repo
+                .event_counts()
+                .since_snapshot("TestAggregate", &id);
+            match sequence {
+                1 | 2 => {
+                    assert_eq!(None, snapshot);
+                    assert_eq!(Some(sequence), events_since_snapshot);
+                }
+                _ => {
+                    assert_eq!(3, snapshot.unwrap().current_sequence);
+                    assert_eq!(Some(sequence - 3), events_since_snapshot);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn deferred_write_retries() {
+        let path = std::env::temp_dir().join(format!("{}.db", uuid::Uuid::new_v4()));
+        let manager = r2d2_sqlite::SqliteConnectionManager::file(&path)
+            .with_init(|c| c.busy_timeout(std::time::Duration::ZERO));
+        let pool = r2d2::Pool::builder().max_size(1).build(manager).unwrap();
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        pool.get()
+            .unwrap()
+            .execute_batch(contents.as_str())
+            .unwrap();
+
+        // another process holds the write lock briefly
+        let (locked, is_locked) = std::sync::mpsc::channel();
+        let (release, do_release) = std::sync::mpsc::channel::<()>();
+        let writer_path = path.clone();
+        let writer = std::thread::spawn(move || {
+            let mut writer = rusqlite::Connection::open(writer_path).unwrap();
+            let lock = writer
+                .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+                .unwrap();
+            locked.send(()).unwrap();
+            do_release.recv().unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(30));
+            lock.commit().unwrap();
+        });
+        is_locked.recv().unwrap();
+        let id = uuid::Uuid::new_v4().to_string();
+        let event = test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+
+        let immediate = SqliteEventRepository::new(pool.clone());
+        let err = immediate
+            .insert_events::<TestAggregate>(std::slice::from_ref(&event))
+            .await
+            .unwrap_err();
+        assert!(err.is_busy());
+
+        let deferred = SqliteEventRepository::new(pool)
+            .with_write_transaction(WriteTransaction::Deferred { max_retries: 10 });
+        release.send(()).unwrap();
+        deferred
+            .insert_events::<TestAggregate>(&[event])
+            .await
+            .unwrap();
+        writer.join().unwrap();
+        assert_eq!(
+            1,
+            deferred
+                .get_events::<TestAggregate>(&id)
+                .await
+                .unwrap()
+                .len()
+        );
+
+        drop(deferred);
+        drop(immediate);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn materialize_view_on_the_fly() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let event_repo = SqliteEventRepository::new(pool);
+        let view = event_repo
+            .materialize_view_on_the_fly::<TestView, TestAggregate>(&id)
+            .await
+            .unwrap();
+        assert_eq!(None, view);
+
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = serde_json::json!({});
+        let mut tested = test_event_envelope(
+            &id,
+            2,
+            TestEvent::Tested(Tested {
+                test_name: "on the fly".to_string(),
+            }),
+        );
+        tested.metadata = serde_json::json!({});
+        event_repo
+            .insert_events::<TestAggregate>(&[created, tested])
+            .await
+            .unwrap();
+        let view = event_repo
+            .materialize_view_on_the_fly::<TestView, TestAggregate>(&id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            vec![
+                TestEvent::Created(Created { id: id.clone() }),
+                TestEvent::Tested(Tested {
+                    test_name: "on the fly".to_string(),
+                }),
+            ],
+            view.events
+        );
+    }
+
+    #[tokio::test]
+    async fn payload_limits() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let event_repo = SqliteEventRepository::new(pool)
+            .with_payload_limits(PayloadLimits::new().with_max_bytes(64));
+        let small = test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        let large = test_event_envelope(
+            &id,
+            2,
+            TestEvent::Tested(Tested {
+                test_name: "x".repeat(64),
+            }),
+        );
+        match event_repo
+            .insert_events::<TestAggregate>(&[small.clone(), large])
+            .await
+        {
+            Err(SqliteAggregateError::PayloadTooLarge { limit: 64, .. }) => {}
+            _ => panic!("expected a payload size error"),
+        }
+        let events = event_repo.get_events::<TestAggregate>(&id).await.unwrap();
+        assert!(events.is_empty());
+
+        event_repo
+            .insert_events::<TestAggregate>(&[small])
+            .await
+            .unwrap();
+        let large_snapshot = serde_json::json!({ "description": "x".repeat(64) });
+        match event_repo
+            .update::<TestAggregate>(large_snapshot, id.clone(), 2, &[])
+            .await
+        {
+            Err(SqliteAggregateError::PayloadTooLarge { .. }) => {}
+            _ => panic!("expected a payload size error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn commit_receipts() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let event_repo = SqliteEventRepository::new(pool)
+            .with_commit_receipts(CommitReceipts::RecordedWithMetadata);
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = serde_json::json!({});
+        let mut tested = test_event_envelope(
+            &id,
+            2,
+            TestEvent::Tested(Tested {
+                test_name: "a receipt".to_string(),
+            }),
+        );
+        tested.metadata = serde_json::json!({});
+        event_repo
+            .persist::<TestAggregate>(&[created, tested], None)
+            .await
+            .unwrap();
+
+        let receipt = event_repo
+            .take_commit_receipt::<TestAggregate>(&id)
+            .unwrap();
+        assert_eq!(2, receipt.aggregate_version);
+        assert!(receipt.global_position > 0);
+        assert_eq!(None, event_repo.take_commit_receipt::<TestAggregate>(&id));
+
+        let events = event_repo.get_events::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(
+            Some(receipt.global_position.to_string().as_str()),
+            events[1].metadata[GLOBAL_POSITION_METADATA_KEY].as_str()
+        );
+        assert!(events[0].metadata[GLOBAL_POSITION_METADATA_KEY].is_string());
+    }
+
+    #[tokio::test]
+    async fn compaction_refused_with_hash_chain() {
+        let repo = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .with_hash_chain(HashChain::PerAggregate)
+            .ready()
+            .await
+            .unwrap()
+            .into_inner();
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut events = vec![test_event_envelope(
+            &id,
+            1,
+            TestEvent::Created(Created { id: id.clone() }),
+        )];
+        for sequence in 2..=3 {
+            events.push(test_event_envelope(
+                &id,
+                sequence,
+                TestEvent::Tested(Tested {
+                    test_name: format!("test {sequence}"),
+                }),
+            ));
+        }
+        for event in &mut events {
+            event.metadata = serde_json::json!({});
         }
-        Ok(current_sequence)
+        repo.persist::<TestAggregate>(&events, None).await.unwrap();
+        assert!(repo
+            .compact_stream::<TestAggregate, _>(&id, 2, |events| events[1].payload.clone())
+            .await
+            .is_err());
+        assert_eq!(
+            3,
+            repo.get_events::<TestAggregate>(&id).await.unwrap().len()
+        );
+        assert!(repo.verify_hash_chain().await.unwrap().is_ok());
     }
-}
-
-#[cfg(test)]
-mod test {
-    use cqrs_es::persist::PersistedEventRepository;
-    use std::fs;
-
-    use crate::error::SqliteAggregateError;
-    use crate::testing::tests::{
-        snapshot_context, test_event_envelope, Created, SomethingElse, TestAggregate, TestEvent,
-        Tested, TEST_CONNECTION_STRING,
-    };
-    use crate::{default_sqlite_pool, SqliteEventRepository};
 
     #[tokio::test]
-    async fn event_repositories() {
+    async fn compact_stream() {
         let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
         let contents = fs::read_to_string("db/init.sql").unwrap();
         let conn = pool.get().unwrap();
@@ -400,78 +3345,99 @@ mod test {
         drop(conn);
 
         let id = uuid::Uuid::new_v4().to_string();
-        let event_repo: SqliteEventRepository =
-            SqliteEventRepository::new(pool.clone()).with_streaming_channel_size(1);
-        let events = event_repo.get_events::<TestAggregate>(&id).await.unwrap();
-        assert!(events.is_empty());
-
+        let event_repo = SqliteEventRepository::new(pool);
+        let mut events = vec![test_event_envelope(
+            &id,
+            1,
+            TestEvent::Created(Created { id: id.clone() }),
+        )];
+        for (sequence, test_name) in [(2, "a"), (3, "b"), (4, "c")] {
+            events.push(test_event_envelope(
+                &id,
+                sequence,
+                TestEvent::Tested(Tested {
+                    test_name: test_name.to_string(),
+                }),
+            ));
+        }
+        for event in &mut events {
+            event.metadata = serde_json::json!({});
+        }
         event_repo
-            .insert_events::<TestAggregate>(&[
-                test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() })),
-                test_event_envelope(
-                    &id,
-                    2,
-                    TestEvent::Tested(Tested {
-                        test_name: "a test was run".to_string(),
-                    }),
-                ),
-            ])
+            .insert_events::<TestAggregate>(&events)
+            .await
+            .unwrap();
+        event_repo
+            .insert::<TestAggregate>(serde_json::json!({}), id.clone(), 1, &[])
+            .await
             .unwrap();
-        let events = event_repo.get_events::<TestAggregate>(&id).await.unwrap();
-        assert_eq!(2, events.len());
-        events.iter().for_each(|e| assert_eq!(&id, &e.aggregate_id));
-
-        // Optimistic lock error
-        let result = event_repo
-            .insert_events::<TestAggregate>(&[
-                test_event_envelope(
-                    &id,
-                    3,
-                    TestEvent::SomethingElse(SomethingElse {
-                        description: "this should not persist".to_string(),
-                    }),
-                ),
-                test_event_envelope(
-                    &id,
-                    2,
-                    TestEvent::SomethingElse(SomethingElse {
-                        description: "bad sequence number".to_string(),
-                    }),
-                ),
-            ])
-            .unwrap_err();
-        match result {
-            SqliteAggregateError::OptimisticLock => {}
-            _ => panic!("invalid error result found during insert: {}", result),
-        };
-
-        let events = event_repo.get_events::<TestAggregate>(&id).await.unwrap();
-        assert_eq!(2, events.len());
 
-        verify_replay_stream(&id, event_repo).await;
-    }
+        let removed = event_repo
+            .compact_stream::<TestAggregate, _>(&id, 3, |events| {
+                let names: Vec<String> = events
+                    .iter()
+                    .filter_map(|event| match &event.payload {
+                        TestEvent::Tested(tested) => Some(tested.test_name.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                TestEvent::Tested(Tested {
+                    test_name: names.join(","),
+                })
+            })
+            .await
+            .unwrap();
+        assert_eq!(2, removed);
 
-    async fn verify_replay_stream(id: &str, event_repo: SqliteEventRepository) {
-        let mut stream = event_repo.stream_events::<TestAggregate>(id).await.unwrap();
-        let mut found_in_stream = 0;
-        while (stream.next::<TestAggregate>().await).is_some() {
-            found_in_stream += 1;
-        }
-        assert_eq!(found_in_stream, 2);
+        let compacted = event_repo.get_events::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(
+            vec![(1, "a,b".to_string()), (2, "c".to_string())],
+            compacted
+                .iter()
+                .map(|event| (
+                    event.sequence,
+                    event.payload["Tested"]["test_name"]
+                        .as_str()
+                        .unwrap()
+                        .to_string()
+                ))
+                .collect::<Vec<_>>()
+        );
+        assert!(event_repo
+            .get_snapshot::<TestAggregate>(&id)
+            .await
+            .unwrap()
+            .is_none());
 
-        let mut stream = event_repo
-            .stream_all_events::<TestAggregate>()
+        // optimistic locking applies to the renumbered stream
+        let conflicting = test_event_envelope(
+            &id,
+            2,
+            TestEvent::SomethingElse(SomethingElse {
+                description: "conflict".to_string(),
+            }),
+        );
+        assert!(matches!(
+            event_repo
+                .insert_events::<TestAggregate>(&[conflicting])
+                .await,
+            Err(SqliteAggregateError::OptimisticLock)
+        ));
+        let next = test_event_envelope(
+            &id,
+            3,
+            TestEvent::SomethingElse(SomethingElse {
+                description: "next".to_string(),
+            }),
+        );
+        event_repo
+            .insert_events::<TestAggregate>(&[next])
             .await
             .unwrap();
-        let mut found_in_stream = 0;
-        while (stream.next::<TestAggregate>().await).is_some() {
-            found_in_stream += 1;
-        }
-        assert!(found_in_stream >= 2);
     }
 
     #[tokio::test]
-    async fn snapshot_repositories() {
+    async fn load_from_snapshot() {
         let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
         let contents = fs::read_to_string("db/init.sql").unwrap();
         let conn = pool.get().unwrap();
@@ -479,106 +3445,287 @@ mod test {
         drop(conn);
 
         let id = uuid::Uuid::new_v4().to_string();
-        let event_repo: SqliteEventRepository = SqliteEventRepository::new(pool.clone());
-        let snapshot = event_repo.get_snapshot::<TestAggregate>(&id).await.unwrap();
-        assert_eq!(None, snapshot);
+        let event_repo = SqliteEventRepository::new(pool);
+        let mut events = vec![test_event_envelope(
+            &id,
+            1,
+            TestEvent::Created(Created { id: id.clone() }),
+        )];
+        for (sequence, test_name) in [(2, "before snapshot"), (3, "after snapshot")] {
+            events.push(test_event_envelope(
+                &id,
+                sequence,
+                TestEvent::Tested(Tested {
+                    test_name: test_name.to_string(),
+                }),
+            ));
+        }
+        for event in &mut events {
+            event.metadata = serde_json::json!({});
+        }
 
-        let test_description = "some test snapshot here".to_string();
-        let test_tests = vec!["testA".to_string(), "testB".to_string()];
+        let loaded = event_repo
+            .load_from_snapshot::<TestAggregate>(&id)
+            .await
+            .unwrap();
+        assert!(loaded.aggregate.is_none());
+        assert_eq!(0, loaded.current_sequence());
+
+        let snapshot = TestAggregate {
+            id: id.clone(),
+            description: "snapshot".to_string(),
+            tests: vec!["before snapshot".to_string()],
+        };
         event_repo
             .insert::<TestAggregate>(
-                serde_json::to_value(TestAggregate {
-                    id: id.clone(),
-                    description: test_description.clone(),
-                    tests: test_tests.clone(),
-                })
-                .unwrap(),
+                serde_json::to_value(&snapshot).unwrap(),
                 id.clone(),
                 1,
-                &[],
+                &events[..2],
             )
+            .await
+            .unwrap();
+        event_repo
+            .insert_events::<TestAggregate>(&events[2..])
+            .await
             .unwrap();
 
-        let snapshot = event_repo.get_snapshot::<TestAggregate>(&id).await.unwrap();
+        let loaded = event_repo
+            .load_from_snapshot::<TestAggregate>(&id)
+            .await
+            .unwrap();
+        assert_eq!(Some(&snapshot), loaded.aggregate.as_ref());
+        assert_eq!(2, loaded.snapshot_sequence);
         assert_eq!(
-            Some(snapshot_context(
-                id.clone(),
-                0,
-                1,
-                serde_json::to_value(TestAggregate {
-                    id: id.clone(),
-                    description: test_description.clone(),
-                    tests: test_tests.clone(),
-                })
-                .unwrap()
-            )),
-            snapshot
+            vec![TestEvent::Tested(Tested {
+                test_name: "after snapshot".to_string()
+            })],
+            loaded
+                .events
+                .iter()
+                .map(|event| event.payload.clone())
+                .collect::<Vec<_>>()
         );
+        assert_eq!(3, loaded.current_sequence());
+        assert_eq!(snapshot, loaded.into_aggregate());
+    }
 
-        // sequence iterated, does update
-        event_repo
-            .update::<TestAggregate>(
-                serde_json::to_value(TestAggregate {
-                    id: id.clone(),
-                    description: "a test description that should be saved".to_string(),
-                    tests: test_tests.clone(),
-                })
-                .unwrap(),
-                id.clone(),
-                2,
-                &[],
-            )
+    #[tokio::test]
+    async fn snapshots_in_attached_database() {
+        let manager = r2d2_sqlite::SqliteConnectionManager::memory()
+            .with_init(|conn| conn.execute_batch("ATTACH DATABASE ':memory:' AS snapshot_db"));
+        let pool = r2d2::Pool::builder().max_size(1).build(manager).unwrap();
+        let repo = SqliteEventRepository::new(pool.clone())
+            .with_snapshot_schema("snapshot_db")
+            .ready()
+            .await
             .unwrap();
+        repo.ensure_indexes(&[], &[]).unwrap();
 
-        let snapshot = event_repo.get_snapshot::<TestAggregate>(&id).await.unwrap();
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = serde_json::json!({});
+        let aggregate = serde_json::to_value(TestAggregate {
+            id: id.clone(),
+            description: "attached".to_string(),
+            tests: Vec::new(),
+        })
+        .unwrap();
+        repo.persist::<TestAggregate>(&[created], Some((id.clone(), aggregate.clone(), 1)))
+            .await
+            .unwrap();
+        let snapshot = repo.get_snapshot::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(aggregate, snapshot.unwrap().aggregate);
+
+        let conn = pool.get().unwrap();
+        let count = |table: &str| -> i64 {
+            conn.query_row(&format!("SELECT count(*) FROM {table}"), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        assert_eq!(1, count("snapshot_db.snapshots"));
+        assert_eq!(1, count("main.events"));
+        assert_eq!(0, count("main.sqlite_master WHERE name LIKE 'snapshots%'"));
         assert_eq!(
-            Some(snapshot_context(
-                id.clone(),
-                0,
-                2,
-                serde_json::to_value(TestAggregate {
-                    id: id.clone(),
-                    description: "a test description that should be saved".to_string(),
-                    tests: test_tests.clone(),
-                })
-                .unwrap()
-            )),
-            snapshot
+            2,
+            count("snapshot_db.sqlite_master WHERE name LIKE 'snapshots%'")
         );
+    }
 
-        // sequence out of order or not iterated, does not update
-        let result = event_repo
-            .update::<TestAggregate>(
-                serde_json::to_value(TestAggregate {
-                    id: id.clone(),
-                    description: "a test description that should not be saved".to_string(),
-                    tests: test_tests.clone(),
-                })
-                .unwrap(),
-                id.clone(),
-                2,
-                &[],
-            )
-            .unwrap_err();
-        match result {
-            SqliteAggregateError::OptimisticLock => {}
-            _ => panic!("invalid error result found during insert: {}", result),
+    #[tokio::test]
+    async fn append_unordered() {
+        let repo = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .ready()
+            .await
+            .unwrap();
+        let id = uuid::Uuid::new_v4().to_string();
+        let tested = |test_name: &str| {
+            TestEvent::Tested(Tested {
+                test_name: test_name.to_string(),
+            })
         };
 
-        let snapshot = event_repo.get_snapshot::<TestAggregate>(&id).await.unwrap();
+        let receipt = repo
+            .append_unordered::<TestAggregate>(&id, &[tested("a"), tested("b")])
+            .await
+            .unwrap();
+        assert_eq!(2, receipt.aggregate_version);
+        let appends = (0..4).map(|i| {
+            let repo = repo.clone();
+            let id = id.clone();
+            let event = tested(&i.to_string());
+            tokio::spawn(async move {
+                repo.append_unordered::<TestAggregate>(&id, &[event])
+                    .await
+                    .unwrap()
+            })
+        });
+        for append in appends {
+            append.await.unwrap();
+        }
+
+        let events = repo.get_events::<TestAggregate>(&id).await.unwrap();
         assert_eq!(
-            Some(snapshot_context(
-                id.clone(),
-                0,
-                2,
-                serde_json::to_value(TestAggregate {
-                    id: id.clone(),
-                    description: "a test description that should be saved".to_string(),
-                    tests: test_tests.clone(),
-                })
-                .unwrap()
-            )),
-            snapshot
+            (1..=6).collect::<Vec<_>>(),
+            events
+                .iter()
+                .map(|event| event.sequence)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn insert_events_if_absent() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+        let repo = SqliteEventRepository::new(pool);
+        let id = uuid::Uuid::new_v4().to_string();
+        let event = |sequence| {
+            let mut event = test_event_envelope(
+                &id,
+                sequence,
+                TestEvent::Tested(Tested {
+                    test_name: format!("imported {sequence}"),
+                }),
+            );
+            event.metadata = serde_json::json!({});
+            event
+        };
+
+        let inserted = repo
+            .insert_events_if_absent(&[event(1), event(2)])
+            .await
+            .unwrap();
+        assert_eq!(
+            InsertedEvents {
+                inserted: 2,
+                skipped: 0
+            },
+            inserted
+        );
+        let inserted = repo
+            .insert_events_if_absent(&[event(1), event(2), event(3)])
+            .await
+            .unwrap();
+        assert_eq!(
+            InsertedEvents {
+                inserted: 1,
+                skipped: 2
+            },
+            inserted
+        );
+
+        // sequences are checked across partitions
+        let repo = repo.with_partitioning(Partitioning::BySize { max_events: 10 });
+        let inserted = repo
+            .insert_events_if_absent(&[event(3), event(4)])
+            .await
+            .unwrap();
+        assert_eq!(
+            InsertedEvents {
+                inserted: 1,
+                skipped: 1
+            },
+            inserted
+        );
+        let events = repo.get_events::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(
+            vec![1, 2, 3, 4],
+            events
+                .iter()
+                .map(|event| event.sequence)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn persist_with() {
+        let repo = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .ready()
+            .await
+            .unwrap();
+        repo.with_connection(|conn| {
+            conn.execute_batch("CREATE TABLE outbox (aggregate_id text, sequence integer)")
+        })
+        .unwrap();
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = serde_json::json!({});
+        let outbox = |tx: &CommitTransaction<'_>| {
+            for event in tx.events() {
+                tx.execute(
+                    "INSERT INTO outbox VALUES (?, ?)",
+                    (&event.aggregate_id, event.sequence as i64),
+                )?;
+            }
+            Ok(())
+        };
+
+        let receipt = repo
+            .persist_with::<TestAggregate, _>(&[created.clone()], outbox)
+            .await
+            .unwrap();
+        assert_eq!(1, receipt.aggregate_version);
+
+        let mut tested = test_event_envelope(
+            &id,
+            2,
+            TestEvent::Tested(Tested {
+                test_name: "rolled back".to_string(),
+            }),
         );
+        tested.metadata = serde_json::json!({});
+        assert!(repo
+            .persist_with::<TestAggregate, _>(&[tested.clone()], |tx| {
+                tx.execute("INSERT INTO missing_table VALUES (1)", [])?;
+                Ok(())
+            })
+            .await
+            .is_err());
+        assert!(repo
+            .persist_with::<TestAggregate, _>(&[tested.clone()], |tx| tx
+                .execute(" commit", [])
+                .map(|_| ()))
+            .await
+            .is_err());
+
+        assert_eq!(
+            1,
+            repo.get_events::<TestAggregate>(&id).await.unwrap().len()
+        );
+        let outbox: Vec<(String, i64)> = repo
+            .with_connection(|conn| {
+                conn.prepare("SELECT aggregate_id, sequence FROM outbox")?
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect()
+            })
+            .unwrap();
+        assert_eq!(vec![(id, 1)], outbox);
     }
 }
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use cqrs_es::persist::SerializedEvent;
+use serde_json::Value;
+
+use crate::error::SqliteAggregateError;
+
+type EventValidator = dyn Fn(&str, &Value) -> Result<(), String> + Send + Sync;
+
+/// A registry of payload validators keyed by event type.
+///
+/// A repository configured with a registry validates every event payload before it is inserted,
+/// rejecting the commit with `SqliteAggregateError::InvalidEvent` if any payload fails. The same
+/// registry can be used to check events read from the store, e.g. those written by other
+/// processes, see `EventSchemaRegistry::validate`. Event types without a registered validator
+/// are accepted.
+///
+/// A validator is called with the event version and the serialized payload, it may wrap a JSON
+/// Schema validator or check the payload directly.
+///
+/// ```
+/// use rusqlite_es::EventSchemaRegistry;
+///
+/// let registry = EventSchemaRegistry::new().register("NameAdded", |_version, payload| {
+///     match payload["NameAdded"]["name"].as_str() {
+///         Some(name) if !name.is_empty() => Ok(()),
+///         _ => Err("a name is required".to_string()),
+///     }
+/// });
+/// ```
+#[derive(Clone, Default)]
+pub struct EventSchemaRegistry {
+    validators: HashMap<String, Arc<EventValidator>>,
+}
+
+impl EventSchemaRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Registers the validator for `event_type`, replacing any previously registered validator.
+    pub fn register<F>(mut self, event_type: &str, validator: F) -> Self
+    where
+        F: Fn(&str, &Value) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.validators
+            .insert(event_type.to_string(), Arc::new(validator));
+        self
+    }
+
+    /// Returns true if a validator is registered for `event_type`.
+    pub fn is_registered(&self, event_type: &str) -> bool {
+        self.validators.contains_key(event_type)
+    }
+
+    /// Validates a serialized event against the validator registered for its event type.
+    pub fn validate(&self, event: &SerializedEvent) -> Result<(), SqliteAggregateError> {
+        self.validate_payload(&event.event_type, &event.event_version, &event.payload)
+    }
+
+    pub(crate) fn validate_payload(
+        &self,
+        event_type: &str,
+        event_version: &str,
+        payload: &Value,
+    ) -> Result<(), SqliteAggregateError> {
+        match self.validators.get(event_type) {
+            None => Ok(()),
+            Some(validator) => validator(event_version, payload).map_err(|reason| {
+                SqliteAggregateError::InvalidEvent {
+                    event_type: event_type.to_string(),
+                    reason,
+                }
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use cqrs_es::persist::PersistedEventRepository;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::SqliteEventRepository;
+    use crate::{default_sqlite_pool, EventSchemaRegistry, SqliteAggregateError};
+
+    #[tokio::test]
+    async fn validate_on_insert() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let registry = EventSchemaRegistry::new().register("Tested", |_, payload| {
+            match payload["Tested"]["test_name"].as_str() {
+                Some(name) if !name.is_empty() => Ok(()),
+                _ => Err("a test name is required".to_string()),
+            }
+        });
+        assert!(registry.is_registered("Tested"));
+        assert!(!registry.is_registered("Created"));
+        let event_repo = SqliteEventRepository::new(pool).with_event_schemas(registry);
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let created = test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        let invalid = test_event_envelope(
+            &id,
+            2,
+            TestEvent::Tested(Tested {
+                test_name: "".to_string(),
+            }),
+        );
+        match event_repo
+            .insert_events::<TestAggregate>(&[created.clone(), invalid])
+            .await
+        {
+            Err(SqliteAggregateError::InvalidEvent { event_type, reason }) => {
+                assert_eq!("Tested", event_type);
+                assert_eq!("a test name is required", reason);
+            }
+            _ => panic!("expected an invalid event error"),
+        }
+        assert!(event_repo
+            .get_events::<TestAggregate>(&id)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let valid = test_event_envelope(
+            &id,
+            2,
+            TestEvent::Tested(Tested {
+                test_name: "a test was run".to_string(),
+            }),
+        );
+        event_repo
+            .insert_events::<TestAggregate>(&[created, valid])
+            .await
+            .unwrap();
+        for event in event_repo.get_events::<TestAggregate>(&id).await.unwrap() {
+            event_repo.event_schemas().validate(&event).unwrap();
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use cqrs_es::persist::{
+    EventUpcaster, PersistenceError, ReplayFeed, ReplayStream, SerializedEvent,
+};
+use cqrs_es::{Aggregate, EventEnvelope};
+use futures::stream::BoxStream;
+use futures::{Stream, StreamExt};
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::mapping::deser_event;
+use crate::replay_progress::ReplayRun;
+use crate::{ReplayProgress, Shutdown, StreamErrorPolicy};
+
+/// Accesses a stream of serialized events that may span any number of aggregate types.
+///
+/// Unlike `ReplayStream`, events are not deserialized into a single aggregate's event type,
+/// making this suitable for system-wide audit logs and generic replicators.
+pub struct SerializedEventStream {
+    queue: Receiver<Result<SerializedEvent, PersistenceError>>,
+}
+
+impl SerializedEventStream {
+    pub(crate) fn new(
+        queue_size: usize,
+    ) -> (Sender<Result<SerializedEvent, PersistenceError>>, Self) {
+        let (sender, queue) = tokio::sync::mpsc::channel(queue_size);
+        (sender, Self { queue })
+    }
+
+    /// Receive the next serialized event or error in the stream, if no event is available this
+    /// will block.
+    pub async fn next(&mut self) -> Option<Result<SerializedEvent, PersistenceError>> {
+        self.queue.recv().await
+    }
+}
+
+/// Adapts a `ReplayStream` into a `futures::Stream` of deserialized events, so that replays can
+/// be processed with `StreamExt` and `TryStreamExt` combinators rather than a loop over
+/// `ReplayStream::next`.
+///
+/// ```
+/// # use cqrs_es::doc::MyAggregate;
+/// use cqrs_es::persist::{PersistedEventRepository, PersistenceError};
+/// use futures::StreamExt;
+/// use rusqlite_es::{EnvelopeStream, SqliteEventRepository};
+///
+/// async fn replay_in_batches(repo: &SqliteEventRepository) -> Result<(), PersistenceError> {
+///     let stream = repo.stream_all_events::<MyAggregate>().await?;
+///     let mut batches = EnvelopeStream::<MyAggregate>::new(stream).chunks(100);
+///     while let Some(batch) = batches.next().await {
+///         for event in batch {
+///             let event = event?;
+///             // ...
+///         }
+///     }
+///     Ok(())
+/// }
+/// ```
+pub struct EnvelopeStream<A: Aggregate> {
+    inner: BoxStream<'static, Result<EventEnvelope<A>, PersistenceError>>,
+}
+
+impl<A: Aggregate + 'static> EnvelopeStream<A> {
+    /// Wraps a `ReplayStream`, e.g. as returned by `stream_all_events`.
+    pub fn new(stream: ReplayStream) -> Self {
+        Self::with_upcasters(stream, None)
+    }
+
+    /// Wraps a `ReplayStream`, upcasting events with the provided upcasters as they are read.
+    pub fn with_upcasters(
+        stream: ReplayStream,
+        event_upcasters: Option<Vec<Box<dyn EventUpcaster>>>,
+    ) -> Self {
+        let inner = futures::stream::unfold(
+            (stream, event_upcasters),
+            |(mut stream, event_upcasters)| async move {
+                let event = stream.next::<A>(&event_upcasters).await?;
+                Some((event, (stream, event_upcasters)))
+            },
+        )
+        .boxed();
+        Self { inner }
+    }
+}
+
+impl<A: Aggregate> Stream for EnvelopeStream<A> {
+    type Item = Result<EventEnvelope<A>, PersistenceError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+/// Progress reporting for a replay, with the query counting the events the replay will read.
+pub(crate) type TrackedProgress = Option<(ReplayProgress, String)>;
+
+/// Runs the provided query on a blocking thread, handing each deserialized row to `push`
+/// until the rows are exhausted, `push` reports that the receiving side has gone away,
+/// shutdown is requested or the replay is cancelled. Rows that cannot be deserialized are
+/// handled according to `error_policy`.
+pub(crate) fn feed_events<P, F>(
+    query: String,
+    params: Vec<String>,
+    pool: P,
+    shutdown: Option<Shutdown>,
+    progress: TrackedProgress,
+    error_policy: StreamErrorPolicy,
+    mut push: F,
+) where
+    P: ConnectionProvider,
+    F: FnMut(Result<SerializedEvent, PersistenceError>) -> bool + Send + 'static,
+{
+    let task_shutdown = shutdown.clone();
+    let task = tokio::task::spawn_blocking(move || {
+        let result = read_events(
+            &query,
+            &params,
+            &pool,
+            &task_shutdown,
+            &progress,
+            &error_policy,
+            &mut push,
+        );
+        if let Err(err) = result {
+            push(Err(err.into()));
+        }
+    });
+    if let Some(shutdown) = shutdown {
+        shutdown.track(task);
+    }
+}
+
+fn read_events<P, F>(
+    query: &str,
+    params: &[String],
+    pool: &P,
+    shutdown: &Option<Shutdown>,
+    progress: &TrackedProgress,
+    error_policy: &StreamErrorPolicy,
+    push: &mut F,
+) -> Result<(), SqliteAggregateError>
+where
+    P: ConnectionProvider,
+    F: FnMut(Result<SerializedEvent, PersistenceError>) -> bool,
+{
+    let connection = pool.connection()?;
+    let run = match progress {
+        Some((progress, count_query)) => {
+            let total: i64 =
+                connection.query_row(count_query, rusqlite::params_from_iter(params), |row| {
+                    row.get(0)
+                })?;
+            Some(progress.start(total as usize))
+        }
+        None => None,
+    };
+    let mut statement = connection.prepare_cached(query)?;
+    let mut rows = statement.query(rusqlite::params_from_iter(params))?;
+    while let Some(row) = rows.next()? {
+        if shutdown.as_ref().is_some_and(Shutdown::is_requested) {
+            return Ok(());
+        }
+        if run.as_ref().is_some_and(ReplayRun::is_cancelled) {
+            return Err(SqliteAggregateError::ReplayCancelled);
+        }
+        let event_result = match deser_event(row) {
+            Ok(event) => Ok(event),
+            Err(err) => match error_policy.recover(&connection, row, err) {
+                Some(err) => Err(PersistenceError::from(err)),
+                None => continue,
+            },
+        };
+        if let (Some(run), Ok(event)) = (&run, &event_result) {
+            run.record(&event.aggregate_id);
+        }
+        if !push(event_result) {
+            // The stream was dropped, no one is listening for further events.
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn push_to_replay_feed(
+    mut feed: ReplayFeed,
+) -> impl FnMut(Result<SerializedEvent, PersistenceError>) -> bool + Send + 'static {
+    move |event_result| futures::executor::block_on(feed.push(event_result)).is_ok()
+}
+
+pub(crate) fn push_to_sender(
+    sender: Sender<Result<SerializedEvent, PersistenceError>>,
+) -> impl FnMut(Result<SerializedEvent, PersistenceError>) -> bool + Send + 'static {
+    move |event_result| sender.blocking_send(event_result).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use cqrs_es::persist::PersistedEventRepository;
+    use futures::{StreamExt, TryStreamExt};
+    use serde_json::json;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, EnvelopeStream, SqliteEventRepository};
+
+    #[tokio::test]
+    async fn envelope_stream() {
+        let repo = SqliteEventRepository::connect(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .await
+            .unwrap();
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut events = vec![
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() })),
+            test_event_envelope(
+                &id,
+                2,
+                TestEvent::Tested(Tested {
+                    test_name: "a test".to_string(),
+                }),
+            ),
+            test_event_envelope(
+                &id,
+                3,
+                TestEvent::Tested(Tested {
+                    test_name: "another test".to_string(),
+                }),
+            ),
+        ];
+        for event in &mut events {
+            event.metadata = json!({});
+        }
+        repo.persist::<TestAggregate>(&events, None).await.unwrap();
+
+        let stream = repo.stream_events::<TestAggregate>(&id).await.unwrap();
+        let batches = EnvelopeStream::<TestAggregate>::new(stream)
+            .chunks(2)
+            .map(|batch| batch.len())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(vec![2, 1], batches);
+
+        let stream = repo.stream_events::<TestAggregate>(&id).await.unwrap();
+        let sequences = EnvelopeStream::<TestAggregate>::new(stream)
+            .map_ok(|event| event.sequence)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(vec![1, 2, 3], sequences);
+    }
+}
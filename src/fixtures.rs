@@ -0,0 +1,255 @@
+//! Golden-file fixtures of an aggregate instance's events, for regression tests of aggregate
+//! behavior across releases.
+//!
+//! A test commits the events of a scenario, dumps them with `dump` and compares the fixture's
+//! canonical JSON with a golden file checked in alongside the tests, catching unintended changes
+//! to the events produced or their serialization. Conversely, `replay` loads a golden file
+//! written by an earlier release into a fresh store, verifying that the current release still
+//! reads and applies the events stored by it.
+//!
+//! ```
+//! # use cqrs_es::doc::MyAggregate;
+//! use cqrs_es::persist::PersistenceError;
+//! use rusqlite_es::fixtures::{self, EventFixture};
+//! use rusqlite_es::SqliteEventRepository;
+//!
+//! async fn matches_golden_file(
+//!     repo: &SqliteEventRepository,
+//!     aggregate_id: &str,
+//! ) -> Result<bool, PersistenceError> {
+//!     let fixture = fixtures::dump::<MyAggregate, _>(repo, aggregate_id).await?;
+//!     let path = format!("tests/fixtures/{aggregate_id}.json");
+//!     if std::env::var_os("UPDATE_GOLDEN_FILES").is_some() {
+//!         fixture.write(&path)?;
+//!     }
+//!     Ok(fixture == EventFixture::read(&path)?)
+//! }
+//! ```
+use std::fs;
+use std::path::Path;
+
+use cqrs_es::persist::{PersistedEventRepository, PersistenceError, SerializedEvent};
+use cqrs_es::Aggregate;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::SqliteEventRepository;
+
+/// The events of an aggregate instance as stored in a golden file, see `dump`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EventFixture {
+    /// The type of the aggregate.
+    pub aggregate_type: String,
+    /// The id of the aggregate instance.
+    pub aggregate_id: String,
+    /// The events of the aggregate instance, in order of their sequence.
+    pub events: Vec<FixtureEvent>,
+}
+
+/// An event of an `EventFixture`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FixtureEvent {
+    /// The sequence number of the event.
+    pub sequence: usize,
+    /// The type of the event.
+    pub event_type: String,
+    /// The version of the event.
+    pub event_version: String,
+    /// The serialized event.
+    pub payload: Value,
+    /// The metadata of the event.
+    pub metadata: Value,
+}
+
+impl EventFixture {
+    /// Creates the fixture of an aggregate instance's events.
+    pub fn new(aggregate_type: &str, aggregate_id: &str, events: &[SerializedEvent]) -> Self {
+        Self {
+            aggregate_type: aggregate_type.to_string(),
+            aggregate_id: aggregate_id.to_string(),
+            events: events
+                .iter()
+                .map(|event| FixtureEvent {
+                    sequence: event.sequence,
+                    event_type: event.event_type.clone(),
+                    event_version: event.event_version.clone(),
+                    payload: event.payload.clone(),
+                    metadata: event.metadata.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Replaces the metadata of every event with `{}`, e.g. when the metadata holds timestamps
+    /// or request ids that differ on every run.
+    pub fn without_metadata(self) -> Self {
+        Self {
+            events: self
+                .events
+                .into_iter()
+                .map(|event| FixtureEvent {
+                    metadata: Value::Object(Default::default()),
+                    ..event
+                })
+                .collect(),
+            ..self
+        }
+    }
+
+    /// The events as `SerializedEvent`s.
+    pub fn serialized_events(&self) -> Vec<SerializedEvent> {
+        self.events
+            .iter()
+            .map(|event| {
+                SerializedEvent::new(
+                    self.aggregate_id.clone(),
+                    event.sequence,
+                    self.aggregate_type.clone(),
+                    event.event_type.clone(),
+                    event.event_version.clone(),
+                    event.payload.clone(),
+                    event.metadata.clone(),
+                )
+            })
+            .collect()
+    }
+
+    /// The canonical JSON of the fixture: pretty-printed, with the keys of every object in
+    /// sorted order and a trailing newline, so that equal fixtures produce identical files and
+    /// changes show up as readable diffs.
+    pub fn to_json(&self) -> Result<String, SqliteAggregateError> {
+        let value = canonical(serde_json::to_value(self)?);
+        Ok(format!("{}\n", serde_json::to_string_pretty(&value)?))
+    }
+
+    /// Parses a fixture from its JSON.
+    pub fn from_json(json: &str) -> Result<Self, SqliteAggregateError> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Reads a fixture from a golden file.
+    pub fn read<T: AsRef<Path>>(path: T) -> Result<Self, SqliteAggregateError> {
+        let json = fs::read_to_string(path).map_err(fixture_error)?;
+        Self::from_json(&json)
+    }
+
+    /// Writes the canonical JSON of the fixture to a golden file, creating its directory as
+    /// needed.
+    pub fn write<T: AsRef<Path>>(&self, path: T) -> Result<(), SqliteAggregateError> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(fixture_error)?;
+        }
+        fs::write(path, self.to_json()?).map_err(fixture_error)
+    }
+}
+
+/// Returns the fixture of the events of the aggregate instance `aggregate_id`.
+pub async fn dump<A, P>(
+    repo: &SqliteEventRepository<P>,
+    aggregate_id: &str,
+) -> Result<EventFixture, PersistenceError>
+where
+    A: Aggregate,
+    P: ConnectionProvider,
+{
+    let events = repo.get_events::<A>(aggregate_id).await?;
+    Ok(EventFixture::new(
+        &A::aggregate_type(),
+        aggregate_id,
+        &events,
+    ))
+}
+
+/// Inserts the events of a fixture, e.g. into a fresh store, returning the number of events
+/// inserted. See `import::bulk_load`.
+pub async fn replay<P: ConnectionProvider>(
+    repo: &SqliteEventRepository<P>,
+    fixture: &EventFixture,
+) -> Result<usize, SqliteAggregateError> {
+    crate::import::bulk_load(repo, &fixture.serialized_events()).await
+}
+
+// Sorts the keys of every object, regardless of how `serde_json` orders its maps.
+fn canonical(value: Value) -> Value {
+    match value {
+        Value::Object(object) => {
+            let mut entries = object.into_iter().collect::<Vec<_>>();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, canonical(value)))
+                    .collect(),
+            )
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(canonical).collect()),
+        value => value,
+    }
+}
+
+fn fixture_error(err: std::io::Error) -> SqliteAggregateError {
+    SqliteAggregateError::UnknownError(Box::new(err))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde_json::json;
+
+    use crate::fixtures::{dump, replay, EventFixture};
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, SqliteEventRepository};
+
+    #[tokio::test]
+    async fn golden_file() {
+        let repo = SqliteEventRepository::connect(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .await
+            .unwrap()
+            .into_inner();
+        let id = "golden-1";
+        let mut created = test_event_envelope(id, 1, TestEvent::Created(Created { id: id.into() }));
+        created.metadata = json!({"user_id": "alice", "request_id": "r-1"});
+        let mut tested = test_event_envelope(
+            id,
+            2,
+            TestEvent::Tested(Tested {
+                test_name: "golden".to_string(),
+            }),
+        );
+        tested.metadata = json!({});
+        repo.persist::<TestAggregate>(&[created, tested], None)
+            .await
+            .unwrap();
+
+        let fixture = dump::<TestAggregate, _>(&repo, id).await.unwrap();
+        let json = fixture.to_json().unwrap();
+        assert!(json.ends_with("}\n"));
+        assert!(json.find("\"request_id\"").unwrap() < json.find("\"user_id\"").unwrap());
+        assert_eq!(fixture, EventFixture::from_json(&json).unwrap());
+
+        let path = std::env::temp_dir()
+            .join(uuid::Uuid::new_v4().to_string())
+            .join("golden-1.json");
+        fixture.clone().without_metadata().write(&path).unwrap();
+        let golden = EventFixture::read(&path).unwrap();
+        assert_eq!(json!({}), golden.events[0].metadata);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+        let fresh = SqliteEventRepository::connect(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(2, replay(&fresh, &fixture).await.unwrap());
+        assert_eq!(
+            repo.get_events::<TestAggregate>(id).await.unwrap(),
+            fresh.get_events::<TestAggregate>(id).await.unwrap()
+        );
+    }
+}
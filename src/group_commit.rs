@@ -0,0 +1,327 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use cqrs_es::persist::SerializedEvent;
+use tokio::sync::oneshot;
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::{CommitReceipt, SqliteEventRepository};
+
+/// Coalesces concurrent commits into a single SQLite transaction, see
+/// `SqliteEventRepository::with_group_commit`.
+///
+/// Each committing caller waits until the transaction holding its events has been committed,
+/// which is at most `max_delay` after the first commit of the batch plus the time taken to
+/// write the batch. Few transactions, and so few syncs of the database file, then serve many
+/// commits, trading a little latency for a large gain in throughput, especially with
+/// `PRAGMA synchronous = FULL`.
+///
+/// ```
+/// use std::time::Duration;
+/// use rusqlite_es::GroupCommit;
+///
+/// let group_commit = GroupCommit::new(Duration::from_millis(5))
+///     .with_max_batch(100)
+///     .with_max_per_aggregate(4);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupCommit {
+    max_delay: Duration,
+    max_batch: usize,
+    max_per_aggregate: Option<usize>,
+}
+
+impl GroupCommit {
+    /// Creates a configuration collecting commits for at most `max_delay` before writing them,
+    /// in batches of at most 64 commits.
+    pub fn new(max_delay: Duration) -> Self {
+        Self {
+            max_delay,
+            max_batch: 64,
+            max_per_aggregate: None,
+        }
+    }
+
+    /// Writes a batch as soon as it holds `max_batch` commits.
+    pub fn with_max_batch(self, max_batch: usize) -> Self {
+        Self {
+            max_batch: max_batch.max(1),
+            ..self
+        }
+    }
+
+    /// Takes at most `max_per_aggregate` commits of the same aggregate instance into a batch,
+    /// so that a chatty aggregate instance cannot fill the batches and starve the others of the
+    /// writer. Commits left out are written with the following batches, ahead of the commits
+    /// received after them, so that all commits are still written in the order received unless
+    /// held back.
+    pub fn with_max_per_aggregate(self, max_per_aggregate: usize) -> Self {
+        Self {
+            max_per_aggregate: Some(max_per_aggregate.max(1)),
+            ..self
+        }
+    }
+}
+
+type CommitResult = Result<CommitReceipt, SqliteAggregateError>;
+
+struct PendingCommit {
+    aggregate_type: String,
+    events: Vec<SerializedEvent>,
+    committed: oneshot::Sender<CommitResult>,
+}
+
+impl PendingCommit {
+    fn aggregate(&self) -> (&str, Option<&str>) {
+        (
+            &self.aggregate_type,
+            self.events.first().map(|event| event.aggregate_id.as_str()),
+        )
+    }
+}
+
+// Shared by all clones of a repository, the writer thread is started by the first commit so
+// that it uses the repository's final configuration.
+pub(crate) struct GroupCommitter {
+    config: GroupCommit,
+    pending: OnceLock<Sender<PendingCommit>>,
+}
+
+impl GroupCommitter {
+    pub(crate) fn new(config: GroupCommit) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            pending: OnceLock::new(),
+        })
+    }
+
+    // Commits the events as part of the next batch, resolving once the batch is committed.
+    pub(crate) async fn commit<P: ConnectionProvider>(
+        &self,
+        repo: &SqliteEventRepository<P>,
+        aggregate_type: String,
+        events: &[SerializedEvent],
+    ) -> CommitResult {
+        let pending = self.pending.get_or_init(|| {
+            let (sender, receiver) = mpsc::channel();
+            // the writer's repository does not share the committer, so the writer stops once
+            // every clone of the configured repository has been dropped
+            let writer = repo.without_group_commit();
+            let config = self.config;
+            std::thread::spawn(move || write_batches(writer, config, receiver));
+            sender
+        });
+        let (committed, commit) = oneshot::channel();
+        pending
+            .send(PendingCommit {
+                aggregate_type,
+                events: events.to_vec(),
+                committed,
+            })
+            .map_err(|_| group_commit_stopped())?;
+        commit.await.map_err(|_| group_commit_stopped())?
+    }
+}
+
+fn write_batches<P: ConnectionProvider>(
+    repo: SqliteEventRepository<P>,
+    config: GroupCommit,
+    receiver: Receiver<PendingCommit>,
+) {
+    // the commits held back from earlier batches, in the order received
+    let mut backlog = VecDeque::new();
+    loop {
+        if backlog.is_empty() {
+            match receiver.recv() {
+                Ok(first) => backlog.push_back(first),
+                Err(_) => return,
+            }
+        }
+        let mut batch = Batch::new(config);
+        for pending in std::mem::take(&mut backlog) {
+            if let Some(pending) = batch.push(pending) {
+                backlog.push_back(pending);
+            }
+        }
+        let deadline = Instant::now() + config.max_delay;
+        while !batch.is_full() {
+            match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(pending) => {
+                    if let Some(pending) = batch.push(pending) {
+                        backlog.push_back(pending);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        write_batch(&repo, batch.commits);
+    }
+}
+
+// The commits of the next batch, taking at most `max_per_aggregate` of each aggregate instance.
+struct Batch {
+    config: GroupCommit,
+    commits: Vec<PendingCommit>,
+}
+
+impl Batch {
+    fn new(config: GroupCommit) -> Self {
+        Self {
+            config,
+            commits: Vec::new(),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.commits.len() >= self.config.max_batch
+    }
+
+    // Adds a commit to the batch, returning it if it is held back for a later batch.
+    fn push(&mut self, pending: PendingCommit) -> Option<PendingCommit> {
+        let held_back = self.is_full()
+            || self
+                .config
+                .max_per_aggregate
+                .is_some_and(|max_per_aggregate| {
+                    let aggregate = pending.aggregate();
+                    self.commits
+                        .iter()
+                        .filter(|commit| commit.aggregate() == aggregate)
+                        .count()
+                        >= max_per_aggregate
+                });
+        if held_back {
+            return Some(pending);
+        }
+        self.commits.push(pending);
+        None
+    }
+}
+
+// Writes each commit within a savepoint of a single transaction, so that a failed commit, e.g.
+// an optimistic lock conflict, is rolled back without affecting the rest of the batch.
+fn write_batch<P: ConnectionProvider>(repo: &SqliteEventRepository<P>, batch: Vec<PendingCommit>) {
+    let insert_event_query = repo.query_factory().insert_event();
+    let written = repo.write(|tx| {
+        let mut results = Vec::with_capacity(batch.len());
+        for pending in &batch {
+            tx.execute_batch("SAVEPOINT group_commit")?;
+            let result = repo.persist_aggregate_events(
+                &pending.aggregate_type,
+                insert_event_query,
+                tx,
+                &pending.events,
+            );
+            if result.is_err() {
+                tx.execute_batch("ROLLBACK TO group_commit")?;
+            }
+            tx.execute_batch("RELEASE group_commit")?;
+            results.push(result);
+        }
+        Ok(results)
+    });
+    match written {
+        Ok(results) => {
+            for (pending, result) in batch.into_iter().zip(results) {
+                let _ = pending.committed.send(result);
+            }
+        }
+        Err(err) => {
+            for pending in batch {
+                let _ = pending
+                    .committed
+                    .send(Err(SqliteAggregateError::UnknownError(
+                        format!("group commit failed: {err}").into(),
+                    )));
+            }
+        }
+    }
+}
+
+fn group_commit_stopped() -> SqliteAggregateError {
+    SqliteAggregateError::UnknownError("the group commit writer has stopped".into())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::time::Duration;
+
+    use cqrs_es::persist::{PersistedEventRepository, PersistenceError};
+    use futures::future::join_all;
+    use serde_json::json;
+
+    use crate::group_commit::{Batch, PendingCommit};
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, GroupCommit, SqliteEventRepository};
+
+    #[tokio::test]
+    async fn group_commit() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let repo = SqliteEventRepository::new(pool)
+            .with_group_commit(GroupCommit::new(Duration::from_millis(50)).with_max_batch(4));
+        let ids: Vec<String> = (0..3).map(|_| uuid::Uuid::new_v4().to_string()).collect();
+        let created = |id: &String| {
+            let mut created =
+                test_event_envelope(id, 1, TestEvent::Created(Created { id: id.clone() }));
+            created.metadata = json!({});
+            vec![created]
+        };
+        let commits = [
+            created(&ids[0]),
+            created(&ids[1]),
+            created(&ids[2]),
+            created(&ids[0]),
+        ];
+        let results = join_all(
+            commits
+                .iter()
+                .map(|events| repo.persist::<TestAggregate>(events, None)),
+        )
+        .await;
+
+        // the conflicting commit is rolled back without affecting the rest of its batch
+        assert!(results[..3].iter().all(Result::is_ok));
+        match &results[3] {
+            Err(PersistenceError::OptimisticLockError) => {}
+            result => panic!("expected an optimistic lock error, found {:?}", result),
+        }
+        for id in &ids {
+            assert_eq!(1, repo.get_events::<TestAggregate>(id).await.unwrap().len());
+        }
+    }
+
+    #[test]
+    fn max_per_aggregate() {
+        let pending = |id: &str| PendingCommit {
+            aggregate_type: "TestAggregate".to_string(),
+            events: vec![test_event_envelope(
+                id,
+                1,
+                TestEvent::Created(Created { id: id.into() }),
+            )],
+            committed: tokio::sync::oneshot::channel().0,
+        };
+        let config = GroupCommit::new(Duration::ZERO)
+            .with_max_batch(3)
+            .with_max_per_aggregate(2);
+        let mut batch = Batch::new(config);
+        assert!(batch.push(pending("chatty")).is_none());
+        assert!(batch.push(pending("chatty")).is_none());
+        // the chatty aggregate instance has taken its share of the batch
+        assert!(batch.push(pending("chatty")).is_some());
+        assert!(batch.push(pending("quiet")).is_none());
+        assert!(batch.is_full());
+        assert!(batch.push(pending("other")).is_some());
+    }
+}
@@ -0,0 +1,325 @@
+use cqrs_es::persist::SerializedEvent;
+use rusqlite::{Connection, OptionalExtension};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::mapping::deser_event;
+use crate::sql_query::SqlQueryFactory;
+use crate::SqliteEventRepository;
+
+/// How committed events are linked by their hashes, see
+/// `SqliteEventRepository::with_hash_chain`.
+///
+/// Each event committed with a hash chain records the SHA-256 hash of its contents along with
+/// the hash of the event preceding it in the chain, in its `event_hash` and `prev_hash` columns.
+/// Altering, deleting or inserting an event therefore breaks the chain from that event on,
+/// unless every later hash is recomputed as well, which `SqliteEventRepository::verify_hash_chain`
+/// detects given a head hash recorded elsewhere, e.g. in an auditor's log.
+///
+/// The hash of an event is the lowercase hex-encoded SHA-256 hash of the compact JSON array
+/// `[prev_hash, aggregate_type, aggregate_id, sequence, event_type, event_version, payload,
+/// metadata]`, where `prev_hash` is `null` for the first event of the chain and the keys of the
+/// payload and metadata objects are in sorted order. Payload and metadata are hashed as the
+/// repository reads them: encoded by the metadata codec, if any, and as references to their
+/// blobs if stored externally.
+///
+/// The `prev_hash` and `event_hash` columns of `db/init.sql` must exist, existing events tables
+/// are migrated with `db/migrate_hash_chain.sql`. Events committed before the hash chain was
+/// configured remain unhashed and precede the chain. Hash chains are not supported on a
+/// partitioned store. `compact_stream`, `rename_aggregate_type` and `recode_store` are refused
+/// with a hash chain, since they rewrite stored events and would break the chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashChain {
+    /// Events are not hashed.
+    #[default]
+    None,
+    /// The events of each aggregate instance form a chain in the order of their sequence.
+    /// Chains of different aggregate instances are independent, so this does not detect the
+    /// removal of an aggregate instance's latest events or of whole aggregate instances.
+    PerAggregate,
+    /// All events form a single chain in the order of their global position, each event linked
+    /// to the event committed before it, regardless of its aggregate instance.
+    Global,
+}
+
+/// The outcome of `SqliteEventRepository::verify_hash_chain`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HashChainReport {
+    /// The number of hashed events verified.
+    pub verified: usize,
+    /// The number of events committed before the hash chain was configured.
+    pub unhashed: usize,
+    /// The events at which the chain is broken.
+    pub violations: Vec<ChainViolation>,
+    /// The hash of the last hashed event of a `HashChain::Global` chain, to be recorded and
+    /// compared by the next verification, detecting the removal of the latest events.
+    pub head: Option<String>,
+}
+
+impl HashChainReport {
+    /// Whether the hash chain is intact.
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+/// An event at which the hash chain is broken, see `HashChainReport`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainViolation {
+    /// The type of the aggregate.
+    pub aggregate_type: String,
+    /// The id of the aggregate instance.
+    pub aggregate_id: String,
+    /// The sequence number of the event.
+    pub sequence: usize,
+    /// How the chain is broken.
+    pub kind: ChainViolationKind,
+}
+
+/// How the hash chain is broken at an event, see `ChainViolation`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainViolationKind {
+    /// The event's contents do not match its hash, the event was altered.
+    Altered,
+    /// The event is not linked to the event preceding it, an event was removed or inserted
+    /// before it.
+    Unlinked,
+    /// The event has no hash although events before it in the chain have, it was committed
+    /// bypassing the hash chain.
+    Unhashed,
+}
+
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    /// Recomputes the hash of every event and verifies that each event is linked to the event
+    /// preceding it in the chain configured with `with_hash_chain`, reporting the events at
+    /// which the chain is broken. Events are read one at a time, so stores of any size can be
+    /// verified.
+    ///
+    /// ```
+    /// use rusqlite_es::{SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// async fn audit(
+    ///     repo: &SqliteEventRepository,
+    ///     recorded_head: &str,
+    /// ) -> Result<bool, SqliteAggregateError> {
+    ///     let report = repo.verify_hash_chain().await?;
+    ///     Ok(report.is_ok() && report.head.as_deref() == Some(recorded_head))
+    /// }
+    /// ```
+    pub async fn verify_hash_chain(&self) -> Result<HashChainReport, SqliteAggregateError> {
+        let query_factory = self.query_factory();
+        if query_factory.hash_chain() == HashChain::None {
+            return Err(SqliteAggregateError::UnknownError(
+                "the repository is not configured with a hash chain".into(),
+            ));
+        }
+        let connection = self.pool().connection()?;
+        let mut statement = connection.prepare(query_factory.hash_chain_events())?;
+        let mut rows = statement.query([])?;
+        let mut report = HashChainReport::default();
+        let per_aggregate = query_factory.hash_chain() == HashChain::PerAggregate;
+        // the aggregate instance and hash of the preceding event, and whether the chain has
+        // started, i.e. whether an event preceding it was hashed
+        let mut previous: Option<(String, String, Option<String>)> = None;
+        let mut started = false;
+        while let Some(row) = rows.next()? {
+            let event = deser_event(row)?;
+            let prev_hash: Option<String> = row.get("prev_hash")?;
+            let event_hash: Option<String> = row.get("event_hash")?;
+            let preceding = match previous {
+                Some((aggregate_type, aggregate_id, _))
+                    if per_aggregate
+                        && (aggregate_type != event.aggregate_type
+                            || aggregate_id != event.aggregate_id) =>
+                {
+                    started = false;
+                    None
+                }
+                Some((_, _, hash)) => hash,
+                None => None,
+            };
+            let kind = match &event_hash {
+                None if started => Some(ChainViolationKind::Unhashed),
+                None => {
+                    report.unhashed += 1;
+                    None
+                }
+                Some(event_hash) => {
+                    started = true;
+                    report.verified += 1;
+                    if *event_hash != hash_event(prev_hash.as_deref(), &event)? {
+                        Some(ChainViolationKind::Altered)
+                    } else if prev_hash != preceding {
+                        Some(ChainViolationKind::Unlinked)
+                    } else {
+                        None
+                    }
+                }
+            };
+            if let Some(kind) = kind {
+                report.violations.push(ChainViolation {
+                    aggregate_type: event.aggregate_type.clone(),
+                    aggregate_id: event.aggregate_id.clone(),
+                    sequence: event.sequence,
+                    kind,
+                });
+            }
+            if !per_aggregate && event_hash.is_some() {
+                report.head = event_hash.clone();
+            }
+            previous = Some((event.aggregate_type, event.aggregate_id, event_hash));
+        }
+        Ok(report)
+    }
+}
+
+// Links the event just inserted at `position` to the event preceding it in the hash chain.
+pub(crate) fn chain_event(
+    tx: &Connection,
+    query_factory: &SqlQueryFactory,
+    position: i64,
+) -> Result<(), SqliteAggregateError> {
+    // the event is hashed as it is read, after any metadata added once it was inserted
+    let event = tx
+        .prepare_cached(query_factory.event_at())?
+        .query_row([position], |row| Ok(deser_event(row)))??;
+    let prev_hash: Option<Option<String>> = match query_factory.hash_chain() {
+        HashChain::None => return Ok(()),
+        HashChain::PerAggregate => tx
+            .prepare_cached(query_factory.previous_event_hash())?
+            .query_row(
+                (
+                    &event.aggregate_type,
+                    &event.aggregate_id,
+                    event.sequence as i64,
+                ),
+                |row| row.get(0),
+            )
+            .optional()?,
+        HashChain::Global => tx
+            .prepare_cached(query_factory.previous_global_hash())?
+            .query_row([position], |row| row.get(0))
+            .optional()?,
+    };
+    let prev_hash = prev_hash.flatten();
+    let event_hash = hash_event(prev_hash.as_deref(), &event)?;
+    tx.prepare_cached(query_factory.set_event_hash())?
+        .execute((prev_hash, event_hash, position))?;
+    Ok(())
+}
+
+// The hash of an event linked to the preceding one, see `HashChain`.
+fn hash_event(
+    prev_hash: Option<&str>,
+    event: &SerializedEvent,
+) -> Result<String, SqliteAggregateError> {
+    let contents = json!([
+        prev_hash,
+        event.aggregate_type,
+        event.aggregate_id,
+        event.sequence,
+        event.event_type,
+        event.event_version,
+        event.payload,
+        event.metadata,
+    ]);
+    Ok(hex(&Sha256::digest(serde_json::to_vec(&contents)?)))
+}
+
+// The lowercase hex encoding of a hash.
+pub(crate) fn hex(hash: &[u8]) -> String {
+    hash.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde_json::json;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{
+        default_sqlite_pool, ChainViolation, ChainViolationKind, HashChain, SqliteEventRepository,
+    };
+
+    #[tokio::test]
+    async fn hash_chain() {
+        let event = |id: &str, sequence| {
+            let mut event = match sequence {
+                1 => test_event_envelope(id, 1, TestEvent::Created(Created { id: id.into() })),
+                _ => test_event_envelope(
+                    id,
+                    sequence,
+                    TestEvent::Tested(Tested {
+                        test_name: format!("test {sequence}"),
+                    }),
+                ),
+            };
+            event.metadata = json!({"user_id": "alice"});
+            event
+        };
+        for hash_chain in [HashChain::PerAggregate, HashChain::Global] {
+            let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+            let repo = SqliteEventRepository::new(pool.clone())
+                .with_hash_chain(hash_chain)
+                .ready()
+                .await
+                .unwrap()
+                .into_inner();
+            // committed before the hash chain was configured
+            SqliteEventRepository::new(pool)
+                .persist::<TestAggregate>(&[event("legacy", 1)], None)
+                .await
+                .unwrap();
+            for id in ["chain-1", "chain-2"] {
+                let events = (1..=3)
+                    .map(|sequence| event(id, sequence))
+                    .collect::<Vec<_>>();
+                repo.persist::<TestAggregate>(&events, None).await.unwrap();
+            }
+            let report = repo.verify_hash_chain().await.unwrap();
+            assert!(report.is_ok());
+            assert_eq!(6, report.verified);
+            assert_eq!(1, report.unhashed);
+            assert_eq!(hash_chain == HashChain::Global, report.head.is_some());
+
+            let violation = |id: &str, kind| ChainViolation {
+                aggregate_type: "TestAggregate".to_string(),
+                aggregate_id: id.to_string(),
+                sequence: 2,
+                kind,
+            };
+            repo.with_connection(|conn| {
+                conn.execute_batch(
+                    "UPDATE events SET payload = json_set(payload, '$.Tested.test_name', 'forged')
+                       WHERE aggregate_id = 'chain-1' AND sequence = 2;
+                     DELETE FROM events WHERE aggregate_id = 'chain-2' AND sequence = 1;",
+                )
+            })
+            .unwrap();
+            let report = repo.verify_hash_chain().await.unwrap();
+            assert_eq!(
+                vec![
+                    violation("chain-1", ChainViolationKind::Altered),
+                    violation("chain-2", ChainViolationKind::Unlinked),
+                ],
+                report.violations
+            );
+
+            // events bypassing the hash chain break it as well
+            SqliteEventRepository::new(repo.pool().clone())
+                .persist::<TestAggregate>(&[event("chain-1", 4)], None)
+                .await
+                .unwrap();
+            let report = repo.verify_hash_chain().await.unwrap();
+            assert!(report.violations.contains(&ChainViolation {
+                sequence: 4,
+                ..violation("chain-1", ChainViolationKind::Unhashed)
+            }));
+            assert_eq!(hash_chain == HashChain::Global, report.head.is_some());
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::ops::{Bound, RangeBounds};
+
+use cqrs_es::persist::{EventUpcaster, PersistenceError, SerializedEvent};
+use cqrs_es::{Aggregate, EventEnvelope};
+
+use crate::access_policy::check_access;
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::query_timeout::with_timeout;
+use crate::slow_query::timed;
+use crate::{AccessKind, SqliteEventRepository};
+
+/// An event of an aggregate instance's history, see `SqliteEventRepository::aggregate_history`.
+pub struct HistoryEntry<A: Aggregate> {
+    /// The sequence number of the event for this aggregate instance.
+    pub sequence: usize,
+    /// When the event was committed, an RFC 3339 UTC timestamp, empty for events committed
+    /// before timestamps were recorded, see the `audit` module.
+    pub timestamp: String,
+    /// The type of the event as stored, after upcasting.
+    pub event_type: String,
+    /// The event.
+    pub event: A::Event,
+    /// The metadata of the event.
+    pub metadata: HashMap<String, String>,
+}
+
+// Implemented manually, deriving would needlessly require the aggregate to be `Clone`.
+impl<A: Aggregate> Clone for HistoryEntry<A> {
+    fn clone(&self) -> Self {
+        Self {
+            sequence: self.sequence,
+            timestamp: self.timestamp.clone(),
+            event_type: self.event_type.clone(),
+            event: self.event.clone(),
+            metadata: self.metadata.clone(),
+        }
+    }
+}
+
+impl<A: Aggregate> Debug for HistoryEntry<A> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HistoryEntry")
+            .field("sequence", &self.sequence)
+            .field("timestamp", &self.timestamp)
+            .field("event_type", &self.event_type)
+            .field("event", &self.event)
+            .field("metadata", &self.metadata)
+            .finish()
+    }
+}
+
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    /// Loads the events of the aggregate instance `aggregate_id` within a range of sequence
+    /// numbers as domain events along with their metadata and commit timestamps, e.g. to render
+    /// a page of its history in a user interface. The number of events, e.g. to compute the
+    /// number of pages, is provided by `count_events`.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// use cqrs_es::persist::PersistenceError;
+    /// use rusqlite_es::{HistoryEntry, SqliteEventRepository};
+    ///
+    /// async fn history_page(
+    ///     repo: &SqliteEventRepository,
+    ///     id: &str,
+    ///     page: usize,
+    /// ) -> Result<Vec<HistoryEntry<MyAggregate>>, PersistenceError> {
+    ///     let first = page * 50 + 1;
+    ///     repo.aggregate_history::<MyAggregate, _>(id, first..first + 50).await
+    /// }
+    /// ```
+    pub async fn aggregate_history<A, R>(
+        &self,
+        aggregate_id: &str,
+        range: R,
+    ) -> Result<Vec<HistoryEntry<A>>, PersistenceError>
+    where
+        A: Aggregate,
+        R: RangeBounds<usize>,
+    {
+        self.aggregate_history_with_upcasters(aggregate_id, range, &[])
+            .await
+    }
+
+    /// Loads a range of an aggregate instance's history as `aggregate_history` does, upcasting
+    /// events stored in an earlier version with the provided upcasters, as configured on the
+    /// `PersistedEventStore` the aggregate is loaded through.
+    pub async fn aggregate_history_with_upcasters<A, R>(
+        &self,
+        aggregate_id: &str,
+        range: R,
+        event_upcasters: &[Box<dyn EventUpcaster>],
+    ) -> Result<Vec<HistoryEntry<A>>, PersistenceError>
+    where
+        A: Aggregate,
+        R: RangeBounds<usize>,
+    {
+        let aggregate_type = A::aggregate_type();
+        check_access(
+            self.access_policy(),
+            AccessKind::Aggregate,
+            &aggregate_type,
+            aggregate_id,
+        )?;
+        let first = match range.start_bound() {
+            Bound::Included(first) => *first as i64,
+            Bound::Excluded(first) => *first as i64 + 1,
+            Bound::Unbounded => 0,
+        };
+        let last = match range.end_bound() {
+            Bound::Included(last) => *last as i64,
+            Bound::Excluded(last) => *last as i64 - 1,
+            Bound::Unbounded => i64::MAX,
+        };
+        let query = self.query_factory().aggregate_history();
+        let (first_param, last_param) = (first.to_string(), last.to_string());
+        let connection = self.pool().connection()?;
+        let events = timed(
+            self.slow_query_log(),
+            query,
+            &[&aggregate_type, aggregate_id, &first_param, &last_param],
+            || {
+                with_timeout(&connection, self.query_timeout(), || {
+                    let mut statement = connection.prepare_cached(query)?;
+                    let mut rows = statement.query((&aggregate_type, aggregate_id, first, last))?;
+                    let mut result: Vec<(SerializedEvent, String)> = Default::default();
+                    while let Some(row) = rows.next()? {
+                        result.push((self.read_event(row)?, row.get("created_at")?));
+                    }
+                    Ok::<_, SqliteAggregateError>(result)
+                })
+            },
+            Vec::len,
+        )?;
+        events
+            .into_iter()
+            .map(|(event, timestamp)| {
+                let event = upcast(event, event_upcasters);
+                let event_type = event.event_type.clone();
+                let envelope = EventEnvelope::<A>::try_from(event)?;
+                Ok(HistoryEntry {
+                    sequence: envelope.sequence,
+                    timestamp,
+                    event_type,
+                    event: envelope.payload,
+                    metadata: envelope.metadata,
+                })
+            })
+            .collect()
+    }
+}
+
+fn upcast(
+    mut event: SerializedEvent,
+    event_upcasters: &[Box<dyn EventUpcaster>],
+) -> SerializedEvent {
+    for upcaster in event_upcasters {
+        if upcaster.can_upcast(&event.event_type, &event.event_version) {
+            event = upcaster.upcast(event);
+        }
+    }
+    event
+}
+
+#[cfg(test)]
+mod test {
+    use cqrs_es::persist::{PersistedEventRepository, SemanticVersionEventUpcaster};
+    use serde_json::{json, Value};
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, SqliteEventRepository};
+
+    #[tokio::test]
+    async fn aggregate_history() {
+        let repo = SqliteEventRepository::connect(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .await
+            .unwrap()
+            .into_inner();
+        let id = "history-1";
+        let mut created = test_event_envelope(id, 1, TestEvent::Created(Created { id: id.into() }));
+        created.metadata = json!({"user_id": "alice"});
+        let mut events = vec![created];
+        for sequence in 2..=4 {
+            let mut tested = test_event_envelope(
+                id,
+                sequence,
+                TestEvent::Tested(Tested {
+                    test_name: format!("test {sequence}"),
+                }),
+            );
+            tested.metadata = json!({});
+            events.push(tested);
+        }
+        // stored by an earlier release, naming the test differently
+        events[3].event_version = "0.9.0".to_string();
+        events[3].payload = json!({"Tested": {"name": "legacy"}});
+        repo.persist::<TestAggregate>(&events, None).await.unwrap();
+
+        let history = repo
+            .aggregate_history::<TestAggregate, _>(id, ..3)
+            .await
+            .unwrap();
+        assert_eq!(2, history.len());
+        assert_eq!(
+            TestEvent::Created(Created { id: id.into() }),
+            history[0].event
+        );
+        assert_eq!(
+            Some("alice"),
+            history[0].metadata.get("user_id").map(String::as_str)
+        );
+        assert!(history[1].timestamp.ends_with('Z'));
+
+        // the legacy event is only decoded once upcast
+        assert!(repo
+            .aggregate_history::<TestAggregate, _>(id, 3..)
+            .await
+            .is_err());
+        let upcaster = SemanticVersionEventUpcaster::new(
+            "Tested",
+            "1.0.0",
+            Box::new(|mut payload: Value| {
+                let name = payload["Tested"]["name"].take();
+                json!({"Tested": {"test_name": name}})
+            }),
+        );
+        let history = repo
+            .aggregate_history_with_upcasters::<TestAggregate, _>(id, 3..=4, &[Box::new(upcaster)])
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![3, 4],
+            history
+                .iter()
+                .map(|entry| entry.sequence)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            TestEvent::Tested(Tested {
+                test_name: "legacy".to_string()
+            }),
+            history[1].event
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
+
+use crate::error::SqliteAggregateError;
+
+/// The name of a table (or view or index) interpolated into SQL by the repositories.
+///
+/// Names consisting of ASCII letters, digits and underscores, and not starting with a digit,
+/// are used as-is. Any other name is quoted, escaping embedded double quotes, so that it cannot
+/// alter the statement it is part of. Names that cannot be represented in SQLite, i.e. empty
+/// names or names containing a NUL character, are rejected.
+///
+/// The repositories accept table names as `&str` and escape them in the same way, a
+/// `TableName` validates a name up front, e.g. one read from configuration.
+///
+/// ```
+/// use rusqlite_es::TableName;
+///
+/// assert_eq!("events", TableName::new("events").unwrap().to_string());
+/// assert_eq!(
+///     "\"events\"\"; DROP TABLE events; --\"",
+///     TableName::new("events\"; DROP TABLE events; --").unwrap().to_string()
+/// );
+/// assert!(TableName::new("").is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TableName {
+    schema: Option<String>,
+    name: String,
+}
+
+impl TableName {
+    /// Validates a table name, see `TableName`.
+    pub fn new(name: &str) -> Result<Self, SqliteAggregateError> {
+        validate(name)?;
+        Ok(Self::unchecked(name))
+    }
+
+    /// Qualifies the table with the schema, i.e. the name of an attached database, it is
+    /// stored in. The schema name is validated and quoted in the same way as the table name.
+    ///
+    /// ```
+    /// use rusqlite_es::TableName;
+    ///
+    /// let table = TableName::new("snapshots").unwrap().in_schema("snapshot_db").unwrap();
+    /// assert_eq!("snapshot_db.snapshots", table.to_string());
+    /// ```
+    pub fn in_schema(self, schema: &str) -> Result<Self, SqliteAggregateError> {
+        validate(schema)?;
+        Ok(self.with_schema(Some(schema)))
+    }
+
+    /// The name as provided, i.e. unquoted and without its schema.
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+
+    /// The schema the table is stored in, `None` if the table is not qualified.
+    pub fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    // Wraps a name without validating it, a name that cannot be represented fails once the
+    // statement it is part of is prepared. As any name, it is quoted as needed when displayed.
+    pub(crate) fn unchecked(name: &str) -> Self {
+        Self {
+            schema: None,
+            name: name.to_string(),
+        }
+    }
+
+    // Qualifies or unqualifies the table without validating the schema, see `unchecked`.
+    pub(crate) fn with_schema(self, schema: Option<&str>) -> Self {
+        Self {
+            schema: schema.map(str::to_string),
+            ..self
+        }
+    }
+
+    // The name without its schema, e.g. for the table of a `CREATE INDEX` statement, which is
+    // always in the schema of the index.
+    pub(crate) fn unqualified(&self) -> Self {
+        Self::unchecked(&self.name)
+    }
+
+    // The name of a table or index derived from this one, e.g. `events_partitions`, in the same
+    // schema.
+    pub(crate) fn suffixed(&self, suffix: &str) -> Self {
+        Self {
+            schema: self.schema.clone(),
+            name: format!("{}{suffix}", self.name),
+        }
+    }
+}
+
+impl Display for TableName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(schema) = &self.schema {
+            write!(f, "{}.", quote_identifier(schema))?;
+        }
+        f.write_str(&quote_identifier(&self.name))
+    }
+}
+
+fn validate(name: &str) -> Result<(), SqliteAggregateError> {
+    if name.is_empty() || name.contains('\0') {
+        return Err(SqliteAggregateError::UnknownError(
+            format!("'{}' is not a valid table name", name.escape_default()).into(),
+        ));
+    }
+    Ok(())
+}
+
+// Quotes an identifier unless it consists of ASCII letters, digits and underscores only.
+pub(crate) fn quote_identifier(name: &str) -> Cow<'_, str> {
+    let plain = name
+        .chars()
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    match plain {
+        true => Cow::Borrowed(name),
+        false => Cow::Owned(format!("\"{}\"", name.replace('"', "\"\""))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use cqrs_es::persist::{PersistedEventRepository, ViewContext, ViewRepository};
+    use serde_json::json;
+
+    use crate::identifier::quote_identifier;
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, TestView, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, SqliteEventRepository, SqliteViewRepository, TableName};
+
+    #[test]
+    fn quoted_identifiers() {
+        assert_eq!("_events_2", quote_identifier("_events_2"));
+        assert_eq!("\"2_events\"", quote_identifier("2_events"));
+        assert_eq!("\"my events\"", quote_identifier("my events"));
+        assert_eq!("\"a\"\"b\"", quote_identifier("a\"b"));
+        assert!(TableName::new("a\0b").is_err());
+        assert_eq!(
+            "my events_partitions",
+            TableName::new("my events")
+                .unwrap()
+                .suffixed("_partitions")
+                .as_str()
+        );
+    }
+
+    #[tokio::test]
+    async fn unsafe_table_names() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let events = "events; DROP TABLE events; --";
+        let snapshots = "snap\"shots";
+        let repo = SqliteEventRepository::new(pool.clone())
+            .with_tables(events, snapshots)
+            .ready()
+            .await
+            .unwrap();
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = json!({});
+        let aggregate = serde_json::to_value(TestAggregate {
+            id: id.clone(),
+            description: String::new(),
+            tests: Vec::new(),
+        })
+        .unwrap();
+        repo.persist::<TestAggregate>(&[created], Some((id.clone(), aggregate, 1)))
+            .await
+            .unwrap();
+        assert_eq!(
+            1,
+            repo.get_events::<TestAggregate>(&id).await.unwrap().len()
+        );
+        assert!(repo
+            .get_snapshot::<TestAggregate>(&id)
+            .await
+            .unwrap()
+            .is_some());
+
+        let view_name = "view' OR '1'='1";
+        pool.get()
+            .unwrap()
+            .execute_batch(&format!(
+                "CREATE TABLE {} (view_id text PRIMARY KEY, version bigint NOT NULL, payload json NOT NULL);",
+                TableName::new(view_name).unwrap()
+            ))
+            .unwrap();
+        let view_repo =
+            SqliteViewRepository::<TestView, TestAggregate>::new(view_name, pool.clone());
+        view_repo
+            .update_view(TestView::default(), ViewContext::new(id.clone(), 0))
+            .await
+            .unwrap();
+        assert!(view_repo.load(&id).await.unwrap().is_some());
+
+        // the original events table is untouched
+        let count: i64 = pool
+            .get()
+            .unwrap()
+            .query_row("SELECT count(*) FROM events", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(1, count);
+    }
+}
@@ -0,0 +1,263 @@
+//! Adapters for migrating events exported from other event stores into an SQLite event store.
+//!
+//! Exports are first parsed into `SerializedEvent`s, which may be adjusted as needed (e.g., to
+//! rename event types or set event versions), and then written with `bulk_load`.
+//!
+//! Payloads are stored in the externally tagged form that serde uses for event enums, e.g.
+//! `{"AccountOpened": {"account_id": "acct-1"}}`. Exported payloads that are not already tagged
+//! with their event type are wrapped accordingly. Imported events are given the version
+//! `DEFAULT_EVENT_VERSION`.
+//!
+//! ```
+//! use rusqlite_es::import;
+//! use rusqlite_es::{SqliteAggregateError, SqliteEventRepository};
+//!
+//! async fn migrate(repo: &SqliteEventRepository, export: &str) -> Result<usize, SqliteAggregateError> {
+//!     let events = import::from_eventstoredb_export(export)?;
+//!     import::bulk_load(repo, &events).await
+//! }
+//! ```
+use std::collections::HashMap;
+
+use cqrs_es::persist::SerializedEvent;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::SqliteEventRepository;
+
+/// The event version given to imported events.
+pub const DEFAULT_EVENT_VERSION: &str = "1.0";
+
+/// The Kafka record header that, if present, holds the event type.
+pub const KAFKA_EVENT_TYPE_HEADER: &str = "event_type";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EventStoreDbEvent {
+    #[serde(alias = "eventStreamId")]
+    stream_id: String,
+    event_number: usize,
+    event_type: String,
+    #[serde(default)]
+    data: Value,
+    #[serde(default, alias = "metaData")]
+    metadata: Value,
+}
+
+#[derive(Deserialize)]
+struct KafkaRecord {
+    key: Option<String>,
+    payload: Value,
+    #[serde(default)]
+    headers: Vec<String>,
+}
+
+/// Parses an EventStoreDB JSON export, either a JSON array or newline-delimited JSON of events
+/// with `streamId` (or `eventStreamId`), `eventNumber`, `eventType`, `data` and `metadata` (or
+/// `metaData`) fields.
+///
+/// Streams are expected to follow the `{aggregate_type}-{aggregate_id}` naming convention, the
+/// stream name is split at its first `-`. EventStoreDB event numbers start at 0 and are shifted
+/// to sequence numbers starting at 1.
+pub fn from_eventstoredb_export(
+    export: &str,
+) -> Result<Vec<SerializedEvent>, SqliteAggregateError> {
+    parse_records::<EventStoreDbEvent>(export)?
+        .into_iter()
+        .map(|event| {
+            let (aggregate_type, aggregate_id) =
+                event.stream_id.split_once('-').ok_or_else(|| {
+                    SqliteAggregateError::DeserializationError(
+                        format!("stream '{}' has no aggregate type prefix", event.stream_id).into(),
+                    )
+                })?;
+            let metadata = match event.metadata {
+                Value::Null => Value::Object(Map::new()),
+                metadata => metadata,
+            };
+            Ok(SerializedEvent::new(
+                aggregate_id.to_string(),
+                event.event_number + 1,
+                aggregate_type.to_string(),
+                event.event_type.clone(),
+                DEFAULT_EVENT_VERSION.to_string(),
+                tagged_payload(&event.event_type, event.data),
+                metadata,
+            ))
+        })
+        .collect()
+}
+
+/// Parses a Kafka topic dump in kcat's JSON envelope format (`kcat -C -J`), one record per line,
+/// into events of `aggregate_type`. The record key is the aggregate id and the payload, either a
+/// JSON value or a string holding JSON, is the event.
+///
+/// The event type is taken from the `event_type` record header if present, otherwise from the
+/// payload's tag. Sequence numbers are assigned per aggregate instance, in the order of the
+/// records in the dump.
+pub fn from_kafka_dump(
+    dump: &str,
+    aggregate_type: &str,
+) -> Result<Vec<SerializedEvent>, SqliteAggregateError> {
+    let mut sequences: HashMap<String, usize> = HashMap::new();
+    parse_records::<KafkaRecord>(dump)?
+        .into_iter()
+        .map(|record| {
+            let aggregate_id = record.key.ok_or_else(|| {
+                SqliteAggregateError::DeserializationError("record has no key".into())
+            })?;
+            let payload = match record.payload {
+                Value::String(payload) => serde_json::from_str(&payload)?,
+                payload => payload,
+            };
+            let header = record
+                .headers
+                .chunks(2)
+                .find(|header| header[0] == KAFKA_EVENT_TYPE_HEADER)
+                .and_then(|header| header.get(1).cloned());
+            let event_type = match (header, &payload) {
+                (Some(event_type), _) => event_type,
+                (None, Value::Object(tagged)) if tagged.len() == 1 => {
+                    tagged.keys().next().unwrap().clone()
+                }
+                _ => {
+                    return Err(SqliteAggregateError::DeserializationError(
+                        format!("no event type found for a record of '{aggregate_id}'").into(),
+                    ))
+                }
+            };
+            let sequence = sequences.entry(aggregate_id.clone()).or_default();
+            *sequence += 1;
+            Ok(SerializedEvent::new(
+                aggregate_id,
+                *sequence,
+                aggregate_type.to_string(),
+                event_type.clone(),
+                DEFAULT_EVENT_VERSION.to_string(),
+                tagged_payload(&event_type, payload),
+                Value::Object(Map::new()),
+            ))
+        })
+        .collect()
+}
+
+/// Inserts the events within a single transaction, returning the number of events inserted.
+/// Events may belong to any number of aggregate types and are checked against the repository's
+/// payload limits and event schemas. If any event is rejected, e.g. because it conflicts with
+/// an existing event, none are inserted. To re-run an import, skipping the events already
+/// inserted, use `SqliteEventRepository::insert_events_if_absent` instead.
+///
+/// Views, including transactional views, are not updated and should be rebuilt after importing.
+pub async fn bulk_load<P: ConnectionProvider>(
+    repo: &SqliteEventRepository<P>,
+    events: &[SerializedEvent],
+) -> Result<usize, SqliteAggregateError> {
+    repo.insert_any_events(events).await?;
+    Ok(events.len())
+}
+
+fn parse_records<T: DeserializeOwned>(export: &str) -> Result<Vec<T>, SqliteAggregateError> {
+    if export.trim_start().starts_with('[') {
+        return Ok(serde_json::from_str(export)?);
+    }
+    export
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| Ok(serde_json::from_str(line)?))
+        .collect()
+}
+
+fn tagged_payload(event_type: &str, payload: Value) -> Value {
+    match &payload {
+        Value::Object(tagged) if tagged.len() == 1 && tagged.contains_key(event_type) => payload,
+        _ => {
+            let mut tagged = Map::new();
+            tagged.insert(event_type.to_string(), payload);
+            Value::Object(tagged)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde_json::json;
+
+    use crate::import::{bulk_load, from_eventstoredb_export, from_kafka_dump};
+    use crate::testing::tests::{TestAggregate, TEST_CONNECTION_STRING};
+    use crate::{default_sqlite_pool, SqliteEventRepository};
+
+    #[tokio::test]
+    async fn import_exports() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+        let repo = SqliteEventRepository::new(pool);
+
+        let export = r#"[
+            {"streamId": "TestAggregate-esdb-1", "eventNumber": 0, "eventType": "Created",
+             "data": {"id": "esdb-1"}, "metadata": {"user_id": "alice"}},
+            {"eventStreamId": "TestAggregate-esdb-1", "eventNumber": 1, "eventType": "Tested",
+             "data": {"Tested": {"test_name": "imported"}}}
+        ]"#;
+        let events = from_eventstoredb_export(export).unwrap();
+        assert_eq!(2, events.len());
+        assert_eq!("TestAggregate", events[0].aggregate_type);
+        assert_eq!("esdb-1", events[0].aggregate_id);
+        assert_eq!(1, events[0].sequence);
+        assert_eq!(json!({"Created": {"id": "esdb-1"}}), events[0].payload);
+        assert_eq!(json!({"user_id": "alice"}), events[0].metadata);
+        assert_eq!(2, events[1].sequence);
+        assert_eq!(
+            json!({"Tested": {"test_name": "imported"}}),
+            events[1].payload
+        );
+        assert_eq!(json!({}), events[1].metadata);
+        assert!(from_eventstoredb_export(
+            r#"{"streamId": "unprefixed", "eventNumber": 0, "eventType": "Created"}"#
+        )
+        .is_err());
+
+        let dump = r#"
+{"topic": "tests", "partition": 0, "offset": 0, "key": "kafka-1", "payload": "{\"Created\": {\"id\": \"kafka-1\"}}"}
+{"topic": "tests", "partition": 0, "offset": 1, "key": "kafka-2", "payload": {"Created": {"id": "kafka-2"}}}
+{"topic": "tests", "partition": 0, "offset": 2, "key": "kafka-1", "headers": ["event_type", "Tested"], "payload": {"test_name": "imported"}}
+"#;
+        let kafka_events = from_kafka_dump(dump, "TestAggregate").unwrap();
+        assert_eq!(
+            vec![
+                ("kafka-1", 1, "Created"),
+                ("kafka-2", 1, "Created"),
+                ("kafka-1", 2, "Tested")
+            ],
+            kafka_events
+                .iter()
+                .map(|event| (
+                    event.aggregate_id.as_str(),
+                    event.sequence,
+                    event.event_type.as_str()
+                ))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            json!({"Tested": {"test_name": "imported"}}),
+            kafka_events[2].payload
+        );
+
+        assert_eq!(2, bulk_load(&repo, &events).await.unwrap());
+        assert_eq!(3, bulk_load(&repo, &kafka_events).await.unwrap());
+        let loaded = repo.get_events::<TestAggregate>("kafka-1").await.unwrap();
+        assert_eq!(2, loaded.len());
+        // a conflicting import is rejected as a whole
+        assert!(bulk_load(&repo, &events).await.is_err());
+        let loaded = repo.get_events::<TestAggregate>("esdb-1").await.unwrap();
+        assert_eq!(events, loaded);
+    }
+}
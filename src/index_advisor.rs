@@ -0,0 +1,350 @@
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use rusqlite::Connection;
+use sha2::{Digest, Sha256};
+
+use crate::hash_chain::hex;
+use crate::identifier::{quote_identifier, TableName};
+
+/// Records the JSON-field predicates of queries as they are run and recommends indexes serving
+/// them, e.g. to evolve the schema of views and events as query patterns change. Intended for
+/// development and testing, the recorded predicates are kept in memory.
+///
+/// Once passed to `SqliteViewRepository::with_index_advisor`, the fields compared with
+/// `json_extract` in the `WHERE` clauses of the queries run through
+/// `SqliteViewRepository::with_connection` are recorded for the view table. Once passed to
+/// `SqliteEventRepository::with_index_advisor`, the metadata entries matched by
+/// `EventQuery::with_metadata` are recorded, unless metadata is stored with
+/// `MetadataStorage::Dictionary` or events are partitioned, which cannot be indexed this way.
+/// Queries run elsewhere are recorded by calling `record`.
+///
+/// ```
+/// # use cqrs_es::doc::MyAggregate;
+/// # use cqrs_es::persist::doc::MyView;
+/// use rusqlite_es::{EventQuery, IndexAdvisor, SqliteEventRepository, SqliteViewRepository};
+///
+/// async fn advise(
+///     repo: SqliteEventRepository,
+///     view_repo: SqliteViewRepository<MyView, MyAggregate>,
+/// ) {
+///     let advisor = IndexAdvisor::new();
+///     let repo = repo.with_index_advisor(advisor.clone());
+///     let view_repo = view_repo.with_index_advisor(advisor.clone());
+///     // e.g. while running the application's test suite
+///     let _ = repo.query_events(&EventQuery::new().with_metadata("user_id", "alice")).await;
+///     let _ = view_repo.with_connection(|conn| {
+///         conn.query_row(
+///             "SELECT count(*) FROM my_view_table WHERE json_extract(payload, '$.owner') = ?",
+///             ["alice"],
+///             |row| row.get::<_, i64>(0),
+///         )
+///     });
+///     for recommendation in advisor.recommendations() {
+///         println!("{};", recommendation.index);
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct IndexAdvisor {
+    predicates: Arc<Mutex<BTreeMap<JsonPredicate, usize>>>,
+}
+
+/// A predicate on a field of a JSON column, see `IndexAdvisor`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct JsonPredicate {
+    /// The queried table.
+    pub table: String,
+    /// The JSON column of the table, e.g. `payload` or `metadata`.
+    pub column: String,
+    /// The JSON path of the field, e.g. `$.owner`.
+    pub path: String,
+}
+
+/// The indexes recommended for a recorded `JsonPredicate`, see
+/// `IndexAdvisor::recommendations`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexRecommendation {
+    /// The predicate served by the index.
+    pub predicate: JsonPredicate,
+    /// The number of queries that used the predicate.
+    pub uses: usize,
+    /// An index on the `json_extract` expression, used by queries comparing the same
+    /// expression as recorded, e.g. `EventQuery::with_metadata`.
+    pub index: String,
+    /// A virtual generated column extracting the field and an index on it, for queries that
+    /// select or compare the field by column name, e.g. queries of views written by hand.
+    pub generated_column: Vec<String>,
+}
+
+impl IndexAdvisor {
+    /// Creates an advisor without any recorded predicates.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a query comparing the field at the JSON `path` of `column` in `table`, e.g. with
+    /// `json_extract(payload, '$.owner') = ?`.
+    pub fn record(&self, table: &str, column: &str, path: &str) {
+        let predicate = JsonPredicate {
+            table: table.to_string(),
+            column: column.to_string(),
+            path: path.to_string(),
+        };
+        *self
+            .predicates
+            .lock()
+            .unwrap()
+            .entry(predicate)
+            .or_default() += 1;
+    }
+
+    /// Returns the recommended indexes for the recorded predicates, most used first.
+    pub fn recommendations(&self) -> Vec<IndexRecommendation> {
+        let mut recommendations = self
+            .predicates
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(predicate, uses)| recommend(predicate, *uses))
+            .collect::<Vec<_>>();
+        recommendations.sort_by_key(|recommendation| Reverse(recommendation.uses));
+        recommendations
+    }
+
+    /// Forgets the recorded predicates, e.g. after applying the recommended indexes.
+    pub fn clear(&self) {
+        self.predicates.lock().unwrap().clear();
+    }
+
+    // Records the fields compared with `json_extract` in the `WHERE` clause of `sql`, whose
+    // column and literal path are taken as written, e.g. `json_extract(v.payload, '$.owner')`.
+    fn record_sql(&self, table: &str, sql: &str) {
+        let lowercase = sql.to_ascii_lowercase();
+        let Some(mut at) = lowercase.find("where") else {
+            return;
+        };
+        while let Some(found) = lowercase[at..].find("json_extract(") {
+            at += found + "json_extract(".len();
+            let Some((column, rest)) = sql[at..].split_once(',') else {
+                return;
+            };
+            let column = column.rsplit('.').next().unwrap_or_default().trim();
+            let column = column.trim_matches(|c| matches!(c, '"' | '`' | '[' | ']'));
+            if let Some(path) = string_literal(rest.trim_start()) {
+                let identifier =
+                    !column.is_empty() && column.chars().all(|c| c.is_alphanumeric() || c == '_');
+                if identifier && path.starts_with('$') {
+                    self.record(table, column, &path);
+                }
+            }
+        }
+    }
+}
+
+// The contents of the SQL string literal `sql` starts with, if any.
+fn string_literal(sql: &str) -> Option<String> {
+    let mut chars = sql.strip_prefix('\'')?.chars().peekable();
+    let mut literal = String::new();
+    while let Some(c) = chars.next() {
+        if c == '\'' {
+            if chars.peek() != Some(&'\'') {
+                return Some(literal);
+            }
+            chars.next();
+        }
+        literal.push(c);
+    }
+    None
+}
+
+thread_local! {
+    // The advisor and table recording the statements traced on this thread, see `recording`.
+    static RECORDING: RefCell<Option<(IndexAdvisor, String)>> = const { RefCell::new(None) };
+}
+
+// Runs `f` with `connection`, recording the predicates of the statements it runs against
+// `table` with the advisor.
+pub(crate) fn recording<T>(
+    index_advisor: &IndexAdvisor,
+    table: &str,
+    connection: &mut Connection,
+    f: impl FnOnce(&mut Connection) -> T,
+) -> T {
+    // statements are traced on the thread running them, which is the thread running `f`
+    struct Recording(Option<(IndexAdvisor, String)>);
+
+    impl Drop for Recording {
+        fn drop(&mut self) {
+            RECORDING.with(|recording| *recording.borrow_mut() = self.0.take());
+        }
+    }
+
+    let _previous = Recording(RECORDING.with(|recording| {
+        recording
+            .borrow_mut()
+            .replace((index_advisor.clone(), table.to_string()))
+    }));
+    connection.trace(Some(record_traced));
+    let result = f(connection);
+    connection.trace(None);
+    result
+}
+
+fn record_traced(sql: &str) {
+    RECORDING.with(|recording| {
+        if let Some((index_advisor, table)) = &*recording.borrow() {
+            index_advisor.record_sql(table, sql);
+        }
+    });
+}
+
+fn recommend(predicate: &JsonPredicate, uses: usize) -> IndexRecommendation {
+    let table = TableName::unchecked(&predicate.table);
+    let field = predicate
+        .path
+        .trim_start_matches('$')
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    // paths differing only in punctuation, e.g. `$.a.b` and `$.a_b`, share their sanitized
+    // field, the hash of the path keeps their names apart
+    let generated = format!(
+        "{}_{}_{}",
+        predicate.column,
+        field.trim_matches('_'),
+        &hex(&Sha256::digest(predicate.path.as_bytes()))[..8]
+    );
+    let expression = format!(
+        "json_extract({}, '{}')",
+        quote_identifier(&predicate.column),
+        predicate.path.replace('\'', "''")
+    );
+    let index_name = |suffix: &str| {
+        TableName::unchecked(&format!("{}_{generated}{suffix}", table.as_str()))
+            .with_schema(table.schema())
+    };
+    IndexRecommendation {
+        predicate: predicate.clone(),
+        uses,
+        index: format!(
+            "CREATE INDEX IF NOT EXISTS {} ON {table} ({expression})",
+            index_name("")
+        ),
+        generated_column: vec![
+            format!(
+                "ALTER TABLE {table} ADD COLUMN {} GENERATED ALWAYS AS ({expression}) VIRTUAL",
+                quote_identifier(&generated)
+            ),
+            format!(
+                "CREATE INDEX IF NOT EXISTS {} ON {table} ({})",
+                index_name("_column"),
+                quote_identifier(&generated)
+            ),
+        ],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use cqrs_es::persist::PersistedEventRepository;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, TestView, TEST_CONNECTION_STRING,
+    };
+    use crate::{
+        default_sqlite_pool, EventQuery, IndexAdvisor, JsonPredicate, SqliteEventRepository,
+        SqliteViewRepository,
+    };
+
+    #[tokio::test]
+    async fn index_advisor() {
+        let advisor = IndexAdvisor::new();
+        let repo = SqliteEventRepository::connect(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .await
+            .unwrap()
+            .into_inner()
+            .with_index_advisor(advisor.clone());
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = json!({"user_id": "alice"});
+        repo.persist::<TestAggregate>(&[created], None)
+            .await
+            .unwrap();
+
+        for _ in 0..2 {
+            let query = EventQuery::new().with_metadata("user_id", "alice");
+            assert_eq!(1, repo.query_events(&query).await.unwrap().len());
+        }
+        // the predicates of view queries are recorded, but not the fields they select
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = std::fs::read_to_string("db/init.sql").unwrap();
+        pool.get().unwrap().execute_batch(&contents).unwrap();
+        let view_repo = SqliteViewRepository::<TestView, TestAggregate>::new("test_view", pool)
+            .with_index_advisor(advisor.clone());
+        let owned: i64 = view_repo
+            .with_connection(|conn| {
+                conn.query_row(
+                    "SELECT count(*), json_extract(v.payload, '$.name') FROM test_view AS v WHERE json_extract(v.\"payload\", ?) = 'o''brien'",
+                    ["$.owner"],
+                    |row| row.get(0),
+                )
+            })
+            .unwrap();
+        assert_eq!(0, owned);
+        let recommendations = advisor.recommendations();
+        assert_eq!(2, recommendations.len());
+        assert_eq!(2, recommendations[0].uses);
+        assert_eq!(
+            "CREATE INDEX IF NOT EXISTS events_metadata_user_id_9a10cffa ON events (json_extract(metadata, '$.\"user_id\"'))",
+            recommendations[0].index
+        );
+        assert_eq!(
+            vec![
+                "ALTER TABLE test_view ADD COLUMN payload_owner_2aa0e797 GENERATED ALWAYS AS (json_extract(payload, '$.owner')) VIRTUAL",
+                "CREATE INDEX IF NOT EXISTS test_view_payload_owner_2aa0e797_column ON test_view (payload_owner_2aa0e797)",
+            ],
+            recommendations[1].generated_column
+        );
+
+        // the recommended index serves the recorded query
+        repo.with_connection(|conn| conn.execute_batch(&recommendations[0].index))
+            .unwrap();
+        let report = repo.ensure_indexes(&["user_id"], &[]).unwrap();
+        assert!(report.created.is_empty());
+        advisor.clear();
+        assert!(advisor.recommendations().is_empty());
+    }
+
+    #[test]
+    fn distinct_generated_columns() {
+        let advisor = IndexAdvisor::new();
+        advisor.record("test_view", "payload", "$.a.b");
+        advisor.record("test_view", "payload", "$.a_b");
+        let recommendations = advisor.recommendations();
+        assert_eq!(2, recommendations.len());
+        assert_ne!(recommendations[0].index, recommendations[1].index);
+        assert_ne!(
+            recommendations[0].generated_column,
+            recommendations[1].generated_column
+        );
+        let predicate = JsonPredicate {
+            table: "test_view".to_string(),
+            column: "payload".to_string(),
+            path: "$.a.b".to_string(),
+        };
+        assert_eq!(predicate, recommendations[0].predicate);
+        assert_eq!(
+            vec![
+                "ALTER TABLE test_view ADD COLUMN payload_a_b_fa48f7b8 GENERATED ALWAYS AS (json_extract(payload, '$.a.b')) VIRTUAL",
+                "CREATE INDEX IF NOT EXISTS test_view_payload_a_b_fa48f7b8_column ON test_view (payload_a_b_fa48f7b8)",
+            ],
+            recommendations[0].generated_column
+        );
+    }
+}
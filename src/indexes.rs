@@ -0,0 +1,346 @@
+use rusqlite::types::Value;
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::event_query::metadata_path;
+use crate::identifier::TableName;
+use crate::{
+    EventQuery, EventTableLayout, MetadataStorage, SqliteEventRepository, GLOBAL_POSITION_COLUMN,
+};
+
+/// The outcome of `SqliteEventRepository::ensure_indexes`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IndexReport {
+    /// The names of the indexes that were missing and have been created.
+    pub created: Vec<String>,
+    /// The queries that still scan their whole table, given the current schema.
+    pub unindexed: Vec<UnindexedQuery>,
+}
+
+/// A query that SQLite plans as a scan of its whole table, see `IndexReport`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnindexedQuery {
+    /// A description of the query, e.g. `"get_events"` or `"EventQuery by event type"`.
+    pub query: String,
+    /// The step of the query plan that scans the table, as reported by `EXPLAIN QUERY PLAN`.
+    pub plan: String,
+}
+
+struct RecommendedIndex {
+    name: String,
+    table: TableName,
+    // the indexed columns, or `None` for an expression index which is only found by name
+    columns: Option<Vec<&'static str>>,
+    definition: String,
+    unique: bool,
+}
+
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    /// Creates any of the recommended indexes that are missing and reports the queries that would
+    /// still scan a whole table.
+    ///
+    /// The recommended indexes are:
+    /// - the events primary key on aggregate type, aggregate id and sequence
+    /// - the events `event_type` and `created_at` columns, used by `EventQuery`
+    /// - the events `global_position` column of an `EventTableLayout::WithoutRowid` table
+    /// - the single snapshot per aggregate instance that snapshot updates rely on
+    /// - an index on each of the `metadata_keys`, promoting those entries of the event metadata
+    ///   to indexed columns for `EventQuery::with_metadata` (unless metadata is stored with
+    ///   `MetadataStorage::Dictionary`, which cannot be indexed)
+    /// - the `view_id` of each of the `view_tables`
+    ///
+    /// An index is only created if no equivalent index exists, whatever its name. The tables
+    /// themselves must already exist. Creating the unique snapshot index fails if the snapshots
+    /// table holds more than one snapshot of an aggregate instance, see
+    /// `db/migrate_snapshot_guard.sql`.
+    ///
+    /// ```
+    /// use rusqlite_es::{IndexReport, SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// fn prepare_schema(repo: &SqliteEventRepository) -> Result<IndexReport, SqliteAggregateError> {
+    ///     let report = repo.ensure_indexes(&["user_id"], &["account_query"])?;
+    ///     for unindexed in &report.unindexed {
+    ///         println!("{} is unindexed: {}", unindexed.query, unindexed.plan);
+    ///     }
+    ///     Ok(report)
+    /// }
+    /// ```
+    pub fn ensure_indexes(
+        &self,
+        metadata_keys: &[&str],
+        view_tables: &[&str],
+    ) -> Result<IndexReport, SqliteAggregateError> {
+        let query_factory = self.query_factory();
+        let event_table = &TableName::unchecked(query_factory.event_table());
+        let snapshot_table = query_factory.snapshot_table();
+        let mut recommended = vec![
+            RecommendedIndex::columns(
+                event_table,
+                "aggregate",
+                vec!["aggregate_type", "aggregate_id", "sequence"],
+                true,
+            ),
+            RecommendedIndex::columns(event_table, "event_type", vec!["event_type"], false),
+            RecommendedIndex::columns(event_table, "created_at", vec!["created_at"], false),
+            RecommendedIndex::columns(
+                snapshot_table,
+                "aggregate",
+                vec!["aggregate_type", "aggregate_id"],
+                true,
+            ),
+        ];
+        if query_factory.table_layout() == EventTableLayout::WithoutRowid {
+            recommended.push(RecommendedIndex::columns(
+                event_table,
+                GLOBAL_POSITION_COLUMN,
+                vec![GLOBAL_POSITION_COLUMN],
+                true,
+            ));
+        }
+        // an index cannot reconstitute interned metadata
+        let promoted_keys = match query_factory.metadata_storage() {
+            MetadataStorage::Inline => metadata_keys,
+            MetadataStorage::Dictionary => &[],
+        };
+        for key in promoted_keys {
+            recommended.push(RecommendedIndex {
+                name: format!("{}_metadata_{}", event_table.as_str(), identifier(key)),
+                table: event_table.clone(),
+                columns: None,
+                definition: format!("json_extract(metadata, {})", metadata_path(key)),
+                unique: false,
+            });
+        }
+        for view_table in view_tables {
+            recommended.push(RecommendedIndex::columns(
+                &TableName::unchecked(view_table),
+                "view_id",
+                vec!["view_id"],
+                true,
+            ));
+        }
+
+        let mut probes: Vec<(String, String, Vec<Value>)> = vec![
+            probe("get_events", query_factory.select_events()),
+            probe("get_last_events", &query_factory.get_last_events(0)),
+            probe("stream_all_events", query_factory.all_events()),
+            probe("get_snapshot", query_factory.select_snapshot()),
+            probe("update_snapshot", query_factory.update_snapshot()),
+            query_probe(
+                "EventQuery by aggregate id",
+                EventQuery::new().with_aggregate_id(""),
+                self,
+            ),
+            query_probe(
+                "EventQuery by event type",
+                EventQuery::new().with_event_types(&[""]),
+                self,
+            ),
+            query_probe(
+                "EventQuery by commit time",
+                EventQuery::new().with_created("".."z"),
+                self,
+            ),
+        ];
+        for key in metadata_keys {
+            probes.push(query_probe(
+                &format!("EventQuery by metadata '{key}'"),
+                EventQuery::new().with_metadata(key, ""),
+                self,
+            ));
+        }
+        for view_table in view_tables {
+            probes.push(probe(
+                &format!("view '{view_table}' by view_id"),
+                &format!(
+                    "SELECT payload FROM {} WHERE view_id = ?",
+                    TableName::unchecked(view_table)
+                ),
+            ));
+        }
+
+        let connection = self.pool().connection()?;
+        let mut report = IndexReport::default();
+        for index in recommended {
+            if !index.exists(&connection)? {
+                connection.execute_batch(&index.create())?;
+                report.created.push(index.name);
+            }
+        }
+        for (query, sql, params) in probes {
+            if let Some(plan) = table_scan(&connection, &sql, params)? {
+                report.unindexed.push(UnindexedQuery { query, plan });
+            }
+        }
+        Ok(report)
+    }
+}
+
+impl RecommendedIndex {
+    fn columns(table: &TableName, suffix: &str, columns: Vec<&'static str>, unique: bool) -> Self {
+        Self {
+            name: format!("{}_{suffix}", table.as_str()),
+            table: table.clone(),
+            definition: columns.join(", "),
+            columns: Some(columns),
+            unique,
+        }
+    }
+
+    fn exists(&self, connection: &Connection) -> Result<bool, rusqlite::Error> {
+        let schema = self.table.schema();
+        let master = TableName::unchecked("sqlite_master").with_schema(schema);
+        let named: Option<String> = connection
+            .query_row(
+                &format!("SELECT name FROM {master} WHERE type = 'index' AND name = ?"),
+                [&self.name],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if named.is_some() {
+            return Ok(true);
+        }
+        let Some(columns) = &self.columns else {
+            // an index on an expression is compared by the SQL creating it, ignoring whitespace
+            let compact = |sql: &str| sql.split_whitespace().collect::<String>();
+            let definition = format!("({})", compact(&self.definition));
+            let mut statement = connection.prepare(&format!(
+                "SELECT sql FROM {master} WHERE type = 'index' AND tbl_name = ? AND sql IS NOT NULL"
+            ))?;
+            let created = statement
+                .query_map([self.table.as_str()], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(created.iter().any(|sql| {
+                let sql = compact(sql);
+                sql.ends_with(&definition)
+                    && (!self.unique || sql.to_ascii_uppercase().starts_with("CREATEUNIQUE"))
+            }));
+        };
+        let mut statement =
+            connection.prepare("SELECT name, \"unique\" FROM pragma_index_list(?1, ?2)")?;
+        let indexes = statement
+            .query_map((self.table.as_str(), schema), |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut statement =
+            connection.prepare("SELECT name FROM pragma_index_info(?1, ?2) ORDER BY seqno")?;
+        for (name, unique) in indexes {
+            if self.unique && !unique {
+                continue;
+            }
+            let indexed = statement
+                .query_map((&name, schema), |row| row.get::<_, Option<String>>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            if indexed.len() == columns.len()
+                && indexed
+                    .iter()
+                    .zip(columns)
+                    .all(|(indexed, column)| indexed.as_deref() == Some(*column))
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn create(&self) -> String {
+        let unique = if self.unique { "UNIQUE " } else { "" };
+        format!(
+            "CREATE {unique}INDEX IF NOT EXISTS {} ON {} ({})",
+            TableName::unchecked(&self.name).with_schema(self.table.schema()),
+            self.table.unqualified(),
+            self.definition
+        )
+    }
+}
+
+fn probe(query: &str, sql: &str) -> (String, String, Vec<Value>) {
+    (query.to_string(), sql.to_string(), Vec::new())
+}
+
+fn query_probe<P: ConnectionProvider>(
+    query: &str,
+    event_query: EventQuery,
+    repo: &SqliteEventRepository<P>,
+) -> (String, String, Vec<Value>) {
+    let (sql, params) = event_query.to_sql(repo.query_factory());
+    (query.to_string(), sql, params)
+}
+
+// Returns the step of the query plan that scans a whole table, if any.
+fn table_scan(
+    connection: &Connection,
+    sql: &str,
+    params: Vec<Value>,
+) -> Result<Option<String>, rusqlite::Error> {
+    let mut statement = connection.prepare(&format!("EXPLAIN QUERY PLAN {sql}"))?;
+    // unbound parameters are planned the same as bound ones
+    let params = if params.is_empty() {
+        vec![Value::Null; statement.parameter_count()]
+    } else {
+        params
+    };
+    let mut rows = statement.query(rusqlite::params_from_iter(params))?;
+    // scanning the rows produced by a co-routine, e.g. over the partitions of the events table,
+    // does not scan a table
+    let mut coroutines = Vec::new();
+    while let Some(row) = rows.next()? {
+        let detail: String = row.get(3)?;
+        if let Some(coroutine) = detail.strip_prefix("CO-ROUTINE ") {
+            coroutines.push(coroutine.to_string());
+        } else if let Some(scanned) = detail.strip_prefix("SCAN ") {
+            if !scanned.contains(" INDEX ") && !coroutines.iter().any(|name| name == scanned) {
+                return Ok(Some(detail));
+            }
+        }
+    }
+    Ok(None)
+}
+
+// Restricts a metadata key to the characters allowed in an unquoted index name.
+fn identifier(key: &str) -> String {
+    key.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::testing::tests::TEST_CONNECTION_STRING;
+    use crate::{default_sqlite_pool, SqliteEventRepository};
+
+    #[test]
+    fn ensure_indexes() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        conn.execute_batch("DROP INDEX events_created_at").unwrap();
+        drop(conn);
+        let repo = SqliteEventRepository::new(pool);
+
+        let report = repo.ensure_indexes(&[], &["test_view"]).unwrap();
+        // the primary keys of init.sql are equivalent to the recommended unique indexes
+        assert_eq!(vec!["events_created_at".to_string()], report.created);
+        // the primary key leads with the aggregate type
+        assert_eq!(
+            vec!["EventQuery by aggregate id"],
+            report
+                .unindexed
+                .iter()
+                .map(|unindexed| unindexed.query.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert!(report.unindexed[0].plan.starts_with("SCAN events"));
+
+        let report = repo.ensure_indexes(&["user_id"], &[]).unwrap();
+        assert_eq!(vec!["events_metadata_user_id".to_string()], report.created);
+        assert_eq!(1, report.unindexed.len());
+        let report = repo.ensure_indexes(&["user_id"], &[]).unwrap();
+        assert!(report.created.is_empty());
+    }
+}
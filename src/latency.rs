@@ -0,0 +1,311 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// The number of sub-buckets per power of two of a histogram, the recorded latencies are
+/// reported within 1/8th, i.e. 12.5%, of their actual value.
+const SUB_BUCKET_BITS: u32 = 3;
+
+/// The number of buckets covering latencies of up to `u64::MAX` microseconds.
+const BUCKETS: usize = ((64 - SUB_BUCKET_BITS as usize) + 1) << SUB_BUCKET_BITS;
+
+/// In-process histograms of the latencies of commits and loads, for deployments without
+/// external monitoring, see `SqliteEventRepository::with_latency_metrics`.
+///
+/// A commit is a call of `persist`, whether or not it succeeds, including waiting for the
+/// write lock. A load is a read of an aggregate instance's events or snapshot, loading an
+/// aggregate through a `PersistedEventStore` with snapshots therefore records two loads.
+///
+/// Latencies are recorded in buckets with a resolution of 12.5% and cost a lock and no
+/// allocation each. By default the statistics cover all latencies recorded, `with_window`
+/// limits them to the recent ones. Clones share the same histograms.
+///
+/// ```
+/// use std::time::Duration;
+/// use r2d2::Pool;
+/// use r2d2_sqlite::SqliteConnectionManager;
+/// use rusqlite_es::{LatencyMetrics, SqliteEventRepository};
+///
+/// fn configure_repo(pool: Pool<SqliteConnectionManager>) -> SqliteEventRepository {
+///     let metrics = LatencyMetrics::new().with_window(Duration::from_secs(60));
+///     SqliteEventRepository::new(pool).with_latency_metrics(metrics)
+/// }
+///
+/// fn log_stats(repo: &SqliteEventRepository) {
+///     if let Some(stats) = repo.stats() {
+///         println!("commit p99: {:?}", stats.commits.p99);
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct LatencyMetrics {
+    histograms: Arc<Mutex<Histograms>>,
+    window: Option<Duration>,
+}
+
+/// Percentiles of the latencies recorded by `LatencyMetrics`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    /// The latencies of commits.
+    pub commits: LatencySummary,
+    /// The latencies of loads.
+    pub loads: LatencySummary,
+}
+
+/// Percentiles of one kind of latency, see `LatencyStats`. All durations are zero if no
+/// latency was recorded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencySummary {
+    /// The number of latencies recorded.
+    pub count: u64,
+    /// The median latency.
+    pub p50: Duration,
+    /// The 95th percentile latency.
+    pub p95: Duration,
+    /// The 99th percentile latency.
+    pub p99: Duration,
+    /// The highest latency.
+    pub max: Duration,
+}
+
+// The histograms of the current window and of the preceding one.
+#[derive(Debug)]
+struct Histograms {
+    started: Instant,
+    commits: [Histogram; 2],
+    loads: [Histogram; 2],
+}
+
+impl Default for Histograms {
+    fn default() -> Self {
+        Self::starting(Instant::now())
+    }
+}
+
+impl Histograms {
+    fn starting(started: Instant) -> Self {
+        Self {
+            started,
+            commits: Default::default(),
+            loads: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    counts: Box<[u64; BUCKETS]>,
+    count: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            counts: Box::new([0; BUCKETS]),
+            count: 0,
+            max: 0,
+        }
+    }
+}
+
+impl LatencyMetrics {
+    /// Creates histograms covering all latencies recorded.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Limits the statistics to the latencies recorded within the last one to two `window`s,
+    /// so that they reflect the current load rather than the whole lifetime of the process.
+    pub fn with_window(self, window: Duration) -> Self {
+        Self {
+            window: Some(window),
+            ..self
+        }
+    }
+
+    /// Returns the percentiles of the recorded latencies.
+    pub fn stats(&self) -> LatencyStats {
+        self.stats_at(Instant::now())
+    }
+
+    fn stats_at(&self, now: Instant) -> LatencyStats {
+        let histograms = self.histograms_at(now);
+        LatencyStats {
+            commits: summary(&histograms.commits),
+            loads: summary(&histograms.loads),
+        }
+    }
+
+    /// Discards all recorded latencies.
+    pub fn reset(&self) {
+        *self.histograms() = Default::default();
+    }
+
+    pub(crate) fn record_commit(&self, latency: Duration) {
+        self.histograms().commits[0].record(latency);
+    }
+
+    pub(crate) fn record_load(&self, latency: Duration) {
+        self.histograms().loads[0].record(latency);
+    }
+
+    fn histograms(&self) -> MutexGuard<'_, Histograms> {
+        self.histograms_at(Instant::now())
+    }
+
+    // The histograms, rotated if the current window has passed by `now`.
+    fn histograms_at(&self, now: Instant) -> MutexGuard<'_, Histograms> {
+        let mut histograms = self
+            .histograms
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(window) = self.window {
+            let elapsed = now.saturating_duration_since(histograms.started);
+            if elapsed >= window * 2 {
+                *histograms = Histograms::starting(now);
+            } else if elapsed >= window {
+                histograms.started += window;
+                histograms.commits.rotate_right(1);
+                histograms.loads.rotate_right(1);
+                histograms.commits[0] = Default::default();
+                histograms.loads[0] = Default::default();
+            }
+        }
+        histograms
+    }
+}
+
+impl Histogram {
+    fn record(&mut self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        self.counts[bucket(micros)] += 1;
+        self.count += 1;
+        self.max = self.max.max(micros);
+    }
+}
+
+// Merges the histograms of the current and the preceding window.
+fn summary(histograms: &[Histogram; 2]) -> LatencySummary {
+    let count = histograms[0].count + histograms[1].count;
+    let max = histograms[0].max.max(histograms[1].max);
+    let percentile = |percentile: u64| {
+        // the rank of the percentile, rounded up
+        let rank = (count * percentile).div_ceil(100).max(1);
+        let mut seen = 0;
+        for index in 0..BUCKETS {
+            seen += histograms[0].counts[index] + histograms[1].counts[index];
+            if seen >= rank {
+                return Duration::from_micros(upper_bound(index).min(max));
+            }
+        }
+        Duration::from_micros(max)
+    };
+    match count {
+        0 => Default::default(),
+        _ => LatencySummary {
+            count,
+            p50: percentile(50),
+            p95: percentile(95),
+            p99: percentile(99),
+            max: Duration::from_micros(max),
+        },
+    }
+}
+
+// The bucket of a latency: exact below 8µs, then 8 buckets per power of two.
+fn bucket(micros: u64) -> usize {
+    let magnitude = 63 - micros.max(1).leading_zeros();
+    if magnitude < SUB_BUCKET_BITS {
+        return micros as usize;
+    }
+    let shift = magnitude - SUB_BUCKET_BITS;
+    let sub_bucket = (micros >> shift) & ((1 << SUB_BUCKET_BITS) - 1);
+    (((shift + 1) << SUB_BUCKET_BITS) as u64 + sub_bucket) as usize
+}
+
+// The highest latency within a bucket.
+fn upper_bound(bucket: usize) -> u64 {
+    let sub_buckets = 1 << SUB_BUCKET_BITS;
+    if bucket < sub_buckets {
+        return bucket as u64;
+    }
+    let shift = (bucket / sub_buckets - 1) as u32;
+    let lower = ((sub_buckets + bucket % sub_buckets) as u64) << shift;
+    lower.saturating_add((1 << shift) - 1)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde_json::json;
+
+    use crate::latency::{bucket, upper_bound};
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, LatencyMetrics, SqliteEventRepository};
+
+    #[test]
+    fn buckets() {
+        for micros in [0, 1, 7, 8, 15, 16, 1_000, 123_456, u64::MAX] {
+            let upper = upper_bound(bucket(micros));
+            assert!(upper >= micros);
+            assert!(upper - micros <= micros / 8);
+        }
+        let metrics = LatencyMetrics::new();
+        for millis in 1..=100 {
+            metrics.record_commit(Duration::from_millis(millis));
+        }
+        let stats = metrics.stats().commits;
+        assert_eq!(100, stats.count);
+        assert!(stats.p50 >= Duration::from_millis(50) && stats.p50 <= Duration::from_millis(57));
+        assert!(stats.p99 >= Duration::from_millis(99) && stats.p99 <= Duration::from_millis(100));
+        assert_eq!(Duration::from_millis(100), stats.max);
+        metrics.reset();
+        assert_eq!(0, metrics.stats().commits.count);
+    }
+
+    #[tokio::test]
+    async fn latency_metrics() {
+        // a window no test run outlasts
+        let metrics = LatencyMetrics::new().with_window(Duration::from_secs(3600));
+        let repo = SqliteEventRepository::connect(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .await
+            .unwrap()
+            .into_inner()
+            .with_latency_metrics(metrics.clone());
+        let id = "latency-1";
+        let mut created = test_event_envelope(id, 1, TestEvent::Created(Created { id: id.into() }));
+        created.metadata = json!({});
+        repo.persist::<TestAggregate>(&[created], None)
+            .await
+            .unwrap();
+        repo.get_events::<TestAggregate>(id).await.unwrap();
+        repo.get_snapshot::<TestAggregate>(id).await.unwrap();
+        let stats = repo.stats().unwrap();
+        assert_eq!((1, 2), (stats.commits.count, stats.loads.count));
+        assert!(stats.loads.p50 <= stats.loads.max);
+    }
+
+    #[test]
+    fn window_rotation() {
+        let window = Duration::from_secs(3600);
+        let metrics = LatencyMetrics::new().with_window(window);
+        let started = metrics.histograms().started;
+        metrics.record_commit(Duration::from_millis(1));
+
+        // the preceding window is still covered
+        let stats = metrics.stats_at(started + window + window / 2);
+        assert_eq!(1, stats.commits.count);
+        // latencies recorded more than a window before the current one are discarded
+        let stats = metrics.stats_at(started + window * 2 + window / 2);
+        assert_eq!(0, stats.commits.count);
+
+        let metrics = LatencyMetrics::new().with_window(window);
+        let started = metrics.histograms().started;
+        metrics.record_commit(Duration::from_millis(1));
+        assert_eq!(0, metrics.stats_at(started + window * 2).commits.count);
+    }
+}
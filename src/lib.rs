@@ -1,4 +1,6 @@
-#![forbid(unsafe_code)]
+// loading extensions is inherently unsafe, it is confined to `PoolOptions::with_extension`
+#![cfg_attr(not(feature = "load_extension"), forbid(unsafe_code))]
+#![cfg_attr(feature = "load_extension", deny(unsafe_code))]
 #![deny(missing_docs)]
 #![deny(clippy::all)]
 // #![warn(clippy::pedantic)]
@@ -6,16 +8,149 @@
 //!
 //! > An SQLite implementation of the `EventStore` trait in [cqrs-es](https://crates.io/crates/cqrs-es).
 //!
+/// The version of rusqlite used by this crate, e.g. by code generated with `#[derive(SqliteView)]`.
+pub use rusqlite;
+#[cfg(feature = "derive")]
+pub use sqlite_es_derive::SqliteView;
+
+pub use crate::access_policy::*;
+pub use crate::aggregate_rename::*;
+pub use crate::aggregate_replay::*;
+#[cfg(feature = "analytics")]
+pub use crate::analytics::*;
+pub use crate::blob_store::*;
+pub use crate::capabilities::*;
+#[cfg(unix)]
+pub use crate::change_notifier::*;
+pub use crate::codec_shadow::*;
+pub use crate::command_queue::*;
+pub use crate::commit_receipt::*;
+pub use crate::conflicts::*;
+pub use crate::connection::*;
+pub use crate::connection_init::*;
 pub use crate::cqrs::*;
+pub use crate::cursor::*;
+pub use crate::encryption::*;
+pub use crate::error::*;
+pub use crate::event_aliases::*;
+pub use crate::event_bus::*;
+pub use crate::event_metadata::*;
+pub use crate::event_query::*;
 pub use crate::event_repository::*;
-pub use crate::event_repository::*;
+pub use crate::event_schema::*;
+pub use crate::event_stream::*;
+pub use crate::group_commit::*;
+pub use crate::hash_chain::*;
+pub use crate::history::*;
+pub use crate::identifier::*;
+pub use crate::index_advisor::*;
+pub use crate::indexes::*;
+pub use crate::latency::*;
+pub use crate::merkle::*;
+pub use crate::metadata_codec::*;
+pub use crate::metadata_dictionary::*;
+pub use crate::mirror::*;
+pub use crate::partitioning::*;
+pub use crate::payload_dedup::*;
+pub use crate::payload_limits::*;
+pub use crate::query_registry::*;
+pub use crate::ready_repository::*;
+pub use crate::recode::*;
+pub use crate::replay_progress::*;
+pub use crate::request_scope::*;
+pub use crate::shutdown::*;
+pub use crate::slow_query::*;
+pub use crate::snapshot_diff::*;
+pub use crate::snapshot_policy::*;
+pub use crate::snapshot_upcaster::*;
+pub use crate::sql_functions::*;
+pub use crate::store_config::*;
+pub use crate::stream_errors::*;
+pub use crate::table_layout::*;
+pub use crate::table_view::*;
+pub use crate::test_transaction::*;
 pub use crate::types::*;
+pub use crate::view_progress::*;
+pub use crate::view_rebuild::*;
 pub use crate::view_repository::*;
+pub use crate::warmup::*;
+#[cfg(feature = "web")]
+pub use crate::web::*;
+pub use crate::writer_lease::*;
 
+mod access_policy;
+pub mod admin;
+mod aggregate_rename;
+mod aggregate_replay;
+#[cfg(feature = "analytics")]
+mod analytics;
+pub mod audit;
+mod blob_store;
+mod capabilities;
+#[cfg(unix)]
+mod change_notifier;
+mod codec_shadow;
+mod command_queue;
+mod commit_receipt;
+mod conflicts;
+mod connection;
+mod connection_init;
 mod cqrs;
+mod cursor;
+mod encryption;
 mod error;
+mod event_aliases;
+mod event_bus;
+mod event_metadata;
+mod event_query;
 mod event_repository;
+mod event_schema;
+mod event_stream;
+pub mod fixtures;
+mod group_commit;
+mod hash_chain;
+mod history;
+mod identifier;
+pub mod import;
+mod index_advisor;
+mod indexes;
+mod latency;
+pub mod mapping;
+mod merkle;
+mod metadata_codec;
+mod metadata_dictionary;
+mod mirror;
+mod partitioning;
+mod payload_dedup;
+mod payload_limits;
+mod query_registry;
+mod query_timeout;
+mod ready_repository;
+mod recode;
+mod replay_progress;
+mod request_scope;
+mod shutdown;
+mod slow_query;
+mod snapshot_diff;
+mod snapshot_policy;
+mod snapshot_upcaster;
+mod sql_functions;
 pub(crate) mod sql_query;
+mod store_config;
+mod stream_errors;
+mod table_layout;
+mod table_view;
+mod test_transaction;
 mod testing;
 mod types;
+mod view_progress;
+mod view_rebuild;
 mod view_repository;
+mod warmup;
+#[cfg(feature = "web")]
+mod web;
+mod writer_lease;
+
+// Allows code generated by `sqlite-es-derive` to refer to this crate in its own tests.
+#[cfg(test)]
+extern crate self as rusqlite_es;
@@ -0,0 +1,185 @@
+//! Conversions from rows to the crate's types, for custom queries run through
+//! `SqliteEventRepository::with_connection` or `SqliteViewRepository::with_connection`.
+//!
+//! Rows are read by column name, so a query must select the columns returned by
+//! `SqliteEventRepository::event_columns` or `SqliteEventRepository::snapshot_columns`. These
+//! account for the repository's `JsonEncoding` and `MetadataStorage`, e.g. JSONB payloads are
+//! read back as JSON text and interned metadata is reconstituted.
+//!
+//! ```
+//! use cqrs_es::persist::SerializedEvent;
+//! use rusqlite_es::mapping;
+//! use rusqlite_es::{SqliteAggregateError, SqliteEventRepository};
+//!
+//! fn customer_events(repo: &SqliteEventRepository) -> Result<Vec<SerializedEvent>, SqliteAggregateError> {
+//!     let sql = format!(
+//!         "SELECT {} FROM {} WHERE aggregate_type = 'Customer'",
+//!         repo.event_columns(),
+//!         repo.event_source()
+//!     );
+//!     repo.with_connection(|conn| {
+//!         conn.prepare(&sql)?
+//!             .query_map([], mapping::event_from_row)?
+//!             .collect()
+//!     })
+//! }
+//! ```
+use cqrs_es::persist::{SerializedEvent, SerializedSnapshot};
+use rusqlite::Row;
+use serde_json::Value;
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::SqliteEventRepository;
+
+/// The columns of an event, in the order selected by `SqliteEventRepository::event_columns`.
+pub const EVENT_COLUMNS: [&str; 7] = [
+    "aggregate_type",
+    "aggregate_id",
+    "sequence",
+    "event_type",
+    "event_version",
+    "payload",
+    "metadata",
+];
+
+/// The columns of a snapshot, in the order selected by
+/// `SqliteEventRepository::snapshot_columns`.
+pub const SNAPSHOT_COLUMNS: [&str; 6] = [
+    "aggregate_type",
+    "aggregate_id",
+    "last_sequence",
+    "current_snapshot",
+    "aggregate_version",
+    "payload",
+];
+
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    /// The column list selecting an event as expected by `mapping::event_from_row`.
+    pub fn event_columns(&self) -> &str {
+        self.query_factory().event_columns()
+    }
+
+    /// The (quoted) table or view that events are read from, this is a view over all partitions
+    /// if the repository is partitioned.
+    pub fn event_source(&self) -> String {
+        self.query_factory().event_source().to_string()
+    }
+
+    /// The column list selecting a snapshot as expected by `mapping::snapshot_from_row`.
+    pub fn snapshot_columns(&self) -> &str {
+        self.query_factory().snapshot_columns()
+    }
+}
+
+/// Reads an event from a row holding the `EVENT_COLUMNS`.
+pub fn event_from_row(row: &Row<'_>) -> Result<SerializedEvent, rusqlite::Error> {
+    let aggregate_type: String = row.get("aggregate_type")?;
+    let aggregate_id: String = row.get("aggregate_id")?;
+    let sequence = {
+        let s: i64 = row.get("sequence")?;
+        s as usize
+    };
+    let event_type: String = row.get("event_type")?;
+    let event_version: String = row.get("event_version")?;
+    let payload: Value = row.get("payload")?;
+    let metadata: Value = row.get("metadata")?;
+    Ok(SerializedEvent::new(
+        aggregate_id,
+        sequence,
+        aggregate_type,
+        event_type,
+        event_version,
+        payload,
+        metadata,
+    ))
+}
+
+/// Reads a snapshot from a row holding the `SNAPSHOT_COLUMNS`. The snapshot is returned as
+/// stored, i.e. it is not upcast to the current aggregate version.
+pub fn snapshot_from_row(row: &Row<'_>) -> Result<SerializedSnapshot, rusqlite::Error> {
+    versioned_snapshot(row).map(|(snapshot, _)| snapshot)
+}
+
+pub(crate) fn deser_event(row: &Row<'_>) -> Result<SerializedEvent, SqliteAggregateError> {
+    event_from_row(row).map_err(SqliteAggregateError::from)
+}
+
+// Reads a snapshot along with the aggregate version it was taken at.
+pub(crate) fn versioned_snapshot(
+    row: &Row<'_>,
+) -> Result<(SerializedSnapshot, String), rusqlite::Error> {
+    let aggregate_id = row.get("aggregate_id")?;
+    let s: i64 = row.get("last_sequence")?;
+    let current_sequence = s as usize;
+    let s: i64 = row.get("current_snapshot")?;
+    let current_snapshot = s as usize;
+    let aggregate_version = row.get("aggregate_version")?;
+    let aggregate: Value = row.get("payload")?;
+    Ok((
+        SerializedSnapshot {
+            aggregate_id,
+            aggregate,
+            current_sequence,
+            current_snapshot,
+        },
+        aggregate_version,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde_json::json;
+
+    use crate::mapping::{event_from_row, snapshot_from_row};
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, MetadataStorage, SqliteEventRepository};
+
+    #[tokio::test]
+    async fn custom_queries() {
+        let repo = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .with_metadata_storage(MetadataStorage::Dictionary)
+            .ready()
+            .await
+            .unwrap();
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = json!({"user": "alice"});
+        let aggregate = serde_json::to_value(TestAggregate {
+            id: id.clone(),
+            description: String::new(),
+            tests: Vec::new(),
+        })
+        .unwrap();
+        repo.persist::<TestAggregate>(&[created.clone()], Some((id.clone(), aggregate.clone(), 1)))
+            .await
+            .unwrap();
+
+        let events_sql = format!(
+            "SELECT {} FROM {} WHERE aggregate_id = ?",
+            repo.event_columns(),
+            repo.event_source()
+        );
+        let snapshot_sql = format!(
+            "SELECT {} FROM snapshots WHERE aggregate_id = ?",
+            repo.snapshot_columns()
+        );
+        let (events, snapshot) = repo
+            .with_connection(|conn| {
+                let events = conn
+                    .prepare(&events_sql)?
+                    .query_map([&id], event_from_row)?
+                    .collect::<Result<Vec<_>, _>>()?;
+                let snapshot = conn.query_row(&snapshot_sql, [&id], snapshot_from_row)?;
+                Ok((events, snapshot))
+            })
+            .unwrap();
+        assert_eq!(vec![created], events);
+        assert_eq!(aggregate, snapshot.aggregate);
+        assert_eq!(1, snapshot.current_sequence);
+    }
+}
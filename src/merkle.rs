@@ -0,0 +1,356 @@
+use rusqlite::{OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::hash_chain::hex;
+use crate::{HashChain, SqliteEventRepository};
+
+/// The Merkle root of the hashes of the events committed within a range of global positions,
+/// see `SqliteEventRepository::compute_merkle_root`.
+///
+/// A root is small enough to be published or submitted to a timestamping or notarization
+/// service, e.g. as its JSON serialization. Any later change to an event it covers, including
+/// its removal, then no longer matches the notarized root, and the inclusion of an event is
+/// proven to third parties with an `InclusionProof` without disclosing any other event.
+///
+/// The leaves of the tree are the hashes of the events recorded by the `HashChain`, in the order
+/// of their global position, events committed before the hash chain was configured are not
+/// covered. A leaf is the SHA-256 hash of the byte `0x00` followed by the hex-encoded event hash,
+/// an inner node the SHA-256 hash of the byte `0x01` followed by the hashes of its children. A
+/// level with an odd number of nodes carries its last node up to the next level unchanged.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleRoot {
+    /// The global position of the first event covered.
+    pub first_position: i64,
+    /// The global position of the last event covered.
+    pub last_position: i64,
+    /// The number of events covered.
+    pub leaves: usize,
+    /// The hex-encoded root hash.
+    pub root: String,
+    /// When the root was computed, an RFC 3339 UTC timestamp.
+    pub computed_at: String,
+}
+
+/// Proves that an event is covered by a `MerkleRoot`, see
+/// `SqliteEventRepository::prove_inclusion`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InclusionProof {
+    /// The hash of the event, as recorded by the `HashChain`.
+    pub event_hash: String,
+    /// The global position of the event.
+    pub position: i64,
+    /// The hashes of the siblings of the nodes from the event's leaf up to the root.
+    pub path: Vec<ProofStep>,
+    /// The root covering the event.
+    pub root: MerkleRoot,
+}
+
+/// A sibling on the path of an `InclusionProof`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofStep {
+    /// The hex-encoded hash of the sibling.
+    pub hash: String,
+    /// Whether the sibling is the left child of their parent.
+    pub left: bool,
+}
+
+impl InclusionProof {
+    /// Whether the path leads from the event hash to the root, i.e. whether the event is
+    /// covered by the root. Whether the root itself is authentic is established by comparing it
+    /// with the notarized one.
+    pub fn verify(&self) -> bool {
+        let mut node = leaf(&self.event_hash);
+        for step in &self.path {
+            let Some(sibling) = unhex(&step.hash) else {
+                return false;
+            };
+            node = if step.left {
+                parent(&sibling, &node)
+            } else {
+                parent(&node, &sibling)
+            };
+        }
+        hex(&node) == self.root.root
+    }
+}
+
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    /// Computes and records the Merkle root of the events committed since the last recorded
+    /// root, returning `None` if no event has been committed since. Called periodically, e.g.
+    /// hourly, the recorded roots cover the whole event log in consecutive ranges of positions.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use rusqlite_es::{MerkleRoot, SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// async fn notarize_hourly(
+    ///     repo: SqliteEventRepository,
+    ///     notarize: impl Fn(&MerkleRoot),
+    /// ) -> Result<(), SqliteAggregateError> {
+    ///     let mut interval = tokio::time::interval(Duration::from_secs(3600));
+    ///     loop {
+    ///         interval.tick().await;
+    ///         if let Some(root) = repo.compute_merkle_root().await? {
+    ///             notarize(&root);
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub async fn compute_merkle_root(&self) -> Result<Option<MerkleRoot>, SqliteAggregateError> {
+        let query_factory = self.query_factory();
+        if query_factory.hash_chain() == HashChain::None {
+            return Err(SqliteAggregateError::UnknownError(
+                "the repository is not configured with a hash chain".into(),
+            ));
+        }
+        self.write_async(|tx| {
+            let last_position: i64 =
+                tx.query_row(query_factory.last_merkle_position(), [], |row| row.get(0))?;
+            let leaves = tx
+                .prepare_cached(query_factory.merkle_leaves())?
+                .query_map((last_position, i64::MAX), |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            let (Some((first_position, _)), Some((last_position, _))) =
+                (leaves.first(), leaves.last())
+            else {
+                return Ok(None);
+            };
+            let levels = tree(leaves.iter().map(|(_, hash)| hash.as_str()).collect());
+            let root = hex(&levels[levels.len() - 1][0]);
+            let root = tx
+                .prepare_cached(query_factory.insert_merkle_root())?
+                .query_row(
+                    (first_position, last_position, leaves.len() as i64, root),
+                    merkle_root,
+                )?;
+            Ok(Some(root))
+        })
+        .await
+    }
+
+    /// Returns the recorded Merkle roots covering events after the global position
+    /// `after_position`, oldest first, e.g. to export the roots computed since the last export.
+    pub async fn merkle_roots(
+        &self,
+        after_position: i64,
+    ) -> Result<Vec<MerkleRoot>, SqliteAggregateError> {
+        let connection = self.pool().connection()?;
+        let roots = connection
+            .prepare(self.query_factory().merkle_roots())?
+            .query_map([after_position], merkle_root)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(roots)
+    }
+
+    /// Returns the proof that the event `sequence` of the aggregate instance `aggregate_id` is
+    /// covered by a recorded Merkle root, or `None` if the event does not exist, is not hashed
+    /// or is not covered by a root yet.
+    pub async fn prove_inclusion(
+        &self,
+        aggregate_type: &str,
+        aggregate_id: &str,
+        sequence: usize,
+    ) -> Result<Option<InclusionProof>, SqliteAggregateError> {
+        let query_factory = self.query_factory();
+        let connection = self.pool().connection()?;
+        let event: Option<(i64, Option<String>)> = connection
+            .query_row(
+                query_factory.hashed_event(),
+                (aggregate_type, aggregate_id, sequence as i64),
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let Some((position, Some(event_hash))) = event else {
+            return Ok(None);
+        };
+        let Some(root) = connection
+            .query_row(query_factory.merkle_root_at(), [position], merkle_root)
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        let leaves = connection
+            .prepare(query_factory.merkle_leaves())?
+            .query_map((root.first_position - 1, root.last_position), |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        let Some(mut index) = leaves.iter().position(|(at, _)| *at == position) else {
+            return Ok(None);
+        };
+        let mut path = Vec::new();
+        let levels = tree(leaves.iter().map(|(_, hash)| hash.as_str()).collect());
+        for level in &levels[..levels.len() - 1] {
+            let sibling = index ^ 1;
+            if sibling < level.len() {
+                path.push(ProofStep {
+                    hash: hex(&level[sibling]),
+                    left: sibling < index,
+                });
+            }
+            index /= 2;
+        }
+        Ok(Some(InclusionProof {
+            event_hash,
+            position,
+            path,
+            root,
+        }))
+    }
+}
+
+type Hash = [u8; 32];
+
+// The levels of the Merkle tree over event hashes, from the leaves up to the root.
+fn tree(event_hashes: Vec<&str>) -> Vec<Vec<Hash>> {
+    let mut levels = vec![event_hashes.into_iter().map(leaf).collect::<Vec<_>>()];
+    while levels[levels.len() - 1].len() > 1 {
+        let level = levels[levels.len() - 1]
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => parent(left, right),
+                [node] => *node,
+                _ => unreachable!(),
+            })
+            .collect();
+        levels.push(level);
+    }
+    levels
+}
+
+fn leaf(event_hash: &str) -> Hash {
+    Sha256::new()
+        .chain_update([0])
+        .chain_update(event_hash)
+        .finalize()
+        .into()
+}
+
+fn parent(left: &Hash, right: &Hash) -> Hash {
+    Sha256::new()
+        .chain_update([1])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .into()
+}
+
+fn unhex(hash: &str) -> Option<Hash> {
+    let mut bytes = [0; 32];
+    if hash.len() != 64 || !hash.is_ascii() {
+        return None;
+    }
+    for (byte, digits) in bytes.iter_mut().zip(hash.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn merkle_root(row: &Row<'_>) -> Result<MerkleRoot, rusqlite::Error> {
+    let leaves: i64 = row.get("leaves")?;
+    Ok(MerkleRoot {
+        first_position: row.get("first_position")?,
+        last_position: row.get("last_position")?,
+        leaves: leaves as usize,
+        root: row.get("root")?,
+        computed_at: row.get("computed_at")?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde_json::json;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, HashChain, SqliteEventRepository};
+
+    #[tokio::test]
+    async fn merkle_roots() {
+        let repo = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .with_hash_chain(HashChain::Global)
+            .ready()
+            .await
+            .unwrap()
+            .into_inner();
+        let commit = |id: &'static str, sequences: std::ops::RangeInclusive<usize>| {
+            let repo = repo.clone();
+            async move {
+                let events = sequences
+                    .map(|sequence| {
+                        let mut event = match sequence {
+                            1 => test_event_envelope(
+                                id,
+                                1,
+                                TestEvent::Created(Created { id: id.into() }),
+                            ),
+                            _ => test_event_envelope(
+                                id,
+                                sequence,
+                                TestEvent::Tested(Tested {
+                                    test_name: format!("test {sequence}"),
+                                }),
+                            ),
+                        };
+                        event.metadata = json!({});
+                        event
+                    })
+                    .collect::<Vec<_>>();
+                repo.persist::<TestAggregate>(&events, None).await.unwrap();
+            }
+        };
+        assert_eq!(None, repo.compute_merkle_root().await.unwrap());
+        commit("merkle-1", 1..=5).await;
+        let first = repo.compute_merkle_root().await.unwrap().unwrap();
+        assert_eq!(
+            (1, 5, 5),
+            (first.first_position, first.last_position, first.leaves)
+        );
+        assert_eq!(None, repo.compute_merkle_root().await.unwrap());
+        commit("merkle-2", 1..=2).await;
+        let second = repo.compute_merkle_root().await.unwrap().unwrap();
+        assert_eq!((6, 7), (second.first_position, second.last_position));
+        assert_eq!(vec![second.clone()], repo.merkle_roots(5).await.unwrap());
+
+        for sequence in 1..=5 {
+            let proof = repo
+                .prove_inclusion("TestAggregate", "merkle-1", sequence)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(first, proof.root);
+            assert!(proof.verify());
+        }
+        let proof = repo
+            .prove_inclusion("TestAggregate", "merkle-2", 2)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(proof.verify());
+        assert_eq!(1, proof.path.len());
+
+        // an altered event no longer matches the notarized root
+        let mut forged = repo
+            .prove_inclusion("TestAggregate", "merkle-1", 3)
+            .await
+            .unwrap()
+            .unwrap();
+        forged.event_hash = proof.event_hash;
+        assert!(!forged.verify());
+        commit("merkle-3", 1..=1).await;
+        assert_eq!(
+            None,
+            repo.prove_inclusion("TestAggregate", "merkle-3", 1)
+                .await
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use cqrs_es::persist::{PersistenceError, SerializedEvent};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::error::SqliteAggregateError;
+
+/// Converts event metadata between the form passed through the framework and the form stored,
+/// see `SqliteEventRepository::with_metadata_codec`.
+///
+/// The framework carries metadata as a `HashMap<String, String>`, serialized as a JSON object of
+/// strings. A codec stores it in another shape, e.g. `TypedMetadata` stores numbers and nested
+/// data as such, so that it can be queried with `EventQuery::with_metadata` or SQLite's JSON
+/// functions.
+pub trait MetadataCodec: Send + Sync {
+    /// Converts the metadata of an event being committed into the stored form.
+    fn encode(&self, metadata: &Value) -> Result<Value, SqliteAggregateError>;
+
+    /// Converts stored metadata back into the form expected by the framework.
+    fn decode(&self, metadata: Value) -> Result<Value, SqliteAggregateError>;
+
+    /// Names the codec in the recorded `StoreConfig` and in the progress of `recode_store`. The
+    /// name must be stable across builds, e.g. a string literal, since a store recorded with a
+    /// different name fails to start.
+    fn name(&self) -> &str;
+}
+
+/// Stores event metadata as the serialization of a metadata struct `M`.
+///
+/// Within the framework's metadata map, each entry is a field of `M` holding the JSON encoding
+/// of the field's value, see `to_map` and `from_map`. Entries that are not valid JSON are taken
+/// as strings, so that untyped metadata such as `{"user_id": "alice"}` can still be committed.
+/// Metadata that does not deserialize into `M` is rejected when committed, entries that are not
+/// fields of `M` are not stored.
+///
+/// ```
+/// use std::collections::HashMap;
+///
+/// use serde::{Deserialize, Serialize};
+/// use rusqlite_es::{SqliteAggregateError, TypedMetadata};
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct RequestMetadata {
+///     user_id: String,
+///     attempt: u32,
+/// }
+///
+/// fn command_metadata() -> Result<HashMap<String, String>, SqliteAggregateError> {
+///     TypedMetadata::to_map(&RequestMetadata {
+///         user_id: "alice".to_string(),
+///         attempt: 2,
+///     })
+/// }
+/// ```
+pub struct TypedMetadata<M> {
+    name: String,
+    metadata: PhantomData<fn() -> M>,
+}
+
+impl<M> TypedMetadata<M>
+where
+    M: Serialize + DeserializeOwned,
+{
+    /// Creates a codec storing metadata as the serialization of `M`, named `TypedMetadata`.
+    pub fn new() -> Self {
+        Self {
+            name: "TypedMetadata".to_string(),
+            metadata: PhantomData,
+        }
+    }
+
+    /// Names the codec, see `MetadataCodec::name`, e.g. to tell apart the versions of a
+    /// metadata struct when recoding a store from one into the other.
+    ///
+    /// ```
+    /// use serde::{Deserialize, Serialize};
+    /// use rusqlite_es::TypedMetadata;
+    ///
+    /// #[derive(Serialize, Deserialize)]
+    /// struct RequestMetadataV2 {
+    ///     user_id: String,
+    ///     tenant: Option<String>,
+    /// }
+    ///
+    /// let codec = TypedMetadata::<RequestMetadataV2>::new().with_name("request_metadata_v2");
+    /// # drop(codec);
+    /// ```
+    pub fn with_name(self, name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            ..self
+        }
+    }
+
+    /// Converts a metadata struct into the framework's metadata map, e.g. for
+    /// `CqrsFramework::execute_with_metadata`.
+    pub fn to_map(metadata: &M) -> Result<HashMap<String, String>, SqliteAggregateError> {
+        match serde_json::to_value(metadata)? {
+            Value::Object(fields) => fields
+                .into_iter()
+                .map(|(key, value)| Ok((key, serde_json::to_string(&value)?)))
+                .collect(),
+            _ => Err(SqliteAggregateError::UnknownError(
+                "metadata must serialize to a JSON object".into(),
+            )),
+        }
+    }
+
+    /// Converts the framework's metadata map, e.g. of an `EventEnvelope` loaded by a query, back
+    /// into a metadata struct.
+    pub fn from_map(metadata: &HashMap<String, String>) -> Result<M, SqliteAggregateError> {
+        let fields = metadata
+            .iter()
+            .map(|(key, value)| (key.clone(), parse_entry(value)))
+            .collect::<Map<_, _>>();
+        Ok(serde_json::from_value(Value::Object(fields))?)
+    }
+}
+
+impl<M> Default for TypedMetadata<M>
+where
+    M: Serialize + DeserializeOwned,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M> MetadataCodec for TypedMetadata<M>
+where
+    M: Serialize + DeserializeOwned,
+{
+    fn encode(&self, metadata: &Value) -> Result<Value, SqliteAggregateError> {
+        let Value::Object(fields) = metadata else {
+            return Ok(serde_json::to_value(serde_json::from_value::<M>(
+                metadata.clone(),
+            )?)?);
+        };
+        let parsed = fields
+            .iter()
+            .map(|(key, value)| match value {
+                Value::String(value) => (key.clone(), parse_entry(value)),
+                value => (key.clone(), value.clone()),
+            })
+            .collect::<Map<_, _>>();
+        // metadata already in its stored form, e.g. when importing exported events, is taken
+        // as is
+        let typed = serde_json::from_value::<M>(Value::Object(parsed))
+            .or_else(|_| serde_json::from_value::<M>(metadata.clone()))?;
+        Ok(serde_json::to_value(typed)?)
+    }
+
+    fn decode(&self, metadata: Value) -> Result<Value, SqliteAggregateError> {
+        match metadata {
+            Value::Object(fields) => Ok(Value::Object(
+                fields
+                    .into_iter()
+                    .map(|(key, value)| Ok((key, Value::String(serde_json::to_string(&value)?))))
+                    .collect::<Result<_, SqliteAggregateError>>()?,
+            )),
+            metadata => Ok(metadata),
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+// Decodes the metadata of the events handed to `push` by a stream.
+pub(crate) fn decoded<F>(
+    metadata_codec: Option<Arc<dyn MetadataCodec>>,
+    mut push: F,
+) -> impl FnMut(Result<SerializedEvent, PersistenceError>) -> bool + Send + 'static
+where
+    F: FnMut(Result<SerializedEvent, PersistenceError>) -> bool + Send + 'static,
+{
+    move |event_result| match (&metadata_codec, event_result) {
+        (Some(metadata_codec), Ok(mut event)) => {
+            match metadata_codec.decode(std::mem::take(&mut event.metadata)) {
+                Ok(metadata) => {
+                    event.metadata = metadata;
+                    push(Ok(event))
+                }
+                Err(err) => push(Err(err.into())),
+            }
+        }
+        (_, event_result) => push(event_result),
+    }
+}
+
+// Parses an entry of the framework's metadata map, taking entries that are not valid JSON as
+// strings.
+fn parse_entry(value: &str) -> Value {
+    serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, SqliteEventRepository, TypedMetadata};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct RequestMetadata {
+        user_id: String,
+        attempt: u32,
+        tags: Vec<String>,
+    }
+
+    #[tokio::test]
+    async fn typed_metadata() {
+        let repo = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .with_metadata_codec(Box::new(TypedMetadata::<RequestMetadata>::new()))
+            .ready()
+            .await
+            .unwrap();
+        // recorded under its stable name rather than its type name
+        assert_eq!(
+            Some("TypedMetadata".to_string()),
+            repo.store_config().metadata_codec
+        );
+        let metadata = RequestMetadata {
+            user_id: "123".to_string(),
+            attempt: 2,
+            tags: vec!["retry".to_string()],
+        };
+        let map = TypedMetadata::to_map(&metadata).unwrap();
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = serde_json::to_value(&map).unwrap();
+        repo.persist::<TestAggregate>(&[created.clone()], None)
+            .await
+            .unwrap();
+
+        let stored: serde_json::Value = repo
+            .with_connection(|conn| {
+                conn.query_row(
+                    "SELECT metadata FROM events WHERE aggregate_id = ?",
+                    [&id],
+                    |row| row.get(0),
+                )
+            })
+            .unwrap();
+        assert_eq!(
+            json!({"user_id": "123", "attempt": 2, "tags": ["retry"]}),
+            stored
+        );
+        let events = repo.get_events::<TestAggregate>(&id).await.unwrap();
+        let loaded: HashMap<String, String> =
+            serde_json::from_value(events[0].metadata.clone()).unwrap();
+        assert_eq!(metadata, TypedMetadata::from_map(&loaded).unwrap());
+
+        let mut rejected =
+            test_event_envelope(&id, 2, TestEvent::Created(Created { id: id.clone() }));
+        rejected.metadata = json!({"user_id": "alice"});
+        assert!(repo
+            .persist::<TestAggregate>(&[rejected], None)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,240 @@
+use rusqlite::{Connection, OptionalExtension};
+use serde_json::Value;
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::sql_query::SqlQueryFactory;
+use crate::{Partitioning, SqliteEventRepository, GLOBAL_POSITION_METADATA_KEY};
+
+/// The table holding interned metadata entries, see `MetadataStorage::Dictionary`.
+pub const METADATA_DICTIONARY_TABLE: &str = "metadata_dictionary";
+
+/// How event metadata is stored, see `SqliteEventRepository::with_metadata_storage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetadataStorage {
+    /// The metadata of each event is stored in full with the event.
+    #[default]
+    Inline,
+    /// Each metadata entry, a key with its value, is stored once in the `metadata_dictionary`
+    /// table and events only hold the ids of their entries. This significantly shrinks databases
+    /// whose events repeat the same metadata, e.g. user agents or application versions, at the
+    /// cost of a lookup per entry when events are read.
+    ///
+    /// Metadata that is not a JSON object is stored inline. Filtering on interned metadata, e.g.
+    /// with `EventQuery::with_metadata`, cannot use an index. The `GLOBAL_POSITION_METADATA_KEY`
+    /// entry is not interned per event, it is always read as the global position of the event.
+    Dictionary,
+}
+
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    /// Interns the metadata of events stored inline, e.g. before `MetadataStorage::Dictionary`
+    /// was configured, returning the number of events compacted.
+    ///
+    /// Events are compacted in batches of `batch_size`, each within its own transaction, so this
+    /// may run in the background alongside commits. Events are read identically before and
+    /// after compaction. Nothing is compacted unless the repository is configured with
+    /// `MetadataStorage::Dictionary`.
+    ///
+    /// ```
+    /// use rusqlite_es::{MetadataStorage, SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// async fn compact(repo: SqliteEventRepository) -> Result<usize, SqliteAggregateError> {
+    ///     let repo = repo.with_metadata_storage(MetadataStorage::Dictionary);
+    ///     repo.compact_metadata(1000).await
+    /// }
+    /// ```
+    pub async fn compact_metadata(&self, batch_size: usize) -> Result<usize, SqliteAggregateError> {
+        let query_factory = self.query_factory();
+        if query_factory.metadata_storage() != MetadataStorage::Dictionary {
+            return Ok(0);
+        }
+        if query_factory.partitioning() != Partitioning::None {
+            return Err(SqliteAggregateError::UnknownError(
+                "metadata cannot be compacted in a partitioned store".into(),
+            ));
+        }
+        let mut compacted = 0;
+        loop {
+            let batch = self
+                .write_async(|tx| {
+                    let mut statement =
+                        tx.prepare_cached(query_factory.select_inline_metadata())?;
+                    let rows = statement
+                        .query_map([batch_size as i64], |row| {
+                            Ok((row.get::<_, i64>(0)?, row.get::<_, Value>(1)?))
+                        })?
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let mut update = tx.prepare_cached(query_factory.update_metadata())?;
+                    for (rowid, metadata) in &rows {
+                        let interned = intern_metadata(tx, query_factory, metadata)?;
+                        update.execute((interned, rowid))?;
+                    }
+                    Ok(rows.len())
+                })
+                .await?;
+            compacted += batch;
+            if batch < batch_size {
+                return Ok(compacted);
+            }
+        }
+    }
+}
+
+// Replaces each entry of a metadata object with the id of the entry in the dictionary, adding
+// entries as needed. Metadata that is not an object is returned unchanged.
+pub(crate) fn intern_metadata(
+    connection: &Connection,
+    query_factory: &SqlQueryFactory,
+    metadata: &Value,
+) -> Result<Value, rusqlite::Error> {
+    let Value::Object(entries) = metadata else {
+        return Ok(metadata.clone());
+    };
+    let mut ids = Vec::with_capacity(entries.len());
+    for (key, value) in entries {
+        let id = if key == GLOBAL_POSITION_METADATA_KEY {
+            intern_global_position(connection, query_factory)?
+        } else {
+            intern_entry(connection, query_factory, key, value)?
+        };
+        ids.push(Value::from(id));
+    }
+    Ok(Value::Array(ids))
+}
+
+// Interns the placeholder of the global position recorded in the metadata of an event. A
+// position differs for every event and would add an entry per event to the dictionary, the
+// placeholder is a single entry read as the position of the event holding it.
+pub(crate) fn intern_global_position(
+    connection: &Connection,
+    query_factory: &SqlQueryFactory,
+) -> Result<i64, rusqlite::Error> {
+    intern_entry(
+        connection,
+        query_factory,
+        GLOBAL_POSITION_METADATA_KEY,
+        &Value::Null,
+    )
+}
+
+pub(crate) fn intern_entry(
+    connection: &Connection,
+    query_factory: &SqlQueryFactory,
+    key: &str,
+    value: &Value,
+) -> Result<i64, rusqlite::Error> {
+    let value = value.to_string();
+    let mut select = connection.prepare_cached(query_factory.select_metadata_id())?;
+    if let Some(id) = select
+        .query_row((key, &value), |row| row.get(0))
+        .optional()?
+    {
+        return Ok(id);
+    }
+    connection
+        .prepare_cached(query_factory.intern_metadata())?
+        .execute((key, &value))?;
+    select.query_row((key, &value), |row| row.get(0))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde_json::json;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{
+        default_sqlite_pool, CommitReceipts, EventQuery, MetadataStorage, SqliteEventRepository,
+    };
+
+    #[tokio::test]
+    async fn metadata_dictionary() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let inline_repo = SqliteEventRepository::new(pool);
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = json!({"user_agent": "curl/8.0", "user_id": "alice"});
+        inline_repo
+            .insert_events::<TestAggregate>(&[created.clone()])
+            .await
+            .unwrap();
+
+        let repo = inline_repo
+            .clone()
+            .with_metadata_storage(MetadataStorage::Dictionary)
+            .with_commit_receipts(CommitReceipts::RecordedWithMetadata);
+        let mut tested = test_event_envelope(
+            &id,
+            2,
+            TestEvent::Tested(Tested {
+                test_name: "interned".to_string(),
+            }),
+        );
+        tested.metadata = json!({"user_agent": "curl/8.0", "user_id": "bob"});
+        let receipt = repo
+            .insert_events::<TestAggregate>(&[tested.clone()])
+            .await
+            .unwrap();
+
+        let count_entries = || {
+            repo.with_connection(|conn| {
+                conn.query_row("SELECT count(*) FROM metadata_dictionary", [], |row| {
+                    row.get::<_, i64>(0)
+                })
+            })
+            .unwrap()
+        };
+        // the user agent, user id and global position
+        assert_eq!(3, count_entries());
+        let events = repo.get_events::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(created.metadata, events[0].metadata);
+        assert_eq!(
+            json!({
+                "user_agent": "curl/8.0",
+                "user_id": "bob",
+                "global_position": receipt.global_position.to_string()
+            }),
+            events[1].metadata
+        );
+        let bobs = repo
+            .query_events(&EventQuery::new().with_metadata("user_id", "bob"))
+            .await
+            .unwrap();
+        assert_eq!(1, bobs.len());
+
+        // the global position of every event is read from a single placeholder entry
+        let mut retested = tested.clone();
+        retested.sequence = 3;
+        let retested_receipt = repo
+            .insert_events::<TestAggregate>(&[retested])
+            .await
+            .unwrap();
+        assert_eq!(3, count_entries());
+        let events = repo.get_events::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(
+            json!(retested_receipt.global_position.to_string()),
+            events[2].metadata["global_position"]
+        );
+        assert_ne!(
+            events[1].metadata["global_position"],
+            events[2].metadata["global_position"]
+        );
+
+        assert_eq!(0, inline_repo.compact_metadata(10).await.unwrap());
+        // the seed event of init.sql is compacted along with the first event
+        assert_eq!(2, repo.compact_metadata(1).await.unwrap());
+        assert_eq!(4, count_entries());
+        assert_eq!(events, repo.get_events::<TestAggregate>(&id).await.unwrap());
+        assert_eq!(0, repo.compact_metadata(10).await.unwrap());
+    }
+}
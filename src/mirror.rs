@@ -0,0 +1,464 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use cqrs_es::persist::{
+    PersistedEventRepository, PersistenceError, ReplayStream, SerializedEvent, SerializedSnapshot,
+};
+use cqrs_es::Aggregate;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use serde_json::Value;
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::SqliteEventRepository;
+
+type MirrorErrorHandler = dyn Fn(&PersistenceError) + Send + Sync;
+
+/// How a `MirroredEventRepository` treats a commit that fails on the secondary database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MirrorMode {
+    /// The commit succeeds once it is committed to the primary, failures on the secondary are
+    /// only reported to the configured error handler.
+    #[default]
+    BestEffort,
+    /// The commit fails if it could not be committed to the secondary. The events remain
+    /// committed to the primary, so the databases have diverged until the secondary is repaired.
+    /// The failure is returned as `PersistenceError::UnknownError`, never as a conflict, as a
+    /// retried command would be committed to the primary again.
+    Strict,
+}
+
+/// An aggregate instance whose stream differs between the primary and secondary databases,
+/// see `MirroredEventRepository::divergence`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// The type of the aggregate.
+    pub aggregate_type: String,
+    /// The id of the aggregate instance.
+    pub aggregate_id: String,
+    /// The last sequence number in the primary, `None` if the stream is missing.
+    pub primary_sequence: Option<usize>,
+    /// The last sequence number in the secondary, `None` if the stream is missing.
+    pub secondary_sequence: Option<usize>,
+    /// The number of events in the primary, `None` if the stream is missing.
+    pub primary_events: Option<usize>,
+    /// The number of events in the secondary, `None` if the stream is missing.
+    pub secondary_events: Option<usize>,
+}
+
+// The last sequence number and the number of events of a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct StreamHead {
+    sequence: usize,
+    events: usize,
+}
+
+/// An event repository that writes each commit to a primary and a secondary database, e.g. to
+/// keep a warm standby file on a second disk. Events and snapshots are read from the primary.
+///
+/// A commit is applied to the primary first and then to the secondary, see `MirrorMode` for how
+/// failures on the secondary are handled. Snapshots are written to the secondary as they are on
+/// the primary, replacing any snapshot the secondary holds, so a snapshot the secondary missed
+/// does not fail later commits. The two commits are not atomic, use `divergence` to
+/// detect streams that differ between the databases, e.g. after a crash.
+///
+/// ```
+/// use r2d2::Pool;
+/// use r2d2_sqlite::SqliteConnectionManager;
+/// use rusqlite_es::{MirrorMode, MirroredEventRepository, SqliteEventRepository};
+///
+/// fn configure_repo(
+///     primary: Pool<SqliteConnectionManager>,
+///     secondary: Pool<SqliteConnectionManager>,
+/// ) -> MirroredEventRepository {
+///     MirroredEventRepository::new(
+///         SqliteEventRepository::new(primary),
+///         SqliteEventRepository::new(secondary),
+///     )
+///     .with_mode(MirrorMode::Strict)
+/// }
+/// ```
+#[derive(Clone)]
+pub struct MirroredEventRepository<P = Pool<SqliteConnectionManager>> {
+    primary: SqliteEventRepository<P>,
+    secondary: SqliteEventRepository<P>,
+    mode: MirrorMode,
+    on_error: Option<Arc<MirrorErrorHandler>>,
+}
+
+impl<P: ConnectionProvider> MirroredEventRepository<P> {
+    /// Creates a repository mirroring commits from `primary` to `secondary` on a best-effort
+    /// basis.
+    pub fn new(primary: SqliteEventRepository<P>, secondary: SqliteEventRepository<P>) -> Self {
+        Self {
+            primary,
+            secondary,
+            mode: Default::default(),
+            on_error: None,
+        }
+    }
+
+    /// Configures how failures to commit to the secondary are handled.
+    pub fn with_mode(self, mode: MirrorMode) -> Self {
+        Self { mode, ..self }
+    }
+
+    /// Calls `on_error` whenever a commit fails on the secondary, in either mode.
+    pub fn with_error_handler<F>(self, on_error: F) -> Self
+    where
+        F: Fn(&PersistenceError) + Send + Sync + 'static,
+    {
+        Self {
+            on_error: Some(Arc::new(on_error)),
+            ..self
+        }
+    }
+
+    /// The repository for the primary database.
+    pub fn primary(&self) -> &SqliteEventRepository<P> {
+        &self.primary
+    }
+
+    /// The repository for the secondary database.
+    pub fn secondary(&self) -> &SqliteEventRepository<P> {
+        &self.secondary
+    }
+
+    /// Compares the last sequence number and the number of events of every stream in the two
+    /// databases, returning the streams that differ, i.e. those missing events in either
+    /// database. A stream missing an event in the middle, e.g. after a best-effort commit failed
+    /// on the secondary and later commits succeeded, differs by its number of events. The
+    /// events themselves are not compared.
+    pub async fn divergence(&self) -> Result<Vec<Divergence>, SqliteAggregateError> {
+        let primary = stream_heads(&self.primary)?;
+        let mut secondary = stream_heads(&self.secondary)?;
+        let mut result = Vec::new();
+        for (key, primary_head) in primary {
+            let secondary_head = secondary.remove(&key);
+            if secondary_head != Some(primary_head) {
+                result.push(Divergence {
+                    aggregate_type: key.0,
+                    aggregate_id: key.1,
+                    primary_sequence: Some(primary_head.sequence),
+                    secondary_sequence: secondary_head.map(|head| head.sequence),
+                    primary_events: Some(primary_head.events),
+                    secondary_events: secondary_head.map(|head| head.events),
+                });
+            }
+        }
+        for ((aggregate_type, aggregate_id), secondary_head) in secondary {
+            result.push(Divergence {
+                aggregate_type,
+                aggregate_id,
+                primary_sequence: None,
+                secondary_sequence: Some(secondary_head.sequence),
+                primary_events: None,
+                secondary_events: Some(secondary_head.events),
+            });
+        }
+        Ok(result)
+    }
+}
+
+fn stream_heads<P: ConnectionProvider>(
+    repo: &SqliteEventRepository<P>,
+) -> Result<BTreeMap<(String, String), StreamHead>, SqliteAggregateError> {
+    let connection = repo.pool().connection()?;
+    let mut statement = connection.prepare_cached(repo.query_factory().stream_heads())?;
+    let mut rows = statement.query([])?;
+    let mut heads = BTreeMap::new();
+    while let Some(row) = rows.next()? {
+        let (sequence, events): (i64, i64) = (row.get(2)?, row.get(3)?);
+        let head = StreamHead {
+            sequence: sequence as usize,
+            events: events as usize,
+        };
+        heads.insert((row.get(0)?, row.get(1)?), head);
+    }
+    Ok(heads)
+}
+
+#[async_trait]
+impl<P: ConnectionProvider> PersistedEventRepository for MirroredEventRepository<P> {
+    async fn get_events<A: Aggregate>(
+        &self,
+        aggregate_id: &str,
+    ) -> Result<Vec<SerializedEvent>, PersistenceError> {
+        self.primary.get_events::<A>(aggregate_id).await
+    }
+
+    async fn get_last_events<A: Aggregate>(
+        &self,
+        aggregate_id: &str,
+        last_sequence: usize,
+    ) -> Result<Vec<SerializedEvent>, PersistenceError> {
+        self.primary
+            .get_last_events::<A>(aggregate_id, last_sequence)
+            .await
+    }
+
+    async fn get_snapshot<A: Aggregate>(
+        &self,
+        aggregate_id: &str,
+    ) -> Result<Option<SerializedSnapshot>, PersistenceError> {
+        self.primary.get_snapshot::<A>(aggregate_id).await
+    }
+
+    async fn persist<A: Aggregate>(
+        &self,
+        events: &[SerializedEvent],
+        snapshot_update: Option<(String, Value, usize)>,
+    ) -> Result<(), PersistenceError> {
+        self.primary
+            .persist::<A>(events, snapshot_update.clone())
+            .await?;
+        if let Err(err) = self
+            .secondary
+            .persist_mirrored::<A>(events, snapshot_update)
+            .await
+        {
+            if let Some(on_error) = &self.on_error {
+                on_error(&err);
+            }
+            if self.mode == MirrorMode::Strict {
+                // committed to the primary, the commit must not be reported as a conflict
+                return Err(PersistenceError::UnknownError(Box::new(err)));
+            }
+        }
+        Ok(())
+    }
+
+    async fn stream_events<A: Aggregate>(
+        &self,
+        aggregate_id: &str,
+    ) -> Result<ReplayStream, PersistenceError> {
+        self.primary.stream_events::<A>(aggregate_id).await
+    }
+
+    async fn stream_all_events<A: Aggregate>(&self) -> Result<ReplayStream, PersistenceError> {
+        self.primary.stream_all_events::<A>().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use cqrs_es::persist::{PersistedEventRepository, PersistenceError};
+    use r2d2::Pool;
+    use r2d2_sqlite::SqliteConnectionManager;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{
+        default_sqlite_pool, Divergence, MirrorMode, MirroredEventRepository, PayloadLimits,
+        SqliteEventRepository,
+    };
+
+    fn initialized_pool() -> Pool<SqliteConnectionManager> {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        pool.get()
+            .unwrap()
+            .execute_batch(contents.as_str())
+            .unwrap();
+        pool
+    }
+
+    #[tokio::test]
+    async fn mirrored_commits() {
+        let errors = Arc::new(AtomicUsize::new(0));
+        let counted = errors.clone();
+        // the secondary rejects large payloads, letting the databases diverge
+        let event_repo = MirroredEventRepository::new(
+            SqliteEventRepository::new(initialized_pool()),
+            SqliteEventRepository::new(initialized_pool())
+                .with_payload_limits(PayloadLimits::new().with_max_bytes(100)),
+        )
+        .with_error_handler(move |_| {
+            counted.fetch_add(1, Ordering::SeqCst);
+        });
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let created = test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        event_repo
+            .persist::<TestAggregate>(&[created], None)
+            .await
+            .unwrap();
+        let mirrored = event_repo
+            .secondary()
+            .get_events::<TestAggregate>(&id)
+            .await
+            .unwrap();
+        assert_eq!(1, mirrored.len());
+        assert!(event_repo.divergence().await.unwrap().is_empty());
+
+        let large = |sequence| {
+            test_event_envelope(
+                &id,
+                sequence,
+                TestEvent::Tested(Tested {
+                    test_name: "x".repeat(100),
+                }),
+            )
+        };
+        event_repo
+            .persist::<TestAggregate>(&[large(2)], None)
+            .await
+            .unwrap();
+        assert_eq!(1, errors.load(Ordering::SeqCst));
+
+        let event_repo = event_repo.with_mode(MirrorMode::Strict);
+        assert!(event_repo
+            .persist::<TestAggregate>(&[large(3)], None)
+            .await
+            .is_err());
+        assert_eq!(2, errors.load(Ordering::SeqCst));
+        assert_eq!(
+            vec![Divergence {
+                aggregate_type: "TestAggregate".to_string(),
+                aggregate_id: id.clone(),
+                primary_sequence: Some(3),
+                secondary_sequence: Some(1),
+                primary_events: Some(3),
+                secondary_events: Some(1),
+            }],
+            event_repo.divergence().await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn mirrored_snapshots() {
+        let event_repo = MirroredEventRepository::new(
+            SqliteEventRepository::new(initialized_pool()),
+            SqliteEventRepository::new(initialized_pool()),
+        )
+        .with_mode(MirrorMode::Strict);
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let aggregate = |tests: usize| {
+            serde_json::to_value(TestAggregate {
+                id: id.clone(),
+                description: String::new(),
+                tests: vec!["a test".to_string(); tests],
+            })
+            .unwrap()
+        };
+        let tested = |sequence| {
+            test_event_envelope(
+                &id,
+                sequence,
+                TestEvent::Tested(Tested {
+                    test_name: "a test".to_string(),
+                }),
+            )
+        };
+        let created = test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        event_repo
+            .persist::<TestAggregate>(&[created], None)
+            .await
+            .unwrap();
+        // the first snapshot only reaches the primary
+        event_repo
+            .primary()
+            .persist::<TestAggregate>(&[tested(2)], Some((id.clone(), aggregate(1), 1)))
+            .await
+            .unwrap();
+        assert_eq!(
+            None,
+            event_repo
+                .secondary()
+                .get_snapshot::<TestAggregate>(&id)
+                .await
+                .unwrap()
+        );
+
+        // the next snapshot update replaces the missing snapshot rather than failing on it
+        event_repo
+            .persist::<TestAggregate>(&[tested(3)], Some((id.clone(), aggregate(2), 2)))
+            .await
+            .unwrap();
+        let snapshot = event_repo
+            .secondary()
+            .get_snapshot::<TestAggregate>(&id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(2, snapshot.current_snapshot);
+        assert_eq!(aggregate(2), snapshot.aggregate);
+
+        // a conflict on the secondary is not reported as a conflict of the committed command
+        event_repo
+            .secondary()
+            .persist::<TestAggregate>(&[tested(4)], None)
+            .await
+            .unwrap();
+        let err = event_repo
+            .persist::<TestAggregate>(&[tested(4)], None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PersistenceError::UnknownError(_)), "{err:?}");
+        assert_eq!(
+            4,
+            event_repo
+                .primary()
+                .get_events::<TestAggregate>(&id)
+                .await
+                .unwrap()
+                .len()
+        );
+    }
+
+    #[tokio::test]
+    async fn divergence_with_gap() {
+        // the secondary rejects large payloads, letting a best-effort commit fail on it
+        let event_repo = MirroredEventRepository::new(
+            SqliteEventRepository::new(initialized_pool()),
+            SqliteEventRepository::new(initialized_pool())
+                .with_payload_limits(PayloadLimits::new().with_max_bytes(100)),
+        );
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let tested = |sequence, test_name: String| {
+            test_event_envelope(&id, sequence, TestEvent::Tested(Tested { test_name }))
+        };
+        let created = test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        for event in [
+            created,
+            tested(2, "x".repeat(100)),
+            tested(3, "small".to_string()),
+        ] {
+            event_repo
+                .persist::<TestAggregate>(&[event], None)
+                .await
+                .unwrap();
+        }
+
+        // both databases end with the third event, the secondary misses the second
+        let mirrored = event_repo
+            .secondary()
+            .get_events::<TestAggregate>(&id)
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![1, 3],
+            mirrored.iter().map(|e| e.sequence).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![Divergence {
+                aggregate_type: "TestAggregate".to_string(),
+                aggregate_id: id.clone(),
+                primary_sequence: Some(3),
+                secondary_sequence: Some(3),
+                primary_events: Some(3),
+                secondary_events: Some(2),
+            }],
+            event_repo.divergence().await.unwrap()
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::identifier::TableName;
+use crate::sql_query::SqlQueryFactory;
+use crate::SqliteEventRepository;
+
+/// The number of low bits of a global position that hold the row's position within its
+/// partition, the high bits hold the partition's ordinal. Positions of events in the base
+/// events table are unchanged.
+pub const PARTITION_POSITION_BITS: u32 = 40;
+
+/// How new events are split across event tables, see `SqliteEventRepository::with_partitioning`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Partitioning {
+    /// All events are stored in the events table.
+    #[default]
+    None,
+    /// Events are stored in a table per calendar month (UTC) of their commit, named after the
+    /// events table and the month, e.g. `events_2024_01`.
+    Monthly,
+    /// Events are stored in numbered tables, e.g. `events_p1`, starting a new table once the
+    /// current one holds `max_events` events.
+    BySize {
+        /// The number of events after which a new partition is started.
+        max_events: usize,
+    },
+}
+
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    /// Returns the names of the partitions of the events table, oldest first. The events table
+    /// itself, which holds any events committed before partitioning was configured, is not
+    /// included.
+    pub async fn partitions(&self) -> Result<Vec<String>, SqliteAggregateError> {
+        let connection = self.pool().connection()?;
+        let partitions = select_partitions(&connection, self.query_factory())?;
+        Ok(partitions.into_iter().map(|(_, name)| name).collect())
+    }
+
+    /// Drops a partition along with all of its events, e.g. to enforce a retention period or once
+    /// the partition has been archived. This is far cheaper than deleting the events row by row.
+    ///
+    /// Snapshots and views built from the dropped events are not affected. Aggregate instances
+    /// with events in the partition can no longer be loaded consistently and should be archived
+    /// as a whole, which makes partitioning best suited to short-lived aggregates and log-heavy
+    /// workloads.
+    ///
+    /// ```
+    /// use rusqlite_es::{SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// async fn drop_oldest(repo: &SqliteEventRepository) -> Result<(), SqliteAggregateError> {
+    ///     if let Some(oldest) = repo.partitions().await?.first() {
+    ///         repo.drop_partition(oldest).await?;
+    ///     }
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn drop_partition(&self, partition: &str) -> Result<(), SqliteAggregateError> {
+        let query_factory = self.query_factory();
+        self.write_async(|tx| {
+            let mut partitions = select_partitions(tx, query_factory)?;
+            let Some(index) = partitions.iter().position(|(_, name)| name == partition) else {
+                return Err(SqliteAggregateError::UnknownError(
+                    format!("'{partition}' is not a partition").into(),
+                ));
+            };
+            partitions.remove(index);
+            tx.execute(query_factory.unregister_partition(), [partition])?;
+            tx.execute_batch(&format!("DROP TABLE {}", TableName::unchecked(partition)))?;
+            tx.execute_batch(&query_factory.partition_view(&partitions))?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+// Returns the ordinal and name of the partition that new events are written to, creating it if
+// needed. Must be called within a write transaction.
+pub(crate) fn route_partition(
+    connection: &Connection,
+    query_factory: &SqlQueryFactory,
+) -> Result<(i64, String), rusqlite::Error> {
+    let event_table = query_factory.event_table();
+    let mut partitions = select_partitions(connection, query_factory)?;
+    let next_ordinal = partitions.last().map_or(1, |(ordinal, _)| ordinal + 1);
+    let partition = match query_factory.partitioning() {
+        Partitioning::None => return Ok((0, event_table.to_string())),
+        Partitioning::Monthly => {
+            let month: String =
+                connection.query_row("SELECT strftime('%Y_%m', 'now')", [], |row| row.get(0))?;
+            let partition = format!("{event_table}_{month}");
+            if let Some((ordinal, _)) = partitions.iter().find(|(_, name)| *name == partition) {
+                return Ok((*ordinal, partition));
+            }
+            partition
+        }
+        Partitioning::BySize { max_events } => {
+            if let Some((ordinal, name)) = partitions.last() {
+                let size: Option<i64> = connection
+                    .query_row(
+                        &format!(
+                            "SELECT max({}) FROM {}",
+                            query_factory.position(),
+                            TableName::unchecked(name)
+                        ),
+                        [],
+                        |row| row.get(0),
+                    )
+                    .optional()?
+                    .flatten();
+                if (size.unwrap_or(0) as usize) < max_events {
+                    return Ok((*ordinal, name.clone()));
+                }
+            }
+            format!("{event_table}_p{next_ordinal}")
+        }
+    };
+    connection.execute_batch(&query_factory.create_events_table(&partition))?;
+    connection.execute(
+        query_factory.register_partition(),
+        (next_ordinal, &partition),
+    )?;
+    partitions.push((next_ordinal, partition.clone()));
+    connection.execute_batch(&query_factory.partition_view(&partitions))?;
+    Ok((next_ordinal, partition))
+}
+
+fn select_partitions(
+    connection: &Connection,
+    query_factory: &SqlQueryFactory,
+) -> Result<Vec<(i64, String)>, rusqlite::Error> {
+    let mut statement = connection.prepare_cached(query_factory.select_partitions())?;
+    let partitions = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect();
+    partitions
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use cqrs_es::persist::PersistedEventRepository;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{
+        default_sqlite_pool, Partitioning, SqliteAggregateError, SqliteEventRepository,
+        PARTITION_POSITION_BITS,
+    };
+
+    #[tokio::test]
+    async fn partitioned_events() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let repo = SqliteEventRepository::new(pool);
+        repo.insert_events::<TestAggregate>(&[test_event_envelope(
+            &id,
+            1,
+            TestEvent::Created(Created { id: id.clone() }),
+        )])
+        .await
+        .unwrap();
+
+        let repo = repo.with_partitioning(Partitioning::BySize { max_events: 2 });
+        let tested = |sequence| {
+            test_event_envelope(
+                &id,
+                sequence,
+                TestEvent::Tested(Tested {
+                    test_name: format!("partitioned {sequence}"),
+                }),
+            )
+        };
+        let receipt = repo
+            .insert_events::<TestAggregate>(&[tested(2), tested(3)])
+            .await
+            .unwrap();
+        assert_eq!((1 << PARTITION_POSITION_BITS) + 2, receipt.global_position);
+        repo.insert_events::<TestAggregate>(&[tested(4)])
+            .await
+            .unwrap();
+        assert_eq!(
+            vec!["events_p1", "events_p2"],
+            repo.partitions().await.unwrap()
+        );
+
+        // sequences are unique across partitions
+        match repo.insert_events::<TestAggregate>(&[tested(2)]).await {
+            Err(SqliteAggregateError::OptimisticLock) => {}
+            result => panic!("expected an optimistic lock error, found {:?}", result),
+        }
+
+        let events = repo.get_events::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(
+            vec![1, 2, 3, 4],
+            events
+                .iter()
+                .map(|event| event.sequence)
+                .collect::<Vec<_>>()
+        );
+        let mut stream = repo.stream_everything().await.unwrap();
+        let mut sequences = Vec::new();
+        while let Some(event) = stream.next().await {
+            sequences.push(event.unwrap().sequence);
+        }
+        // the seed event of init.sql precedes the partitioned events
+        assert_eq!(vec![1, 1, 2, 3, 4], sequences);
+
+        repo.drop_partition("events_p1").await.unwrap();
+        assert_eq!(vec!["events_p2"], repo.partitions().await.unwrap());
+        let events = repo.get_events::<TestAggregate>(&id).await.unwrap();
+        assert_eq!(
+            vec![1, 4],
+            events
+                .iter()
+                .map(|event| event.sequence)
+                .collect::<Vec<_>>()
+        );
+        assert!(repo.drop_partition("events").await.is_err());
+    }
+}
@@ -0,0 +1,158 @@
+use rusqlite::{Connection, OptionalExtension};
+use serde_json::Value;
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::sql_query::SqlQueryFactory;
+use crate::SqliteEventRepository;
+
+/// The table holding deduplicated event payloads, see `PayloadStorage::Deduplicated`.
+pub const EVENT_PAYLOADS_TABLE: &str = "event_payloads";
+
+/// How event payloads are stored, see `SqliteEventRepository::with_payload_storage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PayloadStorage {
+    /// The payload of each event is stored in full with the event.
+    #[default]
+    Inline,
+    /// Each distinct payload is stored once in the `event_payloads` table, keyed by its hash,
+    /// and events only hold the id of their payload. This shrinks databases whose events repeat
+    /// identical payloads, e.g. periodic state reports, at the cost of a lookup per event when
+    /// events are committed and read.
+    ///
+    /// Payloads no longer referenced, e.g. after events were deleted, are removed with
+    /// `SqliteEventRepository::collect_payloads`.
+    Deduplicated,
+}
+
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    /// Deletes the deduplicated payloads no longer referenced by any event, returning the
+    /// number of payloads deleted. See `PayloadStorage::Deduplicated`.
+    ///
+    /// ```
+    /// use rusqlite_es::{SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// async fn collect(repo: &SqliteEventRepository) -> Result<(), SqliteAggregateError> {
+    ///     let deleted = repo.collect_payloads().await?;
+    ///     println!("deleted {deleted} unreferenced payloads");
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn collect_payloads(&self) -> Result<usize, SqliteAggregateError> {
+        let query_factory = self.query_factory();
+        if query_factory.payload_storage() != PayloadStorage::Deduplicated {
+            return Ok(0);
+        }
+        self.write_async(|tx| Ok(tx.execute(query_factory.collect_payloads(), [])?))
+            .await
+    }
+}
+
+// Returns the id of a payload in the payloads table, adding the payload as needed.
+pub(crate) fn intern_payload(
+    connection: &Connection,
+    query_factory: &SqlQueryFactory,
+    payload: &Value,
+) -> Result<i64, rusqlite::Error> {
+    let hash = fnv1a(payload.to_string().as_bytes());
+    let mut select = connection.prepare_cached(query_factory.select_payload_id())?;
+    if let Some(id) = select
+        .query_row((&hash, payload), |row| row.get(0))
+        .optional()?
+    {
+        return Ok(id);
+    }
+    connection
+        .prepare_cached(query_factory.intern_payload())?
+        .execute((&hash, payload))?;
+    Ok(connection.last_insert_rowid())
+}
+
+// The hex-encoded 64-bit FNV-1a hash of `contents`.
+fn fnv1a(contents: &[u8]) -> String {
+    let hash = contents
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    format!("{hash:016x}")
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde_json::json;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, PayloadStorage, SqliteEventRepository};
+
+    #[tokio::test]
+    async fn deduplicated_payloads() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let repo =
+            SqliteEventRepository::new(pool).with_payload_storage(PayloadStorage::Deduplicated);
+        let test_name = format!("report {}", uuid::Uuid::new_v4());
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let id = uuid::Uuid::new_v4().to_string();
+            let mut created =
+                test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+            let mut reports = (2..4)
+                .map(|sequence| {
+                    test_event_envelope(
+                        &id,
+                        sequence,
+                        TestEvent::Tested(Tested {
+                            test_name: test_name.clone(),
+                        }),
+                    )
+                })
+                .collect::<Vec<_>>();
+            created.metadata = json!({});
+            reports
+                .iter_mut()
+                .for_each(|report| report.metadata = json!({}));
+            repo.persist::<TestAggregate>(&[vec![created], reports].concat(), None)
+                .await
+                .unwrap();
+            ids.push(id);
+        }
+
+        let stored_payloads = || -> i64 {
+            repo.with_connection(|conn| {
+                conn.query_row(
+                    "SELECT count(*) FROM event_payloads WHERE contents LIKE ?",
+                    [format!("%{test_name}%")],
+                    |row| row.get(0),
+                )
+            })
+            .unwrap()
+        };
+        assert_eq!(1, stored_payloads());
+        let events = repo.get_events::<TestAggregate>(&ids[1]).await.unwrap();
+        assert_eq!(3, events.len());
+        assert_eq!(
+            json!({"Tested": {"test_name": test_name}}),
+            events[2].payload
+        );
+
+        repo.with_connection(|conn| {
+            conn.execute(
+                "DELETE FROM events WHERE aggregate_id IN (?, ?)",
+                (&ids[0], &ids[1]),
+            )
+        })
+        .unwrap();
+        assert!(repo.collect_payloads().await.unwrap() >= 3);
+        assert_eq!(0, stored_payloads());
+    }
+}
@@ -0,0 +1,143 @@
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use crate::error::SqliteAggregateError;
+
+/// The kind of serialized payload being written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadKind {
+    /// An event payload.
+    Event,
+    /// An aggregate snapshot.
+    Snapshot,
+    /// A serialized view.
+    View,
+}
+
+impl Display for PayloadKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PayloadKind::Event => write!(f, "event"),
+            PayloadKind::Snapshot => write!(f, "snapshot"),
+            PayloadKind::View => write!(f, "view"),
+        }
+    }
+}
+
+type PayloadWarning = dyn Fn(PayloadKind, &str, usize) + Send + Sync;
+
+/// Limits on the serialized size of the JSON payloads written by the repositories.
+///
+/// Payloads larger than the maximum size are rejected with
+/// `SqliteAggregateError::PayloadTooLarge`, payloads larger than the warning threshold are
+/// written but reported to the warning callback. Sizes are measured in bytes of serialized JSON.
+///
+/// ```
+/// use rusqlite_es::PayloadLimits;
+///
+/// let limits = PayloadLimits::new()
+///     .with_max_bytes(1024 * 1024)
+///     .with_warning(64 * 1024, |kind, id, size| {
+///         eprintln!("large {kind} payload for {id}: {size} bytes");
+///     });
+/// ```
+#[derive(Clone, Default)]
+pub struct PayloadLimits {
+    max_bytes: Option<usize>,
+    warn_bytes: Option<usize>,
+    on_warning: Option<Arc<PayloadWarning>>,
+}
+
+impl PayloadLimits {
+    /// Creates `PayloadLimits` without any limits.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Rejects payloads larger than `max_bytes`.
+    pub fn with_max_bytes(self, max_bytes: usize) -> Self {
+        Self {
+            max_bytes: Some(max_bytes),
+            ..self
+        }
+    }
+
+    /// Calls `on_warning` with the payload kind, the aggregate or view id and the payload size
+    /// for payloads larger than `warn_bytes`.
+    pub fn with_warning<F>(self, warn_bytes: usize, on_warning: F) -> Self
+    where
+        F: Fn(PayloadKind, &str, usize) + Send + Sync + 'static,
+    {
+        Self {
+            warn_bytes: Some(warn_bytes),
+            on_warning: Some(Arc::new(on_warning)),
+            ..self
+        }
+    }
+
+    pub(crate) fn check(
+        &self,
+        kind: PayloadKind,
+        id: &str,
+        payload: &Value,
+    ) -> Result<(), SqliteAggregateError> {
+        if self.max_bytes.is_none() && self.warn_bytes.is_none() {
+            return Ok(());
+        }
+        let size = serde_json::to_vec(payload)?.len();
+        if let Some(limit) = self.max_bytes {
+            if size > limit {
+                return Err(SqliteAggregateError::PayloadTooLarge { kind, size, limit });
+            }
+        }
+        if let (Some(warn_bytes), Some(on_warning)) = (self.warn_bytes, &self.on_warning) {
+            if size > warn_bytes {
+                on_warning(kind, id, size);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::json;
+
+    use crate::{PayloadKind, PayloadLimits, SqliteAggregateError};
+
+    #[test]
+    fn payload_limits() {
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let recorded = warnings.clone();
+        let limits = PayloadLimits::new()
+            .with_max_bytes(20)
+            .with_warning(10, move |kind, id, size| {
+                recorded.lock().unwrap().push((kind, id.to_string(), size))
+            });
+
+        limits.check(PayloadKind::Event, "a", &json!("ok")).unwrap();
+        limits
+            .check(PayloadKind::View, "b", &json!("twelve bytes"))
+            .unwrap();
+        assert_eq!(
+            vec![(PayloadKind::View, "b".to_string(), 14)],
+            *warnings.lock().unwrap()
+        );
+        match limits.check(
+            PayloadKind::Snapshot,
+            "c",
+            &json!("far more than twenty bytes"),
+        ) {
+            Err(SqliteAggregateError::PayloadTooLarge { kind, size, limit }) => {
+                assert_eq!(PayloadKind::Snapshot, kind);
+                assert_eq!(28, size);
+                assert_eq!(20, limit);
+            }
+            _ => panic!("expected a payload size error"),
+        }
+    }
+}
@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+use async_trait::async_trait;
+use cqrs_es::persist::{PersistedEventRepository, PersistenceError};
+use cqrs_es::{Aggregate, EventEnvelope, Query};
+
+use crate::connection::ConnectionProvider;
+use crate::SqliteEventRepository;
+
+/// A `Query` shared through an `Arc`, e.g. to register the same query processor with several
+/// frameworks while keeping a handle to it. `CqrsFramework` takes its queries boxed, wrapping
+/// the `Arc` allows passing it to `CqrsFramework::new` or `CqrsFramework::append_query`.
+///
+/// ```
+/// # use cqrs_es::doc::MyAggregate;
+/// use std::sync::Arc;
+///
+/// use cqrs_es::{CqrsFramework, EventStore, Query};
+/// use rusqlite_es::SharedQuery;
+///
+/// fn add_audit<ES: EventStore<MyAggregate>>(
+///     cqrs: CqrsFramework<MyAggregate, ES>,
+///     audit: Arc<dyn Query<MyAggregate>>,
+/// ) -> CqrsFramework<MyAggregate, ES> {
+///     cqrs.append_query(Box::new(SharedQuery::new(audit)))
+/// }
+/// ```
+pub struct SharedQuery<A: Aggregate> {
+    query: Arc<dyn Query<A>>,
+}
+
+impl<A: Aggregate> SharedQuery<A> {
+    /// Wraps a shared query.
+    pub fn new(query: Arc<dyn Query<A>>) -> Self {
+        Self { query }
+    }
+}
+
+// Implemented manually, deriving would needlessly require the aggregate to be `Clone`.
+impl<A: Aggregate> Clone for SharedQuery<A> {
+    fn clone(&self) -> Self {
+        Self {
+            query: self.query.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl<A: Aggregate> Query<A> for SharedQuery<A> {
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<A>]) {
+        self.query.dispatch(aggregate_id, events).await;
+    }
+}
+
+/// Identifies a query registered with a `QueryRegistry`, see `QueryRegistry::remove_query`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct QueryId(u64);
+
+/// A list of queries that may be changed after the framework dispatching to it was constructed,
+/// see `sqlite_cqrs_with_registry`.
+///
+/// The registry is itself a `Query`, dispatching committed events to each registered query in
+/// the order they were appended. Cloning is cheap, clones share the list, so a clone may be kept
+/// to add and remove queries while the framework is running, e.g. as plugins are loaded and
+/// unloaded. A query appended with `append_query` receives the events committed from then on,
+/// a new projection that needs the events committed before is appended with
+/// `append_query_with_catch_up` instead.
+///
+/// ```
+/// # use cqrs_es::doc::{MyAggregate, MyService};
+/// use std::sync::Arc;
+///
+/// use cqrs_es::Query;
+/// use r2d2::Pool;
+/// use r2d2_sqlite::SqliteConnectionManager;
+/// use rusqlite_es::{sqlite_cqrs_with_registry, QueryRegistry, SqliteCqrs};
+///
+/// fn configure_cqrs(
+///     pool: Pool<SqliteConnectionManager>,
+///     audit: Arc<dyn Query<MyAggregate>>,
+/// ) -> (SqliteCqrs<MyAggregate>, QueryRegistry<MyAggregate>) {
+///     let queries = QueryRegistry::new();
+///     queries.append_query(audit);
+///     let cqrs = sqlite_cqrs_with_registry(pool, queries.clone(), MyService);
+///     (cqrs, queries)
+/// }
+/// ```
+pub struct QueryRegistry<A: Aggregate> {
+    queries: Arc<RwLock<Vec<Arc<Registered<A>>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+// The events dispatched to a query that is catching up, by aggregate instance.
+type PendingEvents<A> = Vec<(String, Vec<EventEnvelope<A>>)>;
+
+struct Registered<A: Aggregate> {
+    id: QueryId,
+    query: Arc<dyn Query<A>>,
+    // `Some` while the query catches up, holding the events dispatched meanwhile
+    pending: Mutex<Option<PendingEvents<A>>>,
+}
+
+impl<A: Aggregate> QueryRegistry<A> {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self {
+            queries: Default::default(),
+            next_id: Default::default(),
+        }
+    }
+
+    /// Appends a query, which receives the events committed from now on.
+    pub fn append_query(&self, query: Arc<dyn Query<A>>) -> QueryId {
+        self.register(query, None).id
+    }
+
+    /// Appends a query after replaying all events of the aggregate type committed so far to it,
+    /// e.g. a projection added to a running application, returning once it has caught up.
+    ///
+    /// Events committed while the query catches up are held back and dispatched to it once all
+    /// earlier events were replayed, the query therefore receives every event once and in the
+    /// order of each aggregate instance's events. If the replay fails, the query is removed
+    /// again and the error returned.
+    pub async fn append_query_with_catch_up<P: ConnectionProvider>(
+        &self,
+        query: Arc<dyn Query<A>>,
+        repo: &SqliteEventRepository<P>,
+    ) -> Result<QueryId, PersistenceError> {
+        let registered = self.register(query, Some(Vec::new()));
+        match catch_up(&registered, repo).await {
+            Ok(()) => Ok(registered.id),
+            Err(err) => {
+                self.remove_query(registered.id);
+                Err(err)
+            }
+        }
+    }
+
+    /// Removes a query, returning whether it was registered. Events being dispatched at the
+    /// time may still reach the query.
+    pub fn remove_query(&self, id: QueryId) -> bool {
+        let mut queries = self
+            .queries
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let before = queries.len();
+        queries.retain(|registered| registered.id != id);
+        queries.len() != before
+    }
+
+    /// The number of registered queries, including those catching up.
+    pub fn len(&self) -> usize {
+        self.registered().len()
+    }
+
+    /// Whether no queries are registered.
+    pub fn is_empty(&self) -> bool {
+        self.registered().is_empty()
+    }
+
+    fn register(
+        &self,
+        query: Arc<dyn Query<A>>,
+        pending: Option<PendingEvents<A>>,
+    ) -> Arc<Registered<A>> {
+        let registered = Arc::new(Registered {
+            id: QueryId(self.next_id.fetch_add(1, Ordering::SeqCst)),
+            query,
+            pending: Mutex::new(pending),
+        });
+        self.queries
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(registered.clone());
+        registered
+    }
+
+    // The queries registered at this time, the lock is not held while dispatching.
+    fn registered(&self) -> Vec<Arc<Registered<A>>> {
+        self.queries
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+}
+
+// Replays the events committed so far to a query, then the events held back meanwhile, skipping
+// those already replayed, until none are pending and the query joins the live dispatch.
+async fn catch_up<A, P>(
+    registered: &Registered<A>,
+    repo: &SqliteEventRepository<P>,
+) -> Result<(), PersistenceError>
+where
+    A: Aggregate,
+    P: ConnectionProvider,
+{
+    let mut replayed: HashMap<String, usize> = HashMap::new();
+    let mut stream = repo.stream_all_events::<A>().await?;
+    while let Some(event) = stream.next::<A>(&None).await {
+        let event = event?;
+        replayed.insert(event.aggregate_id.clone(), event.sequence);
+        let aggregate_id = event.aggregate_id.clone();
+        registered.query.dispatch(&aggregate_id, &[event]).await;
+    }
+    loop {
+        let pending = {
+            let mut pending = lock(&registered.pending);
+            match pending.as_mut() {
+                Some(events) if !events.is_empty() => std::mem::take(events),
+                _ => {
+                    *pending = None;
+                    return Ok(());
+                }
+            }
+        };
+        for (aggregate_id, events) in pending {
+            let after = replayed.get(&aggregate_id).copied().unwrap_or(0);
+            let events = events
+                .into_iter()
+                .filter(|event| event.sequence > after)
+                .collect::<Vec<_>>();
+            if let Some(last) = events.last() {
+                replayed.insert(aggregate_id.clone(), last.sequence);
+                registered.query.dispatch(&aggregate_id, &events).await;
+            }
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl<A: Aggregate> Default for QueryRegistry<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Implemented manually, deriving would needlessly require the aggregate to be `Clone`.
+impl<A: Aggregate> Clone for QueryRegistry<A> {
+    fn clone(&self) -> Self {
+        Self {
+            queries: self.queries.clone(),
+            next_id: self.next_id.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl<A: Aggregate> Query<A> for QueryRegistry<A> {
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<A>]) {
+        for registered in self.registered() {
+            if let Some(pending) = lock(&registered.pending).as_mut() {
+                pending.push((aggregate_id.to_string(), events.to_vec()));
+                continue;
+            }
+            registered.query.dispatch(aggregate_id, events).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use cqrs_es::persist::{PersistedEventRepository, SerializedEvent};
+    use cqrs_es::{EventEnvelope, Query};
+    use serde_json::json;
+
+    use crate::query_registry::catch_up;
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, TestServices, Tested,
+        TEST_CONNECTION_STRING,
+    };
+    use crate::{
+        default_sqlite_pool, sqlite_cqrs, sqlite_cqrs_with_registry, QueryRegistry, SharedQuery,
+        SqliteEventRepository,
+    };
+
+    #[derive(Default)]
+    struct CountingQuery {
+        events: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Query<TestAggregate> for CountingQuery {
+        async fn dispatch(&self, _aggregate_id: &str, events: &[EventEnvelope<TestAggregate>]) {
+            self.events.fetch_add(events.len(), Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn query_registry() {
+        let counting = Arc::new(CountingQuery::default());
+        let queries = QueryRegistry::<TestAggregate>::new();
+        let _cqrs = sqlite_cqrs_with_registry(
+            default_sqlite_pool(TEST_CONNECTION_STRING),
+            queries.clone(),
+            TestServices,
+        );
+        // the same query is shared with a second framework
+        let _other = sqlite_cqrs(
+            default_sqlite_pool(TEST_CONNECTION_STRING),
+            vec![Box::new(SharedQuery::new(counting.clone()))],
+            TestServices,
+        );
+        assert!(queries.is_empty());
+
+        queries.append_query(counting.clone());
+        let id = "registry-1";
+        let mut created = test_event_envelope(id, 1, TestEvent::Created(Created { id: id.into() }));
+        created.metadata = json!({});
+        let envelope = EventEnvelope::<TestAggregate>::try_from(created).unwrap();
+        queries.dispatch(id, &[envelope]).await;
+        assert_eq!(1, queries.len());
+        assert_eq!(1, counting.events.load(Ordering::SeqCst));
+    }
+
+    fn event(id: &str, sequence: usize) -> SerializedEvent {
+        let event = match sequence {
+            1 => TestEvent::Created(Created { id: id.into() }),
+            _ => TestEvent::Tested(Tested {
+                test_name: format!("catch-up {sequence}"),
+            }),
+        };
+        let mut event = test_event_envelope(id, sequence, event);
+        event.metadata = json!({});
+        event
+    }
+
+    #[tokio::test]
+    async fn catch_up_and_remove() {
+        let repo = SqliteEventRepository::connect(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .await
+            .unwrap()
+            .into_inner();
+        let id = "catch-up-1";
+        repo.persist::<TestAggregate>(&[event(id, 1), event(id, 2)], None)
+            .await
+            .unwrap();
+        let queries = QueryRegistry::<TestAggregate>::new();
+        let counting = Arc::new(CountingQuery::default());
+        let query_id = queries
+            .append_query_with_catch_up(counting.clone(), &repo)
+            .await
+            .unwrap();
+        assert_eq!(2, counting.events.load(Ordering::SeqCst));
+
+        // events dispatched while catching up are held back, those replayed are skipped
+        let late = Arc::new(CountingQuery::default());
+        let registered = queries.register(late.clone(), Some(Vec::new()));
+        repo.persist::<TestAggregate>(&[event(id, 3)], None)
+            .await
+            .unwrap();
+        let committed = [event(id, 2), event(id, 3)]
+            .map(|event| EventEnvelope::<TestAggregate>::try_from(event).unwrap());
+        queries.dispatch(id, &committed).await;
+        assert_eq!(0, late.events.load(Ordering::SeqCst));
+        catch_up(&registered, &repo).await.unwrap();
+        assert_eq!(3, late.events.load(Ordering::SeqCst));
+        assert_eq!(4, counting.events.load(Ordering::SeqCst));
+
+        assert!(queries.remove_query(query_id));
+        assert!(!queries.remove_query(query_id));
+        queries.dispatch(id, &committed[1..]).await;
+        assert_eq!(4, counting.events.load(Ordering::SeqCst));
+        assert_eq!(4, late.events.load(Ordering::SeqCst));
+    }
+}
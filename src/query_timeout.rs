@@ -0,0 +1,90 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rusqlite::Connection;
+
+use crate::error::SqliteAggregateError;
+
+// The number of virtual machine instructions between checks of the deadline.
+const PROGRESS_STEPS: i32 = 1000;
+
+// Runs a query, interrupting it through a progress handler once it runs longer than `timeout`,
+// in which case `SqliteAggregateError::Timeout` is returned.
+pub(crate) fn with_timeout<T, E>(
+    connection: &Connection,
+    timeout: Option<Duration>,
+    query: impl FnOnce() -> Result<T, E>,
+) -> Result<T, E>
+where
+    E: From<SqliteAggregateError>,
+{
+    let Some(timeout) = timeout else {
+        return query();
+    };
+    let deadline = Instant::now() + timeout;
+    let expired = Arc::new(AtomicBool::new(false));
+    let interrupted = expired.clone();
+    connection.progress_handler(
+        PROGRESS_STEPS,
+        Some(move || {
+            let expired = Instant::now() >= deadline;
+            if expired {
+                interrupted.store(true, Ordering::Relaxed);
+            }
+            expired
+        }),
+    );
+    let result = query();
+    // the connection returns to the pool, later queries must not be interrupted
+    connection.progress_handler(0, None::<fn() -> bool>);
+    match result {
+        Err(_) if expired.load(Ordering::Relaxed) => {
+            Err(SqliteAggregateError::Timeout { timeout }.into())
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use rusqlite::Connection;
+
+    use crate::query_timeout::with_timeout;
+    use crate::SqliteAggregateError;
+
+    const ENDLESS_QUERY: &str = "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n) \
+                                 SELECT count(*) FROM n";
+
+    #[test]
+    fn query_timeout() {
+        let connection = Connection::open_in_memory().unwrap();
+        let count = |connection: &Connection, sql: &str| {
+            connection
+                .query_row(sql, [], |row| row.get::<_, i64>(0))
+                .map_err(SqliteAggregateError::from)
+        };
+
+        let result = with_timeout(&connection, Some(Duration::from_millis(20)), || {
+            count(&connection, ENDLESS_QUERY)
+        });
+        assert!(matches!(
+            result,
+            Err(SqliteAggregateError::Timeout { timeout }) if timeout == Duration::from_millis(20)
+        ));
+
+        let result = with_timeout(&connection, Some(Duration::from_secs(10)), || {
+            count(&connection, "SELECT 1")
+        });
+        assert_eq!(1, result.unwrap());
+        // the progress handler is removed once the query completes
+        let bounded = "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n \
+                       WHERE i < 100000) SELECT count(*) FROM n";
+        let _ = with_timeout(&connection, Some(Duration::ZERO), || {
+            count(&connection, bounded)
+        });
+        assert_eq!(100000, count(&connection, bounded).unwrap());
+    }
+}
@@ -0,0 +1,293 @@
+use std::ops::Deref;
+
+use async_trait::async_trait;
+use cqrs_es::persist::{
+    PersistedEventRepository, PersistenceError, ReplayStream, SerializedEvent, SerializedSnapshot,
+};
+use cqrs_es::Aggregate;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use serde_json::Value;
+
+use crate::connection::{with_checked_connection, ConnectionProvider};
+use crate::error::SqliteAggregateError;
+use crate::store_config::{create_store_config_table, verify_store_config};
+use crate::types::JSONB_SQLITE_VERSION;
+use crate::{HashChain, JsonEncoding, Partitioning, SqliteCapabilities, SqliteEventRepository};
+
+/// An event repository whose schema has been verified, returned by
+/// `SqliteEventRepository::connect` and `SqliteEventRepository::ready`.
+///
+/// All tables required by the repository's configuration exist with the expected columns, so
+/// none of its operations fail because of a missing table or column. A `ReadyRepository`
+/// dereferences to the underlying `SqliteEventRepository` and is used in its place, e.g. with a
+/// `PersistedEventStore`.
+#[derive(Clone)]
+pub struct ReadyRepository<P = Pool<SqliteConnectionManager>> {
+    repo: SqliteEventRepository<P>,
+}
+
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    /// Creates a repository with the default tables, 'events' and 'snapshots', creating them if
+    /// they do not yet exist, see `ready`.
+    ///
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{ReadyRepository, SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// async fn connect(pool: Pool<SqliteConnectionManager>) -> Result<ReadyRepository, SqliteAggregateError> {
+    ///     SqliteEventRepository::connect(pool).await
+    /// }
+    /// ```
+    pub async fn connect(pool: P) -> Result<ReadyRepository<P>, SqliteAggregateError> {
+        Self::new(pool).ready().await
+    }
+
+    /// Creates any missing tables and indexes required by the repository's configuration and
+    /// verifies that existing tables have the expected columns, e.g. that they have been
+    /// migrated to the current schema. View tables are not created, the dead-letter table of a
+    /// `StreamErrorPolicy::DeadLetter` and the table of a `ConflictLog` are.
+    ///
+    /// The repository's configuration is recorded in the `store_config` table the first time
+    /// and verified against the recorded one afterwards, failing if it differs, see
+    /// `StoreConfig`.
+    ///
+    /// ```
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{ReadyRepository, SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// async fn connect(pool: Pool<SqliteConnectionManager>) -> Result<ReadyRepository, SqliteAggregateError> {
+    ///     SqliteEventRepository::new(pool)
+    ///         .with_tables("my_event_table", "my_snapshot_table")
+    ///         .ready()
+    ///         .await
+    /// }
+    /// ```
+    pub async fn ready(self) -> Result<ReadyRepository<P>, SqliteAggregateError> {
+        let query_factory = self.query_factory();
+        if query_factory.cascading_deletes() && query_factory.snapshot_table().schema().is_some() {
+            return Err(SqliteAggregateError::UnknownError(
+                "cascading deletes are not supported with a snapshot schema".into(),
+            ));
+        }
+        if query_factory.hash_chain() != HashChain::None
+            && query_factory.partitioning() != Partitioning::None
+        {
+            return Err(SqliteAggregateError::UnknownError(
+                "hash chains are not supported on a partitioned store".into(),
+            ));
+        }
+        // the JSONB functions may also be provided by a system library built with them
+        if self.json_encoding() == JsonEncoding::Jsonb
+            && !SqliteCapabilities::detect(&*self.pool().connection()?)?.jsonb
+        {
+            return Err(SqliteAggregateError::UnsupportedSqliteVersion {
+                required: JSONB_SQLITE_VERSION,
+                found: rusqlite::version(),
+            });
+        }
+        let missing = with_checked_connection(self.pool(), |connection| {
+            let mut statement = connection.prepare("SELECT name FROM pragma_table_info(?1, ?2)")?;
+            let mut missing = Vec::new();
+            for (table, required) in query_factory.required_columns() {
+                let columns = statement
+                    .query_map((table.as_str(), table.schema()), |row| {
+                        row.get::<_, String>(0)
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                // tables that do not exist yet are created below
+                if columns.is_empty() {
+                    continue;
+                }
+                for column in required {
+                    if !columns.iter().any(|found| found == column) {
+                        missing.push(format!("{table}.{column}"));
+                    }
+                }
+            }
+            Ok(missing)
+        })?;
+        if !missing.is_empty() {
+            return Err(SqliteAggregateError::UnknownError(
+                format!("missing columns: {}", missing.join(", ")).into(),
+            ));
+        }
+        let config = self.store_config();
+        with_checked_connection(self.pool(), |connection| {
+            connection.execute_batch(&create_store_config_table())
+        })?;
+        verify_store_config(&*self.pool().connection()?, &config)?;
+        let mut schema = query_factory.create_schema();
+        if let Some(dead_letter_table) = self.stream_error_policy().create_dead_letter_table() {
+            schema.push_str(&dead_letter_table);
+        }
+        if let Some(conflicts_table) = self
+            .conflict_log()
+            .as_ref()
+            .and_then(|conflict_log| conflict_log.create_table())
+        {
+            schema.push_str(&conflicts_table);
+        }
+        with_checked_connection(self.pool(), |connection| connection.execute_batch(&schema))?;
+        Ok(ReadyRepository { repo: self })
+    }
+}
+
+impl<P> ReadyRepository<P> {
+    /// Returns the underlying repository.
+    pub fn into_inner(self) -> SqliteEventRepository<P> {
+        self.repo
+    }
+}
+
+impl<P> Deref for ReadyRepository<P> {
+    type Target = SqliteEventRepository<P>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.repo
+    }
+}
+
+#[async_trait]
+impl<P: ConnectionProvider> PersistedEventRepository for ReadyRepository<P> {
+    async fn get_events<A: Aggregate>(
+        &self,
+        aggregate_id: &str,
+    ) -> Result<Vec<SerializedEvent>, PersistenceError> {
+        self.repo.get_events::<A>(aggregate_id).await
+    }
+
+    async fn get_last_events<A: Aggregate>(
+        &self,
+        aggregate_id: &str,
+        last_sequence: usize,
+    ) -> Result<Vec<SerializedEvent>, PersistenceError> {
+        self.repo
+            .get_last_events::<A>(aggregate_id, last_sequence)
+            .await
+    }
+
+    async fn get_snapshot<A: Aggregate>(
+        &self,
+        aggregate_id: &str,
+    ) -> Result<Option<SerializedSnapshot>, PersistenceError> {
+        self.repo.get_snapshot::<A>(aggregate_id).await
+    }
+
+    async fn persist<A: Aggregate>(
+        &self,
+        events: &[SerializedEvent],
+        snapshot_update: Option<(String, Value, usize)>,
+    ) -> Result<(), PersistenceError> {
+        self.repo.persist::<A>(events, snapshot_update).await
+    }
+
+    async fn stream_events<A: Aggregate>(
+        &self,
+        aggregate_id: &str,
+    ) -> Result<ReplayStream, PersistenceError> {
+        self.repo.stream_events::<A>(aggregate_id).await
+    }
+
+    async fn stream_all_events<A: Aggregate>(&self) -> Result<ReplayStream, PersistenceError> {
+        self.repo.stream_all_events::<A>().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cqrs_es::persist::PersistedEventRepository;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, MetadataStorage, Partitioning, SqliteEventRepository};
+
+    #[tokio::test]
+    async fn ready_repository() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let repo = SqliteEventRepository::connect(pool.clone()).await.unwrap();
+        let id = uuid::Uuid::new_v4().to_string();
+        repo.persist::<TestAggregate>(
+            &[test_event_envelope(
+                &id,
+                1,
+                TestEvent::Created(Created { id: id.clone() }),
+            )],
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            1,
+            repo.get_events::<TestAggregate>(&id).await.unwrap().len()
+        );
+        assert_eq!(None, repo.get_snapshot::<TestAggregate>(&id).await.unwrap());
+
+        // the schema is only extended as required, once the new configuration is recorded
+        let reconfigured = SqliteEventRepository::new(pool.clone())
+            .with_metadata_storage(MetadataStorage::Dictionary)
+            .with_partitioning(Partitioning::Monthly);
+        reconfigured.record_store_config().unwrap();
+        reconfigured.ready().await.unwrap();
+
+        let outdated =
+            SqliteEventRepository::new(pool.clone()).with_tables("old_events", "snapshots");
+        pool.get()
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE old_events (aggregate_type text, aggregate_id text, sequence bigint, \
+                 event_type text, event_version text, payload json, metadata json)",
+            )
+            .unwrap();
+        let err = outdated.ready().await.err().unwrap();
+        assert_eq!("missing columns: old_events.created_at", err.to_string());
+    }
+
+    #[tokio::test]
+    async fn cascading_deletes() {
+        let repo = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .with_cascading_deletes(true)
+            .ready()
+            .await
+            .unwrap();
+        let id = uuid::Uuid::new_v4().to_string();
+        let created = test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        let tested = test_event_envelope(
+            &id,
+            2,
+            TestEvent::Tested(Tested {
+                test_name: "deleted".to_string(),
+            }),
+        );
+        let snapshot = serde_json::to_value(TestAggregate::default()).unwrap();
+        repo.persist::<TestAggregate>(&[created, tested], Some((id.clone(), snapshot, 1)))
+            .await
+            .unwrap();
+
+        let delete = |sequence: i64| {
+            repo.with_connection(|conn| {
+                conn.execute(
+                    "DELETE FROM events WHERE aggregate_id = ? AND sequence = ?",
+                    (&id, sequence),
+                )
+            })
+            .unwrap()
+        };
+        assert_eq!(1, delete(2));
+        assert!(repo
+            .get_snapshot::<TestAggregate>(&id)
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(1, delete(1));
+        assert_eq!(None, repo.get_snapshot::<TestAggregate>(&id).await.unwrap());
+
+        let attached = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .with_snapshot_schema("snapshot_db")
+            .with_cascading_deletes(true);
+        assert!(attached.ready().await.is_err());
+    }
+}
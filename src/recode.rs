@@ -0,0 +1,267 @@
+use rusqlite::OptionalExtension;
+use serde_json::Value;
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::metadata_dictionary::intern_metadata;
+use crate::{HashChain, MetadataCodec, MetadataStorage, Partitioning, SqliteEventRepository};
+
+/// The table tracking the progress of `SqliteEventRepository::recode_store`.
+pub const RECODE_PROGRESS_TABLE: &str = "recode_progress";
+
+/// The progress of recoding an events table into a codec, see
+/// `SqliteEventRepository::recode_progress`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecodeProgress {
+    /// The position of the last event recoded.
+    pub position: i64,
+    /// The number of events recoded so far, across all runs.
+    pub recoded: usize,
+    /// When the last batch was recoded.
+    pub updated_at: String,
+}
+
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    /// Rewrites the metadata of the stored events from the repository's metadata codec, if any,
+    /// into `target_codec`, returning the number of events recoded by this run.
+    ///
+    /// Events are recoded in order of their position in batches of `batch_size`, each within
+    /// its own transaction, so this may run in the background alongside commits. The position
+    /// reached is recorded in the `recode_progress` table along with each batch, a run that was
+    /// interrupted, e.g. by a crash, continues where it stopped when started again. Events
+    /// committed while the store is recoded are still stored with the repository's codec, so
+    /// the store should be recoded once more right before the repository is switched to
+    /// `target_codec` and its `StoreConfig` recorded; that final run only recodes the events
+    /// committed since the previous run. Recoding is refused in a store configured with a
+    /// `HashChain`, it would break the chain.
+    ///
+    /// Only metadata is recoded. Payloads have no pluggable codec: they are stored in the
+    /// repository's `JsonEncoding`, and since rows of either encoding are read, switching it
+    /// with `with_json_encoding` requires no rewrite of the stored events.
+    ///
+    /// While the store is being recoded it holds metadata in both codecs, and the repository,
+    /// still configured with its previous codec, decodes the events already recoded with that
+    /// codec: their metadata is read in whatever form the previous codec makes of the target
+    /// codec's, or fails to decode. Until the repository is switched to `target_codec`, reads
+    /// relying on metadata should therefore be paused, unless the previous codec decodes both
+    /// forms alike. Commits are unaffected.
+    ///
+    /// ```
+    /// use rusqlite_es::{SqliteAggregateError, SqliteEventRepository, TypedMetadata};
+    /// # #[derive(serde::Serialize, serde::Deserialize)]
+    /// # struct RequestMetadata { user_id: String }
+    ///
+    /// async fn migrate(repo: SqliteEventRepository) -> Result<(), SqliteAggregateError> {
+    ///     let target = TypedMetadata::<RequestMetadata>::new();
+    ///     repo.recode_store(&target, 1000).await?;
+    ///     repo.with_metadata_codec(Box::new(target))
+    ///         .record_store_config()
+    /// }
+    /// ```
+    pub async fn recode_store(
+        &self,
+        target_codec: &dyn MetadataCodec,
+        batch_size: usize,
+    ) -> Result<usize, SqliteAggregateError> {
+        let query_factory = self.query_factory();
+        if query_factory.partitioning() != Partitioning::None {
+            return Err(SqliteAggregateError::UnknownError(
+                "a partitioned store cannot be recoded".into(),
+            ));
+        }
+        // the recoded metadata would no longer match the hashes of the events
+        if query_factory.hash_chain() != HashChain::None {
+            return Err(SqliteAggregateError::UnknownError(
+                "a store with a hash chain cannot be recoded".into(),
+            ));
+        }
+        let batch_size = batch_size.max(1);
+        let events_table = query_factory.event_table().to_string();
+        let codec = target_codec.name();
+        self.write_async(|tx| Ok(tx.execute_batch(&create_recode_progress_table())?))
+            .await?;
+        let mut recoded = 0;
+        loop {
+            let batch = self.write_async(|tx| {
+                let position: i64 = tx
+                    .query_row(
+                        &format!("SELECT position FROM {RECODE_PROGRESS_TABLE} WHERE events_table = ? AND codec = ?"),
+                        (&events_table, codec),
+                        |row| row.get(0),
+                    )
+                    .optional()?
+                    .unwrap_or(0);
+                let rows = tx
+                    .prepare_cached(query_factory.select_metadata_after())?
+                    .query_map((position, batch_size as i64), |row| {
+                        Ok((row.get::<_, i64>(0)?, row.get::<_, Value>(1)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                let Some((last_position, _)) = rows.last() else {
+                    return Ok(0);
+                };
+                let mut update = tx.prepare_cached(query_factory.update_metadata())?;
+                for (position, metadata) in &rows {
+                    let metadata = match self.metadata_codec() {
+                        Some(metadata_codec) => metadata_codec.decode(metadata.clone())?,
+                        None => metadata.clone(),
+                    };
+                    let mut metadata = target_codec.encode(&metadata)?;
+                    if query_factory.metadata_storage() == MetadataStorage::Dictionary {
+                        metadata = intern_metadata(tx, query_factory, &metadata)?;
+                    }
+                    update.execute((metadata, position))?;
+                }
+                tx.execute(
+                    &format!(
+                        "INSERT INTO {RECODE_PROGRESS_TABLE} (events_table, codec, position, recoded) VALUES (?1, ?2, ?3, ?4)
+  ON CONFLICT (events_table, codec) DO UPDATE SET position = ?3, recoded = recoded + ?4, updated_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now')"
+                    ),
+                    (&events_table, codec, last_position, rows.len() as i64),
+                )?;
+                Ok(rows.len())
+            }).await?;
+            recoded += batch;
+            if batch < batch_size {
+                return Ok(recoded);
+            }
+        }
+    }
+
+    /// Returns the progress of recoding the events table into the codec named `codec`, see
+    /// `recode_store` and `MetadataCodec::name`, or `None` if it was never recoded into it.
+    pub fn recode_progress(
+        &self,
+        codec: &str,
+    ) -> Result<Option<RecodeProgress>, SqliteAggregateError> {
+        let connection = self.pool().connection()?;
+        connection.execute_batch(&create_recode_progress_table())?;
+        let progress = connection
+            .query_row(
+                &format!("SELECT position, recoded, updated_at FROM {RECODE_PROGRESS_TABLE} WHERE events_table = ? AND codec = ?"),
+                (self.query_factory().event_table().to_string(), codec),
+                |row| {
+                    Ok(RecodeProgress {
+                        position: row.get(0)?,
+                        recoded: row.get::<_, i64>(1)? as usize,
+                        updated_at: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(progress)
+    }
+}
+
+fn create_recode_progress_table() -> String {
+    format!(
+        "CREATE TABLE IF NOT EXISTS {RECODE_PROGRESS_TABLE}
+(
+    events_table text    NOT NULL,
+    codec        text    NOT NULL,
+    position     integer NOT NULL,
+    recoded      integer NOT NULL,
+    updated_at   text    NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    PRIMARY KEY (events_table, codec)
+);
+"
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, TEST_CONNECTION_STRING,
+    };
+    use crate::{
+        default_sqlite_pool, HashChain, MetadataCodec, SqliteEventRepository, TypedMetadata,
+    };
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct RequestMetadata {
+        user_id: String,
+        attempt: u32,
+    }
+
+    #[tokio::test]
+    async fn recode_store() {
+        let repo = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .with_tables("recoded_events", "recoded_snapshots")
+            .ready()
+            .await
+            .unwrap()
+            .into_inner();
+        let target = TypedMetadata::<RequestMetadata>::new();
+        let before = repo
+            .recode_progress(target.name())
+            .unwrap()
+            .map_or(0, |progress| progress.recoded);
+        let ids = (0..3)
+            .map(|_| uuid::Uuid::new_v4().to_string())
+            .collect::<Vec<_>>();
+        for id in &ids {
+            let mut created =
+                test_event_envelope(id, 1, TestEvent::Created(Created { id: id.clone() }));
+            created.metadata = json!({"user_id": "alice", "attempt": "2"});
+            repo.persist::<TestAggregate>(&[created], None)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(3, repo.recode_store(&target, 2).await.unwrap());
+        // a later run only recodes events committed since
+        assert_eq!(0, repo.recode_store(&target, 2).await.unwrap());
+        let progress = repo.recode_progress(target.name()).unwrap().unwrap();
+        assert_eq!(before + 3, progress.recoded);
+
+        let stored: serde_json::Value = repo
+            .with_connection(|conn| {
+                conn.query_row(
+                    "SELECT metadata FROM recoded_events WHERE aggregate_id = ?",
+                    [&ids[0]],
+                    |row| row.get(0),
+                )
+            })
+            .unwrap();
+        assert_eq!(json!({"user_id": "alice", "attempt": 2}), stored);
+        let recoded = repo.with_metadata_codec(Box::new(target));
+        let events = recoded.get_events::<TestAggregate>(&ids[2]).await.unwrap();
+        let metadata: HashMap<String, String> =
+            serde_json::from_value(events[0].metadata.clone()).unwrap();
+        assert_eq!(
+            RequestMetadata {
+                user_id: "alice".to_string(),
+                attempt: 2,
+            },
+            TypedMetadata::from_map(&metadata).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn recode_refused_with_hash_chain() {
+        let repo = SqliteEventRepository::new(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .with_hash_chain(HashChain::Global)
+            .ready()
+            .await
+            .unwrap()
+            .into_inner();
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = json!({"user_id": "alice", "attempt": "2"});
+        repo.persist::<TestAggregate>(&[created], None)
+            .await
+            .unwrap();
+        assert!(repo
+            .recode_store(&TypedMetadata::<RequestMetadata>::new(), 10)
+            .await
+            .is_err());
+        assert!(repo.verify_hash_chain().await.unwrap().is_ok());
+    }
+}
@@ -0,0 +1,268 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Reports the progress of long-running replays and allows them to be cancelled.
+///
+/// A repository configured with a `ReplayProgress` (see
+/// `SqliteEventRepository::with_replay_progress`) updates it as events are read by
+/// `stream_all_events`, and therefore by `QueryReplay::replay_all`, and by
+/// `stream_everything`. The total is counted when a replay starts.
+///
+/// Each replay keeps its own counts, so replays running concurrently through the same
+/// repository, e.g. of two aggregate types, do not overwrite each other's: the report sums them
+/// up. A replay starting once all earlier ones have completed resets the counts.
+///
+/// A `ReplayProgress` is cheap to clone, all clones share the same state, so one clone can be
+/// polled or cancelled from another task while the replay runs. A cancelled replay stops before
+/// its next event is read and ends with `SqliteAggregateError::ReplayCancelled`, as do all other
+/// replays reporting to the handle. Cancellation is permanent, use a new `ReplayProgress` for
+/// subsequent replays.
+///
+/// ```
+/// use rusqlite_es::ReplayProgress;
+///
+/// fn report(progress: &ReplayProgress) {
+///     let report = progress.report();
+///     println!(
+///         "{} of {:?} events, {:.0} events/s, eta {:?}, at {:?}",
+///         report.processed, report.total, report.rate, report.eta, report.current_aggregate
+///     );
+/// }
+/// ```
+#[derive(Clone, Default)]
+pub struct ReplayProgress {
+    inner: Arc<ProgressState>,
+}
+
+#[derive(Default)]
+struct ProgressState {
+    cancelled: AtomicBool,
+    // the replays started since all earlier ones completed, in the order they were started
+    runs: Mutex<Vec<Arc<RunState>>>,
+}
+
+struct RunState {
+    finished: AtomicBool,
+    counts: Mutex<ProgressCounts>,
+}
+
+struct ProgressCounts {
+    started: Instant,
+    total: usize,
+    processed: usize,
+    current_aggregate: Option<String>,
+}
+
+/// A single replay reporting to a `ReplayProgress`, it completes when dropped.
+pub(crate) struct ReplayRun {
+    progress: ReplayProgress,
+    state: Arc<RunState>,
+}
+
+/// A point-in-time view of a replay's progress, see `ReplayProgress::report`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgressReport {
+    /// The number of events read so far.
+    pub processed: usize,
+    /// The number of events the replay will read, once the replay has started.
+    pub total: Option<usize>,
+    /// The average number of events read per second since the replay started.
+    pub rate: f64,
+    /// The estimated time until the replay completes, once an estimate is possible.
+    pub eta: Option<Duration>,
+    /// The id of the aggregate instance whose event was read most recently, by the replay
+    /// started last if several are running.
+    pub current_aggregate: Option<String>,
+}
+
+impl ReplayProgress {
+    /// Creates a new `ReplayProgress` handle.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Requests that the replay stops before reading its next event.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true once cancellation has been requested.
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Returns the current progress of the replays, summed up if several are running.
+    pub fn report(&self) -> ProgressReport {
+        let runs = self.inner.runs.lock().unwrap();
+        let mut started: Option<Instant> = None;
+        let mut total = None;
+        let mut processed = 0;
+        let mut current_aggregate = None;
+        for run in runs.iter() {
+            let counts = run.counts.lock().unwrap();
+            started = Some(started.map_or(counts.started, |started| started.min(counts.started)));
+            total = Some(total.unwrap_or(0) + counts.total);
+            processed += counts.processed;
+            if counts.current_aggregate.is_some() {
+                current_aggregate.clone_from(&counts.current_aggregate);
+            }
+        }
+        let elapsed = started.map_or(0.0, |started| started.elapsed().as_secs_f64());
+        let rate = if elapsed > 0.0 {
+            processed as f64 / elapsed
+        } else {
+            0.0
+        };
+        let eta = match total {
+            Some(total) if rate > 0.0 => Some(Duration::from_secs_f64(
+                total.saturating_sub(processed) as f64 / rate,
+            )),
+            _ => None,
+        };
+        ProgressReport {
+            processed,
+            total,
+            rate,
+            eta,
+            current_aggregate,
+        }
+    }
+
+    // Starts reporting a replay of `total` events, discarding the counts of earlier replays if
+    // all have completed.
+    pub(crate) fn start(&self, total: usize) -> ReplayRun {
+        let state = Arc::new(RunState {
+            finished: AtomicBool::new(false),
+            counts: Mutex::new(ProgressCounts {
+                started: Instant::now(),
+                total,
+                processed: 0,
+                current_aggregate: None,
+            }),
+        });
+        let mut runs = self.inner.runs.lock().unwrap();
+        if runs.iter().all(|run| run.finished.load(Ordering::SeqCst)) {
+            runs.clear();
+        }
+        runs.push(state.clone());
+        ReplayRun {
+            progress: self.clone(),
+            state,
+        }
+    }
+}
+
+impl ReplayRun {
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.progress.is_cancelled()
+    }
+
+    pub(crate) fn record(&self, aggregate_id: &str) {
+        let mut counts = self.state.counts.lock().unwrap();
+        counts.processed += 1;
+        if counts.current_aggregate.as_deref() != Some(aggregate_id) {
+            counts.current_aggregate = Some(aggregate_id.to_string());
+        }
+    }
+}
+
+impl Drop for ReplayRun {
+    fn drop(&mut self) {
+        self.state.finished.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use cqrs_es::persist::{PersistedEventRepository, PersistenceError};
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, ReplayProgress, SqliteAggregateError, SqliteEventRepository};
+
+    #[tokio::test]
+    async fn replay_progress() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let progress = ReplayProgress::new();
+        let event_repo = SqliteEventRepository::new(pool).with_replay_progress(progress.clone());
+        assert_eq!(None, progress.report().total);
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = serde_json::json!({});
+        let mut tested = test_event_envelope(
+            &id,
+            2,
+            TestEvent::Tested(Tested {
+                test_name: "replayed".to_string(),
+            }),
+        );
+        tested.metadata = serde_json::json!({});
+        event_repo
+            .insert_events::<TestAggregate>(&[created, tested])
+            .await
+            .unwrap();
+
+        let mut stream = event_repo
+            .stream_all_events::<TestAggregate>()
+            .await
+            .unwrap();
+        while let Some(event) = stream.next::<TestAggregate>(&None).await {
+            event.unwrap();
+        }
+        let report = progress.report();
+        assert_eq!(2, report.processed);
+        assert_eq!(Some(2), report.total);
+        assert_eq!(Some(id.as_str()), report.current_aggregate.as_deref());
+
+        progress.cancel();
+        let mut stream = event_repo.stream_everything().await.unwrap();
+        match stream.next().await {
+            Some(Err(PersistenceError::UnknownError(err))) => assert!(matches!(
+                err.downcast_ref::<SqliteAggregateError>(),
+                Some(SqliteAggregateError::ReplayCancelled)
+            )),
+            _ => panic!("expected the replay to be cancelled"),
+        }
+        assert!(stream.next().await.is_none());
+        assert_eq!(0, progress.report().processed);
+    }
+
+    #[test]
+    fn concurrent_replays() {
+        let progress = ReplayProgress::new();
+        let first = progress.start(3);
+        first.record("a");
+        let second = progress.start(2);
+        second.record("b");
+        first.record("a");
+        let report = progress.report();
+        assert_eq!(3, report.processed);
+        assert_eq!(Some(5), report.total);
+        assert_eq!(Some("b"), report.current_aggregate.as_deref());
+
+        // a replay starting while another runs keeps the counts of those completed meanwhile
+        drop(first);
+        let third = progress.start(1);
+        assert_eq!(Some(6), progress.report().total);
+
+        // a replay starting once all have completed resets the counts
+        drop(second);
+        drop(third);
+        let fourth = progress.start(4);
+        fourth.record("c");
+        let report = progress.report();
+        assert_eq!(1, report.processed);
+        assert_eq!(Some(4), report.total);
+    }
+}
@@ -0,0 +1,137 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use std::future::Future;
+
+tokio::task_local! {
+    static REQUEST_SCOPE: Arc<Mutex<ScopeStats>>;
+}
+
+/// The statements run within a request scope, see `with_request_scope`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScopeStats {
+    /// The number of statements run.
+    pub statements: usize,
+    /// The number of rows read or written by the statements.
+    pub rows: usize,
+    /// The time spent running the statements.
+    pub duration: Duration,
+}
+
+/// Runs a logical operation, e.g. handling a request or dispatching events to a query, and
+/// counts the statements run by the repositories on its behalf, the rows they read or wrote and
+/// the time spent in SQLite. This helps to spot N+1 patterns, e.g. a projection loading a view
+/// per event.
+///
+/// The counts are returned along with the operation's result and emitted as a `tracing` debug
+/// event with the target `rusqlite_es::request_scope` and the fields `scope`, `statements`,
+/// `rows` and `duration_us`. Counted are the statements loading and committing events and
+/// snapshots, `query_events` and loading and updating views; statements run through
+/// `with_connection` or by event streams, which run on their own tasks, are not. Scopes may be
+/// nested, statements are counted by the innermost scope only.
+///
+/// ```
+/// # use cqrs_es::doc::MyAggregate;
+/// # use cqrs_es::persist::doc::MyView;
+/// use cqrs_es::persist::ViewRepository;
+/// use rusqlite_es::{with_request_scope, SqliteViewRepository};
+///
+/// async fn load_views(view_repo: &SqliteViewRepository<MyView, MyAggregate>, ids: &[&str]) {
+///     let (_, stats) = with_request_scope("load_views", || async {
+///         for id in ids {
+///             view_repo.load(id).await.ok();
+///         }
+///     })
+///     .await;
+///     assert!(stats.statements <= ids.len());
+/// }
+/// ```
+pub async fn with_request_scope<F, Fut, T>(scope: &str, operation: F) -> (T, ScopeStats)
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = T>,
+{
+    let counts = Arc::new(Mutex::new(ScopeStats::default()));
+    let result = REQUEST_SCOPE.scope(counts.clone(), operation()).await;
+    let stats = *counts.lock().unwrap();
+    tracing::debug!(
+        target: "rusqlite_es::request_scope",
+        scope,
+        statements = stats.statements,
+        rows = stats.rows,
+        duration_us = stats.duration.as_micros() as u64,
+        "request scope"
+    );
+    (result, stats)
+}
+
+// Returns true if running within `with_request_scope`.
+pub(crate) fn in_request_scope() -> bool {
+    REQUEST_SCOPE.try_with(|_| ()).is_ok()
+}
+
+// Counts a statement towards the current request scope, if any.
+pub(crate) fn record_statement(rows: usize, duration: Duration) {
+    let _ = REQUEST_SCOPE.try_with(|counts| {
+        let mut counts = counts.lock().unwrap();
+        counts.statements += 1;
+        counts.rows += rows;
+        counts.duration += duration;
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use cqrs_es::persist::{PersistedEventRepository, ViewRepository};
+    use serde_json::json;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, TestView, TEST_CONNECTION_STRING,
+    };
+    use crate::{
+        default_sqlite_pool, with_request_scope, SqliteEventRepository, SqliteViewRepository,
+    };
+
+    #[tokio::test]
+    async fn request_scope() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+        let repo = SqliteEventRepository::new(pool.clone());
+        let view_repo = SqliteViewRepository::<TestView, TestAggregate>::new("test_view", pool);
+        let id = uuid::Uuid::new_v4().to_string();
+
+        let (_, stats) = with_request_scope("commit", || async {
+            let mut created =
+                test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+            created.metadata = json!({});
+            repo.persist::<TestAggregate>(&[created], None)
+                .await
+                .unwrap();
+            repo.get_events::<TestAggregate>(&id).await.unwrap();
+        })
+        .await;
+        assert_eq!(2, stats.statements);
+        assert_eq!(2, stats.rows);
+
+        let (loaded, stats) = with_request_scope("load", || async {
+            let (nested, nested_stats) =
+                with_request_scope("nested", || async { view_repo.load(&id).await.unwrap() }).await;
+            assert_eq!(1, nested_stats.statements);
+            view_repo.load(&id).await.unwrap();
+            view_repo.load(&id).await.unwrap();
+            nested
+        })
+        .await;
+        assert!(loaded.is_none());
+        assert_eq!(2, stats.statements);
+        assert_eq!(0, stats.rows);
+
+        // outside of a scope, nothing is counted
+        repo.get_events::<TestAggregate>(&id).await.unwrap();
+    }
+}
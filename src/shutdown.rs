@@ -0,0 +1,201 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout_at, Instant};
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+
+/// Coordinates the graceful shutdown of background work started by the repositories.
+///
+/// A `Shutdown` is cheap to clone, all clones share the same state. Repositories configured
+/// with a `Shutdown` register their background tasks (e.g., event streams) with it so that
+/// `shutdown` can stop them, wait for them to complete and then leave the database in a tidy
+/// state.
+///
+/// ```
+/// use std::time::Duration;
+/// use rusqlite_es::{default_sqlite_pool, Shutdown, SqliteEventRepository};
+///
+/// # async fn run() {
+/// let pool = default_sqlite_pool("shutdown_example.db");
+/// let shutdown = Shutdown::new();
+/// let repo = SqliteEventRepository::new(pool.clone()).with_shutdown(shutdown.clone());
+/// // ... run the application ...
+/// shutdown
+///     .shutdown(pool, Duration::from_secs(10))
+///     .await
+///     .expect("shutdown failed");
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    inner: Arc<ShutdownState>,
+}
+
+#[derive(Default)]
+struct ShutdownState {
+    requested: AtomicBool,
+    notify: Notify,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Shutdown {
+    /// Creates a new `Shutdown` handle.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns true once shutdown has been requested.
+    pub fn is_requested(&self) -> bool {
+        self.inner.requested.load(Ordering::SeqCst)
+    }
+
+    /// Completes once shutdown has been requested. Application background loops, e.g. pollers,
+    /// can `select!` on this to stop alongside the repositories.
+    pub async fn requested(&self) {
+        loop {
+            let notified = self.inner.notify.notified();
+            if self.is_requested() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Registers a background task that should be completed before shutdown finishes.
+    pub fn track(&self, task: JoinHandle<()>) {
+        let mut tasks = self.inner.tasks.lock().unwrap();
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(task);
+    }
+
+    /// Requests shutdown and waits up to `timeout` for all tracked tasks to complete, then
+    /// performs a final WAL checkpoint (truncating the WAL file) and drops the provided handle
+    /// of the connection pool. The pool's connections are closed once all of its clones, e.g.
+    /// those held by the repositories, are dropped.
+    ///
+    /// Streams stop before their next event is read, a stream whose buffer is full will only
+    /// stop once its consumer reads from it or drops it. Tasks that have not completed within
+    /// `timeout` are aborted if they have not started yet and otherwise left to complete on
+    /// their own, and shutdown fails without a checkpoint, which the streams still reading
+    /// would prevent from truncating the WAL file.
+    pub async fn shutdown<P: ConnectionProvider>(
+        &self,
+        pool: P,
+        timeout: Duration,
+    ) -> Result<(), SqliteAggregateError> {
+        self.inner.requested.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+        let deadline = Instant::now() + timeout;
+        loop {
+            let task = self.inner.tasks.lock().unwrap().pop();
+            match task {
+                Some(mut task) => match timeout_at(deadline, &mut task).await {
+                    Ok(result) => {
+                        result.map_err(|err| SqliteAggregateError::UnknownError(Box::new(err)))?
+                    }
+                    Err(_) => {
+                        let mut tasks = self.inner.tasks.lock().unwrap();
+                        tasks.push(task);
+                        let remaining = tasks
+                            .drain(..)
+                            .filter(|task| !task.is_finished())
+                            .inspect(JoinHandle::abort)
+                            .count();
+                        return Err(SqliteAggregateError::UnknownError(
+                            format!(
+                                "{remaining} background tasks did not complete within {timeout:?}"
+                            )
+                            .into(),
+                        ));
+                    }
+                },
+                None => break,
+            }
+        }
+        tokio::task::spawn_blocking(move || {
+            let connection = pool.connection()?;
+            connection.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+            Ok(())
+        })
+        .await
+        .map_err(|err| SqliteAggregateError::UnknownError(Box::new(err)))?
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::time::Duration;
+
+    use crate::testing::tests::{
+        test_event_envelope, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, Shutdown, SqliteEventRepository};
+
+    #[tokio::test]
+    async fn shutdown_stops_streams() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let shutdown = Shutdown::new();
+        let repo = SqliteEventRepository::new(pool.clone()).with_shutdown(shutdown.clone());
+        let mut stream = repo.stream_everything().await.unwrap();
+        assert!(stream.next().await.is_some());
+
+        let waiting = shutdown.clone();
+        let requested = tokio::spawn(async move { waiting.requested().await });
+        shutdown
+            .shutdown(pool, Duration::from_secs(10))
+            .await
+            .unwrap();
+        requested.await.unwrap();
+        assert!(shutdown.is_requested());
+
+        let mut stream = repo.stream_everything().await.unwrap();
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn shutdown_times_out() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let shutdown = Shutdown::new();
+        let repo = SqliteEventRepository::new(pool.clone())
+            .with_shutdown(shutdown.clone())
+            .with_streaming_channel_size(1);
+        let id = uuid::Uuid::new_v4().to_string();
+        let events = (1..=4)
+            .map(|sequence| {
+                test_event_envelope(
+                    &id,
+                    sequence,
+                    TestEvent::Tested(Tested {
+                        test_name: format!("test {sequence}"),
+                    }),
+                )
+            })
+            .collect::<Vec<_>>();
+        repo.insert_events::<TestAggregate>(&events).await.unwrap();
+
+        // the stream's buffer is full and it is not consumed, it cannot stop
+        let stream = repo.stream_everything().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(shutdown
+            .shutdown(pool, Duration::from_millis(50))
+            .await
+            .is_err());
+        drop(stream);
+    }
+}
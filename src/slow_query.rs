@@ -0,0 +1,183 @@
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::request_scope::{in_request_scope, record_statement};
+
+/// The placeholder logged in place of event, snapshot and view payloads and of metadata.
+pub const REDACTED_PARAM: &str = "<redacted>";
+
+type SlowQueryHandler = dyn Fn(&SlowQuery) + Send + Sync;
+
+/// Reports repository queries that take longer than a threshold, e.g. to find view queries
+/// missing an index, see `SqliteEventRepository::with_slow_query_log` and
+/// `SqliteViewRepository::with_slow_query_log`.
+///
+/// By default each slow query is emitted as a `tracing` warning with the target
+/// `rusqlite_es::slow_query` and the fields `sql`, `params`, `duration_ms` and `rows`.
+///
+/// ```
+/// use std::time::Duration;
+/// use rusqlite_es::SlowQueryLog;
+///
+/// let log = SlowQueryLog::new(Duration::from_millis(50))
+///     .with_handler(|query| eprintln!("{:?} took {:?}", query.sql, query.duration));
+/// ```
+#[derive(Clone)]
+pub struct SlowQueryLog {
+    threshold: Duration,
+    handler: Option<Arc<SlowQueryHandler>>,
+}
+
+/// A query that exceeded the threshold of a `SlowQueryLog`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowQuery {
+    /// The SQL of the query.
+    pub sql: String,
+    /// The parameters of the query in order, with payloads and metadata redacted.
+    pub params: Vec<String>,
+    /// How long the query took.
+    pub duration: Duration,
+    /// The number of rows read or written.
+    pub rows: usize,
+}
+
+impl SlowQueryLog {
+    /// Creates a log of queries taking longer than `threshold`.
+    pub fn new(threshold: Duration) -> Self {
+        Self {
+            threshold,
+            handler: None,
+        }
+    }
+
+    /// Calls `handler` with each slow query instead of emitting a `tracing` warning.
+    pub fn with_handler<F>(self, handler: F) -> Self
+    where
+        F: Fn(&SlowQuery) + Send + Sync + 'static,
+    {
+        Self {
+            handler: Some(Arc::new(handler)),
+            ..self
+        }
+    }
+
+    fn report(&self, query: SlowQuery) {
+        match &self.handler {
+            Some(handler) => handler(&query),
+            None => tracing::warn!(
+                target: "rusqlite_es::slow_query",
+                sql = %query.sql,
+                params = ?query.params,
+                duration_ms = query.duration.as_millis() as u64,
+                rows = query.rows,
+                "slow query"
+            ),
+        }
+    }
+}
+
+impl Debug for SlowQueryLog {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SlowQueryLog")
+            .field("threshold", &self.threshold)
+            .finish_non_exhaustive()
+    }
+}
+
+// Runs a query, reporting it to the log if it takes longer than the threshold and counting it
+// towards the current request scope. `rows` counts the rows read or written by the query's
+// result.
+pub(crate) fn timed<T, E>(
+    log: &Option<SlowQueryLog>,
+    sql: &str,
+    params: &[&str],
+    query: impl FnOnce() -> Result<T, E>,
+    rows: impl FnOnce(&T) -> usize,
+) -> Result<T, E> {
+    if log.is_none() && !in_request_scope() {
+        return query();
+    }
+    let started = Instant::now();
+    let result = query()?;
+    let duration = started.elapsed();
+    let rows = rows(&result);
+    record_statement(rows, duration);
+    match log {
+        Some(log) if duration >= log.threshold => log.report(SlowQuery {
+            sql: sql.trim().to_string(),
+            params: params.iter().map(|param| param.to_string()).collect(),
+            duration,
+            rows,
+        }),
+        _ => {}
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use cqrs_es::persist::{PersistedEventRepository, ViewContext, ViewRepository};
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, TestView, TEST_CONNECTION_STRING,
+    };
+    use crate::{
+        default_sqlite_pool, SlowQuery, SlowQueryLog, SqliteEventRepository, SqliteViewRepository,
+        REDACTED_PARAM,
+    };
+
+    #[tokio::test]
+    async fn slow_query_log() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let logged: Arc<Mutex<Vec<SlowQuery>>> = Default::default();
+        let reported = logged.clone();
+        // every query exceeds a zero threshold
+        let log = SlowQueryLog::new(Duration::ZERO)
+            .with_handler(move |query| reported.lock().unwrap().push(query.clone()));
+        let event_repo = SqliteEventRepository::new(pool.clone()).with_slow_query_log(log.clone());
+        let view_repo = SqliteViewRepository::<TestView, TestAggregate>::new("test_view", pool)
+            .with_slow_query_log(log);
+
+        let id = uuid::Uuid::new_v4().to_string();
+        event_repo
+            .persist::<TestAggregate>(
+                &[test_event_envelope(
+                    &id,
+                    1,
+                    TestEvent::Created(Created { id: id.clone() }),
+                )],
+                None,
+            )
+            .await
+            .unwrap();
+        event_repo.get_events::<TestAggregate>(&id).await.unwrap();
+        view_repo
+            .update_view(TestView { events: vec![] }, ViewContext::new(id.clone(), 0))
+            .await
+            .unwrap();
+        view_repo.load(&id).await.unwrap();
+
+        let logged = logged.lock().unwrap();
+        assert_eq!(4, logged.len());
+        assert!(logged[0].sql.starts_with("INSERT INTO events"));
+        assert_eq!(REDACTED_PARAM, logged[0].params[5]);
+        assert_eq!(1, logged[0].rows);
+        assert!(logged[1].sql.starts_with("SELECT"));
+        assert_eq!(vec!["TestAggregate", id.as_str()], logged[1].params);
+        assert_eq!(1, logged[1].rows);
+        assert!(logged[2].sql.starts_with("INSERT INTO test_view"));
+        assert_eq!(vec![REDACTED_PARAM, "1", id.as_str()], logged[2].params);
+        assert_eq!(vec![id.as_str()], logged[3].params);
+        assert_eq!(1, logged[3].rows);
+    }
+}
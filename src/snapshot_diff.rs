@@ -0,0 +1,238 @@
+use cqrs_es::persist::{PersistedEventRepository, PersistenceError, SerializedEvent};
+use cqrs_es::{Aggregate, EventEnvelope};
+use serde_json::Value;
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::SqliteEventRepository;
+
+/// The outcome of `SqliteEventRepository::diff_snapshot_vs_replay`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotDiff {
+    /// The sequence of the last event included in the snapshot, or `None` if the aggregate
+    /// instance has no usable snapshot.
+    pub snapshot_sequence: Option<usize>,
+    /// The sequence of the last event of the aggregate instance.
+    pub last_sequence: usize,
+    /// Where the aggregate hydrated from its snapshot differs from the aggregate replayed from
+    /// all of its events, empty if they are identical.
+    pub differences: Vec<SnapshotDifference>,
+}
+
+/// A value that differs between an aggregate hydrated from its snapshot and one replayed from
+/// its events, see `SnapshotDiff`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotDifference {
+    /// The JSON pointer of the value within the serialized aggregate, e.g. `/tests/0`.
+    pub path: String,
+    /// The value hydrated from the snapshot and its tail events, `None` if absent.
+    pub snapshot: Option<Value>,
+    /// The value replayed from all events, `None` if absent.
+    pub replay: Option<Value>,
+}
+
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    /// Hydrates an aggregate instance both from its snapshot followed by the events committed
+    /// since, and from a full replay of its events, and compares the two serialized aggregates.
+    ///
+    /// Differences reveal snapshot drift, e.g. a bug in `Aggregate::apply` that has since been
+    /// fixed, or an event that was rewritten after the snapshot was taken. Registered snapshot
+    /// upcasters are applied, but the snapshot is not rewritten even if it is unusable.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// use rusqlite_es::SqliteEventRepository;
+    ///
+    /// async fn report_drift(repo: &SqliteEventRepository, id: &str) {
+    ///     let diff = repo.diff_snapshot_vs_replay::<MyAggregate>(id).await.unwrap();
+    ///     for difference in diff.differences {
+    ///         println!(
+    ///             "{}: {:?} in the snapshot, {:?} when replayed",
+    ///             difference.path, difference.snapshot, difference.replay
+    ///         );
+    ///     }
+    /// }
+    /// ```
+    pub async fn diff_snapshot_vs_replay<A: Aggregate>(
+        &self,
+        aggregate_id: &str,
+    ) -> Result<SnapshotDiff, PersistenceError> {
+        let snapshot = match self.select_snapshot::<A>(aggregate_id)? {
+            Some((snapshot, aggregate_version)) => {
+                self.upcast_snapshot::<A>(snapshot, aggregate_version)
+            }
+            None => None,
+        };
+        let events = self.get_events::<A>(aggregate_id).await?;
+        let last_sequence = events.last().map_or(0, |event| event.sequence);
+        let replayed = serialize(replay(A::default(), events)?)?;
+
+        let Some(snapshot) = snapshot else {
+            return Ok(SnapshotDiff {
+                snapshot_sequence: None,
+                last_sequence,
+                differences: Vec::new(),
+            });
+        };
+        let aggregate: A = serde_json::from_value(snapshot.aggregate)
+            .map_err(|err| SqliteAggregateError::DeserializationError(Box::new(err)))?;
+        let tail = self
+            .get_last_events::<A>(aggregate_id, snapshot.current_snapshot)
+            .await?;
+        let hydrated = serialize(replay(aggregate, tail)?)?;
+
+        let mut differences = Vec::new();
+        diff_values("", Some(&hydrated), Some(&replayed), &mut differences);
+        Ok(SnapshotDiff {
+            snapshot_sequence: Some(snapshot.current_snapshot),
+            last_sequence,
+            differences,
+        })
+    }
+}
+
+// Applies the events to the aggregate in order.
+pub(crate) fn replay<A: Aggregate>(
+    mut aggregate: A,
+    events: Vec<SerializedEvent>,
+) -> Result<A, SqliteAggregateError> {
+    for event in events {
+        let envelope: EventEnvelope<A> = event
+            .try_into()
+            .map_err(|err| SqliteAggregateError::DeserializationError(Box::new(err)))?;
+        aggregate.apply(envelope.payload);
+    }
+    Ok(aggregate)
+}
+
+fn serialize<A: Aggregate>(aggregate: A) -> Result<Value, SqliteAggregateError> {
+    Ok(serde_json::to_value(aggregate)?)
+}
+
+// Appends the differences between two values to `differences`, descending into objects and
+// arrays present on both sides.
+fn diff_values(
+    path: &str,
+    snapshot: Option<&Value>,
+    replay: Option<&Value>,
+    differences: &mut Vec<SnapshotDifference>,
+) {
+    match (snapshot, replay) {
+        (Some(Value::Object(snapshot)), Some(Value::Object(replay))) => {
+            for (key, value) in snapshot {
+                diff_values(
+                    &child_path(path, key),
+                    Some(value),
+                    replay.get(key),
+                    differences,
+                );
+            }
+            for (key, value) in replay {
+                if !snapshot.contains_key(key) {
+                    diff_values(&child_path(path, key), None, Some(value), differences);
+                }
+            }
+        }
+        (Some(Value::Array(snapshot)), Some(Value::Array(replay))) => {
+            for index in 0..snapshot.len().max(replay.len()) {
+                diff_values(
+                    &child_path(path, &index.to_string()),
+                    snapshot.get(index),
+                    replay.get(index),
+                    differences,
+                );
+            }
+        }
+        (snapshot, replay) if snapshot != replay => differences.push(SnapshotDifference {
+            path: path.to_string(),
+            snapshot: snapshot.cloned(),
+            replay: replay.cloned(),
+        }),
+        _ => {}
+    }
+}
+
+// Escapes a key as a JSON pointer reference token (RFC 6901).
+fn child_path(path: &str, key: &str) -> String {
+    format!("{path}/{}", key.replace('~', "~0").replace('/', "~1"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde_json::json;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, SnapshotDifference, SqliteEventRepository};
+
+    #[tokio::test]
+    async fn diff_snapshot_vs_replay() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let repo = SqliteEventRepository::new(pool);
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = json!({});
+        repo.persist::<TestAggregate>(&[created], None)
+            .await
+            .unwrap();
+        let diff = repo
+            .diff_snapshot_vs_replay::<TestAggregate>(&id)
+            .await
+            .unwrap();
+        assert_eq!(None, diff.snapshot_sequence);
+        assert_eq!(1, diff.last_sequence);
+        assert!(diff.differences.is_empty());
+
+        // `TestAggregate::apply` ignores events, so a snapshot holding any state has drifted
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = json!({});
+        let drifted = json!({"id": id, "description": "", "tests": ["drift"]});
+        repo.persist::<TestAggregate>(&[created], Some((id.clone(), drifted, 1)))
+            .await
+            .unwrap();
+        let mut tested = test_event_envelope(
+            &id,
+            2,
+            TestEvent::Tested(Tested {
+                test_name: "drift".to_string(),
+            }),
+        );
+        tested.metadata = json!({});
+        repo.persist::<TestAggregate>(&[tested], None)
+            .await
+            .unwrap();
+        let diff = repo
+            .diff_snapshot_vs_replay::<TestAggregate>(&id)
+            .await
+            .unwrap();
+        assert_eq!(Some(1), diff.snapshot_sequence);
+        assert_eq!(2, diff.last_sequence);
+        assert_eq!(
+            vec![
+                SnapshotDifference {
+                    path: "/id".to_string(),
+                    snapshot: Some(json!(id)),
+                    replay: Some(json!("")),
+                },
+                SnapshotDifference {
+                    path: "/tests/0".to_string(),
+                    snapshot: Some(json!("drift")),
+                    replay: None,
+                },
+            ],
+            diff.differences
+        );
+    }
+}
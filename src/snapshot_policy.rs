@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Decides, per aggregate instance, whether a snapshot offered by the framework is written.
+///
+/// The framework offers snapshots at a fixed interval (`snapshot_size`). Combined with a small
+/// interval (e.g., `sqlite_snapshot_cqrs(pool, queries, 1, services)` offers a snapshot with
+/// every commit), a policy lets the snapshot frequency adapt to each aggregate, e.g. snapshotting
+/// "hot" aggregates more often. Skipping an offered snapshot is always safe, the framework
+/// resumes from the last stored snapshot.
+///
+/// Any `Fn(&str, &str, usize) -> bool` closure taking the aggregate type, aggregate id and the
+/// number of events committed since the last snapshot can be used as a policy.
+///
+/// ```
+/// use rusqlite_es::SnapshotPolicy;
+///
+/// fn every_fifty_events() -> impl SnapshotPolicy {
+///     |_aggregate_type: &str, _aggregate_id: &str, events_since_snapshot: usize| {
+///         events_since_snapshot >= 50
+///     }
+/// }
+/// ```
+pub trait SnapshotPolicy: Send + Sync {
+    /// Returns true if the offered snapshot should be written, given the number of events
+    /// (including those being committed) since the aggregate instance's last snapshot.
+    fn should_snapshot(
+        &self,
+        aggregate_type: &str,
+        aggregate_id: &str,
+        events_since_snapshot: usize,
+    ) -> bool;
+}
+
+impl<F> SnapshotPolicy for F
+where
+    F: Fn(&str, &str, usize) -> bool + Send + Sync,
+{
+    fn should_snapshot(
+        &self,
+        aggregate_type: &str,
+        aggregate_id: &str,
+        events_since_snapshot: usize,
+    ) -> bool {
+        self(aggregate_type, aggregate_id, events_since_snapshot)
+    }
+}
+
+/// Per-aggregate counts of the events committed since each aggregate instance's last snapshot,
+/// maintained by the repository as events are persisted.
+///
+/// Counts are only tracked for aggregate instances that have been committed to through this
+/// repository since it was created.
+#[derive(Clone, Default)]
+pub struct EventCounts {
+    counts: Arc<Mutex<HashMap<(String, String), usize>>>,
+}
+
+impl EventCounts {
+    /// Returns the number of events committed since the aggregate instance's last snapshot, if
+    /// known.
+    pub fn since_snapshot(&self, aggregate_type: &str, aggregate_id: &str) -> Option<usize> {
+        self.counts
+            .lock()
+            .unwrap()
+            .get(&(aggregate_type.to_string(), aggregate_id.to_string()))
+            .copied()
+    }
+
+    pub(crate) fn set(&self, aggregate_type: &str, aggregate_id: &str, count: usize) {
+        self.counts.lock().unwrap().insert(
+            (aggregate_type.to_string(), aggregate_id.to_string()),
+            count,
+        );
+    }
+
+    pub(crate) fn add_if_tracked(&self, aggregate_type: &str, aggregate_id: &str, count: usize) {
+        if let Some(current) = self
+            .counts
+            .lock()
+            .unwrap()
+            .get_mut(&(aggregate_type.to_string(), aggregate_id.to_string()))
+        {
+            *current += count;
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use serde_json::Value;
+
+/// The aggregate version recorded for snapshots of aggregates that have not been assigned a
+/// version via `SqliteEventRepository::with_aggregate_version`.
+pub const UNVERSIONED_AGGREGATE: &str = "";
+
+/// Used to upgrade a serialized aggregate snapshot that was stored with an older version of the
+/// aggregate's structure.
+///
+/// Snapshots whose version cannot be upcast to the aggregate's current version are ignored and
+/// the aggregate is instead rebuilt by replaying its events.
+pub trait SnapshotUpcaster: Send + Sync {
+    /// Examines the aggregate type and version of a stored snapshot to determine if this
+    /// upcaster can upcast it.
+    fn can_upcast(&self, aggregate_type: &str, aggregate_version: &str) -> bool;
+
+    /// Modifies the serialized aggregate, returning it along with its new version.
+    fn upcast(&self, aggregate: Value) -> (Value, String);
+}
+
+/// A `SnapshotUpcaster` that upgrades snapshots of a single aggregate type from one specific
+/// version to another.
+///
+/// ```
+/// use rusqlite_es::VersionSnapshotUpcaster;
+/// use serde_json::Value;
+///
+/// let upcaster = VersionSnapshotUpcaster::new(
+///     "Customer",
+///     "1.0",
+///     "2.0",
+///     Box::new(|mut aggregate| {
+///         if let Value::Object(object) = &mut aggregate {
+///             object.insert("loyalty_points".to_string(), Value::from(0));
+///         }
+///         aggregate
+///     }),
+/// );
+/// ```
+pub struct VersionSnapshotUpcaster {
+    aggregate_type: String,
+    from_version: String,
+    to_version: String,
+    f: Box<dyn Fn(Value) -> Value + Send + Sync>,
+}
+
+impl VersionSnapshotUpcaster {
+    /// Creates a `VersionSnapshotUpcaster` that applies `f` to snapshots of `aggregate_type`
+    /// stored with `from_version`, marking the result as `to_version`.
+    pub fn new(
+        aggregate_type: &str,
+        from_version: &str,
+        to_version: &str,
+        f: Box<dyn Fn(Value) -> Value + Send + Sync>,
+    ) -> Self {
+        Self {
+            aggregate_type: aggregate_type.to_string(),
+            from_version: from_version.to_string(),
+            to_version: to_version.to_string(),
+            f,
+        }
+    }
+}
+
+impl SnapshotUpcaster for VersionSnapshotUpcaster {
+    fn can_upcast(&self, aggregate_type: &str, aggregate_version: &str) -> bool {
+        self.aggregate_type == aggregate_type && self.from_version == aggregate_version
+    }
+
+    fn upcast(&self, aggregate: Value) -> (Value, String) {
+        ((self.f)(aggregate), self.to_version.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use crate::{SnapshotUpcaster, VersionSnapshotUpcaster};
+
+    #[test]
+    fn version_snapshot_upcaster() {
+        let upcaster = VersionSnapshotUpcaster::new(
+            "TestAggregate",
+            "1",
+            "2",
+            Box::new(|mut aggregate| {
+                aggregate["tests"] = json!([]);
+                aggregate
+            }),
+        );
+        assert!(upcaster.can_upcast("TestAggregate", "1"));
+        assert!(!upcaster.can_upcast("TestAggregate", "2"));
+        assert!(!upcaster.can_upcast("OtherAggregate", "1"));
+
+        let (aggregate, version) = upcaster.upcast(json!({"id": "a"}));
+        assert_eq!(json!({"id": "a", "tests": []}), aggregate);
+        assert_eq!("2", version);
+    }
+}
@@ -0,0 +1,109 @@
+use std::panic::RefUnwindSafe;
+use std::sync::Arc;
+
+use rusqlite::functions::{Context, FunctionFlags};
+use rusqlite::ToSql;
+
+use crate::connection_init::{register_init, ConnectionInit};
+
+/// Registers a deterministic application-defined SQL function on every connection of the pools
+/// built afterwards with `default_sqlite_pool` or `sqlite_pool`, so that the queries of
+/// projections, e.g. of a `SqliteTableViewRepository`, and ad-hoc view queries can use the
+/// same domain logic, e.g. rounding amounts of money, on any connection they run on.
+///
+/// The function is called `name` in SQL and takes `n_args` arguments, or any number of them if
+/// `n_args` is -1. It must return the same result for the same arguments, which lets SQLite
+/// evaluate it once per statement and use it in indexes on expressions, generated columns and
+/// `CHECK` constraints. A database using it in its schema must always be opened with the
+/// function registered, as SQLite otherwise fails to read or write the tables depending on it.
+///
+/// ```
+/// use rusqlite_es::{default_sqlite_pool, register_sql_function};
+///
+/// // rounds an amount of money to cents, half away from zero
+/// register_sql_function("round_cents", 1, |ctx| {
+///     let amount: f64 = ctx.get(0)?;
+///     Ok((amount * 100.0).round() / 100.0)
+/// });
+/// let pool = default_sqlite_pool(":memory:");
+/// let rounded: f64 = pool
+///     .get()
+///     .unwrap()
+///     .query_row("SELECT round_cents(12.345)", [], |row| row.get(0))
+///     .unwrap();
+/// assert_eq!(12.35, rounded);
+/// ```
+pub fn register_sql_function<F, T>(name: &str, n_args: i32, function: F)
+where
+    F: Fn(&Context<'_>) -> Result<T, rusqlite::Error> + Send + Sync + RefUnwindSafe + 'static,
+    T: ToSql,
+{
+    register_init(sql_function(name, n_args, function));
+}
+
+// A connection initializer registering a deterministic scalar function.
+pub(crate) fn sql_function<F, T>(name: &str, n_args: i32, function: F) -> ConnectionInit
+where
+    F: Fn(&Context<'_>) -> Result<T, rusqlite::Error> + Send + Sync + RefUnwindSafe + 'static,
+    T: ToSql,
+{
+    let name = name.to_string();
+    let function = Arc::new(function);
+    ConnectionInit::new(move |conn| {
+        let function = function.clone();
+        conn.create_scalar_function(
+            name.as_str(),
+            n_args,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+            move |ctx| function(ctx),
+        )
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::testing::tests::TEST_CONNECTION_STRING;
+    use crate::{default_sqlite_pool, register_sql_function, sqlite_pool, PoolOptions};
+
+    #[test]
+    fn sql_functions() {
+        register_sql_function("test_cents", 1, |ctx| {
+            let amount: f64 = ctx.get(0)?;
+            Ok((amount * 100.0).round() as i64)
+        });
+        let conn = default_sqlite_pool(TEST_CONNECTION_STRING).get().unwrap();
+        let cents: i64 = conn
+            .query_row("SELECT test_cents(12.345)", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(1235, cents);
+
+        let options = PoolOptions::new().with_function("account_prefix", 1, |ctx| {
+            let account: String = ctx.get(0)?;
+            Ok(account.split('-').next().unwrap_or_default().to_string())
+        });
+        let conn = sqlite_pool(TEST_CONNECTION_STRING, options)
+            .unwrap()
+            .get()
+            .unwrap();
+        // deterministic functions may be used in indexes on expressions
+        conn.execute_batch(
+            "CREATE TABLE accounts (view_id text PRIMARY KEY, balance real);
+             CREATE INDEX accounts_prefix ON accounts (account_prefix(view_id));
+             INSERT INTO accounts VALUES ('eu-1', 10), ('eu-2', 5), ('us-1', 7);",
+        )
+        .unwrap();
+        let balance: f64 = conn
+            .query_row(
+                "SELECT sum(balance) FROM accounts WHERE account_prefix(view_id) = 'eu'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(15.0, balance);
+        assert!(conn
+            .query_row("SELECT account_prefix('eu-1', 'us-1')", [], |row| {
+                row.get::<_, String>(0)
+            })
+            .is_err());
+    }
+}
@@ -1,76 +1,1122 @@
+use crate::identifier::TableName;
+use crate::{
+    EventTableLayout, HashChain, JsonEncoding, MetadataStorage, Partitioning, PayloadStorage,
+};
+
+#[derive(Clone)]
 pub(crate) struct SqlQueryFactory {
-    event_table: String,
+    event_table: TableName,
+    snapshot_table: TableName,
+    json_encoding: JsonEncoding,
+    metadata_storage: MetadataStorage,
+    payload_storage: PayloadStorage,
+    partitioning: Partitioning,
+    table_layout: EventTableLayout,
+    cascading_deletes: bool,
+    hash_chain: HashChain,
+    event_source: TableName,
+    metadata: String,
+    #[cfg(feature = "analytics")]
+    event_payload: String,
+    event_columns: String,
+    snapshot_columns: String,
     select_events: String,
     insert_event: String,
+    insert_event_if_absent: String,
     all_events: String,
+    everything: String,
+    count_all_events: String,
+    count_everything: String,
+    count_events: String,
+    count_events_by_type: String,
+    aggregate_history: String,
     insert_snapshot: String,
     update_snapshot: String,
+    rewrite_snapshot: String,
+    delete_snapshot: String,
     select_snapshot: String,
+    set_global_position: String,
+    compact_first_event: String,
+    delete_compacted_events: String,
+    shift_events: String,
+    restore_shifted_events: String,
+    stream_heads: String,
+    intern_metadata: String,
+    select_metadata_id: String,
+    append_metadata: String,
+    select_inline_metadata: String,
+    update_metadata: String,
+    select_partitions: String,
+    register_partition: String,
+    unregister_partition: String,
+    count_sequence: String,
+    next_position: String,
+    aggregate_ids: String,
+    recent_aggregate_ids: String,
+    last_sequence: String,
+    event_position: String,
+    head_position: String,
+    count_aggregates: String,
+    aggregate_id_at: String,
+    count_snapshots: String,
+    count_rename_conflicts: String,
+    rename_events: String,
+    rename_snapshots: String,
+    select_payload_id: String,
+    intern_payload: String,
+    collect_payloads: String,
+    select_metadata_after: String,
+    previous_event_hash: String,
+    previous_global_hash: String,
+    event_at: String,
+    set_event_hash: String,
+    hash_chain_events: String,
+    merkle_leaves: String,
+    last_merkle_position: String,
+    insert_merkle_root: String,
+    merkle_roots: String,
+    merkle_root_at: String,
+    hashed_event: String,
 }
 
 impl SqlQueryFactory {
-    pub fn new(event_table: &str, snapshot_table: &str) -> Self {
+    pub fn new(event_table: &str, snapshot_table: &str, json_encoding: JsonEncoding) -> Self {
+        Self::build(
+            event_table,
+            TableName::unchecked(snapshot_table),
+            json_encoding,
+            MetadataStorage::default(),
+            PayloadStorage::default(),
+            Partitioning::default(),
+            EventTableLayout::default(),
+            false,
+            HashChain::default(),
+        )
+    }
+    #[allow(clippy::too_many_arguments)]
+    fn build(
+        event_table: &str,
+        snapshot_table: TableName,
+        json_encoding: JsonEncoding,
+        metadata_storage: MetadataStorage,
+        payload_storage: PayloadStorage,
+        partitioning: Partitioning,
+        table_layout: EventTableLayout,
+        cascading_deletes: bool,
+        hash_chain: HashChain,
+    ) -> Self {
+        let event_table = TableName::unchecked(event_table);
+        let payload = json_encoding.read_column("payload");
+        let json = json_encoding.write_param();
+        let (json_set, json_insert) = match json_encoding {
+            JsonEncoding::Text => ("json_set", "json_insert"),
+            JsonEncoding::Jsonb => ("jsonb_set", "jsonb_insert"),
+        };
+        let inline_metadata = match json_encoding {
+            JsonEncoding::Text => "metadata",
+            JsonEncoding::Jsonb => "json(metadata)",
+        };
+        // interned metadata is stored as an array of dictionary ids
+        let dictionary = crate::METADATA_DICTIONARY_TABLE;
+        let (metadata, metadata_column) = match metadata_storage {
+            MetadataStorage::Inline => (
+                "metadata".to_string(),
+                json_encoding.read_column("metadata"),
+            ),
+            // the global position is interned as a placeholder and read as the event's position,
+            // see `intern_global_position`
+            MetadataStorage::Dictionary => {
+                let position_key = crate::GLOBAL_POSITION_METADATA_KEY;
+                let position = table_layout.position_column();
+                let metadata = format!("CASE json_type(metadata) WHEN 'array' THEN json_replace((SELECT json_group_object(d.key, json(d.value)) FROM json_each(metadata) AS m JOIN {dictionary} AS d ON d.id = m.value), '$.{position_key}', CAST({position} AS TEXT)) ELSE {inline_metadata} END");
+                let metadata_column = format!("{metadata} AS metadata");
+                (metadata, metadata_column)
+            }
+        };
+        // a deduplicated payload is stored as the id of its row in the payloads table
+        let payloads = crate::EVENT_PAYLOADS_TABLE;
+        let inline_payload = match json_encoding {
+            JsonEncoding::Text => "payload",
+            JsonEncoding::Jsonb => "json(payload)",
+        };
+        let contents = match json_encoding {
+            JsonEncoding::Text => "p.contents",
+            JsonEncoding::Jsonb => "json(p.contents)",
+        };
+        let event_payload = match payload_storage {
+            PayloadStorage::Inline => "payload".to_string(),
+            PayloadStorage::Deduplicated => format!("CASE json_type(payload) WHEN 'integer' THEN (SELECT {contents} FROM {payloads} AS p WHERE p.id = json_extract(payload, '$')) ELSE {inline_payload} END"),
+        };
+        let event_payload_column = match payload_storage {
+            PayloadStorage::Inline => payload.clone(),
+            PayloadStorage::Deduplicated => format!("{event_payload} AS payload"),
+        };
+        let position_key = crate::GLOBAL_POSITION_METADATA_KEY;
+        let position = table_layout.position_column();
+        // without a rowid, the global position is assigned explicitly, see `next_position`
+        let (position_column, position_param) = match table_layout {
+            EventTableLayout::Rowid => ("", ""),
+            EventTableLayout::WithoutRowid => (", global_position", ", ?"),
+        };
+        // events are read from a view over all partitions, see `Partitioning`
+        let event_source = match partitioning {
+            Partitioning::None => event_table.clone(),
+            _ => event_table.suffixed("_all"),
+        };
+        let partition_table = event_table.suffixed("_partitions");
+        let merkle_roots = event_table.suffixed("_merkle_roots");
+        let merkle_root_columns = "first_position, last_position, leaves, root, computed_at";
+        let event_columns = format!(
+            "aggregate_type, aggregate_id, sequence, event_type, event_version, {event_payload_column}, {metadata_column}"
+        );
+        let snapshot_columns = format!(
+            "aggregate_type, aggregate_id, last_sequence, current_snapshot, aggregate_version, {payload}"
+        );
         Self {
-            event_table: event_table.to_string(),
+            json_encoding,
             select_events: format!("
-SELECT aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata
-  FROM {}
+SELECT {event_columns}
+  FROM {event_source}
   WHERE aggregate_type = ? AND aggregate_id = ?
-  ORDER BY sequence", event_table),
+  ORDER BY sequence"),
             insert_event: format!("
-INSERT INTO {} (aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata)
-VALUES (?, ?, ?, ?, ?, ?, ?)", event_table),
+INSERT INTO {event_table} (aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata, created_at{position_column})
+VALUES (?, ?, ?, ?, ?, {json}, {json}, strftime('%Y-%m-%dT%H:%M:%fZ', 'now'){position_param})"),
+            insert_event_if_absent: format!("
+INSERT OR IGNORE INTO {event_table} (aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata, created_at{position_column})
+VALUES (?, ?, ?, ?, ?, {json}, {json}, strftime('%Y-%m-%dT%H:%M:%fZ', 'now'){position_param})"),
             all_events: format!("
-SELECT aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata
-  FROM {}
+SELECT {event_columns}
+  FROM {event_source}
   WHERE aggregate_type = ?
-  ORDER BY sequence", event_table),
+  ORDER BY sequence"),
+            everything: format!("
+SELECT {event_columns}
+  FROM {event_source}
+  ORDER BY {position}"),
+            count_all_events: format!("
+SELECT count(*)
+  FROM {event_source}
+  WHERE aggregate_type = ?"),
+            count_everything: format!("
+SELECT count(*)
+  FROM {event_source}"),
+            count_events: format!("
+SELECT count(*)
+  FROM {event_source}
+  WHERE aggregate_type = ? AND aggregate_id = ?"),
+            count_events_by_type: format!("
+SELECT count(*)
+  FROM {event_source}
+  WHERE aggregate_type = ? AND event_type = ?"),
+            aggregate_history: format!("
+SELECT {event_columns}, created_at
+  FROM {event_source}
+  WHERE aggregate_type = ? AND aggregate_id = ? AND sequence >= ? AND sequence <= ?
+  ORDER BY sequence"),
             insert_snapshot: format!("
-INSERT INTO {} (aggregate_type, aggregate_id, last_sequence, current_snapshot, payload)
-VALUES (?, ?, ?, ?, ?)", snapshot_table),
+INSERT INTO {snapshot_table} (aggregate_type, aggregate_id, last_sequence, current_snapshot, aggregate_version, payload)
+VALUES (?, ?, ?, ?, ?, {json})"),
             update_snapshot: format!("
-UPDATE {}
-  SET last_sequence= ? , payload= ?, current_snapshot= ?
-  WHERE aggregate_type= ? AND aggregate_id= ? AND current_snapshot= ?", snapshot_table),
+UPDATE {snapshot_table}
+  SET last_sequence= ? , payload= {json}, current_snapshot= ?, aggregate_version= ?
+  WHERE aggregate_type= ? AND aggregate_id= ? AND last_sequence < ?"),
+            rewrite_snapshot: format!("
+UPDATE {snapshot_table}
+  SET last_sequence= ? , payload= {json}, current_snapshot= ?, aggregate_version= ?
+  WHERE aggregate_type= ? AND aggregate_id= ? AND current_snapshot= ?"),
+            delete_snapshot: format!("
+DELETE FROM {snapshot_table}
+  WHERE aggregate_type = ? AND aggregate_id = ?"),
             select_snapshot: format!("
-SELECT aggregate_type, aggregate_id, last_sequence, current_snapshot, payload
-  FROM {}
-  WHERE aggregate_type = ? AND aggregate_id = ?", snapshot_table)
+SELECT {snapshot_columns}
+  FROM {snapshot_table}
+  WHERE aggregate_type = ? AND aggregate_id = ?"),
+            set_global_position: format!("
+UPDATE {event_table}
+  SET metadata = {json_set}(metadata, '$.{position_key}', CAST(? AS TEXT))
+  WHERE {position} = ?"),
+            compact_first_event: format!("
+UPDATE {event_table}
+  SET event_type = ?, event_version = ?, payload = {json}
+  WHERE aggregate_type = ? AND aggregate_id = ? AND sequence = 1"),
+            delete_compacted_events: format!("
+DELETE FROM {event_table}
+  WHERE aggregate_type = ? AND aggregate_id = ? AND sequence > 1 AND sequence <= ?"),
+            shift_events: format!("
+UPDATE {event_table}
+  SET sequence = sequence + ?
+  WHERE aggregate_type = ? AND aggregate_id = ? AND sequence > ?"),
+            restore_shifted_events: format!("
+UPDATE {event_table}
+  SET sequence = sequence - ?
+  WHERE aggregate_type = ? AND aggregate_id = ? AND sequence > ?"),
+            stream_heads: format!("
+SELECT aggregate_type, aggregate_id, max(sequence), count(*)
+  FROM {event_source}
+  GROUP BY aggregate_type, aggregate_id"),
+            intern_metadata: format!("
+INSERT INTO {dictionary} (key, value)
+VALUES (?, ?)
+  ON CONFLICT (key, value) DO NOTHING"),
+            select_metadata_id: format!("
+SELECT id
+  FROM {dictionary}
+  WHERE key = ? AND value = ?"),
+            append_metadata: format!("
+UPDATE {event_table}
+  SET metadata = {json_insert}(metadata, '$[#]', ?)
+  WHERE {position} = ?"),
+            select_inline_metadata: format!("
+SELECT {position}, {inline_metadata}
+  FROM {event_table}
+  WHERE json_type(metadata) = 'object'
+  LIMIT ?"),
+            update_metadata: format!("
+UPDATE {event_table}
+  SET metadata = {json}
+  WHERE {position} = ?"),
+            select_partitions: format!("
+SELECT ordinal, name
+  FROM {partition_table}
+  ORDER BY ordinal"),
+            register_partition: format!("
+INSERT INTO {partition_table} (ordinal, name)
+VALUES (?, ?)"),
+            unregister_partition: format!("
+DELETE FROM {partition_table}
+  WHERE name = ?"),
+            count_sequence: format!("
+SELECT count(*)
+  FROM {event_source}
+  WHERE aggregate_type = ? AND aggregate_id = ? AND sequence = ?"),
+            next_position: format!("
+SELECT coalesce(max({position}), 0) + 1
+  FROM {event_table}"),
+            aggregate_ids: format!("
+SELECT DISTINCT aggregate_id
+  FROM {event_source}
+  WHERE aggregate_type = ? AND aggregate_id > ?
+  ORDER BY aggregate_id
+  LIMIT ?"),
+            recent_aggregate_ids: format!("
+SELECT aggregate_id
+  FROM {event_source}
+  WHERE aggregate_type = ?
+  GROUP BY aggregate_id
+  ORDER BY max({position}) DESC
+  LIMIT ?"),
+            last_sequence: format!("
+SELECT coalesce(max(sequence), 0)
+  FROM {event_source}
+  WHERE aggregate_type = ? AND aggregate_id = ?"),
+            event_position: format!("
+SELECT {position}
+  FROM {event_source}
+  WHERE aggregate_type = ? AND aggregate_id = ? AND sequence = ?"),
+            head_position: format!("
+SELECT coalesce(max({position}), 0)
+  FROM {event_source}"),
+            count_aggregates: format!("
+SELECT count(DISTINCT aggregate_id)
+  FROM {event_source}
+  WHERE aggregate_type = ?"),
+            aggregate_id_at: format!("
+SELECT DISTINCT aggregate_id
+  FROM {event_source}
+  WHERE aggregate_type = ?
+  ORDER BY aggregate_id
+  LIMIT 1 OFFSET ?"),
+            count_snapshots: format!("
+SELECT count(*)
+  FROM {snapshot_table}
+  WHERE aggregate_type = ?"),
+            count_rename_conflicts: format!("
+SELECT count(DISTINCT aggregate_id)
+  FROM {event_table} AS renamed
+  WHERE aggregate_type = ?1
+    AND EXISTS (SELECT 1 FROM {event_table} WHERE aggregate_type = ?2 AND aggregate_id = renamed.aggregate_id)"),
+            rename_events: format!("
+UPDATE {event_table}
+  SET aggregate_type = ?2
+  WHERE aggregate_type = ?1
+    AND aggregate_id IN (SELECT DISTINCT aggregate_id FROM {event_table} WHERE aggregate_type = ?1 LIMIT ?3)
+  RETURNING aggregate_id"),
+            rename_snapshots: format!("
+UPDATE {snapshot_table}
+  SET aggregate_type = ?2
+  WHERE aggregate_type = ?1
+    AND aggregate_id IN (SELECT aggregate_id FROM {snapshot_table} WHERE aggregate_type = ?1 LIMIT ?3)
+  RETURNING aggregate_id"),
+            select_payload_id: format!("
+SELECT id
+  FROM {payloads}
+  WHERE hash = ? AND contents = {json}"),
+            intern_payload: format!("
+INSERT INTO {payloads} (hash, contents)
+VALUES (?, {json})"),
+            collect_payloads: format!("
+DELETE FROM {payloads}
+  WHERE id NOT IN (SELECT json_extract(payload, '$') FROM {event_source} WHERE json_type(payload) = 'integer')"),
+            select_metadata_after: format!("
+SELECT {position}, {metadata_column}
+  FROM {event_table}
+  WHERE {position} > ?
+  ORDER BY {position}
+  LIMIT ?"),
+            previous_event_hash: format!("
+SELECT event_hash
+  FROM {event_table}
+  WHERE aggregate_type = ? AND aggregate_id = ? AND sequence < ?
+  ORDER BY sequence DESC
+  LIMIT 1"),
+            previous_global_hash: format!("
+SELECT event_hash
+  FROM {event_table}
+  WHERE {position} < ?
+  ORDER BY {position} DESC
+  LIMIT 1"),
+            event_at: format!("
+SELECT {event_columns}
+  FROM {event_table}
+  WHERE {position} = ?"),
+            set_event_hash: format!("
+UPDATE {event_table}
+  SET prev_hash = ?, event_hash = ?
+  WHERE {position} = ?"),
+            hash_chain_events: format!("
+SELECT {event_columns}, prev_hash, event_hash
+  FROM {event_table}
+  ORDER BY {}", match hash_chain {
+                HashChain::Global => position,
+                _ => "aggregate_type, aggregate_id, sequence",
+            }),
+            merkle_leaves: format!("
+SELECT {position}, event_hash
+  FROM {event_table}
+  WHERE {position} > ? AND {position} <= ? AND event_hash IS NOT NULL
+  ORDER BY {position}"),
+            last_merkle_position: format!("
+SELECT coalesce(max(last_position), 0)
+  FROM {merkle_roots}"),
+            insert_merkle_root: format!("
+INSERT INTO {merkle_roots} (first_position, last_position, leaves, root)
+VALUES (?, ?, ?, ?)
+  RETURNING {merkle_root_columns}"),
+            merkle_roots: format!("
+SELECT {merkle_root_columns}
+  FROM {merkle_roots}
+  WHERE last_position > ?
+  ORDER BY first_position"),
+            merkle_root_at: format!("
+SELECT {merkle_root_columns}
+  FROM {merkle_roots}
+  WHERE first_position <= ?1 AND last_position >= ?1"),
+            hashed_event: format!("
+SELECT {position}, event_hash
+  FROM {event_table}
+  WHERE aggregate_type = ? AND aggregate_id = ? AND sequence = ?"),
+            metadata_storage,
+            payload_storage,
+            partitioning,
+            table_layout,
+            cascading_deletes,
+            hash_chain,
+            event_source,
+            metadata,
+            #[cfg(feature = "analytics")]
+            event_payload,
+            event_columns,
+            snapshot_columns,
+            event_table,
+            snapshot_table,
         }
     }
+    pub fn with_json_encoding(&self, json_encoding: JsonEncoding) -> Self {
+        Self::build(
+            self.event_table.as_str(),
+            self.snapshot_table.clone(),
+            json_encoding,
+            self.metadata_storage,
+            self.payload_storage,
+            self.partitioning,
+            self.table_layout,
+            self.cascading_deletes,
+            self.hash_chain,
+        )
+    }
+    pub fn with_metadata_storage(&self, metadata_storage: MetadataStorage) -> Self {
+        Self::build(
+            self.event_table.as_str(),
+            self.snapshot_table.clone(),
+            self.json_encoding,
+            metadata_storage,
+            self.payload_storage,
+            self.partitioning,
+            self.table_layout,
+            self.cascading_deletes,
+            self.hash_chain,
+        )
+    }
+    pub fn with_payload_storage(&self, payload_storage: PayloadStorage) -> Self {
+        Self::build(
+            self.event_table.as_str(),
+            self.snapshot_table.clone(),
+            self.json_encoding,
+            self.metadata_storage,
+            payload_storage,
+            self.partitioning,
+            self.table_layout,
+            self.cascading_deletes,
+            self.hash_chain,
+        )
+    }
+    pub fn with_partitioning(&self, partitioning: Partitioning) -> Self {
+        Self::build(
+            self.event_table.as_str(),
+            self.snapshot_table.clone(),
+            self.json_encoding,
+            self.metadata_storage,
+            self.payload_storage,
+            partitioning,
+            self.table_layout,
+            self.cascading_deletes,
+            self.hash_chain,
+        )
+    }
+    pub fn with_table_layout(&self, table_layout: EventTableLayout) -> Self {
+        Self::build(
+            self.event_table.as_str(),
+            self.snapshot_table.clone(),
+            self.json_encoding,
+            self.metadata_storage,
+            self.payload_storage,
+            self.partitioning,
+            table_layout,
+            self.cascading_deletes,
+            self.hash_chain,
+        )
+    }
+    // The queries storing snapshots in the provided schema, e.g. an attached database.
+    pub fn with_snapshot_schema(&self, schema: Option<&str>) -> Self {
+        Self::build(
+            self.event_table.as_str(),
+            self.snapshot_table.clone().with_schema(schema),
+            self.json_encoding,
+            self.metadata_storage,
+            self.payload_storage,
+            self.partitioning,
+            self.table_layout,
+            self.cascading_deletes,
+            self.hash_chain,
+        )
+    }
+    // The schema deleting the snapshot of an aggregate instance along with its last event.
+    pub fn with_cascading_deletes(&self, cascading_deletes: bool) -> Self {
+        Self::build(
+            self.event_table.as_str(),
+            self.snapshot_table.clone(),
+            self.json_encoding,
+            self.metadata_storage,
+            self.payload_storage,
+            self.partitioning,
+            self.table_layout,
+            cascading_deletes,
+            self.hash_chain,
+        )
+    }
+    // The queries linking committed events by their hashes, see `HashChain`.
+    pub fn with_hash_chain(&self, hash_chain: HashChain) -> Self {
+        Self::build(
+            self.event_table.as_str(),
+            self.snapshot_table.clone(),
+            self.json_encoding,
+            self.metadata_storage,
+            self.payload_storage,
+            self.partitioning,
+            self.table_layout,
+            self.cascading_deletes,
+            hash_chain,
+        )
+    }
+    pub fn table_layout(&self) -> EventTableLayout {
+        self.table_layout
+    }
+    pub fn cascading_deletes(&self) -> bool {
+        self.cascading_deletes
+    }
+    pub fn hash_chain(&self) -> HashChain {
+        self.hash_chain
+    }
+    // The column ordering events by global position, see `EventTableLayout`.
+    pub fn position(&self) -> &str {
+        self.table_layout.position_column()
+    }
+    pub fn partitioning(&self) -> Partitioning {
+        self.partitioning
+    }
+    pub fn metadata_storage(&self) -> MetadataStorage {
+        self.metadata_storage
+    }
+    pub fn payload_storage(&self) -> PayloadStorage {
+        self.payload_storage
+    }
+    // The metadata column, reconstituted from the dictionary if metadata is interned.
+    pub fn metadata(&self) -> &str {
+        &self.metadata
+    }
+    pub fn event_table(&self) -> &str {
+        self.event_table.as_str()
+    }
+    pub fn snapshot_table(&self) -> &TableName {
+        &self.snapshot_table
+    }
+    pub fn event_columns(&self) -> &str {
+        &self.event_columns
+    }
+    pub fn snapshot_columns(&self) -> &str {
+        &self.snapshot_columns
+    }
+    pub fn event_source(&self) -> &TableName {
+        &self.event_source
+    }
     pub fn select_events(&self) -> &str {
         &self.select_events
     }
     pub fn insert_event(&self) -> &str {
         &self.insert_event
     }
+    // Inserts an event unless an event with the same sequence already exists.
+    pub fn insert_event_if_absent(&self) -> &str {
+        &self.insert_event_if_absent
+    }
     pub fn insert_snapshot(&self) -> &str {
         &self.insert_snapshot
     }
     pub fn update_snapshot(&self) -> &str {
         &self.update_snapshot
     }
+    pub fn rewrite_snapshot(&self) -> &str {
+        &self.rewrite_snapshot
+    }
+    pub fn delete_snapshot(&self) -> &str {
+        &self.delete_snapshot
+    }
     pub fn select_snapshot(&self) -> &str {
         &self.select_snapshot
     }
     pub fn all_events(&self) -> &str {
         &self.all_events
     }
+    pub fn everything(&self) -> &str {
+        &self.everything
+    }
+    pub fn count_all_events(&self) -> &str {
+        &self.count_all_events
+    }
+    pub fn count_everything(&self) -> &str {
+        &self.count_everything
+    }
+    pub fn count_events(&self) -> &str {
+        &self.count_events
+    }
+    pub fn count_events_by_type(&self) -> &str {
+        &self.count_events_by_type
+    }
+    // The events of an aggregate instance within an inclusive range of sequences.
+    pub fn aggregate_history(&self) -> &str {
+        &self.aggregate_history
+    }
+    pub fn set_global_position(&self) -> &str {
+        &self.set_global_position
+    }
+    pub fn compact_first_event(&self) -> &str {
+        &self.compact_first_event
+    }
+    pub fn delete_compacted_events(&self) -> &str {
+        &self.delete_compacted_events
+    }
+    pub fn shift_events(&self) -> &str {
+        &self.shift_events
+    }
+    pub fn restore_shifted_events(&self) -> &str {
+        &self.restore_shifted_events
+    }
+    pub fn stream_heads(&self) -> &str {
+        &self.stream_heads
+    }
+    pub fn intern_metadata(&self) -> &str {
+        &self.intern_metadata
+    }
+    pub fn select_metadata_id(&self) -> &str {
+        &self.select_metadata_id
+    }
+    pub fn append_metadata(&self) -> &str {
+        &self.append_metadata
+    }
+    pub fn select_inline_metadata(&self) -> &str {
+        &self.select_inline_metadata
+    }
+    pub fn update_metadata(&self) -> &str {
+        &self.update_metadata
+    }
+    pub fn select_partitions(&self) -> &str {
+        &self.select_partitions
+    }
+    pub fn register_partition(&self) -> &str {
+        &self.register_partition
+    }
+    pub fn unregister_partition(&self) -> &str {
+        &self.unregister_partition
+    }
+    pub fn count_sequence(&self) -> &str {
+        &self.count_sequence
+    }
+    pub fn next_position(&self) -> &str {
+        &self.next_position
+    }
+    // The next page of aggregate ids of a type, following the given id.
+    pub fn aggregate_ids(&self) -> &str {
+        &self.aggregate_ids
+    }
+    // The ids of the aggregate instances of a type with the most recently committed events.
+    pub fn recent_aggregate_ids(&self) -> &str {
+        &self.recent_aggregate_ids
+    }
+    // The sequence of the last event of an aggregate instance, 0 if it has none.
+    pub fn last_sequence(&self) -> &str {
+        &self.last_sequence
+    }
+    // The global position of an event.
+    pub fn event_position(&self) -> &str {
+        &self.event_position
+    }
+    // The global position of the last committed event, 0 without events.
+    pub fn head_position(&self) -> &str {
+        &self.head_position
+    }
+    // The number of aggregate instances of a type.
+    pub fn count_aggregates(&self) -> &str {
+        &self.count_aggregates
+    }
+    // The aggregate id of a type at an offset, in the order of aggregate ids.
+    pub fn aggregate_id_at(&self) -> &str {
+        &self.aggregate_id_at
+    }
+    // The number of snapshots of an aggregate type.
+    pub fn count_snapshots(&self) -> &str {
+        &self.count_snapshots
+    }
+    // The number of aggregate instances of a type whose id is also used by another type.
+    pub fn count_rename_conflicts(&self) -> &str {
+        &self.count_rename_conflicts
+    }
+    // Moves the events of a batch of aggregate instances to another aggregate type.
+    pub fn rename_events(&self) -> &str {
+        &self.rename_events
+    }
+    // Moves a batch of snapshots to another aggregate type.
+    pub fn rename_snapshots(&self) -> &str {
+        &self.rename_snapshots
+    }
+    // The id of a deduplicated payload, looked up by its hash and contents.
+    pub fn select_payload_id(&self) -> &str {
+        &self.select_payload_id
+    }
+    // Adds a payload to the payloads table.
+    pub fn intern_payload(&self) -> &str {
+        &self.intern_payload
+    }
+    // Deletes the deduplicated payloads no longer referenced by any event.
+    pub fn collect_payloads(&self) -> &str {
+        &self.collect_payloads
+    }
+    // A batch of events' metadata following a position, in the stored form.
+    pub fn select_metadata_after(&self) -> &str {
+        &self.select_metadata_after
+    }
+    // The hash of the event preceding a sequence of an aggregate instance, see `HashChain`.
+    pub fn previous_event_hash(&self) -> &str {
+        &self.previous_event_hash
+    }
+    // The hash of the event preceding a position, see `HashChain`.
+    pub fn previous_global_hash(&self) -> &str {
+        &self.previous_global_hash
+    }
+    // The event at a position, as read by the repository.
+    pub fn event_at(&self) -> &str {
+        &self.event_at
+    }
+    // Records the hashes linking an event into the hash chain.
+    pub fn set_event_hash(&self) -> &str {
+        &self.set_event_hash
+    }
+    // All events with their hashes, in the order of the hash chain.
+    pub fn hash_chain_events(&self) -> &str {
+        &self.hash_chain_events
+    }
+    // The hashes of the events within a range of positions, the leaves of a Merkle tree.
+    pub fn merkle_leaves(&self) -> &str {
+        &self.merkle_leaves
+    }
+    // The last position covered by a Merkle root, or 0.
+    pub fn last_merkle_position(&self) -> &str {
+        &self.last_merkle_position
+    }
+    pub fn insert_merkle_root(&self) -> &str {
+        &self.insert_merkle_root
+    }
+    // The Merkle roots covering events after a position.
+    pub fn merkle_roots(&self) -> &str {
+        &self.merkle_roots
+    }
+    // The Merkle root covering a position.
+    pub fn merkle_root_at(&self) -> &str {
+        &self.merkle_root_at
+    }
+    // The position and hash of an event.
+    pub fn hashed_event(&self) -> &str {
+        &self.hashed_event
+    }
+    // Creates an events table, or a partition of one.
+    pub fn create_events_table(&self, partition: &str) -> String {
+        let partition = TableName::unchecked(partition);
+        let event_type_index = partition.suffixed("_event_type");
+        let created_at_index = partition.suffixed("_created_at");
+        let mut schema = match self.table_layout {
+            EventTableLayout::Rowid => format!(
+                "
+CREATE TABLE IF NOT EXISTS {partition}
+(
+    aggregate_type text                         NOT NULL,
+    aggregate_id   text                         NOT NULL,
+    sequence       bigint CHECK (sequence >= 0) NOT NULL,
+    event_type     text                         NOT NULL,
+    event_version  text                         NOT NULL,
+    payload        json                         NOT NULL,
+    metadata       json                         NOT NULL,
+    created_at     text                         NOT NULL DEFAULT '',
+    prev_hash      text,
+    event_hash     text,
+    PRIMARY KEY (aggregate_type, aggregate_id, sequence)
+);
+CREATE INDEX IF NOT EXISTS {event_type_index} ON {partition} (event_type);
+CREATE INDEX IF NOT EXISTS {created_at_index} ON {partition} (created_at);"
+            ),
+            EventTableLayout::WithoutRowid => format!(
+                "
+CREATE TABLE IF NOT EXISTS {partition}
+(
+    aggregate_type  text                         NOT NULL,
+    aggregate_id    text                         NOT NULL,
+    sequence        bigint CHECK (sequence >= 0) NOT NULL,
+    event_type      text                         NOT NULL,
+    event_version   text                         NOT NULL,
+    payload         json                         NOT NULL,
+    metadata        json                         NOT NULL,
+    created_at      text                         NOT NULL DEFAULT '',
+    prev_hash       text,
+    event_hash      text,
+    global_position integer                      NOT NULL,
+    PRIMARY KEY (aggregate_type, aggregate_id, sequence)
+) WITHOUT ROWID;
+CREATE UNIQUE INDEX IF NOT EXISTS {position_index} ON {partition} (global_position);
+CREATE INDEX IF NOT EXISTS {event_type_index} ON {partition} (event_type);
+CREATE INDEX IF NOT EXISTS {created_at_index} ON {partition} (created_at);",
+                position_index = partition.suffixed("_global_position")
+            ),
+        };
+        if self.cascading_deletes {
+            // tables within a trigger cannot be qualified, they are those of the trigger's schema
+            schema.push_str(&format!(
+                "
+CREATE TRIGGER IF NOT EXISTS {} AFTER DELETE ON {partition}
+  WHEN NOT EXISTS (SELECT 1 FROM {} WHERE aggregate_type = old.aggregate_type AND aggregate_id = old.aggregate_id)
+BEGIN
+  DELETE FROM {} WHERE aggregate_type = old.aggregate_type AND aggregate_id = old.aggregate_id;
+END;",
+                partition.suffixed("_cascade"),
+                self.event_source.unqualified(),
+                self.snapshot_table.unqualified()
+            ));
+        }
+        schema
+    }
+    // Creates the tables and indexes needed by the configured repository.
+    pub fn create_schema(&self) -> String {
+        let event_table = &self.event_table;
+        let snapshot_table = &self.snapshot_table;
+        let mut schema = self.create_events_table(event_table.as_str());
+        let snapshot_index = snapshot_table.suffixed("_aggregate");
+        let partition_table = event_table.suffixed("_partitions");
+        schema.push_str(&format!(
+            "
+CREATE TABLE IF NOT EXISTS {snapshot_table}
+(
+    aggregate_type    text                                 NOT NULL,
+    aggregate_id      text                                 NOT NULL,
+    last_sequence     bigint CHECK (last_sequence >= 0)    NOT NULL,
+    current_snapshot  bigint CHECK (current_snapshot >= 0) NOT NULL,
+    aggregate_version text                                 NOT NULL DEFAULT '',
+    payload           json                                 NOT NULL,
+    PRIMARY KEY (aggregate_type, aggregate_id, last_sequence)
+);
+CREATE UNIQUE INDEX IF NOT EXISTS {snapshot_index} ON {} (aggregate_type, aggregate_id);",
+            snapshot_table.unqualified()
+        ));
+        if self.hash_chain != HashChain::None {
+            schema.push_str(&format!(
+                "
+CREATE TABLE IF NOT EXISTS {}
+(
+    first_position integer NOT NULL,
+    last_position  integer NOT NULL,
+    leaves         integer NOT NULL,
+    root           text    NOT NULL,
+    computed_at    text    NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    PRIMARY KEY (first_position)
+);",
+                event_table.suffixed("_merkle_roots")
+            ));
+        }
+        if self.metadata_storage == MetadataStorage::Dictionary {
+            schema.push_str(&format!(
+                "
+CREATE TABLE IF NOT EXISTS {}
+(
+    id    integer NOT NULL,
+    key   text    NOT NULL,
+    value json    NOT NULL,
+    PRIMARY KEY (id),
+    UNIQUE (key, value)
+);",
+                crate::METADATA_DICTIONARY_TABLE
+            ));
+        }
+        if self.payload_storage == PayloadStorage::Deduplicated {
+            schema.push_str(&format!(
+                "
+CREATE TABLE IF NOT EXISTS {payloads}
+(
+    id       integer NOT NULL,
+    hash     text    NOT NULL,
+    contents json    NOT NULL,
+    PRIMARY KEY (id)
+);
+CREATE INDEX IF NOT EXISTS {payloads_hash} ON {payloads} (hash);",
+                payloads = TableName::unchecked(crate::EVENT_PAYLOADS_TABLE),
+                payloads_hash = TableName::unchecked(crate::EVENT_PAYLOADS_TABLE).suffixed("_hash"),
+            ));
+        }
+        if self.partitioning != Partitioning::None {
+            schema.push_str(&format!(
+                "
+CREATE TABLE IF NOT EXISTS {partition_table}
+(
+    ordinal integer NOT NULL,
+    name    text    NOT NULL UNIQUE,
+    PRIMARY KEY (ordinal)
+);
+CREATE VIEW IF NOT EXISTS {} AS
+SELECT {position} AS {position}, aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata, created_at FROM {event_table};",
+                &self.event_source,
+                position = self.position()
+            ));
+        }
+        schema
+    }
+    // The columns each table of the configured repository must have.
+    pub fn required_columns(&self) -> Vec<(TableName, &'static [&'static str])> {
+        let mut required: Vec<(TableName, &'static [&'static str])> = vec![
+            (
+                self.event_table.clone(),
+                &[
+                    "aggregate_type",
+                    "aggregate_id",
+                    "sequence",
+                    "event_type",
+                    "event_version",
+                    "payload",
+                    "metadata",
+                    "created_at",
+                ],
+            ),
+            (
+                self.snapshot_table.clone(),
+                &[
+                    "aggregate_type",
+                    "aggregate_id",
+                    "last_sequence",
+                    "current_snapshot",
+                    "aggregate_version",
+                    "payload",
+                ],
+            ),
+        ];
+        if self.table_layout == EventTableLayout::WithoutRowid {
+            required.push((self.event_table.clone(), &[crate::GLOBAL_POSITION_COLUMN]));
+        }
+        if self.hash_chain != HashChain::None {
+            required.push((self.event_table.clone(), &["prev_hash", "event_hash"]));
+            required.push((
+                self.event_table.suffixed("_merkle_roots"),
+                &[
+                    "first_position",
+                    "last_position",
+                    "leaves",
+                    "root",
+                    "computed_at",
+                ],
+            ));
+        }
+        if self.metadata_storage == MetadataStorage::Dictionary {
+            required.push((
+                TableName::unchecked(crate::METADATA_DICTIONARY_TABLE),
+                &["id", "key", "value"],
+            ));
+        }
+        if self.payload_storage == PayloadStorage::Deduplicated {
+            required.push((
+                TableName::unchecked(crate::EVENT_PAYLOADS_TABLE),
+                &["id", "hash", "contents"],
+            ));
+        }
+        required
+    }
+    // Replaces the view over the events table and its partitions, a partition's positions are
+    // offset by its ordinal so that global positions remain unique and in commit order.
+    pub fn partition_view(&self, partitions: &[(i64, String)]) -> String {
+        let shift = crate::PARTITION_POSITION_BITS;
+        let position = self.position();
+        let columns =
+            "aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata, created_at";
+        let mut selects = vec![format!(
+            "SELECT {position} AS {position}, {columns} FROM {}",
+            &self.event_table
+        )];
+        for (ordinal, partition) in partitions {
+            let partition = TableName::unchecked(partition);
+            selects.push(format!(
+                "SELECT ({ordinal} << {shift}) + {position}, {columns} FROM {partition}"
+            ));
+        }
+        format!(
+            "
+DROP VIEW IF EXISTS {};
+CREATE VIEW {} AS
+{}",
+            &self.event_source,
+            &self.event_source,
+            selects.join("\nUNION ALL\n")
+        )
+    }
+    // The queries writing to a partition rather than the events table.
+    pub fn for_partition(&self, partition: &str) -> Self {
+        Self::build(
+            partition,
+            self.snapshot_table.clone(),
+            self.json_encoding,
+            self.metadata_storage,
+            self.payload_storage,
+            Partitioning::None,
+            self.table_layout,
+            self.cascading_deletes,
+            self.hash_chain,
+        )
+    }
     pub fn get_last_events(&self, last_sequence: usize) -> String {
         format!(
             "
-SELECT aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata
+SELECT {}
   FROM {}
   WHERE aggregate_type = ? AND aggregate_id = ? AND sequence > {}
   ORDER BY sequence",
-            &self.event_table, last_sequence
+            &self.event_columns, &self.event_source, last_sequence
+        )
+    }
+    // Selects the snapshots of `count` aggregate instances of a type.
+    pub fn select_snapshots(&self, count: usize) -> String {
+        format!(
+            "
+SELECT {}
+  FROM {}
+  WHERE aggregate_type = ? AND aggregate_id IN ({})",
+            &self.snapshot_columns,
+            &self.snapshot_table,
+            vec!["?"; count].join(", ")
+        )
+    }
+    // Selects the events of `count` aggregate instances of a type, each following the sequence
+    // bound along with its aggregate id.
+    pub fn tail_events(&self, count: usize) -> String {
+        format!(
+            "
+WITH tails (id, after) AS (VALUES {})
+SELECT {}
+  FROM {} JOIN tails ON aggregate_id = tails.id
+  WHERE aggregate_type = ? AND sequence > tails.after
+  ORDER BY aggregate_id, sequence",
+            vec!["(?, ?)"; count].join(", "),
+            &self.event_columns,
+            &self.event_source
+        )
+    }
+    #[cfg(feature = "analytics")]
+    pub fn analytics_events(&self, field_count: usize) -> String {
+        let fields: String = (0..field_count)
+            .map(|_| {
+                format!(
+                    ", json_extract({}, '$.\"' || event_type || '\".' || ?)",
+                    &self.event_payload
+                )
+            })
+            .collect();
+        format!(
+            "
+SELECT aggregate_type, aggregate_id, sequence, event_type, event_version, created_at{}
+  FROM {}
+  ORDER BY {}",
+            fields,
+            &self.event_source,
+            self.position()
+        )
+    }
+    pub fn query_events(&self, conditions: &str, order: &str, limited: bool) -> String {
+        let limit = if limited { "\n  LIMIT ?" } else { "" };
+        format!(
+            "
+SELECT {}, {} AS position
+  FROM {}
+  WHERE {}
+  ORDER BY {} {}{}",
+            &self.event_columns,
+            self.position(),
+            &self.event_source,
+            conditions,
+            self.position(),
+            order,
+            limit
+        )
+    }
+    pub fn audit_events(&self, conditions: &str) -> String {
+        format!(
+            "
+SELECT {}, created_at, json_extract({}, ?) AS actor
+  FROM {}
+  WHERE {}
+  ORDER BY {}",
+            &self.event_columns,
+            &self.metadata,
+            &self.event_source,
+            conditions,
+            self.position()
         )
     }
 }
 
 #[test]
 fn test_queries() {
-    let query_factory = SqlQueryFactory::new("my_events", "my_snapshots");
+    let query_factory = SqlQueryFactory::new("my_events", "my_snapshots", JsonEncoding::Text);
     assert_eq!(
         query_factory.select_events(),
         "
@@ -80,8 +1126,11 @@ SELECT aggregate_type, aggregate_id, sequence, event_type, event_version, payloa
   ORDER BY sequence"
     );
     assert_eq!(query_factory.insert_event(), "
-INSERT INTO my_events (aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata)
-VALUES (?, ?, ?, ?, ?, ?, ?)");
+INSERT INTO my_events (aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata, created_at)
+VALUES (?, ?, ?, ?, ?, ?, ?, strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))");
+    assert_eq!(query_factory.insert_event_if_absent(), "
+INSERT OR IGNORE INTO my_events (aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata, created_at)
+VALUES (?, ?, ?, ?, ?, ?, ?, strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))");
     assert_eq!(
         query_factory.all_events(),
         "
@@ -89,24 +1138,109 @@ SELECT aggregate_type, aggregate_id, sequence, event_type, event_version, payloa
   FROM my_events
   WHERE aggregate_type = ?
   ORDER BY sequence"
+    );
+    assert_eq!(
+        query_factory.everything(),
+        "
+SELECT aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata
+  FROM my_events
+  ORDER BY rowid"
+    );
+    assert_eq!(
+        query_factory.count_all_events(),
+        "
+SELECT count(*)
+  FROM my_events
+  WHERE aggregate_type = ?"
+    );
+    assert_eq!(
+        query_factory.count_everything(),
+        "
+SELECT count(*)
+  FROM my_events"
+    );
+    assert_eq!(
+        query_factory.count_events(),
+        "
+SELECT count(*)
+  FROM my_events
+  WHERE aggregate_type = ? AND aggregate_id = ?"
+    );
+    assert_eq!(
+        query_factory.count_events_by_type(),
+        "
+SELECT count(*)
+  FROM my_events
+  WHERE aggregate_type = ? AND event_type = ?"
+    );
+    assert_eq!(
+        query_factory.aggregate_history(),
+        "
+SELECT aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata, created_at
+  FROM my_events
+  WHERE aggregate_type = ? AND aggregate_id = ? AND sequence >= ? AND sequence <= ?
+  ORDER BY sequence"
+    );
+    assert_eq!(
+        query_factory.previous_event_hash(),
+        "
+SELECT event_hash
+  FROM my_events
+  WHERE aggregate_type = ? AND aggregate_id = ? AND sequence < ?
+  ORDER BY sequence DESC
+  LIMIT 1"
+    );
+    assert_eq!(
+        query_factory.set_event_hash(),
+        "
+UPDATE my_events
+  SET prev_hash = ?, event_hash = ?
+  WHERE rowid = ?"
+    );
+    assert_eq!(
+        query_factory.merkle_root_at(),
+        "
+SELECT first_position, last_position, leaves, root, computed_at
+  FROM my_events_merkle_roots
+  WHERE first_position <= ?1 AND last_position >= ?1"
+    );
+    assert_eq!(
+        query_factory.with_hash_chain(HashChain::Global).hash_chain_events(),
+        "
+SELECT aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata, prev_hash, event_hash
+  FROM my_events
+  ORDER BY rowid"
     );
     assert_eq!(
         query_factory.insert_snapshot(),
         "
-INSERT INTO my_snapshots (aggregate_type, aggregate_id, last_sequence, current_snapshot, payload)
-VALUES (?, ?, ?, ?, ?)"
+INSERT INTO my_snapshots (aggregate_type, aggregate_id, last_sequence, current_snapshot, aggregate_version, payload)
+VALUES (?, ?, ?, ?, ?, ?)"
     );
     assert_eq!(
         query_factory.update_snapshot(),
         "
 UPDATE my_snapshots
-  SET last_sequence= ? , payload= ?, current_snapshot= ?
+  SET last_sequence= ? , payload= ?, current_snapshot= ?, aggregate_version= ?
+  WHERE aggregate_type= ? AND aggregate_id= ? AND last_sequence < ?"
+    );
+    assert_eq!(
+        query_factory.rewrite_snapshot(),
+        "
+UPDATE my_snapshots
+  SET last_sequence= ? , payload= ?, current_snapshot= ?, aggregate_version= ?
   WHERE aggregate_type= ? AND aggregate_id= ? AND current_snapshot= ?"
+    );
+    assert_eq!(
+        query_factory.delete_snapshot(),
+        "
+DELETE FROM my_snapshots
+  WHERE aggregate_type = ? AND aggregate_id = ?"
     );
     assert_eq!(
         query_factory.select_snapshot(),
         "
-SELECT aggregate_type, aggregate_id, last_sequence, current_snapshot, payload
+SELECT aggregate_type, aggregate_id, last_sequence, current_snapshot, aggregate_version, payload
   FROM my_snapshots
   WHERE aggregate_type = ? AND aggregate_id = ?"
     );
@@ -118,4 +1252,278 @@ SELECT aggregate_type, aggregate_id, sequence, event_type, event_version, payloa
   WHERE aggregate_type = ? AND aggregate_id = ? AND sequence > 20
   ORDER BY sequence"
     );
+    assert_eq!(
+        query_factory.select_snapshots(2),
+        "
+SELECT aggregate_type, aggregate_id, last_sequence, current_snapshot, aggregate_version, payload
+  FROM my_snapshots
+  WHERE aggregate_type = ? AND aggregate_id IN (?, ?)"
+    );
+    assert_eq!(
+        query_factory.tail_events(2),
+        "
+WITH tails (id, after) AS (VALUES (?, ?), (?, ?))
+SELECT aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata
+  FROM my_events JOIN tails ON aggregate_id = tails.id
+  WHERE aggregate_type = ? AND sequence > tails.after
+  ORDER BY aggregate_id, sequence"
+    );
+    assert_eq!(
+        query_factory.set_global_position(),
+        "
+UPDATE my_events
+  SET metadata = json_set(metadata, '$.global_position', CAST(? AS TEXT))
+  WHERE rowid = ?"
+    );
+    assert_eq!(
+        query_factory.compact_first_event(),
+        "
+UPDATE my_events
+  SET event_type = ?, event_version = ?, payload = ?
+  WHERE aggregate_type = ? AND aggregate_id = ? AND sequence = 1"
+    );
+    assert_eq!(
+        query_factory.delete_compacted_events(),
+        "
+DELETE FROM my_events
+  WHERE aggregate_type = ? AND aggregate_id = ? AND sequence > 1 AND sequence <= ?"
+    );
+    assert_eq!(
+        query_factory.shift_events(),
+        "
+UPDATE my_events
+  SET sequence = sequence + ?
+  WHERE aggregate_type = ? AND aggregate_id = ? AND sequence > ?"
+    );
+    assert_eq!(
+        query_factory.restore_shifted_events(),
+        "
+UPDATE my_events
+  SET sequence = sequence - ?
+  WHERE aggregate_type = ? AND aggregate_id = ? AND sequence > ?"
+    );
+    assert_eq!(
+        query_factory.stream_heads(),
+        "
+SELECT aggregate_type, aggregate_id, max(sequence), count(*)
+  FROM my_events
+  GROUP BY aggregate_type, aggregate_id"
+    );
+    assert_eq!(
+        query_factory.intern_metadata(),
+        "
+INSERT INTO metadata_dictionary (key, value)
+VALUES (?, ?)
+  ON CONFLICT (key, value) DO NOTHING"
+    );
+    assert_eq!(
+        query_factory.append_metadata(),
+        "
+UPDATE my_events
+  SET metadata = json_insert(metadata, '$[#]', ?)
+  WHERE rowid = ?"
+    );
+    let dictionary_factory = query_factory.with_metadata_storage(MetadataStorage::Dictionary);
+    assert_eq!(
+        dictionary_factory.select_events(),
+        "
+SELECT aggregate_type, aggregate_id, sequence, event_type, event_version, payload, CASE json_type(metadata) WHEN 'array' THEN json_replace((SELECT json_group_object(d.key, json(d.value)) FROM json_each(metadata) AS m JOIN metadata_dictionary AS d ON d.id = m.value), '$.global_position', CAST(rowid AS TEXT)) ELSE metadata END AS metadata
+  FROM my_events
+  WHERE aggregate_type = ? AND aggregate_id = ?
+  ORDER BY sequence"
+    );
+    assert_eq!(
+        query_factory.select_metadata_after(),
+        "
+SELECT rowid, metadata
+  FROM my_events
+  WHERE rowid > ?
+  ORDER BY rowid
+  LIMIT ?"
+    );
+    let deduplicated_factory = query_factory.with_payload_storage(PayloadStorage::Deduplicated);
+    assert_eq!(
+        deduplicated_factory.select_events(),
+        "
+SELECT aggregate_type, aggregate_id, sequence, event_type, event_version, CASE json_type(payload) WHEN 'integer' THEN (SELECT p.contents FROM event_payloads AS p WHERE p.id = json_extract(payload, '$')) ELSE payload END AS payload, metadata
+  FROM my_events
+  WHERE aggregate_type = ? AND aggregate_id = ?
+  ORDER BY sequence"
+    );
+    assert_eq!(
+        query_factory.select_payload_id(),
+        "
+SELECT id
+  FROM event_payloads
+  WHERE hash = ? AND contents = ?"
+    );
+    assert_eq!(
+        query_factory.intern_payload(),
+        "
+INSERT INTO event_payloads (hash, contents)
+VALUES (?, ?)"
+    );
+    assert_eq!(
+        query_factory.collect_payloads(),
+        "
+DELETE FROM event_payloads
+  WHERE id NOT IN (SELECT json_extract(payload, '$') FROM my_events WHERE json_type(payload) = 'integer')"
+    );
+    let without_rowid = query_factory.with_table_layout(EventTableLayout::WithoutRowid);
+    assert_eq!(without_rowid.insert_event(), "
+INSERT INTO my_events (aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata, created_at, global_position)
+VALUES (?, ?, ?, ?, ?, ?, ?, strftime('%Y-%m-%dT%H:%M:%fZ', 'now'), ?)");
+    assert_eq!(
+        without_rowid.everything(),
+        "
+SELECT aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata
+  FROM my_events
+  ORDER BY global_position"
+    );
+    assert_eq!(
+        without_rowid.next_position(),
+        "
+SELECT coalesce(max(global_position), 0) + 1
+  FROM my_events"
+    );
+    assert_eq!(
+        query_factory.aggregate_ids(),
+        "
+SELECT DISTINCT aggregate_id
+  FROM my_events
+  WHERE aggregate_type = ? AND aggregate_id > ?
+  ORDER BY aggregate_id
+  LIMIT ?"
+    );
+    assert_eq!(
+        query_factory.recent_aggregate_ids(),
+        "
+SELECT aggregate_id
+  FROM my_events
+  WHERE aggregate_type = ?
+  GROUP BY aggregate_id
+  ORDER BY max(rowid) DESC
+  LIMIT ?"
+    );
+    assert_eq!(
+        query_factory.last_sequence(),
+        "
+SELECT coalesce(max(sequence), 0)
+  FROM my_events
+  WHERE aggregate_type = ? AND aggregate_id = ?"
+    );
+    assert_eq!(
+        query_factory.event_position(),
+        "
+SELECT rowid
+  FROM my_events
+  WHERE aggregate_type = ? AND aggregate_id = ? AND sequence = ?"
+    );
+    assert_eq!(
+        query_factory.head_position(),
+        "
+SELECT coalesce(max(rowid), 0)
+  FROM my_events"
+    );
+    assert_eq!(
+        query_factory.count_aggregates(),
+        "
+SELECT count(DISTINCT aggregate_id)
+  FROM my_events
+  WHERE aggregate_type = ?"
+    );
+    assert_eq!(
+        query_factory.aggregate_id_at(),
+        "
+SELECT DISTINCT aggregate_id
+  FROM my_events
+  WHERE aggregate_type = ?
+  ORDER BY aggregate_id
+  LIMIT 1 OFFSET ?"
+    );
+    assert_eq!(
+        query_factory.count_snapshots(),
+        "
+SELECT count(*)
+  FROM my_snapshots
+  WHERE aggregate_type = ?"
+    );
+    assert_eq!(
+        query_factory.count_rename_conflicts(),
+        "
+SELECT count(DISTINCT aggregate_id)
+  FROM my_events AS renamed
+  WHERE aggregate_type = ?1
+    AND EXISTS (SELECT 1 FROM my_events WHERE aggregate_type = ?2 AND aggregate_id = renamed.aggregate_id)"
+    );
+    assert_eq!(
+        query_factory.rename_events(),
+        "
+UPDATE my_events
+  SET aggregate_type = ?2
+  WHERE aggregate_type = ?1
+    AND aggregate_id IN (SELECT DISTINCT aggregate_id FROM my_events WHERE aggregate_type = ?1 LIMIT ?3)
+  RETURNING aggregate_id"
+    );
+    assert_eq!(
+        query_factory.rename_snapshots(),
+        "
+UPDATE my_snapshots
+  SET aggregate_type = ?2
+  WHERE aggregate_type = ?1
+    AND aggregate_id IN (SELECT aggregate_id FROM my_snapshots WHERE aggregate_type = ?1 LIMIT ?3)
+  RETURNING aggregate_id"
+    );
+    #[cfg(feature = "analytics")]
+    assert_eq!(
+        query_factory.analytics_events(1),
+        "
+SELECT aggregate_type, aggregate_id, sequence, event_type, event_version, created_at, json_extract(payload, '$.\"' || event_type || '\".' || ?)
+  FROM my_events
+  ORDER BY rowid"
+    );
+    assert_eq!(
+        query_factory.audit_events("aggregate_id = ?"),
+        "
+SELECT aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata, created_at, json_extract(metadata, ?) AS actor
+  FROM my_events
+  WHERE aggregate_id = ?
+  ORDER BY rowid"
+    );
+}
+
+#[test]
+fn test_jsonb_queries() {
+    let query_factory = SqlQueryFactory::new("my_events", "my_snapshots", JsonEncoding::Jsonb);
+    assert_eq!(
+        query_factory.select_events(),
+        "
+SELECT aggregate_type, aggregate_id, sequence, event_type, event_version, json(payload) AS payload, json(metadata) AS metadata
+  FROM my_events
+  WHERE aggregate_type = ? AND aggregate_id = ?
+  ORDER BY sequence"
+    );
+    assert_eq!(query_factory.insert_event(), "
+INSERT INTO my_events (aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata, created_at)
+VALUES (?, ?, ?, ?, ?, jsonb(?), jsonb(?), strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))");
+    assert_eq!(
+        query_factory.insert_snapshot(),
+        "
+INSERT INTO my_snapshots (aggregate_type, aggregate_id, last_sequence, current_snapshot, aggregate_version, payload)
+VALUES (?, ?, ?, ?, ?, jsonb(?))"
+    );
+    assert_eq!(
+        query_factory.update_snapshot(),
+        "
+UPDATE my_snapshots
+  SET last_sequence= ? , payload= jsonb(?), current_snapshot= ?, aggregate_version= ?
+  WHERE aggregate_type= ? AND aggregate_id= ? AND last_sequence < ?"
+    );
+    assert_eq!(
+        query_factory.select_snapshot(),
+        "
+SELECT aggregate_type, aggregate_id, last_sequence, current_snapshot, aggregate_version, json(payload) AS payload
+  FROM my_snapshots
+  WHERE aggregate_type = ? AND aggregate_id = ?"
+    );
 }
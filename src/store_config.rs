@@ -0,0 +1,279 @@
+use std::collections::BTreeSet;
+
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::connection::{with_checked_connection, ConnectionProvider};
+use crate::error::SqliteAggregateError;
+use crate::{HashChain, PayloadStorage, SqliteEventRepository};
+
+/// The table recording the configuration of each event store, see
+/// `SqliteEventRepository::store_config`.
+pub const STORE_CONFIG_TABLE: &str = "store_config";
+
+/// The version of the layout of the tables written by the repositories, recorded with the
+/// `StoreConfig`. It only changes with releases requiring a migration of existing tables.
+pub const STORE_SCHEMA_VERSION: u32 = 1;
+
+/// The settings of an event repository that determine how events, snapshots and metadata are
+/// stored, see `SqliteEventRepository::store_config`.
+///
+/// The configuration is recorded the first time a repository is made ready and verified by
+/// every later `SqliteEventRepository::ready`, so that a process configured with e.g. another
+/// JSON encoding or metadata codec fails to start rather than writing data that other processes
+/// cannot read.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreConfig {
+    /// The version of the table layout, see `STORE_SCHEMA_VERSION`.
+    pub schema_version: u32,
+    /// The name of the events table.
+    pub events_table: String,
+    /// The name of the snapshots table, including its schema if any.
+    pub snapshots_table: String,
+    /// The encoding of payloads, see `JsonEncoding`.
+    pub json_encoding: String,
+    /// The storage of metadata, see `MetadataStorage`.
+    pub metadata_storage: String,
+    /// The storage of payloads, see `PayloadStorage`. It is only recorded for deduplicated
+    /// payloads, so that configurations recorded before payloads could be deduplicated still
+    /// match.
+    #[serde(
+        default = "inline_payloads",
+        skip_serializing_if = "is_inline_payloads"
+    )]
+    pub payload_storage: String,
+    /// The name of the metadata codec, if any, see `MetadataCodec::name`.
+    pub metadata_codec: Option<String>,
+    /// The partitioning of events, see `Partitioning`.
+    pub partitioning: String,
+    /// The layout of the events table, see `EventTableLayout`.
+    pub table_layout: String,
+    /// Whether snapshots are deleted along with the events of their aggregate instance.
+    pub cascading_deletes: bool,
+    /// The hash chain linking events, see `HashChain`. It is only recorded if events are
+    /// hashed, so that configurations recorded before hash chains were introduced still match.
+    #[serde(default = "no_hash_chain", skip_serializing_if = "is_no_hash_chain")]
+    pub hash_chain: String,
+}
+
+impl StoreConfig {
+    /// Returns a fingerprint of the configuration, the hex-encoded 64-bit FNV-1a hash of its
+    /// JSON serialization.
+    pub fn fingerprint(&self) -> String {
+        let json = serde_json::to_string(self).unwrap_or_default();
+        let hash = json.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+        format!("{hash:016x}")
+    }
+
+    // Returns the settings differing from `recorded`, along with both values.
+    fn differences(&self, recorded: &Value) -> Vec<String> {
+        let (Ok(Value::Object(current)), Value::Object(recorded)) =
+            (serde_json::to_value(self), recorded)
+        else {
+            return Vec::new();
+        };
+        // settings only recorded when they differ from their default may be missing from either
+        let settings = current
+            .keys()
+            .chain(recorded.keys())
+            .collect::<BTreeSet<_>>();
+        settings
+            .into_iter()
+            .filter_map(|setting| {
+                let value = current.get(setting).unwrap_or(&Value::Null);
+                let recorded = recorded.get(setting).unwrap_or(&Value::Null);
+                (recorded != value).then(|| format!("{setting} is {value}, recorded {recorded}"))
+            })
+            .collect()
+    }
+}
+
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    /// Returns the settings of the repository that determine how data is stored.
+    ///
+    /// ```
+    /// use rusqlite_es::SqliteEventRepository;
+    ///
+    /// fn log_config(repo: &SqliteEventRepository) {
+    ///     let config = repo.store_config();
+    ///     println!("{} ({})", config.events_table, config.fingerprint());
+    /// }
+    /// ```
+    pub fn store_config(&self) -> StoreConfig {
+        let query_factory = self.query_factory();
+        StoreConfig {
+            schema_version: STORE_SCHEMA_VERSION,
+            events_table: query_factory.event_table().to_string(),
+            snapshots_table: query_factory.snapshot_table().to_string(),
+            json_encoding: format!("{:?}", self.json_encoding()),
+            metadata_storage: format!("{:?}", query_factory.metadata_storage()),
+            payload_storage: format!("{:?}", query_factory.payload_storage()),
+            metadata_codec: self
+                .metadata_codec()
+                .as_ref()
+                .map(|metadata_codec| metadata_codec.name().to_string()),
+            partitioning: format!("{:?}", query_factory.partitioning()),
+            table_layout: format!("{:?}", query_factory.table_layout()),
+            cascading_deletes: query_factory.cascading_deletes(),
+            hash_chain: format!("{:?}", query_factory.hash_chain()),
+        }
+    }
+
+    /// Records the repository's configuration in place of the one recorded for its events
+    /// table, e.g. after migrating the stored data to another JSON encoding. Until then
+    /// `ready` fails for a repository whose configuration differs from the recorded one.
+    ///
+    /// ```
+    /// use rusqlite_es::{JsonEncoding, SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// fn migrated(repo: SqliteEventRepository) -> Result<(), SqliteAggregateError> {
+    ///     // ...convert the stored payloads first
+    ///     repo.with_json_encoding(JsonEncoding::Jsonb)
+    ///         .record_store_config()
+    /// }
+    /// ```
+    pub fn record_store_config(&self) -> Result<(), SqliteAggregateError> {
+        let config = self.store_config();
+        with_checked_connection(self.pool(), |connection| {
+            connection.execute_batch(&create_store_config_table())?;
+            connection.execute(
+                &format!(
+                    "INSERT OR REPLACE INTO {STORE_CONFIG_TABLE} (events_table, fingerprint, config) VALUES (?, ?, ?)"
+                ),
+                (
+                    &config.events_table,
+                    config.fingerprint(),
+                    serde_json::to_value(&config).map_err(to_sql_error)?,
+                ),
+            )?;
+            Ok(())
+        })
+    }
+}
+
+fn inline_payloads() -> String {
+    format!("{:?}", PayloadStorage::Inline)
+}
+
+fn is_inline_payloads(payload_storage: &str) -> bool {
+    payload_storage == inline_payloads()
+}
+
+fn no_hash_chain() -> String {
+    format!("{:?}", HashChain::None)
+}
+
+fn is_no_hash_chain(hash_chain: &str) -> bool {
+    hash_chain == no_hash_chain()
+}
+
+pub(crate) fn create_store_config_table() -> String {
+    format!(
+        "CREATE TABLE IF NOT EXISTS {STORE_CONFIG_TABLE}
+(
+    events_table text NOT NULL,
+    fingerprint  text NOT NULL,
+    config       json NOT NULL,
+    recorded_at  text NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    PRIMARY KEY (events_table)
+);
+"
+    )
+}
+
+// Records the configuration if none is recorded for its events table, otherwise verifies that
+// it matches the recorded one.
+pub(crate) fn verify_store_config(
+    connection: &Connection,
+    config: &StoreConfig,
+) -> Result<(), SqliteAggregateError> {
+    let fingerprint = config.fingerprint();
+    connection.execute(
+        &format!(
+            "INSERT OR IGNORE INTO {STORE_CONFIG_TABLE} (events_table, fingerprint, config) VALUES (?, ?, ?)"
+        ),
+        (
+            &config.events_table,
+            &fingerprint,
+            serde_json::to_value(config)?,
+        ),
+    )?;
+    let recorded: Option<(String, Value)> = connection
+        .query_row(
+            &format!("SELECT fingerprint, config FROM {STORE_CONFIG_TABLE} WHERE events_table = ?"),
+            [&config.events_table],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    match recorded {
+        Some((recorded_fingerprint, recorded)) if recorded_fingerprint != fingerprint => {
+            Err(SqliteAggregateError::UnknownError(
+                format!(
+                    "the configuration of the event store '{}' does not match the recorded one: {}",
+                    config.events_table,
+                    config.differences(&recorded).join(", ")
+                )
+                .into(),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn to_sql_error(err: serde_json::Error) -> rusqlite::Error {
+    rusqlite::Error::ToSqlConversionFailure(Box::new(err))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::testing::tests::TEST_CONNECTION_STRING;
+    use crate::{
+        default_sqlite_pool, MetadataStorage, SqliteEventRepository, StoreConfig,
+        STORE_SCHEMA_VERSION,
+    };
+
+    #[tokio::test]
+    async fn store_config() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let repo = SqliteEventRepository::new(pool.clone());
+        let config = repo.store_config();
+        assert_eq!(
+            StoreConfig {
+                schema_version: STORE_SCHEMA_VERSION,
+                events_table: "events".to_string(),
+                snapshots_table: "snapshots".to_string(),
+                json_encoding: "Text".to_string(),
+                metadata_storage: "Inline".to_string(),
+                payload_storage: "Inline".to_string(),
+                metadata_codec: None,
+                partitioning: "None".to_string(),
+                table_layout: "Rowid".to_string(),
+                cascading_deletes: false,
+                hash_chain: "None".to_string(),
+            },
+            config
+        );
+        assert_eq!(16, config.fingerprint().len());
+        repo.ready().await.unwrap();
+        SqliteEventRepository::connect(pool.clone()).await.unwrap();
+
+        let err = SqliteEventRepository::new(pool.clone())
+            .with_metadata_storage(MetadataStorage::Dictionary)
+            .ready()
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            "the configuration of the event store 'events' does not match the recorded one: metadata_storage is \"Dictionary\", recorded \"Inline\"",
+            err.to_string()
+        );
+
+        let migrated = SqliteEventRepository::new(pool.clone())
+            .with_metadata_storage(MetadataStorage::Dictionary);
+        migrated.record_store_config().unwrap();
+        assert!(SqliteEventRepository::connect(pool).await.is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use rusqlite::types::Value;
+use rusqlite::{Connection, Row};
+
+use crate::error::SqliteAggregateError;
+use crate::identifier::TableName;
+
+/// How streamed events that cannot be read are handled, see
+/// `SqliteEventRepository::with_stream_error_policy`.
+///
+/// This applies to rows of the events table that cannot be read as a `SerializedEvent`, e.g. a
+/// payload that is not valid JSON, while streaming events with `stream_events`,
+/// `stream_all_events` or `stream_everything`. Events that are read but cannot be deserialized
+/// into the aggregate's event type are reported by `ReplayStream` as usual.
+///
+/// ```
+/// use rusqlite_es::StreamErrorPolicy;
+///
+/// let policy = StreamErrorPolicy::DeadLetter("events_dead_letter".to_string());
+/// println!("{}", policy.create_dead_letter_table().unwrap());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum StreamErrorPolicy {
+    /// The error is passed on to the stream in place of the event, the default.
+    #[default]
+    FailFast,
+    /// The row is skipped and reported as a `tracing` warning with the target
+    /// `rusqlite_es::stream_errors`.
+    SkipAndReport,
+    /// The row is skipped, reported as with `SkipAndReport` and copied as-is into the named
+    /// dead-letter table along with the error, see `create_dead_letter_table`. If the row cannot
+    /// be copied, the stream ends with that error.
+    DeadLetter(String),
+}
+
+impl StreamErrorPolicy {
+    /// Returns the DDL creating the dead-letter table if it does not yet exist, `None` unless
+    /// the policy is `DeadLetter`. The table is also created by `SqliteEventRepository::ready`.
+    ///
+    /// The event columns are untyped so that any value can be copied into them.
+    pub fn create_dead_letter_table(&self) -> Option<String> {
+        let Self::DeadLetter(table) = self else {
+            return None;
+        };
+        let table = TableName::unchecked(table);
+        Some(format!(
+            "
+CREATE TABLE IF NOT EXISTS {table}
+(
+    id             integer NOT NULL,
+    aggregate_type,
+    aggregate_id,
+    sequence,
+    event_type,
+    event_version,
+    payload,
+    metadata,
+    error          text    NOT NULL,
+    recorded_at    text    NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    PRIMARY KEY (id)
+);"
+        ))
+    }
+
+    // Handles a row that could not be read, returning the error to pass on to the stream or
+    // `None` to skip the row.
+    pub(crate) fn recover(
+        &self,
+        connection: &Connection,
+        row: &Row<'_>,
+        err: SqliteAggregateError,
+    ) -> Option<SqliteAggregateError> {
+        if *self == Self::FailFast {
+            return Some(err);
+        }
+        // the event columns are read as stored, they are the first seven columns of every
+        // event query
+        let columns = (0..7)
+            .map(|index| row.get::<_, Value>(index).unwrap_or(Value::Null))
+            .collect::<Vec<_>>();
+        tracing::warn!(
+            target: "rusqlite_es::stream_errors",
+            aggregate_type = ?columns[0],
+            aggregate_id = ?columns[1],
+            sequence = ?columns[2],
+            error = %err,
+            "skipped an event that could not be read"
+        );
+        let Self::DeadLetter(table) = self else {
+            return None;
+        };
+        let table = TableName::unchecked(table);
+        let insert = format!(
+            "INSERT INTO {table} (aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata, error) VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+        );
+        let mut params = columns;
+        params.push(Value::Text(err.to_string()));
+        match connection
+            .prepare_cached(&insert)
+            .and_then(|mut statement| statement.execute(rusqlite::params_from_iter(params)))
+        {
+            Ok(_) => None,
+            Err(err) => Some(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde_json::json;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, SqliteEventRepository, StreamErrorPolicy};
+
+    #[tokio::test]
+    async fn stream_error_policy() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        conn.execute_batch(
+            "INSERT INTO events (aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata)
+             VALUES ('TestAggregate', 'corrupt', 1, 'Created', '1.0', 'not json', '{}');",
+        )
+        .unwrap();
+        drop(conn);
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = json!({});
+        let repo = SqliteEventRepository::new(pool);
+        repo.insert_events::<TestAggregate>(&[created])
+            .await
+            .unwrap();
+
+        let read = |repo: SqliteEventRepository| async move {
+            let mut stream = repo.stream_everything().await.unwrap();
+            let (mut events, mut errors) = (0, 0);
+            while let Some(event) = stream.next().await {
+                match event {
+                    Ok(_) => events += 1,
+                    Err(_) => errors += 1,
+                }
+            }
+            (events, errors)
+        };
+        assert_eq!((2, 1), read(repo.clone()).await);
+        let skipping = repo
+            .clone()
+            .with_stream_error_policy(StreamErrorPolicy::SkipAndReport);
+        assert_eq!((2, 0), read(skipping).await);
+
+        let policy = StreamErrorPolicy::DeadLetter("events_dead_letter".to_string());
+        let ddl = policy.create_dead_letter_table().unwrap();
+        repo.with_connection(|conn| conn.execute_batch(&ddl))
+            .unwrap();
+        let dead_lettering = repo.with_stream_error_policy(policy);
+        let mut stream = dead_lettering
+            .stream_all_events::<TestAggregate>()
+            .await
+            .unwrap();
+        let mut found = 0;
+        while let Some(event) = stream.next::<TestAggregate>(&None).await {
+            assert_eq!(id, event.unwrap().aggregate_id);
+            found += 1;
+        }
+        assert_eq!(1, found);
+        let (aggregate_id, payload): (String, String) = dead_lettering
+            .with_connection(|conn| {
+                conn.query_row(
+                    "SELECT aggregate_id, payload FROM events_dead_letter",
+                    [],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+            })
+            .unwrap();
+        assert_eq!("corrupt", aggregate_id);
+        assert_eq!("not json", payload);
+    }
+}
@@ -0,0 +1,116 @@
+/// The column holding the global position of events in a `EventTableLayout::WithoutRowid`
+/// events table.
+pub const GLOBAL_POSITION_COLUMN: &str = "global_position";
+
+/// How events are keyed and ordered within the events table, see
+/// `SqliteEventRepository::with_table_layout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EventTableLayout {
+    /// Events are stored in a rowid table, their `rowid` is their global position.
+    #[default]
+    Rowid,
+    /// Events are stored in a `WITHOUT ROWID` table clustered on its primary key, aggregate
+    /// type, aggregate id and sequence, with their global position held in an explicit
+    /// `global_position` column.
+    ///
+    /// The events of an aggregate instance are then stored next to each other, which
+    /// significantly speeds up loading aggregates from a large store, at the cost of slower
+    /// reads in commit order, e.g. `stream_everything`, which use the `global_position` index.
+    /// The table must be created with this layout, see `SqliteEventRepository::ready`, an
+    /// existing table cannot be converted in place.
+    WithoutRowid,
+}
+
+impl EventTableLayout {
+    /// The column ordering events by their global position.
+    pub fn position_column(&self) -> &'static str {
+        match self {
+            EventTableLayout::Rowid => "rowid",
+            EventTableLayout::WithoutRowid => GLOBAL_POSITION_COLUMN,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde_json::json;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, Tested, TEST_CONNECTION_STRING,
+    };
+    use crate::{
+        default_sqlite_pool, CommitReceipts, EventOrder, EventQuery, EventTableLayout,
+        SqliteEventRepository, GLOBAL_POSITION_METADATA_KEY,
+    };
+
+    #[tokio::test]
+    async fn without_rowid_table() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let repo = SqliteEventRepository::new(pool)
+            .with_table_layout(EventTableLayout::WithoutRowid)
+            .with_commit_receipts(CommitReceipts::RecordedWithMetadata)
+            .ready()
+            .await
+            .unwrap();
+        let without_rowid: String = repo
+            .with_connection(|conn| {
+                conn.query_row(
+                    "SELECT sql FROM sqlite_master WHERE name = 'events'",
+                    [],
+                    |row| row.get(0),
+                )
+            })
+            .unwrap();
+        assert!(without_rowid.ends_with("WITHOUT ROWID"));
+
+        let first = uuid::Uuid::new_v4().to_string();
+        let second = uuid::Uuid::new_v4().to_string();
+        let created = |id: &String| {
+            let mut created =
+                test_event_envelope(id, 1, TestEvent::Created(Created { id: id.clone() }));
+            created.metadata = json!({});
+            created
+        };
+        let mut tested = test_event_envelope(
+            &first,
+            2,
+            TestEvent::Tested(Tested {
+                test_name: "clustered".to_string(),
+            }),
+        );
+        tested.metadata = json!({});
+        repo.insert_events::<TestAggregate>(&[created(&first)])
+            .await
+            .unwrap();
+        repo.insert_events::<TestAggregate>(&[created(&second)])
+            .await
+            .unwrap();
+        let receipt = repo
+            .insert_events::<TestAggregate>(&[tested])
+            .await
+            .unwrap();
+        assert_eq!(3, receipt.global_position);
+
+        let events = repo.get_events::<TestAggregate>(&first).await.unwrap();
+        assert_eq!(2, events.len());
+        assert_eq!(json!("3"), events[1].metadata[GLOBAL_POSITION_METADATA_KEY]);
+
+        // events are read in commit order rather than in the order of the primary key
+        let recent = repo
+            .query_events(
+                &EventQuery::new()
+                    .with_positions(2..)
+                    .with_order(EventOrder::MostRecent),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![(first.as_str(), 2), (second.as_str(), 1)],
+            recent
+                .iter()
+                .map(|event| (event.aggregate_id.as_str(), event.sequence))
+                .collect::<Vec<_>>()
+        );
+    }
+}
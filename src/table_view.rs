@@ -0,0 +1,340 @@
+use std::marker::PhantomData;
+
+use async_trait::async_trait;
+use cqrs_es::persist::{PersistenceError, ViewContext, ViewRepository};
+use cqrs_es::{Aggregate, View};
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::types::ToSqlOutput;
+use rusqlite::{Connection, OptionalExtension, Row, ToSql};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::connection::{with_checked_connection, ConnectionProvider};
+use crate::error::SqliteAggregateError;
+use crate::identifier::{quote_identifier, TableName};
+
+/// A view stored in a table with a column per field rather than as a single serialized
+/// payload, so that views can be filtered and indexed on their fields with plain SQL. See
+/// `SqliteTableViewRepository`.
+///
+/// Usually derived with `#[derive(SqliteView)]`, which requires the `derive` feature.
+pub trait SqliteView: Sized {
+    /// The name of the view table.
+    const TABLE: &'static str;
+    /// The columns of the view, in the order of `to_row` and `from_row`.
+    const COLUMNS: &'static [ViewColumn];
+
+    /// Returns the value of each column.
+    fn to_row(&self) -> Result<Vec<ToSqlOutput<'_>>, rusqlite::Error>;
+
+    /// Reads a view from a row holding its columns, starting at column index `offset`.
+    fn from_row(row: &Row<'_>, offset: usize) -> Result<Self, rusqlite::Error>;
+
+    /// Returns the DDL creating the view table, keyed by `view_id` and with the `version` used by
+    /// `ViewContext`, and any indexes declared on its columns.
+    fn create_table() -> String {
+        let mut columns = vec![
+            "view_id text NOT NULL PRIMARY KEY".to_string(),
+            "version bigint CHECK (version >= 0) NOT NULL".to_string(),
+        ];
+        for column in Self::COLUMNS {
+            let not_null = if column.nullable { "" } else { " NOT NULL" };
+            columns.push(format!(
+                "{} {}{not_null}",
+                quote_identifier(column.name),
+                column.sql_type
+            ));
+        }
+        let table = TableName::unchecked(Self::TABLE);
+        let mut ddl = format!(
+            "CREATE TABLE IF NOT EXISTS {table} (\n  {}\n);\n",
+            columns.join(",\n  ")
+        );
+        for column in Self::COLUMNS.iter().filter(|column| column.indexed) {
+            ddl.push_str(&format!(
+                "CREATE INDEX IF NOT EXISTS {} ON {table} ({});\n",
+                table.suffixed(&format!("_{}", column.name)),
+                quote_identifier(column.name)
+            ));
+        }
+        ddl
+    }
+}
+
+/// A column of a `SqliteView`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ViewColumn {
+    /// The name of the column.
+    pub name: &'static str,
+    /// The SQL type of the column, e.g. `text` or `json`.
+    pub sql_type: &'static str,
+    /// Whether the column may hold NULL.
+    pub nullable: bool,
+    /// Whether the column is indexed.
+    pub indexed: bool,
+}
+
+/// Serializes a field stored in a `json` column of a `SqliteView`.
+pub fn json_column<T: Serialize>(value: &T) -> Result<ToSqlOutput<'static>, rusqlite::Error> {
+    let json = serde_json::to_string(value)
+        .map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))?;
+    Ok(ToSqlOutput::from(json))
+}
+
+/// Deserializes a field stored in a `json` column of a `SqliteView`.
+pub fn json_from_row<T: DeserializeOwned>(
+    row: &Row<'_>,
+    index: usize,
+) -> Result<T, rusqlite::Error> {
+    let json: serde_json::Value = row.get(index)?;
+    serde_json::from_value(json).map_err(|err| {
+        rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(err))
+    })
+}
+
+/// A query repository storing views in a table with a column per field, see `SqliteView`.
+///
+/// Cloning is cheap, clones share the connection pool.
+pub struct SqliteTableViewRepository<V, A, P = Pool<SqliteConnectionManager>> {
+    insert_sql: String,
+    update_sql: String,
+    select_sql: String,
+    pool: P,
+    _phantom: PhantomData<(V, A)>,
+}
+
+// Implemented manually, deriving would needlessly require the view and aggregate to be `Clone`.
+impl<V, A, P: Clone> Clone for SqliteTableViewRepository<V, A, P> {
+    fn clone(&self) -> Self {
+        Self {
+            insert_sql: self.insert_sql.clone(),
+            update_sql: self.update_sql.clone(),
+            select_sql: self.select_sql.clone(),
+            pool: self.pool.clone(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<V, A, P> SqliteTableViewRepository<V, A, P>
+where
+    V: View<A> + SqliteView,
+    A: Aggregate,
+    P: ConnectionProvider,
+{
+    /// Creates a new `SqliteTableViewRepository` storing views in the table `V::TABLE`, which
+    /// can be created with `create_table`.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{SqliteTableViewRepository, SqliteView};
+    ///
+    /// fn configure_view_repo<V: cqrs_es::View<MyAggregate> + SqliteView>(
+    ///     pool: Pool<SqliteConnectionManager>,
+    /// ) -> SqliteTableViewRepository<V, MyAggregate> {
+    ///     SqliteTableViewRepository::new(pool)
+    /// }
+    /// ```
+    pub fn new(pool: P) -> Self {
+        let columns = V::COLUMNS
+            .iter()
+            .map(|column| quote_identifier(column.name))
+            .collect::<Vec<_>>();
+        let table = TableName::unchecked(V::TABLE);
+        let insert_sql = format!(
+            "INSERT INTO {table} (view_id, version, {}) VALUES (?, ?{})",
+            columns.join(", "),
+            ", ?".repeat(columns.len())
+        );
+        let update_sql = format!(
+            "UPDATE {table} SET version= ?, {} WHERE view_id= ?",
+            columns
+                .iter()
+                .map(|column| format!("{column}= ?"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let select_sql = format!(
+            "SELECT version, {} FROM {table} WHERE view_id= ?",
+            columns.join(", ")
+        );
+        Self {
+            insert_sql,
+            update_sql,
+            select_sql,
+            pool,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Creates the view table and its indexes if they do not yet exist, see
+    /// `SqliteView::create_table`.
+    pub fn create_table(&self) -> Result<(), SqliteAggregateError> {
+        with_checked_connection(&self.pool, |connection| {
+            connection.execute_batch(&V::create_table())
+        })
+    }
+
+    fn select_view(
+        &self,
+        connection: &Connection,
+        view_id: &str,
+    ) -> Result<Option<(V, ViewContext)>, SqliteAggregateError> {
+        let mut statement = connection.prepare_cached(&self.select_sql)?;
+        let row = statement
+            .query_row([view_id], |row| Ok((row.get(0)?, V::from_row(row, 1)?)))
+            .optional()?;
+        Ok(row.map(|(version, view)| (view, ViewContext::new(view_id.to_string(), version))))
+    }
+
+    fn write_view(
+        &self,
+        connection: &Connection,
+        view: V,
+        context: ViewContext,
+    ) -> Result<(), SqliteAggregateError> {
+        let version = context.version + 1;
+        let columns = view.to_row()?;
+        let mut params: Vec<&dyn ToSql> = Vec::with_capacity(columns.len() + 2);
+        if context.version == 0 {
+            params.push(&context.view_instance_id);
+            params.push(&version);
+            params.extend(columns.iter().map(|column| column as &dyn ToSql));
+        } else {
+            params.push(&version);
+            params.extend(columns.iter().map(|column| column as &dyn ToSql));
+            params.push(&context.view_instance_id);
+        }
+        let sql = match context.version {
+            0 => &self.insert_sql,
+            _ => &self.update_sql,
+        };
+        connection
+            .prepare_cached(sql)?
+            .execute(rusqlite::params_from_iter(params))?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<V, A, P> ViewRepository<V, A> for SqliteTableViewRepository<V, A, P>
+where
+    V: View<A> + SqliteView,
+    A: Aggregate,
+    P: ConnectionProvider,
+{
+    async fn load(&self, view_id: &str) -> Result<Option<V>, PersistenceError> {
+        let connection = self.pool.connection()?;
+        Ok(self
+            .select_view(&connection, view_id)?
+            .map(|(view, _)| view))
+    }
+
+    async fn load_with_context(
+        &self,
+        view_id: &str,
+    ) -> Result<Option<(V, ViewContext)>, PersistenceError> {
+        let connection = self.pool.connection()?;
+        Ok(self.select_view(&connection, view_id)?)
+    }
+
+    async fn update_view(&self, view: V, context: ViewContext) -> Result<(), PersistenceError> {
+        let connection = self.pool.connection()?;
+        Ok(self.write_view(&connection, view, context)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cqrs_es::persist::{ViewContext, ViewRepository};
+    use cqrs_es::{EventEnvelope, View};
+    use serde::{Deserialize, Serialize};
+
+    use crate::testing::tests::{TestAggregate, TestEvent, TEST_CONNECTION_STRING};
+    use crate::{default_sqlite_pool, SqliteTableViewRepository, SqliteView, ViewColumn};
+
+    #[derive(
+        Debug, Default, Clone, PartialEq, Serialize, Deserialize, sqlite_es_derive::SqliteView,
+    )]
+    #[sqlite_view(table = "test_table_view")]
+    struct TestTableView {
+        #[sqlite_view(index)]
+        name: String,
+        count: u32,
+        score: Option<f64>,
+        events: Vec<TestEvent>,
+    }
+
+    impl View<TestAggregate> for TestTableView {
+        fn update(&mut self, event: &EventEnvelope<TestAggregate>) {
+            self.count += 1;
+            self.events.push(event.payload.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn table_view_repository() {
+        assert_eq!("test_table_view", TestTableView::TABLE);
+        assert_eq!(
+            ViewColumn {
+                name: "score",
+                sql_type: "real",
+                nullable: true,
+                indexed: false,
+            },
+            TestTableView::COLUMNS[2]
+        );
+        assert_eq!(
+            "CREATE TABLE IF NOT EXISTS test_table_view (
+  view_id text NOT NULL PRIMARY KEY,
+  version bigint CHECK (version >= 0) NOT NULL,
+  name text NOT NULL,
+  count integer NOT NULL,
+  score real,
+  events json NOT NULL
+);
+CREATE INDEX IF NOT EXISTS test_table_view_name ON test_table_view (name);
+",
+            TestTableView::create_table()
+        );
+
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let repo = SqliteTableViewRepository::<TestTableView, TestAggregate>::new(pool.clone());
+        repo.create_table().unwrap();
+
+        let view_id = uuid::Uuid::new_v4().to_string();
+        let view = TestTableView {
+            name: "mapped".to_string(),
+            count: 1,
+            score: None,
+            events: vec![],
+        };
+        repo.update_view(view.clone(), ViewContext::new(view_id.clone(), 0))
+            .await
+            .unwrap();
+        let (found, context) = repo.load_with_context(&view_id).await.unwrap().unwrap();
+        assert_eq!(view, found);
+        assert_eq!(1, context.version);
+
+        let updated = TestTableView {
+            score: Some(0.5),
+            count: 2,
+            ..view
+        };
+        repo.update_view(updated.clone(), context).await.unwrap();
+        assert_eq!(Some(updated), repo.load(&view_id).await.unwrap());
+
+        let conn = pool.get().unwrap();
+        let score: f64 = conn
+            .query_row(
+                "SELECT score FROM test_table_view WHERE name = 'mapped'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(0.5, score);
+    }
+}
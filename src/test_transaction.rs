@@ -0,0 +1,176 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::time::Duration;
+
+use rusqlite::Connection;
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::{SqliteEventRepository, SqliteViewRepository};
+
+/// How long `TestTransaction::open` waits for the transactions of other tests sharing the
+/// database file.
+const TEST_BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A `ConnectionProvider` running everything within a single transaction that is rolled back
+/// once the provider and all of its clones are dropped, so that integration tests against a
+/// shared database file leave no data behind and need not isolate their data, e.g. with random
+/// aggregate ids.
+///
+/// The repositories commit events and views within savepoints of the test transaction, so a
+/// failed commit, e.g. an optimistic lock conflict, is rolled back on its own as it would be
+/// otherwise, and all data committed by the test is visible within the test. The transaction
+/// holds the database's write lock for the duration of the test, tests sharing a database file
+/// therefore run one at a time, each waiting for the previous one to complete.
+///
+/// The connection is checked out by one operation at a time, an operation started while another
+/// holds it, e.g. an event stream that has not been fully consumed, fails with
+/// `SqliteAggregateError::ConnectionError` rather than waiting.
+///
+/// ```
+/// use rusqlite_es::{SqliteAggregateError, TestTransaction, TestTransactionEventRepository};
+///
+/// fn test_repo(database: &str) -> Result<TestTransactionEventRepository, SqliteAggregateError> {
+///     // the schema is expected to exist, e.g. created once with `admin::init_schema`
+///     let transaction = TestTransaction::open(database)?;
+///     Ok(TestTransactionEventRepository::new(transaction))
+/// }
+/// ```
+#[derive(Clone)]
+pub struct TestTransaction {
+    connection: Arc<RolledBack>,
+}
+
+// Rolls back the transaction of its connection when dropped.
+struct RolledBack(Mutex<Connection>);
+
+impl Drop for RolledBack {
+    fn drop(&mut self) {
+        if let Ok(connection) = self.0.get_mut() {
+            if !connection.is_autocommit() {
+                let _ = connection.execute_batch("ROLLBACK");
+            }
+        }
+    }
+}
+
+impl TestTransaction {
+    /// Begins the test transaction on an existing connection, waiting for the write lock.
+    pub fn begin(connection: Connection) -> Result<Self, SqliteAggregateError> {
+        connection.execute_batch("BEGIN IMMEDIATE")?;
+        Ok(Self {
+            connection: Arc::new(RolledBack(Mutex::new(connection))),
+        })
+    }
+
+    /// Opens a connection to the database file at `path` using write-ahead logging and begins
+    /// the test transaction.
+    pub fn open<T: AsRef<Path>>(path: T) -> Result<Self, SqliteAggregateError> {
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "wal")?;
+        connection.busy_timeout(TEST_BUSY_TIMEOUT)?;
+        Self::begin(connection)
+    }
+}
+
+impl ConnectionProvider for TestTransaction {
+    type Connection<'a> = MutexGuard<'a, Connection>;
+
+    fn connection(&self) -> Result<Self::Connection<'_>, SqliteAggregateError> {
+        // waiting would deadlock the test, the holder being e.g. an event stream the test has
+        // not consumed
+        self.connection.0.try_lock().map_err(|err| match err {
+            TryLockError::WouldBlock => SqliteAggregateError::ConnectionError(
+                "the test transaction's connection is already checked out, e.g. by an event stream that has not been consumed".into(),
+            ),
+            TryLockError::Poisoned(err) => {
+                SqliteAggregateError::ConnectionError(err.to_string().into())
+            }
+        })
+    }
+}
+
+/// An event repository whose commits are rolled back at the end of the test, see
+/// `TestTransaction`.
+pub type TestTransactionEventRepository = SqliteEventRepository<TestTransaction>;
+
+/// A view repository whose commits are rolled back at the end of the test, see
+/// `TestTransaction`.
+pub type TestTransactionViewRepository<V, A> = SqliteViewRepository<V, A, TestTransaction>;
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use cqrs_es::persist::{PersistedEventRepository, ViewRepository};
+    use serde_json::json;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, TestView, Tested,
+    };
+    use crate::{
+        ConnectionProvider, SqliteAggregateError, TestTransaction, TestTransactionEventRepository,
+        TestTransactionViewRepository,
+    };
+
+    #[tokio::test]
+    async fn rolled_back() {
+        let path = std::env::temp_dir().join(format!("{}.db", uuid::Uuid::new_v4()));
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute_batch(contents.as_str())
+            .unwrap();
+
+        let transaction = TestTransaction::open(&path).unwrap();
+        let view_repo = TestTransactionViewRepository::<TestView, TestAggregate>::new(
+            "test_view",
+            transaction.clone(),
+        );
+        let repo = TestTransactionEventRepository::new(transaction)
+            .with_transactional_view(view_repo.clone());
+        let id = "account-1";
+        let mut created = test_event_envelope(id, 1, TestEvent::Created(Created { id: id.into() }));
+        created.metadata = json!({});
+        repo.insert_events::<TestAggregate>(&[created.clone()])
+            .await
+            .unwrap();
+        // a failed commit is rolled back on its own
+        let mut tested = test_event_envelope(
+            id,
+            2,
+            TestEvent::Tested(Tested {
+                test_name: "rolled back".to_string(),
+            }),
+        );
+        tested.metadata = json!({});
+        assert!(matches!(
+            repo.insert_events::<TestAggregate>(&[tested, created])
+                .await,
+            Err(SqliteAggregateError::OptimisticLock)
+        ));
+        assert_eq!(1, repo.get_events::<TestAggregate>(id).await.unwrap().len());
+        assert!(view_repo.load(id).await.unwrap().is_some());
+        // a second checkout fails rather than deadlocking the test
+        let held = repo.pool().connection().unwrap();
+        assert!(matches!(
+            repo.pool().connection(),
+            Err(SqliteAggregateError::ConnectionError(_))
+        ));
+        drop(held);
+        // the transaction is rolled back once its last clone is dropped
+        drop(repo);
+        drop(view_repo);
+
+        let count: i64 = rusqlite::Connection::open(&path)
+            .unwrap()
+            .query_row(
+                "SELECT count(*) FROM events WHERE aggregate_id = ?",
+                [id],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(0, count);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,8 +1,8 @@
 #[cfg(test)]
 pub(crate) mod tests {
-    use crate::SqliteViewRepository;
+    use crate::SqliteGenericQuery;
     use async_trait::async_trait;
-    use cqrs_es::persist::{GenericQuery, SerializedEvent, SerializedSnapshot};
+    use cqrs_es::persist::{SerializedEvent, SerializedSnapshot};
     use cqrs_es::{Aggregate, DomainEvent, EventEnvelope, View};
     use serde::{Deserialize, Serialize};
     use serde_json::Value;
@@ -99,8 +99,7 @@ pub(crate) mod tests {
 
     pub(crate) enum TestCommand {}
 
-    pub(crate) type TestQueryRepository =
-        GenericQuery<SqliteViewRepository<TestView, TestAggregate>, TestView, TestAggregate>;
+    pub(crate) type TestQueryRepository = SqliteGenericQuery<TestView, TestAggregate>;
 
     #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
     pub(crate) struct TestView {
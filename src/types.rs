@@ -1,7 +1,88 @@
-use crate::SqliteEventRepository;
-use cqrs_es::persist::PersistedEventStore;
+use std::time::Duration;
+
+use crate::{SqliteEventRepository, SqliteViewRepository};
+use cqrs_es::persist::{GenericQuery, PersistedEventStore};
 use cqrs_es::CqrsFramework;
+use rusqlite::TransactionBehavior;
 
 /// A convenience type for a CqrsFramework backed by
 /// [SqliteEventRepository](struct.SqliteEventRepository.html).
 pub type SqliteCqrs<A> = CqrsFramework<A, PersistedEventStore<SqliteEventRepository, A>>;
+
+/// A convenience type for a GenericQuery backed by
+/// [SqliteViewRepository](struct.SqliteViewRepository.html).
+pub type SqliteGenericQuery<V, A> = GenericQuery<SqliteViewRepository<V, A>, V, A>;
+
+/// The encoding used to store JSON payloads, metadata and views.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JsonEncoding {
+    /// JSON is stored as text, this is supported by all SQLite versions.
+    #[default]
+    Text,
+    /// JSON is stored using SQLite's binary JSONB encoding, reducing the parsing overhead of
+    /// `json_extract` and similar functions. Values are read back through `json()`, which
+    /// transparently accepts rows stored in either encoding.
+    ///
+    /// _Requires SQLite 3.45.0 or later, `SqliteEventRepository::ready` fails with
+    /// `SqliteAggregateError::UnsupportedSqliteVersion` otherwise._
+    Jsonb,
+}
+
+// The first SQLite version providing the JSONB functions.
+pub(crate) const JSONB_SQLITE_VERSION: &str = "3.45.0";
+
+impl JsonEncoding {
+    pub(crate) fn read_column(&self, column: &str) -> String {
+        match self {
+            JsonEncoding::Text => column.to_string(),
+            JsonEncoding::Jsonb => format!("json({column}) AS {column}"),
+        }
+    }
+
+    pub(crate) fn write_param(&self) -> &'static str {
+        match self {
+            JsonEncoding::Text => "?",
+            JsonEncoding::Jsonb => "jsonb(?)",
+        }
+    }
+}
+
+/// The transaction behavior used when committing events and snapshots.
+///
+/// SQLite allows a single writer at a time. An `IMMEDIATE` transaction acquires the write lock
+/// when it begins, waiting up to the connection's busy timeout for other writers. A `DEFERRED`
+/// transaction only acquires the write lock on its first write, which allows readers to proceed
+/// for longer but fails immediately with `SQLITE_BUSY` when the lock cannot be upgraded (the
+/// busy timeout does not apply). Deferred transactions must therefore specify how many times a
+/// failed commit is retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteTransaction {
+    /// Acquire the write lock when the transaction begins, recommended unless multiple processes
+    /// write to the database concurrently and the busy timeout is causing contention.
+    #[default]
+    Immediate,
+    /// Acquire the write lock on first write, retrying the whole commit up to `max_retries`
+    /// times (with a short, linearly increasing backoff) if the database is busy.
+    Deferred {
+        /// The number of retries after the initial attempt.
+        max_retries: usize,
+    },
+}
+
+impl WriteTransaction {
+    pub(crate) fn behavior(&self) -> TransactionBehavior {
+        match self {
+            WriteTransaction::Immediate => TransactionBehavior::Immediate,
+            WriteTransaction::Deferred { .. } => TransactionBehavior::Deferred,
+        }
+    }
+
+    pub(crate) fn retry_backoff(&self, attempt: usize) -> Option<Duration> {
+        match self {
+            WriteTransaction::Deferred { max_retries } if attempt < *max_retries => {
+                Some(Duration::from_millis(10 * (attempt as u64 + 1)))
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use cqrs_es::{Aggregate, EventEnvelope};
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::connection::{with_checked_connection, ConnectionProvider};
+use crate::error::SqliteAggregateError;
+use crate::SqliteEventRepository;
+
+/// The table recording the progress of views, see
+/// `SqliteViewRepository::with_progress_tracking`.
+pub const VIEW_PROGRESS_TABLE: &str = "view_progress";
+
+/// How far a view lags behind the events, see `SqliteEventRepository::projection_lag`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProjectionLag {
+    /// The name of the view, as passed to `SqliteViewRepository::new`.
+    pub view_name: String,
+    /// The global position of the last event dispatched to the view.
+    pub position: i64,
+    /// The number of positions between the last event dispatched to the view and the last
+    /// committed event. Positions count the events of all aggregate types, some of which may
+    /// never be dispatched to the view.
+    pub behind: i64,
+    /// When the view's progress was last recorded, as an RFC 3339 timestamp in UTC.
+    pub updated_at: String,
+}
+
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    /// Reports how far each view tracked with `SqliteViewRepository::with_progress_tracking`
+    /// lags behind the last committed event, e.g. to alert on projections that are stuck.
+    ///
+    /// ```
+    /// use rusqlite_es::{SqliteAggregateError, SqliteEventRepository};
+    ///
+    /// fn stuck_views(repo: &SqliteEventRepository) -> Result<Vec<String>, SqliteAggregateError> {
+    ///     Ok(repo
+    ///         .projection_lag()?
+    ///         .into_iter()
+    ///         .filter(|lag| lag.behind > 10_000)
+    ///         .map(|lag| lag.view_name)
+    ///         .collect())
+    /// }
+    /// ```
+    pub fn projection_lag(&self) -> Result<Vec<ProjectionLag>, SqliteAggregateError> {
+        let head_position = self.query_factory().head_position();
+        with_checked_connection(self.pool(), |connection| {
+            let head: i64 = connection.query_row(head_position, [], |row| row.get(0))?;
+            connection
+                .prepare(&format!(
+                    "SELECT view_name, position, updated_at FROM {VIEW_PROGRESS_TABLE} ORDER BY view_name"
+                ))?
+                .query_map([], |row| {
+                    let position = row.get(1)?;
+                    Ok(ProjectionLag {
+                        view_name: row.get(0)?,
+                        position,
+                        behind: (head - position).max(0),
+                        updated_at: row.get(2)?,
+                    })
+                })?
+                .collect()
+        })
+    }
+}
+
+// Records the global position of `event`, the last event dispatched to a view, unless the view
+// has already progressed beyond it, e.g. when events are redelivered.
+pub(crate) fn record_progress<A: Aggregate>(
+    connection: &Connection,
+    progress_sql: &str,
+    view_name: &str,
+    aggregate_type: &str,
+    event: &EventEnvelope<A>,
+) -> Result<(), SqliteAggregateError> {
+    let position: Option<i64> = connection
+        .prepare_cached(progress_sql)?
+        .query_row(
+            (aggregate_type, &event.aggregate_id, event.sequence as i64),
+            |row| row.get(0),
+        )
+        .optional()?;
+    let Some(position) = position else {
+        return Ok(());
+    };
+    connection
+        .prepare_cached(&format!(
+            "INSERT INTO {VIEW_PROGRESS_TABLE} (view_name, position) VALUES (?, ?)
+  ON CONFLICT (view_name) DO UPDATE
+  SET position = max(position, excluded.position), updated_at = excluded.updated_at"
+        ))?
+        .execute((view_name, position))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde_json::json;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, TestView, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, SqliteEventRepository, SqliteViewRepository};
+
+    #[tokio::test]
+    async fn projection_lag() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+        let repo = SqliteEventRepository::new(pool.clone());
+        let view_repo = SqliteViewRepository::<TestView, TestAggregate>::new("test_view", pool)
+            .with_progress_tracking(&repo);
+        let repo = repo.with_transactional_view(view_repo);
+        assert!(repo.projection_lag().unwrap().is_empty());
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = json!({});
+        repo.persist::<TestAggregate>(&[created], None)
+            .await
+            .unwrap();
+        let lag = repo.projection_lag().unwrap();
+        assert_eq!(1, lag.len());
+        assert_eq!("test_view", lag[0].view_name);
+        // the seeded 'Customer' event precedes the committed event
+        assert_eq!(2, lag[0].position);
+        assert_eq!(0, lag[0].behind);
+
+        repo.with_connection(|conn| {
+            conn.execute(
+                "INSERT INTO events (aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata) VALUES ('Other', 'other', 1, '', '', '{}', '{}')",
+                [],
+            )
+        })
+        .unwrap();
+        assert_eq!(1, repo.projection_lag().unwrap()[0].behind);
+    }
+}
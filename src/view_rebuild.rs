@@ -0,0 +1,286 @@
+use std::sync::Arc;
+
+use cqrs_es::{Aggregate, EventEnvelope, View};
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::replay_progress::ReplayRun;
+use crate::{SqliteEventRepository, SqliteViewRepository};
+
+/// Configures `SqliteEventRepository::rebuild_view`.
+///
+/// ```
+/// use rusqlite_es::ViewRebuild;
+///
+/// let rebuild = ViewRebuild::new().with_workers(8).with_batch_size(500);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ViewRebuild {
+    workers: usize,
+    batch_size: usize,
+}
+
+impl Default for ViewRebuild {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            batch_size: 100,
+        }
+    }
+}
+
+impl ViewRebuild {
+    /// Creates a rebuild by 4 workers, each writing the views of 100 aggregate instances at a
+    /// time.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Splits the aggregate instances into `workers` ranges of aggregate ids, rebuilt concurrently.
+    pub fn with_workers(self, workers: usize) -> Self {
+        Self {
+            workers: workers.max(1),
+            ..self
+        }
+    }
+
+    /// Applies the events of `batch_size` aggregate instances at a time, writing the affected
+    /// views in a single transaction per batch.
+    pub fn with_batch_size(self, batch_size: usize) -> Self {
+        Self {
+            batch_size: batch_size.max(1),
+            ..self
+        }
+    }
+}
+
+// The aggregate ids following `after`, up to and including `last`, rebuilt by one worker.
+struct IdRange {
+    after: String,
+    last: Option<String>,
+}
+
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    /// Rebuilds the views of `view_repo` from the events of every aggregate instance of type
+    /// `A`, returning the number of aggregate instances replayed.
+    ///
+    /// The aggregate instances are split into ranges of aggregate ids, one per worker, and the
+    /// workers run concurrently on blocking threads. Each worker reads the events of its range
+    /// one batch of aggregate instances at a time and applies them to the views within a single
+    /// transaction per batch. Reading and applying events proceed in parallel, while writes are
+    /// serialized by SQLite, so the pools should provide a connection per worker.
+    ///
+    /// The views are expected to be cleared beforehand, e.g. with `admin::clear_view`; with
+    /// event deduplication (see `SqliteViewRepository::with_event_dedup`) events already applied
+    /// to a view are skipped, so an interrupted rebuild can be run again. Views combining the
+    /// events of several aggregate instances (see `SqliteViewRepository::with_view_ids`) receive
+    /// them in the order of the workers' batches rather than the order of commits.
+    ///
+    /// Rebuilds report to and can be cancelled through the repository's `ReplayProgress`. A
+    /// cancelled rebuild ends with `SqliteAggregateError::ReplayCancelled` once all workers have
+    /// stopped, leaving the batches already written in place.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// # use cqrs_es::persist::doc::MyView;
+    /// use rusqlite_es::{SqliteAggregateError, SqliteEventRepository, SqliteViewRepository, ViewRebuild};
+    ///
+    /// async fn rebuild(
+    ///     repo: &SqliteEventRepository,
+    ///     view_repo: &SqliteViewRepository<MyView, MyAggregate>,
+    /// ) -> Result<usize, SqliteAggregateError> {
+    ///     repo.rebuild_view(view_repo, ViewRebuild::new().with_workers(8))
+    ///         .await
+    /// }
+    /// ```
+    pub async fn rebuild_view<V, A, VP>(
+        &self,
+        view_repo: &SqliteViewRepository<V, A, VP>,
+        options: ViewRebuild,
+    ) -> Result<usize, SqliteAggregateError>
+    where
+        V: View<A> + 'static,
+        A: Aggregate + 'static,
+        VP: ConnectionProvider,
+    {
+        let aggregate_type = A::aggregate_type();
+        // the workers report to a single run, completed once all of them are
+        let run = match self.replay_progress() {
+            Some(progress) => {
+                let total: i64 = self.pool().connection()?.query_row(
+                    self.query_factory().count_all_events(),
+                    [&aggregate_type],
+                    |row| row.get(0),
+                )?;
+                Some(Arc::new(progress.start(total as usize)))
+            }
+            None => None,
+        };
+        let tasks = self
+            .id_ranges(&aggregate_type, options.workers)?
+            .into_iter()
+            .map(|range| {
+                let repo = self.clone();
+                let view_repo = view_repo.clone();
+                let run = run.clone();
+                tokio::task::spawn_blocking(move || {
+                    repo.rebuild_range(&view_repo, range, options.batch_size, run)
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut rebuilt = 0;
+        let mut failed = None;
+        // every worker is awaited, so that none still writes once the rebuild has returned
+        for task in tasks {
+            match task
+                .await
+                .map_err(|err| SqliteAggregateError::UnknownError(Box::new(err)))
+                .and_then(|result| result)
+            {
+                Ok(count) => rebuilt += count,
+                Err(err) => {
+                    failed.get_or_insert(err);
+                }
+            }
+        }
+        match failed {
+            Some(err) => Err(err),
+            None => Ok(rebuilt),
+        }
+    }
+
+    // Splits the aggregate ids of a type into up to `workers` ranges of equal size.
+    fn id_ranges(
+        &self,
+        aggregate_type: &str,
+        workers: usize,
+    ) -> Result<Vec<IdRange>, SqliteAggregateError> {
+        let connection = self.pool().connection()?;
+        let count: i64 = connection.query_row(
+            self.query_factory().count_aggregates(),
+            [aggregate_type],
+            |row| row.get(0),
+        )?;
+        let workers = (workers as i64).clamp(1, count.max(1));
+        let mut statement = connection.prepare_cached(self.query_factory().aggregate_id_at())?;
+        let mut ranges = Vec::with_capacity(workers as usize);
+        let mut after = String::new();
+        for worker in 1..workers {
+            let last: String = statement
+                .query_row((aggregate_type, worker * count / workers - 1), |row| {
+                    row.get(0)
+                })?;
+            ranges.push(IdRange {
+                after: std::mem::replace(&mut after, last.clone()),
+                last: Some(last),
+            });
+        }
+        ranges.push(IdRange { after, last: None });
+        Ok(ranges)
+    }
+
+    fn rebuild_range<V, A, VP>(
+        &self,
+        view_repo: &SqliteViewRepository<V, A, VP>,
+        range: IdRange,
+        batch_size: usize,
+        run: Option<Arc<ReplayRun>>,
+    ) -> Result<usize, SqliteAggregateError>
+    where
+        V: View<A>,
+        A: Aggregate,
+        VP: ConnectionProvider,
+    {
+        let aggregate_type = A::aggregate_type();
+        let mut after = range.after;
+        let mut rebuilt = 0;
+        loop {
+            let mut ids = self.aggregate_ids(&aggregate_type, &after, batch_size)?;
+            if let Some(last) = &range.last {
+                ids.retain(|id| id <= last);
+            }
+            let Some(next) = ids.last() else {
+                return Ok(rebuilt);
+            };
+            after = next.clone();
+            let mut events = Vec::new();
+            for aggregate_id in &ids {
+                if run.as_deref().is_some_and(ReplayRun::is_cancelled) {
+                    return Err(SqliteAggregateError::ReplayCancelled);
+                }
+                for event in self.aggregate_events(&aggregate_type, aggregate_id)? {
+                    if let Some(run) = &run {
+                        run.record(aggregate_id);
+                    }
+                    events.push(EventEnvelope::<A>::try_from(event).map_err(|err| {
+                        SqliteAggregateError::DeserializationError(Box::new(err))
+                    })?);
+                }
+            }
+            view_repo.dispatch_events(&events)?;
+            rebuilt += ids.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use cqrs_es::persist::ViewRepository;
+    use serde_json::json;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, TestView, Tested,
+        TEST_CONNECTION_STRING,
+    };
+    use crate::{
+        default_sqlite_pool, ReplayProgress, SqliteEventRepository, SqliteViewRepository,
+        ViewRebuild,
+    };
+
+    #[tokio::test]
+    async fn rebuild_view() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let progress = ReplayProgress::new();
+        let repo = SqliteEventRepository::new(pool.clone()).with_replay_progress(progress.clone());
+        let view_repo = SqliteViewRepository::<TestView, TestAggregate>::new("test_view", pool);
+        let ids = (0..7)
+            .map(|_| uuid::Uuid::new_v4().to_string())
+            .collect::<Vec<_>>();
+        for id in &ids {
+            let mut created =
+                test_event_envelope(id, 1, TestEvent::Created(Created { id: id.clone() }));
+            created.metadata = json!({});
+            let mut tested = test_event_envelope(
+                id,
+                2,
+                TestEvent::Tested(Tested {
+                    test_name: "rebuilt".to_string(),
+                }),
+            );
+            tested.metadata = json!({});
+            repo.insert_events::<TestAggregate>(&[created, tested])
+                .await
+                .unwrap();
+        }
+
+        let rebuild = ViewRebuild::new().with_workers(3).with_batch_size(2);
+        assert_eq!(7, repo.rebuild_view(&view_repo, rebuild).await.unwrap());
+        assert_eq!(14, progress.report().processed);
+        for id in &ids {
+            let view = view_repo.load(id).await.unwrap().unwrap();
+            assert_eq!(2, view.events.len());
+        }
+
+        // events already applied are skipped
+        assert_eq!(7, repo.rebuild_view(&view_repo, rebuild).await.unwrap());
+        let view = view_repo.load(&ids[0]).await.unwrap().unwrap();
+        assert_eq!(2, view.events.len());
+    }
+}
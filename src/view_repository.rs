@@ -1,144 +1,1060 @@
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
-use cqrs_es::persist::{PersistenceError, ViewContext, ViewRepository};
-use cqrs_es::{Aggregate, View};
+use cqrs_es::persist::{PersistenceError, SerializedEvent, ViewContext, ViewRepository};
+use cqrs_es::{Aggregate, EventEnvelope, Query, View};
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::OptionalExtension;
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior};
+use serde_json::{Map, Value};
 
+use crate::access_policy::check_access;
+use crate::connection::{in_transaction, with_checked_connection, ConnectionProvider};
 use crate::error::SqliteAggregateError;
+use crate::identifier::TableName;
+use crate::index_advisor::recording;
+use crate::query_timeout::with_timeout;
+use crate::slow_query::timed;
+use crate::view_progress::record_progress;
+use crate::{
+    AccessKind, AccessPolicy, ConsistencyToken, Cursor, IndexAdvisor, JsonEncoding, Page,
+    PayloadKind, PayloadLimits, SlowQueryLog, SqliteEventRepository, REDACTED_PARAM,
+};
+
+const DEFAULT_CONSISTENCY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How often `load_at_least` checks whether the view has caught up.
+const CONSISTENCY_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 /// An SQLite backed query repository for use in backing a `GenericQuery`.
-pub struct SqliteViewRepository<V, A> {
+///
+/// The repository can also be used as a `Query` itself, applying the dispatched events to the
+/// views of their aggregate instances (or those of `with_view_ids`) within a single transaction and skipping events already
+/// applied, see `with_event_dedup`. Errors are reported as `tracing` errors with the target
+/// `rusqlite_es::view`, unless passed to an error handler, see `with_error_handler`.
+///
+/// Cloning is cheap, clones share the connection pool.
+pub struct SqliteViewRepository<V, A, P = Pool<SqliteConnectionManager>> {
+    view_name: String,
     insert_sql: String,
     update_sql: String,
     select_sql: String,
-    pool: Pool<SqliteConnectionManager>,
+    select_applied_sql: String,
+    insert_applied_sql: String,
+    update_applied_sql: String,
+    list_sql: String,
+    pool: P,
+    payload_limits: PayloadLimits,
+    slow_query_log: Option<SlowQueryLog>,
+    query_timeout: Option<Duration>,
+    // explicitly configured, otherwise enabled unless the view has `view_ids`
+    event_dedup: Option<bool>,
+    // whether the view table was found to have the `last_applied` column, see `with_event_dedup`
+    applied_column: Arc<AtomicBool>,
+    view_ids: Option<Arc<dyn ViewIdMapper<A>>>,
+    consistency_timeout: Duration,
+    progress_sql: Option<String>,
+    error_handler: Option<Arc<ViewErrorHandler>>,
+    access_policy: Option<Arc<dyn AccessPolicy>>,
+    index_advisor: Option<IndexAdvisor>,
     _phantom: PhantomData<(V, A)>,
 }
 
-impl<V, A> SqliteViewRepository<V, A>
+/// Receives the errors of a `SqliteViewRepository` used as a `Query`, see
+/// `SqliteViewRepository::with_error_handler`.
+pub type ViewErrorHandler = dyn Fn(SqliteAggregateError) + Send + Sync;
+
+/// Maps an event to the ids of the view instances it affects, for views that are not keyed by
+/// aggregate id, e.g. rollups per customer and per account. See
+/// `SqliteViewRepository::with_view_ids`.
+///
+/// ```
+/// # use cqrs_es::doc::MyAggregate;
+/// use cqrs_es::{EventEnvelope, View};
+/// use rusqlite_es::ViewIds;
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Debug, Default, Serialize, Deserialize)]
+/// struct ActivityView {
+///     events: usize,
+/// }
+///
+/// impl View<MyAggregate> for ActivityView {
+///     fn update(&mut self, _event: &EventEnvelope<MyAggregate>) {
+///         self.events += 1;
+///     }
+/// }
+///
+/// impl ViewIds<MyAggregate> for ActivityView {
+///     fn view_ids_for(event: &EventEnvelope<MyAggregate>) -> Vec<String> {
+///         // the activity of the aggregate instance and across all instances
+///         vec![event.aggregate_id.clone(), "all".to_string()]
+///     }
+/// }
+/// ```
+pub trait ViewIds<A: Aggregate>: View<A> {
+    /// Returns the ids of the view instances to which the event is applied, none if it does not
+    /// affect any view instance.
+    fn view_ids_for(event: &EventEnvelope<A>) -> Vec<String>;
+}
+
+// `ViewIds` of a view type as a trait object, the repository does not require its view to
+// implement `ViewIds`.
+trait ViewIdMapper<A>: Send + Sync {
+    fn view_ids(&self, event: &EventEnvelope<A>) -> Vec<String>
+    where
+        A: Aggregate;
+}
+
+struct ViewIdsOf<V>(PhantomData<V>);
+
+impl<V: ViewIds<A>, A: Aggregate> ViewIdMapper<A> for ViewIdsOf<V> {
+    fn view_ids(&self, event: &EventEnvelope<A>) -> Vec<String> {
+        V::view_ids_for(event)
+    }
+}
+
+// Implemented manually, deriving would needlessly require the view and aggregate to be `Clone`.
+impl<V, A, P: Clone> Clone for SqliteViewRepository<V, A, P> {
+    fn clone(&self) -> Self {
+        Self {
+            view_name: self.view_name.clone(),
+            insert_sql: self.insert_sql.clone(),
+            update_sql: self.update_sql.clone(),
+            select_sql: self.select_sql.clone(),
+            select_applied_sql: self.select_applied_sql.clone(),
+            insert_applied_sql: self.insert_applied_sql.clone(),
+            update_applied_sql: self.update_applied_sql.clone(),
+            list_sql: self.list_sql.clone(),
+            pool: self.pool.clone(),
+            payload_limits: self.payload_limits.clone(),
+            slow_query_log: self.slow_query_log.clone(),
+            query_timeout: self.query_timeout,
+            event_dedup: self.event_dedup,
+            applied_column: self.applied_column.clone(),
+            view_ids: self.view_ids.clone(),
+            consistency_timeout: self.consistency_timeout,
+            progress_sql: self.progress_sql.clone(),
+            error_handler: self.error_handler.clone(),
+            access_policy: self.access_policy.clone(),
+            index_advisor: self.index_advisor.clone(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<V, A, P> SqliteViewRepository<V, A, P>
 where
     V: View<A>,
     A: Aggregate,
+    P: ConnectionProvider,
 {
     /// Creates a new `SqliteViewRepository` that will store serialized views in an SQLite table
     /// named identically to the `view_name` value provided. This table should be created by the
     /// user before using this query repository (see `/db/init.sql` sql initialization file).
+    /// A `view_name` that is not a plain identifier is quoted, see `TableName`.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// # use cqrs_es::persist::doc::MyView;
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::SqliteViewRepository;
+    ///
+    /// fn configure_view_repo(pool: Pool<SqliteConnectionManager>) -> SqliteViewRepository<MyView,MyAggregate> {
+    ///     SqliteViewRepository::new("my_view_table", pool)
+    /// }
+    /// ```
+    pub fn new(view_name: &str, pool: P) -> Self {
+        Self::use_encoding(view_name, pool, JsonEncoding::default())
+    }
+
+    /// Configures the encoding used to store serialized views, see `JsonEncoding`.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// # use cqrs_es::persist::doc::MyView;
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{JsonEncoding, SqliteViewRepository};
+    ///
+    /// fn configure_view_repo(pool: Pool<SqliteConnectionManager>) -> SqliteViewRepository<MyView,MyAggregate> {
+    ///     SqliteViewRepository::new("my_view_table", pool).with_json_encoding(JsonEncoding::Jsonb)
+    /// }
+    /// ```
+    pub fn with_json_encoding(self, json_encoding: JsonEncoding) -> Self {
+        // only the statements depend on the encoding, everything else configured is kept
+        let encoded = Self::use_encoding(&self.view_name, self.pool, json_encoding);
+        Self {
+            insert_sql: encoded.insert_sql,
+            update_sql: encoded.update_sql,
+            select_sql: encoded.select_sql,
+            select_applied_sql: encoded.select_applied_sql,
+            insert_applied_sql: encoded.insert_applied_sql,
+            update_applied_sql: encoded.update_applied_sql,
+            list_sql: encoded.list_sql,
+            pool: encoded.pool,
+            ..self
+        }
+    }
+
+    /// Configures limits on the serialized size of views, see `PayloadLimits`.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// # use cqrs_es::persist::doc::MyView;
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{PayloadLimits, SqliteViewRepository};
+    ///
+    /// fn configure_view_repo(pool: Pool<SqliteConnectionManager>) -> SqliteViewRepository<MyView,MyAggregate> {
+    ///     SqliteViewRepository::new("my_view_table", pool)
+    ///         .with_payload_limits(PayloadLimits::new().with_max_bytes(256 * 1024))
+    /// }
+    /// ```
+    pub fn with_payload_limits(self, payload_limits: PayloadLimits) -> Self {
+        Self {
+            payload_limits,
+            ..self
+        }
+    }
+
+    /// Configures the repository to report loads and updates of views that take longer than the
+    /// log's threshold, see `SlowQueryLog`.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// # use cqrs_es::persist::doc::MyView;
+    /// use std::time::Duration;
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{SlowQueryLog, SqliteViewRepository};
+    ///
+    /// fn configure_view_repo(pool: Pool<SqliteConnectionManager>) -> SqliteViewRepository<MyView,MyAggregate> {
+    ///     SqliteViewRepository::new("my_view_table", pool)
+    ///         .with_slow_query_log(SlowQueryLog::new(Duration::from_millis(100)))
+    /// }
+    /// ```
+    pub fn with_slow_query_log(self, slow_query_log: SlowQueryLog) -> Self {
+        Self {
+            slow_query_log: Some(slow_query_log),
+            ..self
+        }
+    }
+
+    /// Configures the repository to interrupt loads of views that run longer than
+    /// `query_timeout`, returning `SqliteAggregateError::Timeout`, so that a pathological view
+    /// query cannot hold on to its connection.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// # use cqrs_es::persist::doc::MyView;
+    /// use std::time::Duration;
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::SqliteViewRepository;
+    ///
+    /// fn configure_view_repo(pool: Pool<SqliteConnectionManager>) -> SqliteViewRepository<MyView,MyAggregate> {
+    ///     SqliteViewRepository::new("my_view_table", pool)
+    ///         .with_query_timeout(Duration::from_secs(2))
+    /// }
+    /// ```
+    pub fn with_query_timeout(self, query_timeout: Duration) -> Self {
+        Self {
+            query_timeout: Some(query_timeout),
+            ..self
+        }
+    }
+
+    /// Configures the repository to record the fields of the view table compared with
+    /// `json_extract` by the queries run through `with_connection`, see `IndexAdvisor`.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// # use cqrs_es::persist::doc::MyView;
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{IndexAdvisor, SqliteViewRepository};
+    ///
+    /// fn configure_view_repo(pool: Pool<SqliteConnectionManager>, advisor: IndexAdvisor) -> SqliteViewRepository<MyView,MyAggregate> {
+    ///     SqliteViewRepository::new("my_view_table", pool).with_index_advisor(advisor)
+    /// }
+    /// ```
+    pub fn with_index_advisor(self, index_advisor: IndexAdvisor) -> Self {
+        Self {
+            index_advisor: Some(index_advisor),
+            ..self
+        }
+    }
+
+    /// Configures the repository to consult `access_policy` before a view is loaded with `load`,
+    /// refused loads fail with `SqliteAggregateError::AccessDenied`, and to leave the views it
+    /// refuses out of the pages of `list_views`. Views loaded to apply events, i.e. with
+    /// `load_with_context`, are not checked, so that views stay current whoever commits the
+    /// events. See `AccessPolicy`.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// # use cqrs_es::persist::doc::MyView;
+    /// use rusqlite_es::{AccessRequest, SqliteViewRepository};
+    ///
+    /// fn configure_view_repo(
+    ///     repo: SqliteViewRepository<MyView, MyAggregate>,
+    /// ) -> SqliteViewRepository<MyView, MyAggregate> {
+    ///     repo.with_access_policy(Box::new(|request: &AccessRequest| {
+    ///         request.claims.get("user_id").map(String::as_str) == Some(request.id)
+    ///     }))
+    /// }
+    /// ```
+    pub fn with_access_policy(self, access_policy: Box<dyn AccessPolicy>) -> Self {
+        Self {
+            access_policy: Some(Arc::from(access_policy)),
+            ..self
+        }
+    }
+
+    /// Configures whether events already applied to a view are skipped when events are
+    /// dispatched by the repository, i.e. as a `Query` or a transactional view (see
+    /// `SqliteEventRepository::with_transactional_view`). Enabled by default, unless the
+    /// repository applies events by `with_view_ids`.
+    ///
+    /// The sequence of the last event applied from each aggregate instance is stored in the
+    /// `last_applied` column of the view table, so that replayed or redelivered events are not
+    /// applied twice (see `/db/init.sql`). The column is added to view tables created without
+    /// it the first time events are applied, so the repository's connections must be allowed to
+    /// alter the view table unless it is migrated beforehand. Views updated through
+    /// `ViewRepository::update_view`, e.g. by a `GenericQuery`, are not deduplicated.
+    ///
+    /// The column holds an entry for every aggregate instance that has ever affected the view,
+    /// and is read and rewritten along with the view for every dispatched batch. That is a single
+    /// entry for views keyed by aggregate id, but a view combining many aggregate instances, e.g.
+    /// a rollup across all instances through `with_view_ids`, grows with the store and so does
+    /// the cost of each update.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// # use cqrs_es::persist::doc::MyView;
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::SqliteViewRepository;
+    ///
+    /// fn configure_view_repo(pool: Pool<SqliteConnectionManager>) -> SqliteViewRepository<MyView,MyAggregate> {
+    ///     SqliteViewRepository::new("my_view_table", pool).with_event_dedup(false)
+    /// }
+    /// ```
+    pub fn with_event_dedup(self, event_dedup: bool) -> Self {
+        Self {
+            event_dedup: Some(event_dedup),
+            ..self
+        }
+    }
+
+    /// Applies each event dispatched by the repository to the view instances returned by
+    /// `ViewIds::view_ids_for` rather than to the view keyed by its aggregate id, i.e. when used
+    /// as a `Query` or a transactional view. All affected view instances are updated within a
+    /// single transaction.
+    ///
+    /// Event deduplication is disabled for such views unless enabled explicitly with
+    /// `with_event_dedup`, as the events applied are tracked per aggregate instance and a view
+    /// combining many instances would carry all of them, see `with_event_dedup`.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// # use cqrs_es::persist::doc::MyView;
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{SqliteViewRepository, ViewIds};
+    ///
+    /// fn configure_view_repo<V: ViewIds<MyAggregate> + 'static>(pool: Pool<SqliteConnectionManager>) -> SqliteViewRepository<V, MyAggregate> {
+    ///     SqliteViewRepository::new("my_view_table", pool).with_view_ids()
+    /// }
+    /// ```
+    pub fn with_view_ids(self) -> Self
+    where
+        V: ViewIds<A> + 'static,
+    {
+        Self {
+            view_ids: Some(Arc::new(ViewIdsOf::<V>(PhantomData))),
+            ..self
+        }
+    }
+
+    /// Records the global position of the last event dispatched to the view in the
+    /// `view_progress` table (see `/db/init.sql`), for monitoring with
+    /// `SqliteEventRepository::projection_lag`. The positions are looked up in the events of
+    /// `event_repo`, within the transaction updating the view.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// # use cqrs_es::persist::doc::MyView;
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::{SqliteEventRepository, SqliteViewRepository};
+    ///
+    /// fn configure_view_repo(
+    ///     pool: Pool<SqliteConnectionManager>,
+    ///     event_repo: &SqliteEventRepository,
+    /// ) -> SqliteViewRepository<MyView, MyAggregate> {
+    ///     SqliteViewRepository::new("my_view_table", pool).with_progress_tracking(event_repo)
+    /// }
+    /// ```
+    pub fn with_progress_tracking<EP: ConnectionProvider>(
+        self,
+        event_repo: &SqliteEventRepository<EP>,
+    ) -> Self {
+        Self {
+            progress_sql: Some(event_repo.query_factory().event_position().to_string()),
+            ..self
+        }
+    }
+
+    /// Passes the errors raised while applying dispatched events to the view to `error_handler`,
+    /// e.g. to update metrics or raise alerts, in place of reporting them as `tracing` errors.
+    /// This is the equivalent of `GenericQuery::use_error_handler` for a repository used as a
+    /// `Query` itself.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// # use cqrs_es::persist::doc::MyView;
+    /// use r2d2::Pool;
+    /// use r2d2_sqlite::SqliteConnectionManager;
+    /// use rusqlite_es::SqliteViewRepository;
+    ///
+    /// fn configure_view_repo(pool: Pool<SqliteConnectionManager>) -> SqliteViewRepository<MyView,MyAggregate> {
+    ///     SqliteViewRepository::new("my_view_table", pool)
+    ///         .with_error_handler(Box::new(|err| eprintln!("my_view_table is stale: {err}")))
+    /// }
+    /// ```
+    pub fn with_error_handler(self, error_handler: Box<ViewErrorHandler>) -> Self {
+        Self {
+            error_handler: Some(error_handler.into()),
+            ..self
+        }
+    }
+
+    /// Configures how long `load_at_least` waits for a view to catch up with a commit, 5 seconds
+    /// by default.
     ///
     /// ```
     /// # use cqrs_es::doc::MyAggregate;
     /// # use cqrs_es::persist::doc::MyView;
+    /// use std::time::Duration;
     /// use r2d2::Pool;
     /// use r2d2_sqlite::SqliteConnectionManager;
-    /// use sqlite_es::SqliteViewRepository;
+    /// use rusqlite_es::SqliteViewRepository;
     ///
     /// fn configure_view_repo(pool: Pool<SqliteConnectionManager>) -> SqliteViewRepository<MyView,MyAggregate> {
     ///     SqliteViewRepository::new("my_view_table", pool)
+    ///         .with_consistency_timeout(Duration::from_millis(500))
     /// }
     /// ```
-    pub fn new(view_name: &str, pool: Pool<SqliteConnectionManager>) -> Self {
+    pub fn with_consistency_timeout(self, consistency_timeout: Duration) -> Self {
+        Self {
+            consistency_timeout,
+            ..self
+        }
+    }
+
+    /// Loads a view once it reflects the commit identified by `token`, i.e. once the events of
+    /// that commit have been applied to it, for "read your writes" across requests. Returns
+    /// `SqliteAggregateError::ViewBehind` if the view has not caught up within the consistency
+    /// timeout, see `with_consistency_timeout`.
+    ///
+    /// Progress is tracked per aggregate instance by event deduplication (see
+    /// `with_event_dedup`), which must be enabled. The view must be updated by this repository,
+    /// i.e. as a `Query` or a transactional view, and must be affected by the committed
+    /// aggregate instance, otherwise it never catches up. Loads do not add the `last_applied`
+    /// column, an error is returned until events are applied to the view table or the table is
+    /// migrated.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// # use cqrs_es::persist::doc::MyView;
+    /// use rusqlite_es::{ConsistencyToken, SqliteAggregateError, SqliteViewRepository};
+    ///
+    /// async fn read_your_writes(
+    ///     repo: &SqliteViewRepository<MyView, MyAggregate>,
+    ///     token: &str,
+    /// ) -> Result<MyView, SqliteAggregateError> {
+    ///     let token: ConsistencyToken = token.parse()?;
+    ///     repo.load_at_least(token.aggregate_id(), &token).await
+    /// }
+    /// ```
+    pub async fn load_at_least(
+        &self,
+        view_id: &str,
+        token: &ConsistencyToken,
+    ) -> Result<V, SqliteAggregateError> {
+        if !self.event_dedup() {
+            return Err(SqliteAggregateError::UnknownError(
+                "consistency tokens require event deduplication".into(),
+            ));
+        }
+        check_access(
+            &self.access_policy,
+            AccessKind::View,
+            &self.view_name,
+            view_id,
+        )?;
+        let deadline = tokio::time::Instant::now() + self.consistency_timeout;
+        loop {
+            let row = {
+                let connection = self.pool.connection()?;
+                // the column is only added when events are applied, loads do not alter the table
+                if !self.has_applied_column(&connection)? {
+                    return Err(SqliteAggregateError::UnknownError(
+                        format!(
+                            "view table {} has no last_applied column, no events were applied to it yet",
+                            self.view_name
+                        )
+                        .into(),
+                    ));
+                }
+                self.select_row(&connection, view_id, true)?
+            };
+            if let Some(row) = row {
+                let applied = row
+                    .last_applied
+                    .get(token.aggregate_id())
+                    .and_then(Value::as_u64)
+                    .unwrap_or(0);
+                if applied >= token.aggregate_version() as u64 {
+                    return Ok(row.view);
+                }
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Err(SqliteAggregateError::ViewBehind {
+                    view_id: view_id.to_string(),
+                });
+            }
+            tokio::time::sleep(CONSISTENCY_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Loads a page of at most `limit` views along with their view ids, in the order in which
+    /// they were first stored. The following page is loaded with the cursor returned with the
+    /// page, see `Cursor`. Views are ordered by their `rowid`, so the view table must not be a
+    /// `WITHOUT ROWID` table.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// # use cqrs_es::persist::doc::MyView;
+    /// use cqrs_es::persist::PersistenceError;
+    /// use rusqlite_es::{Cursor, Page, SqliteViewRepository};
+    ///
+    /// async fn list(
+    ///     repo: &SqliteViewRepository<MyView, MyAggregate>,
+    ///     cursor: Option<Cursor>,
+    /// ) -> Result<Page<(String, MyView)>, PersistenceError> {
+    ///     repo.list_views(cursor, 50).await
+    /// }
+    /// ```
+    pub async fn list_views(
+        &self,
+        cursor: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Page<(String, V)>, PersistenceError> {
+        let after = match cursor {
+            Some(cursor) => cursor.view_position()?,
+            None => 0,
+        };
+        let connection = self.pool.connection()?;
+        let after_param = after.to_string();
+        let limit_param = limit.to_string();
+        let rows: Vec<(i64, String, Value)> = timed(
+            &self.slow_query_log,
+            &self.list_sql,
+            &[&after_param, &limit_param],
+            || {
+                with_timeout(&connection, self.query_timeout, || {
+                    connection
+                        .prepare_cached(&self.list_sql)?
+                        .query_map((after, limit as i64), |row| {
+                            Ok((row.get(0)?, row.get(1)?, row.get("payload")?))
+                        })?
+                        .collect::<Result<_, _>>()
+                        .map_err(SqliteAggregateError::from)
+                })
+            },
+            Vec::len,
+        )?;
+        let views = rows
+            .into_iter()
+            .map(|(rowid, view_id, payload)| {
+                Ok(((view_id, serde_json::from_value(payload)?), rowid))
+            })
+            .collect::<Result<Vec<_>, SqliteAggregateError>>()?;
+        let mut page = Page::new(views, Some(limit), Cursor::views);
+        // refused views are left out after the page is cut, so that its cursor still follows
+        // the last view read
+        page.items.retain(|(view_id, _)| {
+            check_access(
+                &self.access_policy,
+                AccessKind::View,
+                &self.view_name,
+                view_id,
+            )
+            .is_ok()
+        });
+        Ok(page)
+    }
+
+    /// Runs `f` with a connection checked out from the repository's pool, e.g. to query the view
+    /// table directly. A transaction left open by `f` is rolled back and reported as an error,
+    /// see `SqliteEventRepository::with_connection`. The predicates of the queries run by `f`
+    /// are recorded with the repository's index advisor, if any, see `with_index_advisor`.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// # use cqrs_es::persist::doc::MyView;
+    /// use rusqlite_es::{SqliteAggregateError, SqliteViewRepository};
+    ///
+    /// fn view_count(repo: &SqliteViewRepository<MyView, MyAggregate>) -> Result<i64, SqliteAggregateError> {
+    ///     repo.with_connection(|conn| {
+    ///         conn.query_row("SELECT count(*) FROM my_view_table", [], |row| row.get(0))
+    ///     })
+    /// }
+    /// ```
+    pub fn with_connection<F, T>(&self, f: F) -> Result<T, SqliteAggregateError>
+    where
+        F: FnOnce(&mut Connection) -> Result<T, rusqlite::Error>,
+    {
+        match &self.index_advisor {
+            Some(index_advisor) => with_checked_connection(&self.pool, |connection| {
+                recording(index_advisor, &self.view_name, connection, f)
+            }),
+            None => with_checked_connection(&self.pool, f),
+        }
+    }
+
+    fn use_encoding(view_name: &str, pool: P, json_encoding: JsonEncoding) -> Self {
+        let table = TableName::unchecked(view_name);
+        let json = json_encoding.write_param();
         let insert_sql = format!(
-            "INSERT INTO {} (payload, version, view_id) VALUES ( ?, ?, ? )",
-            view_name
+            "INSERT INTO {} (payload, version, view_id) VALUES ( {}, ?, ? )",
+            table, json
         );
         let update_sql = format!(
-            "UPDATE {} SET payload= ? , version= ? WHERE view_id= ?",
-            view_name
+            "UPDATE {} SET payload= {} , version= ? WHERE view_id= ?",
+            table, json
+        );
+        let select_sql = format!(
+            "SELECT version,{} FROM {} WHERE view_id= ?",
+            json_encoding.read_column("payload"),
+            table
+        );
+        let select_applied_sql = format!(
+            "SELECT version,{},last_applied FROM {} WHERE view_id= ?",
+            json_encoding.read_column("payload"),
+            table
+        );
+        let insert_applied_sql = format!(
+            "INSERT INTO {} (payload, version, last_applied, view_id) VALUES ( {}, ?, ?, ? )",
+            table, json
+        );
+        let update_applied_sql = format!(
+            "UPDATE {} SET payload= {} , version= ? , last_applied= ? WHERE view_id= ?",
+            table, json
+        );
+        let list_sql = format!(
+            "SELECT rowid,view_id,{} FROM {} WHERE rowid > ? ORDER BY rowid LIMIT ?",
+            json_encoding.read_column("payload"),
+            table
         );
-        let select_sql = format!("SELECT version,payload FROM {} WHERE view_id= ?", view_name);
         Self {
+            view_name: view_name.to_string(),
             insert_sql,
             update_sql,
             select_sql,
+            select_applied_sql,
+            insert_applied_sql,
+            update_applied_sql,
+            list_sql,
             pool,
+            payload_limits: Default::default(),
+            slow_query_log: None,
+            query_timeout: None,
+            event_dedup: None,
+            applied_column: Default::default(),
+            view_ids: None,
+            consistency_timeout: DEFAULT_CONSISTENCY_TIMEOUT,
+            progress_sql: None,
+            error_handler: None,
+            access_policy: None,
+            index_advisor: None,
             _phantom: Default::default(),
         }
     }
 }
 
-#[async_trait]
-impl<V, A> ViewRepository<V, A> for SqliteViewRepository<V, A>
+impl<V, A, P> SqliteViewRepository<V, A, P>
 where
     V: View<A>,
     A: Aggregate,
+    P: ConnectionProvider,
 {
-    async fn load(&self, view_id: &str) -> Result<Option<V>, PersistenceError> {
-        let connection = self.pool.get().map_err(SqliteAggregateError::from)?;
-        let mut statement = connection
-            .prepare_cached(self.select_sql.as_str())
-            .map_err(SqliteAggregateError::from)?;
-        let row = statement
-            .query_row([view_id], |row| {
-                let payload = row.get("payload")?;
-                Ok(payload)
-            })
-            .optional()
-            .map_err(SqliteAggregateError::from)?;
-        match row {
-            None => Ok(None),
-            Some(value) => {
-                let view = serde_json::from_value(value)?;
-                Ok(Some(view))
-            }
-        }
+    fn select_view(
+        &self,
+        connection: &Connection,
+        view_id: &str,
+    ) -> Result<Option<(V, ViewContext)>, SqliteAggregateError> {
+        Ok(self
+            .select_row(connection, view_id, false)?
+            .map(|row| (row.view, row.context)))
     }
 
-    async fn load_with_context(
+    // Selects a view, along with the events applied to it if `with_applied`.
+    fn select_row(
         &self,
+        connection: &Connection,
         view_id: &str,
-    ) -> Result<Option<(V, ViewContext)>, PersistenceError> {
-        let connection = self.pool.get().map_err(SqliteAggregateError::from)?;
-        let mut statement = connection
-            .prepare_cached(self.select_sql.as_str())
-            .map_err(SqliteAggregateError::from)?;
-        let row = statement
-            .query_row([view_id], |row| {
-                let version = row.get("version")?;
-                let value = row.get("payload")?;
-                Ok((version, value))
-            })
-            .optional()
-            .map_err(SqliteAggregateError::from)?;
+        with_applied: bool,
+    ) -> Result<Option<ViewRow<V>>, SqliteAggregateError> {
+        let sql = match with_applied {
+            true => &self.select_applied_sql,
+            false => &self.select_sql,
+        };
+        let row: Option<(i64, Value, Option<Value>)> = timed(
+            &self.slow_query_log,
+            sql,
+            &[view_id],
+            || {
+                with_timeout(connection, self.query_timeout, || {
+                    connection
+                        .prepare_cached(sql)?
+                        .query_row([view_id], |row| {
+                            let version = row.get("version")?;
+                            let value = row.get("payload")?;
+                            let last_applied = match with_applied {
+                                true => Some(row.get("last_applied")?),
+                                false => None,
+                            };
+                            Ok((version, value, last_applied))
+                        })
+                        .optional()
+                        .map_err(SqliteAggregateError::from)
+                })
+            },
+            |row| usize::from(row.is_some()),
+        )?;
         match row {
             None => Ok(None),
-            Some((version, value)) => {
+            Some((version, value, last_applied)) => {
                 let view = serde_json::from_value(value)?;
-                let view_context = ViewContext::new(view_id.to_string(), version);
-                Ok(Some((view, view_context)))
+                let last_applied = match last_applied {
+                    Some(Value::Object(last_applied)) => last_applied,
+                    _ => Map::new(),
+                };
+                Ok(Some(ViewRow {
+                    view,
+                    context: ViewContext::new(view_id.to_string(), version),
+                    last_applied,
+                }))
             }
         }
     }
 
-    async fn update_view(&self, view: V, context: ViewContext) -> Result<(), PersistenceError> {
-        let sql = match context.version {
-            0 => &self.insert_sql,
-            _ => &self.update_sql,
+    fn write_view(
+        &self,
+        connection: &Connection,
+        view: V,
+        context: ViewContext,
+    ) -> Result<(), SqliteAggregateError> {
+        self.write_row(connection, view, context, None)
+    }
+
+    // Writes a view, along with the events applied to it if `last_applied` is provided.
+    fn write_row(
+        &self,
+        connection: &Connection,
+        view: V,
+        context: ViewContext,
+        last_applied: Option<Value>,
+    ) -> Result<(), SqliteAggregateError> {
+        let sql = match (context.version, &last_applied) {
+            (0, None) => &self.insert_sql,
+            (_, None) => &self.update_sql,
+            (0, Some(_)) => &self.insert_applied_sql,
+            (_, Some(_)) => &self.update_applied_sql,
         };
-        let connection = self.pool.get().map_err(SqliteAggregateError::from)?;
-        let mut statement = connection
-            .prepare_cached(sql)
-            .map_err(SqliteAggregateError::from)?;
+        let mut statement = connection.prepare_cached(sql)?;
 
         let version = context.version + 1;
-        let payload = serde_json::to_value(&view).map_err(SqliteAggregateError::from)?;
-        statement
-            .execute((payload, &version, context.view_instance_id))
-            .map_err(SqliteAggregateError::from)?;
+        let payload = serde_json::to_value(&view)?;
+        self.payload_limits
+            .check(PayloadKind::View, &context.view_instance_id, &payload)?;
+        let version_param = version.to_string();
+        let mut logged_params = vec![REDACTED_PARAM, &version_param];
+        if last_applied.is_some() {
+            logged_params.push(REDACTED_PARAM);
+        }
+        logged_params.push(&context.view_instance_id);
+        timed(
+            &self.slow_query_log,
+            sql,
+            &logged_params,
+            || match &last_applied {
+                None => statement.execute((&payload, &version, &context.view_instance_id)),
+                Some(last_applied) => {
+                    statement.execute((&payload, &version, last_applied, &context.view_instance_id))
+                }
+            },
+            |updated| *updated,
+        )?;
+        Ok(())
+    }
 
+    // Adds the `last_applied` column to a view table created without it if deduplication is
+    // enabled. The column is only remembered once found, a column added here may still be rolled
+    // back along with the caller's transaction.
+    fn ensure_applied_column(&self, connection: &Connection) -> Result<(), SqliteAggregateError> {
+        if !self.event_dedup() || self.has_applied_column(connection)? {
+            return Ok(());
+        }
+        let table = TableName::unchecked(&self.view_name);
+        match connection.execute_batch(&format!(
+            "ALTER TABLE {table} ADD COLUMN last_applied json NOT NULL DEFAULT '{{}}'"
+        )) {
+            // added concurrently by another repository
+            Err(rusqlite::Error::SqliteFailure(_, Some(message)))
+                if message.starts_with("duplicate column name") =>
+            {
+                Ok(())
+            }
+            result => result.map_err(SqliteAggregateError::from),
+        }
+    }
+
+    // Whether the view table has the `last_applied` column, remembered once found.
+    fn has_applied_column(&self, connection: &Connection) -> Result<bool, SqliteAggregateError> {
+        if self.applied_column.load(Ordering::Acquire) {
+            return Ok(true);
+        }
+        let table = TableName::unchecked(&self.view_name);
+        let found: bool = connection
+            .prepare_cached(
+                "SELECT count(*) > 0 FROM pragma_table_info(?1, ?2) WHERE name = 'last_applied'",
+            )?
+            .query_row((table.as_str(), table.schema()), |row| row.get(0))?;
+        if found {
+            self.applied_column.store(true, Ordering::Release);
+        }
+        Ok(found)
+    }
+
+    fn event_dedup(&self) -> bool {
+        self.event_dedup.unwrap_or(self.view_ids.is_none())
+    }
+
+    // Applies events to the views they affect, by default the view keyed by the event's aggregate
+    // id as with `GenericQuery`, see `with_view_ids`. With deduplication enabled, events already
+    // applied to a view are skipped and views to which no event was applied are left untouched.
+    fn apply_events(
+        &self,
+        connection: &Connection,
+        events: &[EventEnvelope<A>],
+    ) -> Result<(), SqliteAggregateError> {
+        self.ensure_applied_column(connection)?;
+        // the views loaded so far and whether any event was applied to them, in load order
+        let mut loaded: Vec<(ViewRow<V>, bool)> = Vec::new();
+        for event in events {
+            let view_ids = match &self.view_ids {
+                Some(view_ids) => view_ids.view_ids(event),
+                None => vec![event.aggregate_id.clone()],
+            };
+            for view_id in view_ids {
+                let index = match loaded
+                    .iter()
+                    .position(|(row, _)| row.context.view_instance_id == view_id)
+                {
+                    Some(index) => index,
+                    None => {
+                        let row = self
+                            .select_row(connection, &view_id, self.event_dedup())?
+                            .unwrap_or_else(|| ViewRow {
+                                view: V::default(),
+                                context: ViewContext::new(view_id, 0),
+                                last_applied: Map::new(),
+                            });
+                        loaded.push((row, false));
+                        loaded.len() - 1
+                    }
+                };
+                let (row, changed) = &mut loaded[index];
+                let last_applied = row
+                    .last_applied
+                    .get(&event.aggregate_id)
+                    .and_then(Value::as_u64)
+                    .unwrap_or(0);
+                if !self.event_dedup() || event.sequence as u64 > last_applied {
+                    row.view.update(event);
+                    row.last_applied
+                        .insert(event.aggregate_id.clone(), event.sequence.into());
+                    *changed = true;
+                }
+            }
+        }
+        for (row, changed) in loaded {
+            if changed {
+                self.write_applied(connection, row)?;
+            }
+        }
+        if let (Some(progress_sql), Some(event)) = (&self.progress_sql, events.last()) {
+            record_progress(
+                connection,
+                progress_sql,
+                &self.view_name,
+                &A::aggregate_type(),
+                event,
+            )?;
+        }
         Ok(())
     }
+
+    fn write_applied(
+        &self,
+        connection: &Connection,
+        row: ViewRow<V>,
+    ) -> Result<(), SqliteAggregateError> {
+        let last_applied = self
+            .event_dedup()
+            .then_some(Value::Object(row.last_applied));
+        self.write_row(connection, row.view, row.context, last_applied)
+    }
+
+    pub(crate) fn dispatch_events(
+        &self,
+        events: &[EventEnvelope<A>],
+    ) -> Result<(), SqliteAggregateError> {
+        let mut connection = self.pool.connection()?;
+        in_transaction(&mut connection, TransactionBehavior::Deferred, |tx| {
+            self.apply_events(tx, events)
+        })
+    }
+}
+
+// A view as stored, with the sequence of the last event applied from each aggregate instance.
+struct ViewRow<V> {
+    view: V,
+    context: ViewContext,
+    last_applied: Map<String, Value>,
+}
+
+#[async_trait]
+impl<V, A, P> ViewRepository<V, A> for SqliteViewRepository<V, A, P>
+where
+    V: View<A>,
+    A: Aggregate,
+    P: ConnectionProvider,
+{
+    async fn load(&self, view_id: &str) -> Result<Option<V>, PersistenceError> {
+        check_access(
+            &self.access_policy,
+            AccessKind::View,
+            &self.view_name,
+            view_id,
+        )?;
+        let connection = self.pool.connection()?;
+        Ok(self
+            .select_view(&connection, view_id)?
+            .map(|(view, _)| view))
+    }
+
+    async fn load_with_context(
+        &self,
+        view_id: &str,
+    ) -> Result<Option<(V, ViewContext)>, PersistenceError> {
+        let connection = self.pool.connection()?;
+        Ok(self.select_view(&connection, view_id)?)
+    }
+
+    async fn update_view(&self, view: V, context: ViewContext) -> Result<(), PersistenceError> {
+        let connection = self.pool.connection()?;
+        Ok(self.write_view(&connection, view, context)?)
+    }
+}
+
+/// A view updated within the transaction that commits the events it is built from, see
+/// `SqliteEventRepository::with_transactional_view`.
+pub(crate) trait TransactionalView: Send + Sync {
+    fn aggregate_type(&self) -> String;
+
+    fn apply(
+        &self,
+        tx: &Connection,
+        events: &[SerializedEvent],
+    ) -> Result<(), SqliteAggregateError>;
+}
+
+impl<V, A, P> TransactionalView for SqliteViewRepository<V, A, P>
+where
+    V: View<A>,
+    A: Aggregate,
+    P: ConnectionProvider,
+{
+    fn aggregate_type(&self) -> String {
+        A::aggregate_type()
+    }
+
+    fn apply(
+        &self,
+        tx: &Connection,
+        events: &[SerializedEvent],
+    ) -> Result<(), SqliteAggregateError> {
+        let events = events
+            .iter()
+            .map(|event| EventEnvelope::<A>::try_from(event.clone()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| SqliteAggregateError::DeserializationError(Box::new(err)))?;
+        self.apply_events(tx, &events)
+    }
+}
+
+#[async_trait]
+impl<V, A, P> Query<A> for SqliteViewRepository<V, A, P>
+where
+    V: View<A>,
+    A: Aggregate,
+    P: ConnectionProvider,
+{
+    async fn dispatch(&self, _aggregate_id: &str, events: &[EventEnvelope<A>]) {
+        let Err(err) = self.dispatch_events(events) else {
+            return;
+        };
+        match &self.error_handler {
+            Some(error_handler) => error_handler(err),
+            None => tracing::error!(
+                target: "rusqlite_es::view",
+                view = %self.view_name,
+                error = %err,
+                "events could not be applied to the view"
+            ),
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use crate::testing::tests::{
-        Created, TestAggregate, TestEvent, TestView, TEST_CONNECTION_STRING,
+        test_event_envelope, Created, TestAggregate, TestEvent, TestView, Tested,
+        TEST_CONNECTION_STRING,
     };
-    use crate::{default_sqlite_pool, SqliteViewRepository};
-    use cqrs_es::persist::{ViewContext, ViewRepository};
+    use crate::{
+        default_sqlite_pool, CommitReceipts, ConsistencyToken, SqliteAggregateError,
+        SqliteEventRepository, SqliteViewRepository, ViewIds,
+    };
+    use cqrs_es::persist::{
+        PersistedEventRepository, PersistenceError, ViewContext, ViewRepository,
+    };
+    use cqrs_es::{EventEnvelope, Query, View};
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
     use std::fs;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
 
     #[tokio::test]
     async fn test_valid_view_repository() {
@@ -181,4 +1097,335 @@ mod test {
 
         assert_eq!(found, updated_view);
     }
+
+    #[test]
+    fn repositories_are_shareable() {
+        fn assert_shareable<T: Clone + Send + Sync + 'static>() {}
+        assert_shareable::<SqliteViewRepository<TestView, TestAggregate>>();
+        assert_shareable::<crate::SqliteEventRepository>();
+        assert_shareable::<crate::SingleConnectionEventRepository>();
+    }
+
+    #[tokio::test]
+    async fn transactional_view() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let view_repo =
+            SqliteViewRepository::<TestView, TestAggregate>::new("test_view", pool.clone());
+        let event_repo =
+            SqliteEventRepository::new(pool).with_transactional_view(view_repo.clone());
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = serde_json::json!({});
+        event_repo
+            .persist::<TestAggregate>(&[created.clone()], None)
+            .await
+            .unwrap();
+        let (view, context) = view_repo.load_with_context(&id).await.unwrap().unwrap();
+        assert_eq!(
+            vec![TestEvent::Created(Created { id: id.clone() })],
+            view.events
+        );
+        assert_eq!(1, context.version);
+
+        // a conflicting commit leaves the view untouched
+        let mut tested = test_event_envelope(
+            &id,
+            2,
+            TestEvent::Tested(Tested {
+                test_name: "not committed".to_string(),
+            }),
+        );
+        tested.metadata = serde_json::json!({});
+        let result = event_repo
+            .persist::<TestAggregate>(&[tested, created], None)
+            .await;
+        assert!(matches!(result, Err(PersistenceError::OptimisticLockError)));
+        let (view, context) = view_repo.load_with_context(&id).await.unwrap().unwrap();
+        assert_eq!(1, view.events.len());
+        assert_eq!(1, context.version);
+    }
+
+    #[tokio::test]
+    async fn redispatched_events_are_skipped() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let envelope = |sequence, payload| EventEnvelope::<TestAggregate> {
+            aggregate_id: id.clone(),
+            sequence,
+            payload,
+            metadata: HashMap::new(),
+        };
+        let created = envelope(1, TestEvent::Created(Created { id: id.clone() }));
+        let tested = envelope(
+            2,
+            TestEvent::Tested(Tested {
+                test_name: "applied once".to_string(),
+            }),
+        );
+
+        let repo = SqliteViewRepository::<TestView, TestAggregate>::new("test_view", pool.clone());
+        repo.dispatch(&id, std::slice::from_ref(&created)).await;
+        // a replay redelivers the first event along with the second
+        repo.dispatch(&id, &[created.clone(), tested.clone()]).await;
+        repo.dispatch(&id, std::slice::from_ref(&tested)).await;
+        let (view, context) = repo.load_with_context(&id).await.unwrap().unwrap();
+        assert_eq!(
+            vec![created.payload.clone(), tested.payload.clone()],
+            view.events
+        );
+        assert_eq!(2, context.version);
+
+        let repo = repo.with_event_dedup(false);
+        repo.dispatch(&id, std::slice::from_ref(&tested)).await;
+        let view = repo.load(&id).await.unwrap().unwrap();
+        assert_eq!(3, view.events.len());
+    }
+
+    #[tokio::test]
+    async fn views_without_last_applied() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        pool.get()
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE legacy_view (view_id text PRIMARY KEY, version bigint NOT NULL, payload json NOT NULL)",
+            )
+            .unwrap();
+        let id = uuid::Uuid::new_v4().to_string();
+        let created = EventEnvelope::<TestAggregate> {
+            aggregate_id: id.clone(),
+            sequence: 1,
+            payload: TestEvent::Created(Created { id: id.clone() }),
+            metadata: HashMap::new(),
+        };
+        let tested = EventEnvelope::<TestAggregate> {
+            aggregate_id: id.clone(),
+            sequence: 2,
+            payload: TestEvent::Tested(Tested {
+                test_name: "a test was run".to_string(),
+            }),
+            metadata: HashMap::new(),
+        };
+
+        // without deduplication the table is left as it is
+        let repo =
+            SqliteViewRepository::<TestView, TestAggregate>::new("legacy_view", pool.clone())
+                .with_event_dedup(false);
+        repo.dispatch(&id, std::slice::from_ref(&created)).await;
+        assert_eq!(1, repo.load(&id).await.unwrap().unwrap().events.len());
+        let columns = || {
+            pool.get()
+                .unwrap()
+                .query_row(
+                    "SELECT count(*) FROM pragma_table_info('legacy_view')",
+                    [],
+                    |row| row.get::<_, i64>(0),
+                )
+                .unwrap()
+        };
+        assert_eq!(3, columns());
+
+        // loads do not alter the table
+        let repo =
+            SqliteViewRepository::<TestView, TestAggregate>::new("legacy_view", pool.clone());
+        let token: ConsistencyToken = format!("2.2.{id}").parse().unwrap();
+        let err = repo.load_at_least(&id, &token).await.unwrap_err();
+        assert!(err.to_string().contains("no last_applied column"), "{err}");
+        assert_eq!(3, columns());
+
+        // the `last_applied` column is added once events are deduplicated
+        repo.dispatch(&id, &[created.clone(), tested.clone()]).await;
+        assert_eq!(4, columns());
+        repo.dispatch(&id, &[created, tested]).await;
+        assert_eq!(3, repo.load(&id).await.unwrap().unwrap().events.len());
+        assert_eq!(
+            3,
+            repo.load_at_least(&id, &token).await.unwrap().events.len()
+        );
+    }
+
+    #[tokio::test]
+    async fn error_handler() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let reported = errors.clone();
+        let repo = SqliteViewRepository::<TestView, TestAggregate>::new("missing_view", pool)
+            .with_error_handler(Box::new(move |err| {
+                reported.lock().unwrap().push(err.to_string())
+            }));
+        let id = uuid::Uuid::new_v4().to_string();
+        let created = EventEnvelope::<TestAggregate> {
+            aggregate_id: id.clone(),
+            sequence: 1,
+            payload: TestEvent::Created(Created { id: id.clone() }),
+            metadata: HashMap::new(),
+        };
+        repo.dispatch(&id, &[created]).await;
+        let errors = errors.lock().unwrap();
+        assert_eq!(1, errors.len());
+        assert!(errors[0].contains("missing_view"), "{}", errors[0]);
+    }
+
+    #[tokio::test]
+    async fn load_at_least() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let view_repo =
+            SqliteViewRepository::<TestView, TestAggregate>::new("test_view", pool.clone())
+                .with_consistency_timeout(Duration::from_millis(50));
+        let event_repo =
+            SqliteEventRepository::new(pool).with_commit_receipts(CommitReceipts::Recorded);
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = serde_json::json!({});
+        event_repo
+            .persist::<TestAggregate>(&[created], None)
+            .await
+            .unwrap();
+        let token: ConsistencyToken = event_repo
+            .take_consistency_token::<TestAggregate>(&id)
+            .unwrap()
+            .to_string()
+            .parse()
+            .unwrap();
+        assert_eq!(1, token.aggregate_version());
+
+        // the events have not been dispatched to the view yet
+        assert!(matches!(
+            view_repo.load_at_least(&id, &token).await,
+            Err(SqliteAggregateError::ViewBehind { .. })
+        ));
+        let events = event_repo
+            .get_events::<TestAggregate>(&id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|event| EventEnvelope::try_from(event).unwrap())
+            .collect::<Vec<_>>();
+        let dispatcher = view_repo.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            dispatcher.dispatch("", &events).await;
+        });
+        let view = view_repo.load_at_least(&id, &token).await.unwrap();
+        assert_eq!(1, view.events.len());
+    }
+
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    struct TestRollup {
+        ids: Vec<String>,
+    }
+
+    impl View<TestAggregate> for TestRollup {
+        fn update(&mut self, event: &EventEnvelope<TestAggregate>) {
+            self.ids.push(event.aggregate_id.clone());
+        }
+    }
+
+    impl ViewIds<TestAggregate> for TestRollup {
+        fn view_ids_for(event: &EventEnvelope<TestAggregate>) -> Vec<String> {
+            match &event.payload {
+                TestEvent::Created(_) => vec![event.aggregate_id.clone(), "all".to_string()],
+                _ => vec![],
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn composite_view_ids() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(contents.as_str()).unwrap();
+        drop(conn);
+
+        let created = |id: &str| EventEnvelope::<TestAggregate> {
+            aggregate_id: id.to_string(),
+            sequence: 1,
+            payload: TestEvent::Created(Created { id: id.to_string() }),
+            metadata: HashMap::new(),
+        };
+        let tested = EventEnvelope::<TestAggregate> {
+            sequence: 2,
+            payload: TestEvent::Tested(Tested {
+                test_name: "not in any view".to_string(),
+            }),
+            ..created("first")
+        };
+
+        let repo =
+            SqliteViewRepository::<TestRollup, TestAggregate>::new("test_view", pool.clone())
+                .with_view_ids()
+                .with_event_dedup(true);
+        repo.dispatch("first", &[created("first"), tested]).await;
+        repo.dispatch("second", &[created("second")]).await;
+        // redelivered events are skipped for every view they were applied to
+        repo.dispatch("first", &[created("first")]).await;
+
+        let all = repo.load("all").await.unwrap().unwrap();
+        assert_eq!(vec!["first", "second"], all.ids);
+        let first = repo.load("first").await.unwrap().unwrap();
+        assert_eq!(vec!["first"], first.ids);
+        let second = repo.load("second").await.unwrap().unwrap();
+        assert_eq!(vec!["second"], second.ids);
+
+        // deduplication of such views is not enabled by default
+        let repo = SqliteViewRepository::<TestRollup, TestAggregate>::new("test_view", pool)
+            .with_view_ids();
+        repo.dispatch("third", &[created("third")]).await;
+        repo.dispatch("third", &[created("third")]).await;
+        let third = repo.load("third").await.unwrap().unwrap();
+        assert_eq!(vec!["third", "third"], third.ids);
+    }
+
+    #[tokio::test]
+    async fn list_views() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        pool.get().unwrap().execute_batch(&contents).unwrap();
+        let repo = SqliteViewRepository::<TestView, TestAggregate>::new("test_view", pool);
+        let ids = ["a", "b", "c"];
+        for id in ids {
+            let view = TestView {
+                events: vec![TestEvent::Created(Created { id: id.to_string() })],
+            };
+            repo.update_view(view, ViewContext::new(id.to_string(), 0))
+                .await
+                .unwrap();
+        }
+
+        let first = repo.list_views(None, 2).await.unwrap();
+        assert_eq!(
+            vec!["a", "b"],
+            first
+                .items
+                .iter()
+                .map(|(id, _)| id.as_str())
+                .collect::<Vec<_>>()
+        );
+        let cursor = first.next.unwrap();
+        let second = repo
+            .list_views(Some(cursor.to_string().parse().unwrap()), 2)
+            .await
+            .unwrap();
+        assert_eq!(1, second.items.len());
+        assert_eq!("c", second.items[0].0);
+        assert_eq!(None, second.next);
+    }
 }
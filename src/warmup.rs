@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+
+use cqrs_es::persist::PersistenceError;
+use cqrs_es::Aggregate;
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::SqliteEventRepository;
+
+/// The aggregate instances loaded by `SqliteEventRepository::warmup`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Warmup<'a> {
+    /// The aggregate instances with the provided ids.
+    Aggregates(&'a [&'a str]),
+    /// The provided number of aggregate instances with the most recently committed events.
+    MostRecent(usize),
+}
+
+impl<P: ConnectionProvider> SqliteEventRepository<P> {
+    /// Loads aggregate instances of type `A` ahead of their first command, e.g. right after
+    /// process start, to reduce the latency of the first requests.
+    ///
+    /// Reading the snapshots and events of the aggregate instances, see `load_aggregates`,
+    /// brings their table and index pages into the page cache of the connection used and into
+    /// the operating system's file cache, which is shared by all connections. The loaded
+    /// aggregate instances are returned, e.g. to seed an application-level cache.
+    ///
+    /// Warmup is run by the application itself rather than on behalf of a caller, so the
+    /// `AccessPolicy`, if any, is not consulted and needs no claims to grant it.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// use cqrs_es::persist::PersistenceError;
+    /// use rusqlite_es::{SqliteEventRepository, Warmup};
+    ///
+    /// async fn warm_up(repo: &SqliteEventRepository) -> Result<(), PersistenceError> {
+    ///     let loaded = repo.warmup::<MyAggregate>(Warmup::MostRecent(1000)).await?;
+    ///     println!("warmed up {} aggregates", loaded.len());
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn warmup<A: Aggregate>(
+        &self,
+        warmup: Warmup<'_>,
+    ) -> Result<HashMap<String, A>, PersistenceError> {
+        let loaded = match warmup {
+            Warmup::Aggregates(aggregate_ids) => {
+                self.load_aggregates_unchecked::<A>(aggregate_ids)?
+            }
+            Warmup::MostRecent(count) => {
+                let aggregate_ids = self.recent_aggregate_ids(&A::aggregate_type(), count)?;
+                let aggregate_ids = aggregate_ids.iter().map(String::as_str).collect::<Vec<_>>();
+                self.load_aggregates_unchecked::<A>(&aggregate_ids)?
+            }
+        };
+        Ok(loaded)
+    }
+
+    fn recent_aggregate_ids(
+        &self,
+        aggregate_type: &str,
+        count: usize,
+    ) -> Result<Vec<String>, SqliteAggregateError> {
+        let connection = self.pool().connection()?;
+        let mut statement =
+            connection.prepare_cached(self.query_factory().recent_aggregate_ids())?;
+        let ids = statement
+            .query_map((aggregate_type, count as i64), |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cqrs_es::persist::PersistedEventRepository;
+    use serde_json::json;
+
+    use crate::testing::tests::{
+        test_event_envelope, Created, TestAggregate, TestEvent, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, AccessRequest, SqliteEventRepository, Warmup};
+
+    #[tokio::test]
+    async fn warmup() {
+        let repo = SqliteEventRepository::connect(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .await
+            .unwrap();
+        let ids = (0..3)
+            .map(|_| uuid::Uuid::new_v4().to_string())
+            .collect::<Vec<_>>();
+        for id in &ids {
+            let mut created =
+                test_event_envelope(id, 1, TestEvent::Created(Created { id: id.clone() }));
+            created.metadata = json!({});
+            repo.persist::<TestAggregate>(&[created], None)
+                .await
+                .unwrap();
+        }
+
+        let loaded = repo
+            .warmup::<TestAggregate>(Warmup::MostRecent(2))
+            .await
+            .unwrap();
+        assert_eq!(2, loaded.len());
+        assert!(loaded.contains_key(&ids[1]));
+        assert!(loaded.contains_key(&ids[2]));
+
+        let loaded = repo
+            .warmup::<TestAggregate>(Warmup::Aggregates(&[&ids[0]]))
+            .await
+            .unwrap();
+        assert!(loaded.contains_key(&ids[0]));
+    }
+
+    #[tokio::test]
+    async fn warmup_with_access_policy() {
+        let repo = SqliteEventRepository::connect(default_sqlite_pool(TEST_CONNECTION_STRING))
+            .await
+            .unwrap()
+            .into_inner()
+            .with_access_policy(Box::new(|_: &AccessRequest| false));
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut created =
+            test_event_envelope(&id, 1, TestEvent::Created(Created { id: id.clone() }));
+        created.metadata = json!({});
+        repo.persist::<TestAggregate>(&[created], None)
+            .await
+            .unwrap();
+
+        // warmup is not made on behalf of a caller, the policy only applies to callers
+        let loaded = repo
+            .warmup::<TestAggregate>(Warmup::MostRecent(1))
+            .await
+            .unwrap();
+        assert!(loaded.contains_key(&id));
+        assert!(repo.load_aggregates::<TestAggregate>(&[&id]).await.is_err());
+    }
+}
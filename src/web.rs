@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use cqrs_es::persist::{PersistenceError, ViewRepository};
+use cqrs_es::{Aggregate, AggregateError, View};
+
+use crate::{CommandPermit, CommandQueue, SqliteAggregateError, SqliteCqrs, SqliteViewRepository};
+
+/// Application state for web services bundling the command side (`SqliteCqrs`) with the view
+/// repository serving queries for an aggregate.
+///
+/// `CqrsState` is cheap to clone and is `Send + Sync`, so it can be used directly as shared
+/// state by web frameworks, e.g. axum's `State` extractor or actix-web's `Data`, without
+/// further wrapping. See `examples/axum.rs` for a complete service.
+///
+/// ```
+/// # use cqrs_es::doc::{MyAggregate, MyService};
+/// # use cqrs_es::persist::doc::MyView;
+/// use std::sync::Arc;
+/// use rusqlite_es::{default_sqlite_pool, sqlite_cqrs, CqrsState, SqliteViewRepository};
+///
+/// let pool = default_sqlite_pool(":memory:");
+/// let view_repo = Arc::new(SqliteViewRepository::<MyView, MyAggregate>::new(
+///     "my_view",
+///     pool.clone(),
+/// ));
+/// let cqrs = sqlite_cqrs::<MyAggregate>(pool, vec![], MyService);
+/// let state = CqrsState::new(cqrs, view_repo);
+/// # drop(state);
+/// ```
+pub struct CqrsState<A, V>
+where
+    A: Aggregate,
+    V: View<A>,
+{
+    cqrs: Arc<SqliteCqrs<A>>,
+    view_repository: Arc<SqliteViewRepository<V, A>>,
+    command_queue: Option<CommandQueue>,
+}
+
+// Implemented manually, deriving would needlessly require the view and aggregate to be `Clone`.
+impl<A, V> Clone for CqrsState<A, V>
+where
+    A: Aggregate,
+    V: View<A>,
+{
+    fn clone(&self) -> Self {
+        Self {
+            cqrs: self.cqrs.clone(),
+            view_repository: self.view_repository.clone(),
+            command_queue: self.command_queue.clone(),
+        }
+    }
+}
+
+impl<A, V> CqrsState<A, V>
+where
+    A: Aggregate,
+    V: View<A>,
+{
+    /// Creates the state from a framework and the view repository used by its queries. The view
+    /// repository is shared with the query processing the framework's events.
+    pub fn new(cqrs: SqliteCqrs<A>, view_repository: Arc<SqliteViewRepository<V, A>>) -> Self {
+        Self {
+            cqrs: Arc::new(cqrs),
+            view_repository,
+            command_queue: None,
+        }
+    }
+
+    /// Queues the commands executed on the same aggregate instance, so that they run one after
+    /// the other rather than conflicting, see `CommandQueue`.
+    ///
+    /// ```
+    /// # use cqrs_es::doc::MyAggregate;
+    /// # use cqrs_es::persist::doc::MyView;
+    /// use rusqlite_es::{CommandQueue, CqrsState};
+    ///
+    /// fn queue_commands(state: CqrsState<MyAggregate, MyView>) -> CqrsState<MyAggregate, MyView> {
+    ///     state.with_command_queue(CommandQueue::new().with_max_waiting(100))
+    /// }
+    /// ```
+    pub fn with_command_queue(self, command_queue: CommandQueue) -> Self {
+        Self {
+            command_queue: Some(command_queue),
+            ..self
+        }
+    }
+
+    /// Executes a command against the aggregate instance `aggregate_id`.
+    pub async fn execute(
+        &self,
+        aggregate_id: &str,
+        command: A::Command,
+    ) -> Result<(), AggregateError<A::Error>> {
+        let _permit = self.queue(aggregate_id).await?;
+        self.cqrs.execute(aggregate_id, command).await
+    }
+
+    /// Executes a command against the aggregate instance `aggregate_id`, recording the provided
+    /// metadata (e.g., the `user_id` of the requester) with any resulting events.
+    pub async fn execute_with_metadata(
+        &self,
+        aggregate_id: &str,
+        command: A::Command,
+        metadata: HashMap<String, String>,
+    ) -> Result<(), AggregateError<A::Error>> {
+        let _permit = self.queue(aggregate_id).await?;
+        self.cqrs
+            .execute_with_metadata(aggregate_id, command, metadata)
+            .await
+    }
+
+    /// Loads the view for `view_id`, if it exists.
+    pub async fn load_view(&self, view_id: &str) -> Result<Option<V>, PersistenceError> {
+        self.view_repository.load(view_id).await
+    }
+
+    /// The framework used to execute commands.
+    pub fn cqrs(&self) -> &SqliteCqrs<A> {
+        &self.cqrs
+    }
+
+    /// The view repository used to serve queries.
+    pub fn view_repository(&self) -> &SqliteViewRepository<V, A> {
+        &self.view_repository
+    }
+
+    // Waits for the commands queued before on the aggregate instance, if commands are queued.
+    async fn queue(
+        &self,
+        aggregate_id: &str,
+    ) -> Result<Option<CommandPermit>, SqliteAggregateError> {
+        match &self.command_queue {
+            Some(command_queue) => Ok(Some(command_queue.lock(aggregate_id).await?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::sync::Arc;
+
+    use cqrs_es::persist::{ViewContext, ViewRepository};
+
+    use crate::testing::tests::{
+        Created, TestAggregate, TestEvent, TestServices, TestView, TEST_CONNECTION_STRING,
+    };
+    use crate::{default_sqlite_pool, sqlite_cqrs, CqrsState, SqliteViewRepository};
+
+    #[tokio::test]
+    async fn shared_state() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        pool.get()
+            .unwrap()
+            .execute_batch(contents.as_str())
+            .unwrap();
+
+        let view_repo = Arc::new(SqliteViewRepository::<TestView, TestAggregate>::new(
+            "test_view",
+            pool.clone(),
+        ));
+        let cqrs = sqlite_cqrs(pool, vec![], TestServices);
+        let state = CqrsState::new(cqrs, view_repo);
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let view = TestView {
+            events: vec![TestEvent::Created(Created { id: id.clone() })],
+        };
+        state
+            .view_repository()
+            .update_view(view.clone(), ViewContext::new(id.clone(), 0))
+            .await
+            .unwrap();
+
+        let handler_state = state.clone();
+        let found = tokio::spawn(async move { handler_state.load_view(&id).await })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some(view), found);
+        assert!(state.load_view("unknown").await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,140 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::connection::ConnectionProvider;
+use crate::error::SqliteAggregateError;
+use crate::identifier::TableName;
+
+const DEFAULT_LEASE_TABLE: &str = "writer_leases";
+const DEFAULT_LEASE_TTL: Duration = Duration::from_secs(30);
+
+/// An advisory lease used to elect a single writer among processes sharing a database file.
+///
+/// Each process creates a `WriterLease` with the same name and its own unique holder id, and
+/// periodically calls `try_acquire`. Only one holder obtains the lease at a time, it should act
+/// as the writer (or relay) and renew the lease by calling `try_acquire` again well within the
+/// lease's time-to-live. The other processes run read-only until the lease is released or
+/// expires, avoiding contention on SQLite's single write lock.
+///
+/// Leases are stored in the `writer_leases` table (see `/db/init.sql`). Expiry is based on the
+/// system clock, which is shared by processes on the same host.
+///
+/// ```
+/// use r2d2::Pool;
+/// use r2d2_sqlite::SqliteConnectionManager;
+/// use rusqlite_es::WriterLease;
+///
+/// async fn run(pool: Pool<SqliteConnectionManager>) {
+///     let lease = WriterLease::new(pool, "relay", &std::process::id().to_string());
+///     if lease.try_acquire().await.unwrap() {
+///         // ... act as the writer, renewing the lease periodically ...
+///         lease.release().await.unwrap();
+///     }
+/// }
+/// ```
+#[derive(Clone)]
+pub struct WriterLease<P> {
+    pool: P,
+    name: String,
+    holder: String,
+    ttl: Duration,
+    acquire_sql: String,
+    release_sql: String,
+}
+
+impl<P: ConnectionProvider> WriterLease<P> {
+    /// Creates a lease named `name` for the holder identified by `holder`, using the default
+    /// table 'writer_leases' and a time-to-live of 30 seconds.
+    pub fn new(pool: P, name: &str, holder: &str) -> Self {
+        Self::use_table(pool, name, holder, DEFAULT_LEASE_TABLE, DEFAULT_LEASE_TTL)
+    }
+
+    /// Configures how long the lease is held after it is acquired or renewed.
+    pub fn with_ttl(self, ttl: Duration) -> Self {
+        Self { ttl, ..self }
+    }
+
+    /// Configures the lease to be stored in the provided table.
+    pub fn with_table(self, table: &str) -> Self {
+        Self::use_table(self.pool, &self.name, &self.holder, table, self.ttl)
+    }
+
+    fn use_table(pool: P, name: &str, holder: &str, table: &str, ttl: Duration) -> Self {
+        let table = TableName::unchecked(table);
+        Self {
+            pool,
+            name: name.to_string(),
+            holder: holder.to_string(),
+            ttl,
+            acquire_sql: format!(
+                "
+INSERT INTO {table} (name, holder, expires_at) VALUES (?, ?, ?)
+  ON CONFLICT (name) DO UPDATE SET holder = excluded.holder, expires_at = excluded.expires_at
+  WHERE {table}.holder = excluded.holder OR {table}.expires_at <= ?"
+            ),
+            release_sql: format!("DELETE FROM {table} WHERE name = ? AND holder = ?"),
+        }
+    }
+
+    /// Attempts to acquire the lease, or renews it if it is already held by this holder.
+    /// Returns true if this holder now holds the lease.
+    pub async fn try_acquire(&self) -> Result<bool, SqliteAggregateError> {
+        let now = now_millis();
+        let expires_at = now.saturating_add(self.ttl.as_millis() as i64);
+        let connection = self.pool.connection()?;
+        let rows_affected = connection.execute(
+            &self.acquire_sql,
+            (&self.name, &self.holder, expires_at, now),
+        )?;
+        Ok(rows_affected == 1)
+    }
+
+    /// Releases the lease if it is held by this holder, allowing another holder to acquire it
+    /// immediately rather than after it expires.
+    pub async fn release(&self) -> Result<(), SqliteAggregateError> {
+        let connection = self.pool.connection()?;
+        connection.execute(&self.release_sql, (&self.name, &self.holder))?;
+        Ok(())
+    }
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as i64)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::time::Duration;
+
+    use crate::testing::tests::TEST_CONNECTION_STRING;
+    use crate::{default_sqlite_pool, WriterLease};
+
+    #[tokio::test]
+    async fn single_writer() {
+        let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        pool.get()
+            .unwrap()
+            .execute_batch(contents.as_str())
+            .unwrap();
+
+        let first = WriterLease::new(pool.clone(), "relay", "first");
+        let second = WriterLease::new(pool.clone(), "relay", "second");
+        assert!(first.try_acquire().await.unwrap());
+        assert!(first.try_acquire().await.unwrap());
+        assert!(!second.try_acquire().await.unwrap());
+
+        first.release().await.unwrap();
+        assert!(second.try_acquire().await.unwrap());
+        assert!(!first.try_acquire().await.unwrap());
+
+        // an expired lease can be taken over
+        let expiring = WriterLease::new(pool.clone(), "expiring", "first").with_ttl(Duration::ZERO);
+        let other = WriterLease::new(pool, "expiring", "second");
+        assert!(expiring.try_acquire().await.unwrap());
+        assert!(other.try_acquire().await.unwrap());
+        assert!(!expiring.try_acquire().await.unwrap());
+    }
+}
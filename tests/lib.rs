@@ -1,14 +1,70 @@
-use cqrs_es::doc::{Customer, CustomerEvent};
-use cqrs_es::persist::{PersistedEventStore, SemanticVersionEventUpcaster};
-use cqrs_es::EventStore;
+use cqrs_es::doc::{Customer, CustomerCommand, CustomerEvent, CustomerService};
+use cqrs_es::persist::{
+    PersistedEventRepository, PersistedEventStore, SemanticVersionEventUpcaster, ViewRepository,
+};
+use cqrs_es::{AggregateError, EventEnvelope, EventStore, View};
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite_es::{default_sqlite_pool, SqliteEventRepository};
+use rusqlite_es::{
+    default_sqlite_pool, sqlite_cqrs, sqlite_generic_query, sqlite_snapshot_cqrs,
+    SqliteEventRepository, SqliteViewRepository,
+};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 const TEST_CONNECTION_STRING: &str = ":memory:";
 
+// An SQLite database file initialized with `db/init.sql`, removed when dropped.
+struct TempDatabase {
+    path: PathBuf,
+    pool: Pool<SqliteConnectionManager>,
+}
+
+impl TempDatabase {
+    fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("rusqlite-es-{}.db", uuid::Uuid::new_v4()));
+        let manager = SqliteConnectionManager::file(&path).with_init(|conn| {
+            conn.pragma_update(None, "journal_mode", "wal")?;
+            conn.busy_timeout(Duration::from_secs(5))
+        });
+        let pool = Pool::builder().max_size(4).build(manager).unwrap();
+        let contents = fs::read_to_string("db/init.sql").unwrap();
+        pool.get().unwrap().execute_batch(&contents).unwrap();
+        Self { path, pool }
+    }
+}
+
+impl Drop for TempDatabase {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = self.path.clone().into_os_string();
+            path.push(suffix);
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CustomerView {
+    name: String,
+    email: String,
+    updates: usize,
+}
+
+impl View<Customer> for CustomerView {
+    fn update(&mut self, event: &EventEnvelope<Customer>) {
+        match &event.payload {
+            CustomerEvent::NameAdded { name } => self.name = name.clone(),
+            CustomerEvent::EmailUpdated { new_email } => self.email = new_email.clone(),
+        }
+        self.updates += 1;
+    }
+}
+
 async fn new_test_event_store(
     pool: Pool<SqliteConnectionManager>,
 ) -> PersistedEventStore<SqliteEventRepository, Customer> {
@@ -45,6 +101,50 @@ async fn commit_and_load_events_snapshot_store() {
     simple_es_commit_and_load_test(event_store).await;
 }
 
+// The tables as created by the `db/init.sql` of the first release.
+const BASELINE_TABLES: &str = "
+CREATE TABLE events
+(
+    aggregate_type text                         NOT NULL,
+    aggregate_id   text                         NOT NULL,
+    sequence       bigint CHECK (sequence >= 0) NOT NULL,
+    event_type     text                         NOT NULL,
+    event_version  text                         NOT NULL,
+    payload        json                         NOT NULL,
+    metadata       json                         NOT NULL,
+    PRIMARY KEY (aggregate_type, aggregate_id, sequence)
+);
+CREATE TABLE snapshots
+(
+    aggregate_type   text                                 NOT NULL,
+    aggregate_id     text                                 NOT NULL,
+    last_sequence    bigint CHECK (last_sequence >= 0)    NOT NULL,
+    current_snapshot bigint CHECK (current_snapshot >= 0) NOT NULL,
+    payload          json                                 NOT NULL,
+    PRIMARY KEY (aggregate_type, aggregate_id, last_sequence)
+);";
+
+#[tokio::test]
+async fn commit_and_load_events_migrated_store() {
+    let pool = default_sqlite_pool(TEST_CONNECTION_STRING);
+    let conn = pool.get().unwrap();
+    conn.execute_batch(BASELINE_TABLES).unwrap();
+    for migration in [
+        "db/migrate_created_at.sql",
+        "db/migrate_snapshot_version.sql",
+    ] {
+        let migration = fs::read_to_string(migration).unwrap();
+        conn.execute_batch(&migration).unwrap();
+    }
+    drop(conn);
+
+    simple_es_commit_and_load_test(new_test_event_store(pool.clone()).await).await;
+    let repo = SqliteEventRepository::new(pool);
+    let event_store =
+        PersistedEventStore::<SqliteEventRepository, Customer>::new_aggregate_store(repo);
+    simple_es_commit_and_load_test(event_store).await;
+}
+
 async fn simple_es_commit_and_load_test(
     event_store: PersistedEventStore<SqliteEventRepository, Customer>,
 ) {
@@ -112,3 +212,180 @@ async fn upcasted_event() {
     assert_eq!(1, result.current_sequence);
     assert_eq!(None, result.current_snapshot);
 }
+
+#[tokio::test]
+async fn framework_updates_views() {
+    let db = TempDatabase::new();
+    let view_repo = Arc::new(SqliteViewRepository::<CustomerView, Customer>::new(
+        "test_view",
+        db.pool.clone(),
+    ));
+    let mut query = sqlite_generic_query::<CustomerView, Customer>("test_view", db.pool.clone());
+    query.use_error_handler(Box::new(|err| panic!("{err}")));
+    let cqrs = sqlite_cqrs(db.pool.clone(), vec![Box::new(query)], CustomerService);
+
+    let id = uuid::Uuid::new_v4().to_string();
+    cqrs.execute(
+        &id,
+        CustomerCommand::AddCustomerName {
+            name: "alice".to_string(),
+        },
+    )
+    .await
+    .unwrap();
+    cqrs.execute(
+        &id,
+        CustomerCommand::UpdateEmail {
+            new_email: "alice@example.com".to_string(),
+        },
+    )
+    .await
+    .unwrap();
+    let rejected = cqrs
+        .execute(
+            &id,
+            CustomerCommand::AddCustomerName {
+                name: "bob".to_string(),
+            },
+        )
+        .await;
+    assert!(matches!(rejected, Err(AggregateError::UserError(_))));
+
+    let view = view_repo.load(&id).await.unwrap().unwrap();
+    assert_eq!("alice", view.name);
+    assert_eq!("alice@example.com", view.email);
+    assert_eq!(2, view.updates);
+}
+
+#[tokio::test]
+async fn concurrent_commits_conflict() {
+    let db = TempDatabase::new();
+    let event_store = new_test_event_store(db.pool.clone()).await;
+    let id = uuid::Uuid::new_v4().to_string();
+    let first = event_store.load_aggregate(&id).await.unwrap();
+    let second = event_store.load_aggregate(&id).await.unwrap();
+    let name_added = |name: &str| {
+        vec![CustomerEvent::NameAdded {
+            name: name.to_string(),
+        }]
+    };
+
+    event_store
+        .commit(name_added("alice"), first, Default::default())
+        .await
+        .unwrap();
+    let conflict = event_store
+        .commit(name_added("bob"), second, Default::default())
+        .await;
+    assert!(matches!(conflict, Err(AggregateError::AggregateConflict)));
+    assert_eq!(1, event_store.load_events(&id).await.unwrap().len());
+}
+
+#[tokio::test]
+async fn snapshots_are_taken() {
+    let db = TempDatabase::new();
+    let cqrs = sqlite_snapshot_cqrs::<Customer>(db.pool.clone(), vec![], 2, CustomerService);
+    let id = uuid::Uuid::new_v4().to_string();
+    for attempt in 0..3 {
+        cqrs.execute(
+            &id,
+            CustomerCommand::UpdateEmail {
+                new_email: format!("{attempt}@example.com"),
+            },
+        )
+        .await
+        .unwrap();
+    }
+
+    let repo = SqliteEventRepository::new(db.pool.clone());
+    let snapshot = repo.get_snapshot::<Customer>(&id).await.unwrap().unwrap();
+    assert_eq!(2, snapshot.current_sequence);
+    let customer: Customer = serde_json::from_value(snapshot.aggregate).unwrap();
+    assert_eq!("1@example.com", customer.email);
+    let event_store = PersistedEventStore::<_, Customer>::new_snapshot_store(repo, 2);
+    let context = event_store.load_aggregate(&id).await.unwrap();
+    assert_eq!(3, context.current_sequence);
+}
+
+#[tokio::test]
+async fn events_are_streamed() {
+    let db = TempDatabase::new();
+    // the event seeded by `db/init.sql` only loads through an upcaster
+    db.pool
+        .get()
+        .unwrap()
+        .execute("DELETE FROM events", [])
+        .unwrap();
+    let cqrs = sqlite_cqrs::<Customer>(db.pool.clone(), vec![], CustomerService);
+    let ids = (0..3)
+        .map(|_| uuid::Uuid::new_v4().to_string())
+        .collect::<Vec<_>>();
+    for id in &ids {
+        cqrs.execute(id, CustomerCommand::AddCustomerName { name: id.clone() })
+            .await
+            .unwrap();
+    }
+
+    let repo = SqliteEventRepository::new(db.pool.clone());
+    let mut stream = repo.stream_events::<Customer>(&ids[1]).await.unwrap();
+    let event = stream.next::<Customer>(&None).await.unwrap().unwrap();
+    assert_eq!(ids[1], event.aggregate_id);
+    assert!(stream.next::<Customer>(&None).await.is_none());
+
+    let mut stream = repo.stream_all_events::<Customer>().await.unwrap();
+    let mut streamed = Vec::new();
+    while let Some(event) = stream.next::<Customer>(&None).await {
+        streamed.push(event.unwrap().aggregate_id);
+    }
+    // events are streamed in order of their sequence, not across aggregate instances
+    streamed.sort();
+    let mut expected = ids.clone();
+    expected.sort();
+    assert_eq!(expected, streamed);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_commands() {
+    let db = TempDatabase::new();
+    let cqrs = Arc::new(sqlite_cqrs::<Customer>(
+        db.pool.clone(),
+        vec![],
+        CustomerService,
+    ));
+    let id = uuid::Uuid::new_v4().to_string();
+    let tasks = (0..16)
+        .map(|attempt| {
+            let cqrs = cqrs.clone();
+            let id = format!("{id}-{}", attempt % 4);
+            tokio::spawn(async move {
+                let command = CustomerCommand::UpdateEmail {
+                    new_email: format!("{attempt}@example.com"),
+                };
+                // commands for the same customer may conflict and are retried
+                loop {
+                    match cqrs.execute(&id, command.clone()).await {
+                        Err(AggregateError::AggregateConflict) => continue,
+                        result => return result,
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for task in tasks {
+        task.await.unwrap().unwrap();
+    }
+
+    let repo = SqliteEventRepository::new(db.pool.clone());
+    for customer in 0..4 {
+        let events = repo
+            .get_events::<Customer>(&format!("{id}-{customer}"))
+            .await
+            .unwrap();
+        assert_eq!(4, events.len());
+        let sequences = events
+            .iter()
+            .map(|event| event.sequence)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![1, 2, 3, 4], sequences);
+    }
+}